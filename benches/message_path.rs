@@ -0,0 +1,125 @@
+/*!
+ * Benchmarks of the message path.
+ *
+ * Every message sent or received goes through these: the envelope is
+ * encoded and signed, or decoded and verified, the rate limiter and the
+ * deduplication cache are checked for each one received, and the history
+ * is sealed and opened with the storage key. Run them with `cargo bench`,
+ * saving a baseline before a change to compare it against after.
+ */
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libp2p::{identity::Keypair, PeerId};
+use sec_msg::dedup::DedupCache;
+use sec_msg::delivery::MessageId;
+use sec_msg::protocol::{Envelope, Payload, TextMessage};
+use sec_msg::rate_limit::{Limit, Limits, MessageClass, RateLimiter};
+use sec_msg::storage::Vault;
+use zeroize::Zeroizing;
+
+/// Sizes of the messages benchmarked, in bytes.
+const SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+/// Returns a text message of a size.
+fn text(size: usize) -> Payload {
+    Payload::Text(TextMessage {
+        id: MessageId::random(),
+        body: "a".repeat(size),
+        ack_requested: false,
+    })
+}
+
+fn envelope(c: &mut Criterion) {
+    let key = Keypair::generate_ed25519();
+    let mut group = c.benchmark_group("envelope");
+    for size in SIZES {
+        let payload = text(size);
+        let encoded = Envelope::seal("chat", &payload, Some("alice".to_string()), &key)
+            .unwrap()
+            .encode()
+            .unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("encode/{}", size), |b| {
+            b.iter(|| {
+                Envelope::seal("chat", black_box(&payload), Some("alice".to_string()), &key)
+                    .unwrap()
+                    .encode()
+                    .unwrap()
+            })
+        });
+        group.bench_function(format!("decode/{}", size), |b| {
+            b.iter(|| {
+                Envelope::decode(black_box(&encoded))
+                    .unwrap()
+                    .open("chat")
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn encryption(c: &mut Criterion) {
+    // Deriving the key is slow on purpose, so it is done once.
+    let vault = Vault::new(Zeroizing::new("correct horse battery staple".to_string()));
+    let mut group = c.benchmark_group("encryption");
+    for size in SIZES {
+        let plaintext = vec![7; size];
+        let sealed = vault.seal(&plaintext);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("seal/{}", size), |b| {
+            b.iter(|| vault.seal(black_box(&plaintext)))
+        });
+        group.bench_function(format!("open/{}", size), |b| {
+            b.iter(|| vault.open(black_box(&sealed)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn rate_limiter(c: &mut Criterion) {
+    let peers: Vec<PeerId> = (0..1000).map(|_| PeerId::random()).collect();
+    let mut group = c.benchmark_group("rate_limiter");
+    group.bench_function("check", |b| {
+        let mut limiter = RateLimiter::new(Limits::new(Limit::new(u32::MAX, u32::MAX)), 1024);
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % peers.len();
+            limiter.check(black_box(peers[i]), "chat", MessageClass::Chat)
+        })
+    });
+    group.bench_function("check_evicting", |b| {
+        // More peers than buckets, so every check evicts one.
+        let mut limiter = RateLimiter::new(Limits::new(Limit::new(60, 10)), 100);
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % peers.len();
+            limiter.check(black_box(peers[i]), "chat", MessageClass::Chat)
+        })
+    });
+    group.finish();
+}
+
+fn dedup_cache(c: &mut Criterion) {
+    let envelope = vec![7; 1024];
+    let mut group = c.benchmark_group("dedup_cache");
+    group.bench_function("duplicate", |b| {
+        let mut cache = DedupCache::new();
+        cache.insert("chat", &envelope);
+        b.iter(|| cache.insert("chat", black_box(&envelope)))
+    });
+    group.bench_function("new", |b| {
+        let mut cache = DedupCache::new();
+        b.iter_batched(
+            || MessageId::random().0,
+            |id| cache.insert("chat", black_box(&id)),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, envelope, encryption, rate_limiter, dedup_cache);
+criterion_main!(benches);
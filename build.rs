@@ -0,0 +1,19 @@
+/*!
+ * Build script for the messaging application.
+ *
+ * Generates the gRPC service from `proto/sec_msg.proto` when the `grpc`
+ * feature is on, with a bundled `protoc` so none needs to be installed.
+ */
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        // Only the server: the generated client's `connect` constructor
+        // clashes with the `Connect` call, and clients bring their own.
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/sec_msg.proto"], &["proto"])?;
+    }
+    Ok(())
+}
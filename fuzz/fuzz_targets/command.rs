@@ -0,0 +1,82 @@
+/*!
+ * Fuzz target for the parsing of user input.
+ *
+ * The input is read as a line typed by the user, and handled by a node over
+ * the in-memory transport, without peers, as the terminal interface would
+ * handle it. The node lives across inputs, so a command can act on the
+ * state left by the ones before it. Commands reading or writing files are
+ * skipped, as the fuzzer would fill the disk with them.
+ */
+
+#![no_main]
+
+use std::cell::RefCell;
+
+use libfuzzer_sys::fuzz_target;
+use libp2p::identity::Keypair;
+use sec_msg::cli::Options;
+use sec_msg::config::Config;
+use sec_msg::chaos::ChaosControl;
+use sec_msg::network::create_memory_swarm;
+use sec_msg::node::Node;
+use sec_msg::ui::handle_user_input;
+use tokio::runtime::Runtime;
+
+/// Commands not fuzzed, as they read or write files.
+const SKIPPED: [&str; 5] = [
+    "/send-file",
+    "/accept-file",
+    "/export",
+    "/identity",
+    "/reload",
+];
+
+thread_local! {
+    static NODE: RefCell<(Runtime, Node)> = RefCell::new(start());
+}
+
+/// Starts a node listening nowhere.
+fn start() -> (Runtime, Node) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let node = runtime.block_on(async {
+        let mut config = Config::from_options(&Options::default()).unwrap();
+        config.listen_addrs = Vec::new();
+        let local_key = Keypair::ed25519_from_bytes([7; 32]).unwrap();
+        let peer_id = local_key.public().to_peer_id();
+        let swarm = create_memory_swarm(
+            local_key.clone(),
+            peer_id,
+            &config.topics[0],
+            config.pubsub_protocol,
+            ChaosControl::default(),
+        )
+        .unwrap();
+        Node::with_swarm(swarm, &config, local_key, None, None).unwrap()
+    });
+    (runtime, node)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    if SKIPPED.iter().any(|command| line.starts_with(command)) {
+        return;
+    }
+    NODE.with(|node| {
+        let (runtime, node) = &mut *node.borrow_mut();
+        let peer_id = *node.swarm.local_peer_id();
+        runtime.block_on(handle_user_input(
+            line.to_string(),
+            &mut node.swarm,
+            &mut node.state,
+        ));
+        let state = &mut node.state;
+        state
+            .outbound
+            .flush(node.swarm.behaviour_mut(), &mut state.middleware, peer_id);
+    });
+});
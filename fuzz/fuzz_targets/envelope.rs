@@ -0,0 +1,45 @@
+/*!
+ * Fuzz target for the decoding of envelopes.
+ *
+ * The input is read twice. As received message data, it is decoded and its
+ * signature checked, as every message from a peer is. As the kind,
+ * compression and data of a body, it is signed with a fixed key so it gets
+ * past the signature, then opened through the middleware and decoded, as
+ * the body of a message from a peer would be.
+ */
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use libp2p::identity::Keypair;
+use sec_msg::middleware::{Compressor, Pipeline};
+use sec_msg::protocol::{Body, Envelope};
+
+/// The key bodies are signed with.
+fn key() -> &'static Keypair {
+    static KEY: OnceLock<Keypair> = OnceLock::new();
+    KEY.get_or_init(|| Keypair::ed25519_from_bytes([7; 32]).unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(envelope) = Envelope::decode(data) {
+        let _ = envelope.open("fuzz");
+        let _ = envelope.encode();
+    }
+
+    let [kind, compression, data @ ..] = data else {
+        return;
+    };
+    let body = Body {
+        kind: (*kind).into(),
+        compression: (*compression).into(),
+        data: data.to_vec(),
+    };
+    let encoded = Envelope::sign("fuzz", body, None, key()).unwrap().encode().unwrap();
+    let envelope = Envelope::decode(&encoded).unwrap();
+    let mut pipeline = Pipeline::new();
+    pipeline.register(100, Box::new(Compressor));
+    let _ = pipeline.open("fuzz", &envelope);
+});
@@ -0,0 +1,45 @@
+/*!
+ * Fuzz target for the reassembly of fragmented envelopes.
+ *
+ * The input is read as a run of fragments, each a peer, a set, an index, a
+ * total and the length of its data followed by the data, so fragments of
+ * several peers and sets interleave, repeat and contradict each other as a
+ * hostile peer would send them. Every envelope reassembled is decoded.
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libp2p::identity::Keypair;
+use sec_msg::delivery::MessageId;
+use sec_msg::protocol::{Envelope, Fragment, Reassembler, FRAGMENT_SIZE};
+
+/// Bytes read before the data of a fragment.
+const HEADER_LEN: usize = 7;
+
+fuzz_target!(|data: &[u8]| {
+    let peers = [1, 2].map(|seed| {
+        let key = Keypair::ed25519_from_bytes([seed; 32]).unwrap();
+        key.public().to_peer_id()
+    });
+    let mut reassembler = Reassembler::new();
+    let mut rest = data;
+    while rest.len() >= HEADER_LEN {
+        let (header, tail) = rest.split_at(HEADER_LEN);
+        let length = (header[6] as usize).min(tail.len());
+        let (chunk, tail) = tail.split_at(length);
+        rest = tail;
+        let fragment = Fragment {
+            set_id: MessageId([header[1]; 16]),
+            index: u16::from_le_bytes([header[2], header[3]]),
+            total: u16::from_le_bytes([header[4], header[5]]),
+            data: chunk.to_vec(),
+        };
+        let total = fragment.total as usize;
+        let peer = peers[header[0] as usize % peers.len()];
+        if let Some(encoded) = reassembler.insert(peer, fragment) {
+            assert!(encoded.len() <= total * FRAGMENT_SIZE);
+            let _ = Envelope::decode(&encoded);
+        }
+    }
+});
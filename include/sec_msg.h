@@ -0,0 +1,71 @@
+/*
+ * C interface of sec_msg, built with the `ffi` feature.
+ *
+ * Functions returning int return 0 on success and -1 on failure, and
+ * sec_msg_node_new returns NULL on failure; sec_msg_last_error then tells
+ * why on the calling thread. Strings are UTF-8 and NUL-terminated, and
+ * those passed to callbacks are only valid during the call.
+ */
+
+#ifndef SEC_MSG_H
+#define SEC_MSG_H
+
+#include <stdbool.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A running node. */
+typedef struct SecMsgNode SecMsgNode;
+
+/*
+ * Called with a message received on a subscribed topic: the context given
+ * with the callback, the topic, the base58 peer ID of the signer, the
+ * display name claimed, or NULL, and the body.
+ */
+typedef void (*SecMsgMessageCallback)(void *context, const char *topic, const char *peer,
+                                      const char *name, const char *body);
+
+/*
+ * Called when a peer connects or disconnects: the context given with the
+ * callback, the base58 peer ID and whether it connected.
+ */
+typedef void (*SecMsgPeerCallback)(void *context, const char *peer, bool connected);
+
+/*
+ * Creates a node from its data directory, or the default one when home is
+ * NULL, and starts it. The passphrase is needed when storage is encrypted.
+ */
+SecMsgNode *sec_msg_node_new(const char *home, const char *passphrase);
+
+/*
+ * Sets the function called on the callback thread, replacing the previous
+ * one, or removes it when NULL.
+ */
+int sec_msg_set_message_callback(SecMsgNode *node, SecMsgMessageCallback callback,
+                                 void *context);
+int sec_msg_set_peer_callback(SecMsgNode *node, SecMsgPeerCallback callback, void *context);
+
+/* Joins a topic, whose messages are then passed to the message callback. */
+int sec_msg_subscribe(SecMsgNode *node, const char *topic);
+
+/* Publishes a text message on a topic. */
+int sec_msg_publish(SecMsgNode *node, const char *topic, const char *body);
+
+/*
+ * Stops the node, then frees it. Must not be called from a callback.
+ */
+int sec_msg_shutdown(SecMsgNode *node);
+
+/*
+ * Returns why the last call failed on this thread, or NULL. The string
+ * stays valid until the next failing call on the thread.
+ */
+const char *sec_msg_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SEC_MSG_H */
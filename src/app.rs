@@ -0,0 +1,55 @@
+/*!
+ * Application event module for the messaging application.
+ *
+ * The swarm loop owns the swarm and the application state, and the tasks
+ * around it only talk to it over channels: they send an `AppEvent` to the
+ * loop, which answers the user interface with `UiEvent`s. The terminal UI
+ * and stdin send the lines typed, over a bounded channel of their own so
+ * they wait while messages pile up, the streams of file transfers report
+ * their progress, hangup signals ask for the configuration to be
+ * reloaded, a timer asks for maintenance every few minutes, Ctrl-C and
+ * termination signals ask the node to stop, control clients of the daemon
+ * send their requests, the email gateway passes on replies, content
+ * filters say when they decided on a message and the terminal UI which
+ * messages the user saw, none of them touching the swarm. This keeps the tasks independent of the network, so they can be
+ * replaced or driven by tests.
+ */
+
+use libp2p::PeerId;
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+
+use crate::delivery::MessageId;
+use crate::ipc::{Request, Response};
+use crate::shutdown::Signal;
+use crate::stream::TransferEvent;
+
+/// What the tasks around the swarm loop tell it.
+#[derive(Debug)]
+pub enum AppEvent {
+    /// A line typed by the user, a message or a command, sent by programs
+    /// embedding the node. The terminal UI and stdin send theirs to
+    /// `Node::input` instead.
+    Input(String),
+    /// The user quit, or stdin was closed.
+    InputClosed,
+    /// A streamed file transfer progressed.
+    Transfer(Box<TransferEvent>),
+    /// A hangup signal asked for the configuration to be reloaded.
+    Reload,
+    /// The maintenance interval elapsed.
+    Maintain,
+    /// A signal asked the process to stop.
+    Signal(Signal),
+    /// A control client of the daemon sent a request, to be answered on
+    /// the channel.
+    Ipc(Request, oneshot::Sender<Response>),
+    /// A contact replied to a direct message by email.
+    Email(PeerId, String),
+    /// A content filter decided on the text message it held back.
+    Decided(MessageId),
+    /// The terminal UI showed a message in the focused conversation.
+    Read(MessageId),
+}
+
+/// The channel to the swarm loop.
+pub type AppEvents = UnboundedSender<AppEvent>;
@@ -0,0 +1,182 @@
+/*!
+ * Backup module for the messaging application.
+ *
+ * This module moves an identity to another machine: the keypair, and with
+ * it the peer ID, is exported along with the contact list to a single file
+ * encrypted with a passphrase, which is imported on the new machine before
+ * the first start. Contacts keep their aliases, notes and trust levels, so
+ * no fingerprint has to be compared again.
+ */
+
+use std::{error::Error, path::Path, sync::Arc};
+
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::cli::IdentityCommand;
+use crate::config::Config;
+use crate::contacts::{Contact, Contacts};
+use crate::storage::{self, Vault};
+use crate::utils;
+
+/// What a backup holds.
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    /// The protobuf encoded keypair.
+    #[serde(with = "serde_bytes")]
+    keypair: Vec<u8>,
+    contacts: Vec<Contact>,
+}
+
+/// Writes a backup of an identity.
+///
+/// # Arguments
+///
+/// * `path` - The backup file.
+/// * `local_key` - The identity keypair.
+/// * `contacts` - The contacts of the identity.
+/// * `vault` - The vault the backup is sealed with.
+/// * `force` - Whether an existing file is replaced.
+pub fn export(
+    path: &Path,
+    local_key: &identity::Keypair,
+    contacts: &[&Contact],
+    vault: &Vault,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    if path.exists() && !force {
+        return Err(format!("{:?} already exists", path).into());
+    }
+    let backup = Backup {
+        keypair: local_key.to_protobuf_encoding()?,
+        contacts: contacts.iter().map(|contact| (*contact).clone()).collect(),
+    };
+    let bytes = Zeroizing::new(bincode::serialize(&backup)?);
+    // Wipes the encoded keypair, as the serialized copy will be.
+    drop(Zeroizing::new(backup.keypair));
+    storage::write(path, &bytes, Some(vault))?;
+    Ok(())
+}
+
+/// Reads a backup written by `export`.
+///
+/// # Arguments
+///
+/// * `path` - The backup file.
+/// * `vault` - The vault the backup was sealed with.
+///
+/// # Returns
+///
+/// A `Result` containing the identity keypair and its contacts.
+pub fn import(
+    path: &Path,
+    vault: &Vault,
+) -> Result<(identity::Keypair, Vec<Contact>), Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    if !storage::is_sealed(&data) {
+        return Err(format!("{:?} is not an identity backup", path).into());
+    }
+    let bytes = Zeroizing::new(vault.open(&data)?);
+    let backup: Backup = bincode::deserialize(&bytes)?;
+    let keypair = Zeroizing::new(backup.keypair);
+    let local_key = identity::Keypair::from_protobuf_encoding(&keypair)?;
+    Ok((local_key, backup.contacts))
+}
+
+/// Runs `identity export` or `identity import`, asking for the passphrase
+/// of the backup.
+///
+/// # Arguments
+///
+/// * `command` - What to do.
+/// * `config` - The configuration, naming the identity and data files.
+pub fn run(command: &IdentityCommand, config: &Config) -> Result<(), Box<dyn Error>> {
+    let identity = config
+        .identity
+        .clone()
+        .or_else(|| config.dirs.as_ref().map(|dirs| dirs.identity()))
+        .ok_or("No data directory, set identity in the configuration")?;
+    let vault = match config.encrypt_storage {
+        true => Some(Arc::new(Vault::new(storage::passphrase(
+            "Passphrase: ",
+            false,
+        )?))),
+        false => None,
+    };
+    let contacts = match config.dirs.as_ref().map(|dirs| dirs.contacts()) {
+        Some(path) => Contacts::load(path, vault.clone())?,
+        None => Contacts::new(),
+    };
+    match command {
+        IdentityCommand::Export { path, force } => {
+            if !identity.exists() {
+                return Err(format!("No identity in {:?} to export", identity).into());
+            }
+            let (local_key, peer_id) = utils::load_keypair(&identity, vault.as_deref())?;
+            let backup = Vault::new(storage::passphrase("Backup passphrase: ", true)?);
+            export(path, &local_key, &contacts.list(), &backup, *force)
+                .map_err(|e| format!("{}, pass --force to replace it", e))?;
+            println!("Wrote {}", path.display());
+            print_summary(&peer_id, contacts.list().len());
+        }
+        IdentityCommand::Import { path, force } => {
+            let backup = Vault::new(storage::passphrase("Backup passphrase: ", false)?);
+            let (local_key, imported) =
+                import(path, &backup).map_err(|e| format!("Failed to import {:?}: {}", path, e))?;
+            utils::install_keypair(&local_key, &identity, *force, vault.as_deref())?;
+            let mut contacts = contacts;
+            let count = imported.len();
+            for contact in imported {
+                if let Err(e) = contacts.insert(contact) {
+                    eprintln!("Skipped a contact: {}", e);
+                }
+            }
+            contacts.save()?;
+            println!("Wrote {}", identity.display());
+            print_summary(&local_key.public().to_peer_id(), count);
+        }
+    }
+    Ok(())
+}
+
+fn print_summary(peer_id: &PeerId, contacts: usize) {
+    println!("Peer ID:     {}", peer_id);
+    println!("Fingerprint: {}", crate::security::fingerprint(peer_id));
+    println!("Contacts:    {}", contacts);
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity, PeerId};
+    use zeroize::Zeroizing;
+
+    use super::{export, import};
+    use crate::contacts::{Contacts, Trust};
+    use crate::storage::Vault;
+
+    #[test]
+    fn test_export_and_import() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-backup-{}", std::process::id()));
+        let path = dir.join("identity.backup");
+        let vault = Vault::new(Zeroizing::new("correct horse".to_string()));
+        let local_key = identity::Keypair::generate_ed25519();
+        let mut contacts = Contacts::new();
+        let alice = PeerId::random();
+        contacts.add(alice, "alice").unwrap();
+        contacts.get_mut(&alice).unwrap().trust = Trust::Verified;
+
+        export(&path, &local_key, &contacts.list(), &vault, false).unwrap();
+        assert!(export(&path, &local_key, &contacts.list(), &vault, false).is_err());
+        let (imported, imported_contacts) = import(&path, &vault).unwrap();
+        assert_eq!(imported.public(), local_key.public());
+        assert_eq!(imported_contacts.len(), 1);
+        assert_eq!(imported_contacts[0].trust, Trust::Verified);
+
+        let wrong = Vault::new(Zeroizing::new("battery staple".to_string()));
+        assert!(import(&path, &wrong).is_err());
+        std::fs::write(&path, b"not a backup").unwrap();
+        assert!(import(&path, &vault).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+/*!
+ * Chaos module for the messaging application.
+ *
+ * Tests of resilience run nodes over a hostile network. The chaos transport
+ * wraps the connections of the in-memory transport and, frame by frame
+ * written on them, delays a frame, drops it, lets it overtake the frame
+ * before it or resets the connection, each with a configurable probability.
+ * Connections of libp2p are reliable and encrypted streams, so a frame lost
+ * or out of order breaks the stream and the receiver closes the connection,
+ * as it would a TCP connection beyond repair, while latency only slows
+ * messages down. Nodes then have to find each other again and deliver what
+ * they still hold, as they would on a flaky network.
+ *
+ * The chaos of a transport is changed while its connections run, so a test
+ * lets its nodes settle on a calm network before the storm:
+ *
+ * ```ignore
+ * let net = TestNet::new(2, &["chat"]).await?;
+ * net.chaos().set(Chaos { reset: 0.1, ..Chaos::default() });
+ * ```
+ *
+ * The transport is built for the tests of the crate, and for those of other
+ * crates with the `testing` feature.
+ */
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures::{AsyncRead, AsyncWrite};
+use log::debug;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Most frames a connection holds back before writers have to wait.
+const MAX_QUEUED: usize = 64;
+
+/// How hostile a network is.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    /// Delay added to every frame.
+    pub latency: Duration,
+    /// Longest random delay added to the latency of a frame.
+    pub jitter: Duration,
+    /// Probability of a frame being lost.
+    pub drop: f64,
+    /// Probability of a frame overtaking the frame before it.
+    pub reorder: f64,
+    /// Probability of a connection being reset when a frame is written.
+    pub reset: f64,
+}
+
+impl Chaos {
+    /// Returns whether the network is left alone.
+    pub fn is_calm(&self) -> bool {
+        *self == Chaos::default()
+    }
+
+    /// Returns whether an event of a probability happens.
+    fn roll(probability: f64) -> bool {
+        probability > 0.0 && rand::random::<f64>() < probability
+    }
+}
+
+/// The chaos shared by the connections of a transport.
+#[derive(Debug, Default, Clone)]
+pub struct ChaosControl(Arc<Mutex<Chaos>>);
+
+impl ChaosControl {
+    /// Returns the chaos of the connections.
+    pub fn get(&self) -> Chaos {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Changes the chaos of the connections, open ones included.
+    pub fn set(&self, chaos: Chaos) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = chaos;
+    }
+}
+
+/// A connection whose frames are delayed, lost, reordered or reset.
+pub struct ChaosStream<S> {
+    inner: S,
+    control: ChaosControl,
+    /// Frames written and not sent yet, with when they may be sent.
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    /// How much of the first frame was sent.
+    sent: usize,
+    timer: Pin<Box<Sleep>>,
+    reset: bool,
+}
+
+impl<S> ChaosStream<S> {
+    /// Wraps a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The connection.
+    /// * `control` - The chaos of the connection.
+    pub fn new(inner: S, control: ChaosControl) -> Self {
+        ChaosStream {
+            inner,
+            control,
+            queue: VecDeque::new(),
+            sent: 0,
+            timer: Box::pin(sleep_until(Instant::now())),
+            reset: false,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ChaosStream<S> {
+    /// Sends the frames held back whose time came.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some((at, frame)) = self.queue.front() {
+            if *at > Instant::now() {
+                self.timer.as_mut().reset(*at);
+                ready!(self.timer.as_mut().poll(cx));
+            }
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &frame[self.sent..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += written;
+            if self.sent == frame.len() {
+                self.queue.pop_front();
+                self.sent = 0;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Returns the error of a connection that was reset.
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "Connection reset by chaos")
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ChaosStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ChaosStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        let chaos = self.control.get();
+        if chaos.is_calm() && self.queue.is_empty() {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+        if let Poll::Ready(Err(e)) = self.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if self.queue.len() >= MAX_QUEUED {
+            return Poll::Pending;
+        }
+
+        if Chaos::roll(chaos.reset) {
+            debug!("Chaos resets a connection");
+            self.reset = true;
+            return Poll::Ready(Err(reset_error()));
+        }
+        if Chaos::roll(chaos.drop) {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let jitter = chaos.jitter.mul_f64(rand::random());
+        // Frames are sent in order whatever their jitter, only reordering
+        // letting one overtake another.
+        let at = self
+            .queue
+            .back()
+            .map_or(Instant::now(), |(at, _)| *at)
+            .max(Instant::now() + chaos.latency + jitter);
+        self.queue.push_back((at, buf.to_vec()));
+        // The frame before this one overtaken must not be partly sent.
+        let last = self.queue.len() - 1;
+        if last > 0 && (last > 1 || self.sent == 0) && Chaos::roll(chaos.reorder) {
+            let frame = std::mem::take(&mut self.queue[last].1);
+            self.queue[last].1 = std::mem::replace(&mut self.queue[last - 1].1, frame);
+        }
+        if let Poll::Ready(Err(e)) = self.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        ready!(self.poll_send(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.reset {
+            ready!(self.poll_send(cx))?;
+        }
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::time::{sleep, timeout, Instant};
+
+    use super::Chaos;
+    use crate::testing::TestNet;
+
+    #[tokio::test]
+    async fn test_latency_and_reset() {
+        let net = TestNet::new(2, &["chat"]).await.unwrap();
+        let mut messages = net.client(1).subscribe("chat").await.unwrap();
+
+        let latency = Duration::from_millis(100);
+        net.chaos().set(Chaos {
+            latency,
+            ..Chaos::default()
+        });
+        let start = Instant::now();
+        net.client(0).publish("chat", "slow").await.unwrap();
+        assert_eq!(messages.next().await.unwrap().body, "slow");
+        assert!(start.elapsed() >= latency);
+
+        net.chaos().set(Chaos {
+            reset: 1.0,
+            ..Chaos::default()
+        });
+        let _ = net.client(0).publish("chat", "lost").await;
+        timeout(Duration::from_secs(10), async {
+            while !net.client(1).peers().await.unwrap().is_empty() {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("The connection was not reset");
+
+        // Once calm, the supervisor redials and messages flow again.
+        net.chaos().set(Chaos::default());
+        timeout(Duration::from_secs(20), async {
+            loop {
+                let _ = net.client(0).publish("chat", "back").await;
+                if let Ok(Some(message)) =
+                    timeout(Duration::from_millis(500), messages.next()).await
+                {
+                    if message.body == "back" {
+                        break;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("The nodes did not reconnect");
+        net.shutdown().await;
+    }
+}
@@ -0,0 +1,524 @@
+/*!
+ * Command line module for the messaging application.
+ *
+ * This module declares the options and subcommands of the binary. Running
+ * it without a subcommand is the same as `chat`. Besides chatting, it can
+ * run a headless node that only forwards messages and answers peer
+ * lookups (`relay`), run a headless node driven over a socket (`daemon`,
+ * controlled with `ctl`), create an identity file (`keygen`), write a
+ * starting configuration file (`config init`), play back the events a
+ * node recorded (`replay`), and simulate many peers in one process
+ * (`simulate`). Options given here take
+ * precedence over the environment and the configuration file.
+ */
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use libp2p::Multiaddr;
+
+use crate::render::Output;
+use crate::{feed, mqtt, simulate};
+
+/// Peer-to-peer chat over libp2p.
+#[derive(Debug, Default, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: Options,
+    #[command(subcommand)]
+    pub command: Option<Mode>,
+}
+
+/// Options of the binary, the global ones also accepted after a subcommand.
+#[derive(Debug, Default, Args)]
+pub struct Options {
+    /// Configuration file, which must exist when given.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Directory of a portable install, keeping the configuration file
+    /// and the identity together instead of in the platform directories.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub home: Option<PathBuf>,
+    /// Address to listen on, may be repeated.
+    #[arg(long, global = true, value_name = "MULTIADDR")]
+    pub listen: Vec<Multiaddr>,
+    /// Topic to join at startup, may be repeated; the first is active.
+    #[arg(long, global = true, value_name = "TOPIC")]
+    pub topic: Vec<String>,
+    /// Log filter, such as `debug` or `sec_msg=trace`.
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Publishes the lines read from stdin to a topic, exiting once stdin
+    /// is closed.
+    #[arg(long, value_name = "TOPIC")]
+    pub stdin_pipe: Option<String>,
+    /// What is written to stdout: `text`, or `json` for bots and bridges.
+    #[arg(long, value_name = "FORMAT")]
+    pub output: Option<Output>,
+    /// Records the events received to a file, to be played back with
+    /// `replay`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+}
+
+/// What the binary does.
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum Mode {
+    /// Chats with peers, the default.
+    Chat,
+    /// Runs a headless node that forwards messages and answers peer
+    /// lookups, to bootstrap other peers.
+    Relay,
+    /// Runs a headless node driven by `ctl` over a Unix domain socket.
+    Daemon {
+        /// The socket, `sec_msg.sock` in the data directory by default.
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+        /// Also serves the gRPC control API on this address, which has no
+        /// authentication and should be local.
+        #[arg(long, value_name = "ADDR")]
+        grpc: Option<SocketAddr>,
+        /// Also serves the HTTP API on this address, to clients sending the
+        /// token set in `SEC_MSG_HTTP_TOKEN`.
+        #[arg(long, value_name = "ADDR")]
+        http: Option<SocketAddr>,
+        /// Also runs the identity kept in a directory, as `--home` would,
+        /// addressed by name with `ctl --as`; may be repeated.
+        #[arg(long = "identity", value_name = "NAME=DIR", value_parser = named_home)]
+        identities: Vec<(String, PathBuf)>,
+        /// Mirrors a topic to a Nostr relay and publishes the replies on
+        /// it; may be repeated.
+        #[arg(long = "nostr", value_name = "TOPIC=RELAY", value_parser = nostr_mirror)]
+        mirrors: Vec<(String, String)>,
+        /// Bridges topics to the MQTT broker at this URL, which may hold a
+        /// user name and password.
+        #[arg(long, value_name = "URL", value_parser = mqtt_broker)]
+        mqtt: Option<String>,
+        /// Bridges a topic to an MQTT topic, or from an MQTT filter with
+        /// wildcards; may be repeated.
+        #[arg(
+            long = "mqtt-topic",
+            value_name = "TOPIC=MQTT_TOPIC",
+            value_parser = mqtt_route,
+            requires = "mqtt"
+        )]
+        mqtt_routes: Vec<(String, String)>,
+        /// The quality of service of the MQTT bridge, from 0 to 2.
+        #[arg(
+            long = "mqtt-qos",
+            value_name = "QOS",
+            default_value_t = mqtt::DEFAULT_QOS,
+            value_parser = clap::value_parser!(u8).range(0..=2)
+        )]
+        mqtt_qos: u8,
+        /// Publishes the new entries of an RSS or Atom feed on a topic; may
+        /// be repeated.
+        #[arg(long = "feed", value_name = "TOPIC=URL", value_parser = feed_source)]
+        feeds: Vec<(String, String)>,
+        /// How often the feeds are polled, in seconds.
+        #[arg(
+            long = "feed-interval",
+            value_name = "SECS",
+            default_value_t = feed::DEFAULT_INTERVAL,
+            value_parser = clap::value_parser!(u64).range(10..),
+            requires = "feeds"
+        )]
+        feed_interval: u64,
+        /// Posts the messages of a topic to a webhook, signed with the
+        /// secret set in `SEC_MSG_WEBHOOK_SECRET`; may be repeated.
+        #[arg(long = "webhook", value_name = "TOPIC=URL", value_parser = webhook_target)]
+        webhooks: Vec<(String, String)>,
+    },
+    /// Sends a request to a node run with `daemon`.
+    Ctl {
+        /// The socket of the daemon, `sec_msg.sock` in the data directory
+        /// by default.
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+        /// The identity of the daemon asked, the default one if not given.
+        #[arg(long = "as", value_name = "NAME")]
+        identity: Option<String>,
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Creates an identity file and prints its peer ID.
+    Keygen {
+        /// The file to create, the configured identity file by default.
+        path: Option<PathBuf>,
+        /// Replaces an existing identity file.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Manages the configuration file.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Moves the identity and contacts to another machine.
+    #[command(subcommand)]
+    Identity(IdentityCommand),
+    /// Plays back the events recorded with `--record` on an offline node.
+    Replay {
+        /// The recording.
+        path: PathBuf,
+        /// Plays the events back without waiting for the time they came at.
+        #[arg(long)]
+        fast: bool,
+    },
+    /// Runs virtual peers in this process publishing on the first topic,
+    /// and reports the latency and loss of their messages.
+    Simulate {
+        /// The number of peers.
+        #[arg(
+            long,
+            default_value_t = simulate::DEFAULT_PEERS,
+            value_parser = clap::value_parser!(u16).range(2..=1000)
+        )]
+        peers: u16,
+        /// The number of peers each peer connects to when it starts.
+        #[arg(
+            long,
+            default_value_t = simulate::DEFAULT_LINKS,
+            value_parser = clap::value_parser!(u16).range(1..)
+        )]
+        links: u16,
+        /// The messages published per second, by random peers.
+        #[arg(
+            long,
+            default_value_t = simulate::DEFAULT_RATE,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        rate: u32,
+        /// How long messages are published, in seconds.
+        #[arg(
+            long,
+            value_name = "SECS",
+            default_value_t = simulate::DEFAULT_DURATION,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        duration: u64,
+    },
+}
+
+/// What is done with the configuration file.
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum ConfigCommand {
+    /// Writes a configuration file listing every setting.
+    Init {
+        /// Replaces an existing configuration file.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// What is asked of a daemon.
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum CtlCommand {
+    /// Joins a topic and prints its messages as they arrive.
+    Subscribe { topic: String },
+    /// Publishes a message on a topic.
+    Publish {
+        topic: String,
+        #[arg(required = true)]
+        message: Vec<String>,
+    },
+    /// Lists the connected peers.
+    Peers,
+    /// Dials a peer at a multiaddress.
+    Connect { address: String },
+    /// Prints the peer ID, addresses, topics and peer count of the node.
+    Status,
+}
+
+/// What is done with the identity.
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum IdentityCommand {
+    /// Writes the identity and contacts to a file encrypted with a
+    /// passphrase.
+    Export {
+        /// The file to write.
+        path: PathBuf,
+        /// Replaces an existing file.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Reads the identity and contacts from a file written by `export`.
+    Import {
+        /// The file to read.
+        path: PathBuf,
+        /// Replaces an existing identity file.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Parses an identity run by a daemon, given as `NAME=DIR`.
+fn named_home(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, dir)) if !name.trim().is_empty() && !dir.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(dir)))
+        }
+        _ => Err("expected a name and a directory, such as bot=/srv/bot".to_string()),
+    }
+}
+
+/// Parses a topic mirrored by a daemon, given as `TOPIC=RELAY`.
+fn nostr_mirror(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((topic, relay))
+            if !topic.trim().is_empty()
+                && (relay.starts_with("wss://") || relay.starts_with("ws://")) =>
+        {
+            Ok((topic.to_string(), relay.to_string()))
+        }
+        _ => Err("expected a topic and a relay URL, such as chat=wss://relay.example".to_string()),
+    }
+}
+
+/// Parses the URL of an MQTT broker.
+fn mqtt_broker(value: &str) -> Result<String, String> {
+    match value.starts_with("mqtt://") || value.starts_with("mqtts://") {
+        true => Ok(value.to_string()),
+        false => Err("expected a broker URL, such as mqtt://localhost:1883".to_string()),
+    }
+}
+
+/// Parses a topic bridged to MQTT, given as `TOPIC=MQTT_TOPIC`.
+fn mqtt_route(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((topic, mqtt_topic)) if !topic.trim().is_empty() && !mqtt_topic.is_empty() => {
+            Ok((topic.to_string(), mqtt_topic.to_string()))
+        }
+        _ => Err("expected a topic and an MQTT topic, such as lights=home/lights".to_string()),
+    }
+}
+
+/// Parses a feed watched by a daemon, given as `TOPIC=URL`.
+fn feed_source(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((topic, url))
+            if !topic.trim().is_empty()
+                && (url.starts_with("https://") || url.starts_with("http://")) =>
+        {
+            Ok((topic.to_string(), url.to_string()))
+        }
+        _ => Err(
+            "expected a topic and a feed URL, such as news=https://example.com/feed".to_string(),
+        ),
+    }
+}
+
+/// Parses a topic posted to a webhook, given as `TOPIC=URL`.
+fn webhook_target(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((topic, url))
+            if !topic.trim().is_empty()
+                && (url.starts_with("https://") || url.starts_with("http://")) =>
+        {
+            Ok((topic.to_string(), url.to_string()))
+        }
+        _ => Err(
+            "expected a topic and a webhook URL, such as ci=https://example.com/hook".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use super::{Cli, ConfigCommand, CtlCommand, IdentityCommand, Mode};
+    use crate::render::Output;
+
+    #[test]
+    fn test_parse_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from(["sec_msg"]);
+        assert_eq!(cli.command, None);
+        assert!(cli.options.topic.is_empty());
+
+        let cli = Cli::parse_from([
+            "sec_msg",
+            "--output=json",
+            "relay",
+            "--listen",
+            "/ip4/0.0.0.0/tcp/4001",
+            "--topic",
+            "rust",
+            "--topic",
+            "chat",
+        ]);
+        assert_eq!(cli.command, Some(Mode::Relay));
+        assert_eq!(cli.options.topic, vec!["rust", "chat"]);
+        assert_eq!(cli.options.listen.len(), 1);
+        assert_eq!(cli.options.output, Some(Output::Json));
+
+        let cli = Cli::parse_from(["sec_msg", "config", "init", "--config", "/tmp/c.toml"]);
+        assert_eq!(
+            cli.command,
+            Some(Mode::Config(ConfigCommand::Init { force: false }))
+        );
+        assert_eq!(cli.options.config, Some("/tmp/c.toml".into()));
+
+        let cli = Cli::parse_from(["sec_msg", "identity", "import", "backup.key"]);
+        assert_eq!(
+            cli.command,
+            Some(Mode::Identity(IdentityCommand::Import {
+                path: "backup.key".into(),
+                force: false
+            }))
+        );
+
+        let cli = Cli::parse_from(["sec_msg", "ctl", "publish", "chat", "hello", "world"]);
+        assert_eq!(
+            cli.command,
+            Some(Mode::Ctl {
+                socket: None,
+                identity: None,
+                command: CtlCommand::Publish {
+                    topic: "chat".to_string(),
+                    message: vec!["hello".to_string(), "world".to_string()],
+                }
+            })
+        );
+        assert!(Cli::try_parse_from(["sec_msg", "ctl", "publish", "chat"]).is_err());
+
+        let cli = Cli::parse_from(["sec_msg", "daemon", "--grpc", "127.0.0.1:50051"]);
+        assert_eq!(
+            cli.command,
+            Some(Mode::Daemon {
+                socket: None,
+                grpc: Some("127.0.0.1:50051".parse().unwrap()),
+                http: None,
+                identities: Vec::new(),
+                mirrors: Vec::new(),
+                mqtt: None,
+                mqtt_routes: Vec::new(),
+                mqtt_qos: 1,
+                feeds: Vec::new(),
+                feed_interval: 300,
+                webhooks: Vec::new(),
+            })
+        );
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--grpc", "localhost"]).is_err());
+
+        let cli = Cli::parse_from(["sec_msg", "daemon", "--identity", "bot=/srv/bot"]);
+        let Some(Mode::Daemon { identities, .. }) = cli.command else {
+            panic!("expected the daemon");
+        };
+        assert_eq!(identities, vec![("bot".to_string(), "/srv/bot".into())]);
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--identity", "/srv/bot"]).is_err());
+        let cli = Cli::parse_from(["sec_msg", "daemon", "--nostr", "chat=wss://relay.example"]);
+        let Some(Mode::Daemon { mirrors, .. }) = cli.command else {
+            panic!("expected the daemon");
+        };
+        assert_eq!(
+            mirrors,
+            vec![("chat".to_string(), "wss://relay.example".to_string())]
+        );
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--nostr", "chat=https://x"]).is_err());
+        let cli = Cli::parse_from([
+            "sec_msg",
+            "daemon",
+            "--mqtt",
+            "mqtt://localhost",
+            "--mqtt-topic",
+            "lights=home/lights",
+            "--mqtt-qos",
+            "2",
+        ]);
+        let Some(Mode::Daemon {
+            mqtt_routes,
+            mqtt_qos,
+            ..
+        }) = cli.command
+        else {
+            panic!("expected the daemon");
+        };
+        assert_eq!(
+            mqtt_routes,
+            vec![("lights".to_string(), "home/lights".to_string())]
+        );
+        assert_eq!(mqtt_qos, 2);
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--mqtt-topic", "a=b"]).is_err());
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--mqtt", "http://x"]).is_err());
+        assert!(Cli::try_parse_from([
+            "sec_msg",
+            "daemon",
+            "--mqtt",
+            "mqtt://x",
+            "--mqtt-qos",
+            "3"
+        ])
+        .is_err());
+        let cli = Cli::parse_from([
+            "sec_msg",
+            "daemon",
+            "--feed",
+            "news=https://example.com/feed",
+            "--feed-interval",
+            "60",
+        ]);
+        let Some(Mode::Daemon {
+            feeds,
+            feed_interval,
+            ..
+        }) = cli.command
+        else {
+            panic!("expected the daemon");
+        };
+        assert_eq!(
+            feeds,
+            vec![("news".to_string(), "https://example.com/feed".to_string())]
+        );
+        assert_eq!(feed_interval, 60);
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--feed", "news=ftp://x"]).is_err());
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--feed-interval", "60"]).is_err());
+        let cli = Cli::parse_from([
+            "sec_msg",
+            "daemon",
+            "--webhook",
+            "ci=https://example.com/hook",
+        ]);
+        let Some(Mode::Daemon { webhooks, .. }) = cli.command else {
+            panic!("expected the daemon");
+        };
+        assert_eq!(
+            webhooks,
+            vec![("ci".to_string(), "https://example.com/hook".to_string())]
+        );
+        assert!(Cli::try_parse_from(["sec_msg", "daemon", "--webhook", "https://x"]).is_err());
+        let cli = Cli::parse_from(["sec_msg", "relay", "--record", "events.rec"]);
+        assert_eq!(cli.options.record, Some("events.rec".into()));
+        let cli = Cli::parse_from(["sec_msg", "replay", "events.rec", "--fast"]);
+        assert_eq!(
+            cli.command,
+            Some(Mode::Replay {
+                path: "events.rec".into(),
+                fast: true,
+            })
+        );
+        assert!(Cli::try_parse_from(["sec_msg", "replay"]).is_err());
+        let cli = Cli::parse_from(["sec_msg", "simulate", "--peers", "50", "--rate", "10"]);
+        assert_eq!(
+            cli.command,
+            Some(Mode::Simulate {
+                peers: 50,
+                links: 8,
+                rate: 10,
+                duration: 30,
+            })
+        );
+        assert!(Cli::try_parse_from(["sec_msg", "simulate", "--peers", "1"]).is_err());
+        assert!(Cli::try_parse_from(["sec_msg", "simulate", "--rate", "0"]).is_err());
+        let cli = Cli::parse_from(["sec_msg", "ctl", "--as", "bot", "peers"]);
+        assert!(matches!(
+            cli.command,
+            Some(Mode::Ctl { identity: Some(name), .. }) if name == "bot"
+        ));
+
+        assert!(Cli::try_parse_from(["sec_msg", "--listen", "nowhere"]).is_err());
+        assert!(Cli::try_parse_from(["sec_msg", "--output", "xml"]).is_err());
+        assert!(Cli::try_parse_from(["sec_msg", "serve"]).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+/*!
+ * Client module for the messaging application.
+ *
+ * A `Client` is a handle to a running `Node` for programs embedding the
+ * crate, such as bots: it publishes, subscribes to the messages of a topic
+ * as a stream, dials peers and lists them, without touching the swarm.
+ * Handles are cheap to clone and can be moved to other tasks, and every
+ * call is a request of the control protocol of the `ipc` module, handed to
+ * the swarm loop over its event channel like those of the daemon's
+ * clients.
+ *
+ * A process may run the nodes of several identities, say a personal one and
+ * a bot, each with its own swarm, topics and peers. `Clients` holds their
+ * handles by name, so the daemon and embedding programs address each
+ * identity on its own.
+ */
+
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use futures::{stream, Stream, StreamExt};
+use libp2p::{Multiaddr, PeerId};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::app::AppEvents;
+use crate::ipc::{ask, subscribe, unsubscribe, PeerInfo, Request, Response, Subscribers};
+
+/// Errors returned by a `Client`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The node stopped, or is shutting down.
+    Stopped,
+    /// Messages are text, and the body was not UTF-8.
+    NotText,
+    /// The node refused the request.
+    Refused(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Stopped => write!(f, "the node stopped"),
+            ClientError::NotText => write!(f, "the message is not UTF-8 text"),
+            ClientError::Refused(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+/// A message received on a subscribed topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub id: String,
+    /// The signer of the message.
+    pub peer: PeerId,
+    /// The display name claimed with the message, if any.
+    pub name: Option<String>,
+    pub body: String,
+}
+
+/// A handle to a running node.
+#[derive(Clone)]
+pub struct Client {
+    events: AppEvents,
+    subscribers: Subscribers,
+}
+
+impl Client {
+    /// Creates a handle, as `Node::client` does.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The channel to the swarm loop.
+    /// * `subscribers` - The subscribers the node forwards messages to.
+    pub fn new(events: AppEvents, subscribers: Subscribers) -> Self {
+        Client {
+            events,
+            subscribers,
+        }
+    }
+
+    /// Hands a request to the node as it is, for the servers of the daemon.
+    ///
+    /// # Returns
+    ///
+    /// The response, or `None` if the node is shutting down.
+    pub async fn ask(&self, request: Request) -> Option<Response> {
+        ask(&self.events, request).await
+    }
+
+    /// Forwards the messages of a subscribed topic to a server's client.
+    pub fn forward(&self, topic: String, client: &UnboundedSender<Response>) {
+        subscribe(&self.subscribers, topic, client);
+    }
+
+    /// Stops forwarding messages to a server's client that went away.
+    pub fn forget(&self, client: &UnboundedSender<Response>) {
+        unsubscribe(&self.subscribers, client);
+    }
+
+    /// Hands a request to the node, turning errors into `ClientError`s.
+    async fn request(&self, request: Request) -> Result<Response, ClientError> {
+        match self.ask(request).await {
+            Some(Response::Error { message }) => Err(ClientError::Refused(message)),
+            Some(response) => Ok(response),
+            None => Err(ClientError::Stopped),
+        }
+    }
+
+    /// Publishes a text message on a topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic, joined already or not.
+    /// * `body` - The message, which must be UTF-8.
+    pub async fn publish(&self, topic: &str, body: impl Into<Vec<u8>>) -> Result<(), ClientError> {
+        let body = String::from_utf8(body.into()).map_err(|_| ClientError::NotText)?;
+        self.request(Request::Publish {
+            topic: topic.to_string(),
+            body,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Publishes bytes that are not text on a topic, which peers never show
+    /// as chat.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic, joined already or not.
+    /// * `media_type` - The media type of the data, such as `image/png`.
+    /// * `data` - The data.
+    pub async fn publish_binary(
+        &self,
+        topic: &str,
+        media_type: &str,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        self.request(Request::PublishBinary {
+            topic: topic.to_string(),
+            media_type: media_type.to_string(),
+            data: data.into(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Joins a topic, if needed, and follows its text messages.
+    ///
+    /// # Returns
+    ///
+    /// A stream of the messages received on the topic from then on, which
+    /// ends when the node stops.
+    pub async fn subscribe(
+        &self,
+        topic: &str,
+    ) -> Result<impl Stream<Item = Message> + Send + Unpin, ClientError> {
+        self.request(Request::Subscribe {
+            topic: topic.to_string(),
+        })
+        .await?;
+        // The node forgets the channel once the stream is dropped.
+        let (client, mut messages) = mpsc::unbounded_channel();
+        subscribe(&self.subscribers, topic.to_string(), &client);
+        let stream = stream::poll_fn(move |cx| messages.poll_recv(cx)).filter_map(|response| {
+            futures::future::ready(match response {
+                Response::Message {
+                    topic,
+                    id,
+                    peer,
+                    name,
+                    body,
+                } => peer.parse().ok().map(|peer| Message {
+                    topic,
+                    id,
+                    peer,
+                    name,
+                    body,
+                }),
+                _ => None,
+            })
+        });
+        Ok(Box::pin(stream))
+    }
+
+    /// Dials a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the peer.
+    pub async fn dial(&self, addr: &Multiaddr) -> Result<(), ClientError> {
+        self.request(Request::Connect {
+            address: addr.to_string(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Lists the connected peers.
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, ClientError> {
+        match self.request(Request::Peers).await? {
+            Response::Peers { peers } => Ok(peers),
+            response => Err(ClientError::Refused(format!(
+                "unexpected response: {:?}",
+                response
+            ))),
+        }
+    }
+}
+
+/// Handles to the nodes of the identities run by a process: the default
+/// one, and the others by name.
+#[derive(Clone)]
+pub struct Clients {
+    default: Client,
+    named: BTreeMap<String, Client>,
+}
+
+impl Clients {
+    /// Creates the handles, with only the default identity.
+    pub fn new(default: Client) -> Self {
+        Clients {
+            default,
+            named: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the handle of an identity.
+    ///
+    /// # Returns
+    ///
+    /// Whether the name was free.
+    pub fn insert(&mut self, name: &str, client: Client) -> bool {
+        if self.named.contains_key(name) {
+            return false;
+        }
+        self.named.insert(name.to_string(), client);
+        true
+    }
+
+    /// Returns the handle of an identity, the default one without a name.
+    pub fn get(&self, name: Option<&str>) -> Option<&Client> {
+        match name {
+            Some(name) => self.named.get(name),
+            None => Some(&self.default),
+        }
+    }
+
+    /// Returns the names of the identities besides the default one.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.named.keys().map(String::as_str)
+    }
+
+    /// Returns the handles of every identity.
+    pub fn iter(&self) -> impl Iterator<Item = &Client> {
+        std::iter::once(&self.default).chain(self.named.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use libp2p::PeerId;
+    use tokio::sync::mpsc;
+
+    use super::{Client, ClientError};
+    use crate::app::AppEvent;
+    use crate::ipc::{Request, Response, Subscribers};
+
+    #[tokio::test]
+    async fn test_client() {
+        let (events, mut requests) = mpsc::unbounded_channel();
+        let subscribers = Subscribers::default();
+        let client = Client::new(events, subscribers.clone());
+        // Stands in for the swarm loop.
+        let node = tokio::spawn(async move {
+            while let Some(AppEvent::Ipc(request, reply)) = requests.recv().await {
+                let response = match request {
+                    Request::Publish { body, .. } if body.is_empty() => Response::Error {
+                        message: "Empty message".to_string(),
+                    },
+                    _ => Response::Ok,
+                };
+                let _ = reply.send(response);
+            }
+        });
+
+        client.publish("chat", "hi").await.unwrap();
+        assert_eq!(
+            client.publish("chat", vec![0xff]).await,
+            Err(ClientError::NotText)
+        );
+        assert_eq!(
+            client.publish("chat", "").await,
+            Err(ClientError::Refused("Empty message".to_string()))
+        );
+
+        let mut messages = client.subscribe("chat").await.unwrap();
+        let peer = PeerId::random();
+        for sender in subscribers.lock().unwrap().get("chat").unwrap() {
+            sender
+                .send(Response::Message {
+                    topic: "chat".to_string(),
+                    id: "1".to_string(),
+                    peer: peer.to_base58(),
+                    name: None,
+                    body: "hello".to_string(),
+                })
+                .unwrap();
+        }
+        let message = messages.next().await.unwrap();
+        assert_eq!((message.peer, message.body.as_str()), (peer, "hello"));
+
+        drop(client);
+        node.await.unwrap();
+    }
+}
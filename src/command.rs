@@ -0,0 +1,1929 @@
+/*!
+ * Command module for the messaging application.
+ *
+ * This module provides the registry of slash commands. Each command is
+ * described once by its name, its arguments, a help text and its handler;
+ * the dispatcher, usage errors and `/help` are all derived from the
+ * registry.
+ */
+
+use std::{error::Error, fmt, path::Path};
+
+use chrono::TimeZone;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use log::{error, info, warn};
+
+use crate::backup;
+use crate::contacts::Trust;
+use crate::delivery::MessageId;
+use crate::discovery::directory_key;
+use crate::emoji;
+use crate::event::{
+    advertise_topics, handle_reaction, is_hidden, publish_profile, request_history,
+    save_moderation, show_text,
+};
+use crate::export::{self, ExportFormat};
+use crate::history::HISTORY_LIMIT;
+use crate::invite::InviteToken;
+use crate::moderation::{self, Action, ModerationAction};
+use crate::note::{note_topic, Note};
+use crate::presence::PresenceStatus;
+use crate::profile::{Profile, MAX_NAME_LEN};
+use crate::protocol::{inbox_topic, Payload, Protocols, TextMessage};
+use crate::reaction::{Reaction, MAX_REACTION_LEN};
+use crate::reload;
+use crate::render::Clock;
+use crate::reputation::Reputation;
+use crate::security::{fingerprint, sanitize, MAX_RENDERED_LEN, MAX_RENDERED_NAME_LEN};
+use crate::state::AppState;
+use crate::stats::{format_bytes, format_duration, Stats};
+use crate::stream::STREAM_PROTOCOL;
+use crate::topic::PubsubProtocol;
+use crate::transfer::FileRequest;
+use crate::ui::publish_payload;
+use crate::version::Compatibility;
+
+/// Maximum number of messages listed by `/search`.
+const SEARCH_RESULTS: usize = 50;
+
+/// Errors produced while running a command.
+#[derive(Debug)]
+pub enum CommandError {
+    /// No command has this name.
+    Unknown(String),
+    /// The arguments do not match the usage of the command.
+    Usage(&'static Command),
+    /// The command could not be carried out.
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => {
+                write!(f, "Unknown command {}, see /help", name)
+            }
+            CommandError::Usage(command) => write!(f, "Usage: {}", command.usage()),
+            CommandError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// Outcome of a command handler.
+///
+/// Handlers report mismatching arguments as `Err(None)`, leaving the usage
+/// message to the dispatcher.
+type CommandResult = Result<(), Option<String>>;
+
+/// Runs a command with the arguments following its name.
+type Handler = fn(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult;
+
+/// What an argument completes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+    /// A command name.
+    Command,
+    /// A peer ID, also found by display name.
+    Peer,
+    /// A subscribed topic.
+    Topic,
+    /// Free text, where display names complete.
+    Text,
+}
+
+/// A slash command.
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments, as shown in the usage.
+    pub args: &'static str,
+    pub help: &'static str,
+    /// What each argument completes to.
+    pub completes: &'static [Arg],
+    handler: Handler,
+}
+
+impl Command {
+    /// Returns the usage of the command.
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+}
+
+/// The registered commands, in the order `/help` lists them.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "/help",
+        args: "[command]",
+        help: "Lists the commands or describes one",
+        completes: &[Arg::Command],
+        handler: help,
+    },
+    Command {
+        name: "/quit",
+        args: "",
+        help: "Leaves the topics, sends what is pending and exits",
+        completes: &[],
+        handler: quit,
+    },
+    Command {
+        name: "/connect",
+        args: "<multiaddress>",
+        help: "Dials a peer",
+        completes: &[],
+        handler: connect,
+    },
+    Command {
+        name: "/join",
+        args: "<topic> [--protocol floodsub|gossipsub|both]",
+        help: "Subscribes to a topic",
+        completes: &[Arg::Topic],
+        handler: join,
+    },
+    Command {
+        name: "/leave",
+        args: "[topic]",
+        help: "Unsubscribes from a topic, the active one by default",
+        completes: &[Arg::Topic],
+        handler: leave,
+    },
+    Command {
+        name: "/topic",
+        args: "[topic]",
+        help: "Shows the topic plain input is published to, or switches it",
+        completes: &[Arg::Topic],
+        handler: switch_topic,
+    },
+    Command {
+        name: "/msg",
+        args: "<peer id> <message>",
+        help: "Sends a direct message",
+        completes: &[Arg::Peer, Arg::Text],
+        handler: direct_message,
+    },
+    Command {
+        name: "/send-file",
+        args: "<peer id> <path>",
+        help: "Offers a file to a peer",
+        completes: &[Arg::Peer, Arg::Text],
+        handler: send_file,
+    },
+    Command {
+        name: "/accept-file",
+        args: "<transfer id>",
+        help: "Accepts a file offer",
+        completes: &[],
+        handler: accept_file,
+    },
+    Command {
+        name: "/op",
+        args: "<peer id>",
+        help: "Grants moderator rights on the active topic, or nominates a moderator",
+        completes: &[Arg::Peer],
+        handler: op,
+    },
+    Command {
+        name: "/handover",
+        args: "<peer id>",
+        help: "Hands the founder role of the active topic over to a peer",
+        completes: &[Arg::Peer],
+        handler: handover,
+    },
+    Command {
+        name: "/mute",
+        args: "<peer id> [minutes]",
+        help: "Mutes a peer on the active topic",
+        completes: &[Arg::Peer],
+        handler: mute,
+    },
+    Command {
+        name: "/kick",
+        args: "<peer id>",
+        help: "Kicks a peer from the active topic",
+        completes: &[Arg::Peer],
+        handler: kick,
+    },
+    Command {
+        name: "/bans",
+        args: "[lift <peer> [topic]]",
+        help: "Lists muted, kicked and banned peers, or lifts a mute",
+        completes: &[Arg::Text, Arg::Peer, Arg::Topic],
+        handler: bans,
+    },
+    Command {
+        name: "/ignore",
+        args: "<peer>",
+        help: "Hides the messages of a peer, on every topic and by email, for you only",
+        completes: &[Arg::Peer],
+        handler: ignore,
+    },
+    Command {
+        name: "/ignored",
+        args: "[lift <peer>]",
+        help: "Lists ignored peers, or shows the messages of one again",
+        completes: &[Arg::Text, Arg::Peer],
+        handler: ignored,
+    },
+    Command {
+        name: "/quarantine",
+        args: "[release <n> | drop <n> | clear]",
+        help: "Lists the messages held back by content filters, or shows or discards them",
+        completes: &[Arg::Text],
+        handler: quarantine,
+    },
+    Command {
+        name: "/group",
+        args: "[create | add <peer> | remove <peer>]",
+        help: "Shows the roster of the active topic, makes it a private group, or edits its members",
+        completes: &[Arg::Text, Arg::Peer],
+        handler: group,
+    },
+    Command {
+        name: "/invite",
+        args: "create <peer> [minutes] | use <token>",
+        help: "Issues an invite to the active topic for a peer, or joins a private topic with one",
+        completes: &[Arg::Text, Arg::Peer],
+        handler: invite,
+    },
+    Command {
+        name: "/note",
+        args: "<name> [append <text> | insert <index> <text> | delete <index> <count>]",
+        help: "Shows or edits a shared note",
+        completes: &[],
+        handler: note,
+    },
+    Command {
+        name: "/receipts",
+        args: "<on|off> [peer id]",
+        help: "Enables or disables read receipts",
+        completes: &[Arg::Text, Arg::Peer],
+        handler: receipts,
+    },
+    Command {
+        name: "/topics",
+        args: "[advertise <topic> | withdraw <topic> | discover | found]",
+        help: "Lists, advertises or discovers topics",
+        completes: &[Arg::Text, Arg::Topic],
+        handler: topics,
+    },
+    Command {
+        name: "/react",
+        args: "<message id> <emoji>",
+        help: "Reacts to a message",
+        completes: &[],
+        handler: react,
+    },
+    Command {
+        name: "/unreact",
+        args: "<message id> <emoji>",
+        help: "Removes a reaction",
+        completes: &[],
+        handler: unreact,
+    },
+    Command {
+        name: "/peers",
+        args: "",
+        help: "Lists the connected peers and the peers around",
+        completes: &[],
+        handler: peers,
+    },
+    Command {
+        name: "/contact",
+        args: "add <peer> <alias> | remove <contact> | note <contact> <text> | trust <contact> <untrusted|unverified|verified> | list",
+        help: "Keeps peers you know under an alias, with notes and trust",
+        completes: &[Arg::Text, Arg::Peer],
+        handler: contact,
+    },
+    Command {
+        name: "/identity",
+        args: "export <file>",
+        help: "Writes your identity and contacts to a file for another machine",
+        completes: &[],
+        handler: identity,
+    },
+    Command {
+        name: "/whois",
+        args: "<peer id|name>",
+        help: "Shows everything known about a peer",
+        completes: &[Arg::Peer],
+        handler: whois,
+    },
+    Command {
+        name: "/nick",
+        args: "<name>",
+        help: "Sets your display name",
+        completes: &[],
+        handler: nick,
+    },
+    Command {
+        name: "/notify",
+        args: "<on|off> | dnd <on|off> | highlights <on|off> | mute <topic> | unmute <topic>",
+        help: "Configures desktop notifications for direct messages, mentions and keywords",
+        completes: &[Arg::Text, Arg::Topic],
+        handler: notify,
+    },
+    Command {
+        name: "/watch",
+        args: "[keyword]",
+        help: "Highlights messages containing a keyword, or lists the keywords",
+        completes: &[],
+        handler: watch,
+    },
+    Command {
+        name: "/unwatch",
+        args: "<keyword>",
+        help: "Stops highlighting a keyword",
+        completes: &[],
+        handler: unwatch,
+    },
+    Command {
+        name: "/format",
+        args: "<12h|24h> | suffix <on|off>",
+        help: "Sets how message times and senders are shown",
+        completes: &[],
+        handler: format,
+    },
+    Command {
+        name: "/search",
+        args: "<pattern>",
+        help: "Finds earlier messages containing a pattern",
+        completes: &[],
+        handler: search,
+    },
+    Command {
+        name: "/export",
+        args: "<topic> [--format json|markdown|txt] [--since date]",
+        help: "Writes the kept messages of a topic to a file",
+        completes: &[Arg::Topic],
+        handler: export,
+    },
+    Command {
+        name: "/status",
+        args: "<online|away>",
+        help: "Sets your presence status",
+        completes: &[],
+        handler: status,
+    },
+    Command {
+        name: "/stats",
+        args: "",
+        help: "Shows uptime, message counts and traffic",
+        completes: &[],
+        handler: stats,
+    },
+    Command {
+        name: "/reload",
+        args: "",
+        help: "Re-reads the configuration, as SIGHUP does",
+        completes: &[],
+        handler: reload_config,
+    },
+];
+
+/// Looks up a registered command.
+///
+/// # Arguments
+///
+/// * `name` - The command name, including the leading slash.
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Runs a command line.
+///
+/// # Arguments
+///
+/// * `line` - The command line, starting with the command name.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn run(
+    line: &str,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) -> Result<(), CommandError> {
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let command = find(name).ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+    (command.handler)(args.trim(), swarm, state).map_err(|reason| match reason {
+        Some(reason) => CommandError::Failed(reason),
+        None => CommandError::Usage(command),
+    })
+}
+
+/// The peers and topics arguments complete to.
+#[derive(Debug, Clone, Default)]
+pub struct Completions {
+    /// Known peers and their display names.
+    pub peers: Vec<(PeerId, Option<String>)>,
+    pub topics: Vec<String>,
+}
+
+impl Completions {
+    /// Collects the connected and present peers and the subscribed topics.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The application state.
+    pub fn new(state: &AppState) -> Self {
+        let mut peers: Vec<PeerId> = state
+            .peers
+            .peers()
+            .into_iter()
+            .map(|(peer, _)| *peer)
+            .chain(state.presence.peers().into_iter().map(|(peer, _)| *peer))
+            .collect();
+        peers.sort();
+        peers.dedup();
+        Completions {
+            peers: peers
+                .into_iter()
+                .map(|peer| (peer, state.profiles.name(&peer).map(str::to_string)))
+                .collect(),
+            topics: state
+                .topics
+                .subscribed()
+                .map(|(topic, _)| topic.to_string())
+                .collect(),
+        }
+    }
+
+    /// Completes the last word of an input line.
+    ///
+    /// Peers are completed to their peer ID, even when found by display
+    /// name, since commands take peer IDs.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The input line.
+    ///
+    /// # Returns
+    ///
+    /// The byte offset of the last word and the words it may complete to.
+    pub fn complete(&self, line: &str) -> (usize, Vec<String>) {
+        let word = line.rsplit(char::is_whitespace).next().unwrap_or_default();
+        let start = line.len() - word.len();
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+        let arg = match before.split_first() {
+            None if word.starts_with('/') => Arg::Command,
+            Some((name, args)) if name.starts_with('/') => find(name)
+                .and_then(|command| command.completes.get(args.len()).copied())
+                .unwrap_or(Arg::Text),
+            _ => Arg::Text,
+        };
+        let mut candidates: Vec<String> = match arg {
+            Arg::Command => COMMANDS
+                .iter()
+                .map(|command| command.name.to_string())
+                .filter(|name| name.starts_with(word))
+                .collect(),
+            Arg::Peer => self
+                .peers
+                .iter()
+                .filter(|(peer, name)| {
+                    peer.to_string().starts_with(word)
+                        || name.as_ref().is_some_and(|name| name.starts_with(word))
+                })
+                .map(|(peer, _)| peer.to_string())
+                .collect(),
+            Arg::Topic => self
+                .topics
+                .iter()
+                .filter(|topic| topic.starts_with(word))
+                .cloned()
+                .collect(),
+            Arg::Text if word.is_empty() => Vec::new(),
+            Arg::Text if word.starts_with(':') => emoji::complete(word),
+            Arg::Text => self
+                .peers
+                .iter()
+                .filter_map(|(_, name)| name.clone())
+                .filter(|name| name.starts_with(word))
+                .collect(),
+        };
+        candidates.sort();
+        candidates.dedup();
+        (start, candidates)
+    }
+}
+
+fn help(args: &str, _swarm: &mut Swarm<Protocols>, _state: &mut AppState) -> CommandResult {
+    if args.is_empty() {
+        for command in COMMANDS {
+            info!("{} - {}", command.usage(), command.help);
+        }
+        return Ok(());
+    }
+    let name = format!("/{}", args.trim_start_matches('/'));
+    let command = find(&name).ok_or_else(|| Some(CommandError::Unknown(name).to_string()))?;
+    info!("Usage: {}\n{}", command.usage(), command.help);
+    Ok(())
+}
+
+fn connect(args: &str, swarm: &mut Swarm<Protocols>, _state: &mut AppState) -> CommandResult {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(None);
+    }
+    let addr = args
+        .parse::<Multiaddr>()
+        .map_err(|_| "Invalid multiaddress".to_string())?;
+    info!("Dialing {:?}", addr);
+    swarm
+        .dial(addr)
+        .map_err(|e| format!("Failed to dial address: {:?}", e))?;
+    Ok(())
+}
+
+fn quit(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if !args.is_empty() {
+        return Err(None);
+    }
+    state.quitting = true;
+    Ok(())
+}
+
+fn join(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let (topic, protocol) = match parts[..] {
+        [topic] => (topic, state.topics.default_protocol()),
+        [topic, "--protocol", protocol] => (topic, protocol.parse::<PubsubProtocol>()?),
+        _ => return Err(None),
+    };
+    if state.topics.is_subscribed(topic) {
+        return Err(Some(format!("Already subscribed to topic: {:?}", topic)));
+    }
+    // No one else being on the topic, joining it creates it.
+    if join_topic(topic, protocol, swarm, state)
+        && swarm.behaviour().topic_peers(topic).is_empty()
+        && state.moderation.founder(topic).is_none()
+    {
+        found_topic(state, topic);
+    }
+    Ok(())
+}
+
+/// Subscribes to a topic and asks the peers on it for its history.
+///
+/// # Returns
+///
+/// `true` if the topic was subscribed to.
+pub fn join_topic(
+    topic: &str,
+    protocol: PubsubProtocol,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) -> bool {
+    if swarm.behaviour_mut().subscribe(topic, protocol).is_err() {
+        return false;
+    }
+    state.topics.join(topic, protocol);
+    for peer in swarm.behaviour().topic_peers(topic) {
+        request_history(peer, topic, swarm, state);
+    }
+    true
+}
+
+fn leave(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let topic = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => state
+            .topics
+            .active()
+            .map(str::to_string)
+            .ok_or_else(|| "Not subscribed to any topic".to_string())?,
+        [topic] => topic.to_string(),
+        _ => return Err(None),
+    };
+    if !state.topics.is_subscribed(&topic) {
+        return Err(Some(format!("Not subscribed to topic: {:?}", topic)));
+    }
+    let protocol = state.topics.protocol(&topic);
+    if let Err(e) = swarm.behaviour_mut().unsubscribe(&topic, protocol) {
+        error!("Failed to leave topic: {:?} on {:?}", e, topic);
+    }
+    // Drop local state even if a protocol was already unsubscribed.
+    state.topics.leave(&topic);
+    if state.discovery.withdraw(&topic) {
+        advertise_topics(swarm, state);
+    }
+    Ok(())
+}
+
+fn switch_topic(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => match state.topics.active() {
+            Some(topic) => info!("Publishing to topic {:?}", topic),
+            None => info!("Not subscribed to any topic, use /join <topic>"),
+        },
+        [topic] => {
+            if !state.topics.switch(topic) {
+                return Err(Some(format!("Not subscribed to topic: {:?}", topic)));
+            }
+            info!("Publishing to topic {:?}", topic);
+        }
+        _ => return Err(None),
+    }
+    Ok(())
+}
+
+fn direct_message(
+    args: &str,
+    _swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) -> CommandResult {
+    let (peer, body) = args.split_once(char::is_whitespace).ok_or(None)?;
+    let peer_id = parse_peer(peer)?;
+    let id = MessageId::random();
+    let body = emoji::expand(body);
+    let payload = Payload::Text(TextMessage {
+        id,
+        body: body.clone(),
+        ack_requested: true,
+    });
+    if publish_payload(state, &inbox_topic(&peer_id), &payload) {
+        state.deliveries.track(id, peer_id);
+        if let Some(outbox) = &mut state.email {
+            outbox.hold(id, peer_id, &body);
+        }
+    }
+    Ok(())
+}
+
+fn send_file(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let (peer, path) = args.split_once(char::is_whitespace).ok_or(None)?;
+    let peer_id = parse_peer(peer)?;
+    let path = path.trim();
+    let request = state
+        .transfers
+        .offer(peer_id, Path::new(path))
+        .map_err(|e| format!("Failed to offer file: {}", e))?;
+    info!("Offering {:?} to {:?}", path, peer_id);
+    swarm
+        .behaviour_mut()
+        .file_transfer
+        .send_request(&peer_id, request);
+    Ok(())
+}
+
+fn accept_file(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(None);
+    }
+    let (peer_id, request) = state
+        .transfers
+        .find_offer(args)
+        .ok_or_else(|| "No matching file offer".into())
+        .and_then(|transfer_id| state.transfers.accept(&transfer_id))
+        .map_err(|e| format!("Failed to accept file: {}", e))?;
+    let streamed = match request {
+        FileRequest::Chunk { transfer_id, .. } => state
+            .transfers
+            .incoming_mut(&transfer_id)
+            .filter(|file| file.is_streamed())
+            .filter(|_| state.versions.supports(&peer_id, &STREAM_PROTOCOL))
+            .map(|_| transfer_id),
+        FileRequest::Offer { .. } => None,
+    };
+    if let Some(transfer_id) = streamed {
+        swarm.behaviour_mut().streams.open(peer_id, transfer_id);
+        return Ok(());
+    }
+    let request_id = swarm
+        .behaviour_mut()
+        .file_transfer
+        .send_request(&peer_id, request.clone());
+    state.transfers.track_request(request_id, &request);
+    Ok(())
+}
+
+fn op(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let [peer] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    let peer = parse_peer(peer)?;
+    let topic = active_topic(state)?;
+    let local_peer_id = state.local_key.public().to_peer_id();
+    let founder = state.moderation.founder(&topic);
+    if let Some(founder) = founder.filter(|founder| *founder != local_peer_id) {
+        if !state.moderation.is_moderator(&topic, &local_peer_id) {
+            return Err(Some(format!(
+                "Only the founder {:?} and moderators can add moderators",
+                founder
+            )));
+        }
+        // Without the founder, the moderators add one by majority.
+        if issue_moderation(state, &topic, Action::Nominate { peer }) {
+            let (votes, quorum) = state.moderation.nominations(&topic, &peer);
+            if state.moderation.is_moderator(&topic, &peer) {
+                info!("{} is now a moderator of {:?}", peer, topic);
+            } else {
+                info!("Nominated {}, {} of {} nominations", peer, votes, quorum);
+            }
+        }
+        return Ok(());
+    }
+    if found_topic(state, &topic) {
+        issue_moderation(state, &topic, Action::Grant { peer });
+    }
+    Ok(())
+}
+
+fn handover(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let [peer] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    let peer = parse_peer(peer)?;
+    let topic = active_topic(state)?;
+    let local_peer_id = state.local_key.public().to_peer_id();
+    if state.moderation.founder(&topic) != Some(local_peer_id) {
+        return Err(Some(format!("You are not the founder of {:?}", topic)));
+    }
+    if peer == local_peer_id {
+        return Err(Some(format!("You already founded {:?}", topic)));
+    }
+    issue_moderation(state, &topic, Action::Transfer { peer });
+    Ok(())
+}
+
+fn mute(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let action = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [peer] => Action::Mute {
+            peer: parse_peer(peer)?,
+            seconds: None,
+        },
+        [peer, minutes] => Action::Mute {
+            peer: parse_peer(peer)?,
+            seconds: Some(minutes.parse::<u64>().map_err(|_| None)? * 60),
+        },
+        _ => return Err(None),
+    };
+    moderate(state, action)
+}
+
+fn kick(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let [peer] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    let action = Action::Kick {
+        peer: parse_peer(peer)?,
+    };
+    moderate(state, action)
+}
+
+fn bans(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let (peer, topic) = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            let restrictions = state.moderation.restrictions();
+            info!("{} muted or kicked peers", restrictions.len());
+            for restriction in restrictions {
+                let until = match restriction
+                    .until
+                    .and_then(|until| chrono::Local.timestamp_millis_opt(until as i64).single())
+                {
+                    Some(until) => format!("until {}", until.format("%Y-%m-%d %H:%M")),
+                    None => "for good".to_string(),
+                };
+                info!(
+                    "  [{}] {} {} {}",
+                    restriction.topic,
+                    state.profiles.label(&restriction.peer, None),
+                    restriction.peer,
+                    until
+                );
+            }
+            let bans = state
+                .middleware
+                .get::<Reputation>()
+                .map(Reputation::bans)
+                .unwrap_or_default();
+            if !bans.is_empty() {
+                info!("{} peers banned for flooding", bans.len());
+            }
+            for ban in bans {
+                let Some(until) = chrono::Local
+                    .timestamp_millis_opt(ban.until as i64)
+                    .single()
+                else {
+                    continue;
+                };
+                info!(
+                    "  {} {} until {}, ban {}",
+                    state.profiles.label(&ban.peer, None),
+                    ban.peer,
+                    until.format("%Y-%m-%d %H:%M"),
+                    ban.bans
+                );
+            }
+            return Ok(());
+        }
+        ["lift", peer] => (find_peer(peer, state)?, active_topic(state)?),
+        ["lift", peer, topic] => (find_peer(peer, state)?, topic.to_string()),
+        _ => return Err(None),
+    };
+    if !state.moderation.is_muted(&topic, &peer) {
+        return Err(Some(format!("{} is not muted on {:?}", peer, topic)));
+    }
+    let local_peer_id = state.local_key.public().to_peer_id();
+    if state.moderation.is_moderator(&topic, &local_peer_id) {
+        // A mute running out right away lifts it for everyone.
+        let action = Action::Mute {
+            peer,
+            seconds: Some(0),
+        };
+        if !issue_moderation(state, &topic, action) {
+            return Err(Some(format!("Failed to lift the mute of {}", peer)));
+        }
+    } else {
+        state.moderation.lift(&topic, &peer);
+        save_moderation(state);
+        info!("Showing {} on {:?} again, for you only", peer, topic);
+    }
+    Ok(())
+}
+
+fn ignore(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let [peer] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    let peer = find_peer(peer, state)?;
+    if peer == state.local_key.public().to_peer_id() {
+        return Err(Some("You cannot ignore yourself".to_string()));
+    }
+    match state.ignored.ignore(peer) {
+        Ok(true) => info!(
+            "Ignoring {} {}, see /ignored",
+            state.profiles.label(&peer, None),
+            peer
+        ),
+        Ok(false) => warn!("{} is already ignored", peer),
+        Err(e) => error!("Failed to save the ignored peers: {}", e),
+    }
+    Ok(())
+}
+
+fn ignored(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            let ignored = state.ignored.list();
+            info!("{} ignored peers", ignored.len());
+            for (peer, since) in ignored {
+                let since = chrono::Local
+                    .timestamp_millis_opt(since as i64)
+                    .single()
+                    .map(|since| since.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                info!(
+                    "  {} {} since {}",
+                    state.profiles.label(&peer, None),
+                    peer,
+                    since
+                );
+            }
+        }
+        ["lift", peer] => {
+            let peer = find_peer(peer, state)?;
+            match state.ignored.lift(&peer) {
+                Ok(true) => info!("Showing {} again", peer),
+                Ok(false) => warn!("{} is not ignored", peer),
+                Err(e) => error!("Failed to save the ignored peers: {}", e),
+            }
+        }
+        _ => return Err(None),
+    }
+    Ok(())
+}
+
+fn quarantine(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            let mut held = state.quarantine.list().peekable();
+            if held.peek().is_none() {
+                info!("No messages are held back");
+            }
+            for message in held {
+                info!(
+                    "#{} [{}] {}: {} ({})",
+                    message.number,
+                    message.topic,
+                    sanitize(
+                        &state
+                            .profiles
+                            .label(&message.source, message.sender.as_deref()),
+                        MAX_RENDERED_NAME_LEN
+                    ),
+                    sanitize(&message.text.body, MAX_RENDERED_LEN),
+                    sanitize(&message.reason, MAX_RENDERED_LEN)
+                );
+            }
+        }
+        [verb @ ("release" | "drop"), n] => {
+            let n = n.trim_start_matches('#').parse::<u64>().map_err(|_| None)?;
+            let message = state
+                .quarantine
+                .list()
+                .find(|message| message.number == n)
+                .ok_or_else(|| Some(format!("No message #{} is held back", n)))?;
+            // The sender may have been muted, removed or ignored since.
+            if verb == "release" && is_hidden(state, &message.topic, &message.source) {
+                return Err(Some(format!(
+                    "Message #{} is from a muted, uninvited or ignored peer",
+                    n
+                )));
+            }
+            match state.quarantine.take(n) {
+                Some(message) if verb == "release" => show_text(&message, None, state),
+                _ => info!("Discarded message #{}", n),
+            }
+        }
+        ["clear"] => info!("Discarded {} messages", state.quarantine.clear()),
+        _ => return Err(None),
+    }
+    Ok(())
+}
+
+fn group(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let topic = active_topic(state)?;
+    let action = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            show_roster(&topic, state);
+            return Ok(());
+        }
+        ["create"] if state.moderation.is_private(&topic) => {
+            return Err(Some(format!("{:?} is already a private group", topic)));
+        }
+        ["create"] if !found_topic(state, &topic) => {
+            return Err(Some(format!(
+                "Only the founder of {:?} can close it",
+                topic
+            )));
+        }
+        ["create"] => {
+            issue_moderation(state, &topic, Action::Close);
+            return Ok(());
+        }
+        ["add", peer] => Action::Add {
+            peer: find_peer(peer, state)?,
+        },
+        ["remove", peer] => Action::Remove {
+            peer: find_peer(peer, state)?,
+        },
+        _ => return Err(None),
+    };
+    moderate(state, action)
+}
+
+fn invite(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        ["create", peer] => create_invite(find_peer(peer, state)?, None, state),
+        ["create", peer, minutes] => {
+            let seconds = minutes
+                .parse::<u64>()
+                .ok()
+                .and_then(|minutes| minutes.checked_mul(60))
+                .ok_or(None)?;
+            create_invite(find_peer(peer, state)?, Some(seconds), state)
+        }
+        ["use", token] => use_invite(token, swarm, state),
+        _ => Err(None),
+    }
+}
+
+fn note(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let parts: Vec<&str> = args.splitn(3, char::is_whitespace).collect();
+    let name = parts.first().filter(|name| !name.is_empty()).ok_or(None)?;
+    let topic = note_topic(name);
+    if !state.topics.is_subscribed(&topic) {
+        let protocol = state.topics.default_protocol();
+        if swarm.behaviour_mut().subscribe(&topic, protocol).is_err() {
+            return Ok(());
+        }
+        state.topics.join(&topic, protocol);
+        for peer in swarm.behaviour().topic_peers(&topic) {
+            request_history(peer, &topic, swarm, state);
+        }
+    }
+
+    let site = state.local_key.public().to_peer_id();
+    let note = state
+        .notes
+        .entry(topic.clone())
+        .or_insert_with(|| Note::new(site));
+    let ops = match parts[1..] {
+        [] => Vec::new(),
+        ["append", text] => note.insert(usize::MAX, text),
+        ["insert", rest] => {
+            let (index, text) = rest
+                .split_once(char::is_whitespace)
+                .and_then(|(index, text)| Some((index.parse::<usize>().ok()?, text)))
+                .ok_or(None)?;
+            note.insert(index, text)
+        }
+        ["delete", rest] => {
+            let (index, count) = rest
+                .split_once(char::is_whitespace)
+                .and_then(|(index, count)| {
+                    Some((index.parse::<usize>().ok()?, count.trim().parse().ok()?))
+                })
+                .ok_or(None)?;
+            note.delete(index, count)
+        }
+        _ => return Err(None),
+    };
+    // Notes are edited by peers, show each line sanitized.
+    let text: Vec<String> = note
+        .text()
+        .lines()
+        .map(|line| sanitize(line, MAX_RENDERED_LEN))
+        .collect();
+    info!("[{}]\n{}", topic, text.join("\n"));
+    if !ops.is_empty() {
+        publish_payload(state, &topic, &Payload::Note(ops));
+    }
+    Ok(())
+}
+
+fn receipts(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let (setting, peer) = match parts[..] {
+        [setting] => (setting, None),
+        [setting, peer] => (setting, Some(parse_peer(peer)?)),
+        _ => return Err(None),
+    };
+    let enabled = match setting {
+        "on" => true,
+        "off" => false,
+        _ => return Err(None),
+    };
+    match peer {
+        Some(peer_id) => {
+            state.read_receipts.set(peer_id, enabled);
+            info!("Read receipts {} for {:?}", setting, peer_id);
+        }
+        None => {
+            state.read_receipts.set_default(enabled);
+            info!("Read receipts {}", setting);
+        }
+    }
+    Ok(())
+}
+
+fn topics(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            let advertised = state.discovery.advertised();
+            for (topic, protocol) in state.topics.subscribed() {
+                let public = advertised.iter().any(|t| t == topic);
+                info!(
+                    "{} ({}){}",
+                    topic,
+                    protocol,
+                    if public { ", advertised" } else { "" }
+                );
+            }
+        }
+        ["advertise", topic] => {
+            if !state.topics.is_subscribed(topic) {
+                return Err(Some(format!("Not subscribed to topic: {:?}", topic)));
+            }
+            if state.discovery.advertise(topic) {
+                advertise_topics(swarm, state);
+                info!("Advertising topic {:?}", topic);
+            }
+        }
+        ["withdraw", topic] => {
+            if state.discovery.withdraw(topic) {
+                advertise_topics(swarm, state);
+                info!("Stopped advertising topic {:?}", topic);
+            }
+        }
+        ["discover"] => {
+            let query = swarm
+                .behaviour_mut()
+                .kademlia
+                .get_providers(directory_key());
+            state.discovery.start(query);
+            info!("Discovering topics...");
+        }
+        ["found"] => {
+            for (topic, peers) in state.discovery.discovered() {
+                info!("{} advertised by {} peers: {:?}", topic, peers.len(), peers);
+            }
+        }
+        _ => return Err(None),
+    }
+    Ok(())
+}
+
+fn react(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    send_reaction(args, false, state)
+}
+
+fn unreact(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    send_reaction(args, true, state)
+}
+
+fn peers(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if !args.is_empty() {
+        return Err(None);
+    }
+    let connected = state.peers.peers();
+    let present = state.presence.peers();
+    if connected.is_empty() && present.is_empty() {
+        info!("No peers around");
+    }
+    for (peer_id, peer) in connected {
+        let presence = state.presence.get(peer_id);
+        let agent = state
+            .versions
+            .get(peer_id)
+            .map_or("unknown version", |version| version.agent.as_str());
+        let common: Vec<&str> = state
+            .topics
+            .subscribed()
+            .map(|(topic, _)| topic)
+            .filter(|topic| swarm.behaviour().topic_peers(topic).contains(peer_id))
+            .collect();
+        info!(
+            "{:?} ({}, {}) is {} at {}, connected {}s ago, latency {}, topics: {}",
+            peer_id,
+            state
+                .profiles
+                .name(peer_id)
+                .or(presence.and_then(|presence| presence.name.as_deref()))
+                .unwrap_or("anonymous"),
+            agent,
+            presence.map_or("connected".to_string(), |presence| presence
+                .status
+                .to_string()),
+            peer.address,
+            peer.connected_at.elapsed().as_secs(),
+            peer.rtt.map_or("unknown".to_string(), |rtt| format!(
+                "{}ms",
+                rtt.as_millis()
+            )),
+            if common.is_empty() {
+                "none".to_string()
+            } else {
+                common.join(", ")
+            }
+        );
+    }
+    // Peers reached through others only announce their presence.
+    for (peer_id, presence) in present {
+        if state.peers.is_connected(peer_id) {
+            continue;
+        }
+        info!(
+            "{:?} ({}) is {} through other peers, last seen {}s ago, capabilities: {}",
+            peer_id,
+            state
+                .profiles
+                .name(peer_id)
+                .or(presence.name.as_deref())
+                .unwrap_or("anonymous"),
+            presence.status,
+            presence.last_seen.elapsed().as_secs(),
+            presence.capabilities.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn whois(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let [peer] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    let peer_id = find_peer(peer, state)?;
+    let presence = state.presence.get(&peer_id);
+    info!(
+        "{:?} ({})",
+        peer_id,
+        state
+            .profiles
+            .name(&peer_id)
+            .or(presence.and_then(|presence| presence.name.as_deref()))
+            .unwrap_or("anonymous")
+    );
+    info!("  fingerprint: {}", fingerprint(&peer_id));
+    match state.versions.get(&peer_id) {
+        Some(version) => {
+            let compatibility = match version.compatibility {
+                Compatibility::Compatible => "compatible".to_string(),
+                Compatibility::Older(envelope_version) => {
+                    format!("older envelope version {}", envelope_version)
+                }
+                Compatibility::Newer(envelope_version) => {
+                    format!("newer envelope version {}", envelope_version)
+                }
+                Compatibility::Foreign => "not sec_msg".to_string(),
+            };
+            info!("  agent: {} ({})", version.agent, compatibility);
+        }
+        None => info!("  agent: unknown"),
+    }
+    let mut addresses: Vec<String> = state
+        .peers
+        .get(&peer_id)
+        .map(|peer| format!("{} (connected)", peer.address))
+        .into_iter()
+        .collect();
+    if let Some(version) = state.versions.get(&peer_id) {
+        addresses.extend(version.listen_addrs.iter().map(ToString::to_string));
+    }
+    info!(
+        "  addresses: {}",
+        if addresses.is_empty() {
+            "unknown".to_string()
+        } else {
+            addresses.join(", ")
+        }
+    );
+    let common: Vec<&str> = state
+        .topics
+        .subscribed()
+        .map(|(topic, _)| topic)
+        .filter(|topic| swarm.behaviour().topic_peers(topic).contains(&peer_id))
+        .collect();
+    info!(
+        "  shared topics: {}",
+        if common.is_empty() {
+            "none".to_string()
+        } else {
+            common.join(", ")
+        }
+    );
+    if let Some(presence) = presence {
+        info!("  presence: {}", presence.status);
+    }
+    match state.peers.seen(&peer_id) {
+        Some(seen) => info!(
+            "  first seen {}s ago, last seen {}",
+            seen.first.elapsed().as_secs(),
+            if state.peers.is_connected(&peer_id) {
+                "now".to_string()
+            } else {
+                format!("{}s ago", seen.last.elapsed().as_secs())
+            }
+        ),
+        None => info!("  never seen"),
+    }
+    Ok(())
+}
+
+fn export(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let mut args = args.split_whitespace();
+    let topic = args.next().ok_or(None)?;
+    let (mut format, mut since) = (ExportFormat::Text, 0);
+    while let Some(flag) = args.next() {
+        match (flag, args.next()) {
+            ("--format", Some(value)) => format = value.parse()?,
+            ("--since", Some(value)) => since = export::parse_since(value)?,
+            _ => return Err(None),
+        }
+    }
+    if state.history.is_empty(topic) {
+        return Err(Some(format!("No messages of {} are kept", topic)));
+    }
+    let messages = export::collect(
+        topic,
+        &state.history.recent(topic, HISTORY_LIMIT),
+        since,
+        &state.profiles,
+        &mut state.middleware,
+    );
+    let name: String = topic
+        .chars()
+        .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' {
+            true => c,
+            false => '-',
+        })
+        .collect();
+    let path = std::env::current_dir()
+        .map_err(|e| e.to_string())?
+        .join(format!(
+            "{}-{}.{}",
+            name,
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            format.extension()
+        ));
+    std::fs::write(&path, export::format(topic, &messages, format))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    info!("Exported {} messages to {}", messages.len(), path.display());
+    Ok(())
+}
+
+fn stats(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if !args.is_empty() {
+        return Err(None);
+    }
+    let Some(stats) = state.middleware.get::<Stats>() else {
+        return Err(Some("No metrics layer is registered".to_string()));
+    };
+    let network = swarm.network_info();
+    info!(
+        "Up {}, {} connections to {} peers",
+        format_duration(stats.uptime()),
+        network.connection_counters().num_established(),
+        network.num_peers()
+    );
+    info!(
+        "Traffic: {} sent, {} received",
+        format_bytes(stats.bytes_sent),
+        format_bytes(stats.bytes_received)
+    );
+    for (layer, count) in stats.drops() {
+        info!("Dropped {} messages in {}", count, layer);
+    }
+    for (topic, counts) in stats.topics() {
+        info!(
+            "  {}: {} sent, {} received",
+            topic, counts.sent, counts.received
+        );
+    }
+    Ok(())
+}
+
+fn reload_config(args: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if !args.is_empty() {
+        return Err(None);
+    }
+    reload::reload(swarm, state).map_err(Some)
+}
+
+fn contact(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let mut words = args.splitn(3, char::is_whitespace);
+    let (action, peer, rest) = (words.next(), words.next(), words.next());
+    let peer = match (action, peer) {
+        (Some("list"), None) => {
+            let contacts = state.contacts.list();
+            info!("{} contacts", contacts.len());
+            for contact in contacts {
+                info!(
+                    "  {} {} {} [{}]{}",
+                    contact.alias,
+                    contact.peer,
+                    fingerprint(&contact.peer),
+                    contact.trust,
+                    match contact.notes.is_empty() {
+                        true => String::new(),
+                        false => format!(": {}", contact.notes),
+                    }
+                );
+            }
+            return Ok(());
+        }
+        (Some("add"), Some(peer)) => parse_peer(peer)?,
+        (Some("remove" | "note" | "trust"), Some(peer)) => find_peer(peer, state)?,
+        _ => return Err(None),
+    };
+    let rest = rest.map(str::trim).unwrap_or_default();
+    if action != Some("add") && state.contacts.get(&peer).is_none() {
+        return Err(Some(format!("{} is not a contact", peer)));
+    }
+    match (action, rest) {
+        (Some("add"), alias) if !alias.is_empty() => {
+            state.contacts.add(peer, alias)?;
+            state.profiles.set_alias(peer, Some(alias.to_string()));
+            info!("Added {} as {}", peer, alias);
+        }
+        (Some("remove"), "") => {
+            state.contacts.remove(&peer);
+            state.profiles.set_alias(peer, None);
+            info!("Removed {} from the contacts", peer);
+        }
+        (Some("note"), notes) => {
+            if let Some(contact) = state.contacts.get_mut(&peer) {
+                contact.notes = notes.to_string();
+            }
+            info!("Noted on {}", peer);
+        }
+        (Some("trust"), trust) => {
+            let trust: Trust = trust.parse()?;
+            if let Some(contact) = state.contacts.get_mut(&peer) {
+                contact.trust = trust;
+            }
+            info!("{} is now {}", peer, trust);
+        }
+        _ => return Err(None),
+    }
+    state
+        .contacts
+        .save()
+        .map_err(|e| format!("Failed to save the contacts: {}", e).into())
+}
+
+fn identity(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let ["export", path] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    // The terminal cannot be asked for another passphrase while in use.
+    let vault = state.vault.as_ref().ok_or(
+        "Backups are encrypted with the storage passphrase: set encrypt = true in [storage], \
+         or run `sec_msg identity export` instead"
+            .to_string(),
+    )?;
+    let path = Path::new(path);
+    backup::export(path, &state.local_key, &state.contacts.list(), vault, false)
+        .map_err(|e| format!("Failed to export to {}: {}", path.display(), e))?;
+    info!(
+        "Exported your identity and {} contacts to {}, encrypted with the storage passphrase",
+        state.contacts.list().len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn nick(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if args.is_empty() {
+        return Err(None);
+    }
+    let profile = Profile {
+        name: args.to_string(),
+    };
+    if !profile.is_valid() {
+        return Err(Some(format!(
+            "Names are 1 to {} characters without whitespace",
+            MAX_NAME_LEN
+        )));
+    }
+    let local_peer_id = state.local_key.public().to_peer_id();
+    let (peers, _) = state.profiles.collisions(&local_peer_id, &profile.name);
+    state.profiles.set_own(Some(profile.name.clone()));
+    state.display_name = Some(profile.name);
+    publish_profile(state);
+    info!("You are now known as {}", args);
+    for peer in peers {
+        warn!(
+            "{:?} calls itself {} too; it is shown as {}",
+            peer,
+            args,
+            state.profiles.label(&peer, None)
+        );
+    }
+    Ok(())
+}
+
+fn notify(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let notifier = &mut state.notifier;
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        ["on"] => notifier.set_enabled(true),
+        ["off"] => notifier.set_enabled(false),
+        ["dnd", "on"] => notifier.set_do_not_disturb(true),
+        ["dnd", "off"] => notifier.set_do_not_disturb(false),
+        ["highlights", "on"] => notifier.set_highlights(true),
+        ["highlights", "off"] => notifier.set_highlights(false),
+        ["mute", topic] => {
+            if !notifier.mute(topic) {
+                return Err(Some(format!("Already muted: {:?}", topic)));
+            }
+        }
+        ["unmute", topic] => {
+            if !notifier.unmute(topic) {
+                return Err(Some(format!("Not muted: {:?}", topic)));
+            }
+        }
+        _ => return Err(None),
+    }
+    info!("Notifications: {}", args);
+    Ok(())
+}
+
+fn watch(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] if state.notifier.keywords().is_empty() => info!("No watched keywords"),
+        [] => info!("Watched keywords: {}", state.notifier.keywords().join(", ")),
+        [keyword] => {
+            if !state.notifier.watch(keyword) {
+                return Err(Some(format!("Already watched: {:?}", keyword)));
+            }
+            info!("Watching {:?}", keyword);
+        }
+        _ => return Err(None),
+    }
+    Ok(())
+}
+
+fn unwatch(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let [keyword] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    if !state.notifier.unwatch(keyword) {
+        return Err(Some(format!("Not watched: {:?}", keyword)));
+    }
+    info!("Stopped watching {:?}", keyword);
+    Ok(())
+}
+
+fn format(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let format = &mut state.renderer.format;
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        ["suffix", "on"] => format.peer_suffix = true,
+        ["suffix", "off"] => format.peer_suffix = false,
+        [clock] => format.clock = clock.parse::<Clock>().map_err(|_| None)?,
+        _ => return Err(None),
+    }
+    info!(
+        "Showing {} times, peer ID suffixes {}",
+        format.clock,
+        if format.peer_suffix { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+fn search(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    if args.is_empty() {
+        return Err(None);
+    }
+    let found = state.renderer.scrollback.search(args);
+    let shown = &found[found.len().saturating_sub(SEARCH_RESULTS)..];
+    info!(
+        "{} messages match {:?}{}",
+        found.len(),
+        args,
+        if shown.len() < found.len() {
+            format!(", showing the latest {}", shown.len())
+        } else {
+            String::new()
+        }
+    );
+    for message in shown {
+        info!("{}", message);
+    }
+    Ok(())
+}
+
+fn status(args: &str, _swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let status = match args {
+        "online" => PresenceStatus::Online,
+        "away" => PresenceStatus::Away,
+        _ => return Err(None),
+    };
+    state.presence.set_status(status);
+    info!("You are now {}", status);
+    Ok(())
+}
+
+/// Parses a peer ID argument.
+fn parse_peer(peer: &str) -> Result<PeerId, Option<String>> {
+    peer.parse()
+        .map_err(|_| Some(format!("Invalid peer id: {:?}", peer)))
+}
+
+/// Finds a peer by peer ID, alias or display name.
+fn find_peer(peer: &str, state: &AppState) -> Result<PeerId, Option<String>> {
+    if let Ok(peer_id) = peer.parse() {
+        return Ok(peer_id);
+    }
+    match state.profiles.named(peer)[..] {
+        [peer_id] => Ok(peer_id),
+        [] => Err(Some(format!("No peer named {:?}", peer))),
+        _ => Err(Some(format!(
+            "Several peers are named {:?}, use a peer id",
+            peer
+        ))),
+    }
+}
+
+/// Returns the active topic.
+fn active_topic(state: &AppState) -> Result<String, Option<String>> {
+    state
+        .topics
+        .active()
+        .map(str::to_string)
+        .ok_or_else(|| Some("Not subscribed to any topic".to_string()))
+}
+
+/// Issues a mute or kick on the active topic, if we moderate it.
+fn moderate(state: &mut AppState, action: Action) -> CommandResult {
+    let topic = active_topic(state)?;
+    let local_peer_id = state.local_key.public().to_peer_id();
+    if !state.moderation.is_moderator(&topic, &local_peer_id) {
+        return Err(Some(format!("You are not a moderator of {:?}", topic)));
+    }
+    issue_moderation(state, &topic, action);
+    Ok(())
+}
+
+/// Lists the founder, admins and members of a topic.
+fn show_roster(topic: &str, state: &AppState) {
+    let Some(roster) = state.moderation.roster(topic) else {
+        info!("{:?} is open to everyone and has no founder", topic);
+        return;
+    };
+    if roster.private {
+        info!("{:?} is a private group", topic);
+    } else {
+        info!("{:?} is open to everyone", topic);
+    }
+    let roles = roster
+        .founder
+        .map(|founder| ("founder", founder))
+        .into_iter()
+        .chain(roster.admins.into_iter().map(|admin| ("admin", admin)))
+        .chain(roster.members.into_iter().map(|member| ("member", member)));
+    for (role, peer) in roles {
+        info!("  {} {} {}", role, state.profiles.label(&peer, None), peer);
+    }
+}
+
+/// Issues an invite to the active topic for a peer, closing the topic first if
+/// we founded it, or founding it if it has no founder yet, and it is still
+/// open.
+fn create_invite(invitee: PeerId, seconds: Option<u64>, state: &mut AppState) -> CommandResult {
+    let topic = active_topic(state)?;
+    let expires = match seconds {
+        Some(seconds) => Some(
+            seconds
+                .checked_mul(1000)
+                .and_then(|millis| moderation::now().checked_add(millis))
+                .ok_or(None)?,
+        ),
+        None => None,
+    };
+    let local_peer_id = state.local_key.public().to_peer_id();
+    let founder = state.moderation.founder(&topic);
+    if founder.is_none_or(|founder| founder == local_peer_id) {
+        if !found_topic(state, &topic)
+            || !state.moderation.is_private(&topic)
+                && !issue_moderation(state, &topic, Action::Close)
+        {
+            return Ok(());
+        }
+    } else if !state.moderation.is_moderator(&topic, &local_peer_id) {
+        return Err(Some(format!("You are not a moderator of {:?}", topic)));
+    } else if !state.moderation.is_private(&topic) {
+        warn!(
+            "{:?} stays open to everyone until its founder creates an invite",
+            topic
+        );
+    }
+    let token = InviteToken::issue(&topic, invitee, expires, &state.local_key)
+        .map_err(|e| format!("Failed to sign the invite: {}", e))?;
+    let until =
+        match expires.and_then(|until| chrono::Local.timestamp_millis_opt(until as i64).single()) {
+            Some(until) => format!("valid until {}", until.format("%Y-%m-%d %H:%M")),
+            None => "never expiring".to_string(),
+        };
+    info!(
+        "Invite to {:?} for {}, {}: {}",
+        topic,
+        state.profiles.label(&invitee, None),
+        until,
+        token.encode()
+    );
+    Ok(())
+}
+
+/// Joins the topic of an invite and presents the invite to its members.
+fn use_invite(text: &str, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> CommandResult {
+    let token = InviteToken::decode(text).map_err(|e| e.to_string())?;
+    let inviter = token.verify(moderation::now()).map_err(|e| e.to_string())?;
+    let local_peer_id = state.local_key.public().to_peer_id();
+    if token.invitee != local_peer_id {
+        return Err(Some(format!(
+            "This invite is for {}, not you",
+            token.invitee
+        )));
+    }
+    let topic = token.topic.clone();
+    if !state.topics.is_subscribed(&topic)
+        && !join_topic(&topic, state.topics.default_protocol(), swarm, state)
+    {
+        return Err(Some(format!("Failed to join {:?}", topic)));
+    }
+    let directive = ModerationAction {
+        topic: topic.clone(),
+        action: Action::Join { token },
+    };
+    // The topic roles may not be known here yet, the members check the
+    // invite either way.
+    if state
+        .moderation
+        .apply(&local_peer_id, &directive, moderation::now())
+        .is_ok()
+    {
+        save_moderation(state);
+    }
+    if publish_payload(state, &topic, &Payload::Moderation(directive)) {
+        info!(
+            "Joining {:?} with an invite from {}",
+            topic,
+            state.profiles.label(&inviter, None)
+        );
+    }
+    Ok(())
+}
+
+/// Publishes a reaction, or its removal, and applies it locally.
+fn send_reaction(args: &str, removed: bool, state: &mut AppState) -> CommandResult {
+    let [target, emoji] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(None);
+    };
+    let (target, topic) = state
+        .reactions
+        .resolve(target)
+        .ok_or_else(|| format!("Unknown or ambiguous message id: {:?}", target))?;
+    let topic = topic.to_string();
+    let reaction = Reaction {
+        target,
+        emoji: emoji::expand(emoji),
+        removed,
+    };
+    if !reaction.is_valid() {
+        return Err(Some(format!(
+            "Reactions are at most {} characters",
+            MAX_REACTION_LEN
+        )));
+    }
+    let local_peer_id = state.local_key.public().to_peer_id();
+    if publish_payload(state, &topic, &Payload::Reaction(reaction.clone())) {
+        handle_reaction(local_peer_id, &topic, &reaction, state);
+    }
+    Ok(())
+}
+
+/// Applies a moderation directive locally and broadcasts it on the topic.
+///
+/// # Arguments
+///
+/// * `state` - The application state.
+/// * `topic` - The moderated topic.
+/// * `action` - The moderation action.
+///
+/// # Returns
+///
+/// `true` if the directive was applied and queued for publishing.
+fn issue_moderation(state: &mut AppState, topic: &str, action: Action) -> bool {
+    let directive = ModerationAction {
+        topic: topic.to_string(),
+        action,
+    };
+    let local_peer_id = state.local_key.public().to_peer_id();
+    if let Err(e) = state
+        .moderation
+        .apply(&local_peer_id, &directive, moderation::now())
+    {
+        error!(
+            "Failed to apply {:?} on {:?}: {}",
+            directive.action, topic, e
+        );
+        return false;
+    }
+    save_moderation(state);
+    info!("[{}] Issued {:?}", topic, directive.action);
+    publish_payload(state, topic, &Payload::Moderation(directive))
+}
+
+/// Founds a topic no one founded yet.
+///
+/// # Returns
+///
+/// `true` if the local peer is the founder of the topic.
+fn found_topic(state: &mut AppState, topic: &str) -> bool {
+    let local_peer_id = state.local_key.public().to_peer_id();
+    match state.moderation.founder(topic) {
+        Some(founder) => founder == local_peer_id,
+        None => issue_moderation(state, topic, Action::Found),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use libp2p::{identity, PeerId};
+    use tokio::sync::mpsc;
+
+    use super::{find, quarantine, CommandError, Completions, COMMANDS};
+    use crate::chaos::ChaosControl;
+    use crate::cli::Options;
+    use crate::config::Config;
+    use crate::contacts::Contacts;
+    use crate::content_filter::Quarantined;
+    use crate::delivery::MessageId;
+    use crate::moderation::{now, Action, ModerationAction};
+    use crate::network::create_memory_swarm;
+    use crate::protocol::TextMessage;
+    use crate::state::AppState;
+    use crate::topic::PubsubProtocol;
+
+    #[test]
+    fn test_registry() {
+        let names: HashSet<&str> = COMMANDS.iter().map(|command| command.name).collect();
+        assert_eq!(names.len(), COMMANDS.len());
+        assert!(COMMANDS.iter().all(|command| command.name.starts_with('/')));
+
+        let join = find("/join").unwrap();
+        assert_eq!(
+            join.usage(),
+            "/join <topic> [--protocol floodsub|gossipsub|both]"
+        );
+        assert_eq!(find("/peers").unwrap().usage(), "/peers");
+        assert!(find("/jion").is_none());
+        assert_eq!(
+            CommandError::Unknown("/jion".to_string()).to_string(),
+            "Unknown command /jion, see /help"
+        );
+        assert_eq!(
+            CommandError::Usage(join).to_string(),
+            "Usage: /join <topic> [--protocol floodsub|gossipsub|both]"
+        );
+    }
+
+    #[test]
+    fn test_completions() {
+        let alice = PeerId::random();
+        let completions = Completions {
+            peers: vec![(alice, Some("alice".to_string())), (PeerId::random(), None)],
+            topics: vec!["rust".to_string(), "rustaceans".to_string()],
+        };
+        assert_eq!(
+            completions.complete("/re"),
+            (
+                0,
+                vec![
+                    "/react".to_string(),
+                    "/receipts".to_string(),
+                    "/reload".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            completions.complete("/msg ali"),
+            (5, vec![alice.to_string()])
+        );
+        assert_eq!(completions.complete("/join ru").1.len(), 2);
+        assert_eq!(
+            completions.complete("hi al"),
+            (3, vec!["alice".to_string()])
+        );
+        assert!(completions.complete("/note ru").1.is_empty());
+        assert_eq!(
+            completions.complete("ship it :rocke"),
+            (8, vec![":rocket:".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_release() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let local_peer_id = local_key.public().to_peer_id();
+        let config = Config::from_options(&Options::default()).unwrap();
+        let (events, _) = mpsc::unbounded_channel();
+        let (ui, _ui) = mpsc::unbounded_channel();
+        let mut state = AppState::new(
+            local_key.clone(),
+            &config,
+            Contacts::new(),
+            events,
+            Some(ui),
+        );
+        let mut swarm = create_memory_swarm(
+            local_key,
+            local_peer_id,
+            "chat",
+            PubsubProtocol::Both,
+            ChaosControl::default(),
+        )
+        .unwrap();
+        let troll = PeerId::random();
+        let number = state.quarantine.hold(Quarantined {
+            number: 0,
+            topic: "chat".to_string(),
+            source: troll,
+            sender: None,
+            timestamp: 0,
+            text: TextMessage {
+                id: MessageId::random(),
+                body: "spam".to_string(),
+                ack_requested: false,
+            },
+            reason: "spam".to_string(),
+        });
+
+        // A peer muted since its message was held back stays hidden.
+        let founder = PeerId::random();
+        for action in [
+            Action::Found,
+            Action::Mute {
+                peer: troll,
+                seconds: None,
+            },
+        ] {
+            let directive = ModerationAction {
+                topic: "chat".to_string(),
+                action,
+            };
+            state.moderation.apply(&founder, &directive, now()).unwrap();
+        }
+        let release = format!("release {}", number);
+        assert!(quarantine(&release, &mut swarm, &mut state).is_err());
+        assert_eq!(state.quarantine.list().count(), 1);
+        assert!(state.moderation.lift("chat", &troll));
+        assert!(quarantine(&release, &mut swarm, &mut state).is_ok());
+        assert_eq!(state.quarantine.list().count(), 0);
+    }
+}
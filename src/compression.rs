@@ -0,0 +1,156 @@
+/*!
+ * Compression module for the messaging application.
+ *
+ * This module provides the optional LZ4 compression applied to envelope
+ * payloads and file chunks. The algorithm used is carried alongside the
+ * data, so receivers know how to restore it and can reject algorithms they
+ * do not support.
+ */
+
+use std::{error::Error, fmt};
+
+use serde::{Deserialize, Serialize};
+
+/// Payloads smaller than this are never compressed.
+pub const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// Compression algorithm applied to a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u8", into = "u8")]
+pub enum Compression {
+    None,
+    Lz4,
+    /// An algorithm introduced by a newer client.
+    Unknown(u8),
+}
+
+impl From<u8> for Compression {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Compression::None,
+            1 => Compression::Lz4,
+            other => Compression::Unknown(other),
+        }
+    }
+}
+
+impl From<Compression> for u8 {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Unknown(other) => other,
+        }
+    }
+}
+
+/// Errors produced while decompressing data.
+#[derive(Debug)]
+pub enum CompressionError {
+    /// The data uses an algorithm this client does not support.
+    Unsupported(u8),
+    /// The data would decompress beyond the allowed size.
+    TooLarge(usize),
+    /// The data is not valid for its algorithm.
+    Corrupt,
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::Unsupported(id) => write!(f, "unsupported compression {}", id),
+            CompressionError::TooLarge(size) => {
+                write!(f, "decompressed size of {} bytes exceeds the limit", size)
+            }
+            CompressionError::Corrupt => write!(f, "corrupt compressed data"),
+        }
+    }
+}
+
+impl Error for CompressionError {}
+
+/// Compresses data if that makes it smaller.
+///
+/// # Arguments
+///
+/// * `data` - The data to compress.
+///
+/// # Returns
+///
+/// The algorithm used and the resulting bytes.
+pub fn compress(data: Vec<u8>) -> (Compression, Vec<u8>) {
+    if data.len() < MIN_COMPRESSED_SIZE {
+        return (Compression::None, data);
+    }
+    let compressed = lz4_flex::block::compress_prepend_size(&data);
+    if compressed.len() < data.len() {
+        (Compression::Lz4, compressed)
+    } else {
+        (Compression::None, data)
+    }
+}
+
+/// Restores data compressed with `compress`.
+///
+/// # Arguments
+///
+/// * `compression` - The algorithm the data was compressed with.
+/// * `data` - The compressed data.
+/// * `max_size` - The largest decompressed size accepted.
+///
+/// # Returns
+///
+/// A `Result` containing the decompressed bytes or an error.
+pub fn decompress(
+    compression: Compression,
+    data: &[u8],
+    max_size: usize,
+) -> Result<Vec<u8>, CompressionError> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => {
+            // Check the declared size before allocating for it.
+            let (size, compressed) =
+                lz4_flex::block::uncompressed_size(data).map_err(|_| CompressionError::Corrupt)?;
+            if size > max_size {
+                return Err(CompressionError::TooLarge(size));
+            }
+            lz4_flex::block::decompress(compressed, size).map_err(|_| CompressionError::Corrupt)
+        }
+        Compression::Unknown(id) => Err(CompressionError::Unsupported(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, Compression, CompressionError};
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let data = "hello ".repeat(200).into_bytes();
+        let (compression, compressed) = compress(data.clone());
+        assert_eq!(compression, Compression::Lz4);
+        assert!(compressed.len() < data.len());
+        assert_eq!(
+            decompress(compression, &compressed, data.len()).unwrap(),
+            data
+        );
+
+        let (compression, _) = compress(b"short".to_vec());
+        assert_eq!(compression, Compression::None);
+    }
+
+    #[test]
+    fn test_decompress_limits() {
+        let data = vec![0u8; 10_000];
+        let (compression, compressed) = compress(data);
+        assert!(matches!(
+            decompress(compression, &compressed, 1_000),
+            Err(CompressionError::TooLarge(10_000))
+        ));
+        assert!(matches!(
+            decompress(Compression::Unknown(9), &compressed, 10_000),
+            Err(CompressionError::Unsupported(9))
+        ));
+    }
+}
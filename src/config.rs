@@ -2,35 +2,1699 @@
  * Configuration module for the messaging application.
  *
  * This module provides a structure for reading and storing configuration
- * values such as the log level.
+ * values such as the log level, the identity, the listen addresses, the
+ * bootstrap peers, the topics joined at startup, the download directory,
+ * the rate limits applied to each peer, the default pubsub protocols, the
+ * outbound rate, the user interface, its key bindings and theme, desktop
+ * notifications, watched keywords, the message format, whether stored
+ * files are encrypted, how long connections are kept, the content filters
+ * of inbound messages, and the email gateway.
+ *
+ * Settings are layered: the defaults are overridden by the TOML
+ * configuration file, which is overridden by environment variables, which
+ * are overridden by the command line options. Every key of the file has
+ * an environment variable named after it, `SEC_MSG_` followed by the key in
+ * capitals with dots as underscores, such as `SEC_MSG_UI_THEME` for
+ * `theme` in `[ui]`; lists are comma-separated. The file is read from
+ * `--config <path>`, `SEC_MSG_CONFIG`, or `config.toml` in the
+ * configuration directory, where it may be missing. `config init` writes a
+ * starting file listing every setting.
  */
 
-use std::env;
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fmt::Display,
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::cli::Options;
+use crate::content_filter::{FilterAction, FilterSettings};
+use crate::dirs::Dirs;
+use crate::email::{EmailSettings, KEY_PASSPHRASE_VAR, PASSWORD_VAR};
+use crate::keys::Keymap;
+use crate::logfile::{LogFile, Rotation};
+use crate::network::ConnectionSettings;
+use crate::note::is_note_topic;
+use crate::presence::PRESENCE_TOPIC;
+use crate::protocol::{is_inbox_topic, DEFAULT_MAX_MESSAGE_SIZE, MAX_PAYLOAD_SIZE};
+use crate::rate_limit::{Limit, Limits};
+use crate::render::{Clock, MessageFormat, Output};
+use crate::theme::ThemeName;
+use crate::topic::PubsubProtocol;
+use crate::ui::Interface;
+use crate::validate::{
+    is_tcp, log_filter, parsed, switch, tcp_port, Checker, ConfigError, Origin, Setting,
+};
+use crate::webhook;
 
 /// Configuration structure containing application settings.
+#[derive(Clone)]
 pub struct Config {
     pub log_level: String,
+    /// File logs are also written to, if enabled.
+    pub log_file: Option<LogFile>,
+    /// File the identity keypair is kept in, the one in the data directory
+    /// if it exists, a new identity being used on every run without one.
+    pub identity: Option<PathBuf>,
+    /// Where files are kept between runs, if the platform has a place.
+    pub dirs: Option<Dirs>,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Peers dialed at startup.
+    pub bootstrap: Vec<Multiaddr>,
+    /// Topics joined at startup, the first one active.
+    pub topics: Vec<String>,
+    pub download_dir: PathBuf,
+    /// Messages processed per peer and topic, by default, from contacts,
+    /// on topics with limits of their own and of control messages.
+    pub rate_limits: Limits,
+    /// Maximum number of peers and topics tracked by the rate limiter.
+    pub rate_limit_peers: usize,
+    /// Pubsub protocols used by topics joined without a choice.
+    pub pubsub_protocol: PubsubProtocol,
+    /// Maximum number of bytes sent per second.
+    pub outbound_rate: usize,
+    /// Largest message sent or accepted, in bytes once encoded.
+    pub max_message_size: usize,
+    /// User interface, the terminal UI when run interactively.
+    pub interface: Interface,
+    /// Key bindings of the terminal UI, the defaults with the keys of the
+    /// configuration file and then the `binding=key` pairs of
+    /// `SEC_MSG_UI_KEYS` replacing theirs.
+    pub keymap: Keymap,
+    /// Theme of the terminal UI.
+    pub theme: ThemeName,
+    /// Whether desktop notifications are raised.
+    pub notifications: bool,
+    /// Keywords highlighted like mentions of the local display name.
+    pub keywords: Vec<String>,
+    /// How chat messages are rendered.
+    pub message_format: MessageFormat,
+    /// Whether the identity and the history are encrypted at rest with a
+    /// passphrase.
+    pub encrypt_storage: bool,
+    /// How long connections are kept.
+    pub connection: ConnectionSettings,
+    /// What inbound messages are checked against before being shown.
+    pub content_filter: FilterSettings,
+    /// Topic the lines piped to stdin are published to, set by
+    /// `--stdin-pipe <topic>`.
+    pub pipe_topic: Option<String>,
+    /// What is written to stdout, set by `--output text|json`.
+    pub output: Output,
+    /// The email gateway, on when an SMTP server is set.
+    pub email: Option<EmailSettings>,
+}
+
+/// The configuration file written by `config init`, every setting
+/// commented out with its default.
+const TEMPLATE: &str = r#"# Configuration of sec_msg. Environment variables and command line options
+# override the settings below. Each setting has an environment variable named
+# SEC_MSG_ and its key in capitals with dots as underscores, such as
+# SEC_MSG_UI_THEME for theme in [ui], lists being comma-separated.
+
+# Log filter, such as "debug" or "sec_msg=trace".
+# log_level = "info"
+
+# File the identity keypair is kept in, created on first use. Without it the
+# identity.key created by `sec_msg keygen` in the data directory is used, if
+# any, and otherwise a new peer ID on every run.
+# identity = "/path/to/identity.key"
+
+# Addresses to listen on.
+# listen = ["/ip4/0.0.0.0/tcp/0"]
+
+# Peers dialed at startup, ending with /p2p/<peer id> to look up others.
+# bootstrap = []
+
+# Topics joined at startup, the first one active.
+# topics = ["chat"]
+
+# Where received files are saved.
+# download_dir = "downloads"
+
+# Pubsub protocols of topics joined without a choice: "floodsub",
+# "gossipsub" or "both".
+# pubsub = "both"
+
+# Maximum number of bytes sent per second.
+# outbound_rate = 4194304
+
+# Largest message sent or accepted in bytes, larger content being sent as a
+# file with /send-file.
+# max_message_size = 1048576
+
+# Whether desktop notifications are raised.
+# notifications = true
+
+# Keywords highlighted like mentions of your display name.
+# keywords = []
+
+[rate_limit]
+# Sustained number of messages processed per peer and minute.
+# per_minute = 100
+# Number of messages a peer may send at once.
+# burst = 20
+# Maximum number of peers tracked, counted once per topic.
+# peers = 10000
+# Peers allowed the contact limits instead of the ones above.
+# contacts = []
+# Limits of contacts, the ones above where left out.
+# contact = { per_minute = 300, burst = 60 }
+# Limits of every peer on a topic, overriding the ones above.
+# topics = { announcements = { per_minute = 10, burst = 2 } }
+# Limits of the receipts, presence beacons and other control messages of
+# every peer on a topic, counted apart from its messages.
+# control = { per_minute = 300, burst = 60 }
+
+[log]
+# Whether logs are also written to sec_msg.log in the data directory, which
+# is not encrypted.
+# file = false
+# Size in bytes from which the file is rotated.
+# max_size = 10485760
+# Whether the file is also rotated "hourly", "daily" or "never".
+# rotate = "daily"
+# Number of rotated files kept, named sec_msg.log.1 and up.
+# keep = 7
+
+[storage]
+# Whether the identity and the message history are encrypted with a
+# passphrase, typed at startup or set in SEC_MSG_PASSPHRASE. The history is
+# only kept between runs when encrypted.
+# encrypt = false
+
+[connection]
+# Seconds a connection without traffic is kept open.
+# idle_timeout = 30
+# Whether connections without traffic are kept open whatever the idle
+# timeout, for relays and bridges that stay quiet for long.
+# keep_alive = false
+# Seconds dialing a peer may take, the handshake included, up to an hour.
+# dial_timeout = 10
+# Events of a connection buffered before it waits for the node.
+# event_buffer = 7
+
+# Inbound messages are checked against the filters below before being shown.
+[filter]
+# Regular expressions matched against the body of chat messages.
+# patterns = []
+# What is done with messages matching a pattern: "drop" them, "flag" them
+# when shown, or "quarantine" them until released with /quarantine.
+# action = "flag"
+# Program and arguments started once, sent each message as a JSON line and
+# answering with a line of "pass", or "drop", "flag" or "quarantine"
+# followed by a reason.
+# command = ["/path/to/filter"]
+
+[ui]
+# "tui" or "plain", the terminal UI when run interactively.
+# interface = "tui"
+# "default", "high-contrast" or "monochrome".
+# theme = "default"
+# "24h" or "12h".
+# clock = "24h"
+# Whether senders are followed by the end of their peer ID.
+# peer_suffix = false
+
+# Keys replacing the defaults of a binding, one key or a list of them.
+[ui.keys]
+# next = "alt-right"
+# quit = ["ctrl-c", "ctrl-d"]
+
+# Direct messages to contacts that stay offline are sent by email, and their
+# replies read back, when built with the email feature.
+[email]
+# Address emails are sent from and replies are read at.
+# address = "me@example.com"
+# SMTP server, "smtps://host" or "smtp://host?tls=required" for STARTTLS.
+# Setting it turns the gateway on.
+# smtp = "smtps://smtp.example.com"
+# IMAP server replies are read from, checked every minute.
+# imap = "imaps://imap.example.com"
+# User name on both servers, the address by default. The password is set in
+# SEC_MSG_EMAIL_PASSWORD.
+# user = "me@example.com"
+# Email addresses of contacts, keyed by peer ID.
+# contacts = {}
+# Armored PGP public keys of contacts by peer ID, emails to them are
+# encrypted to.
+# keys = {}
+# Armored PGP secret key encrypted replies are decrypted with, its passphrase
+# in SEC_MSG_EMAIL_KEY_PASSPHRASE if it has one.
+# secret_key = "/path/to/secret.asc"
+"#;
+
+/// Settings of the configuration file, all optional, with where they are
+/// in the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    log_level: Option<Spanned<String>>,
+    identity: Option<Spanned<PathBuf>>,
+    listen: Option<Spanned<Vec<Spanned<String>>>>,
+    bootstrap: Option<Spanned<Vec<Spanned<String>>>>,
+    topics: Option<Spanned<Vec<Spanned<String>>>>,
+    download_dir: Option<Spanned<PathBuf>>,
+    pubsub: Option<Spanned<String>>,
+    outbound_rate: Option<Spanned<usize>>,
+    max_message_size: Option<Spanned<usize>>,
+    notifications: Option<bool>,
+    keywords: Option<Vec<String>>,
+    rate_limit: RateLimitSection,
+    log: LogSection,
+    storage: StorageSection,
+    connection: ConnectionSection,
+    filter: FilterSection,
+    ui: UiSection,
+    email: EmailSection,
+}
+
+/// The `[rate_limit]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitSection {
+    per_minute: Option<Spanned<u32>>,
+    burst: Option<Spanned<u32>>,
+    peers: Option<Spanned<usize>>,
+    contacts: Option<Spanned<Vec<Spanned<String>>>>,
+    contact: Option<LimitTable>,
+    /// Limits by topic.
+    topics: BTreeMap<String, Spanned<LimitTable>>,
+    control: Option<LimitTable>,
+}
+
+/// Limits of a class of peers in the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitTable {
+    per_minute: Option<Spanned<u32>>,
+    burst: Option<Spanned<u32>>,
+}
+
+/// The `[log]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    file: Option<Spanned<bool>>,
+    max_size: Option<Spanned<u64>>,
+    rotate: Option<Spanned<String>>,
+    keep: Option<Spanned<usize>>,
+}
+
+/// The `[storage]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageSection {
+    encrypt: Option<bool>,
+}
+
+/// The `[connection]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConnectionSection {
+    idle_timeout: Option<Spanned<u64>>,
+    keep_alive: Option<bool>,
+    dial_timeout: Option<Spanned<u64>>,
+    event_buffer: Option<Spanned<usize>>,
+}
+
+/// The `[filter]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilterSection {
+    patterns: Option<Spanned<Vec<Spanned<String>>>>,
+    action: Option<Spanned<String>>,
+    command: Option<Spanned<Vec<Spanned<String>>>>,
+}
+
+/// The `[email]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EmailSection {
+    address: Option<Spanned<String>>,
+    smtp: Option<Spanned<String>>,
+    imap: Option<Spanned<String>>,
+    user: Option<Spanned<String>>,
+    /// Addresses by peer ID.
+    contacts: BTreeMap<String, Spanned<String>>,
+    /// Public key files by peer ID.
+    keys: BTreeMap<String, Spanned<PathBuf>>,
+    secret_key: Option<Spanned<PathBuf>>,
+}
+
+/// The `[ui]` table of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UiSection {
+    interface: Option<Spanned<String>>,
+    theme: Option<Spanned<String>>,
+    clock: Option<Spanned<String>>,
+    peer_suffix: Option<bool>,
+    /// Keys by binding, one key or a list of them.
+    keys: BTreeMap<String, Spanned<Keys>>,
+}
+
+/// The keys of a binding in the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Keys {
+    One(String),
+    Many(Vec<String>),
 }
 
 impl Config {
-    /// Creates a new `Config` instance with default values.
+    /// Creates a new `Config` from the configuration file, the environment
+    /// and the command line.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The command line options.
     ///
     /// # Returns
     ///
-    /// A new `Config` instance.
-    pub fn new() -> Self {
-        let log_level = env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
-        Config { log_level }
+    /// A `Result` containing the `Config`, or an error if the
+    /// configuration file cannot be read or parsed, an explicitly given one
+    /// is missing, or any setting is invalid, in which case every invalid
+    /// setting is listed.
+    pub fn new(options: &Options) -> Result<Self, Box<dyn Error>> {
+        let dirs = Dirs::new(home(options).as_deref());
+        let explicit = explicit_path(options);
+        let default = dirs.as_ref().map(Dirs::config_file);
+        let (file, source) = match explicit.clone().or(default) {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(text) => {
+                    let file = toml::from_str(&text)
+                        .map_err(|e| format!("Invalid configuration file {:?}: {}", path, e))?;
+                    (file, Some((path, text)))
+                }
+                // Only the default configuration file may be missing.
+                Err(e) if e.kind() == io::ErrorKind::NotFound && explicit.is_none() => {
+                    (ConfigFile::default(), None)
+                }
+                Err(e) => {
+                    return Err(
+                        format!("Failed to read configuration file {:?}: {}", path, e).into(),
+                    )
+                }
+            },
+            None => (ConfigFile::default(), None),
+        };
+        let source = source
+            .as_ref()
+            .map(|(path, text)| (path.as_path(), text.as_str()));
+        let env = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Ok(Config::layer(file, source, env, options, dirs)?)
+    }
+
+    /// Creates a configuration from command line options alone, ignoring
+    /// the configuration file, the environment and the data directory, for
+    /// nodes run by tests.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The command line options.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn from_options(options: &Options) -> Result<Self, ConfigError> {
+        Config::layer(ConfigFile::default(), None, BTreeMap::new(), options, None)
+    }
+
+    /// Layers the environment and the command line on the configuration
+    /// file, checking every setting.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The settings of the configuration file.
+    /// * `source` - The path and text of the configuration file, if read.
+    /// * `env` - The environment variables.
+    /// * `options` - The command line options.
+    /// * `dirs` - Where files are kept between runs.
+    fn layer(
+        file: ConfigFile,
+        source: Option<(&Path, &str)>,
+        env: BTreeMap<String, String>,
+        options: &Options,
+        dirs: Option<Dirs>,
+    ) -> Result<Self, ConfigError> {
+        let mut check = Checker::new(source, env);
+        check.unknown_env("SEC_MSG_", &ENV_VARS, EXPECTED_ENV);
+        let pipe_topic = options
+            .stdin_pipe
+            .clone()
+            .map(|topic| Setting::new(topic, Origin::Cli("--stdin-pipe")));
+        let output = options.output.unwrap_or(Output::Text);
+        let log_level = match &options.log_level {
+            Some(level) => match log_filter(level) {
+                Ok(level) => Some(level),
+                Err(e) => {
+                    check.report(&Origin::Cli("--log-level"), e);
+                    None
+                }
+            },
+            None => check
+                .env("SEC_MSG_LOG_LEVEL", log_filter)
+                .or_else(|| check.env("RUST_LOG", log_filter))
+                .or_else(|| check.file("log_level", file.log_level, log_filter))
+                .map(|level| level.value),
+        }
+        .unwrap_or_else(|| "info".to_string());
+        let log_file = check
+            .env("SEC_MSG_LOG_FILE", switch)
+            .or_else(|| check.value("log.file", file.log.file))
+            .filter(|enabled| enabled.value);
+        let max_size = check
+            .env("SEC_MSG_LOG_MAX_SIZE", parsed(EXPECTED_COUNT))
+            .or_else(|| check.value("log.max_size", file.log.max_size));
+        let max_size = positive(&mut check, max_size).unwrap_or(10 * 1024 * 1024);
+        let rotation = check
+            .env("SEC_MSG_LOG_ROTATE", parsed(EXPECTED_ROTATION))
+            .or_else(|| check.file("log.rotate", file.log.rotate, parsed(EXPECTED_ROTATION)))
+            .map(|rotation| rotation.value)
+            .unwrap_or(Rotation::Daily);
+        let keep = check
+            .env("SEC_MSG_LOG_KEEP", parsed(EXPECTED_NUMBER))
+            .or_else(|| check.value("log.keep", file.log.keep))
+            .map(|keep| keep.value)
+            .unwrap_or(7);
+        let log_file = match (log_file, &dirs) {
+            (Some(_), Some(dirs)) => Some(LogFile {
+                path: dirs.log(),
+                max_size,
+                rotation,
+                keep,
+            }),
+            (Some(enabled), None) => {
+                check.report(
+                    &enabled.origin,
+                    "there is no data directory to write the log file to, expected --home",
+                );
+                None
+            }
+            (None, _) => None,
+        };
+        let identity = check
+            .env("SEC_MSG_IDENTITY", |value| Ok(PathBuf::from(value)))
+            .or_else(|| check.value("identity", file.identity));
+        if let Some(identity) = identity.as_ref().filter(|path| path.value.is_dir()) {
+            check.report(
+                &identity.origin,
+                format!(
+                    "{:?} is a directory, expected the file the identity is kept in",
+                    identity.value
+                ),
+            );
+        }
+        let identity = identity.map(|path| path.value).or_else(|| {
+            dirs.as_ref()
+                .map(Dirs::identity)
+                .filter(|path| path.exists())
+        });
+        let listen_addrs = Some(&options.listen)
+            .filter(|addrs| !addrs.is_empty())
+            .map(|addrs| {
+                let origin = Origin::Cli("--listen");
+                let items = addrs
+                    .iter()
+                    .map(|addr| Setting::new(addr.clone(), origin.clone()))
+                    .collect();
+                Setting::new(items, origin)
+            })
+            .or_else(|| check.env_list("SEC_MSG_LISTEN", parsed(EXPECTED_ADDRESS)))
+            .or_else(|| check.file_list("listen", file.listen, parsed(EXPECTED_ADDRESS)));
+        let listen_addrs = match listen_addrs {
+            Some(addrs) => check_listen_addrs(&mut check, addrs),
+            None => vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid address")],
+        };
+        let bootstrap = check
+            .env_list("SEC_MSG_BOOTSTRAP", parsed(EXPECTED_ADDRESS))
+            .or_else(|| check.file_list("bootstrap", file.bootstrap, parsed(EXPECTED_ADDRESS)))
+            .map(|addrs| check_bootstrap(&mut check, addrs))
+            .unwrap_or_default();
+        let topics = Some(&options.topic)
+            .filter(|topics| !topics.is_empty())
+            .map(|topics| {
+                let origin = Origin::Cli("--topic");
+                let items = topics
+                    .iter()
+                    .map(|topic| Setting::new(topic.clone(), origin.clone()))
+                    .collect();
+                Setting::new(items, origin)
+            })
+            .or_else(|| check.env_list("SEC_MSG_TOPICS", |topic| Ok(topic.to_string())))
+            .or_else(|| check.file_list("topics", file.topics, |topic| Ok(topic.to_string())));
+        let topics = match topics {
+            Some(topics) => check_topics(&mut check, topics),
+            None => vec!["chat".to_string()],
+        };
+        if let Some(topic) = &pipe_topic {
+            if let Err(e) = topic_name(&topic.value) {
+                check.report(&topic.origin, e);
+            }
+        }
+        let download_dir = check
+            .env("SEC_MSG_DOWNLOAD_DIR", |value| Ok(PathBuf::from(value)))
+            .or_else(|| check.value("download_dir", file.download_dir));
+        if let Some(dir) = download_dir
+            .as_ref()
+            .filter(|dir| dir.value.exists() && !dir.value.is_dir())
+        {
+            check.report(
+                &dir.origin,
+                format!("{:?} is a file, expected a directory", dir.value),
+            );
+        }
+        let download_dir = download_dir
+            .map(|dir| dir.value)
+            .unwrap_or_else(|| PathBuf::from("downloads"));
+        let per_minute = check
+            .env("SEC_MSG_RATE_LIMIT_PER_MINUTE", parsed(EXPECTED_COUNT))
+            .or_else(|| check.env("SEC_MSG_RATE_LIMIT", parsed(EXPECTED_COUNT)))
+            .or_else(|| check.value("rate_limit.per_minute", file.rate_limit.per_minute));
+        let burst = check
+            .env("SEC_MSG_RATE_LIMIT_BURST", parsed(EXPECTED_COUNT))
+            .or_else(|| check.env("SEC_MSG_RATE_BURST", parsed(EXPECTED_COUNT)))
+            .or_else(|| check.value("rate_limit.burst", file.rate_limit.burst));
+        let mut rate_limits =
+            Limits::new(limit(&mut check, per_minute, burst, Limit::new(100, 20)));
+        let contact = file.rate_limit.contact;
+        let contact_per_minute = check
+            .env(
+                "SEC_MSG_RATE_LIMIT_CONTACT_PER_MINUTE",
+                parsed(EXPECTED_COUNT),
+            )
+            .or_else(|| {
+                let per_minute = contact.as_ref().and_then(|table| table.per_minute.clone());
+                check.value("rate_limit.contact.per_minute", per_minute)
+            });
+        let contact_burst = check
+            .env("SEC_MSG_RATE_LIMIT_CONTACT_BURST", parsed(EXPECTED_COUNT))
+            .or_else(|| {
+                let burst = contact.as_ref().and_then(|table| table.burst.clone());
+                check.value("rate_limit.contact.burst", burst)
+            });
+        if contact.is_some() || contact_per_minute.is_some() || contact_burst.is_some() {
+            rate_limits.contact = Some(limit(
+                &mut check,
+                contact_per_minute,
+                contact_burst,
+                rate_limits.default,
+            ));
+        }
+        rate_limits.contacts = check
+            .env_list("SEC_MSG_RATE_LIMIT_CONTACTS", parsed(EXPECTED_PEER_ID))
+            .or_else(|| {
+                check.file_list(
+                    "rate_limit.contacts",
+                    file.rate_limit.contacts,
+                    parsed::<PeerId>(EXPECTED_PEER_ID),
+                )
+            })
+            .map(|peers| peers.value.into_iter().map(|peer| peer.value).collect())
+            .unwrap_or_default();
+        match check.env_list("SEC_MSG_RATE_LIMIT_TOPICS", topic_limit) {
+            Some(topics) => {
+                for topic in topics.value {
+                    let (name, per_minute, burst) = topic.value;
+                    if let Err(e) = topic_name(&name) {
+                        check.report(&topic.origin, e);
+                    }
+                    let burst = burst.unwrap_or(rate_limits.default.burst);
+                    rate_limits
+                        .topics
+                        .insert(name, Limit::new(per_minute, burst));
+                }
+            }
+            None => {
+                for (topic, table) in file.rate_limit.topics {
+                    let key = format!("rate_limit.topics.{}", topic);
+                    if let Err(e) = topic_name(&topic) {
+                        let origin = check.in_file(&key, table.span());
+                        check.report(&origin, e);
+                    }
+                    let table = table.into_inner();
+                    let per_minute = check.value(&format!("{}.per_minute", key), table.per_minute);
+                    let burst = check.value(&format!("{}.burst", key), table.burst);
+                    let limit = limit(&mut check, per_minute, burst, rate_limits.default);
+                    rate_limits.topics.insert(topic, limit);
+                }
+            }
+        }
+        let control = file.rate_limit.control;
+        let control_per_minute = check
+            .env(
+                "SEC_MSG_RATE_LIMIT_CONTROL_PER_MINUTE",
+                parsed(EXPECTED_COUNT),
+            )
+            .or_else(|| {
+                let per_minute = control.as_ref().and_then(|table| table.per_minute.clone());
+                check.value("rate_limit.control.per_minute", per_minute)
+            });
+        let control_burst = check
+            .env("SEC_MSG_RATE_LIMIT_CONTROL_BURST", parsed(EXPECTED_COUNT))
+            .or_else(|| {
+                let burst = control.as_ref().and_then(|table| table.burst.clone());
+                check.value("rate_limit.control.burst", burst)
+            });
+        rate_limits.control = limit(
+            &mut check,
+            control_per_minute,
+            control_burst,
+            Limit::new(300, 60),
+        );
+        let rate_limit_peers = check
+            .env("SEC_MSG_RATE_LIMIT_PEERS", parsed(EXPECTED_COUNT))
+            .or_else(|| check.value("rate_limit.peers", file.rate_limit.peers));
+        let rate_limit_peers = positive(&mut check, rate_limit_peers).unwrap_or(10_000);
+        let pubsub_protocol = check
+            .env("SEC_MSG_PUBSUB", parsed(EXPECTED_PUBSUB))
+            .or_else(|| check.file("pubsub", file.pubsub, parsed(EXPECTED_PUBSUB)))
+            .map(|protocol| protocol.value)
+            .unwrap_or(PubsubProtocol::Both);
+        let outbound_rate = check
+            .env("SEC_MSG_OUTBOUND_RATE", parsed(EXPECTED_COUNT))
+            .or_else(|| check.value("outbound_rate", file.outbound_rate));
+        let outbound_rate = positive(&mut check, outbound_rate).unwrap_or(4 * 1024 * 1024);
+        let max_message_size = check
+            .env("SEC_MSG_MAX_MESSAGE_SIZE", parsed(EXPECTED_COUNT))
+            .or_else(|| check.value("max_message_size", file.max_message_size));
+        if let Some(size) = max_message_size
+            .as_ref()
+            .filter(|size| size.value > MAX_PAYLOAD_SIZE)
+        {
+            check.report(
+                &size.origin,
+                format!(
+                    "{} is too large, expected at most {} bytes",
+                    size.value, MAX_PAYLOAD_SIZE
+                ),
+            );
+        }
+        let max_message_size =
+            positive(&mut check, max_message_size).unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        let interface = check
+            .env("SEC_MSG_UI_INTERFACE", parsed(EXPECTED_INTERFACE))
+            .or_else(|| check.env("SEC_MSG_UI", parsed(EXPECTED_INTERFACE)))
+            .or_else(|| {
+                check.file(
+                    "ui.interface",
+                    file.ui.interface,
+                    parsed(EXPECTED_INTERFACE),
+                )
+            });
+        // Only the plain interface leaves stdout to pipes and JSON lines.
+        let interface = if pipe_topic.is_some() || output == Output::Json {
+            if let Some(tui) = interface.filter(|ui| ui.value == Interface::Tui) {
+                let option = if pipe_topic.is_some() {
+                    "--stdin-pipe"
+                } else {
+                    "--output json"
+                };
+                check.report(
+                    &tui.origin,
+                    format!(
+                        "the terminal UI cannot be used with {}, expected plain",
+                        option
+                    ),
+                );
+            }
+            Interface::Plain
+        } else {
+            interface.map(|ui| ui.value).unwrap_or(
+                if io::stdin().is_terminal() && io::stdout().is_terminal() {
+                    Interface::Tui
+                } else {
+                    Interface::Plain
+                },
+            )
+        };
+        let mut keymap = Keymap::default();
+        for (binding, keys) in file.ui.keys {
+            let origin = check.in_file(&format!("ui.keys.{}", binding), keys.span());
+            let pairs: Vec<String> = match keys.into_inner() {
+                Keys::One(key) => vec![key],
+                Keys::Many(keys) => keys,
+            }
+            .iter()
+            .map(|key| format!("{}={}", binding, key))
+            .collect();
+            match keymap.clone().rebind(&pairs.join(",")) {
+                Ok(rebound) => keymap = rebound,
+                Err(e) => check.report(&origin, e),
+            }
+        }
+        let rebind = |keys: &str| keymap.clone().rebind(keys);
+        if let Some(rebound) = check
+            .env("SEC_MSG_UI_KEYS", rebind)
+            .or_else(|| check.env("SEC_MSG_KEYS", rebind))
+        {
+            keymap = rebound.value;
+        }
+        let theme = check
+            .env("SEC_MSG_UI_THEME", parsed(EXPECTED_THEME))
+            .or_else(|| check.env("SEC_MSG_THEME", parsed(EXPECTED_THEME)))
+            .or_else(|| check.file("ui.theme", file.ui.theme, parsed(EXPECTED_THEME)))
+            .map(|theme| theme.value)
+            .unwrap_or(ThemeName::Default);
+        let notifications = check
+            .env("SEC_MSG_NOTIFICATIONS", switch)
+            .map(|notifications| notifications.value)
+            .or(file.notifications)
+            .unwrap_or(true);
+        let keywords = check
+            .env_list("SEC_MSG_KEYWORDS", |keyword| Ok(keyword.to_string()))
+            .map(|keywords| keywords.value.into_iter().map(|k| k.value).collect())
+            .or(file.keywords)
+            .unwrap_or_default();
+        let message_format = MessageFormat {
+            clock: check
+                .env("SEC_MSG_UI_CLOCK", parsed(EXPECTED_CLOCK))
+                .or_else(|| check.env("SEC_MSG_CLOCK", parsed(EXPECTED_CLOCK)))
+                .or_else(|| check.file("ui.clock", file.ui.clock, parsed(EXPECTED_CLOCK)))
+                .map(|clock| clock.value)
+                .unwrap_or(Clock::H24),
+            peer_suffix: check
+                .env("SEC_MSG_UI_PEER_SUFFIX", switch)
+                .or_else(|| check.env("SEC_MSG_PEER_SUFFIX", switch))
+                .map(|suffix| suffix.value)
+                .or(file.ui.peer_suffix)
+                .unwrap_or(false),
+        };
+        let encrypt_storage = check
+            .env("SEC_MSG_STORAGE_ENCRYPT", switch)
+            .map(|encrypt| encrypt.value)
+            .or(file.storage.encrypt)
+            .unwrap_or(false);
+        let connection = check_connection(&mut check, file.connection);
+        let content_filter = check_filter(&mut check, file.filter);
+        let email = check_email(&mut check, file.email);
+        check.finish()?;
+        Ok(Config {
+            log_level,
+            log_file,
+            identity,
+            dirs,
+            listen_addrs,
+            bootstrap,
+            topics,
+            download_dir,
+            rate_limits,
+            rate_limit_peers,
+            pubsub_protocol,
+            outbound_rate,
+            max_message_size,
+            interface,
+            keymap,
+            theme,
+            notifications,
+            keywords,
+            message_format,
+            encrypt_storage,
+            connection,
+            content_filter,
+            pipe_topic: pipe_topic.map(|topic| topic.value),
+            output,
+            email,
+        })
+    }
+}
+
+/// Environment variables read, each named after the key of the
+/// configuration file it overrides, along with the shorter names they had
+/// before, the ones locating the file and the passphrase.
+const ENV_VARS: [&str; 58] = [
+    "SEC_MSG_CONFIG",
+    "SEC_MSG_HOME",
+    "SEC_MSG_PASSPHRASE",
+    "SEC_MSG_HTTP_TOKEN",
+    PASSWORD_VAR,
+    KEY_PASSPHRASE_VAR,
+    webhook::SECRET_VAR,
+    "SEC_MSG_LOG_LEVEL",
+    "SEC_MSG_LOG_FILE",
+    "SEC_MSG_LOG_MAX_SIZE",
+    "SEC_MSG_LOG_ROTATE",
+    "SEC_MSG_LOG_KEEP",
+    "SEC_MSG_IDENTITY",
+    "SEC_MSG_LISTEN",
+    "SEC_MSG_BOOTSTRAP",
+    "SEC_MSG_TOPICS",
+    "SEC_MSG_DOWNLOAD_DIR",
+    "SEC_MSG_PUBSUB",
+    "SEC_MSG_OUTBOUND_RATE",
+    "SEC_MSG_MAX_MESSAGE_SIZE",
+    "SEC_MSG_NOTIFICATIONS",
+    "SEC_MSG_KEYWORDS",
+    "SEC_MSG_RATE_LIMIT_PER_MINUTE",
+    "SEC_MSG_RATE_LIMIT_BURST",
+    "SEC_MSG_RATE_LIMIT_PEERS",
+    "SEC_MSG_RATE_LIMIT_CONTACTS",
+    "SEC_MSG_RATE_LIMIT_CONTACT_PER_MINUTE",
+    "SEC_MSG_RATE_LIMIT_CONTACT_BURST",
+    "SEC_MSG_RATE_LIMIT_CONTROL_PER_MINUTE",
+    "SEC_MSG_RATE_LIMIT_CONTROL_BURST",
+    "SEC_MSG_RATE_LIMIT_TOPICS",
+    "SEC_MSG_STORAGE_ENCRYPT",
+    "SEC_MSG_CONNECTION_IDLE_TIMEOUT",
+    "SEC_MSG_CONNECTION_KEEP_ALIVE",
+    "SEC_MSG_CONNECTION_DIAL_TIMEOUT",
+    "SEC_MSG_CONNECTION_EVENT_BUFFER",
+    "SEC_MSG_FILTER_PATTERNS",
+    "SEC_MSG_FILTER_ACTION",
+    "SEC_MSG_FILTER_COMMAND",
+    "SEC_MSG_UI_INTERFACE",
+    "SEC_MSG_UI_THEME",
+    "SEC_MSG_UI_CLOCK",
+    "SEC_MSG_UI_PEER_SUFFIX",
+    "SEC_MSG_UI_KEYS",
+    "SEC_MSG_EMAIL_ADDRESS",
+    "SEC_MSG_EMAIL_SMTP",
+    "SEC_MSG_EMAIL_IMAP",
+    "SEC_MSG_EMAIL_USER",
+    "SEC_MSG_EMAIL_CONTACTS",
+    "SEC_MSG_EMAIL_KEYS",
+    "SEC_MSG_EMAIL_SECRET_KEY",
+    "SEC_MSG_RATE_LIMIT",
+    "SEC_MSG_RATE_BURST",
+    "SEC_MSG_UI",
+    "SEC_MSG_THEME",
+    "SEC_MSG_CLOCK",
+    "SEC_MSG_PEER_SUFFIX",
+    "SEC_MSG_KEYS",
+];
+
+/// Longest dial timeout, in seconds.
+const MAX_DIAL_TIMEOUT: u64 = 3600;
+
+/// What an environment variable of the application expects.
+const EXPECTED_ENV: &str =
+    "SEC_MSG_ followed by a key of the configuration file, such as SEC_MSG_UI_THEME";
+
+/// What the limit of a topic in the environment expects.
+const EXPECTED_TOPIC_LIMIT: &str = "topic=per_minute or topic=per_minute/burst";
+
+/// What a filter pattern expects.
+const EXPECTED_PATTERN: &str = "a regular expression";
+
+/// What the filter action expects.
+const EXPECTED_FILTER_ACTION: &str = "drop, flag or quarantine";
+
+/// What an address setting expects.
+const EXPECTED_ADDRESS: &str = "an address such as /ip4/0.0.0.0/tcp/4001";
+
+/// What a count setting expects.
+const EXPECTED_COUNT: &str = "a positive whole number";
+
+/// What a number setting that may be zero expects.
+const EXPECTED_NUMBER: &str = "a whole number";
+
+/// What the rotation of the log file expects.
+const EXPECTED_ROTATION: &str = "hourly, daily or never";
+
+/// What a peer ID setting expects.
+const EXPECTED_PEER_ID: &str = "a peer ID such as 12D3KooW...";
+
+/// What the pubsub setting expects.
+const EXPECTED_PUBSUB: &str = "floodsub, gossipsub or both";
+
+/// What the interface setting expects.
+const EXPECTED_INTERFACE: &str = "tui or plain";
+
+/// What the theme setting expects.
+const EXPECTED_THEME: &str = "default, high-contrast or monochrome";
+
+/// What the clock setting expects.
+const EXPECTED_CLOCK: &str = "24h or 12h";
+
+/// What an email address setting expects.
+const EXPECTED_EMAIL: &str = "an email address such as me@example.com";
+
+/// What the SMTP server expects.
+const EXPECTED_SMTP: &str = "a URL such as smtps://smtp.example.com";
+
+/// What the IMAP server expects.
+const EXPECTED_IMAP: &str = "a URL such as imaps://imap.example.com";
+
+/// What the address of a contact in the environment expects.
+const EXPECTED_EMAIL_CONTACT: &str = "peer=address, such as 12D3KooW...=alice@example.com";
+
+/// What the key of a contact in the environment expects.
+const EXPECTED_EMAIL_KEY: &str = "peer=path, such as 12D3KooW...=/path/to/alice.asc";
+
+/// Checks the addresses listened on, which the transport must support.
+fn check_listen_addrs(
+    check: &mut Checker,
+    addrs: Setting<Vec<Setting<Multiaddr>>>,
+) -> Vec<Multiaddr> {
+    if addrs.value.is_empty() {
+        check.report(
+            &addrs.origin,
+            "no address to listen on, expected at least one",
+        );
     }
+    let mut listen_addrs: Vec<Multiaddr> = Vec::new();
+    for addr in addrs.value {
+        if !is_tcp(&addr.value) {
+            check.report(
+                &addr.origin,
+                format!(
+                    "cannot listen on {}, expected an IP address and TCP port such as /ip4/0.0.0.0/tcp/4001",
+                    addr.value
+                ),
+            );
+        } else if listen_addrs.contains(&addr.value) {
+            check.report(&addr.origin, format!("{} is listed twice", addr.value));
+        } else {
+            listen_addrs.push(addr.value);
+        }
+    }
+    listen_addrs
+}
+
+/// Checks the addresses of the bootstrap peers, which must be dialable.
+fn check_bootstrap(check: &mut Checker, addrs: Setting<Vec<Setting<Multiaddr>>>) -> Vec<Multiaddr> {
+    let mut bootstrap = Vec::new();
+    for addr in addrs.value {
+        if !is_tcp(&addr.value) {
+            check.report(
+                &addr.origin,
+                format!(
+                    "cannot dial {}, expected an IP address and TCP port such as /ip4/203.0.113.7/tcp/4001",
+                    addr.value
+                ),
+            );
+        } else if tcp_port(&addr.value) == Some(0) {
+            check.report(
+                &addr.origin,
+                format!(
+                    "cannot dial port 0 of {}, expected the port the peer listens on",
+                    addr.value
+                ),
+            );
+        } else {
+            bootstrap.push(addr.value);
+        }
+    }
+    bootstrap
+}
+
+/// Checks the topics joined at startup.
+fn check_topics(check: &mut Checker, topics: Setting<Vec<Setting<String>>>) -> Vec<String> {
+    if topics.value.is_empty() {
+        check.report(&topics.origin, "no topic to join, expected at least one");
+    }
+    let mut names: Vec<String> = Vec::new();
+    for topic in topics.value {
+        if let Err(e) = topic_name(&topic.value) {
+            check.report(&topic.origin, e);
+        } else if names.contains(&topic.value) {
+            check.report(&topic.origin, format!("{:?} is listed twice", topic.value));
+        } else {
+            names.push(topic.value);
+        }
+    }
+    names
+}
+
+/// Checks that a topic can be joined.
+fn topic_name(topic: &str) -> Result<(), String> {
+    if topic.is_empty() || topic.contains(char::is_whitespace) {
+        Err(format!(
+            "{:?} is invalid, expected a topic name without spaces",
+            topic
+        ))
+    } else if topic == PRESENCE_TOPIC || is_inbox_topic(topic) || is_note_topic(topic) {
+        Err(format!(
+            "{:?} is reserved, expected another topic name",
+            topic
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that a count is positive.
+fn positive<T>(check: &mut Checker, count: Option<Setting<T>>) -> Option<T>
+where
+    T: Default + PartialEq + Display,
+{
+    let count = count?;
+    if count.value == T::default() {
+        check.report(
+            &count.origin,
+            format!("{} is too small, expected {}", count.value, EXPECTED_COUNT),
+        );
+        return None;
+    }
+    Some(count.value)
+}
+
+/// Checks the limits of a class of peers, the defaults filling in the
+/// values left out.
+fn limit(
+    check: &mut Checker,
+    per_minute: Option<Setting<u32>>,
+    burst: Option<Setting<u32>>,
+    default: Limit,
+) -> Limit {
+    Limit::new(
+        positive(check, per_minute).unwrap_or(default.per_minute),
+        positive(check, burst).unwrap_or(default.burst),
+    )
+}
+
+/// Checks how long connections are kept.
+fn check_connection(check: &mut Checker, file: ConnectionSection) -> ConnectionSettings {
+    let default = ConnectionSettings::default();
+    let idle_timeout = check
+        .env("SEC_MSG_CONNECTION_IDLE_TIMEOUT", parsed(EXPECTED_COUNT))
+        .or_else(|| check.value("connection.idle_timeout", file.idle_timeout));
+    let keep_alive = check
+        .env("SEC_MSG_CONNECTION_KEEP_ALIVE", switch)
+        .map(|keep_alive| keep_alive.value)
+        .or(file.keep_alive)
+        .unwrap_or(default.keep_alive);
+    let dial_timeout = check
+        .env("SEC_MSG_CONNECTION_DIAL_TIMEOUT", parsed(EXPECTED_COUNT))
+        .or_else(|| check.value("connection.dial_timeout", file.dial_timeout));
+    // Dials are timed from now, which a longer timeout would overflow.
+    if let Some(timeout) = dial_timeout
+        .as_ref()
+        .filter(|timeout| timeout.value > MAX_DIAL_TIMEOUT)
+    {
+        check.report(
+            &timeout.origin,
+            format!(
+                "{} is too large, expected at most {} seconds",
+                timeout.value, MAX_DIAL_TIMEOUT
+            ),
+        );
+    }
+    let event_buffer = check
+        .env("SEC_MSG_CONNECTION_EVENT_BUFFER", parsed(EXPECTED_NUMBER))
+        .or_else(|| check.value("connection.event_buffer", file.event_buffer));
+    ConnectionSettings {
+        idle_timeout: positive(check, idle_timeout)
+            .map(Duration::from_secs)
+            .unwrap_or(default.idle_timeout),
+        dial_timeout: positive(check, dial_timeout)
+            .map(Duration::from_secs)
+            .unwrap_or(default.dial_timeout),
+        keep_alive,
+        event_buffer: event_buffer
+            .map(|buffer| buffer.value)
+            .unwrap_or(default.event_buffer),
+    }
+}
+
+/// Checks the content filters of inbound messages.
+fn check_filter(check: &mut Checker, file: FilterSection) -> FilterSettings {
+    let default = FilterSettings::default();
+    let patterns = check
+        .env_list("SEC_MSG_FILTER_PATTERNS", parsed(EXPECTED_PATTERN))
+        .or_else(|| check.file_list("filter.patterns", file.patterns, parsed(EXPECTED_PATTERN)))
+        .map(|patterns| patterns.value.into_iter().map(|p| p.value).collect())
+        .unwrap_or(default.patterns);
+    let action = check
+        .env(
+            "SEC_MSG_FILTER_ACTION",
+            parsed::<FilterAction>(EXPECTED_FILTER_ACTION),
+        )
+        .or_else(|| check.file("filter.action", file.action, parsed(EXPECTED_FILTER_ACTION)))
+        .map(|action| action.value)
+        .unwrap_or(default.action);
+    let command = check
+        .env_list("SEC_MSG_FILTER_COMMAND", |arg| Ok(arg.to_string()))
+        .or_else(|| check.file_list("filter.command", file.command, |arg| Ok(arg.to_string())))
+        .map(|command| command.value.into_iter().map(|arg| arg.value).collect())
+        .filter(|command: &Vec<String>| !command.is_empty());
+    FilterSettings {
+        patterns,
+        action,
+        command,
+    }
+}
+
+/// Checks the settings of the email gateway, which is on when an SMTP
+/// server is set.
+fn check_email(check: &mut Checker, file: EmailSection) -> Option<EmailSettings> {
+    let address = check
+        .env("SEC_MSG_EMAIL_ADDRESS", email_address)
+        .or_else(|| check.file("email.address", file.address, email_address));
+    let smtp = check
+        .env(
+            "SEC_MSG_EMAIL_SMTP",
+            server(&["smtp", "smtps"], EXPECTED_SMTP),
+        )
+        .or_else(|| {
+            let parse = server(&["smtp", "smtps"], EXPECTED_SMTP);
+            check.file("email.smtp", file.smtp, parse)
+        });
+    let imap = check
+        .env(
+            "SEC_MSG_EMAIL_IMAP",
+            server(&["imap", "imaps"], EXPECTED_IMAP),
+        )
+        .or_else(|| {
+            let parse = server(&["imap", "imaps"], EXPECTED_IMAP);
+            check.file("email.imap", file.imap, parse)
+        });
+    let user = check
+        .env("SEC_MSG_EMAIL_USER", |user| Ok(user.to_string()))
+        .or_else(|| check.value("email.user", file.user));
+    let contacts = match check.env_list("SEC_MSG_EMAIL_CONTACTS", email_contact) {
+        Some(contacts) => contacts.value.into_iter().map(|c| c.value).collect(),
+        None => {
+            let mut contacts = BTreeMap::new();
+            for (peer, address) in file.contacts {
+                let origin = check.in_file(&format!("email.contacts.{}", peer), address.span());
+                let peer = parsed::<PeerId>(EXPECTED_PEER_ID)(&peer);
+                match (peer, email_address(address.get_ref())) {
+                    (Ok(peer), Ok(address)) => {
+                        contacts.insert(peer, address);
+                    }
+                    (Err(e), _) | (_, Err(e)) => check.report(&origin, e),
+                }
+            }
+            contacts
+        }
+    };
+    let keys = match check.env_list("SEC_MSG_EMAIL_KEYS", email_key) {
+        Some(keys) => keys.value.into_iter().map(|key| key.value).collect(),
+        None => {
+            let mut keys = BTreeMap::new();
+            for (peer, path) in file.keys {
+                let origin = check.in_file(&format!("email.keys.{}", peer), path.span());
+                match parsed::<PeerId>(EXPECTED_PEER_ID)(&peer) {
+                    Ok(peer) => {
+                        keys.insert(peer, path.into_inner());
+                    }
+                    Err(e) => check.report(&origin, e),
+                }
+            }
+            keys
+        }
+    };
+    let secret_key = check
+        .env("SEC_MSG_EMAIL_SECRET_KEY", |path| Ok(PathBuf::from(path)))
+        .or_else(|| check.value("email.secret_key", file.secret_key));
+    let smtp = match (smtp, imap.as_ref()) {
+        (Some(smtp), _) => smtp,
+        (None, Some(imap)) => {
+            check.report(
+                &imap.origin,
+                "replies are only read with the gateway on, expected email.smtp",
+            );
+            return None;
+        }
+        (None, None) => return None,
+    };
+    let Some(address) = address else {
+        check.report(
+            &smtp.origin,
+            "no address to send email from, expected email.address",
+        );
+        return None;
+    };
+    Some(EmailSettings {
+        user: user.map(|user| user.value).unwrap_or(address.value.clone()),
+        address: address.value,
+        smtp: smtp.value,
+        imap: imap.map(|imap| imap.value),
+        contacts,
+        keys,
+        secret_key: secret_key.map(|path| path.value),
+    })
+}
+
+/// Checks an email address, loosely.
+fn email_address(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    match value.split_once('@') {
+        Some((user, domain))
+            if !user.is_empty() && domain.contains('.') && !value.contains(char::is_whitespace) =>
+        {
+            Ok(value.to_string())
+        }
+        _ => Err(format!(
+            "{:?} is invalid, expected {}",
+            value, EXPECTED_EMAIL
+        )),
+    }
+}
+
+/// Returns a parser for the URL of a mail server with one of the schemes.
+fn server(
+    schemes: &'static [&'static str],
+    expected: &'static str,
+) -> impl Fn(&str) -> Result<String, String> {
+    move |value| {
+        let value = value.trim();
+        match value.split_once("://") {
+            Some((scheme, host)) if schemes.contains(&scheme) && !host.is_empty() => {
+                Ok(value.to_string())
+            }
+            _ => Err(format!("{:?} is invalid, expected {}", value, expected)),
+        }
+    }
+}
+
+/// Parses the address of a contact in the environment, such as
+/// `12D3KooW...=alice@example.com`.
+fn email_contact(value: &str) -> Result<(PeerId, String), String> {
+    let invalid = || {
+        format!(
+            "{:?} is invalid, expected {}",
+            value, EXPECTED_EMAIL_CONTACT
+        )
+    };
+    let (peer, address) = value.split_once('=').ok_or_else(invalid)?;
+    let peer = peer.trim().parse().map_err(|_| invalid())?;
+    Ok((peer, email_address(address)?))
+}
+
+/// Parses the key of a contact in the environment, such as
+/// `12D3KooW...=/path/to/alice.asc`.
+fn email_key(value: &str) -> Result<(PeerId, PathBuf), String> {
+    let invalid = || format!("{:?} is invalid, expected {}", value, EXPECTED_EMAIL_KEY);
+    let (peer, path) = value.split_once('=').ok_or_else(invalid)?;
+    let peer = peer.trim().parse().map_err(|_| invalid())?;
+    Ok((peer, PathBuf::from(path.trim())))
+}
+
+/// Parses the limits of a topic in the environment, such as
+/// `announcements=10/2`.
+fn topic_limit(value: &str) -> Result<(String, u32, Option<u32>), String> {
+    let invalid = || format!("{:?} is invalid, expected {}", value, EXPECTED_TOPIC_LIMIT);
+    let count = |count: &str| {
+        count
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(invalid)
+    };
+    let (topic, limit) = value.split_once('=').ok_or_else(invalid)?;
+    let (per_minute, burst) = match limit.split_once('/') {
+        Some((per_minute, burst)) => (count(per_minute)?, Some(count(burst)?)),
+        None => (count(limit)?, None),
+    };
+    Ok((topic.trim().to_string(), per_minute, burst))
+}
+
+/// Returns the configuration file given on the command line or in the
+/// environment.
+fn explicit_path(options: &Options) -> Option<PathBuf> {
+    options
+        .config
+        .clone()
+        .or_else(|| env::var_os("SEC_MSG_CONFIG").map(PathBuf::from))
+}
+
+/// Returns the directory of a portable install, given on the command line
+/// or in the environment.
+fn home(options: &Options) -> Option<PathBuf> {
+    options
+        .home
+        .clone()
+        .or_else(|| env::var_os("SEC_MSG_HOME").map(PathBuf::from))
+}
+
+/// Writes a configuration file listing every setting, for `config init`.
+///
+/// # Arguments
+///
+/// * `options` - The command line options, which may name the file.
+/// * `force` - Whether an existing file is replaced.
+///
+/// # Returns
+///
+/// A `Result` containing the path of the file written.
+pub fn init(options: &Options, force: bool) -> Result<PathBuf, Box<dyn Error>> {
+    let path = explicit_path(options)
+        .or_else(|| Dirs::new(home(options).as_deref()).map(|dirs| dirs.config_file()))
+        .ok_or("No configuration directory, pass --config <path>")?;
+    write_template(&path, force)?;
+    Ok(path)
+}
+
+/// Writes the configuration template to a file.
+fn write_template(path: &Path, force: bool) -> Result<(), Box<dyn Error>> {
+    if path.exists() && !force {
+        return Err(format!("{:?} already exists, pass --force to replace it", path).into());
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, TEMPLATE)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use super::*;
 
     #[test]
     fn test_new_config() {
-        let config = Config::new();
+        let config = Config::layer(
+            ConfigFile::default(),
+            None,
+            BTreeMap::new(),
+            &Options::default(),
+            None,
+        )
+        .unwrap();
         assert_eq!(config.log_level, "info");
+        assert_eq!(config.identity, None);
+        assert_eq!(config.dirs, None);
+        assert_eq!(
+            config.listen_addrs,
+            vec!["/ip4/0.0.0.0/tcp/0".parse::<Multiaddr>().unwrap()]
+        );
+        assert!(config.bootstrap.is_empty());
+        assert_eq!(config.topics, vec!["chat".to_string()]);
+        assert_eq!(config.download_dir, std::path::PathBuf::from("downloads"));
+        let mut rate_limits = Limits::new(Limit::new(100, 20));
+        rate_limits.control = Limit::new(300, 60);
+        assert_eq!(config.rate_limits, rate_limits);
+        assert_eq!(config.rate_limit_peers, 10_000);
+        assert_eq!(config.pubsub_protocol, PubsubProtocol::Both);
+        assert_eq!(config.outbound_rate, 4 * 1024 * 1024);
+        assert_eq!(config.max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(config.keymap, Keymap::default());
+        assert_eq!(config.theme, ThemeName::Default);
+        assert!(config.notifications);
+        assert!(config.keywords.is_empty());
+        assert_eq!(config.message_format.clock, Clock::H24);
+        assert!(!config.message_format.peer_suffix);
+        assert!(!config.encrypt_storage);
+        assert_eq!(config.pipe_topic, None);
+        assert_eq!(config.output, Output::Text);
+        assert_eq!(config.email, None);
+    }
+
+    #[test]
+    fn test_config_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            identity = "/tmp/sec_msg.key"
+            listen = ["/ip4/0.0.0.0/tcp/4001"]
+            topics = ["general"]
+            keywords = ["release"]
+
+            [rate_limit]
+            burst = 5
+            contacts = ["12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE"]
+            contact = { per_minute = 600 }
+            topics = { announcements = { burst = 1 } }
+            control = { burst = 10 }
+
+            [connection]
+            idle_timeout = 3600
+            keep_alive = true
+
+            [filter]
+            patterns = ["(?i)buy now"]
+            action = "quarantine"
+
+            [ui]
+            theme = "monochrome"
+            clock = "12h"
+            keys = { quit = "ctrl-q", next = ["ctrl-n", "alt-n"] }
+
+            [email]
+            address = "me@example.com"
+            smtp = "smtp://localhost:2525"
+            user = "me"
+            contacts = { "12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE" = "alice@example.com" }
+            "#,
+        )
+        .unwrap();
+        let options = Options {
+            output: Some(Output::Json),
+            topic: vec!["rust".to_string(), "chat".to_string()],
+            ..Options::default()
+        };
+        let config = Config::layer(file, None, BTreeMap::new(), &options, None).unwrap();
+        assert_eq!(config.identity, Some(PathBuf::from("/tmp/sec_msg.key")));
+        assert_eq!(
+            config.listen_addrs,
+            vec!["/ip4/0.0.0.0/tcp/4001".parse::<Multiaddr>().unwrap()]
+        );
+        assert_eq!(config.topics, vec!["rust".to_string(), "chat".to_string()]);
+        assert_eq!(config.keywords, vec!["release".to_string()]);
+        assert_eq!(config.rate_limits.default, Limit::new(100, 5));
+        assert_eq!(config.rate_limits.contact, Some(Limit::new(600, 5)));
+        assert_eq!(config.rate_limits.contacts.len(), 1);
+        assert_eq!(
+            config.rate_limits.topics.get("announcements"),
+            Some(&Limit::new(100, 1))
+        );
+        assert_eq!(config.rate_limits.control, Limit::new(300, 10));
+        assert_eq!(config.content_filter.patterns.len(), 1);
+        assert_eq!(config.content_filter.action, FilterAction::Quarantine);
+        assert_eq!(config.content_filter.command, None);
+        assert_eq!(config.theme, ThemeName::Monochrome);
+        assert_eq!(config.message_format.clock, Clock::H12);
+        assert_ne!(config.keymap, Keymap::default());
+        assert_eq!(config.connection.idle_timeout, Duration::from_secs(3600));
+        assert!(config.connection.keep_alive);
+        assert_eq!(
+            config.connection.dial_timeout,
+            ConnectionSettings::default().dial_timeout
+        );
+        // The command line still forces the plain interface.
+        assert_eq!(config.output, Output::Json);
+        assert_eq!(config.interface, Interface::Plain);
+        let email = config.email.unwrap();
+        assert_eq!(email.user, "me");
+        assert_eq!(email.imap, None);
+        assert_eq!(
+            email.contacts.values().collect::<Vec<_>>(),
+            vec!["alice@example.com"]
+        );
+
+        let invalid = |text: &str| {
+            toml::from_str::<ConfigFile>(text)
+                .map_err(|e| e.to_string())
+                .and_then(|file| {
+                    Config::layer(file, None, BTreeMap::new(), &Options::default(), None)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .is_err()
+        };
+        assert!(invalid("listen = [\"not an address\"]"));
+        assert!(invalid("[ui]\ntheme = \"neon\""));
+        assert!(invalid("[ui]\nkeys = { jump = \"ctrl-j\" }"));
+        assert!(invalid("topics = []"));
+        assert!(invalid("unknown = 1"));
+        assert!(invalid("listen = [\"/dns4/example.org/tcp/4001\"]"));
+        assert!(invalid("bootstrap = [\"/ip4/127.0.0.1/tcp/0\"]"));
+        assert!(invalid("topics = [\"presence\"]"));
+        assert!(invalid("[rate_limit]\nburst = 0"));
+        assert!(invalid("[rate_limit]\ncontacts = [\"alice\"]"));
+        assert!(invalid("[rate_limit.topics.\"dm/someone\"]\nburst = 2"));
+        assert!(invalid("[rate_limit.contact]\nper_minute = 0"));
+        assert!(invalid("[rate_limit.contact]\nrate = 10"));
+        assert!(invalid("log_level = \"sec_msg=loud\""));
+        assert!(invalid("[filter]\npatterns = [\"(unclosed\"]"));
+        assert!(invalid("[filter]\naction = \"ban\""));
+        assert!(invalid("[email]\nsmtp = \"smtps://smtp.example.com\""));
+        assert!(invalid(
+            "[email]\naddress = \"me\"\nsmtp = \"smtps://smtp.example.com\""
+        ));
+        assert!(invalid("[email]\nimap = \"imaps://imap.example.com\""));
+        assert!(invalid(
+            "[email]\naddress = \"me@example.com\"\nsmtp = \"https://smtp.example.com\""
+        ));
+        assert!(invalid("[email.contacts]\nalice = \"alice@example.com\""));
+    }
+
+    #[test]
+    fn test_validation() {
+        let text = r#"topics = ["chat", "dm/someone", "chat"]
+outbound_rate = 0
+max_message_size = 100000000
+
+[log]
+file = true
+
+[ui]
+interface = "tui"
+keys = { quit = "ctrl-q", jump = "ctrl-j" }
+
+[connection]
+dial_timeout = 7200
+"#;
+        let file: ConfigFile = toml::from_str(text).unwrap();
+        let options = Options {
+            stdin_pipe: Some("two words".to_string()),
+            ..Options::default()
+        };
+        let error = Config::layer(
+            file,
+            Some((Path::new("sec_msg.toml"), text)),
+            BTreeMap::new(),
+            &options,
+            None,
+        )
+        .err()
+        .unwrap();
+        // Every problem is listed at once, with where it was set.
+        let problems: Vec<String> = error.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "sec_msg.toml:6: log.file: there is no data directory to write the log file to, expected --home",
+                "sec_msg.toml:1: topics: \"dm/someone\" is reserved, expected another topic name",
+                "sec_msg.toml:1: topics: \"chat\" is listed twice",
+                "option --stdin-pipe: \"two words\" is invalid, expected a topic name without spaces",
+                "sec_msg.toml:2: outbound_rate: 0 is too small, expected a positive whole number",
+                "sec_msg.toml:3: max_message_size: 100000000 is too large, expected at most 14680064 bytes",
+                "sec_msg.toml:9: ui.interface: the terminal UI cannot be used with --stdin-pipe, expected plain",
+                "sec_msg.toml:10: ui.keys.jump: unknown binding \"jump\"",
+                "sec_msg.toml:13: connection.dial_timeout: 7200 is too large, expected at most 3600 seconds",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_environment() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            topics = ["general"]
+
+            [rate_limit]
+            per_minute = 30
+            topics = { announcements = { burst = 1 } }
+
+            [ui]
+            theme = "monochrome"
+            "#,
+        )
+        .unwrap();
+        let env = BTreeMap::from(
+            [
+                ("SEC_MSG_TOPICS", "rust, chat"),
+                ("SEC_MSG_LOG_LEVEL", "debug"),
+                ("RUST_LOG", "trace"),
+                ("SEC_MSG_RATE_LIMIT_BURST", "5"),
+                ("SEC_MSG_RATE_LIMIT_CONTACT_PER_MINUTE", "600"),
+                ("SEC_MSG_RATE_LIMIT_TOPICS", "alerts=10/2,rust=20"),
+                ("SEC_MSG_UI_THEME", "high-contrast"),
+                ("SEC_MSG_THEME", "default"),
+                ("SEC_MSG_EMAIL_ADDRESS", "me@example.com"),
+                ("SEC_MSG_EMAIL_SMTP", "smtps://smtp.example.com"),
+                (
+                    "SEC_MSG_EMAIL_CONTACTS",
+                    "12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE=alice@example.com",
+                ),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        let options = Options {
+            topic: vec!["cli".to_string()],
+            ..Options::default()
+        };
+        let config = Config::layer(file, None, env, &options, None).unwrap();
+        // The command line overrides the environment, which overrides the
+        // file, the names of the keys winning over the shorter ones.
+        assert_eq!(config.topics, vec!["cli".to_string()]);
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.theme, ThemeName::HighContrast);
+        assert_eq!(config.rate_limits.default, Limit::new(30, 5));
+        assert_eq!(config.rate_limits.contact, Some(Limit::new(600, 5)));
+        assert_eq!(
+            config.rate_limits.topics,
+            HashMap::from([
+                ("alerts".to_string(), Limit::new(10, 2)),
+                ("rust".to_string(), Limit::new(20, 5)),
+            ])
+        );
+        let email = config.email.unwrap();
+        assert_eq!(email.user, "me@example.com");
+        assert_eq!(email.contacts.len(), 1);
+
+        let env = BTreeMap::from(
+            [
+                ("SEC_MSG_LISTEN_ADDR", "/ip4/0.0.0.0/tcp/4001"),
+                ("SEC_MSG_RATE_LIMIT_TOPICS", "alerts=10/0,presence=5"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        let error = Config::layer(ConfigFile::default(), None, env, &Options::default(), None)
+            .err()
+            .unwrap();
+        let problems: Vec<String> = error.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "environment variable SEC_MSG_LISTEN_ADDR: unknown setting, expected SEC_MSG_ followed by a key of the configuration file, such as SEC_MSG_UI_THEME",
+                "environment variable SEC_MSG_RATE_LIMIT_TOPICS: \"alerts=10/0\" is invalid, expected topic=per_minute or topic=per_minute/burst",
+                "environment variable SEC_MSG_RATE_LIMIT_TOPICS: \"presence\" is reserved, expected another topic name",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dirs_identity() {
+        let home = std::env::temp_dir().join(format!("sec_msg-home-{}", std::process::id()));
+        let dirs = Dirs::new(Some(&home));
+        let layer = || {
+            Config::layer(
+                ConfigFile::default(),
+                None,
+                BTreeMap::new(),
+                &Options::default(),
+                dirs.clone(),
+            )
+        };
+        // The identity in the data directory is only used once created.
+        assert_eq!(layer().unwrap().identity, None);
+        fs::create_dir_all(&home).unwrap();
+        fs::write(home.join("identity.key"), b"").unwrap();
+        assert_eq!(layer().unwrap().identity, Some(home.join("identity.key")));
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_template() {
+        // The template changes nothing until settings are uncommented.
+        let file: ConfigFile = toml::from_str(TEMPLATE).unwrap();
+        assert!(file.topics.is_none() && file.ui.keys.is_empty());
+        let uncommented: String = TEMPLATE
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting) if setting.contains(" = ") => setting,
+                _ => line,
+            })
+            .fold(String::new(), |text, line| text + line + "\n");
+        let file: ConfigFile = toml::from_str(&uncommented).unwrap();
+        let config = Config::layer(file, None, BTreeMap::new(), &Options::default(), None).unwrap();
+        assert_eq!(config.listen_addrs.len(), 1);
+        assert_eq!(config.keymap, Keymap::default());
+        let email = config.email.unwrap();
+        assert_eq!(email.user, "me@example.com");
+        assert!(email.contacts.is_empty());
+
+        let path = std::env::temp_dir()
+            .join(format!("sec_msg-{}", std::process::id()))
+            .join("config.toml");
+        write_template(&path, false).unwrap();
+        assert!(write_template(&path, false).is_err());
+        write_template(&path, true).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
     }
 }
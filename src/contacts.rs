@@ -0,0 +1,248 @@
+/*!
+ * Contacts module for the messaging application.
+ *
+ * Contacts are peers the user knows, kept between runs under an alias of
+ * the user's choosing along with their public key, notes and how much they
+ * are trusted. Unlike display names, which peers announce themselves,
+ * aliases are set locally, so messages of a contact are always shown under
+ * the alias the user gave them.
+ *
+ * The contact list is written to the data directory on every change, and
+ * sealed like the identity when storage is encrypted.
+ */
+
+use std::{collections::BTreeMap, error::Error, fmt, path::PathBuf, str::FromStr, sync::Arc};
+
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::profile::{Profile, MAX_NAME_LEN};
+use crate::storage::{self, Vault};
+
+/// How much a contact is trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trust {
+    Untrusted,
+    /// Known, but the fingerprint was not compared yet.
+    Unverified,
+    /// The fingerprint was compared with the peer out of band.
+    Verified,
+}
+
+impl FromStr for Trust {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "untrusted" => Ok(Trust::Untrusted),
+            "unverified" => Ok(Trust::Unverified),
+            "verified" => Ok(Trust::Verified),
+            _ => Err(format!(
+                "Unknown trust level: {} (untrusted, unverified or verified)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trust::Untrusted => write!(f, "untrusted"),
+            Trust::Unverified => write!(f, "unverified"),
+            Trust::Verified => write!(f, "verified"),
+        }
+    }
+}
+
+/// A peer known to the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub peer: PeerId,
+    pub alias: String,
+    /// The protobuf encoded public key, if the peer ID embeds it.
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    pub notes: String,
+    pub trust: Trust,
+}
+
+/// The contacts of the user, by peer ID.
+pub struct Contacts {
+    contacts: BTreeMap<PeerId, Contact>,
+    /// The file the contacts are saved to, and the vault sealing it.
+    file: Option<(PathBuf, Option<Arc<Vault>>)>,
+}
+
+impl Contacts {
+    /// Creates a new, empty contact list kept in memory only.
+    pub fn new() -> Self {
+        Contacts {
+            contacts: BTreeMap::new(),
+            file: None,
+        }
+    }
+
+    /// Loads the contact list saved to a file, saving it there on every
+    /// change.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The contacts file, which need not exist yet.
+    /// * `vault` - The vault, if storage encryption is on.
+    pub fn load(path: PathBuf, vault: Option<Arc<Vault>>) -> Result<Self, Box<dyn Error>> {
+        let contacts: Vec<Contact> = match storage::read(&path, vault.as_deref())? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => Vec::new(),
+        };
+        Ok(Contacts {
+            contacts: contacts
+                .into_iter()
+                .map(|contact| (contact.peer, contact))
+                .collect(),
+            file: Some((path, vault)),
+        })
+    }
+
+    /// Adds a contact, or renames it if it exists.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, or why the alias cannot be used.
+    pub fn add(&mut self, peer: PeerId, alias: &str) -> Result<(), String> {
+        let profile = Profile {
+            name: alias.to_string(),
+        };
+        if !profile.is_valid() {
+            return Err(format!(
+                "Aliases are 1 to {} characters without whitespace",
+                MAX_NAME_LEN
+            ));
+        }
+        if let Some(other) = self
+            .contacts
+            .values()
+            .find(|contact| contact.peer != peer && contact.alias.eq_ignore_ascii_case(alias))
+        {
+            return Err(format!(
+                "{} is already the alias of {}",
+                other.alias, other.peer
+            ));
+        }
+        self.contacts
+            .entry(peer)
+            .and_modify(|contact| contact.alias = alias.to_string())
+            .or_insert_with(|| Contact {
+                peer,
+                alias: alias.to_string(),
+                public_key: public_key(&peer)
+                    .map(|key| key.encode_protobuf())
+                    .unwrap_or_default(),
+                notes: String::new(),
+                trust: Trust::Unverified,
+            });
+        Ok(())
+    }
+
+    /// Adds a contact as it was kept elsewhere, such as in a backup.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, or why the alias cannot be used.
+    pub fn insert(&mut self, contact: Contact) -> Result<(), String> {
+        self.add(contact.peer, &contact.alias)?;
+        self.contacts.insert(contact.peer, contact);
+        Ok(())
+    }
+
+    /// Removes a contact.
+    pub fn remove(&mut self, peer: &PeerId) -> Option<Contact> {
+        self.contacts.remove(peer)
+    }
+
+    /// Returns a contact.
+    pub fn get(&self, peer: &PeerId) -> Option<&Contact> {
+        self.contacts.get(peer)
+    }
+
+    /// Returns a contact to change.
+    pub fn get_mut(&mut self, peer: &PeerId) -> Option<&mut Contact> {
+        self.contacts.get_mut(peer)
+    }
+
+    /// Returns the contacts, ordered by alias.
+    pub fn list(&self) -> Vec<&Contact> {
+        let mut contacts: Vec<&Contact> = self.contacts.values().collect();
+        contacts.sort_by_key(|contact| contact.alias.to_lowercase());
+        contacts
+    }
+
+    /// Saves the contacts to their file, if they have one.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some((path, vault)) = &self.file {
+            let contacts: Vec<&Contact> = self.contacts.values().collect();
+            storage::write(path, &bincode::serialize(&contacts)?, vault.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Contacts {
+    fn default() -> Self {
+        Contacts::new()
+    }
+}
+
+/// Returns the public key embedded in a peer ID, as in those of Ed25519
+/// keys.
+pub fn public_key(peer: &PeerId) -> Option<identity::PublicKey> {
+    let multihash: &libp2p::multihash::Multihash<64> = peer.as_ref();
+    // Keys short enough to be embedded use the identity hash, code 0.
+    match multihash.code() {
+        0 => identity::PublicKey::try_decode_protobuf(multihash.digest()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use libp2p::{identity, PeerId};
+    use zeroize::Zeroizing;
+
+    use super::{Contacts, Trust};
+    use crate::storage::Vault;
+
+    #[test]
+    fn test_contacts() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-contacts-{}", std::process::id()));
+        let path = dir.join("contacts.db");
+        let vault = Arc::new(Vault::new(Zeroizing::new("correct horse".to_string())));
+        let mut contacts = Contacts::load(path.clone(), Some(vault.clone())).unwrap();
+        let key = identity::Keypair::generate_ed25519();
+        let (alice, bob) = (key.public().to_peer_id(), PeerId::random());
+
+        contacts.add(alice, "alice").unwrap();
+        assert!(contacts.add(bob, "Alice").is_err());
+        assert!(contacts.add(bob, "bob smith").is_err());
+        contacts.add(bob, "bob").unwrap();
+        contacts.get_mut(&alice).unwrap().trust = Trust::Verified;
+        // Renaming keeps the rest of the contact.
+        contacts.add(alice, "al").unwrap();
+        let contact = contacts.get(&alice).unwrap();
+        assert_eq!(contact.trust, Trust::Verified);
+        assert_eq!(contact.public_key, key.public().encode_protobuf());
+        contacts.save().unwrap();
+
+        let mut loaded = Contacts::load(path.clone(), Some(vault)).unwrap();
+        let aliases: Vec<&str> = loaded.list().iter().map(|c| c.alias.as_str()).collect();
+        assert_eq!(aliases, ["al", "bob"]);
+        assert!(loaded.remove(&bob).is_some());
+        assert!(Contacts::load(path, None).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!("verified".parse(), Ok(Trust::Verified));
+        assert!("trusted".parse::<Trust>().is_err());
+    }
+}
@@ -0,0 +1,579 @@
+/*!
+ * Content filter module for the messaging application.
+ *
+ * Operators of public topics deploy their own anti-abuse policies as
+ * content filters: hooks run on the text messages received, before they
+ * are shown or handed to clients, that let a message through or drop, flag
+ * or quarantine it. Flagged messages are shown marked with the reason, and
+ * quarantined ones are held back until the user reviews them with
+ * `/quarantine`.
+ *
+ * Two filters are configured in the `[filter]` table: a list of regular
+ * expressions with the action taken on a match, and an external command
+ * deciding on every message. Any other `Hook` registered in code can filter
+ * content the same way, by returning a flag or quarantine verdict.
+ *
+ * The command is started once and kept running on a thread of its own, so
+ * the swarm loop never waits for it: the message is held back meanwhile,
+ * and the hooks run on it again once the verdict came back. The command
+ * reads one JSON object per line on stdin, with the `topic`, `id`, `peer`,
+ * `name` and `body` of a message, and answers each with a line of its own:
+ * empty or `pass` to let the message through, or `drop`, `flag` or
+ * `quarantine` followed by an optional reason. A command that fails or
+ * takes longer than `COMMAND_TIMEOUT` to answer lets the message through,
+ * and so do the messages after it until the command is started again, at
+ * longer intervals while it keeps failing.
+ */
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use log::{debug, warn};
+use regex::Regex;
+
+use crate::app::{AppEvent, AppEvents};
+use crate::delivery::MessageId;
+use crate::hooks::{Direction, Hook, HookContext, Verdict};
+use crate::protocol::{Payload, TextMessage};
+use crate::render::JsonLine;
+
+/// How long the filter command may take to answer for a message.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long messages go through unfiltered after the filter command first
+/// failed, before it is started again.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest messages go through unfiltered while the filter command
+/// keeps failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Most messages held back in quarantine, the oldest making room.
+pub const QUARANTINE_LIMIT: usize = 100;
+
+/// What a filter does with a message it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Drop,
+    Flag,
+    Quarantine,
+}
+
+impl FilterAction {
+    /// Returns the verdict taking the action for a reason.
+    pub fn verdict(self, reason: String) -> Verdict {
+        match self {
+            FilterAction::Drop => Verdict::Drop(reason),
+            FilterAction::Flag => Verdict::Flag(reason),
+            FilterAction::Quarantine => Verdict::Quarantine(reason),
+        }
+    }
+}
+
+impl FromStr for FilterAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(FilterAction::Drop),
+            "flag" => Ok(FilterAction::Flag),
+            "quarantine" => Ok(FilterAction::Quarantine),
+            _ => Err(format!("unknown filter action: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FilterAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterAction::Drop => write!(f, "drop"),
+            FilterAction::Flag => write!(f, "flag"),
+            FilterAction::Quarantine => write!(f, "quarantine"),
+        }
+    }
+}
+
+/// The content filters of the configuration.
+#[derive(Debug, Clone)]
+pub struct FilterSettings {
+    /// Patterns matched against the body of received text messages.
+    pub patterns: Vec<Regex>,
+    /// What is done with a message matching a pattern.
+    pub action: FilterAction,
+    /// The filter command and its arguments, if any.
+    pub command: Option<Vec<String>>,
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings {
+            patterns: Vec::new(),
+            action: FilterAction::Flag,
+            command: None,
+        }
+    }
+}
+
+impl FilterSettings {
+    /// Returns the hooks of the configured filters, patterns first.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The channel to the swarm loop, told when the filter
+    ///   command decided on a message.
+    pub fn hooks(&self, events: &AppEvents) -> Vec<Box<dyn Hook>> {
+        let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+        if !self.patterns.is_empty() {
+            hooks.push(Box::new(PatternFilter::new(
+                self.patterns.clone(),
+                self.action,
+            )));
+        }
+        if let Some(command) = &self.command {
+            hooks.push(Box::new(CommandFilter::new(
+                command.clone(),
+                events.clone(),
+            )));
+        }
+        hooks
+    }
+}
+
+/// Returns the text message a content filter looks at, if any.
+fn inbound_text<'a>(context: &HookContext, payload: &'a Payload) -> Option<&'a TextMessage> {
+    match payload {
+        Payload::Text(text) if context.direction == Direction::Inbound => Some(text),
+        _ => None,
+    }
+}
+
+/// A filter matching received text messages against regular expressions.
+pub struct PatternFilter {
+    patterns: Vec<Regex>,
+    action: FilterAction,
+}
+
+impl PatternFilter {
+    /// Creates a filter taking `action` on messages matching any pattern.
+    pub fn new(patterns: Vec<Regex>, action: FilterAction) -> Self {
+        PatternFilter { patterns, action }
+    }
+}
+
+impl Hook for PatternFilter {
+    fn name(&self) -> &str {
+        "patterns"
+    }
+
+    fn handle(
+        &mut self,
+        context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        let Some(text) = inbound_text(context, payload) else {
+            return Ok(Verdict::Continue);
+        };
+        Ok(
+            match self
+                .patterns
+                .iter()
+                .find(|pattern| pattern.is_match(&text.body))
+            {
+                Some(pattern) => self
+                    .action
+                    .verdict(format!("matches {:?}", pattern.as_str())),
+                None => Verdict::Continue,
+            },
+        )
+    }
+}
+
+/// A filter asking an external command about received text messages.
+pub struct CommandFilter {
+    /// The messages to ask the command thread about, by id.
+    requests: Sender<(MessageId, String)>,
+    /// The verdicts of the command thread, by message id.
+    verdicts: Receiver<(MessageId, Verdict)>,
+    /// The verdicts received, kept until their message is hooked again.
+    decided: HashMap<MessageId, Verdict>,
+}
+
+impl CommandFilter {
+    /// Creates a filter running `command`, the program followed by its
+    /// arguments, once the first message arrives.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The program and its arguments.
+    /// * `events` - The channel to the swarm loop, told when the command
+    ///   decided on a message.
+    pub fn new(command: Vec<String>, events: AppEvents) -> Self {
+        let (requests, asked) = mpsc::channel();
+        let (answers, verdicts) = mpsc::channel();
+        let worker = Worker {
+            command,
+            running: None,
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+        };
+        thread::spawn(move || worker.run(asked, answers, events));
+        CommandFilter {
+            requests,
+            verdicts,
+            decided: HashMap::new(),
+        }
+    }
+}
+
+impl Hook for CommandFilter {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn handle(
+        &mut self,
+        context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        let Some(text) = inbound_text(context, payload) else {
+            return Ok(Verdict::Continue);
+        };
+        self.decided.extend(self.verdicts.try_iter());
+        if let Some(verdict) = self.decided.remove(&text.id) {
+            return Ok(verdict);
+        }
+        let request = JsonLine::new("message")
+            .str("topic", context.topic)
+            .str("id", &text.id.to_string())
+            .str("peer", &context.peer.to_base58())
+            .str("name", context.sender.unwrap_or_default())
+            .str("body", &text.body)
+            .to_string();
+        self.requests
+            .send((text.id, request))
+            .map_err(|_| "the filter command thread stopped")?;
+        Ok(Verdict::Pending)
+    }
+}
+
+/// The running filter command.
+struct Running {
+    child: Child,
+    stdin: ChildStdin,
+    /// The lines the command printed, read on a thread of their own.
+    lines: Receiver<String>,
+}
+
+/// The thread running the filter command for a `CommandFilter`.
+struct Worker {
+    command: Vec<String>,
+    running: Option<Running>,
+    /// How long messages go through unfiltered the next time the command
+    /// fails.
+    backoff: Duration,
+    /// When the command may be started again, if it failed.
+    retry_at: Option<Instant>,
+}
+
+impl Worker {
+    /// Answers the messages asked about until the filter is dropped, each
+    /// verdict followed by an `AppEvent::Decided` to the swarm loop.
+    fn run(
+        mut self,
+        asked: Receiver<(MessageId, String)>,
+        answers: Sender<(MessageId, Verdict)>,
+        events: AppEvents,
+    ) {
+        for (id, request) in asked {
+            let verdict = self.decide(&request);
+            if answers.send((id, verdict)).is_err() || events.send(AppEvent::Decided(id)).is_err() {
+                break;
+            }
+        }
+        self.stop();
+    }
+
+    /// Asks the command about a message, letting it through while the
+    /// command is down.
+    fn decide(&mut self, request: &str) -> Verdict {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Verdict::Continue;
+        }
+        match self.ask(request) {
+            Ok(verdict) => {
+                self.backoff = MIN_BACKOFF;
+                self.retry_at = None;
+                verdict
+            }
+            Err(e) => {
+                warn!(
+                    "Filter command failed, letting messages through for {:?}: {}",
+                    self.backoff, e
+                );
+                self.stop();
+                self.retry_at = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                Verdict::Continue
+            }
+        }
+    }
+
+    /// Starts the command.
+    fn start(&self) -> Result<Running, Box<dyn Error>> {
+        let (program, args) = self.command.split_first().ok_or("no filter command")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or("no stdin")?;
+        let stdout = child.stdout.take().ok_or("no stdout")?;
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        debug!("Started filter command {:?}", program);
+        Ok(Running {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    /// Asks the command about a message and parses its answer.
+    fn ask(&mut self, request: &str) -> Result<Verdict, Box<dyn Error>> {
+        let running = match &mut self.running {
+            Some(running) => running,
+            None => self.running.insert(self.start()?),
+        };
+        writeln!(running.stdin, "{}", request)?;
+        running.stdin.flush()?;
+        let answer = running
+            .lines
+            .recv_timeout(COMMAND_TIMEOUT)
+            .map_err(|_| "the filter command did not answer")?;
+        parse_answer(&answer)
+    }
+
+    /// Stops the command, if running.
+    fn stop(&mut self) {
+        if let Some(mut running) = self.running.take() {
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+/// Parses the answer of the filter command about a message.
+fn parse_answer(answer: &str) -> Result<Verdict, Box<dyn Error>> {
+    let (action, reason) = answer
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((answer.trim(), ""));
+    if action.is_empty() || action.eq_ignore_ascii_case("pass") {
+        return Ok(Verdict::Continue);
+    }
+    let reason = match reason.trim() {
+        "" => "filter command".to_string(),
+        reason => reason.to_string(),
+    };
+    Ok(action.parse::<FilterAction>()?.verdict(reason))
+}
+
+/// A text message held back by a content filter.
+#[derive(Debug, Clone)]
+pub struct Quarantined {
+    /// Number the user refers to the message by.
+    pub number: u64,
+    pub topic: String,
+    pub source: PeerId,
+    /// The display name claimed with the message, if any.
+    pub sender: Option<String>,
+    /// When the message was sent, in milliseconds since the epoch.
+    pub timestamp: u64,
+    pub text: TextMessage,
+    pub reason: String,
+}
+
+/// The text messages held back for review, oldest first.
+#[derive(Default)]
+pub struct Quarantine {
+    messages: VecDeque<Quarantined>,
+    next: u64,
+}
+
+impl Quarantine {
+    /// Creates an empty quarantine.
+    pub fn new() -> Self {
+        Quarantine::default()
+    }
+
+    /// Holds a message back, forgetting the oldest one if full.
+    ///
+    /// # Returns
+    ///
+    /// The number the message is referred to by.
+    pub fn hold(&mut self, mut message: Quarantined) -> u64 {
+        self.next += 1;
+        message.number = self.next;
+        if self.messages.len() == QUARANTINE_LIMIT {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+        self.next
+    }
+
+    /// Returns the messages held back, oldest first.
+    pub fn list(&self) -> impl Iterator<Item = &Quarantined> {
+        self.messages.iter()
+    }
+
+    /// Takes a message out of quarantine, to be shown or discarded.
+    pub fn take(&mut self, number: u64) -> Option<Quarantined> {
+        let index = self
+            .messages
+            .iter()
+            .position(|message| message.number == number)?;
+        self.messages.remove(index)
+    }
+
+    /// Discards every message held back.
+    ///
+    /// # Returns
+    ///
+    /// How many messages were discarded.
+    pub fn clear(&mut self) -> usize {
+        let count = self.messages.len();
+        self.messages.clear();
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+    use regex::Regex;
+    use tokio::sync::mpsc;
+
+    use super::{
+        parse_answer, CommandFilter, FilterAction, PatternFilter, Quarantine, Quarantined,
+        QUARANTINE_LIMIT,
+    };
+    use crate::app::AppEvent;
+    use crate::delivery::MessageId;
+    use crate::hooks::{Direction, Hook, HookContext, Verdict};
+    use crate::protocol::{Payload, TextMessage};
+
+    fn text(body: &str) -> TextMessage {
+        TextMessage {
+            id: MessageId::random(),
+            body: body.to_string(),
+            ack_requested: false,
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut context = HookContext {
+            direction: Direction::Inbound,
+            topic: "chat",
+            peer: PeerId::random(),
+            sender: None,
+        };
+        let pattern = Regex::new("(?i)buy now").unwrap();
+        let mut filter = PatternFilter::new(vec![pattern], FilterAction::Quarantine);
+        let mut spam = Payload::Text(text("BUY NOW, cheap"));
+        assert_eq!(
+            filter.handle(&context, &mut spam).unwrap(),
+            Verdict::Quarantine("matches \"(?i)buy now\"".to_string())
+        );
+        let mut ham = Payload::Text(text("hello"));
+        assert_eq!(
+            filter.handle(&context, &mut ham).unwrap(),
+            Verdict::Continue
+        );
+
+        assert_eq!(parse_answer("").unwrap(), Verdict::Continue);
+        assert_eq!(
+            parse_answer("flag  slurs ").unwrap(),
+            Verdict::Flag("slurs".to_string())
+        );
+        assert!(parse_answer("maybe").is_err());
+
+        // A command answering every message with its body. Messages are
+        // held back until it decided, and then hooked again.
+        let (events, mut decided) = mpsc::unbounded_channel();
+        let script = "while read -r line; do echo flag \"$line\"; done";
+        let mut filter = CommandFilter::new(
+            vec!["sh".into(), "-c".into(), script.into()],
+            events.clone(),
+        );
+        assert_eq!(
+            filter.handle(&context, &mut spam).unwrap(),
+            Verdict::Pending
+        );
+        assert!(matches!(
+            decided.blocking_recv(),
+            Some(AppEvent::Decided(_))
+        ));
+        match filter.handle(&context, &mut spam).unwrap() {
+            Verdict::Flag(reason) => assert!(reason.contains(r#""body":"BUY NOW, cheap""#)),
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        }
+        context.direction = Direction::Outbound;
+        assert_eq!(
+            filter.handle(&context, &mut spam).unwrap(),
+            Verdict::Continue
+        );
+
+        // A command that fails lets messages through.
+        let mut filter =
+            CommandFilter::new(vec!["sh".into(), "-c".into(), "exit 0".into()], events);
+        context.direction = Direction::Inbound;
+        assert_eq!(filter.handle(&context, &mut ham).unwrap(), Verdict::Pending);
+        assert!(matches!(
+            decided.blocking_recv(),
+            Some(AppEvent::Decided(_))
+        ));
+        assert_eq!(
+            filter.handle(&context, &mut ham).unwrap(),
+            Verdict::Continue
+        );
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let mut quarantine = Quarantine::new();
+        let message = |body: &str| Quarantined {
+            number: 0,
+            topic: "chat".to_string(),
+            source: PeerId::random(),
+            sender: None,
+            timestamp: 0,
+            text: text(body),
+            reason: "spam".to_string(),
+        };
+        for _ in 0..=QUARANTINE_LIMIT {
+            quarantine.hold(message("spam"));
+        }
+        assert_eq!(quarantine.list().count(), QUARANTINE_LIMIT);
+        assert!(quarantine.take(1).is_none());
+        assert_eq!(quarantine.take(2).unwrap().number, 2);
+        assert_eq!(quarantine.clear(), QUARANTINE_LIMIT - 1);
+    }
+}
@@ -0,0 +1,131 @@
+/*!
+ * Deduplication module for the messaging application.
+ *
+ * Every message is published on both Floodsub and Gossipsub, so each one
+ * normally arrives twice. This module remembers the envelopes received
+ * recently, keyed on the hash of the topic and the envelope bytes, so that
+ * each logical message is processed only once. The cache is the middleware
+ * layer filtering inbound duplicates.
+ */
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use web_time::Instant;
+
+use crate::hooks::{Direction, Verdict};
+use crate::middleware::{Context, Middleware};
+
+/// How long a received envelope is remembered.
+pub const DEDUP_TTL: Duration = Duration::from_secs(2 * 60);
+
+/// Maximum number of envelopes remembered at once.
+pub const DEDUP_CAPACITY: usize = 16 * 1024;
+
+/// Cache of recently received envelopes.
+pub struct DedupCache {
+    seen: HashSet<[u8; 32]>,
+    order: VecDeque<([u8; 32], Instant)>,
+}
+
+impl DedupCache {
+    /// Creates a new, empty `DedupCache`.
+    pub fn new() -> Self {
+        DedupCache {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records an envelope received on a topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope was received on.
+    /// * `data` - The raw envelope bytes.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the envelope was already received recently.
+    pub fn insert(&mut self, topic: &str, data: &[u8]) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(topic.as_bytes());
+        hasher.update([0]);
+        hasher.update(data);
+        let key: [u8; 32] = hasher.finalize().into();
+        if !self.seen.insert(key) {
+            return false;
+        }
+        self.order.push_back((key, Instant::now()));
+        if self.order.len() > DEDUP_CAPACITY {
+            if let Some((key, _)) = self.order.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+        true
+    }
+
+    /// Forgets envelopes received more than `ttl` ago.
+    pub fn expire(&mut self, ttl: Duration) {
+        let now = Instant::now();
+        while let Some(&(key, received_at)) = self.order.front() {
+            if now.duration_since(received_at) < ttl {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&key);
+        }
+    }
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        DedupCache::new()
+    }
+}
+
+impl Middleware for DedupCache {
+    fn name(&self) -> &str {
+        "dedup"
+    }
+
+    fn filter(&mut self, context: &Context, data: &[u8]) -> Verdict {
+        match context.direction == Direction::Outbound || self.insert(context.topic, data) {
+            true => Verdict::Continue,
+            false => Verdict::Drop("duplicate".to_string()),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.expire(DEDUP_TTL);
+    }
+
+    fn compact(&mut self) {
+        self.seen.shrink_to_fit();
+        self.order.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DedupCache;
+
+    #[test]
+    fn test_dedup_and_expire() {
+        let mut cache = DedupCache::new();
+        assert!(cache.insert("chat", b"envelope"));
+        assert!(!cache.insert("chat", b"envelope"));
+        assert!(cache.insert("other", b"envelope"));
+        assert!(cache.insert("chat", b"another"));
+
+        cache.expire(Duration::from_secs(60));
+        assert!(!cache.insert("chat", b"envelope"));
+        cache.expire(Duration::ZERO);
+        assert!(cache.insert("chat", b"envelope"));
+    }
+}
@@ -0,0 +1,283 @@
+/*!
+ * Delivery tracking module for the messaging application.
+ *
+ * This module provides delivery and read receipts, a tracker for
+ * messages awaiting acknowledgment from their recipients, and the local
+ * read receipt privacy settings.
+ */
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+/// How long to wait for a delivery receipt before reporting a message as undelivered.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a delivered message keeps waiting for a read receipt.
+pub const READ_RECEIPT_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Unique identifier of a published message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MessageId(pub [u8; 16]);
+
+impl MessageId {
+    /// Generates a new random message ID.
+    pub fn random() -> Self {
+        MessageId(rand::random())
+    }
+
+    /// Returns the prefix of the hex ID shown to users.
+    pub fn short(&self) -> String {
+        self.to_string()[..8].to_string()
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a receipt acknowledges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptKind {
+    /// The message reached the recipient.
+    Delivered,
+    /// The message was shown in the conversation the recipient focused.
+    Read,
+}
+
+/// A receipt sent back by the recipient of a message.
+///
+/// Receipts are authenticated by the signature of the envelope carrying them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    pub message_id: MessageId,
+    pub kind: ReceiptKind,
+}
+
+/// A sent message awaiting a delivery or read receipt.
+pub struct Pending {
+    pub recipient: PeerId,
+    pub sent_at: Instant,
+    pub delivered: bool,
+}
+
+/// Tracks sent messages until they are read or time out.
+pub struct DeliveryTracker {
+    pending: HashMap<MessageId, Pending>,
+}
+
+impl DeliveryTracker {
+    /// Creates a new, empty `DeliveryTracker`.
+    pub fn new() -> Self {
+        DeliveryTracker {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Starts tracking a message sent to the specified recipient.
+    pub fn track(&mut self, message_id: MessageId, recipient: PeerId) {
+        self.pending.insert(
+            message_id,
+            Pending {
+                recipient,
+                sent_at: Instant::now(),
+                delivered: false,
+            },
+        );
+    }
+
+    /// Applies a verified receipt to a tracked message.
+    ///
+    /// A read receipt also implies delivery and stops tracking the message.
+    ///
+    /// # Arguments
+    ///
+    /// * `message_id` - The acknowledged message ID.
+    /// * `kind` - What the receipt acknowledges.
+    /// * `from` - The peer that signed the receipt.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the receipt changed the status of a message sent to `from`.
+    pub fn confirm(&mut self, message_id: &MessageId, kind: ReceiptKind, from: &PeerId) -> bool {
+        let Some(pending) = self.pending.get_mut(message_id) else {
+            return false;
+        };
+        if pending.recipient != *from {
+            return false;
+        }
+        match kind {
+            ReceiptKind::Delivered if pending.delivered => false,
+            ReceiptKind::Delivered => {
+                pending.delivered = true;
+                true
+            }
+            ReceiptKind::Read => {
+                self.pending.remove(message_id);
+                true
+            }
+        }
+    }
+
+    /// Stops tracking stale messages.
+    ///
+    /// Undelivered messages are dropped after `timeout`, delivered messages
+    /// after `READ_RECEIPT_RETENTION`.
+    ///
+    /// # Returns
+    ///
+    /// The messages that were never delivered.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(MessageId, Pending)> {
+        let now = Instant::now();
+        let expired: Vec<MessageId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| {
+                let limit = if pending.delivered {
+                    READ_RECEIPT_RETENTION
+                } else {
+                    timeout
+                };
+                now.duration_since(pending.sent_at) >= limit
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| self.pending.remove(&id).map(|pending| (id, pending)))
+            .filter(|(_, pending)| !pending.delivered)
+            .collect()
+    }
+}
+
+impl Default for DeliveryTracker {
+    fn default() -> Self {
+        DeliveryTracker::new()
+    }
+}
+
+/// Local privacy settings controlling when read receipts are sent, and
+/// the received messages waiting to be read to send them for.
+pub struct ReadReceiptPolicy {
+    default: bool,
+    overrides: HashMap<PeerId, bool>,
+    /// Messages whose sender asked for receipts, with when they arrived.
+    unread: HashMap<MessageId, (PeerId, Instant)>,
+}
+
+impl ReadReceiptPolicy {
+    /// Creates a new policy that sends no read receipts.
+    pub fn new() -> Self {
+        ReadReceiptPolicy {
+            default: false,
+            overrides: HashMap::new(),
+            unread: HashMap::new(),
+        }
+    }
+
+    /// Sets whether read receipts are sent in conversations without an override.
+    pub fn set_default(&mut self, enabled: bool) {
+        self.default = enabled;
+    }
+
+    /// Sets whether read receipts are sent in the conversation with `peer_id`.
+    pub fn set(&mut self, peer_id: PeerId, enabled: bool) {
+        self.overrides.insert(peer_id, enabled);
+    }
+
+    /// Returns whether a read receipt may be sent to `peer_id`.
+    pub fn allows(&self, peer_id: &PeerId) -> bool {
+        self.overrides.get(peer_id).copied().unwrap_or(self.default)
+    }
+
+    /// Remembers a received message whose sender asked for receipts until
+    /// it is read, forgetting those its sender stopped waiting for.
+    pub fn arrived(&mut self, message_id: MessageId, sender: PeerId) {
+        let now = Instant::now();
+        self.unread
+            .retain(|_, (_, at)| now.duration_since(*at) < READ_RECEIPT_RETENTION);
+        self.unread.insert(message_id, (sender, now));
+    }
+
+    /// Marks a message as read.
+    ///
+    /// # Returns
+    ///
+    /// The peer to send a read receipt to, if it asked for one and may get
+    /// it.
+    pub fn read(&mut self, message_id: &MessageId) -> Option<PeerId> {
+        let (sender, _) = self.unread.remove(message_id)?;
+        self.allows(&sender).then_some(sender)
+    }
+}
+
+impl Default for ReadReceiptPolicy {
+    fn default() -> Self {
+        ReadReceiptPolicy::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libp2p::PeerId;
+
+    use super::{DeliveryTracker, MessageId, ReadReceiptPolicy, ReceiptKind};
+
+    #[test]
+    fn test_tracker_confirm_and_expire() {
+        let recipient = PeerId::random();
+        let mut tracker = DeliveryTracker::new();
+        let delivered = MessageId::random();
+        let lost = MessageId::random();
+        tracker.track(delivered, recipient);
+        tracker.track(lost, recipient);
+
+        let delivered_kind = ReceiptKind::Delivered;
+        assert!(!tracker.confirm(&delivered, delivered_kind, &PeerId::random()));
+        assert!(tracker.confirm(&delivered, delivered_kind, &recipient));
+        assert!(!tracker.confirm(&delivered, delivered_kind, &recipient));
+
+        let expired = tracker.expire(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, lost);
+
+        // Delivered messages keep waiting for a read receipt.
+        assert!(tracker.confirm(&delivered, ReceiptKind::Read, &recipient));
+        assert!(!tracker.confirm(&delivered, ReceiptKind::Read, &recipient));
+    }
+
+    #[test]
+    fn test_read_receipt_policy() {
+        let friend = PeerId::random();
+        let stranger = PeerId::random();
+        let mut policy = ReadReceiptPolicy::new();
+        assert!(!policy.allows(&friend));
+
+        policy.set(friend, true);
+        assert!(policy.allows(&friend));
+        assert!(!policy.allows(&stranger));
+
+        policy.set_default(true);
+        policy.set(friend, false);
+        assert!(!policy.allows(&friend));
+        assert!(policy.allows(&stranger));
+
+        // Receipts go out once per message read, and only if asked for.
+        let (asked, muted) = (MessageId::random(), MessageId::random());
+        policy.arrived(asked, stranger);
+        policy.arrived(muted, friend);
+        assert_eq!(policy.read(&asked), Some(stranger));
+        assert_eq!(policy.read(&asked), None);
+        assert_eq!(policy.read(&muted), None);
+        assert_eq!(policy.read(&MessageId::random()), None);
+    }
+}
@@ -0,0 +1,176 @@
+/*!
+ * Directories module for the messaging application.
+ *
+ * This module decides where files are kept between runs: the configuration
+ * file in the configuration directory, and the identity keypair and the
+ * message history in the data directory. These follow the XDG base
+ * directory specification on Linux, `XDG_CONFIG_HOME` and `XDG_DATA_HOME`
+ * included, and the platform conventions on macOS and Windows. Portable
+ * installs pass `--home <dir>` or set `SEC_MSG_HOME` to keep every file in
+ * a single directory instead.
+ */
+
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+
+/// Name of the configuration file in the configuration directory.
+const CONFIG_FILE: &str = "config.toml";
+
+/// Name of the identity file in the data directory.
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Name of the history file in the data directory.
+const HISTORY_FILE: &str = "history.db";
+
+/// Name of the contacts file in the data directory.
+const CONTACTS_FILE: &str = "contacts.db";
+
+/// Name of the moderation file in the data directory.
+const MODERATION_FILE: &str = "moderation.db";
+
+/// Name of the reputation file in the data directory.
+const REPUTATION_FILE: &str = "reputation.db";
+
+/// Name of the file of ignored peers in the data directory.
+const IGNORED_FILE: &str = "ignored.db";
+
+/// Name of the control socket of the daemon in the data directory.
+const SOCKET_FILE: &str = "sec_msg.sock";
+
+/// Name of the log file in the data directory.
+const LOG_FILE: &str = "sec_msg.log";
+
+/// Where the application keeps its files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dirs {
+    config: PathBuf,
+    data: PathBuf,
+}
+
+impl Dirs {
+    /// Returns the directories of the platform, or a single directory for
+    /// portable installs.
+    ///
+    /// # Arguments
+    ///
+    /// * `home` - The directory of a portable install.
+    ///
+    /// # Returns
+    ///
+    /// The directories, or `None` if the platform has no home directory.
+    pub fn new(home: Option<&Path>) -> Option<Self> {
+        match home {
+            Some(home) => Some(Dirs {
+                config: home.to_path_buf(),
+                data: home.to_path_buf(),
+            }),
+            None => ProjectDirs::from("", "", "sec_msg").map(|dirs| Dirs {
+                config: dirs.config_dir().to_path_buf(),
+                data: dirs.data_dir().to_path_buf(),
+            }),
+        }
+    }
+
+    /// Returns the configuration file read when none is given.
+    pub fn config_file(&self) -> PathBuf {
+        self.config.join(CONFIG_FILE)
+    }
+
+    /// Returns the identity file created by `keygen` and loaded when none
+    /// is configured.
+    pub fn identity(&self) -> PathBuf {
+        self.data.join(IDENTITY_FILE)
+    }
+
+    /// Returns the file the recent messages of each topic are kept in
+    /// between runs, when storage is encrypted.
+    pub fn history(&self) -> PathBuf {
+        self.data.join(HISTORY_FILE)
+    }
+
+    /// Returns the file the contact list is kept in.
+    pub fn contacts(&self) -> PathBuf {
+        self.data.join(CONTACTS_FILE)
+    }
+
+    /// Returns the file the founders, moderators and muted peers of topics
+    /// are kept in.
+    pub fn moderation(&self) -> PathBuf {
+        self.data.join(MODERATION_FILE)
+    }
+
+    /// Returns the file the bans of peers flooding the node are kept in.
+    pub fn reputation(&self) -> PathBuf {
+        self.data.join(REPUTATION_FILE)
+    }
+
+    /// Returns the file the peers the user ignores are kept in.
+    pub fn ignored(&self) -> PathBuf {
+        self.data.join(IGNORED_FILE)
+    }
+
+    /// Returns the socket the daemon is controlled through.
+    pub fn socket(&self) -> PathBuf {
+        self.data.join(SOCKET_FILE)
+    }
+
+    /// Returns the file logs are written to, when enabled.
+    pub fn log(&self) -> PathBuf {
+        self.data.join(LOG_FILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Dirs;
+
+    #[test]
+    fn test_dirs() {
+        let portable = Dirs::new(Some(Path::new("/media/usb/sec_msg"))).unwrap();
+        assert_eq!(
+            portable.config_file(),
+            PathBuf::from("/media/usb/sec_msg/config.toml")
+        );
+        assert_eq!(
+            portable.identity(),
+            PathBuf::from("/media/usb/sec_msg/identity.key")
+        );
+        assert_eq!(
+            portable.history(),
+            PathBuf::from("/media/usb/sec_msg/history.db")
+        );
+        assert_eq!(
+            portable.contacts(),
+            PathBuf::from("/media/usb/sec_msg/contacts.db")
+        );
+        assert_eq!(
+            portable.moderation(),
+            PathBuf::from("/media/usb/sec_msg/moderation.db")
+        );
+        assert_eq!(
+            portable.reputation(),
+            PathBuf::from("/media/usb/sec_msg/reputation.db")
+        );
+        assert_eq!(
+            portable.ignored(),
+            PathBuf::from("/media/usb/sec_msg/ignored.db")
+        );
+        assert_eq!(
+            portable.socket(),
+            PathBuf::from("/media/usb/sec_msg/sec_msg.sock")
+        );
+        assert_eq!(
+            portable.log(),
+            PathBuf::from("/media/usb/sec_msg/sec_msg.log")
+        );
+
+        // The platform directories depend on the environment.
+        if let Some(platform) = Dirs::new(None) {
+            assert!(platform.config_file().ends_with("config.toml"));
+            assert!(platform.identity().ends_with("identity.key"));
+        }
+    }
+}
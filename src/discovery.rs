@@ -0,0 +1,135 @@
+/*!
+ * Topic discovery module for the messaging application.
+ *
+ * Public topics are advertised on a Kademlia DHT. Every advertising peer is
+ * a provider of a well-known directory key and stores the signed list of
+ * its public topics under a key derived from its peer ID. Discovering
+ * topics looks up the directory providers, then fetches and verifies the
+ * list published by each of them.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use libp2p::{kad, PeerId, StreamProtocol};
+
+/// Protocol name of the DHT used for topic discovery.
+pub const DISCOVERY_PROTOCOL: StreamProtocol = StreamProtocol::new("/sec_msg/kad/1.0.0");
+
+/// Name of the topic directory, which advertisements are sealed for.
+pub const DIRECTORY: &str = "sec_msg/topics";
+
+/// Returns the key every advertising peer provides.
+pub fn directory_key() -> kad::RecordKey {
+    kad::RecordKey::new(&DIRECTORY)
+}
+
+/// Returns the key under which a peer stores its advertised topics.
+pub fn advertisement_key(peer_id: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{}/{}", DIRECTORY, peer_id))
+}
+
+/// Topics advertised locally and discovered from other peers.
+pub struct Discovery {
+    advertised: BTreeSet<String>,
+    queries: HashSet<kad::QueryId>,
+    found: BTreeMap<String, BTreeSet<PeerId>>,
+}
+
+impl Discovery {
+    /// Creates a new `Discovery` advertising no topics.
+    pub fn new() -> Self {
+        Discovery {
+            advertised: BTreeSet::new(),
+            queries: HashSet::new(),
+            found: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a topic to the advertised topics.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the topic was already advertised.
+    pub fn advertise(&mut self, topic: &str) -> bool {
+        self.advertised.insert(topic.to_string())
+    }
+
+    /// Removes a topic from the advertised topics.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the topic was not advertised.
+    pub fn withdraw(&mut self, topic: &str) -> bool {
+        self.advertised.remove(topic)
+    }
+
+    /// Returns the advertised topics, in order.
+    pub fn advertised(&self) -> Vec<String> {
+        self.advertised.iter().cloned().collect()
+    }
+
+    /// Starts a new discovery, forgetting the results of the previous one.
+    pub fn start(&mut self, query: kad::QueryId) {
+        self.queries.clear();
+        self.found.clear();
+        self.queries.insert(query);
+    }
+
+    /// Tracks a query issued as part of the current discovery.
+    pub fn track(&mut self, query: kad::QueryId) {
+        self.queries.insert(query);
+    }
+
+    /// Returns whether a query is part of the current discovery.
+    pub fn is_tracked(&self, query: &kad::QueryId) -> bool {
+        self.queries.contains(query)
+    }
+
+    /// Records topics advertised by a peer.
+    ///
+    /// # Returns
+    ///
+    /// The topics not previously known to be advertised by the peer.
+    pub fn found(&mut self, peer_id: PeerId, topics: Vec<String>) -> Vec<String> {
+        topics
+            .into_iter()
+            .filter(|topic| self.found.entry(topic.clone()).or_default().insert(peer_id))
+            .collect()
+    }
+
+    /// Returns the discovered topics and the peers advertising them.
+    pub fn discovered(&self) -> &BTreeMap<String, BTreeSet<PeerId>> {
+        &self.found
+    }
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{advertisement_key, directory_key, Discovery};
+
+    #[test]
+    fn test_discovery() {
+        let mut discovery = Discovery::new();
+        assert!(discovery.advertise("rust"));
+        assert!(!discovery.advertise("rust"));
+        assert!(discovery.advertise("chat"));
+        assert!(discovery.withdraw("chat"));
+        assert_eq!(discovery.advertised(), vec!["rust".to_string()]);
+
+        let peer = PeerId::random();
+        let topics = vec!["rust".to_string(), "go".to_string()];
+        assert_eq!(discovery.found(peer, topics.clone()).len(), 2);
+        assert!(discovery.found(peer, topics).is_empty());
+        assert_eq!(discovery.discovered()["rust"].len(), 1);
+
+        assert_ne!(advertisement_key(&peer), directory_key());
+    }
+}
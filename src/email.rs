@@ -0,0 +1,555 @@
+/*!
+ * Email module for the messaging application.
+ *
+ * Not everyone a user talks to keeps a node running. With the `[email]`
+ * settings, a direct message to a contact that has an email address and
+ * stays unacknowledged for `ACK_TIMEOUT` is sent to that address over SMTP
+ * instead, encrypted to the contact's PGP key when one is configured. The
+ * gateway also checks an IMAP mailbox every minute, and the unread emails
+ * from the address of a contact are shown in the conversation with that
+ * contact, without the quoted text, after decrypting them with the secret
+ * key of the user if they are encrypted.
+ *
+ * Sender addresses are easily forged, so replies by email are marked as
+ * such and carry none of the trust of signed messages. The gateway is
+ * built with the `email` feature.
+ */
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+use libp2p::PeerId;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::delivery::MessageId;
+
+/// The environment variable holding the password of the mail servers.
+pub const PASSWORD_VAR: &str = "SEC_MSG_EMAIL_PASSWORD";
+
+/// The environment variable holding the passphrase of the secret PGP key.
+pub const KEY_PASSPHRASE_VAR: &str = "SEC_MSG_EMAIL_KEY_PASSPHRASE";
+
+/// The settings of the gateway, from the `[email]` table of the
+/// configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailSettings {
+    /// The address emails are sent from and replies are read at.
+    pub address: String,
+    /// The URL of the SMTP server, such as `smtps://smtp.example.com`.
+    pub smtp: String,
+    /// The URL of the IMAP server, if replies are read.
+    pub imap: Option<String>,
+    /// The user name on both servers.
+    pub user: String,
+    /// The addresses of contacts.
+    pub contacts: BTreeMap<PeerId, String>,
+    /// The armored PGP public keys of contacts, emails to them are
+    /// encrypted to.
+    pub keys: BTreeMap<PeerId, PathBuf>,
+    /// The armored PGP secret key replies are decrypted with.
+    pub secret_key: Option<PathBuf>,
+}
+
+/// A direct message sent by email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub id: MessageId,
+    pub peer: PeerId,
+    pub address: String,
+    pub body: String,
+}
+
+/// The direct messages to contacts with an address, held until they are
+/// delivered or time out.
+pub struct Outbox {
+    addresses: BTreeMap<PeerId, String>,
+    held: HashMap<MessageId, (PeerId, String)>,
+    /// The channel to the task sending the emails.
+    mails: UnboundedSender<Mail>,
+}
+
+impl Outbox {
+    /// Creates an outbox.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - The addresses of contacts.
+    /// * `mails` - The channel to the task sending the emails.
+    pub fn new(addresses: BTreeMap<PeerId, String>, mails: UnboundedSender<Mail>) -> Self {
+        Outbox {
+            addresses,
+            held: HashMap::new(),
+            mails,
+        }
+    }
+
+    /// Holds a direct message, if its recipient has an address.
+    pub fn hold(&mut self, id: MessageId, peer: PeerId, body: &str) {
+        if self.addresses.contains_key(&peer) {
+            self.held.insert(id, (peer, body.to_string()));
+        }
+    }
+
+    /// Forgets a direct message that was delivered.
+    pub fn release(&mut self, id: &MessageId) {
+        self.held.remove(id);
+    }
+
+    /// Sends a direct message that timed out by email.
+    ///
+    /// # Returns
+    ///
+    /// The address it is sent to, or `None` if the message was not held.
+    pub fn send(&mut self, id: &MessageId) -> Option<&str> {
+        let (peer, body) = self.held.remove(id)?;
+        let address = self.addresses.get(&peer)?;
+        let mail = Mail {
+            id: *id,
+            peer,
+            address: address.clone(),
+            body,
+        };
+        self.mails.send(mail).ok()?;
+        Some(address)
+    }
+}
+
+/// Returns the text of a reply, without the quoted message and the
+/// signature.
+pub fn reply_text(body: &str) -> String {
+    let mut lines = Vec::new();
+    for line in body.lines() {
+        let trimmed = line.trim_end();
+        let quote_header = trimmed.starts_with("On ") && trimmed.ends_with("wrote:");
+        if trimmed.starts_with('>')
+            || quote_header
+            // The signature separator keeps its space only untrimmed.
+            || line == "-- "
+            || trimmed == "-----Original Message-----"
+        {
+            break;
+        }
+        lines.push(trimmed);
+    }
+    lines.join("\n").trim().to_string()
+}
+
+#[cfg(feature = "email")]
+pub use gateway::start;
+
+#[cfg(feature = "email")]
+mod gateway {
+    use std::{collections::BTreeMap, env, error::Error, path::Path, time::Duration};
+
+    use async_imap::Client as ImapClient;
+    use futures::TryStreamExt;
+    use lettre::{
+        message::{header::ContentType, Mailbox},
+        transport::smtp::authentication::Credentials,
+        AsyncSmtpTransport, AsyncTransport, Message as Email, Tokio1Executor,
+    };
+    use libp2p::PeerId;
+    use log::{debug, error, info, warn};
+    use mail_parser::MessageParser;
+    use pgp::{
+        crypto::sym::SymmetricKeyAlgorithm, types::PublicKeyTrait, Deserializable,
+        Message as PgpMessage, SignedPublicKey, SignedSecretKey,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpStream,
+        sync::mpsc,
+    };
+    use tokio_rustls::{
+        rustls::{self, pki_types::ServerName},
+        TlsConnector,
+    };
+
+    use super::{reply_text, EmailSettings, Mail, Outbox, KEY_PASSPHRASE_VAR, PASSWORD_VAR};
+    use crate::app::{AppEvent, AppEvents};
+
+    /// How often the mailbox is checked for replies.
+    const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+    const PGP_BEGIN: &str = "-----BEGIN PGP MESSAGE-----";
+    const PGP_END: &str = "-----END PGP MESSAGE-----";
+
+    /// Starts the gateway.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The settings of the gateway.
+    /// * `events` - The channel to the swarm loop, replies are sent to.
+    /// * `local_peer_id` - The peer ID of the node, named in the emails.
+    ///
+    /// # Returns
+    ///
+    /// The outbox the swarm loop hands timed out direct messages to, or an
+    /// error if a server URL or a key is invalid.
+    pub fn start(
+        settings: EmailSettings,
+        events: AppEvents,
+        local_peer_id: &PeerId,
+    ) -> Result<Outbox, Box<dyn Error>> {
+        let password = env::var(PASSWORD_VAR).ok();
+        let from: Mailbox = settings
+            .address
+            .parse()
+            .map_err(|e| format!("Invalid email address {}: {}", settings.address, e))?;
+        let mut smtp = AsyncSmtpTransport::<Tokio1Executor>::from_url(&settings.smtp)
+            .map_err(|e| format!("Invalid SMTP server {}: {}", settings.smtp, e))?;
+        if let Some(password) = &password {
+            smtp = smtp.credentials(Credentials::new(settings.user.clone(), password.clone()));
+        }
+        let smtp = smtp.build();
+        let mut keys = BTreeMap::new();
+        for (peer, path) in &settings.keys {
+            keys.insert(*peer, public_key(path)?);
+        }
+        let (mails, mut outgoing) = mpsc::unbounded_channel::<Mail>();
+        let signature = format!(
+            "-- \nSent by sec_msg from {} while you were offline, replies are passed on.",
+            local_peer_id
+        );
+        tokio::spawn(async move {
+            while let Some(mail) = outgoing.recv().await {
+                match send(&smtp, &from, keys.get(&mail.peer), &signature, &mail).await {
+                    Ok(()) => info!("Sent message {} to {} by email", mail.id, mail.address),
+                    Err(e) => error!(
+                        "Failed to send message {} to {}: {}",
+                        mail.id, mail.address, e
+                    ),
+                }
+            }
+        });
+        if let Some(imap) = settings.imap.clone() {
+            let secret = match &settings.secret_key {
+                Some(path) => Some(secret_key(path)?),
+                None => None,
+            };
+            let password = password.ok_or(format!("Reading replies needs {}", PASSWORD_VAR))?;
+            let inbox = Inbox {
+                server: imap,
+                user: settings.user.clone(),
+                password,
+            };
+            let senders = settings
+                .contacts
+                .iter()
+                .map(|(peer, address)| (address.to_lowercase(), *peer))
+                .collect();
+            tokio::spawn(poll(inbox, senders, secret, events));
+        }
+        Ok(Outbox::new(settings.contacts, mails))
+    }
+
+    /// Reads an armored PGP public key, which must be able to encrypt.
+    fn public_key(path: &Path) -> Result<SignedPublicKey, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read PGP key {:?}: {}", path, e))?;
+        let (key, _) = SignedPublicKey::from_string(&text)
+            .map_err(|e| format!("Invalid PGP key {:?}: {}", path, e))?;
+        let encrypts =
+            key.is_encryption_key() || key.public_subkeys.iter().any(|k| k.is_encryption_key());
+        match encrypts {
+            true => Ok(key),
+            false => Err(format!("The PGP key {:?} cannot encrypt", path).into()),
+        }
+    }
+
+    /// Reads an armored PGP secret key.
+    fn secret_key(path: &Path) -> Result<SignedSecretKey, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read PGP key {:?}: {}", path, e))?;
+        let (key, _) = SignedSecretKey::from_string(&text)
+            .map_err(|e| format!("Invalid PGP key {:?}: {}", path, e))?;
+        Ok(key)
+    }
+
+    /// Encrypts a text to a key, preferring its encryption subkey.
+    fn encrypt(key: &SignedPublicKey, text: &str) -> Result<String, pgp::errors::Error> {
+        let message = PgpMessage::new_literal("", text);
+        let mut rng = rand::thread_rng();
+        let algorithm = SymmetricKeyAlgorithm::AES128;
+        let encrypted = match key.public_subkeys.iter().find(|k| k.is_encryption_key()) {
+            Some(subkey) => message.encrypt_to_keys_seipdv1(&mut rng, algorithm, &[subkey])?,
+            None => message.encrypt_to_keys_seipdv1(&mut rng, algorithm, &[key])?,
+        };
+        encrypted.to_armored_string(None.into())
+    }
+
+    /// Decrypts the first armored PGP message in a text.
+    fn decrypt(key: &SignedSecretKey, text: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(start) = text.find(PGP_BEGIN) else {
+            return Ok(None);
+        };
+        let end = text[start..]
+            .find(PGP_END)
+            .ok_or("Unterminated PGP message")?;
+        let armored = &text[start..start + end + PGP_END.len()];
+        let (message, _) = PgpMessage::from_string(armored)?;
+        let passphrase = env::var(KEY_PASSPHRASE_VAR).unwrap_or_default();
+        let (decrypted, _) = message.decrypt(|| passphrase, &[key])?;
+        let content = decrypted.get_content()?.ok_or("Empty PGP message")?;
+        Ok(Some(String::from_utf8(content)?))
+    }
+
+    /// Sends a direct message by email.
+    async fn send(
+        smtp: &AsyncSmtpTransport<Tokio1Executor>,
+        from: &Mailbox,
+        key: Option<&SignedPublicKey>,
+        signature: &str,
+        mail: &Mail,
+    ) -> Result<(), Box<dyn Error>> {
+        let text = format!("{}\n\n{}", mail.body, signature);
+        let body = match key {
+            Some(key) => encrypt(key, &text)?,
+            None => text,
+        };
+        let email = Email::builder()
+            .from(from.clone())
+            .to(mail.address.parse()?)
+            .subject(format!("Direct message {}", mail.id.short()))
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        smtp.send(email).await?;
+        Ok(())
+    }
+
+    /// Where and as whom replies are read.
+    struct Inbox {
+        server: String,
+        user: String,
+        password: String,
+    }
+
+    /// Checks the inbox for replies every `POLL_INTERVAL`.
+    async fn poll(
+        inbox: Inbox,
+        senders: BTreeMap<String, PeerId>,
+        secret: Option<SignedSecretKey>,
+        events: AppEvents,
+    ) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let replies = match fetch(&inbox).await {
+                Ok(replies) => replies,
+                Err(e) => {
+                    warn!("Failed to read email from {}: {}", inbox.server, e);
+                    continue;
+                }
+            };
+            for raw in replies {
+                let Some((peer, body)) = parse(&raw, &senders, secret.as_ref()) else {
+                    continue;
+                };
+                if events.send(AppEvent::Email(peer, body)).is_err() {
+                    // The node stopped.
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reads an email, returning the contact that sent it and its reply.
+    fn parse(
+        raw: &[u8],
+        senders: &BTreeMap<String, PeerId>,
+        secret: Option<&SignedSecretKey>,
+    ) -> Option<(PeerId, String)> {
+        let message = MessageParser::default().parse(raw)?;
+        let from = message.from()?.first()?.address()?.to_lowercase();
+        let Some(peer) = senders.get(&from) else {
+            debug!("Ignoring email from {}, who is not a contact", from);
+            return None;
+        };
+        let mut body = message.body_text(0).unwrap_or_default().into_owned();
+        if let Some(key) = secret {
+            match decrypt(key, &String::from_utf8_lossy(raw)) {
+                Ok(Some(text)) => body = text,
+                Ok(None) => {}
+                Err(e) => warn!("Failed to decrypt email from {}: {}", from, e),
+            }
+        }
+        let reply = reply_text(&body);
+        (!reply.is_empty()).then_some((*peer, reply))
+    }
+
+    /// Fetches the unread emails of the inbox, marking them read.
+    async fn fetch(inbox: &Inbox) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let (tls, address) = match inbox.server.split_once("://") {
+            Some(("imaps", address)) => (true, address),
+            Some(("imap", address)) => (false, address),
+            _ => return Err(format!("Invalid IMAP server {}", inbox.server).into()),
+        };
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (address, if tls { 993 } else { 143 }),
+        };
+        let stream = TcpStream::connect((host, port)).await?;
+        if !tls {
+            return session(stream, inbox).await;
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder_with_provider(
+            rustls::crypto::ring::default_provider().into(),
+        )
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let name = ServerName::try_from(host.to_string())?;
+        let stream = TlsConnector::from(std::sync::Arc::new(config))
+            .connect(name, stream)
+            .await?;
+        session(stream, inbox).await
+    }
+
+    /// Logs in and fetches the unread emails over a connection.
+    async fn session<T>(stream: T, inbox: &Inbox) -> Result<Vec<Vec<u8>>, Box<dyn Error>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug + Send,
+    {
+        let mut client = ImapClient::new(stream);
+        client.read_response().await.ok_or("No greeting")??;
+        let mut session = client
+            .login(&inbox.user, &inbox.password)
+            .await
+            .map_err(|(e, _)| e)?;
+        session.select("INBOX").await?;
+        let unread = session.search("UNSEEN").await?;
+        let mut emails = Vec::new();
+        if !unread.is_empty() {
+            let set: Vec<String> = unread.iter().map(u32::to_string).collect();
+            // Fetching the bodies marks the emails read.
+            let fetches: Vec<_> = session
+                .fetch(set.join(","), "RFC822")
+                .await?
+                .try_collect()
+                .await?;
+            emails.extend(fetches.iter().filter_map(|f| f.body().map(<[u8]>::to_vec)));
+        }
+        session.logout().await?;
+        Ok(emails)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::BTreeMap;
+
+        use libp2p::PeerId;
+        use pgp::{
+            composed::{KeyType, SecretKeyParamsBuilder, SubkeyParamsBuilder},
+            crypto::ecc_curve::ECCCurve,
+            types::SecretKeyTrait,
+        };
+
+        use super::{decrypt, encrypt, parse};
+
+        #[test]
+        fn test_gateway() {
+            let params = SecretKeyParamsBuilder::default()
+                .key_type(KeyType::EdDSALegacy)
+                .can_certify(true)
+                .can_sign(true)
+                .primary_user_id("Me <me@example.com>".into())
+                .subkey(
+                    SubkeyParamsBuilder::default()
+                        .key_type(KeyType::ECDH(ECCCurve::Curve25519))
+                        .can_encrypt(true)
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap();
+            let mut rng = rand::thread_rng();
+            let secret = params.generate(&mut rng).unwrap();
+            let secret = secret.sign(&mut rng, String::new).unwrap();
+            let public = secret
+                .public_key()
+                .sign(&mut rng, &secret, String::new)
+                .unwrap();
+            let armored = encrypt(&public, "meet at noon").unwrap();
+            assert!(!armored.contains("meet at noon"));
+            let text = format!("Hi\n\n{}\n", armored);
+            assert_eq!(
+                decrypt(&secret, &text).unwrap().as_deref(),
+                Some("meet at noon")
+            );
+            assert_eq!(decrypt(&secret, "no armor here").unwrap(), None);
+
+            let alice = PeerId::random();
+            let senders = BTreeMap::from([("alice@example.com".to_string(), alice)]);
+            let email = b"From: Alice <Alice@Example.com>\r\n\
+                To: me@example.com\r\n\
+                Subject: Re: Direct message 0a1b2c3d\r\n\
+                \r\n\
+                Sounds good!\r\n\
+                \r\n\
+                On Mon, 1 Jan 2024, me@example.com wrote:\r\n\
+                > meet at noon\r\n";
+            assert_eq!(
+                parse(email, &senders, None),
+                Some((alice, "Sounds good!".to_string()))
+            );
+            let stranger = b"From: mallory@example.com\r\n\r\nhello\r\n";
+            assert_eq!(parse(stranger, &senders, None), None);
+        }
+    }
+}
+
+/// Fails, as the email gateway is built with the `email` feature.
+#[cfg(not(feature = "email"))]
+pub fn start(
+    _settings: EmailSettings,
+    _events: crate::app::AppEvents,
+    _local_peer_id: &PeerId,
+) -> Result<Outbox, Box<dyn std::error::Error>> {
+    Err("Built without the email gateway, rebuild with --features email".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use libp2p::PeerId;
+    use tokio::sync::mpsc;
+
+    use super::{reply_text, Outbox};
+    use crate::delivery::MessageId;
+
+    #[test]
+    fn test_outbox() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let addresses = BTreeMap::from([(alice, "alice@example.com".to_string())]);
+        let (mails, mut sent) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(addresses, mails);
+        let (first, second, third) = (
+            MessageId::random(),
+            MessageId::random(),
+            MessageId::random(),
+        );
+        outbox.hold(first, alice, "are you there?");
+        outbox.hold(second, alice, "lunch?");
+        // Contacts without an address are left out.
+        outbox.hold(third, bob, "hi");
+        outbox.release(&second);
+        assert_eq!(outbox.send(&first), Some("alice@example.com"));
+        assert_eq!(outbox.send(&first), None);
+        assert_eq!(outbox.send(&second), None);
+        assert_eq!(outbox.send(&third), None);
+        let mail = sent.try_recv().unwrap();
+        assert_eq!((mail.id, mail.peer), (first, alice));
+        assert_eq!(mail.body, "are you there?");
+        assert!(sent.try_recv().is_err());
+
+        let reply = "Yes!\n\nSee you\n-- \nAlice\n";
+        assert_eq!(reply_text(reply), "Yes!\n\nSee you");
+        assert_eq!(reply_text("> quoted only"), "");
+    }
+}
@@ -0,0 +1,92 @@
+/*!
+ * Emoji module for the messaging application.
+ *
+ * Typing emoji in a terminal is awkward, so messages may name them by
+ * shortcode instead, as in `:tada:`. This module expands the shortcodes
+ * of the lines the user sends, before they are published, and completes
+ * partially typed shortcodes. Text between colons that is not a known
+ * shortcode is left untouched.
+ */
+
+/// Minimum number of shortcode characters typed before completing, since
+/// a single character matches hundreds of shortcodes.
+const MIN_COMPLETION_LEN: usize = 2;
+
+/// Replaces the known `:shortcodes:` of a text with their emoji.
+///
+/// # Arguments
+///
+/// * `text` - The text typed by the user.
+pub fn expand(text: &str) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .map(|end| &after[..end])
+            .filter(|code| code.chars().all(is_shortcode_char))
+            .and_then(|code| emojis::get_by_shortcode(code).map(|emoji| (code, emoji)));
+        match emoji {
+            Some((code, emoji)) => {
+                expanded.push_str(emoji.as_str());
+                rest = &after[code.len() + 1..];
+            }
+            // The closing colon may open the next shortcode.
+            None => {
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Returns the shortcodes a partially typed `:shortcode` completes to,
+/// with their colons.
+///
+/// # Arguments
+///
+/// * `word` - The word being typed, starting with a colon.
+pub fn complete(word: &str) -> Vec<String> {
+    let Some(code) = word.strip_prefix(':') else {
+        return Vec::new();
+    };
+    if code.chars().count() < MIN_COMPLETION_LEN || !code.chars().all(is_shortcode_char) {
+        return Vec::new();
+    }
+    emojis::iter()
+        .flat_map(|emoji| emoji.shortcodes())
+        .filter(|shortcode| shortcode.starts_with(code))
+        .map(|shortcode| format!(":{}:", shortcode))
+        .collect()
+}
+
+/// Returns whether a character may appear in a shortcode.
+fn is_shortcode_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete, expand};
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("ship it :rocket:"), "ship it 🚀");
+        assert_eq!(expand(":+1::tada:"), "👍🎉");
+        assert_eq!(expand("at 12:30:tada:"), "at 12:30🎉");
+        assert_eq!(expand(":not_an_emoji: :"), ":not_an_emoji: :");
+        assert_eq!(expand("std::mem::take"), "std::mem::take");
+    }
+
+    #[test]
+    fn test_complete() {
+        assert!(complete(":rock").contains(&":rocket:".to_string()));
+        assert!(complete(":r").is_empty());
+        assert!(complete("rock").is_empty());
+        assert!(complete(":rocket:").is_empty());
+    }
+}
@@ -5,22 +5,88 @@
  * events for Floodsub and Gossipsub.
  */
 
-use crate::protocol::{ProtocolEvent, Protocols};
+use crate::compression::CompressionError;
+use crate::content_filter::Quarantined;
+use crate::dedup::DedupCache;
+use crate::delivery::{MessageId, Receipt, ReceiptKind, ACK_TIMEOUT};
+use crate::discovery::{advertisement_key, directory_key, DIRECTORY};
+use crate::history::{HistoryRequest, HistoryResponse, SavedHistory, HISTORY_LIMIT};
+use crate::hooks::{Direction, HookContext, Verdict};
+use crate::middleware::Context;
+use crate::moderation::{Action, ModerationAction, Rejection};
+use crate::note::{is_note_topic, Note, NoteOp};
+use crate::peers::{Ping, Pong, PING_PROTOCOL};
+use crate::presence::{Presence, PresenceStatus, PRESENCE_TIMEOUT, PRESENCE_TOPIC};
+use crate::profile::Profile;
+use crate::protocol::{
+    inbox_topic, is_inbox_topic, BinaryMessage, Envelope, EnvelopeError, Outbound, Payload,
+    ProtocolEvent, Protocols, TrafficClass, REASSEMBLY_TIMEOUT,
+};
+use crate::reaction::Reaction;
+use crate::render::JsonLine;
+use crate::reputation::Reputation;
+use crate::security::{sanitize, sanitize_multiline, MAX_RENDERED_LEN, MAX_RENDERED_NAME_LEN};
+use crate::state::AppState;
+use crate::stream::{read_request, receive_file, send_file, StreamsEvent, TransferEvent};
+use crate::topic::PubsubProtocol;
+use crate::transfer::{decompress_chunk, FileRequest, FileResponse, TransferId};
+use crate::version::{Compatibility, PeerVersion};
+use futures::StreamExt;
 use libp2p::swarm::{Swarm, SwarmEvent};
-use log::{error, info};
+use libp2p::PeerId;
+use libp2p::{identify, kad, request_response};
+use log::{debug, error, info, warn};
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+
+/// How long shutting down waits for pending messages and connections.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the swarm is given to send what was handed to it before the
+/// connections are closed.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
 
 /// Handles swarm events and dispatches them to the appropriate handlers.
 ///
+/// No event stops the swarm loop: failed dials and closed listeners are
+/// logged, and events the node has no use for are ignored.
+///
 /// # Arguments
 ///
 /// * `event` - The swarm event.
 /// * `swarm` - The libp2p swarm.
-pub async fn handle_event(event: SwarmEvent<ProtocolEvent>, swarm: &mut Swarm<Protocols>) {
+/// * `state` - The application state.
+pub async fn handle_event(
+    event: SwarmEvent<ProtocolEvent>,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    if let Some(recorder) = &mut state.recorder {
+        recorder.record(&event);
+    }
     match event {
         SwarmEvent::Behaviour(event) => match event {
-            ProtocolEvent::Floodsub(floodsub_event) => handle_floodsub_event(floodsub_event).await,
+            ProtocolEvent::Floodsub(floodsub_event) => {
+                handle_floodsub_event(floodsub_event, swarm, state).await
+            }
             ProtocolEvent::Gossipsub(gossipsub_event) => {
-                handle_gossipsub_event(*gossipsub_event).await
+                handle_gossipsub_event(*gossipsub_event, swarm, state).await
+            }
+            ProtocolEvent::FileTransfer(file_transfer_event) => {
+                handle_file_transfer_event(file_transfer_event, swarm, state).await
+            }
+            ProtocolEvent::History(history_event) => {
+                handle_history_event(history_event, swarm, state).await
+            }
+            ProtocolEvent::Ping(ping_event) => handle_ping_event(ping_event, swarm, state),
+            ProtocolEvent::Kademlia(kademlia_event) => {
+                handle_kademlia_event(*kademlia_event, swarm, state)
+            }
+            ProtocolEvent::Streams(streams_event) => {
+                handle_streams_event(streams_event, swarm, state)
+            }
+            ProtocolEvent::Identify(identify_event) => {
+                handle_identify_event(*identify_event, swarm, state)
             }
         },
         SwarmEvent::NewListenAddr {
@@ -28,6 +94,12 @@ pub async fn handle_event(event: SwarmEvent<ProtocolEvent>, swarm: &mut Swarm<Pr
             address,
         } => {
             info!("Listening {:?} on address {:?}", listener_id, address);
+            state
+                .renderer
+                .emit(JsonLine::new("listening").str("address", &address.to_string()));
+            // Peers are expected to be directly reachable, so advertise the
+            // listen addresses in provider records of the discovery DHT.
+            swarm.add_external_address(address);
         }
         SwarmEvent::ConnectionEstablished {
             peer_id,
@@ -41,10 +113,37 @@ pub async fn handle_event(event: SwarmEvent<ProtocolEvent>, swarm: &mut Swarm<Pr
                 "Connected to {:?}, connection_id={:?} endpoint={:?}, num_established={}, concurrent_dial_errors={:?}, established_in={:?}",
                 peer_id, connection_id, endpoint, num_established, concurrent_dial_errors, established_in
             );
+            if state
+                .middleware
+                .get::<Reputation>()
+                .is_some_and(|reputation| reputation.is_banned(&peer_id))
+            {
+                info!("Refusing {:?}, banned for flooding", peer_id);
+                let _ = swarm.disconnect_peer_id(peer_id);
+                return;
+            }
             swarm
                 .behaviour_mut()
                 .floodsub
                 .add_node_to_partial_view(peer_id);
+            state
+                .peers
+                .connected(peer_id, endpoint.get_remote_address().clone());
+            if num_established.get() == 1 {
+                state.renderer.emit(
+                    JsonLine::new("peer_connected")
+                        .str("peer", &peer_id.to_base58())
+                        .str("address", &endpoint.get_remote_address().to_string()),
+                );
+                ping(peer_id, swarm, state);
+            }
+            // Only dialed addresses are known to accept connections.
+            if endpoint.is_dialer() {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer_id, endpoint.get_remote_address().clone());
+            }
         }
         SwarmEvent::ConnectionClosed {
             peer_id,
@@ -57,6 +156,14 @@ pub async fn handle_event(event: SwarmEvent<ProtocolEvent>, swarm: &mut Swarm<Pr
                 "Connection closed for {:?}, endpoint={:?}, num_established={}, connection_id={:?}",
                 peer_id, endpoint, num_established, connection_id
             );
+            if num_established == 0 {
+                state.versions.remove(&peer_id);
+                state.peers.disconnected(&peer_id);
+                state.nat.disconnected(&peer_id);
+                state
+                    .renderer
+                    .emit(JsonLine::new("peer_disconnected").str("peer", &peer_id.to_base58()));
+            }
         }
         SwarmEvent::IncomingConnection {
             local_addr,
@@ -74,36 +181,281 @@ pub async fn handle_event(event: SwarmEvent<ProtocolEvent>, swarm: &mut Swarm<Pr
             error,
             connection_id,
         } => {
-            error!(
+            // Peers hanging up during the handshake are routine.
+            warn!(
                 "Incoming connection error: {:?} from {:?}, send_back_addr={:?}, connection_id={:?}",
                 error, local_addr, send_back_addr, connection_id
             )
         }
+        SwarmEvent::OutgoingConnectionError {
+            connection_id,
+            peer_id,
+            error,
+        } => {
+            warn!(
+                "Failed to dial {:?}: {}, connection_id={:?}",
+                peer_id, error, connection_id
+            );
+        }
+        SwarmEvent::ExpiredListenAddr {
+            listener_id,
+            address,
+        } => {
+            info!(
+                "No longer listening {:?} on address {:?}",
+                listener_id, address
+            );
+            swarm.remove_external_address(&address);
+        }
+        SwarmEvent::ListenerClosed {
+            listener_id,
+            addresses,
+            reason,
+        } => {
+            match reason {
+                Ok(()) => info!("Listener {:?} closed", listener_id),
+                Err(e) => warn!("Listener {:?} closed: {}", listener_id, e),
+            }
+            for address in &addresses {
+                swarm.remove_external_address(address);
+            }
+        }
+        SwarmEvent::ListenerError { listener_id, error } => {
+            warn!("Listener {:?} error: {}", listener_id, error);
+        }
         SwarmEvent::Dialing {
             peer_id,
             connection_id,
         } => {
             info!("Dialing {:?}, connection_id={:?}", peer_id, connection_id);
         }
-        _ => {
-            error!("Unhandled event. Please post github issue.");
+        SwarmEvent::NewExternalAddrCandidate { address }
+        | SwarmEvent::ExternalAddrConfirmed { address }
+        | SwarmEvent::ExternalAddrExpired { address } => {
+            debug!("External address update: {:?}", address);
         }
+        SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
+            debug!("Learned address {:?} of {:?}", address, peer_id);
+        }
+        // Events added by later versions of libp2p are informational.
+        event => debug!("Ignored swarm event: {:?}", event),
     }
 }
 
-/// Handles Floodsub events.
+/// Handles periodic timer ticks.
+///
+/// Reports messages whose delivery receipt did not arrive in time, sending
+/// them by email to contacts with an address, drops fragmented messages
+/// that were not completed in time, forgets idle peers in the rate limiter
+/// and old entries of the deduplication cache, and keeps presence up to
+/// date.
 ///
 /// # Arguments
 ///
-/// * `event` - The Floodsub event.
-async fn handle_floodsub_event(event: libp2p::floodsub::FloodsubEvent) {
-    if let libp2p::floodsub::FloodsubEvent::Message(message) = event {
-        let msg = String::from_utf8_lossy(&message.data);
+/// * `state` - The application state.
+pub fn handle_tick(state: &mut AppState) {
+    for (message_id, pending) in state.deliveries.expire(ACK_TIMEOUT) {
+        match state
+            .email
+            .as_mut()
+            .and_then(|outbox| outbox.send(&message_id))
+        {
+            Some(address) => info!(
+                "Message {} to {:?} undelivered after {:?}, sending it to {}",
+                message_id, pending.recipient, ACK_TIMEOUT, address
+            ),
+            None => warn!(
+                "Message {} to {:?} undelivered after {:?}",
+                message_id, pending.recipient, ACK_TIMEOUT
+            ),
+        }
+    }
+    for (source, set_id) in state.reassembler.expire(REASSEMBLY_TIMEOUT) {
+        warn!(
+            "Dropped incomplete message {} from {:?} after {:?}",
+            set_id, source, REASSEMBLY_TIMEOUT
+        );
+    }
+    state.middleware.tick();
+    for peer in state.presence.expire(PRESENCE_TIMEOUT) {
         info!(
-            "Floodsub message received: '{:?}' from {:?}",
-            msg, message.source
+            "{:?} is gone (no presence for {:?})",
+            peer, PRESENCE_TIMEOUT
         );
     }
+    if state.presence.beacon_due() {
+        publish_presence(state.presence.beacon(), state);
+        // Repeat the profile for peers that joined since it was announced.
+        if state.display_name.is_some() {
+            publish_profile(state);
+        }
+    }
+}
+
+/// Shuts the node down gracefully.
+///
+/// Announces that we go offline, sends the queued messages, leaves the
+/// subscribed topics and closes the connections, handling swarm events
+/// meanwhile. Waits at most `SHUTDOWN_TIMEOUT` for all of it.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub async fn shutdown(swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    state.presence.set_status(PresenceStatus::Offline);
+    publish_presence(state.presence.beacon(), state);
+    let result = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        let local_peer_id = *swarm.local_peer_id();
+        let mut flush_ticker = tokio::time::interval(Duration::from_millis(10));
+        while !state.outbound.is_empty() {
+            tokio::select! {
+                _ = flush_ticker.tick() => {
+                    state
+                        .outbound
+                        .flush(swarm.behaviour_mut(), &mut state.middleware, local_peer_id)
+                }
+                Some(event) = swarm.next() => handle_event(event, swarm, state).await,
+            }
+        }
+        if state.outbound.held() > 0 {
+            warn!(
+                "{} queued messages were not sent, no peer joined their topic",
+                state.outbound.held()
+            );
+        }
+
+        let mut topics: Vec<(String, PubsubProtocol)> = state
+            .topics
+            .subscribed()
+            .map(|(topic, protocol)| (topic.to_string(), protocol))
+            .collect();
+        topics.push((inbox_topic(swarm.local_peer_id()), PubsubProtocol::Both));
+        topics.push((PRESENCE_TOPIC.to_string(), PubsubProtocol::Both));
+        for (topic, protocol) in topics {
+            if let Err(e) = swarm.behaviour_mut().unsubscribe(&topic, protocol) {
+                error!("Failed to leave topic: {:?} on {:?}", e, topic);
+            }
+            state.topics.leave(&topic);
+        }
+
+        let grace = tokio::time::sleep(SHUTDOWN_GRACE);
+        tokio::pin!(grace);
+        loop {
+            tokio::select! {
+                _ = &mut grace => break,
+                Some(event) = swarm.next() => handle_event(event, swarm, state).await,
+            }
+        }
+
+        let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = swarm.disconnect_peer_id(peer);
+        }
+        while swarm.connected_peers().next().is_some() {
+            match swarm.next().await {
+                Some(event) => handle_event(event, swarm, state).await,
+                None => break,
+            }
+        }
+    })
+    .await;
+    if result.is_err() {
+        warn!(
+            "Gave up shutting down gracefully after {:?}",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+}
+
+/// Publishes the local display name in a profile on the presence topic.
+///
+/// # Arguments
+///
+/// * `state` - The application state.
+pub fn publish_profile(state: &mut AppState) {
+    let Some(name) = state.display_name.clone() else {
+        return;
+    };
+    let result = state.outbound.publish_payload(
+        PRESENCE_TOPIC,
+        PubsubProtocol::Both,
+        &Payload::Profile(Profile { name }),
+        state.display_name.clone(),
+        &state.local_key,
+        &mut state.middleware,
+    );
+    if let Err(e) = result {
+        error!("Failed to publish profile: {:?}", e);
+    }
+}
+
+/// Publishes a presence beacon on the presence topic.
+///
+/// # Arguments
+///
+/// * `presence` - The beacon to publish.
+/// * `state` - The application state.
+pub fn publish_presence(presence: Presence, state: &mut AppState) {
+    let result = state.outbound.publish_payload(
+        PRESENCE_TOPIC,
+        PubsubProtocol::Both,
+        &Payload::Presence(presence),
+        state.display_name.clone(),
+        &state.local_key,
+        &mut state.middleware,
+    );
+    if let Err(e) = result {
+        error!("Failed to publish presence: {:?}", e);
+    }
+}
+
+/// Handles Floodsub events.
+///
+/// # Arguments
+///
+/// * `event` - The Floodsub event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+async fn handle_floodsub_event(
+    event: libp2p::floodsub::FloodsubEvent,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    match event {
+        libp2p::floodsub::FloodsubEvent::Message(message) => {
+            info!(
+                "Floodsub message received: {} bytes from {:?}",
+                message.data.len(),
+                message.source
+            );
+            let topic = message.topics.first().map(|t| t.id()).unwrap_or_default();
+            if !state.reassembler.accepts(message.data.len()) {
+                warn!(
+                    "Dropping {} byte message from {:?} on {:?}: too large",
+                    message.data.len(),
+                    message.source,
+                    topic
+                );
+                return;
+            }
+            let context = Context {
+                direction: Direction::Inbound,
+                topic,
+                peer: message.source,
+            };
+            if !state.middleware.filter(&context, &message.data) {
+                return;
+            }
+            handle_message(message.source, topic, &message.data, false, swarm, state);
+        }
+        libp2p::floodsub::FloodsubEvent::Subscribed { topic, .. }
+            if topic.id() == PRESENCE_TOPIC =>
+        {
+            state.presence.announce_soon();
+        }
+        _ => {}
+    }
 }
 
 /// Handles Gossipsub events.
@@ -111,17 +463,1668 @@ async fn handle_floodsub_event(event: libp2p::floodsub::FloodsubEvent) {
 /// # Arguments
 ///
 /// * `event` -  The Gossipsub event.
-async fn handle_gossipsub_event(event: libp2p::gossipsub::Event) {
-    if let libp2p::gossipsub::Event::Message {
-        propagation_source,
-        message_id,
-        message,
-    } = event
-    {
-        let msg = String::from_utf8_lossy(&message.data);
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+async fn handle_gossipsub_event(
+    event: libp2p::gossipsub::Event,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    match event {
+        libp2p::gossipsub::Event::Message {
+            propagation_source,
+            message_id,
+            message,
+        } => {
+            info!(
+                "Gossipsub message received: {} bytes from {:?} with id {:?}, propagation source: {:?}",
+                message.data.len(),
+                message.source,
+                message_id,
+                propagation_source
+            );
+            let source = message.source.unwrap_or(propagation_source);
+            if !state.reassembler.accepts(message.data.len()) {
+                warn!(
+                    "Dropping {} byte message from {:?} on {:?}: too large",
+                    message.data.len(),
+                    source,
+                    message.topic.as_str()
+                );
+                return;
+            }
+            let context = Context {
+                direction: Direction::Inbound,
+                topic: message.topic.as_str(),
+                peer: source,
+            };
+            if !state.middleware.filter(&context, &message.data) {
+                return;
+            }
+            handle_message(
+                source,
+                message.topic.as_str(),
+                &message.data,
+                false,
+                swarm,
+                state,
+            );
+        }
+        libp2p::gossipsub::Event::Subscribed { peer_id, topic } => {
+            let topic = topic.as_str();
+            if topic == PRESENCE_TOPIC {
+                // Let the new peer know about us without waiting a full interval.
+                state.presence.announce_soon();
+            } else if state.topics.is_subscribed(topic)
+                && !is_inbox_topic(topic)
+                && state.history.is_empty(topic)
+            {
+                request_history(peer_id, topic, swarm, state);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Handles history sync events.
+///
+/// # Arguments
+///
+/// * `event` - The history sync event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+async fn handle_history_event(
+    event: request_response::Event<HistoryRequest, HistoryResponse>,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    match event {
+        request_response::Event::Message { peer, message } => match message {
+            request_response::Message::Request {
+                request, channel, ..
+            } => {
+                // Only serve topics we are part of; inboxes are never stored.
+                let envelopes = if !state.topics.is_subscribed(&request.topic) {
+                    Vec::new()
+                } else if is_note_topic(&request.topic) {
+                    note_snapshot(&request.topic, state)
+                } else {
+                    let limit = (request.limit as usize).min(HISTORY_LIMIT);
+                    state.history.recent(&request.topic, limit)
+                };
+                debug!(
+                    "Serving {} envelopes of {:?} to {:?}",
+                    envelopes.len(),
+                    request.topic,
+                    peer
+                );
+                if swarm
+                    .behaviour_mut()
+                    .history
+                    .send_response(channel, HistoryResponse { envelopes })
+                    .is_err()
+                {
+                    error!("Failed to respond to history request from {:?}", peer);
+                }
+            }
+            request_response::Message::Response {
+                request_id,
+                response,
+            } => {
+                let Some(topic) = state.history.take_request(&request_id) else {
+                    return;
+                };
+                if state.topics.is_subscribed(&topic) {
+                    backfill_history(peer, &topic, response.envelopes, state);
+                }
+            }
+        },
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+        } => {
+            if let Some(topic) = state.history.take_request(&request_id) {
+                warn!(
+                    "History request for {:?} to {:?} failed: {:?}",
+                    topic, peer, error
+                );
+            }
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            error!("History request from {:?} failed: {:?}", peer, error);
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+/// Handles ping events, answering pings and recording round trip times.
+///
+/// # Arguments
+///
+/// * `event` - The ping event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn handle_ping_event(
+    event: request_response::Event<Ping, Pong>,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    match event {
+        request_response::Event::Message { peer, message } => match message {
+            request_response::Message::Request {
+                request, channel, ..
+            } => {
+                if swarm
+                    .behaviour_mut()
+                    .ping
+                    .send_response(channel, Pong(request.0))
+                    .is_err()
+                {
+                    debug!("Failed to answer ping from {:?}", peer);
+                }
+            }
+            request_response::Message::Response {
+                request_id,
+                response,
+            } => {
+                if let Some(rtt) = state.peers.pong(&peer, &request_id, &response) {
+                    debug!("Ping to {:?}: {:?}", peer, rtt);
+                }
+            }
+        },
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+        } => {
+            state.peers.ping_failed(&request_id);
+            debug!("Ping to {:?} failed: {:?}", peer, error);
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            debug!("Ping from {:?} failed: {:?}", peer, error);
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+/// Pings a connected peer to measure the round trip time.
+///
+/// # Arguments
+///
+/// * `peer` - The peer to ping.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn ping(peer: PeerId, swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    if !state.versions.supports(&peer, &PING_PROTOCOL) {
+        return;
+    }
+    let nonce = rand::random();
+    let request_id = swarm.behaviour_mut().ping.send_request(&peer, Ping(nonce));
+    state.peers.ping_sent(request_id, nonce);
+}
+
+/// Disconnects the peers banned for flooding the node since the last tick.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn disconnect_banned(swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    let Some(reputation) = state.middleware.get_mut::<Reputation>() else {
+        return;
+    };
+    for peer in reputation.take_banned() {
+        if swarm.disconnect_peer_id(peer).is_ok() {
+            info!("Disconnected {:?}, banned for flooding", peer);
+        }
+    }
+}
+
+/// Pings every connected peer once the ping interval elapsed.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn ping_peers(swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    if !state.peers.ping_due() {
+        return;
+    }
+    let peers: Vec<PeerId> = state
+        .peers
+        .peers()
+        .into_iter()
+        .map(|(peer, _)| *peer)
+        .collect();
+    for peer in peers {
+        ping(peer, swarm, state);
+    }
+}
+
+/// Handles identify events, recording the version and features of peers.
+///
+/// # Arguments
+///
+/// * `event` - The identify event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn handle_identify_event(
+    event: identify::Event,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    let identify::Event::Received { peer_id, info } = event else {
+        return;
+    };
+    let mut version = PeerVersion::new(
+        &info.protocol_version,
+        sanitize(&info.agent_version, MAX_RENDERED_NAME_LEN),
+        info.protocols,
+    );
+    version.listen_addrs = info.listen_addrs.clone();
+    match version.compatibility {
+        Compatibility::Compatible => debug!(
+            "{:?} runs {} with {}",
+            peer_id,
+            version.agent,
+            version.features().join(", ")
+        ),
+        Compatibility::Older(envelope_version) => warn!(
+            "{:?} runs an older {} (envelope version {}) and cannot read our messages",
+            peer_id, version.agent, envelope_version
+        ),
+        Compatibility::Newer(envelope_version) => warn!(
+            "{:?} runs a newer {} (envelope version {}), upgrade to read its messages",
+            peer_id, version.agent, envelope_version
+        ),
+        Compatibility::Foreign => warn!(
+            "{:?} does not run sec_msg ({:?})",
+            peer_id, info.protocol_version
+        ),
+    }
+    if version.compatibility != Compatibility::Compatible {
+        // The mismatch was reported, skip the per-message warnings.
+        state.versions.should_warn(peer_id);
+    }
+    state.versions.insert(peer_id, version);
+    state
+        .nat
+        .observed(peer_id, &info.observed_addr, swarm.listeners());
+    // Announced listen addresses also reach peers that dialed us.
+    for address in info.listen_addrs {
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer_id, address);
+    }
+}
+
+/// Handles topic discovery DHT events.
+///
+/// # Arguments
+///
+/// * `event` - The Kademlia event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn handle_kademlia_event(event: kad::Event, swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    let kad::Event::OutboundQueryProgressed { id, result, .. } = event else {
+        return;
+    };
+    match result {
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+            providers,
+            ..
+        })) if state.discovery.is_tracked(&id) => {
+            let local_peer_id = *swarm.local_peer_id();
+            for provider in providers.into_iter().filter(|p| *p != local_peer_id) {
+                let query = swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_record(advertisement_key(&provider));
+                state.discovery.track(query);
+            }
+        }
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(found)))
+            if state.discovery.is_tracked(&id) =>
+        {
+            let opened = Envelope::decode(&found.record.value)
+                .and_then(|envelope| state.middleware.open(DIRECTORY, &envelope));
+            match opened {
+                // Only the peer a record is keyed by may publish it.
+                Ok((signer, Payload::Topics(topics)))
+                    if advertisement_key(&signer) == found.record.key =>
+                {
+                    for topic in state.discovery.found(signer, topics) {
+                        info!("Discovered topic {:?} advertised by {:?}", topic, signer);
+                    }
+                }
+                Ok((signer, _)) => {
+                    warn!("Ignoring topic advertisement forged by {:?}", signer);
+                }
+                Err(e) => warn!("Ignoring malformed topic advertisement: {}", e),
+            }
+        }
+        kad::QueryResult::GetProviders(Err(e)) => debug!("Topic discovery failed: {:?}", e),
+        kad::QueryResult::GetRecord(Err(e)) => debug!("Topic lookup failed: {:?}", e),
+        kad::QueryResult::PutRecord(Err(e)) => debug!("Topic advertisement failed: {:?}", e),
+        kad::QueryResult::StartProviding(Err(e)) => {
+            debug!("Topic directory announcement failed: {:?}", e)
+        }
+        _ => {}
+    }
+}
+
+/// Publishes the advertised topics on the topic discovery DHT.
+///
+/// The local peer provides the directory key while it advertises any topic.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn advertise_topics(swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    let local_peer_id = *swarm.local_peer_id();
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    let topics = state.discovery.advertised();
+    if topics.is_empty() {
+        kademlia.stop_providing(&directory_key());
+        kademlia.remove_record(&advertisement_key(&local_peer_id));
+        return;
+    }
+    let data = state
+        .middleware
+        .seal(
+            DIRECTORY,
+            &Payload::Topics(topics),
+            state.display_name.clone(),
+            &state.local_key,
+        )
+        .and_then(|envelope| Ok(envelope.encode()?));
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to seal topic advertisement: {:?}", e);
+            return;
+        }
+    };
+    let record = kad::Record::new(advertisement_key(&local_peer_id), data);
+    if let Err(e) = kademlia.put_record(record, kad::Quorum::One) {
+        error!("Failed to store topic advertisement: {:?}", e);
+        return;
+    }
+    if let Err(e) = kademlia.start_providing(directory_key()) {
+        error!("Failed to announce topic directory: {:?}", e);
+    }
+}
+
+/// Asks a peer for the recent envelopes of a topic.
+///
+/// # Arguments
+///
+/// * `peer` - The peer subscribed to the topic.
+/// * `topic` - The topic to backfill.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn request_history(
+    peer: PeerId,
+    topic: &str,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    let request = HistoryRequest {
+        topic: topic.to_string(),
+        limit: HISTORY_LIMIT as u32,
+    };
+    let request_id = swarm.behaviour_mut().history.send_request(&peer, request);
+    state.history.track_request(request_id, topic);
+    debug!("Requested history of {:?} from {:?}", topic, peer);
+}
+
+/// Verifies and displays envelopes received from a history request.
+///
+/// Envelopes already seen are skipped. Moderation directives are applied so
+/// the topic roles are known, but kicks are not enforced retroactively.
+/// Only the entries that verify are kept, and of those only the kinds kept
+/// when received live.
+///
+/// # Arguments
+///
+/// * `peer` - The peer that served the history.
+/// * `topic` - The backfilled topic.
+/// * `envelopes` - The encoded envelopes, oldest first.
+/// * `state` - The application state.
+fn backfill_history(peer: PeerId, topic: &str, envelopes: Vec<ByteBuf>, state: &mut AppState) {
+    let mut backfilled = 0;
+    for data in envelopes {
+        // Also skip envelopes being received live right now.
+        let seen = state.middleware.get_mut::<DedupCache>();
+        if state.history.contains(topic, &data)
+            || seen.is_some_and(|seen| !seen.insert(topic, &data))
+        {
+            continue;
+        }
+        let opened = Envelope::decode(&data).and_then(|envelope| {
+            let (signer, payload) = state.middleware.open(topic, &envelope)?;
+            Ok((envelope, signer, payload))
+        });
+        let (envelope, signer, payload) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!(
+                    "Ignoring history entry from {:?} on {:?}: {}",
+                    peer, topic, e
+                );
+                continue;
+            }
+        };
+        let kept = match payload {
+            Payload::Moderation(_) => true,
+            Payload::Text(_) | Payload::Reaction(_) => state.moderation.is_member(topic, &signer),
+            _ => false,
+        };
+        if kept {
+            state.history.record(topic, &data);
+        }
+        match payload {
+            Payload::Text(_) if is_hidden(state, topic, &signer) => {}
+            Payload::Text(text) => {
+                state.reactions.record(text.id, topic);
+                let sender = state.profiles.label(&signer, envelope.sender.as_deref());
+                let mut message = state.renderer.render(
+                    topic,
+                    &text.id,
+                    &signer,
+                    &sanitize(&sender, MAX_RENDERED_NAME_LEN),
+                    envelope.timestamp,
+                    &sanitize_multiline(&text.body, MAX_RENDERED_LEN),
+                );
+                message.highlight = state
+                    .notifier
+                    .highlight(&message.body, state.display_name.as_deref())
+                    .is_some();
+                state.renderer.show(message);
+            }
+            Payload::Reaction(_) if is_hidden(state, topic, &signer) => {}
+            Payload::Reaction(reaction) => handle_reaction(signer, topic, &reaction, state),
+            Payload::Moderation(directive) if directive.topic == topic => {
+                match state
+                    .moderation
+                    .apply(&signer, &directive, envelope.timestamp)
+                {
+                    Ok(()) => save_moderation(state),
+                    Err(e) => debug!("Skipping moderation history entry on {:?}: {}", topic, e),
+                }
+            }
+            Payload::Note(ops) if is_note_topic(topic) => {
+                apply_note(topic, ops, state);
+            }
+            _ => continue,
+        }
+        backfilled += 1;
+    }
+    if backfilled > 0 {
         info!(
-            "Gossipsub message received: '{:?}' from {:?} wit id {:?}, propagation source: {:?}",
-            msg, message.source, message_id, propagation_source
+            "Backfilled {} messages of {:?} from {:?}",
+            backfilled, topic, peer
         );
     }
 }
+
+/// Restores the history saved by the previous run.
+///
+/// Envelopes of the topics joined are verified and shown like backfilled
+/// ones, and those of other topics are kept to be served and saved again.
+///
+/// # Arguments
+///
+/// * `saved` - The saved envelopes of each topic, oldest first.
+/// * `state` - The application state.
+pub fn restore_history(saved: SavedHistory, state: &mut AppState) {
+    let local_peer_id = PeerId::from(state.local_key.public());
+    for (topic, envelopes) in saved {
+        if state.topics.is_subscribed(&topic) {
+            backfill_history(local_peer_id, &topic, envelopes, state);
+        } else {
+            for data in envelopes {
+                state.history.record(&topic, &data);
+            }
+        }
+    }
+}
+
+/// Handles file transfer events.
+///
+/// # Arguments
+///
+/// * `event` - The file transfer event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+async fn handle_file_transfer_event(
+    event: request_response::Event<FileRequest, FileResponse>,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    match event {
+        request_response::Event::Message { peer, message } => match message {
+            request_response::Message::Request {
+                request, channel, ..
+            } => {
+                let response = match request {
+                    FileRequest::Offer {
+                        transfer_id,
+                        name,
+                        size,
+                        sha256,
+                    } => match state
+                        .transfers
+                        .receive_offer(peer, transfer_id, &name, size, sha256)
+                    {
+                        Ok(()) => {
+                            info!(
+                                "{:?} offers file {:?} ({} bytes), use /accept-file {} to download it",
+                                peer, name, size, transfer_id
+                            );
+                            FileResponse::Ack
+                        }
+                        Err(e) => FileResponse::Error(e.to_string()),
+                    },
+                    FileRequest::Chunk {
+                        transfer_id,
+                        offset,
+                        length,
+                        compression,
+                    } => {
+                        state
+                            .transfers
+                            .read_chunk(&peer, &transfer_id, offset, length, compression)
+                    }
+                };
+                let class = match response {
+                    FileResponse::Chunk { .. } => TrafficClass::Bulk,
+                    _ => TrafficClass::Control,
+                };
+                state
+                    .outbound
+                    .push(class, Outbound::FileResponse { channel, response });
+            }
+            request_response::Message::Response {
+                request_id,
+                response,
+            } => {
+                let Some((transfer_id, offset)) = state.transfers.take_request(&request_id) else {
+                    match response {
+                        FileResponse::Error(e) => error!("{:?} rejected file offer: {}", peer, e),
+                        _ => info!("{:?} received file offer", peer),
+                    }
+                    return;
+                };
+                match response {
+                    FileResponse::Chunk { compression, data } => {
+                        match decompress_chunk(compression, &data) {
+                            Ok(data) => {
+                                receive_chunk(peer, transfer_id, offset, &data, swarm, state)
+                            }
+                            Err(e) => {
+                                error!("Transfer {} from {:?} failed: {}", transfer_id, peer, e);
+                                state.transfers.suspend(&transfer_id);
+                            }
+                        }
+                    }
+                    FileResponse::Error(e) => {
+                        error!("Transfer {} from {:?} failed: {}", transfer_id, peer, e);
+                        state.transfers.suspend(&transfer_id);
+                    }
+                    FileResponse::Ack => {
+                        error!("Unexpected response to chunk request from {:?}", peer);
+                        state.transfers.suspend(&transfer_id);
+                    }
+                }
+            }
+        },
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+        } => match state.transfers.take_request(&request_id) {
+            Some((transfer_id, _)) => {
+                warn!(
+                    "Transfer {} from {:?} interrupted: {:?}, use /accept-file {} to resume",
+                    transfer_id, peer, error, transfer_id
+                );
+                state.transfers.suspend(&transfer_id);
+            }
+            None => error!("Failed to send file offer to {:?}: {:?}", peer, error),
+        },
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            error!("File request from {:?} failed: {:?}", peer, error);
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+/// Handles streams opened for large file transfers.
+///
+/// Streams are handed to transfer tasks. A peer that cannot open a stream
+/// falls back to the chunked file transfer protocol.
+///
+/// # Arguments
+///
+/// * `event` - The streaming event.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn handle_streams_event(event: StreamsEvent, swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    match event {
+        StreamsEvent::Inbound { peer, stream } => {
+            tokio::spawn(read_request(peer, stream, state.events.clone()));
+        }
+        StreamsEvent::Outbound {
+            peer,
+            transfer_id,
+            stream,
+        } => {
+            let Some(file) = state.transfers.incoming_mut(&transfer_id) else {
+                return;
+            };
+            info!(
+                "Streaming {:?} from {:?}, starting at {} of {} bytes",
+                file.name, peer, file.received, file.size
+            );
+            tokio::spawn(receive_file(
+                peer,
+                transfer_id,
+                stream,
+                file.part_path().to_path_buf(),
+                file.received,
+                file.size,
+                state.events.clone(),
+            ));
+        }
+        StreamsEvent::Failed {
+            peer,
+            transfer_id,
+            error,
+        } => {
+            let Some(file) = state.transfers.incoming_mut(&transfer_id) else {
+                return;
+            };
+            warn!(
+                "Could not open a stream to {:?} ({}), falling back to chunked transfer",
+                peer, error
+            );
+            let request = file.next_request(transfer_id);
+            let request_id = swarm
+                .behaviour_mut()
+                .file_transfer
+                .send_request(&peer, request.clone());
+            state.transfers.track_request(request_id, &request);
+        }
+    }
+}
+
+/// Handles progress reported by the streamed transfer tasks.
+///
+/// # Arguments
+///
+/// * `event` - The transfer event.
+/// * `state` - The application state.
+pub fn handle_transfer_event(event: TransferEvent, state: &mut AppState) {
+    match event {
+        TransferEvent::Requested {
+            peer,
+            transfer_id,
+            offset,
+            stream,
+        } => match state.transfers.outgoing_path(&peer, &transfer_id) {
+            Some(path) => {
+                info!(
+                    "Streaming transfer {} to {:?} from offset {}",
+                    transfer_id, peer, offset
+                );
+                tokio::spawn(send_file(
+                    peer,
+                    transfer_id,
+                    stream,
+                    path.to_path_buf(),
+                    offset,
+                    state.events.clone(),
+                ));
+            }
+            // Dropping the stream closes it.
+            None => warn!("{:?} requested unknown transfer {}", peer, transfer_id),
+        },
+        TransferEvent::Progress {
+            transfer_id,
+            received,
+        } => {
+            if let Some(file) = state.transfers.incoming_mut(&transfer_id) {
+                file.received = received;
+                debug!(
+                    "Transfer {}: {} of {} bytes",
+                    transfer_id, received, file.size
+                );
+            }
+        }
+        TransferEvent::Received {
+            peer,
+            transfer_id,
+            result,
+        } => match result {
+            Ok(received) => {
+                if let Some(file) = state.transfers.incoming_mut(&transfer_id) {
+                    file.received = received;
+                }
+                finish_transfer(peer, transfer_id, state);
+            }
+            Err((e, received)) => {
+                if let Some(file) = state.transfers.incoming_mut(&transfer_id) {
+                    file.received = received;
+                }
+                warn!(
+                    "Transfer {} from {:?} interrupted: {}, use /accept-file {} to resume",
+                    transfer_id, peer, e, transfer_id
+                );
+                state.transfers.suspend(&transfer_id);
+            }
+        },
+        TransferEvent::Sent {
+            peer,
+            transfer_id,
+            result,
+        } => match result {
+            Ok(sent) => info!("Streamed {} bytes of {} to {:?}", sent, transfer_id, peer),
+            Err(e) => warn!("Streaming {} to {:?} stopped: {}", transfer_id, peer, e),
+        },
+    }
+}
+
+/// Writes a received chunk and requests the next one, or verifies and saves
+/// the file once it is complete.
+///
+/// # Arguments
+///
+/// * `peer` - The peer sending the file.
+/// * `transfer_id` - The transfer the chunk belongs to.
+/// * `offset` - The offset the chunk was requested at.
+/// * `data` - The chunk data.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn receive_chunk(
+    peer: PeerId,
+    transfer_id: TransferId,
+    offset: u64,
+    data: &[u8],
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    let Some(file) = state.transfers.incoming_mut(&transfer_id) else {
+        return;
+    };
+    if let Err(e) = file.write_chunk(offset, data) {
+        error!("Transfer {} from {:?} failed: {}", transfer_id, peer, e);
+        state.transfers.suspend(&transfer_id);
+        return;
+    }
+
+    if !file.is_complete() {
+        if data.is_empty() {
+            error!("Transfer {} from {:?} stalled", transfer_id, peer);
+            state.transfers.suspend(&transfer_id);
+            return;
+        }
+        let request = file.next_request(transfer_id);
+        let request_id = swarm
+            .behaviour_mut()
+            .file_transfer
+            .send_request(&peer, request.clone());
+        state.transfers.track_request(request_id, &request);
+        return;
+    }
+    finish_transfer(peer, transfer_id, state);
+}
+
+/// Verifies and saves a completely received file.
+///
+/// # Arguments
+///
+/// * `peer` - The peer that sent the file.
+/// * `transfer_id` - The finished transfer.
+/// * `state` - The application state.
+fn finish_transfer(peer: PeerId, transfer_id: TransferId, state: &mut AppState) {
+    if let Some(file) = state.transfers.complete(&transfer_id) {
+        match file.finish() {
+            Ok(path) => {
+                info!("Received file {:?} from {:?}", path, peer);
+                state.renderer.emit(
+                    JsonLine::new("file_received")
+                        .str("peer", &peer.to_base58())
+                        .str("path", &path.to_string_lossy()),
+                );
+            }
+            Err(e) => error!("Transfer {} from {:?} failed: {}", transfer_id, peer, e),
+        }
+    }
+}
+
+/// Returns whether the messages of a peer are hidden on a topic, because
+/// it is muted, not a member of a private topic, or ignored.
+pub(crate) fn is_hidden(state: &AppState, topic: &str, peer: &PeerId) -> bool {
+    state.moderation.is_hidden(topic, peer) || state.ignored.is_ignored(peer)
+}
+
+/// Shows a received text message, acknowledging its delivery if asked to
+/// and its reading once the user saw it.
+///
+/// # Arguments
+///
+/// * `received` - The message and where it came from.
+/// * `flag` - Why a content filter flagged the message, if one did.
+/// * `state` - The application state.
+pub fn show_text(received: &Quarantined, flag: Option<String>, state: &mut AppState) {
+    let Quarantined {
+        topic,
+        source,
+        text,
+        ..
+    } = received;
+    if text.ack_requested {
+        send_receipt(text.id, ReceiptKind::Delivered, *source, state);
+    }
+    state.reactions.record(text.id, topic);
+    // Peers control the body and the claimed name, keep them from taking
+    // over the terminal.
+    let sender = sanitize(
+        &state.profiles.label(source, received.sender.as_deref()),
+        MAX_RENDERED_NAME_LEN,
+    );
+    let body = sanitize_multiline(&text.body, MAX_RENDERED_LEN);
+    let mut message =
+        state
+            .renderer
+            .render(topic, &text.id, source, &sender, received.timestamp, &body);
+    let highlight = state
+        .notifier
+        .highlight(&body, state.display_name.as_deref());
+    message.highlight = highlight.is_some();
+    message.flag = flag;
+    notify_message(topic, &sender, &body, highlight, state);
+    state.renderer.show(message);
+    if text.ack_requested {
+        state.read_receipts.arrived(text.id, *source);
+        // Without the terminal UI, messages are written out as they arrive.
+        if !state.renderer.has_ui() {
+            handle_read(text.id, state);
+        }
+    }
+}
+
+/// Sends a read receipt for a message the user saw, if its sender asked
+/// for one and may get it.
+///
+/// # Arguments
+///
+/// * `id` - The id of the message.
+/// * `state` - The application state.
+pub fn handle_read(id: MessageId, state: &mut AppState) {
+    if let Some(sender) = state.read_receipts.read(&id) {
+        send_receipt(id, ReceiptKind::Read, sender, state);
+    }
+}
+
+/// Holds a text message back for review, unless its sender is hidden.
+///
+/// # Arguments
+///
+/// * `received` - The message, where it came from and why it is held back.
+/// * `state` - The application state.
+fn quarantine(received: Quarantined, state: &mut AppState) {
+    if is_hidden(state, &received.topic, &received.source) {
+        return;
+    }
+    let (topic, source) = (received.topic.clone(), received.source);
+    let number = state.quarantine.hold(received);
+    info!(
+        "[{}] Quarantined message {} from {:?}, see /quarantine",
+        topic, number, source
+    );
+}
+
+/// Runs the hooks again on a text message a content filter held back,
+/// once it decided on it, and shows the message unless a hook stops it.
+///
+/// # Arguments
+///
+/// * `id` - The id of the message.
+/// * `state` - The application state.
+pub fn handle_decided(id: MessageId, state: &mut AppState) {
+    let Some((mut received, data)) = state.pending.remove(&id) else {
+        return;
+    };
+    let context = HookContext {
+        direction: Direction::Inbound,
+        topic: &received.topic,
+        peer: received.source,
+        sender: received.sender.as_deref(),
+    };
+    let mut payload = Payload::Text(received.text.clone());
+    let verdict = state.hooks.run(&context, &mut payload);
+    if let Payload::Text(text) = payload {
+        received.text = text;
+    }
+    let flag = match verdict {
+        Verdict::Continue => None,
+        Verdict::Flag(reason) => Some(reason),
+        Verdict::Drop(_) => return,
+        Verdict::Quarantine(reason) => {
+            received.reason = reason;
+            quarantine(received, state);
+            return;
+        }
+        Verdict::Pending => {
+            state.pending.insert(id, (received, data));
+            return;
+        }
+    };
+    state.history.record(&received.topic, &data);
+    if is_hidden(state, &received.topic, &received.source) {
+        debug!(
+            "Hiding message from muted, uninvited or ignored peer {:?} on {:?}",
+            received.source, received.topic
+        );
+        return;
+    }
+    show_text(&received, flag, state);
+}
+
+/// Decodes and handles the payload of a received message.
+///
+/// # Arguments
+///
+/// * `source` - The peer that authored the message.
+/// * `topic` - The topic the message was published to.
+/// * `data` - The raw message data.
+/// * `reassembled` - Whether the data was reassembled from fragments, whose
+///   timestamps were checked instead.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn handle_message(
+    source: PeerId,
+    topic: &str,
+    data: &[u8],
+    reassembled: bool,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    let envelope = match Envelope::decode(data) {
+        Ok(envelope) => envelope,
+        Err(EnvelopeError::UnsupportedVersion(version)) => {
+            if state.versions.should_warn(source) {
+                warn!(
+                    "Ignoring envelope version {} from {:?} on {:?}, the peer may be running a newer version",
+                    version, source, topic
+                );
+            } else {
+                debug!(
+                    "Ignoring envelope version {} from {:?} on {:?}",
+                    version, source, topic
+                );
+            }
+            return;
+        }
+        Err(e) => {
+            error!(
+                "Failed to decode message from {:?} on {:?}: {}",
+                source, topic, e
+            );
+            return;
+        }
+    };
+    // Replays outlive the dedup cache, so only recent envelopes are taken.
+    if !reassembled {
+        if let Err(e) = envelope.check_fresh() {
+            warn!("Rejected message from {:?} on {:?}: {}", source, topic, e);
+            return;
+        }
+    }
+
+    let (signer, payload) = match state.middleware.open(topic, &envelope) {
+        Ok(opened) => opened,
+        Err(EnvelopeError::UnknownKind(kind)) => {
+            if state.versions.should_warn(source) {
+                warn!(
+                    "Ignoring unknown envelope kind {} from {:?} on {:?}, the peer may be running a newer version",
+                    kind, source, topic
+                );
+            } else {
+                debug!(
+                    "Ignoring unknown envelope kind {} from {:?} on {:?}",
+                    kind, source, topic
+                );
+            }
+            return;
+        }
+        Err(EnvelopeError::Compression(CompressionError::Unsupported(id))) => {
+            warn!(
+                "Ignoring envelope with unsupported compression {} from {:?} on {:?}",
+                id, source, topic
+            );
+            return;
+        }
+        Err(e) => {
+            error!("Rejected message from {:?} on {:?}: {}", source, topic, e);
+            return;
+        }
+    };
+    if signer != source {
+        error!(
+            "Rejected message from {:?} on {:?}: envelope signed by {:?}",
+            source, topic, signer
+        );
+        return;
+    }
+    state.peers.saw(source);
+    // Outsiders only get moderation directives, which carry the joins, into
+    // a private group.
+    if !matches!(payload, Payload::Moderation(_)) && !state.moderation.is_member(topic, &source) {
+        debug!(
+            "Dropping message from non-member {:?} of {:?}",
+            source, topic
+        );
+        return;
+    }
+    let mut payload = payload;
+    let mut flag = None;
+    // Fragments are hooked once reassembled.
+    if !matches!(payload, Payload::Fragment(_)) {
+        let context = HookContext {
+            direction: Direction::Inbound,
+            topic,
+            peer: source,
+            sender: envelope.sender.as_deref(),
+        };
+        match state.hooks.run(&context, &mut payload) {
+            Verdict::Continue => {}
+            Verdict::Flag(reason) => flag = Some(reason),
+            Verdict::Drop(_) => return,
+            Verdict::Quarantine(reason) => {
+                if let Payload::Text(text) = payload {
+                    let received = Quarantined {
+                        number: 0,
+                        topic: topic.to_string(),
+                        source,
+                        sender: envelope.sender.clone(),
+                        timestamp: envelope.timestamp,
+                        text,
+                        reason,
+                    };
+                    quarantine(received, state);
+                }
+                return;
+            }
+            Verdict::Pending => {
+                if let Payload::Text(text) = payload {
+                    let received = Quarantined {
+                        number: 0,
+                        topic: topic.to_string(),
+                        source,
+                        sender: envelope.sender.clone(),
+                        timestamp: envelope.timestamp,
+                        text,
+                        reason: String::new(),
+                    };
+                    state
+                        .pending
+                        .insert(received.text.id, (received, data.to_vec()));
+                }
+                return;
+            }
+        }
+    }
+    if matches!(
+        payload,
+        Payload::Text(_) | Payload::Moderation(_) | Payload::Reaction(_)
+    ) {
+        state.history.record(topic, data);
+    }
+
+    match payload {
+        Payload::Text(_) if is_hidden(state, topic, &source) => {
+            debug!(
+                "Hiding message from muted, uninvited or ignored peer {:?} on {:?}",
+                source, topic
+            );
+        }
+        Payload::Text(text) => {
+            let received = Quarantined {
+                number: 0,
+                topic: topic.to_string(),
+                source,
+                sender: envelope.sender.clone(),
+                timestamp: envelope.timestamp,
+                text,
+                reason: String::new(),
+            };
+            show_text(&received, flag, state);
+        }
+        Payload::Moderation(directive) => {
+            handle_moderation(source, topic, directive, envelope.timestamp, swarm, state)
+        }
+        Payload::Note(ops) => {
+            if is_note_topic(topic) && apply_note(topic, ops, state) {
+                info!("[{}] Note edited by {:?}", topic, source);
+            }
+        }
+        Payload::Presence(presence) => {
+            if topic == PRESENCE_TOPIC {
+                handle_presence(source, presence, envelope.sender.clone(), state);
+            }
+        }
+        Payload::Reaction(_) if is_hidden(state, topic, &source) => {
+            debug!(
+                "Hiding reaction from muted, uninvited or ignored peer {:?} on {:?}",
+                source, topic
+            );
+        }
+        Payload::Reaction(reaction) => handle_reaction(source, topic, &reaction, state),
+        Payload::Profile(profile) => {
+            if topic == PRESENCE_TOPIC {
+                handle_profile(source, profile, state);
+            }
+        }
+        Payload::Topics(_) => {
+            debug!("Ignoring topic advertisement published on {:?}", topic);
+        }
+        Payload::Binary(_) if is_hidden(state, topic, &source) => {
+            debug!(
+                "Hiding binary message from muted, uninvited or ignored peer {:?} on {:?}",
+                source, topic
+            );
+        }
+        Payload::Binary(binary) => {
+            handle_binary(source, topic, &binary, envelope.sender.as_deref(), state)
+        }
+        Payload::Fragment(fragment) => {
+            if let Some(data) = state.reassembler.insert(source, fragment) {
+                handle_message(source, topic, &data, true, swarm, state);
+            }
+        }
+        Payload::Receipt(receipt) => {
+            if state
+                .deliveries
+                .confirm(&receipt.message_id, receipt.kind, &signer)
+            {
+                if let Some(outbox) = &mut state.email {
+                    outbox.release(&receipt.message_id);
+                }
+                let kind = match receipt.kind {
+                    ReceiptKind::Delivered => {
+                        info!("Message {} delivered to {:?}", receipt.message_id, signer);
+                        "delivered"
+                    }
+                    ReceiptKind::Read => {
+                        info!("Message {} seen by {:?}", receipt.message_id, signer);
+                        "read"
+                    }
+                };
+                state.renderer.emit(
+                    JsonLine::new("receipt")
+                        .str("id", &receipt.message_id.to_string())
+                        .str("peer", &signer.to_base58())
+                        .str("kind", kind),
+                );
+            }
+        }
+    }
+}
+
+/// Shows a reply to a direct message that a contact sent by email, in the
+/// conversation with that contact.
+///
+/// # Arguments
+///
+/// * `peer` - The contact whose address the email came from.
+/// * `body` - The text of the reply.
+/// * `state` - The application state.
+pub fn handle_email(peer: PeerId, body: &str, state: &mut AppState) {
+    let local_peer_id = state.local_key.public().to_peer_id();
+    let topic = inbox_topic(&local_peer_id);
+    if is_hidden(state, &topic, &peer) {
+        debug!("Hiding email reply from ignored peer {:?}", peer);
+        return;
+    }
+    // Sender addresses are easily forged, so the reply is marked.
+    let sender = sanitize(
+        &format!("{} (email)", state.profiles.label(&peer, None)),
+        MAX_RENDERED_NAME_LEN,
+    );
+    let body = sanitize_multiline(body, MAX_RENDERED_LEN);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let message = state.renderer.render(
+        &topic,
+        &MessageId::random(),
+        &peer,
+        &sender,
+        timestamp,
+        &body,
+    );
+    notify_message(&topic, &sender, &body, None, state);
+    state.renderer.show(message);
+}
+
+/// Applies a reaction and renders the updated reactions of its target.
+///
+/// # Arguments
+///
+/// * `source` - The peer that signed the reaction.
+/// * `topic` - The topic the reaction was received on.
+/// * `reaction` - The reaction.
+/// * `state` - The application state.
+pub fn handle_reaction(source: PeerId, topic: &str, reaction: &Reaction, state: &mut AppState) {
+    if !state.reactions.apply(source, topic, reaction) {
+        debug!(
+            "Ignoring reaction from {:?} to {} on {:?}",
+            source, reaction.target, topic
+        );
+        return;
+    }
+    let summary = state.reactions.summary(&reaction.target);
+    info!("[{}] #{} {}", topic, reaction.target.short(), summary);
+    state.renderer.emit(
+        JsonLine::new("reactions")
+            .str("id", &reaction.target.to_string())
+            .str("topic", topic)
+            .str("summary", &summary),
+    );
+}
+
+/// Records a presence beacon and reports status changes.
+///
+/// # Arguments
+///
+/// * `source` - The peer that signed the beacon.
+/// * `presence` - The beacon.
+/// * `name` - The display name claimed by the peer, if any.
+/// * `state` - The application state.
+fn handle_presence(source: PeerId, presence: Presence, name: Option<String>, state: &mut AppState) {
+    let status = presence.status;
+    let previous = state.presence.update(source, presence, name);
+    if previous == Some(status) || (previous.is_none() && status == PresenceStatus::Offline) {
+        return;
+    }
+    info!("{:?} is now {}", source, status);
+    state.renderer.emit(
+        JsonLine::new("presence")
+            .str("peer", &source.to_base58())
+            .str("status", &status.to_string()),
+    );
+}
+
+/// Raises a desktop notification for a direct message, a mention or a
+/// watched keyword.
+///
+/// # Arguments
+///
+/// * `topic` - The topic the message was received on.
+/// * `sender` - How the author of the message is shown.
+/// * `body` - The message text.
+/// * `highlight` - The display name or keyword the message is highlighted
+///   for, if any.
+/// * `state` - The application state.
+fn notify_message(
+    topic: &str,
+    sender: &str,
+    body: &str,
+    highlight: Option<&str>,
+    state: &AppState,
+) {
+    let summary = match highlight {
+        _ if is_inbox_topic(topic) => format!("Direct message from {}", sender),
+        Some(_) if !state.notifier.highlights() => return,
+        Some(word) if state.display_name.as_deref() == Some(word) => {
+            format!("{} mentioned you in {}", sender, topic)
+        }
+        Some(word) => format!("{} mentioned {} in {}", sender, word, topic),
+        None => return,
+    };
+    if state.notifier.should_notify(topic) {
+        state.notifier.notify(summary, body.to_string());
+    }
+}
+
+/// Reports binary data published on a topic, which is never shown as chat.
+///
+/// # Arguments
+///
+/// * `source` - The peer that signed the message.
+/// * `topic` - The topic the message was published on.
+/// * `binary` - The message.
+/// * `sender` - The display name claimed with the message, if any.
+/// * `state` - The application state.
+fn handle_binary(
+    source: PeerId,
+    topic: &str,
+    binary: &BinaryMessage,
+    sender: Option<&str>,
+    state: &mut AppState,
+) {
+    if !binary.is_valid() {
+        debug!(
+            "Ignoring binary message with an invalid media type from {:?} on {:?}",
+            source, topic
+        );
+        return;
+    }
+    let sender = sanitize(
+        &state.profiles.label(&source, sender),
+        MAX_RENDERED_NAME_LEN,
+    );
+    info!(
+        "[{}] {} sent {} bytes of {}",
+        topic,
+        sender,
+        binary.data.len(),
+        binary.media_type
+    );
+    state.renderer.emit(
+        JsonLine::new("binary")
+            .str("topic", topic)
+            .str("id", &binary.id.to_string())
+            .str("peer", &source.to_base58())
+            .str("media_type", &binary.media_type)
+            .num("size", binary.data.len() as u64),
+    );
+}
+
+/// Records the display name announced by a peer.
+///
+/// # Arguments
+///
+/// * `source` - The peer that signed the profile.
+/// * `profile` - The announced profile.
+/// * `state` - The application state.
+fn handle_profile(source: PeerId, profile: Profile, state: &mut AppState) {
+    if !profile.is_valid() {
+        debug!("Ignoring invalid profile from {:?}", source);
+        return;
+    }
+    if !state.profiles.set(source, profile.name.clone()) {
+        return;
+    }
+    info!("{:?} is now known as {}", source, profile.name);
+    let (peers, own) = state.profiles.collisions(&source, &profile.name);
+    if own {
+        warn!(
+            "{:?} also calls itself {}, like you; it is shown as {}",
+            source,
+            profile.name,
+            state.profiles.label(&source, None)
+        );
+    }
+    for peer in peers {
+        warn!(
+            "{:?} and {:?} both call themselves {}; they are shown as {} and {}",
+            source,
+            peer,
+            profile.name,
+            state.profiles.label(&source, None),
+            state.profiles.label(&peer, None)
+        );
+    }
+}
+
+/// Applies edits to the local replica of a shared note.
+///
+/// # Arguments
+///
+/// * `topic` - The note topic.
+/// * `ops` - The received edits.
+/// * `state` - The application state.
+///
+/// # Returns
+///
+/// Whether the visible text of the note changed.
+fn apply_note(topic: &str, ops: Vec<NoteOp>, state: &mut AppState) -> bool {
+    let site = state.local_key.public().to_peer_id();
+    state
+        .notes
+        .entry(topic.to_string())
+        .or_insert_with(|| Note::new(site))
+        .apply(ops)
+}
+
+/// Seals the full state of a shared note for a history response.
+///
+/// Note edits are not kept in the envelope history, since a late joiner
+/// needs every edit rather than the most recent ones.
+///
+/// # Arguments
+///
+/// * `topic` - The note topic.
+/// * `state` - The application state.
+fn note_snapshot(topic: &str, state: &mut AppState) -> Vec<ByteBuf> {
+    let Some(ops) = state.notes.get(topic).map(Note::snapshot) else {
+        return Vec::new();
+    };
+    if ops.is_empty() {
+        return Vec::new();
+    }
+    let payload = Payload::Note(ops);
+    let sealed = state.middleware.seal(
+        topic,
+        &payload,
+        state.display_name.clone(),
+        &state.local_key,
+    );
+    match sealed.and_then(|envelope| envelope.encode().map_err(Into::into)) {
+        Ok(data) => vec![ByteBuf::from(data)],
+        Err(e) => {
+            error!("Failed to seal snapshot of {:?}: {}", topic, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Validates and enforces a moderation directive.
+///
+/// # Arguments
+///
+/// * `issuer` - The peer that signed the directive.
+/// * `topic` - The topic the directive was received on.
+/// * `directive` - The moderation directive.
+/// * `issued` - When the directive was issued, in milliseconds since the
+///   epoch.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+fn handle_moderation(
+    issuer: PeerId,
+    topic: &str,
+    directive: ModerationAction,
+    issued: u64,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    if directive.topic != topic {
+        error!(
+            "Rejected moderation directive for {:?} received on {:?}",
+            directive.topic, topic
+        );
+        return;
+    }
+    // Votes arriving after a nominee became a moderator change nothing.
+    let promoted = match &directive.action {
+        Action::Nominate { peer } => !state.moderation.is_moderator(topic, peer),
+        _ => false,
+    };
+    match state.moderation.apply(&issuer, &directive, issued) {
+        Ok(()) => {}
+        Err(Rejection::NotFounded) => {
+            debug!(
+                "Keeping moderation directive {:?} from {:?} until {:?} is founded",
+                directive.action, issuer, topic
+            );
+            state.moderation.defer(issuer, directive, issued);
+            return;
+        }
+        Err(e) => {
+            warn!(
+                "Rejected moderation directive {:?} from {:?} on {:?}: {}",
+                directive.action, issuer, topic, e
+            );
+            return;
+        }
+    }
+    save_moderation(state);
+    let founded = directive.action == Action::Found;
+
+    let local_peer_id = state.local_key.public().to_peer_id();
+    let change = match &directive.action {
+        Action::Join { .. } => {
+            info!("[{}] {:?} joined with an invite", topic, issuer);
+            Some((issuer, "member_added"))
+        }
+        Action::Add { peer } => {
+            info!(
+                "[{}] {} added {} to the group",
+                topic,
+                state.profiles.label(&issuer, None),
+                state.profiles.label(peer, None)
+            );
+            Some((*peer, "member_added"))
+        }
+        Action::Remove { peer } => {
+            info!(
+                "[{}] {} removed {} from the group",
+                topic,
+                state.profiles.label(&issuer, None),
+                state.profiles.label(peer, None)
+            );
+            Some((*peer, "member_removed"))
+        }
+        Action::Transfer { peer } => {
+            info!(
+                "[{}] {} handed the founder role over to {}",
+                topic,
+                state.profiles.label(&issuer, None),
+                state.profiles.label(peer, None)
+            );
+            None
+        }
+        Action::Nominate { peer } if promoted && state.moderation.is_moderator(topic, peer) => {
+            info!(
+                "[{}] {} is now a moderator, nominated by a majority of the moderators",
+                topic,
+                state.profiles.label(peer, None)
+            );
+            None
+        }
+        action => {
+            info!("[{}] {:?} issued {:?}", topic, issuer, action);
+            None
+        }
+    };
+    if let Some((member, event)) = change {
+        state.renderer.emit(
+            JsonLine::new(event)
+                .str("topic", topic)
+                .str("peer", &member.to_base58())
+                .str("by", &issuer.to_base58()),
+        );
+    }
+    let removed = match &directive.action {
+        Action::Kick { peer } | Action::Remove { peer } => *peer == local_peer_id,
+        _ => false,
+    };
+    if removed && state.topics.is_subscribed(topic) {
+        warn!("You were removed from {:?} by {:?}", topic, issuer);
+        let protocol = state.topics.protocol(topic);
+        if let Err(e) = swarm.behaviour_mut().unsubscribe(topic, protocol) {
+            error!("Failed to leave topic: {:?} on {:?}", e, topic);
+        }
+        state.topics.leave(topic);
+    }
+    if founded {
+        for (issuer, directive, issued) in state.moderation.take_deferred(topic) {
+            handle_moderation(issuer, topic, directive, issued, swarm, state);
+        }
+    }
+}
+
+/// Saves the moderation state, logging any failure.
+pub fn save_moderation(state: &mut AppState) {
+    if let Err(e) = state.moderation.save() {
+        error!("Failed to save the moderation state: {}", e);
+    }
+}
+
+/// Sends a receipt to the inbox of the message author.
+///
+/// # Arguments
+///
+/// * `message_id` - The ID of the received message.
+/// * `kind` - What the receipt acknowledges.
+/// * `author` - The peer that authored the message.
+/// * `state` - The application state.
+fn send_receipt(message_id: MessageId, kind: ReceiptKind, author: PeerId, state: &mut AppState) {
+    let payload = Payload::Receipt(Receipt { message_id, kind });
+    let result = state.outbound.publish_payload(
+        &inbox_topic(&author),
+        PubsubProtocol::Both,
+        &payload,
+        state.display_name.clone(),
+        &state.local_key,
+        &mut state.middleware,
+    );
+    if let Err(e) = result {
+        error!("Failed to send {:?} receipt to {:?}: {:?}", kind, author, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity;
+    use serde_bytes::ByteBuf;
+    use tokio::sync::mpsc;
+
+    use super::backfill_history;
+    use crate::cli::Options;
+    use crate::config::Config;
+    use crate::contacts::Contacts;
+    use crate::delivery::MessageId;
+    use crate::protocol::{Envelope, Payload, TextMessage};
+    use crate::state::AppState;
+
+    #[test]
+    fn test_backfill_records_verified_entries() {
+        let local_key = identity::Keypair::generate_ed25519();
+        let config = Config::from_options(&Options::default()).unwrap();
+        let (events, _) = mpsc::unbounded_channel();
+        let mut state = AppState::new(local_key, &config, Contacts::new(), events, None);
+        let author = identity::Keypair::generate_ed25519();
+        let text = Payload::Text(TextMessage {
+            id: MessageId::random(),
+            body: "hello".to_string(),
+            ack_requested: false,
+        });
+        let seal =
+            |topic: &str, payload: &Payload| Envelope::seal(topic, payload, None, &author).unwrap();
+        let mut forged = seal("chat", &text);
+        forged.payload[0] ^= 1;
+        let entries = [
+            b"junk".to_vec(),
+            forged.encode().unwrap(),
+            seal("other", &text).encode().unwrap(),
+            seal("chat", &Payload::Topics(Vec::new())).encode().unwrap(),
+        ];
+        let envelopes = entries.iter().cloned().map(ByteBuf::from).collect();
+        backfill_history(author.public().to_peer_id(), "chat", envelopes, &mut state);
+        assert!(state.history.is_empty("chat"));
+
+        let valid = seal("chat", &text).encode().unwrap();
+        let envelopes = vec![ByteBuf::from(valid.clone())];
+        backfill_history(author.public().to_peer_id(), "chat", envelopes, &mut state);
+        assert_eq!(state.history.recent("chat", 10), vec![ByteBuf::from(valid)]);
+    }
+}
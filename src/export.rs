@@ -0,0 +1,287 @@
+/*!
+ * Export module for the messaging application.
+ *
+ * This module writes the kept history of a topic to a file for archiving
+ * or sharing, as plain text, Markdown or JSON lines. The stored envelopes
+ * are verified again while exporting, so every exported message says
+ * whether its signature holds, along with its sender and when it was sent.
+ */
+
+use std::{fmt::Write, str::FromStr};
+
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use libp2p::PeerId;
+use serde_bytes::ByteBuf;
+
+use crate::delivery::MessageId;
+use crate::middleware::Pipeline;
+use crate::profile::Profiles;
+use crate::protocol::{Envelope, Payload};
+use crate::render::{JsonLine, BODY_INDENT};
+use crate::security::{sanitize, sanitize_multiline, MAX_RENDERED_LEN, MAX_RENDERED_NAME_LEN};
+
+/// File formats history can be exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line, as printed by `--output json`.
+    Json,
+    Markdown,
+    Text,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "txt" | "text" => Ok(ExportFormat::Text),
+            _ => Err(format!("Unknown format: {} (json, markdown or txt)", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    /// Returns the file extension of the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "jsonl",
+            ExportFormat::Markdown => "md",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+/// A message prepared for exporting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedMessage {
+    pub timestamp: u64,
+    /// The signer, if the signature holds.
+    pub source: Option<PeerId>,
+    pub sender: String,
+    /// The message ID and body, if the signature holds.
+    pub text: Option<(MessageId, String)>,
+}
+
+impl ExportedMessage {
+    /// Returns whether the signature of the message holds.
+    pub fn verified(&self) -> bool {
+        self.text.is_some()
+    }
+}
+
+/// Prepares the text messages among stored envelopes for exporting.
+///
+/// # Arguments
+///
+/// * `topic` - The topic the envelopes were received on.
+/// * `envelopes` - The stored envelopes, oldest first.
+/// * `since` - The time in milliseconds before which messages are skipped.
+/// * `profiles` - The display names of peers.
+/// * `middleware` - The middleware the envelopes are opened through.
+///
+/// # Returns
+///
+/// The messages in the order they were stored. Messages whose signature
+/// does not hold are kept without their body, under the name they claim.
+pub fn collect(
+    topic: &str,
+    envelopes: &[ByteBuf],
+    since: u64,
+    profiles: &Profiles,
+    middleware: &mut Pipeline,
+) -> Vec<ExportedMessage> {
+    let mut messages = Vec::new();
+    for data in envelopes {
+        let Ok(envelope) = Envelope::decode(data) else {
+            continue;
+        };
+        if envelope.timestamp < since {
+            continue;
+        }
+        let claimed = envelope.sender.as_deref();
+        let message = match middleware.open(topic, &envelope) {
+            Ok((source, Payload::Text(text))) => ExportedMessage {
+                timestamp: envelope.timestamp,
+                source: Some(source),
+                sender: sanitize(&profiles.label(&source, claimed), MAX_RENDERED_NAME_LEN),
+                text: Some((text.id, sanitize_multiline(&text.body, MAX_RENDERED_LEN))),
+            },
+            Ok(_) => continue,
+            Err(_) => ExportedMessage {
+                timestamp: envelope.timestamp,
+                source: None,
+                sender: sanitize(claimed.unwrap_or("unknown"), MAX_RENDERED_NAME_LEN),
+                text: None,
+            },
+        };
+        messages.push(message);
+    }
+    messages
+}
+
+/// Writes exported messages in a format.
+///
+/// # Arguments
+///
+/// * `topic` - The topic the messages were sent on.
+/// * `messages` - The messages, oldest first.
+/// * `format` - The format to write.
+pub fn format(topic: &str, messages: &[ExportedMessage], format: ExportFormat) -> String {
+    let mut out = String::new();
+    if format == ExportFormat::Markdown {
+        let _ = writeln!(out, "# {}\n", topic);
+        let _ = writeln!(
+            out,
+            "Exported {}, {} messages.",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            messages.len()
+        );
+    }
+    for message in messages {
+        let time = local_time(message.timestamp);
+        let source = message.source.map(|peer| peer.to_base58());
+        match format {
+            ExportFormat::Json => {
+                let mut line = JsonLine::new("message")
+                    .str("topic", topic)
+                    .str("sender", source.as_deref().unwrap_or_default())
+                    .str("name", &message.sender)
+                    .num("timestamp", message.timestamp)
+                    .bool("verified", message.verified());
+                if let Some((id, body)) = &message.text {
+                    line = line.str("id", &id.to_string()).str("body", body);
+                }
+                let _ = writeln!(out, "{}", line);
+            }
+            ExportFormat::Markdown => {
+                let _ = write!(out, "\n**{}**", message.sender);
+                if let Some(source) = &source {
+                    let _ = write!(out, " `{}`", source);
+                }
+                match &message.text {
+                    Some((_, body)) => {
+                        let _ = writeln!(out, " · {} · verified\n", time);
+                        for line in body.lines() {
+                            let _ = writeln!(out, "> {}", line);
+                        }
+                    }
+                    None => {
+                        let _ = writeln!(out, " · {} · invalid signature", time);
+                    }
+                }
+            }
+            ExportFormat::Text => {
+                let _ = write!(out, "{} {}", time, message.sender);
+                if let Some(source) = &source {
+                    let _ = write!(out, " <{}>", source);
+                }
+                match &message.text {
+                    Some((_, body)) => {
+                        let body = body.replace('\n', &format!("\n{}", BODY_INDENT));
+                        let _ = writeln!(out, ": {}", body);
+                    }
+                    None => {
+                        let _ = writeln!(out, ": [invalid signature]");
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Parses the time an export starts at, as `YYYY-MM-DD` or
+/// `YYYY-MM-DDTHH:MM` in local time.
+///
+/// # Returns
+///
+/// A `Result` containing the time in milliseconds since the epoch.
+pub fn parse_since(text: &str) -> Result<u64, String> {
+    let time = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M").or_else(|_| {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .map(|date| date.and_hms_opt(0, 0, 0).expect("midnight"))
+    });
+    time.ok()
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.timestamp_millis().max(0) as u64)
+        .ok_or_else(|| format!("Invalid date: {} (YYYY-MM-DD or YYYY-MM-DDTHH:MM)", text))
+}
+
+/// Formats a time in milliseconds as a local date and time.
+fn local_time(timestamp: u64) -> String {
+    Local
+        .timestamp_millis_opt(timestamp as i64)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity;
+    use serde_bytes::ByteBuf;
+
+    use super::{collect, format, parse_since, ExportFormat};
+    use crate::delivery::MessageId;
+    use crate::middleware::Pipeline;
+    use crate::profile::Profiles;
+    use crate::protocol::{Envelope, Payload, TextMessage};
+
+    fn envelope(body: &str, key: &identity::Keypair) -> Envelope {
+        let text = TextMessage {
+            id: MessageId::random(),
+            body: body.to_string(),
+            ack_requested: false,
+        };
+        Envelope::seal("chat", &Payload::Text(text), Some("alice".to_string()), key).unwrap()
+    }
+
+    #[test]
+    fn test_export() {
+        let key = identity::Keypair::generate_ed25519();
+        let signed = envelope("hello\nworld", &key);
+        let mut forged = envelope("send me your keys", &key);
+        forged.timestamp += 1;
+        let stored: Vec<ByteBuf> = [&signed, &forged]
+            .iter()
+            .map(|envelope| ByteBuf::from(envelope.encode().unwrap()))
+            .collect();
+
+        let messages = collect("chat", &stored, 0, &Profiles::new(), &mut Pipeline::new());
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].verified());
+        assert_eq!(messages[0].source, Some(key.public().to_peer_id()));
+        assert_eq!(messages[1].sender, "alice");
+        assert!(!messages[1].verified());
+        assert_eq!(
+            collect(
+                "chat",
+                &stored,
+                forged.timestamp,
+                &Profiles::new(),
+                &mut Pipeline::new()
+            )
+            .len(),
+            1
+        );
+
+        let text = format("chat", &messages, ExportFormat::Text);
+        assert!(text.contains(": hello\n    world\n"));
+        assert!(text.ends_with("alice: [invalid signature]\n"));
+        assert!(!text.contains("send me your keys"));
+        let markdown = format("chat", &messages, ExportFormat::Markdown);
+        assert!(markdown.starts_with("# chat\n"));
+        assert!(markdown.contains("> hello\n> world\n"));
+        let json = format("chat", &messages, ExportFormat::Json);
+        assert_eq!(json.lines().count(), 2);
+        assert!(json.contains(r#""verified":true"#));
+        assert!(json.contains(r#""body":"hello\nworld""#));
+
+        assert_eq!("md".parse(), Ok(ExportFormat::Markdown));
+        assert!("pdf".parse::<ExportFormat>().is_err());
+        assert!(parse_since("2026-10-01").unwrap() < parse_since("2026-10-01T12:00").unwrap());
+        assert!(parse_since("yesterday").is_err());
+    }
+}
@@ -0,0 +1,346 @@
+/*!
+ * Feed module for the messaging application.
+ *
+ * A daemon can watch RSS and Atom feeds and publish their new entries on
+ * topics, turning the topics into news channels peers subscribe to like
+ * any other. Each entry is published once, as its title in bold followed by
+ * the start of its summary and its link. The first poll of a feed only
+ * records the entries already in it, so starting the daemon does not flood
+ * the topic with old news, and entries dropped from the feed and added
+ * back are not published twice as long as they are remembered.
+ *
+ * Feeds are polled at a fixed interval, with the validators of the last
+ * response so unchanged feeds cost little, and a feed that fails is tried
+ * again at the next poll. The watcher is built with the `feeds` feature.
+ */
+
+/// How often feeds are polled if not given, in seconds.
+pub const DEFAULT_INTERVAL: u64 = 300;
+
+#[cfg(feature = "feeds")]
+pub use watcher::watch;
+
+#[cfg(feature = "feeds")]
+mod watcher {
+    use std::{
+        collections::{HashSet, VecDeque},
+        error::Error,
+        time::Duration,
+    };
+
+    use feed_rs::model::{Entry, Feed};
+    use log::{debug, error, info, warn};
+    use reqwest::{
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+        StatusCode,
+    };
+
+    use crate::client::{Client, ClientError};
+
+    /// Entries of a feed remembered as published.
+    const MAX_SEEN: usize = 1024;
+
+    /// Most entries published from a feed at once, the oldest dropped.
+    const MAX_NEW: usize = 10;
+
+    /// Longest summary published, in characters.
+    const MAX_SUMMARY_LEN: usize = 280;
+
+    /// Longest a feed may take to answer.
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// The entries of a feed already published, oldest first.
+    #[derive(Default)]
+    struct Seen {
+        order: VecDeque<String>,
+        ids: HashSet<String>,
+    }
+
+    impl Seen {
+        /// Remembers an entry, returning whether it is new.
+        fn insert(&mut self, id: &str) -> bool {
+            if !self.ids.insert(id.to_string()) {
+                return false;
+            }
+            self.order.push_back(id.to_string());
+            if self.order.len() > MAX_SEEN {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.ids.remove(&oldest);
+                }
+            }
+            true
+        }
+    }
+
+    /// A feed and what is known of it.
+    struct Watched {
+        topic: String,
+        url: String,
+        seen: Seen,
+        /// Whether the entries of the feed were read once.
+        primed: bool,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    }
+
+    impl Watched {
+        /// Returns the entries of a read of the feed that were not
+        /// published, oldest first, and remembers them.
+        fn fresh(&mut self, feed: Feed) -> Vec<(String, Entry)> {
+            let name = feed.title.map(|title| title.content);
+            // Feeds list their newest entries first.
+            let mut fresh: Vec<_> = feed
+                .entries
+                .into_iter()
+                .rev()
+                .filter(|entry| self.seen.insert(&entry.id))
+                .map(|entry| (format(name.as_deref(), &entry), entry))
+                .collect();
+            if !std::mem::replace(&mut self.primed, true) {
+                return Vec::new();
+            }
+            let skipped = fresh.len().saturating_sub(MAX_NEW);
+            if skipped > 0 {
+                debug!("Skipping {} old entries of {}", skipped, self.url);
+            }
+            fresh.split_off(skipped)
+        }
+    }
+
+    /// Formats an entry as the body of a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The title of the feed, if it has one.
+    /// * `entry` - The entry.
+    fn format(name: Option<&str>, entry: &Entry) -> String {
+        let title = entry
+            .title
+            .as_ref()
+            .map(|title| one_line(&title.content))
+            .unwrap_or_else(|| "Untitled".to_string());
+        let mut body = match name.map(one_line).filter(|name| !name.is_empty()) {
+            Some(name) => format!("**{}: {}**", name, title),
+            None => format!("**{}**", title),
+        };
+        let summary = entry
+            .summary
+            .as_ref()
+            .map(|summary| one_line(&strip_html(&summary.content)))
+            .filter(|summary| !summary.is_empty());
+        if let Some(summary) = summary {
+            body.push('\n');
+            match summary.char_indices().nth(MAX_SUMMARY_LEN) {
+                Some((end, _)) => {
+                    body.push_str(summary[..end].trim_end());
+                    body.push('…');
+                }
+                None => body.push_str(&summary),
+            }
+        }
+        if let Some(link) = entry.links.first() {
+            body.push('\n');
+            body.push_str(&link.href);
+        }
+        body
+    }
+
+    /// Collapses the whitespace of a text into single spaces.
+    fn one_line(text: &str) -> String {
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Returns the text of an HTML fragment, without its tags.
+    fn strip_html(html: &str) -> String {
+        let mut text = String::with_capacity(html.len());
+        let mut in_tag = false;
+        for c in html.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' if in_tag => {
+                    in_tag = false;
+                    text.push(' ');
+                }
+                _ if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&nbsp;", " ")
+            .replace("&amp;", "&")
+    }
+
+    /// Starts watching feeds, publishing their new entries on topics.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The handle to the node.
+    /// * `feeds` - The feeds watched, each with the topic its entries are
+    ///   published on.
+    /// * `interval` - How often the feeds are polled.
+    pub fn watch(
+        client: Client,
+        feeds: Vec<(String, String)>,
+        interval: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("sec_msg/", env!("CARGO_PKG_VERSION")))
+            .timeout(TIMEOUT)
+            .build()?;
+        info!("Watching {} feeds every {:?}", feeds.len(), interval);
+        let feeds = feeds
+            .into_iter()
+            .map(|(topic, url)| Watched {
+                topic,
+                url,
+                seen: Seen::default(),
+                primed: false,
+                etag: None,
+                last_modified: None,
+            })
+            .collect();
+        // Joining the topics waits for the swarm loop, which runs later.
+        tokio::spawn(async move {
+            match run(client, http, feeds, interval).await {
+                Ok(()) | Err(ClientError::Stopped) => {}
+                Err(e) => error!("The feed watcher stopped: {}", e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Polls the feeds until the node stops.
+    async fn run(
+        client: Client,
+        http: reqwest::Client,
+        mut feeds: Vec<Watched>,
+        interval: Duration,
+    ) -> Result<(), ClientError> {
+        for feed in &feeds {
+            // Joined so the node relays the topic, the messages unread.
+            drop(client.subscribe(&feed.topic).await?);
+        }
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            for feed in &mut feeds {
+                let fresh = match poll(&http, feed).await {
+                    Ok(fresh) => fresh,
+                    Err(e) => {
+                        warn!("Failed to read feed {}: {}", feed.url, e);
+                        continue;
+                    }
+                };
+                for (body, entry) in fresh {
+                    debug!("Publishing entry {} of {}", entry.id, feed.url);
+                    match client.publish(&feed.topic, body).await {
+                        Err(ClientError::Stopped) => return Ok(()),
+                        Err(e) => warn!("Failed to publish an entry on {}: {}", feed.topic, e),
+                        Ok(()) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads a feed, returning its entries that were not published.
+    async fn poll(
+        http: &reqwest::Client,
+        feed: &mut Watched,
+    ) -> Result<Vec<(String, Entry)>, Box<dyn Error>> {
+        let mut request = http.get(&feed.url);
+        if let Some(etag) = &feed.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &feed.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Vec::new());
+        }
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+        let parsed = feed_rs::parser::parse(&response.bytes().await?[..])?;
+        feed.etag = etag;
+        feed.last_modified = last_modified;
+        Ok(feed.fresh(parsed))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Seen, Watched};
+
+        fn rss(items: &[&str]) -> feed_rs::model::Feed {
+            let items: String = items
+                .iter()
+                .map(|id| {
+                    format!(
+                        "<item><guid>{0}</guid><title>Post {0}</title>\
+                         <description>&lt;p&gt;About &amp;amp; {0}&lt;/p&gt;</description>\
+                         <link>https://example.com/{0}</link></item>",
+                        id
+                    )
+                })
+                .collect();
+            let text = format!(
+                "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel>\
+                 <title>Example  News</title>{}</channel></rss>",
+                items
+            );
+            feed_rs::parser::parse(text.as_bytes()).unwrap()
+        }
+
+        #[test]
+        fn test_fresh_entries() {
+            let mut feed = Watched {
+                topic: "news".to_string(),
+                url: "https://example.com/feed".to_string(),
+                seen: Seen::default(),
+                primed: false,
+                etag: None,
+                last_modified: None,
+            };
+            // The entries there at first are only remembered.
+            assert!(feed.fresh(rss(&["2", "1"])).is_empty());
+            let fresh = feed.fresh(rss(&["4", "3", "2"]));
+            let bodies: Vec<_> = fresh.into_iter().map(|(body, _)| body).collect();
+            assert_eq!(
+                bodies,
+                vec![
+                    "**Example News: Post 3**\nAbout & 3\nhttps://example.com/3",
+                    "**Example News: Post 4**\nAbout & 4\nhttps://example.com/4",
+                ]
+            );
+            assert!(feed.fresh(rss(&["4", "3", "1"])).is_empty());
+
+            let many: Vec<String> = (10..30).rev().map(|i| i.to_string()).collect();
+            let many: Vec<&str> = many.iter().map(String::as_str).collect();
+            let fresh = feed.fresh(rss(&many));
+            assert_eq!(fresh.len(), super::MAX_NEW);
+            assert!(fresh[0].0.contains("Post 20"));
+            assert!(fresh[9].0.contains("Post 29"));
+        }
+    }
+}
+
+/// Fails, as the feed watcher is built with the `feeds` feature.
+#[cfg(not(feature = "feeds"))]
+pub fn watch(
+    _client: crate::client::Client,
+    _feeds: Vec<(String, String)>,
+    _interval: std::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without the feed watcher, rebuild with --features feeds".into())
+}
@@ -0,0 +1,430 @@
+/*!
+ * FFI module for the messaging application.
+ *
+ * Mobile apps embed the messaging core through a C ABI, declared in
+ * `include/sec_msg.h` and built with the `ffi` feature into a static
+ * library for iOS or a shared one for Android. A node is created from its
+ * data directory and runs on its own threads; the app publishes and
+ * subscribes through it, and is called back when messages arrive and peers
+ * come and go.
+ *
+ * Functions return 0 on success and -1 on failure, or null for the node,
+ * `sec_msg_last_error` then telling why on the calling thread. Callbacks
+ * run on a thread of their own, one at a time, so they may call back into
+ * the library but should return quickly.
+ */
+
+use std::{
+    cell::RefCell,
+    error::Error,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    path::PathBuf,
+    ptr,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{self, UnboundedSender},
+    task::JoinHandle,
+};
+use zeroize::Zeroizing;
+
+use crate::app::{AppEvent, AppEvents};
+use crate::cli::Options;
+use crate::client::{Client, Message};
+use crate::config::Config;
+use crate::hooks::{Direction, Hook, HookContext, Verdict};
+use crate::node::Node;
+use crate::peers::PeerEvent;
+use crate::protocol::Payload;
+use crate::shutdown::Signal;
+use crate::storage::Vault;
+use crate::utils::{generate_keypair, load_keypair};
+
+thread_local! {
+    /// Why the last call failed on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Called with a message received on a subscribed topic: the context given
+/// with the callback, the topic, the base58 peer ID of the signer, the
+/// display name claimed, or null, and the body.
+pub type MessageCallback = extern "C" fn(
+    context: *mut c_void,
+    topic: *const c_char,
+    peer: *const c_char,
+    name: *const c_char,
+    body: *const c_char,
+);
+
+/// Called when a peer connects or disconnects: the context given with the
+/// callback, the base58 peer ID and whether it connected.
+pub type PeerCallback = extern "C" fn(context: *mut c_void, peer: *const c_char, connected: bool);
+
+/// A callback and the context it is called with.
+#[derive(Clone, Copy)]
+struct Callback<F> {
+    function: F,
+    context: *mut c_void,
+}
+
+// SAFETY: the app setting a callback promises that its context may be used
+// from the callback thread.
+unsafe impl<F: Send> Send for Callback<F> {}
+
+/// The callbacks set by the app.
+#[derive(Default)]
+struct Callbacks {
+    message: Option<Callback<MessageCallback>>,
+    peer: Option<Callback<PeerCallback>>,
+}
+
+/// What the callback thread is told.
+enum Notification {
+    Message(Message),
+    Peer(PeerEvent),
+}
+
+/// A running node, as seen from C.
+pub struct SecMsgNode {
+    runtime: Runtime,
+    client: Client,
+    events: AppEvents,
+    task: JoinHandle<Option<Signal>>,
+    callbacks: Arc<Mutex<Callbacks>>,
+    callback_thread: thread::JoinHandle<()>,
+}
+
+/// A hook handing the received text messages to the callback thread.
+struct MessageHook(UnboundedSender<Notification>);
+
+impl Hook for MessageHook {
+    fn name(&self) -> &str {
+        "ffi"
+    }
+
+    fn handle(
+        &mut self,
+        context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        let Payload::Text(text) = payload else {
+            return Ok(Verdict::Continue);
+        };
+        if context.direction != Direction::Inbound {
+            return Ok(Verdict::Continue);
+        }
+        let _ = self.0.send(Notification::Message(Message {
+            topic: context.topic.to_string(),
+            id: text.id.to_string(),
+            peer: context.peer,
+            name: context.sender.map(str::to_string),
+            body: text.body.clone(),
+        }));
+        Ok(Verdict::Continue)
+    }
+}
+
+/// Remembers why a call failed.
+fn fail<T>(message: impl ToString, failed: T) -> T {
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(c_string(&message.to_string())));
+    failed
+}
+
+/// Returns a string as a C string, without the NUL bytes it cannot hold.
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+/// Reads a C string argument.
+///
+/// # Safety
+///
+/// `s` must be null or a NUL-terminated string.
+unsafe fn string_arg<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| format!("{} is not UTF-8", name))
+}
+
+/// Creates a node and starts it.
+fn start(home: Option<&str>, passphrase: Option<&str>) -> Result<SecMsgNode, Box<dyn Error>> {
+    let options = Options {
+        home: home.map(PathBuf::from),
+        ..Options::default()
+    };
+    let config = Config::new(&options).map_err(|e| e.to_string())?;
+    let vault = match (config.encrypt_storage, passphrase) {
+        (true, Some(passphrase)) => {
+            Some(Arc::new(Vault::new(Zeroizing::new(passphrase.to_string()))))
+        }
+        (true, None) => return Err("Storage is encrypted, pass the passphrase".into()),
+        (false, _) => None,
+    };
+    let (local_key, _) = match &config.identity {
+        Some(path) => load_keypair(path, vault.as_deref())?,
+        None => generate_keypair(),
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let mut node = runtime.block_on(Node::new(&config, local_key, vault, None))?;
+    let (notifications, mut notified) = mpsc::unbounded_channel();
+    node.state
+        .hooks
+        .register(100, Box::new(MessageHook(notifications.clone())));
+    let mut peer_events = node.state.peers.watch();
+    runtime.spawn(async move {
+        while let Some(event) = peer_events.recv().await {
+            if notifications.send(Notification::Peer(event)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let callbacks = Arc::new(Mutex::new(Callbacks::default()));
+    let called = callbacks.clone();
+    // Ends once the node stopped, as the senders are gone with it.
+    let callback_thread = thread::spawn(move || {
+        while let Some(notification) = notified.blocking_recv() {
+            // Copied out, so a callback may set the callbacks.
+            let (message, peer) = {
+                let callbacks = called.lock().unwrap_or_else(PoisonError::into_inner);
+                (callbacks.message, callbacks.peer)
+            };
+            match (notification, message, peer) {
+                (Notification::Message(message), Some(callback), _) => {
+                    let name = message.name.as_deref().map(c_string);
+                    (callback.function)(
+                        callback.context,
+                        c_string(&message.topic).as_ptr(),
+                        c_string(&message.peer.to_base58()).as_ptr(),
+                        name.as_ref().map_or(ptr::null(), |name| name.as_ptr()),
+                        c_string(&message.body).as_ptr(),
+                    );
+                }
+                (Notification::Peer(event), _, Some(callback)) => {
+                    let (peer, connected) = match event {
+                        PeerEvent::Connected { peer, .. } => (peer, true),
+                        PeerEvent::Disconnected { peer } => (peer, false),
+                    };
+                    (callback.function)(
+                        callback.context,
+                        c_string(&peer.to_base58()).as_ptr(),
+                        connected,
+                    );
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let client = node.client();
+    let events = node.events();
+    let task = runtime.spawn(node.run());
+    Ok(SecMsgNode {
+        runtime,
+        client,
+        events,
+        task,
+        callbacks,
+        callback_thread,
+    })
+}
+
+/// Creates a node and starts it.
+///
+/// # Arguments
+///
+/// * `home` - The data directory, or null for the default one.
+/// * `passphrase` - The passphrase of the stored files, or null if they
+///   are not encrypted.
+///
+/// # Returns
+///
+/// The node, to be stopped with `sec_msg_shutdown`, or null on failure.
+///
+/// # Safety
+///
+/// `home` and `passphrase` must be null or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn sec_msg_node_new(
+    home: *const c_char,
+    passphrase: *const c_char,
+) -> *mut SecMsgNode {
+    let (home, passphrase) = match (
+        string_arg(home, "home"),
+        string_arg(passphrase, "passphrase"),
+    ) {
+        (Ok(home), Ok(passphrase)) => (home, passphrase),
+        (Err(e), _) | (_, Err(e)) => return fail(e, ptr::null_mut()),
+    };
+    match start(home, passphrase) {
+        Ok(node) => Box::into_raw(Box::new(node)),
+        Err(e) => fail(e, ptr::null_mut()),
+    }
+}
+
+/// Sets the function called with the messages received, replacing the
+/// previous one, or removes it when null.
+///
+/// # Safety
+///
+/// `node` must be a node returned by `sec_msg_node_new` and not shut down,
+/// and `context` must be usable from the callback thread.
+#[no_mangle]
+pub unsafe extern "C" fn sec_msg_set_message_callback(
+    node: *mut SecMsgNode,
+    callback: Option<MessageCallback>,
+    context: *mut c_void,
+) -> c_int {
+    let Some(node) = node.as_ref() else {
+        return fail("node is null", -1);
+    };
+    node.callbacks
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .message = callback.map(|function| Callback { function, context });
+    0
+}
+
+/// Sets the function called when peers connect and disconnect, replacing
+/// the previous one, or removes it when null.
+///
+/// # Safety
+///
+/// `node` must be a node returned by `sec_msg_node_new` and not shut down,
+/// and `context` must be usable from the callback thread.
+#[no_mangle]
+pub unsafe extern "C" fn sec_msg_set_peer_callback(
+    node: *mut SecMsgNode,
+    callback: Option<PeerCallback>,
+    context: *mut c_void,
+) -> c_int {
+    let Some(node) = node.as_ref() else {
+        return fail("node is null", -1);
+    };
+    node.callbacks
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .peer = callback.map(|function| Callback { function, context });
+    0
+}
+
+/// Joins a topic, whose messages are then passed to the message callback.
+///
+/// # Safety
+///
+/// `node` must be a node returned by `sec_msg_node_new` and not shut down,
+/// and `topic` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sec_msg_subscribe(node: *mut SecMsgNode, topic: *const c_char) -> c_int {
+    let Some(node) = node.as_ref() else {
+        return fail("node is null", -1);
+    };
+    let topic = match string_arg(topic, "topic") {
+        Ok(Some(topic)) => topic,
+        Ok(None) => return fail("topic is null", -1),
+        Err(e) => return fail(e, -1),
+    };
+    // The callback gets the messages through the hook, not the stream.
+    match node.runtime.block_on(node.client.subscribe(topic)) {
+        Ok(_) => 0,
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Publishes a text message on a topic.
+///
+/// # Safety
+///
+/// `node` must be a node returned by `sec_msg_node_new` and not shut down,
+/// and `topic` and `body` NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn sec_msg_publish(
+    node: *mut SecMsgNode,
+    topic: *const c_char,
+    body: *const c_char,
+) -> c_int {
+    let Some(node) = node.as_ref() else {
+        return fail("node is null", -1);
+    };
+    let (topic, body) = match (string_arg(topic, "topic"), string_arg(body, "body")) {
+        (Ok(Some(topic)), Ok(Some(body))) => (topic, body),
+        (Err(e), _) | (_, Err(e)) => return fail(e, -1),
+        _ => return fail("topic or body is null", -1),
+    };
+    match node.runtime.block_on(node.client.publish(topic, body)) {
+        Ok(()) => 0,
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Stops a node as quitting does, then frees it.
+///
+/// # Safety
+///
+/// `node` must be null or a node returned by `sec_msg_node_new`, which must
+/// not be used afterwards. It must not be called from a callback.
+#[no_mangle]
+pub unsafe extern "C" fn sec_msg_shutdown(node: *mut SecMsgNode) -> c_int {
+    if node.is_null() {
+        return fail("node is null", -1);
+    }
+    let node = Box::from_raw(node);
+    let _ = node.events.send(AppEvent::InputClosed);
+    let result = node.runtime.block_on(node.task);
+    drop(node.runtime);
+    let _ = node.callback_thread.join();
+    match result {
+        Ok(_) => 0,
+        Err(e) => fail(format!("the node failed: {}", e), -1),
+    }
+}
+
+/// Returns why the last call failed on this thread, or null. The string
+/// stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn sec_msg_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| {
+        error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |error| error.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CStr, ptr};
+
+    use super::{sec_msg_last_error, sec_msg_node_new, sec_msg_publish, sec_msg_shutdown};
+
+    #[test]
+    fn test_ffi_errors() {
+        assert!(sec_msg_last_error().is_null());
+        // SAFETY: null nodes and strings are rejected before any use.
+        unsafe {
+            assert_eq!(
+                sec_msg_publish(ptr::null_mut(), ptr::null(), ptr::null()),
+                -1
+            );
+            let error = CStr::from_ptr(sec_msg_last_error());
+            assert_eq!(error.to_str(), Ok("node is null"));
+            assert_eq!(sec_msg_shutdown(ptr::null_mut()), -1);
+
+            let home = c"/nonexistent/sec_msg";
+            let bad = [0xffu8, 0];
+            assert!(sec_msg_node_new(home.as_ptr(), bad.as_ptr().cast()).is_null());
+            let error = CStr::from_ptr(sec_msg_last_error());
+            assert_eq!(error.to_str(), Ok("passphrase is not UTF-8"));
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/*!
+ * gRPC module for the messaging application.
+ *
+ * Besides its Unix domain socket, a daemon can serve the gRPC service of
+ * `proto/sec_msg.proto` on a TCP address, so programs in any language can
+ * drive it with generated clients: publish, subscribe to the messages of a
+ * topic as a stream, list the peers, dial an address and ask for the
+ * status of the node. The service is built with the `grpc` feature.
+ *
+ * It is another front end to the control protocol of the `ipc` module:
+ * every call becomes a `Request` handed to the swarm loop, and subscribed
+ * streams are fed by the same hook as subscribed socket clients. There is
+ * no authentication, so the service should only listen on a local address.
+ */
+
+#[cfg(feature = "grpc")]
+pub use service::{proto, serve};
+
+#[cfg(feature = "grpc")]
+mod service {
+    use std::{error::Error, net::SocketAddr, pin::Pin};
+
+    use log::{debug, error, info};
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_stream::{
+        wrappers::{TcpListenerStream, UnboundedReceiverStream},
+        Stream, StreamExt,
+    };
+    use tonic::{transport::Server, Status};
+
+    use crate::app::AppEvents;
+    use crate::ipc::{ask, subscribe, Request, Response, Subscribers};
+
+    /// The messages and services generated from `proto/sec_msg.proto`.
+    pub mod proto {
+        tonic::include_proto!("sec_msg.v1");
+    }
+
+    use proto::node_server::{Node, NodeServer};
+
+    /// The service, handing calls to the swarm loop.
+    struct Service {
+        events: AppEvents,
+        subscribers: Subscribers,
+    }
+
+    impl Service {
+        /// Hands a request to the swarm loop and waits for its response.
+        async fn request(&self, request: Request) -> Result<Response, Status> {
+            debug!("gRPC request: {:?}", request);
+            let response = ask(&self.events, request)
+                .await
+                .ok_or_else(|| Status::unavailable("The node is shutting down"))?;
+            match response {
+                Response::Error { message } => Err(Status::failed_precondition(message)),
+                response => Ok(response),
+            }
+        }
+    }
+
+    type MessageStream = Pin<Box<dyn Stream<Item = Result<proto::Message, Status>> + Send>>;
+
+    #[tonic::async_trait]
+    impl Node for Service {
+        type SubscribeStream = MessageStream;
+
+        async fn publish(
+            &self,
+            request: tonic::Request<proto::PublishRequest>,
+        ) -> Result<tonic::Response<proto::PublishResponse>, Status> {
+            let proto::PublishRequest { topic, body } = request.into_inner();
+            self.request(Request::Publish { topic, body }).await?;
+            Ok(tonic::Response::new(proto::PublishResponse {}))
+        }
+
+        async fn subscribe(
+            &self,
+            request: tonic::Request<proto::SubscribeRequest>,
+        ) -> Result<tonic::Response<Self::SubscribeStream>, Status> {
+            let topic = request.into_inner().topic;
+            self.request(Request::Subscribe {
+                topic: topic.clone(),
+            })
+            .await?;
+            // The hook forgets the channel once the client drops the stream.
+            let (client, messages) = mpsc::unbounded_channel();
+            subscribe(&self.subscribers, topic, &client);
+            let stream =
+                UnboundedReceiverStream::new(messages).filter_map(|response| match response {
+                    Response::Message {
+                        topic,
+                        id,
+                        peer,
+                        name,
+                        body,
+                    } => Some(Ok(proto::Message {
+                        topic,
+                        id,
+                        peer,
+                        name,
+                        body,
+                    })),
+                    _ => None,
+                });
+            Ok(tonic::Response::new(Box::pin(stream)))
+        }
+
+        async fn list_peers(
+            &self,
+            _request: tonic::Request<proto::ListPeersRequest>,
+        ) -> Result<tonic::Response<proto::ListPeersResponse>, Status> {
+            let Response::Peers { peers } = self.request(Request::Peers).await? else {
+                return Err(Status::internal("Unexpected response"));
+            };
+            let peers = peers
+                .into_iter()
+                .map(|peer| proto::Peer {
+                    peer: peer.peer,
+                    name: peer.name,
+                    address: peer.address,
+                    latency_ms: peer.latency_ms,
+                })
+                .collect();
+            Ok(tonic::Response::new(proto::ListPeersResponse { peers }))
+        }
+
+        async fn connect(
+            &self,
+            request: tonic::Request<proto::ConnectRequest>,
+        ) -> Result<tonic::Response<proto::ConnectResponse>, Status> {
+            let address = request.into_inner().address;
+            self.request(Request::Connect { address }).await?;
+            Ok(tonic::Response::new(proto::ConnectResponse {}))
+        }
+
+        async fn status(
+            &self,
+            _request: tonic::Request<proto::StatusRequest>,
+        ) -> Result<tonic::Response<proto::StatusResponse>, Status> {
+            let Response::Status {
+                peer_id,
+                version,
+                listen_addrs,
+                topics,
+                peers,
+                nat,
+                queued,
+            } = self.request(Request::Status).await?
+            else {
+                return Err(Status::internal("Unexpected response"));
+            };
+            Ok(tonic::Response::new(proto::StatusResponse {
+                peer_id,
+                version,
+                listen_addrs,
+                topics,
+                peers: peers as u32,
+                nat,
+                queued: queued as u32,
+            }))
+        }
+    }
+
+    /// Serves the gRPC service on a TCP address, handing the calls to the
+    /// swarm loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address listened on.
+    /// * `events` - The channel to the swarm loop.
+    /// * `subscribers` - The clients subscribed to each topic.
+    pub async fn serve(
+        addr: SocketAddr,
+        events: AppEvents,
+        subscribers: Subscribers,
+    ) -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Failed to listen for gRPC on {}: {}", addr, e))?;
+        info!("Serving gRPC on {}", listener.local_addr()?);
+        let service = NodeServer::new(Service {
+            events,
+            subscribers,
+        });
+        tokio::spawn(async move {
+            let incoming = TcpListenerStream::new(listener);
+            if let Err(e) = Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming)
+                .await
+            {
+                error!("gRPC server failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Fails, as the gRPC service is built with the `grpc` feature.
+#[cfg(not(feature = "grpc"))]
+pub async fn serve(
+    _addr: std::net::SocketAddr,
+    _events: crate::app::AppEvents,
+    _subscribers: crate::ipc::Subscribers,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without gRPC, rebuild with --features grpc".into())
+}
@@ -0,0 +1,275 @@
+/*!
+ * History module for the messaging application.
+ *
+ * This module keeps the most recent envelopes received on each topic and
+ * defines the request-response messages of the history sync protocol, which
+ * lets a peer joining a topic backfill its view from existing subscribers.
+ * Envelopes are stored exactly as received, so the requester verifies their
+ * signatures itself, and they are deduplicated by the hash of their bytes.
+ *
+ * When storage is encrypted, the envelopes are also saved on exit and
+ * loaded at startup, so the history survives restarts.
+ */
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    error::Error,
+    path::Path,
+};
+
+use libp2p::{request_response::OutboundRequestId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use serde_bytes::{ByteBuf, Bytes};
+use sha2::{Digest, Sha256};
+
+use crate::protocol::{is_inbox_topic, MAX_ENVELOPE_SIZE};
+use crate::storage::{self, Vault};
+
+/// Protocol name of the history sync protocol.
+pub const HISTORY_PROTOCOL: StreamProtocol = StreamProtocol::new("/sec_msg/history/1.0.0");
+
+/// Number of envelopes kept, and served, per topic.
+pub const HISTORY_LIMIT: usize = 50;
+
+/// Asks a subscriber for the most recent envelopes of a topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub topic: String,
+    pub limit: u32,
+}
+
+/// The most recent envelopes of a topic, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryResponse {
+    pub envelopes: Vec<ByteBuf>,
+}
+
+/// Envelopes of each topic saved by a previous run, oldest first.
+pub type SavedHistory = Vec<(String, Vec<ByteBuf>)>;
+
+/// Recent envelopes of a single topic.
+#[derive(Default)]
+struct TopicHistory {
+    envelopes: VecDeque<([u8; 32], Vec<u8>)>,
+    digests: HashSet<[u8; 32]>,
+}
+
+/// Recent envelopes of all topics and the pending history requests.
+pub struct History {
+    topics: HashMap<String, TopicHistory>,
+    requests: HashMap<OutboundRequestId, String>,
+    /// Whether envelopes were stored since the history was last saved.
+    dirty: bool,
+}
+
+impl History {
+    /// Creates a new, empty `History`.
+    pub fn new() -> Self {
+        History {
+            topics: HashMap::new(),
+            requests: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Stores an envelope received on a topic.
+    ///
+    /// Direct message inboxes are never stored, and neither are reassembled
+    /// envelopes above `MAX_ENVELOPE_SIZE`, which keeps responses small.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope was received on.
+    /// * `data` - The encoded envelope.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the envelope was already stored.
+    pub fn record(&mut self, topic: &str, data: &[u8]) -> bool {
+        if is_inbox_topic(topic) || data.len() > MAX_ENVELOPE_SIZE {
+            return true;
+        }
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        let history = self.topics.entry(topic.to_string()).or_default();
+        if !history.digests.insert(digest) {
+            return false;
+        }
+
+        history.envelopes.push_back((digest, data.to_vec()));
+        self.dirty = true;
+        if history.envelopes.len() > HISTORY_LIMIT {
+            if let Some((digest, _)) = history.envelopes.pop_front() {
+                history.digests.remove(&digest);
+            }
+        }
+        true
+    }
+
+    /// Returns up to `limit` of the most recent envelopes of a topic, oldest first.
+    pub fn recent(&self, topic: &str, limit: usize) -> Vec<ByteBuf> {
+        let Some(history) = self.topics.get(topic) else {
+            return Vec::new();
+        };
+        let skip = history.envelopes.len().saturating_sub(limit);
+        history
+            .envelopes
+            .iter()
+            .skip(skip)
+            .map(|(_, data)| ByteBuf::from(data.clone()))
+            .collect()
+    }
+
+    /// Returns whether an envelope of a topic is stored.
+    pub fn contains(&self, topic: &str, data: &[u8]) -> bool {
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        self.topics
+            .get(topic)
+            .is_some_and(|history| history.digests.contains(&digest))
+    }
+
+    /// Returns whether no envelope of a topic is known yet.
+    pub fn is_empty(&self, topic: &str) -> bool {
+        self.topics
+            .get(topic)
+            .is_none_or(|history| history.envelopes.is_empty())
+    }
+
+    /// Returns whether envelopes were stored since the history was last
+    /// saved or restored.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Marks the history as matching its file, once restored from it.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Saves the envelopes of every topic to a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The history file.
+    /// * `vault` - The vault the file is sealed with.
+    pub fn save(&mut self, path: &Path, vault: &Vault) -> Result<(), Box<dyn Error>> {
+        let topics: BTreeMap<&str, Vec<&Bytes>> = self
+            .topics
+            .iter()
+            .map(|(topic, history)| {
+                let envelopes = history.envelopes.iter().map(|(_, data)| Bytes::new(data));
+                (topic.as_str(), envelopes.collect())
+            })
+            .collect();
+        storage::write(path, &bincode::serialize(&topics)?, Some(vault))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Loads the envelopes saved by `save`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The history file.
+    /// * `vault` - The vault the file is sealed with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the envelopes of each topic, oldest first, to
+    /// be verified and recorded like backfilled ones.
+    pub fn load(path: &Path, vault: &Vault) -> Result<SavedHistory, Box<dyn Error>> {
+        match storage::read(path, Some(vault))? {
+            Some(bytes) => {
+                let topics: BTreeMap<String, Vec<ByteBuf>> = bincode::deserialize(&bytes)?;
+                Ok(topics.into_iter().collect())
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Remembers the topic of an outgoing history request.
+    pub fn track_request(&mut self, request_id: OutboundRequestId, topic: &str) {
+        self.requests.insert(request_id, topic.to_string());
+    }
+
+    /// Returns the topic of a completed history request.
+    pub fn take_request(&mut self, request_id: &OutboundRequestId) -> Option<String> {
+        self.requests.remove(request_id)
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use zeroize::Zeroizing;
+
+    use super::{History, HISTORY_LIMIT};
+    use crate::storage::Vault;
+
+    #[test]
+    fn test_record_deduplicates_and_bounds() {
+        let mut history = History::new();
+        assert!(history.is_empty("chat"));
+        assert!(history.record("chat", b"first"));
+        assert!(!history.record("chat", b"first"));
+        assert!(!history.is_empty("chat"));
+        assert!(history.contains("chat", b"first"));
+        assert!(!history.contains("other", b"first"));
+
+        for i in 0..HISTORY_LIMIT {
+            history.record("chat", format!("message {}", i).as_bytes());
+        }
+        let recent = history.recent("chat", HISTORY_LIMIT * 2);
+        assert_eq!(recent.len(), HISTORY_LIMIT);
+        assert_eq!(recent[0].as_ref(), b"message 0");
+        assert_eq!(
+            history.recent("chat", 1)[0].as_ref(),
+            format!("message {}", HISTORY_LIMIT - 1).as_bytes()
+        );
+
+        // Evicted envelopes are accepted again.
+        assert!(history.record("chat", b"first"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("sec_msg-history-{}.db", std::process::id()));
+        let vault = Vault::new(Zeroizing::new("correct horse".to_string()));
+        assert!(History::load(&path, &vault).unwrap().is_empty());
+
+        let mut history = History::new();
+        history.record("chat", b"first");
+        history.record("chat", b"second");
+        history.record("rust", b"third");
+        assert!(history.is_dirty());
+        history.save(&path, &vault).unwrap();
+        assert!(!history.is_dirty());
+        assert_eq!(
+            History::load(&path, &vault).unwrap(),
+            vec![
+                (
+                    "chat".to_string(),
+                    vec![
+                        ByteBuf::from(b"first".to_vec()),
+                        ByteBuf::from(b"second".to_vec())
+                    ]
+                ),
+                ("rust".to_string(), vec![ByteBuf::from(b"third".to_vec())]),
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_inbox_topics_are_not_stored() {
+        let mut history = History::new();
+        assert!(history.record("dm/peer", b"secret"));
+        assert!(history.is_empty("dm/peer"));
+        assert!(history.recent("dm/peer", HISTORY_LIMIT).is_empty());
+    }
+}
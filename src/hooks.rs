@@ -0,0 +1,374 @@
+/*!
+ * Hooks module for the messaging application.
+ *
+ * Hooks let plugins observe or change the messages going through the
+ * application, to log them, translate them or filter spam. Besides letting
+ * a payload through or dropping it, a hook may flag a payload, which goes
+ * on marked with the reason, or quarantine it, holding it back for the user
+ * to review. A hook that takes a while to decide on a text message holds it
+ * back meanwhile, and the hooks run on it again once it decided, so the
+ * swarm loop never waits on a hook. A plugin implements the `Hook` trait
+ * and is registered in code with a priority, hooks running from the lowest
+ * priority to the highest and in the order they were registered within one.
+ *
+ * Hooks see payloads rather than envelopes: inbound payloads once their
+ * signature was verified, and the payloads the user publishes before they
+ * are signed, so a change made by a hook never breaks a signature. A hook
+ * that fails is skipped and leaves the payload as it found it, and one
+ * that panics is disabled, so a broken plugin cannot take the application
+ * down or keep messages from flowing.
+ */
+
+use std::{
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use libp2p::PeerId;
+use log::{debug, error, log, trace, warn, Level};
+
+use crate::protocol::Payload;
+
+/// Whether a payload was received or is being published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Inbound => write!(f, "inbound"),
+            Direction::Outbound => write!(f, "outbound"),
+        }
+    }
+}
+
+/// What a hook is told about a payload.
+#[derive(Debug, Clone, Copy)]
+pub struct HookContext<'a> {
+    pub direction: Direction,
+    pub topic: &'a str,
+    /// The signer of an inbound payload, or the local peer.
+    pub peer: PeerId,
+    /// The display name claimed with the payload, if any.
+    pub sender: Option<&'a str>,
+}
+
+/// What a hook decides about a payload.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Pass the payload, changed or not, to the next hook.
+    Continue,
+    /// Drop the payload for the reason given.
+    Drop(String),
+    /// Pass the payload on, flagged for the reason given.
+    Flag(String),
+    /// Hold the payload back for review, for the reason given.
+    Quarantine(String),
+    /// Hold a text message back while the hook decides on it. The hook
+    /// sends `AppEvent::Decided` with the message id once it has, and the
+    /// hooks then run on the message again.
+    Pending,
+}
+
+/// A plugin observing or changing payloads.
+pub trait Hook: Send {
+    /// Returns the name the hook is logged under.
+    fn name(&self) -> &str;
+
+    /// Handles a payload, which it may change, and says whether it goes on.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the payload comes from or goes to.
+    /// * `payload` - The payload.
+    fn handle(
+        &mut self,
+        context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>>;
+}
+
+/// A registered hook.
+struct Entry {
+    priority: i32,
+    hook: Box<dyn Hook>,
+    /// Whether the hook panicked, after which it is no longer run.
+    disabled: bool,
+}
+
+/// The registered hooks, in the order they run.
+#[derive(Default)]
+pub struct Hooks {
+    entries: Vec<Entry>,
+}
+
+impl Hooks {
+    /// Creates an empty hook registry.
+    pub fn new() -> Self {
+        Hooks::default()
+    }
+
+    /// Registers a hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - When the hook runs, lower priorities running first.
+    /// * `hook` - The hook.
+    pub fn register(&mut self, priority: i32, hook: Box<dyn Hook>) {
+        debug!("Registered hook {} at priority {}", hook.name(), priority);
+        let index = self
+            .entries
+            .partition_point(|entry| entry.priority <= priority);
+        self.entries.insert(
+            index,
+            Entry {
+                priority,
+                hook,
+                disabled: false,
+            },
+        );
+    }
+
+    /// Runs the hooks on a payload, in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the payload comes from or goes to.
+    /// * `payload` - The payload, changed by the hooks.
+    ///
+    /// # Returns
+    ///
+    /// The verdict of the first hook dropping, quarantining or holding the
+    /// payload,
+    /// which stops the ones after it, otherwise `Verdict::Flag` with the
+    /// reasons of the hooks that flagged it, if any, or `Verdict::Continue`.
+    pub fn run(&mut self, context: &HookContext, payload: &mut Payload) -> Verdict {
+        let mut flags: Vec<String> = Vec::new();
+        for entry in self.entries.iter_mut().filter(|entry| !entry.disabled) {
+            // Hooks work on a copy, so a failing one changes nothing.
+            let mut changed = payload.clone();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                entry.hook.handle(context, &mut changed)
+            }));
+            match result {
+                Ok(Ok(Verdict::Continue)) => *payload = changed,
+                Ok(Ok(Verdict::Flag(reason))) => {
+                    debug!(
+                        "Hook {} flagged an {} payload on {:?}: {}",
+                        entry.hook.name(),
+                        context.direction,
+                        context.topic,
+                        reason
+                    );
+                    *payload = changed;
+                    flags.push(reason);
+                }
+                Ok(Ok(Verdict::Drop(reason))) => {
+                    log_stop(entry.hook.name(), "dropped", context, &reason);
+                    return Verdict::Drop(reason);
+                }
+                Ok(Ok(Verdict::Pending)) => {
+                    log_stop(entry.hook.name(), "held", context, "deciding");
+                    return Verdict::Pending;
+                }
+                Ok(Ok(Verdict::Quarantine(reason))) => {
+                    log_stop(entry.hook.name(), "quarantined", context, &reason);
+                    *payload = changed;
+                    return Verdict::Quarantine(reason);
+                }
+                Ok(Err(e)) => warn!(
+                    "Hook {} failed on an {} payload on {:?}: {}",
+                    entry.hook.name(),
+                    context.direction,
+                    context.topic,
+                    e
+                ),
+                Err(_) => {
+                    error!("Hook {} panicked, disabling it", entry.hook.name());
+                    entry.disabled = true;
+                }
+            }
+        }
+        match flags.is_empty() {
+            true => Verdict::Continue,
+            false => Verdict::Flag(flags.join("; ")),
+        }
+    }
+}
+
+/// Logs that a hook stopped a payload.
+///
+/// # Arguments
+///
+/// * `hook` - The name of the hook.
+/// * `action` - What the hook did with the payload.
+/// * `context` - Where the payload comes from or goes to.
+/// * `reason` - Why the hook stopped it.
+fn log_stop(hook: &str, action: &str, context: &HookContext, reason: &str) {
+    // The user should know why what they sent went nowhere.
+    let level = match context.direction {
+        Direction::Inbound => Level::Debug,
+        Direction::Outbound => Level::Info,
+    };
+    log!(
+        level,
+        "Hook {} {} an {} payload on {:?}: {}",
+        hook,
+        action,
+        context.direction,
+        context.topic,
+        reason
+    );
+}
+
+/// A hook tracing every payload, to follow traffic with `--log-level trace`.
+pub struct TraceHook;
+
+impl Hook for TraceHook {
+    fn name(&self) -> &str {
+        "trace"
+    }
+
+    fn handle(
+        &mut self,
+        context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        trace!(
+            "{} on {:?} by {:?} ({}): {:?}",
+            context.direction,
+            context.topic,
+            context.peer,
+            context.sender.unwrap_or("no name"),
+            payload
+        );
+        Ok(Verdict::Continue)
+    }
+}
+
+/// A hook dropping text messages with nothing but whitespace in them.
+pub struct BlankHook;
+
+impl Hook for BlankHook {
+    fn name(&self) -> &str {
+        "blank"
+    }
+
+    fn handle(
+        &mut self,
+        _context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        match payload {
+            Payload::Text(text) if text.body.trim().is_empty() => {
+                Ok(Verdict::Drop("blank message".to_string()))
+            }
+            _ => Ok(Verdict::Continue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use libp2p::PeerId;
+
+    use super::{BlankHook, Direction, Hook, HookContext, Hooks, Verdict};
+    use crate::delivery::MessageId;
+    use crate::protocol::{Payload, TextMessage};
+
+    /// A hook appending a suffix to text messages, failing or panicking
+    /// on request.
+    struct Suffix(&'static str);
+
+    impl Hook for Suffix {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn handle(
+            &mut self,
+            _context: &HookContext,
+            payload: &mut Payload,
+        ) -> Result<Verdict, Box<dyn Error>> {
+            let Payload::Text(text) = payload else {
+                return Ok(Verdict::Continue);
+            };
+            text.body.push_str(self.0);
+            match text.body.as_str() {
+                body if body.ends_with("fail") => Err("failed".into()),
+                body if body.ends_with("panic") => panic!("hook panicked"),
+                body if body.contains("spam") => Ok(Verdict::Drop("spam".to_string())),
+                body if body.contains("rude") => Ok(Verdict::Flag("rude".to_string())),
+                _ => Ok(Verdict::Continue),
+            }
+        }
+    }
+
+    fn text(body: &str) -> Payload {
+        Payload::Text(TextMessage {
+            id: MessageId::random(),
+            body: body.to_string(),
+            ack_requested: false,
+        })
+    }
+
+    fn body(payload: &Payload) -> &str {
+        match payload {
+            Payload::Text(text) => &text.body,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let context = HookContext {
+            direction: Direction::Inbound,
+            topic: "chat",
+            peer: PeerId::random(),
+            sender: None,
+        };
+        let mut hooks = Hooks::new();
+        hooks.register(1, Box::new(Suffix(" b")));
+        hooks.register(0, Box::new(Suffix(" a")));
+        hooks.register(1, Box::new(Suffix(" c")));
+        let mut payload = text("hi");
+        assert_eq!(hooks.run(&context, &mut payload), Verdict::Continue);
+        assert_eq!(body(&payload), "hi a b c");
+
+        // A failing hook leaves the payload as it was.
+        hooks.register(2, Box::new(Suffix(" fail")));
+        let mut payload = text("hi");
+        assert_eq!(hooks.run(&context, &mut payload), Verdict::Continue);
+        assert_eq!(body(&payload), "hi a b c");
+
+        let mut payload = text("spam");
+        assert_eq!(
+            hooks.run(&context, &mut payload),
+            Verdict::Drop("spam".to_string())
+        );
+        let mut payload = text("rude");
+        assert_eq!(
+            hooks.run(&context, &mut payload),
+            Verdict::Flag("rude; rude; rude".to_string())
+        );
+
+        // A panicking hook is disabled, the others still running.
+        hooks.register(-1, Box::new(Suffix(" panic")));
+        let mut payload = text("hi");
+        assert_eq!(hooks.run(&context, &mut payload), Verdict::Continue);
+        assert_eq!(body(&payload), "hi a b c");
+        assert!(hooks.entries[0].disabled);
+
+        let mut payload = text(" \n");
+        assert_eq!(
+            BlankHook.handle(&context, &mut payload).unwrap(),
+            Verdict::Drop("blank message".to_string())
+        );
+    }
+}
@@ -0,0 +1,371 @@
+/*!
+ * HTTP module for the messaging application.
+ *
+ * Besides its Unix domain socket, a daemon can serve a small HTTP API on a
+ * TCP address, for scripts, dashboards and debugging with `curl`:
+ *
+ * - `POST /topics/{topic}/messages` publishes the body of the request, a
+ *   JSON object such as `{"body": "hi"}`, on a topic.
+ * - `GET /peers` lists the connected peers.
+ * - `GET /status` describes the node.
+ * - `GET /ws` opens a WebSocket, for browser front ends.
+ * - `POST /hooks/{topic}` publishes a JSON document sent by a webhook on a
+ *   topic, as described in the `webhook` module, when built with the
+ *   `webhooks` feature and `SEC_MSG_WEBHOOK_SECRET` is set.
+ *
+ * Every request but webhooks carries the token set in `SEC_MSG_HTTP_TOKEN` as
+ * `Authorization: Bearer <token>`, or as `?token=<token>` in the URL since
+ * browsers cannot set headers on WebSockets. Answers are the JSON responses
+ * of the control protocol of the `ipc` module, errors coming with a 4xx or
+ * 5xx status. A WebSocket speaks that protocol with a JSON object in every
+ * text frame: a client subscribes to topics and publishes with requests,
+ * and receives their messages as they arrive. The API is built with the
+ * `http` feature.
+ */
+
+/// The environment variable holding the token clients authenticate with.
+pub const TOKEN_VAR: &str = "SEC_MSG_HTTP_TOKEN";
+
+#[cfg(feature = "http")]
+pub use server::serve;
+
+#[cfg(feature = "http")]
+mod server {
+    use std::{env, error::Error, net::SocketAddr, sync::Arc};
+
+    use axum::{
+        body::Body,
+        extract::{Path, Request as HttpRequest, State},
+        http::{header, StatusCode},
+        middleware::{self, Next},
+        response::{IntoResponse, Response as HttpResponse},
+        routing::{get, post},
+        Json, Router,
+    };
+    use hyper_util::rt::TokioIo;
+    use log::{debug, error, info};
+    use serde::Deserialize;
+    use soketto::{
+        connection::{Error as WsError, Receiver, Sender},
+        handshake::{self, http::Server},
+    };
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use super::TOKEN_VAR;
+    use crate::app::AppEvents;
+    use crate::ipc::{ask, subscribe, unsubscribe, Request, Response, Subscribers, MAX_FRAME_LEN};
+    #[cfg(feature = "webhooks")]
+    use crate::webhook;
+
+    /// What the handlers share.
+    #[derive(Clone)]
+    struct Gateway {
+        events: AppEvents,
+        subscribers: Subscribers,
+        token: Arc<str>,
+    }
+
+    /// The connection under a WebSocket.
+    type Socket = Compat<TokioIo<hyper::upgrade::Upgraded>>;
+
+    /// The body of a message published over HTTP.
+    #[derive(Deserialize)]
+    struct Message {
+        body: String,
+    }
+
+    /// Hands a request to the swarm loop and answers with its response.
+    async fn answer(gateway: &Gateway, request: Request) -> HttpResponse {
+        debug!("HTTP request: {:?}", request);
+        match ask(&gateway.events, request).await {
+            Some(response @ Response::Error { .. }) => {
+                (StatusCode::BAD_REQUEST, Json(response)).into_response()
+            }
+            Some(response) => Json(response).into_response(),
+            None => error_response(StatusCode::SERVICE_UNAVAILABLE, "The node is shutting down"),
+        }
+    }
+
+    fn error_response(status: StatusCode, message: &str) -> HttpResponse {
+        let message = message.to_string();
+        (status, Json(Response::Error { message })).into_response()
+    }
+
+    async fn publish(
+        State(gateway): State<Gateway>,
+        Path(topic): Path<String>,
+        body: String,
+    ) -> HttpResponse {
+        // Parsed here so clients need not set a content type.
+        match serde_json::from_str::<Message>(&body) {
+            Ok(Message { body }) => answer(&gateway, Request::Publish { topic, body }).await,
+            Err(e) => error_response(StatusCode::BAD_REQUEST, &format!("Invalid body: {}", e)),
+        }
+    }
+
+    async fn peers(State(gateway): State<Gateway>) -> HttpResponse {
+        answer(&gateway, Request::Peers).await
+    }
+
+    async fn status(State(gateway): State<Gateway>) -> HttpResponse {
+        answer(&gateway, Request::Status).await
+    }
+
+    async fn websocket(State(gateway): State<Gateway>, mut request: HttpRequest) -> HttpResponse {
+        if !handshake::http::is_upgrade_request(&request) {
+            return error_response(StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade");
+        }
+        let mut server = Server::new();
+        let response = match server.receive_request(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                let message = format!("Invalid WebSocket upgrade: {}", e);
+                return error_response(StatusCode::BAD_REQUEST, &message);
+            }
+        };
+        // The connection is handed over once the response is sent.
+        let upgrade = hyper::upgrade::on(&mut request);
+        tokio::spawn(async move {
+            match upgrade.await {
+                Ok(upgraded) => {
+                    let mut builder = server.into_builder(TokioIo::new(upgraded).compat());
+                    builder.set_max_message_size(MAX_FRAME_LEN);
+                    let (sender, receiver) = builder.finish();
+                    serve_websocket(sender, receiver, gateway).await;
+                }
+                Err(e) => debug!("Failed to upgrade to a WebSocket: {}", e),
+            }
+        });
+        response.map(|()| Body::empty())
+    }
+
+    /// Serves the requests of a WebSocket client until it disconnects.
+    async fn serve_websocket(
+        mut sender: Sender<Socket>,
+        mut receiver: Receiver<Socket>,
+        gateway: Gateway,
+    ) {
+        let (client, mut outgoing) = mpsc::unbounded_channel();
+        let writing = tokio::spawn(async move {
+            while let Some(response) = outgoing.recv().await {
+                let sent = match serde_json::to_string(&response) {
+                    Ok(text) => sender.send_text_owned(text).await,
+                    Err(e) => {
+                        error!("Failed to encode a response: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = sent.and(sender.flush().await) {
+                    debug!("Failed to answer a WebSocket client: {}", e);
+                    break;
+                }
+            }
+            let _ = sender.close().await;
+        });
+        let mut data = Vec::new();
+        loop {
+            data.clear();
+            match receiver.receive_data(&mut data).await {
+                Ok(_) => {}
+                Err(WsError::Closed) => break,
+                Err(e) => {
+                    debug!("WebSocket client failed: {}", e);
+                    break;
+                }
+            }
+            let request = match serde_json::from_slice::<Request>(&data) {
+                Ok(request) => request,
+                Err(e) => {
+                    let message = format!("Invalid request: {}", e);
+                    let _ = client.send(Response::Error { message });
+                    continue;
+                }
+            };
+            debug!("WebSocket request: {:?}", request);
+            let topic = match &request {
+                Request::Subscribe { topic } => Some(topic.clone()),
+                _ => None,
+            };
+            // The node is shutting down.
+            let Some(response) = ask(&gateway.events, request).await else {
+                break;
+            };
+            if let (Some(topic), Response::Ok) = (topic, &response) {
+                subscribe(&gateway.subscribers, topic, &client);
+            }
+            if client.send(response).is_err() {
+                break;
+            }
+        }
+        unsubscribe(&gateway.subscribers, &client);
+        drop(client);
+        let _ = writing.await;
+    }
+
+    /// What the webhook handler needs.
+    #[cfg(feature = "webhooks")]
+    #[derive(Clone)]
+    struct Hooks {
+        events: AppEvents,
+        secret: Arc<str>,
+    }
+
+    /// Publishes the JSON document of a webhook, signed or carrying the
+    /// secret.
+    #[cfg(feature = "webhooks")]
+    async fn hook(
+        State(hooks): State<Hooks>,
+        Path(topic): Path<String>,
+        headers: axum::http::HeaderMap,
+        body: axum::body::Bytes,
+    ) -> HttpResponse {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let secret = hooks.secret.as_bytes();
+        let trusted = match header(webhook::SIGNATURE_HEADER) {
+            Some(signature) => webhook::verify(secret, &body, signature),
+            None => {
+                header(webhook::SECRET_HEADER).is_some_and(|given| same(given.as_bytes(), secret))
+            }
+        };
+        if !trusted {
+            return error_response(StatusCode::UNAUTHORIZED, "Missing or wrong signature");
+        }
+        let document = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(document) => document,
+            Err(e) => {
+                return error_response(StatusCode::BAD_REQUEST, &format!("Invalid body: {}", e))
+            }
+        };
+        let body = webhook::text(&document);
+        debug!("Webhook on {}: {:?}", topic, body);
+        match ask(&hooks.events, Request::Publish { topic, body }).await {
+            Some(response @ Response::Error { .. }) => {
+                (StatusCode::BAD_REQUEST, Json(response)).into_response()
+            }
+            Some(response) => Json(response).into_response(),
+            None => error_response(StatusCode::SERVICE_UNAVAILABLE, "The node is shutting down"),
+        }
+    }
+
+    /// Turns away requests without the token.
+    async fn authenticate(
+        State(gateway): State<Gateway>,
+        request: HttpRequest,
+        next: Next,
+    ) -> HttpResponse {
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| {
+                request
+                    .uri()
+                    .query()?
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("token="))
+            });
+        match token {
+            Some(token) if same(token.as_bytes(), gateway.token.as_bytes()) => {
+                next.run(request).await
+            }
+            _ => error_response(StatusCode::UNAUTHORIZED, "Missing or wrong token"),
+        }
+    }
+
+    /// Compares tokens in a time independent of where they differ.
+    fn same(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// Serves the HTTP API on a TCP address, handing the requests to the
+    /// swarm loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address listened on.
+    /// * `events` - The channel to the swarm loop.
+    /// * `subscribers` - The clients subscribed to each topic.
+    pub async fn serve(
+        addr: SocketAddr,
+        events: AppEvents,
+        subscribers: Subscribers,
+    ) -> Result<(), Box<dyn Error>> {
+        let token = env::var(TOKEN_VAR)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| format!("Set {} to the token HTTP clients send", TOKEN_VAR))?;
+        #[cfg(feature = "webhooks")]
+        let hooks = env::var(webhook::SECRET_VAR)
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| Hooks {
+                events: events.clone(),
+                secret: secret.into(),
+            });
+        let gateway = Gateway {
+            events,
+            subscribers,
+            token: token.into(),
+        };
+        let app = Router::new()
+            .route("/topics/:topic/messages", post(publish))
+            .route("/peers", get(peers))
+            .route("/status", get(status))
+            .route("/ws", get(websocket))
+            .route_layer(middleware::from_fn_with_state(
+                gateway.clone(),
+                authenticate,
+            ))
+            .with_state(gateway);
+        // Webhooks authenticate with their own secret.
+        #[cfg(feature = "webhooks")]
+        let app = match hooks {
+            Some(hooks) => app.merge(
+                Router::new()
+                    .route("/hooks/:topic", post(hook))
+                    .with_state(hooks),
+            ),
+            None => {
+                info!(
+                    "{} is not set, webhooks are not served",
+                    webhook::SECRET_VAR
+                );
+                app
+            }
+        };
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Failed to listen for HTTP on {}: {}", addr, e))?;
+        info!("Serving HTTP on {}", listener.local_addr()?);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                error!("HTTP server failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::same;
+
+        #[test]
+        fn test_same() {
+            assert!(same(b"secret", b"secret"));
+            assert!(!same(b"secret", b"secreT"));
+            assert!(!same(b"secret", b"secrets"));
+        }
+    }
+}
+
+/// Fails, as the HTTP API is built with the `http` feature.
+#[cfg(not(feature = "http"))]
+pub async fn serve(
+    _addr: std::net::SocketAddr,
+    _events: crate::app::AppEvents,
+    _subscribers: crate::ipc::Subscribers,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without the HTTP API, rebuild with --features http".into())
+}
@@ -0,0 +1,142 @@
+/*!
+ * Ignore module for the messaging application.
+ *
+ * Ignoring a peer hides its messages, reactions and binary messages on
+ * every topic, and its replies by email, for the local user only. Unlike a
+ * ban, which disconnects a peer, or a mute, which moderators issue for
+ * everyone, the peer stays connected and keeps relaying, so noisy but
+ * harmless peers can be quieted without hurting the network.
+ *
+ * The ignored peers are written to the data directory on every change,
+ * sealed like the identity when storage is encrypted.
+ */
+
+use std::{collections::BTreeMap, error::Error, path::PathBuf, sync::Arc};
+
+use libp2p::PeerId;
+
+use crate::moderation::now;
+use crate::storage::{self, Vault};
+
+/// The peers the user ignores, with when they were ignored in milliseconds
+/// since the epoch.
+pub struct IgnoreList {
+    peers: BTreeMap<PeerId, u64>,
+    /// The file the ignored peers are saved to, and the vault sealing it.
+    file: Option<(PathBuf, Option<Arc<Vault>>)>,
+}
+
+impl IgnoreList {
+    /// Creates a new, empty list kept in memory only.
+    pub fn new() -> Self {
+        IgnoreList {
+            peers: BTreeMap::new(),
+            file: None,
+        }
+    }
+
+    /// Loads the ignored peers saved to a file, saving them there on every
+    /// change.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The ignore file, which need not exist yet.
+    /// * `vault` - The vault, if storage encryption is on.
+    pub fn load(path: PathBuf, vault: Option<Arc<Vault>>) -> Result<Self, Box<dyn Error>> {
+        let peers = match storage::read(&path, vault.as_deref())? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => BTreeMap::new(),
+        };
+        Ok(IgnoreList {
+            peers,
+            file: Some((path, vault)),
+        })
+    }
+
+    /// Ignores a peer.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing whether the peer was not ignored yet, or an
+    /// error if the list cannot be saved.
+    pub fn ignore(&mut self, peer: PeerId) -> Result<bool, Box<dyn Error>> {
+        if self.peers.contains_key(&peer) {
+            return Ok(false);
+        }
+        self.peers.insert(peer, now());
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Stops ignoring a peer.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing whether the peer was ignored, or an error if
+    /// the list cannot be saved.
+    pub fn lift(&mut self, peer: &PeerId) -> Result<bool, Box<dyn Error>> {
+        if self.peers.remove(peer).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Returns whether a peer is ignored.
+    pub fn is_ignored(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    /// Returns the ignored peers with when they were ignored, oldest first.
+    pub fn list(&self) -> Vec<(PeerId, u64)> {
+        let mut peers: Vec<(PeerId, u64)> = self
+            .peers
+            .iter()
+            .map(|(peer, since)| (*peer, *since))
+            .collect();
+        peers.sort_by_key(|(_, since)| *since);
+        peers
+    }
+
+    /// Saves the ignored peers to their file, if they have one.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some((path, vault)) = &self.file {
+            storage::write(path, &bincode::serialize(&self.peers)?, vault.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for IgnoreList {
+    fn default() -> Self {
+        IgnoreList::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::IgnoreList;
+
+    #[test]
+    fn test_ignore_and_lift() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-ignore-{}", std::process::id()));
+        let path = dir.join("ignored.db");
+        let mut ignored = IgnoreList::load(path.clone(), None).unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        assert!(ignored.ignore(alice).unwrap());
+        assert!(!ignored.ignore(alice).unwrap());
+        assert!(ignored.ignore(bob).unwrap());
+        assert!(ignored.lift(&bob).unwrap());
+        assert!(!ignored.lift(&bob).unwrap());
+
+        // Ignored peers are kept between runs.
+        let loaded = IgnoreList::load(path, None).unwrap();
+        assert!(loaded.is_ignored(&alice));
+        assert!(!loaded.is_ignored(&bob));
+        assert_eq!(loaded.list().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
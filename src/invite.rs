@@ -0,0 +1,197 @@
+/*!
+ * Invite module for the messaging application.
+ *
+ * This module defines the invite tokens admitting peers to private topics.
+ * A token names the topic, the invited peer, an optional expiry, and a
+ * random nonce, and is signed by the founder or a moderator of the topic.
+ * Tokens are shared out of band as text. A joining peer presents the token
+ * in a signed join directive on the topic, which outsiders and the history
+ * see too, so every member checks that the token is intact, names the peer
+ * that signed the directive, is unexpired by the local clock, was not used
+ * before, and was signed by a peer allowed to invite, before admitting the
+ * joining peer.
+ */
+
+use std::{error::Error, fmt};
+
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the text form of an invite token.
+pub const INVITE_PREFIX: &str = "sec_msg-invite:";
+
+/// An invite to a private topic, signed by the peer issuing it.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InviteToken {
+    pub topic: String,
+    /// The peer the token admits.
+    pub invitee: PeerId,
+    /// When the token stops admitting peers, in milliseconds since the
+    /// epoch.
+    pub expires: Option<u64>,
+    /// Random value telling tokens for the same topic apart.
+    pub nonce: u64,
+    /// The protobuf encoded public key of the issuer.
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Reasons an invite token is refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InviteError {
+    /// The text is not an invite token.
+    Malformed,
+    /// The signature does not match the token.
+    InvalidSignature,
+    /// The token expired.
+    Expired,
+    /// The token admits another peer.
+    WrongPeer,
+    /// The token was used already.
+    Used,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::Malformed => write!(f, "not an invite token"),
+            InviteError::InvalidSignature => write!(f, "invalid invite signature"),
+            InviteError::Expired => write!(f, "invite expired"),
+            InviteError::WrongPeer => write!(f, "invite issued to another peer"),
+            InviteError::Used => write!(f, "invite already used"),
+        }
+    }
+}
+
+impl Error for InviteError {}
+
+impl fmt::Debug for InviteToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InviteToken")
+            .field("topic", &self.topic)
+            .field("invitee", &self.invitee)
+            .field("expires", &self.expires)
+            .field("nonce", &self.nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl InviteToken {
+    /// Issues an invite to a topic signed with the local key.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the invite admits to.
+    /// * `invitee` - The peer the invite admits.
+    /// * `expires` - When the invite expires, in milliseconds since the
+    ///   epoch, if ever.
+    /// * `local_key` - The local identity keypair.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signed `InviteToken` or an error.
+    pub fn issue(
+        topic: &str,
+        invitee: PeerId,
+        expires: Option<u64>,
+        local_key: &identity::Keypair,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut token = InviteToken {
+            topic: topic.to_string(),
+            invitee,
+            expires,
+            nonce: rand::random(),
+            public_key: local_key.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        token.signature = local_key.sign(&token.signed_bytes()?)?;
+        Ok(token)
+    }
+
+    /// Verifies the signature and expiry of the token.
+    ///
+    /// # Arguments
+    ///
+    /// * `at` - When the token is presented, in milliseconds since the epoch.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `PeerId` of the issuer.
+    pub fn verify(&self, at: u64) -> Result<PeerId, InviteError> {
+        let public_key = identity::PublicKey::try_decode_protobuf(&self.public_key)
+            .map_err(|_| InviteError::InvalidSignature)?;
+        let signed = self
+            .signed_bytes()
+            .map_err(|_| InviteError::InvalidSignature)?;
+        if !public_key.verify(&signed, &self.signature) {
+            return Err(InviteError::InvalidSignature);
+        }
+        if self.expires.is_some_and(|expires| expires <= at) {
+            return Err(InviteError::Expired);
+        }
+        Ok(PeerId::from(public_key))
+    }
+
+    /// Returns the text form of the token, to be shared out of band.
+    pub fn encode(&self) -> String {
+        let bytes = bincode::serialize(self).unwrap_or_default();
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{}", INVITE_PREFIX, hex)
+    }
+
+    /// Parses the text form of a token, without verifying it.
+    pub fn decode(text: &str) -> Result<Self, InviteError> {
+        let hex = text
+            .trim()
+            .strip_prefix(INVITE_PREFIX)
+            .filter(|hex| hex.len() % 2 == 0 && hex.is_ascii())
+            .ok_or(InviteError::Malformed)?;
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| InviteError::Malformed)?;
+        bincode::deserialize(&bytes).map_err(|_| InviteError::Malformed)
+    }
+
+    /// Returns the bytes covered by the signature.
+    fn signed_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(&(
+            &self.topic,
+            self.invitee,
+            self.expires,
+            self.nonce,
+            &self.public_key,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity, PeerId};
+
+    use super::{InviteError, InviteToken};
+
+    #[test]
+    fn test_issue_encode_and_verify() {
+        let key = identity::Keypair::generate_ed25519();
+        let invitee = PeerId::random();
+        let token = InviteToken::issue("club", invitee, Some(2_000), &key).unwrap();
+        let decoded = InviteToken::decode(&token.encode()).unwrap();
+        assert_eq!(decoded, token);
+        assert_eq!(decoded.verify(1_000), Ok(key.public().to_peer_id()));
+        assert_eq!(decoded.verify(2_000), Err(InviteError::Expired));
+
+        let mut forged = token.clone();
+        forged.topic = "other".to_string();
+        assert_eq!(forged.verify(1_000), Err(InviteError::InvalidSignature));
+        // The invitee is signed too.
+        let mut stolen = token.clone();
+        stolen.invitee = PeerId::random();
+        assert_eq!(stolen.verify(1_000), Err(InviteError::InvalidSignature));
+        assert_eq!(
+            InviteToken::decode("sec_msg-invite:zz"),
+            Err(InviteError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,678 @@
+/*!
+ * IPC module for the messaging application.
+ *
+ * A node run with `sec_msg daemon` has no user interface, and is driven
+ * over a Unix domain socket instead, by `sec_msg ctl` or any program that
+ * speaks the protocol. Every frame on the socket is a JSON object preceded
+ * by its length as a 32-bit big-endian integer. Clients send requests to
+ * subscribe to a topic, publish on one or list the connected peers, and
+ * the node answers each with one response; a subscribed connection then
+ * also receives the messages of the topic as they arrive. A daemon running
+ * several identities serves them all on one socket, requests naming the
+ * identity they are for, the default one otherwise.
+ *
+ * Connections are served by their own tasks, which hand the requests to
+ * the swarm loop as `AppEvent`s and pass the responses back. Messages are
+ * forwarded by a hook, so they are only seen once verified, and only if no
+ * hook before it dropped them.
+ */
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use libp2p::{Multiaddr, PeerId, Swarm};
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc::UnboundedSender, oneshot},
+};
+
+use crate::app::{AppEvent, AppEvents};
+use crate::command::join_topic;
+use crate::delivery::MessageId;
+use crate::hooks::{Direction, Hook, HookContext, Verdict};
+use crate::protocol::{BinaryMessage, Payload, Protocols};
+use crate::state::AppState;
+use crate::ui::{publish_binary, publish_text};
+use crate::version::agent_version;
+
+/// Largest frame accepted, in bytes.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// What a client asks the node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// Joins a topic, if needed, and forwards its messages.
+    Subscribe { topic: String },
+    /// Publishes a text message on a topic.
+    Publish { topic: String, body: String },
+    /// Publishes bytes that are not text on a topic.
+    PublishBinary {
+        topic: String,
+        media_type: String,
+        data: Vec<u8>,
+    },
+    /// Lists the connected peers.
+    Peers,
+    /// Dials a peer at a multiaddress.
+    Connect { address: String },
+    /// Describes the node.
+    Status,
+}
+
+/// A request on the control socket, for the node of one identity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Routed {
+    /// The identity asked, the default one if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// What the node answers, or forwards to subscribed clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Error {
+        message: String,
+    },
+    Peers {
+        peers: Vec<PeerInfo>,
+    },
+    Status {
+        peer_id: String,
+        version: String,
+        listen_addrs: Vec<String>,
+        topics: Vec<String>,
+        /// The number of connected peers.
+        peers: usize,
+        nat: String,
+        /// The number of messages waiting for a peer to join their topic.
+        #[serde(default)]
+        queued: usize,
+    },
+    /// A message received on a subscribed topic.
+    Message {
+        topic: String,
+        id: String,
+        peer: String,
+        name: Option<String>,
+        body: String,
+    },
+    /// Bytes that are not text received on a subscribed topic.
+    Binary {
+        topic: String,
+        id: String,
+        peer: String,
+        name: Option<String>,
+        media_type: String,
+        data: Vec<u8>,
+    },
+}
+
+/// A connected peer, as listed to clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub peer: String,
+    pub name: Option<String>,
+    pub address: String,
+    pub latency_ms: Option<u64>,
+}
+
+/// The channels of the clients subscribed to each topic.
+pub type Subscribers = Arc<Mutex<HashMap<String, Vec<UnboundedSender<Response>>>>>;
+
+/// Handles a request of a client.
+///
+/// # Arguments
+///
+/// * `request` - The request.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn handle(request: Request, swarm: &mut Swarm<Protocols>, state: &mut AppState) -> Response {
+    let error = |message: String| Response::Error { message };
+    match request {
+        Request::Subscribe { topic } => {
+            if topic.trim().is_empty() {
+                return error("Empty topic".to_string());
+            }
+            let protocol = state.topics.default_protocol();
+            if !state.topics.is_subscribed(&topic) && !join_topic(&topic, protocol, swarm, state) {
+                return error(format!("Failed to subscribe to {:?}", topic));
+            }
+            Response::Ok
+        }
+        Request::Publish { topic, body } => match publish_text(body, &topic, state) {
+            true => Response::Ok,
+            false => error(format!("Failed to publish on {:?}", topic)),
+        },
+        Request::PublishBinary {
+            topic,
+            media_type,
+            data,
+        } => {
+            let binary = BinaryMessage {
+                id: MessageId::random(),
+                media_type,
+                data,
+            };
+            if !binary.is_valid() {
+                return error(format!("Invalid media type: {:?}", binary.media_type));
+            }
+            match publish_binary(binary, &topic, state) {
+                true => Response::Ok,
+                false => error(format!("Failed to publish on {:?}", topic)),
+            }
+        }
+        Request::Peers => Response::Peers {
+            peers: state
+                .peers
+                .peers()
+                .into_iter()
+                .map(|(peer_id, peer)| PeerInfo {
+                    peer: peer_id.to_base58(),
+                    name: state.profiles.name(peer_id).map(str::to_string),
+                    address: peer.address.to_string(),
+                    latency_ms: peer.rtt.map(|rtt| rtt.as_millis() as u64),
+                })
+                .collect(),
+        },
+        Request::Connect { address } => {
+            let Some(addr) = address
+                .parse::<Multiaddr>()
+                .ok()
+                .filter(|addr| !addr.is_empty())
+            else {
+                return error(format!("Invalid multiaddress: {:?}", address));
+            };
+            info!("Dialing {:?}", addr);
+            match swarm.dial(addr) {
+                Ok(()) => Response::Ok,
+                Err(e) => error(format!("Failed to dial address: {}", e)),
+            }
+        }
+        Request::Status => Response::Status {
+            peer_id: swarm.local_peer_id().to_base58(),
+            version: agent_version(),
+            listen_addrs: swarm.listeners().map(ToString::to_string).collect(),
+            topics: state
+                .topics
+                .subscribed()
+                .map(|(topic, _)| topic.to_string())
+                .collect(),
+            peers: swarm.connected_peers().count(),
+            nat: state.nat.status().to_string(),
+            queued: state.outbound.held(),
+        },
+    }
+}
+
+/// Adds a client to the subscribers of a topic, unless it is one already.
+pub fn subscribe(subscribers: &Subscribers, topic: String, client: &UnboundedSender<Response>) {
+    let mut subscribers = subscribers.lock().unwrap_or_else(PoisonError::into_inner);
+    let clients = subscribers.entry(topic).or_default();
+    if !clients.iter().any(|other| other.same_channel(client)) {
+        clients.push(client.clone());
+    }
+}
+
+/// Removes a client from the subscribers of every topic.
+pub fn unsubscribe(subscribers: &Subscribers, client: &UnboundedSender<Response>) {
+    subscribers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .values_mut()
+        .for_each(|clients| clients.retain(|other| !other.same_channel(client)));
+}
+
+/// Hands a request to the swarm loop and waits for its response.
+///
+/// # Arguments
+///
+/// * `events` - The channel to the swarm loop.
+/// * `request` - The request.
+///
+/// # Returns
+///
+/// The response, or `None` if the node is shutting down.
+pub async fn ask(events: &AppEvents, request: Request) -> Option<Response> {
+    let (reply, response) = oneshot::channel();
+    events.send(AppEvent::Ipc(request, reply)).ok()?;
+    response.await.ok()
+}
+
+/// A hook forwarding the text messages received on a topic to the clients
+/// subscribed to it.
+pub struct IpcHook {
+    subscribers: Subscribers,
+}
+
+impl IpcHook {
+    /// Creates a hook forwarding messages to subscribed clients.
+    pub fn new(subscribers: Subscribers) -> Self {
+        IpcHook { subscribers }
+    }
+}
+
+impl Hook for IpcHook {
+    fn name(&self) -> &str {
+        "ipc"
+    }
+
+    fn handle(
+        &mut self,
+        context: &HookContext,
+        payload: &mut Payload,
+    ) -> Result<Verdict, Box<dyn Error>> {
+        if context.direction != Direction::Inbound {
+            return Ok(Verdict::Continue);
+        }
+        let message = match payload {
+            Payload::Text(text) => Response::Message {
+                topic: context.topic.to_string(),
+                id: text.id.to_string(),
+                peer: context.peer.to_base58(),
+                name: context.sender.map(str::to_string),
+                body: text.body.clone(),
+            },
+            Payload::Binary(binary) if binary.is_valid() => Response::Binary {
+                topic: context.topic.to_string(),
+                id: binary.id.to_string(),
+                peer: context.peer.to_base58(),
+                name: context.sender.map(str::to_string),
+                media_type: binary.media_type.clone(),
+                data: binary.data.clone(),
+            },
+            _ => return Ok(Verdict::Continue),
+        };
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(clients) = subscribers.get_mut(context.topic) {
+            // Clients that went away are forgotten.
+            clients.retain(|client| client.send(message.clone()).is_ok());
+        }
+        Ok(Verdict::Continue)
+    }
+}
+
+/// Writes a frame.
+///
+/// # Arguments
+///
+/// * `writer` - Where the frame is written.
+/// * `value` - What the frame holds.
+pub async fn write_frame<W, T>(writer: &mut W, value: &T) -> Result<(), Box<dyn Error>>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let data = serde_json::to_vec(value)?;
+    if data.len() > MAX_FRAME_LEN {
+        return Err(format!("Frame of {} bytes is too large", data.len()).into());
+    }
+    writer.write_u32(data.len() as u32).await?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a frame.
+///
+/// # Returns
+///
+/// A `Result` containing what the frame holds, or `None` if the other side
+/// closed the connection.
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>, Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(format!("Frame of {} bytes is too large", len).into());
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// Returns the short form of a peer ID shown by `ctl`.
+fn short_peer(peer: &str) -> &str {
+    peer.parse::<PeerId>()
+        .map_or(peer, |_| &peer[peer.len().saturating_sub(8)..])
+}
+
+#[cfg(unix)]
+pub use unix::{ctl, serve};
+
+#[cfg(unix)]
+mod unix {
+    use std::{error::Error, fs, os::unix::fs::PermissionsExt, path::Path};
+
+    use log::{debug, info, warn};
+    use tokio::{
+        net::{UnixListener, UnixStream},
+        sync::mpsc,
+    };
+
+    use super::{read_frame, short_peer, write_frame, Request, Response, Routed};
+    use crate::cli::CtlCommand;
+    use crate::client::Clients;
+    use crate::systemd;
+
+    /// Listens on a Unix domain socket, handing the requests of clients to
+    /// the swarm loop. When socket-activated, the socket passed by systemd
+    /// is used instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The socket, replaced if left by an earlier run.
+    /// * `clients` - The nodes of the identities served.
+    pub fn serve(path: &Path, clients: Clients) -> Result<(), Box<dyn Error>> {
+        if let Some(listener) = systemd::activated_listener()
+            .map_err(|e| format!("Invalid socket from systemd: {}", e))?
+        {
+            listener.set_nonblocking(true)?;
+            info!("Listening for control clients on the socket from systemd");
+            accept(UnixListener::from_std(listener)?, clients);
+            return Ok(());
+        }
+        // A socket still accepting connections belongs to a running node.
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("Another node is listening on {:?}", path).into());
+        }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Only the current user may drive the node.
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        info!("Listening for control clients on {:?}", path);
+        accept(listener, clients);
+        Ok(())
+    }
+
+    /// Serves the clients connecting to a socket.
+    fn accept(listener: UnixListener, clients: Clients) {
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_client(stream, clients.clone()));
+                    }
+                    Err(e) => warn!("Failed to accept a control client: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Serves the requests of a client until it disconnects.
+    async fn serve_client(stream: UnixStream, clients: Clients) {
+        let (mut reader, mut writer) = stream.into_split();
+        let (client, mut outgoing) = mpsc::unbounded_channel();
+        let writing = tokio::spawn(async move {
+            while let Some(response) = outgoing.recv().await {
+                if let Err(e) = write_frame(&mut writer, &response).await {
+                    debug!("Failed to answer a control client: {}", e);
+                    break;
+                }
+            }
+        });
+        loop {
+            let Routed { identity, request } = match read_frame(&mut reader).await {
+                Ok(Some(routed)) => routed,
+                Ok(None) => break,
+                Err(e) => {
+                    let message = format!("Invalid request: {}", e);
+                    let _ = client.send(Response::Error { message });
+                    break;
+                }
+            };
+            debug!("Control request for {:?}: {:?}", identity, request);
+            let Some(node) = clients.get(identity.as_deref()) else {
+                let message = format!("No identity named {:?}", identity.unwrap_or_default());
+                if client.send(Response::Error { message }).is_err() {
+                    break;
+                }
+                continue;
+            };
+            let topic = match &request {
+                Request::Subscribe { topic } => Some(topic.clone()),
+                _ => None,
+            };
+            // The node is shutting down.
+            let Some(response) = node.ask(request).await else {
+                break;
+            };
+            if let (Some(topic), Response::Ok) = (topic, &response) {
+                node.forward(topic, &client);
+            }
+            if client.send(response).is_err() {
+                break;
+            }
+        }
+        for node in clients.iter() {
+            node.forget(&client);
+        }
+        drop(client);
+        let _ = writing.await;
+    }
+
+    /// Sends a request to a daemon and prints what it answers, following
+    /// the messages of a topic when subscribing.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The socket of the daemon.
+    /// * `identity` - The identity asked, the default one if not given.
+    /// * `command` - What to ask.
+    /// * `json` - Whether the responses are printed as JSON lines.
+    pub async fn ctl(
+        path: &Path,
+        identity: Option<&str>,
+        command: &CtlCommand,
+        json: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut stream = UnixStream::connect(path).await.map_err(|e| {
+            format!(
+                "Failed to connect to {:?}, is the daemon running? {}",
+                path, e
+            )
+        })?;
+        let request = match command {
+            CtlCommand::Subscribe { topic } => Request::Subscribe {
+                topic: topic.clone(),
+            },
+            CtlCommand::Publish { topic, message } => Request::Publish {
+                topic: topic.clone(),
+                body: message.join(" "),
+            },
+            CtlCommand::Peers => Request::Peers,
+            CtlCommand::Connect { address } => Request::Connect {
+                address: address.clone(),
+            },
+            CtlCommand::Status => Request::Status,
+        };
+        let follow = matches!(request, Request::Subscribe { .. });
+        let routed = Routed {
+            identity: identity.map(str::to_string),
+            request,
+        };
+        write_frame(&mut stream, &routed).await?;
+        while let Some(response) = read_frame::<_, Response>(&mut stream).await? {
+            if json {
+                println!("{}", serde_json::to_string(&response)?);
+            }
+            match response {
+                Response::Error { message } => return Err(message.into()),
+                Response::Ok if follow => eprintln!("Subscribed, waiting for messages"),
+                Response::Ok | Response::Peers { .. } | Response::Status { .. } if json => {
+                    return Ok(())
+                }
+                Response::Ok => return Ok(()),
+                Response::Peers { peers } => {
+                    if peers.is_empty() {
+                        println!("No peers connected");
+                    }
+                    for peer in peers {
+                        println!(
+                            "{} {} {} {}",
+                            peer.peer,
+                            peer.name.as_deref().unwrap_or("anonymous"),
+                            peer.address,
+                            peer.latency_ms
+                                .map_or("unknown".to_string(), |ms| format!("{}ms", ms))
+                        );
+                    }
+                    return Ok(());
+                }
+                Response::Status {
+                    peer_id,
+                    version,
+                    listen_addrs,
+                    topics,
+                    peers,
+                    nat,
+                    queued,
+                } => {
+                    println!("Peer ID:   {}", peer_id);
+                    println!("Version:   {}", version);
+                    println!("Listening: {}", listen_addrs.join(", "));
+                    println!("Topics:    {}", topics.join(", "));
+                    println!("Peers:     {}", peers);
+                    println!("NAT:       {}", nat);
+                    if queued > 0 {
+                        println!("Queued:    {} messages", queued);
+                    }
+                    return Ok(());
+                }
+                Response::Message { .. } if json => {}
+                Response::Message {
+                    topic,
+                    peer,
+                    name,
+                    body,
+                    ..
+                } => {
+                    let sender = name.as_deref().unwrap_or(short_peer(&peer));
+                    println!("[{}] {}: {}", topic, sender, body);
+                }
+                Response::Binary { .. } if json => {}
+                Response::Binary {
+                    topic,
+                    peer,
+                    name,
+                    media_type,
+                    data,
+                    ..
+                } => {
+                    let sender = name.as_deref().unwrap_or(short_peer(&peer));
+                    println!(
+                        "[{}] {} sent {} bytes of {}",
+                        topic,
+                        sender,
+                        data.len(),
+                        media_type
+                    );
+                }
+            }
+        }
+        Err("The daemon closed the connection".into())
+    }
+}
+
+/// Fails, as the daemon needs Unix domain sockets.
+#[cfg(not(unix))]
+pub fn serve(
+    _path: &std::path::Path,
+    _clients: crate::client::Clients,
+) -> Result<(), Box<dyn Error>> {
+    Err("The daemon needs Unix domain sockets".into())
+}
+
+/// Fails, as the daemon needs Unix domain sockets.
+#[cfg(not(unix))]
+pub async fn ctl(
+    _path: &std::path::Path,
+    _identity: Option<&str>,
+    _command: &crate::cli::CtlCommand,
+    _json: bool,
+) -> Result<(), Box<dyn Error>> {
+    Err("The daemon needs Unix domain sockets".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, write_frame, Request, Response, Routed, MAX_FRAME_LEN};
+
+    #[tokio::test]
+    async fn test_frames() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let request = Request::Publish {
+            topic: "chat".to_string(),
+            body: "hello \"world\"".to_string(),
+        };
+        write_frame(&mut client, &request).await.unwrap();
+        write_frame(&mut client, &Request::Peers).await.unwrap();
+        assert_eq!(read_frame(&mut server).await.unwrap(), Some(request));
+        assert_eq!(
+            read_frame::<_, Request>(&mut server).await.unwrap(),
+            Some(Request::Peers)
+        );
+
+        // The wire format is plain JSON behind a length.
+        let json = serde_json::to_string(&Response::Error {
+            message: "no".to_string(),
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"type":"error","message":"no"}"#);
+        let json = serde_json::to_string(&Request::Connect {
+            address: "/ip4/127.0.0.1/tcp/4001".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"op":"connect","address":"/ip4/127.0.0.1/tcp/4001"}"#
+        );
+        let routed: Routed = serde_json::from_str(r#"{"op":"peers","identity":"bot"}"#).unwrap();
+        assert_eq!(routed.identity.as_deref(), Some("bot"));
+        assert_eq!(routed.request, Request::Peers);
+        let frame = br#"{"op":"subscribe","topic":"rust"}"#;
+        tokio::io::AsyncWriteExt::write_u32(&mut client, frame.len() as u32)
+            .await
+            .unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut client, frame)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut server).await.unwrap(),
+            Some(Request::Subscribe {
+                topic: "rust".to_string()
+            })
+        );
+
+        tokio::io::AsyncWriteExt::write_u32(&mut client, MAX_FRAME_LEN as u32 + 1)
+            .await
+            .unwrap();
+        assert!(read_frame::<_, Request>(&mut server).await.is_err());
+        drop(client);
+        assert_eq!(read_frame::<_, Request>(&mut server).await.unwrap(), None);
+    }
+}
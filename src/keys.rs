@@ -0,0 +1,304 @@
+/*!
+ * Key bindings module for the messaging application.
+ *
+ * This module maps the keys pressed in the terminal UI to what they do.
+ * The defaults follow common terminal chat clients, and each binding can
+ * be replaced by listing `binding=key` pairs, such as
+ * `next=ctrl-n,previous=ctrl-p,quit=ctrl-q`. A binding listed more than
+ * once is bound to every key given. Keys are written as an optional
+ * `ctrl-`, `alt-` or `shift-` prefix followed by a character or a key
+ * name.
+ */
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// What a key does in the terminal UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+    /// Sends the input line.
+    Send,
+    /// Starts a new line in the input, to send several lines at once.
+    Newline,
+    /// Quits the application.
+    Quit,
+    /// Completes the last word of the input line.
+    Complete,
+    /// Clears the input line.
+    Clear,
+    /// Focuses the next conversation.
+    Next,
+    /// Focuses the previous conversation.
+    Previous,
+    /// Scrolls the message pane a page up.
+    ScrollUp,
+    /// Scrolls the message pane a page down.
+    ScrollDown,
+    /// Scrolls to the oldest message.
+    ScrollTop,
+    /// Scrolls to the latest message.
+    ScrollBottom,
+    /// Starts a `/search` on the input line.
+    Search,
+    /// Shows messages with or without their Markdown formatting.
+    Raw,
+}
+
+/// Every binding with its name and default keys.
+const BINDINGS: &[(Binding, &str, &[&str])] = &[
+    (Binding::Send, "send", &["enter"]),
+    (Binding::Newline, "newline", &["alt-enter"]),
+    (Binding::Quit, "quit", &["ctrl-c", "ctrl-d"]),
+    (Binding::Complete, "complete", &["tab"]),
+    (Binding::Clear, "clear", &["esc"]),
+    (Binding::Next, "next", &["alt-right"]),
+    (Binding::Previous, "previous", &["alt-left"]),
+    (Binding::ScrollUp, "scroll-up", &["pageup"]),
+    (Binding::ScrollDown, "scroll-down", &["pagedown"]),
+    (Binding::ScrollTop, "scroll-top", &["home"]),
+    (Binding::ScrollBottom, "scroll-bottom", &["end"]),
+    (Binding::Search, "search", &["ctrl-f"]),
+    (Binding::Raw, "raw", &["alt-r"]),
+];
+
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BINDINGS
+            .iter()
+            .find(|(_, name, _)| *name == s)
+            .map(|(binding, _, _)| *binding)
+            .ok_or_else(|| format!("unknown binding {:?}", s))
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name, _) = BINDINGS
+            .iter()
+            .find(|(binding, _, _)| binding == self)
+            .expect("every binding is listed");
+        write!(f, "{}", name)
+    }
+}
+
+/// A key with its modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    /// Returns the key of a key press.
+    pub fn of(event: &KeyEvent) -> Self {
+        let mut modifiers = event.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT);
+        // Uppercase characters already tell shift was held.
+        if !matches!(event.code, KeyCode::Char(_)) {
+            modifiers |= event.modifiers & KeyModifiers::SHIFT;
+        }
+        let code = match event.code {
+            KeyCode::Char(c) if !modifiers.is_empty() => KeyCode::Char(c.to_ascii_lowercase()),
+            // Some terminals report shift-tab as back tab.
+            KeyCode::BackTab => {
+                modifiers |= KeyModifiers::SHIFT;
+                KeyCode::Tab
+            }
+            code => code,
+        };
+        Key { code, modifiers }
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s.trim();
+        loop {
+            let lower = rest.to_ascii_lowercase();
+            let (modifier, len) = if lower.starts_with("ctrl-") {
+                (KeyModifiers::CONTROL, 5)
+            } else if lower.starts_with("alt-") {
+                (KeyModifiers::ALT, 4)
+            } else if lower.starts_with("shift-") {
+                (KeyModifiers::SHIFT, 6)
+            } else {
+                break;
+            };
+            modifiers |= modifier;
+            rest = &rest[len..];
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(if modifiers.is_empty() {
+                c
+            } else {
+                c.to_ascii_lowercase()
+            }),
+            _ => match rest.to_ascii_lowercase().as_str() {
+                "enter" => KeyCode::Enter,
+                "tab" => KeyCode::Tab,
+                "esc" => KeyCode::Esc,
+                "backspace" => KeyCode::Backspace,
+                "space" => KeyCode::Char(' '),
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "pageup" | "pgup" => KeyCode::PageUp,
+                "pagedown" | "pgdn" => KeyCode::PageDown,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "insert" => KeyCode::Insert,
+                "delete" => KeyCode::Delete,
+                f if f.starts_with('f') => f[1..]
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=12).contains(n))
+                    .map(KeyCode::F)
+                    .ok_or_else(|| format!("unknown key {:?}", s))?,
+                _ => return Err(format!("unknown key {:?}", s)),
+            },
+        };
+        // A shifted character is written as the character itself.
+        if matches!(code, KeyCode::Char(_)) && modifiers.contains(KeyModifiers::SHIFT) {
+            return Err(format!("write {:?} without shift-", s));
+        }
+        Ok(Key { code, modifiers })
+    }
+}
+
+/// The keys bound to each binding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: HashMap<Key, Binding>,
+}
+
+impl Keymap {
+    /// Returns what a key press does, if it is bound.
+    pub fn binding(&self, event: &KeyEvent) -> Option<Binding> {
+        self.bindings.get(&Key::of(event)).copied()
+    }
+
+    /// Binds the keys of `binding=key` pairs separated by commas, replacing
+    /// the keys the bindings listed had.
+    pub fn rebind(mut self, pairs: &str) -> Result<Self, String> {
+        let mut overrides: Vec<(Key, Binding)> = Vec::new();
+        for pair in pairs
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (binding, key) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected binding=key, got {:?}", pair))?;
+            overrides.push((key.parse()?, binding.trim().parse()?));
+        }
+        self.bindings
+            .retain(|_, binding| !overrides.iter().any(|(_, bound)| bound == binding));
+        self.bindings.extend(overrides);
+        Ok(self)
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = BINDINGS
+            .iter()
+            .flat_map(|(binding, _, keys)| {
+                keys.iter()
+                    .map(move |key| (key.parse().expect("default keys parse"), *binding))
+            })
+            .collect();
+        Keymap { bindings }
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    /// Parses `binding=key` pairs separated by commas, replacing the
+    /// default keys of the bindings listed.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Keymap::default().rebind(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    use super::{Binding, Key, Keymap};
+
+    #[test]
+    fn test_parse_keys() {
+        let key = |code, modifiers| Key { code, modifiers };
+        assert_eq!(
+            "ctrl-c".parse(),
+            Ok(key(KeyCode::Char('c'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            "Alt-Ctrl-PgUp".parse(),
+            Ok(key(
+                KeyCode::PageUp,
+                KeyModifiers::ALT | KeyModifiers::CONTROL
+            ))
+        );
+        assert_eq!("f5".parse(), Ok(key(KeyCode::F(5), KeyModifiers::NONE)));
+        assert_eq!("?".parse(), Ok(key(KeyCode::Char('?'), KeyModifiers::NONE)));
+        assert!("f13".parse::<Key>().is_err());
+        assert!("hyper-x".parse::<Key>().is_err());
+        assert!("shift-a".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn test_keymap() {
+        let press = |code, modifiers| KeyEvent::new(code, modifiers);
+        let keymap = Keymap::default();
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Some(Binding::Quit)
+        );
+        // Caps lock does not change control keys.
+        assert_eq!(
+            keymap.binding(&press(
+                KeyCode::Char('C'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            )),
+            Some(Binding::Quit)
+        );
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Char('c'), KeyModifiers::NONE)),
+            None
+        );
+
+        let keymap: Keymap = "next=ctrl-n, next=alt-l,quit=ctrl-q".parse().unwrap();
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Char('n'), KeyModifiers::CONTROL)),
+            Some(Binding::Next)
+        );
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Char('l'), KeyModifiers::ALT)),
+            Some(Binding::Next)
+        );
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Right, KeyModifiers::ALT)),
+            None
+        );
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            None
+        );
+        assert_eq!(
+            keymap.binding(&press(KeyCode::Left, KeyModifiers::ALT)),
+            Some(Binding::Previous)
+        );
+        assert!("next".parse::<Keymap>().is_err());
+        assert!("jump=ctrl-j".parse::<Keymap>().is_err());
+        assert_eq!(Binding::ScrollUp.to_string(), "scroll-up");
+    }
+}
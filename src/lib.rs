@@ -0,0 +1,127 @@
+/*!
+ * Library of the messaging application.
+ *
+ * The messaging stack is a library so other programs can embed it without
+ * the chat UI: a `Node` runs the swarm and the application state from a
+ * `Config`, and is driven over its event channel. The `sec_msg` binary is
+ * a thin command line front end to it, adding the terminal UI, stdin and
+ * the `keygen`, `config` and `identity` subcommands.
+ */
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod app;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backup;
+#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+pub mod chaos;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cli;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod command;
+pub mod compression;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+pub mod contacts;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod content_filter;
+pub mod dedup;
+pub mod delivery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dirs;
+pub mod discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod email;
+pub mod emoji;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod event;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod export;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod feed;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+pub mod ffi;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod grpc;
+pub mod history;
+pub mod hooks;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod http;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ignore;
+pub mod invite;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ipc;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod keys;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod logfile;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod maintenance;
+pub mod markdown;
+pub mod middleware;
+pub mod moderation;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mqtt;
+pub mod nat;
+pub mod network;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod node;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod nostr;
+pub mod note;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod notify;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod outgoing;
+pub mod peers;
+pub mod presence;
+pub mod profile;
+pub mod protocol;
+pub mod rate_limit;
+pub mod reaction;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod record;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod reload;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod render;
+pub mod reputation;
+pub mod security;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod shutdown;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod simulate;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod supervisor;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod systemd;
+#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod theme;
+pub mod topic;
+pub mod transfer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ui;
+pub mod utils;
+pub mod validate;
+pub mod version;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod webhook;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use app::{AppEvent, AppEvents};
+#[cfg(not(target_arch = "wasm32"))]
+pub use client::{Client, ClientError, Clients, Message};
+#[cfg(not(target_arch = "wasm32"))]
+pub use config::Config;
+#[cfg(not(target_arch = "wasm32"))]
+pub use node::Node;
+pub use protocol::{Envelope, Payload, Protocols, TextMessage};
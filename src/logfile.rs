@@ -0,0 +1,214 @@
+/*!
+ * Log file module for the messaging application.
+ *
+ * Besides stderr or the terminal UI, logs can be written to a file in the
+ * data directory, so problems can be looked into after the fact. The file
+ * is rotated once it reaches a size or a new hour or day begins: it is
+ * renamed with the suffix `.1`, older files moving up by one, and the
+ * files beyond the number kept are deleted.
+ */
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Local};
+
+/// When the log file is rotated, besides when it grows too large.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    /// Only when it grows too large.
+    Never,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            "never" => Ok(Rotation::Never),
+            _ => Err(format!("Unknown rotation: {}", s)),
+        }
+    }
+}
+
+impl Rotation {
+    /// Returns the period a time falls in, which changes when the file is
+    /// due to be rotated.
+    fn period(&self, time: DateTime<Local>) -> String {
+        match self {
+            Rotation::Hourly => time.format("%Y-%m-%d %H").to_string(),
+            Rotation::Daily => time.format("%Y-%m-%d").to_string(),
+            Rotation::Never => String::new(),
+        }
+    }
+}
+
+/// Where logs are written and when they are rotated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Size in bytes from which the file is rotated.
+    pub max_size: u64,
+    pub rotation: Rotation,
+    /// Number of rotated files kept.
+    pub keep: usize,
+}
+
+/// A log file rotated as it grows and ages.
+pub struct RollingFile {
+    settings: LogFile,
+    file: File,
+    size: u64,
+    /// The period the lines of the file were written in.
+    period: String,
+}
+
+impl RollingFile {
+    /// Opens the log file, appending to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - Where logs are written and when they are rotated.
+    pub fn open(settings: LogFile) -> io::Result<Self> {
+        if let Some(dir) = settings
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir)?;
+        }
+        let file = append(&settings.path)?;
+        let metadata = file.metadata()?;
+        // A file left by an earlier run belongs to the period it was last
+        // written in.
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(RollingFile {
+            period: settings.rotation.period(modified),
+            size: metadata.len(),
+            settings,
+            file,
+        })
+    }
+
+    /// Appends a line, rotating the file first if it is due.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.write_line_at(line, Local::now())
+    }
+
+    fn write_line_at(&mut self, line: &str, now: DateTime<Local>) -> io::Result<()> {
+        let period = self.settings.rotation.period(now);
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && (period != self.period || self.size + len > self.settings.max_size) {
+            self.rotate()?;
+        }
+        self.period = period;
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Moves the file to the first rotated one, deleting the oldest.
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.settings.path;
+        let keep = self.settings.keep;
+        if keep == 0 {
+            remove(path)?;
+        } else {
+            remove(&rotated(path, keep))?;
+            for index in (1..keep).rev() {
+                let from = rotated(path, index);
+                if from.exists() {
+                    fs::rename(from, rotated(path, index + 1))?;
+                }
+            }
+            fs::rename(path, rotated(path, 1))?;
+        }
+        self.file = append(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Opens a file for appending, readable by the current user only.
+fn append(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Returns the path of a rotated file.
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Deletes a file, if it exists.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{Duration, Local};
+
+    use super::{rotated, LogFile, RollingFile, Rotation};
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-log-{}", std::process::id()));
+        let path = dir.join("sec_msg.log");
+        let mut file = RollingFile::open(LogFile {
+            path: path.clone(),
+            max_size: 20,
+            rotation: Rotation::Daily,
+            keep: 2,
+        })
+        .unwrap();
+        let now = Local::now();
+
+        file.write_line_at("first line", now).unwrap();
+        file.write_line_at("second", now).unwrap();
+        // Growing beyond the size rotates.
+        file.write_line_at("third line", now).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third line\n");
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 1)).unwrap(),
+            "first line\nsecond\n"
+        );
+        // So does a new day, the oldest files beyond those kept going.
+        file.write_line_at("tomorrow", now + Duration::days(1))
+            .unwrap();
+        file.write_line_at("next week", now + Duration::days(7))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "next week\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 1)).unwrap(), "tomorrow\n");
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 2)).unwrap(),
+            "third line\n"
+        );
+        assert!(!rotated(&path, 3).exists());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!("hourly".parse(), Ok(Rotation::Hourly));
+        assert!("weekly".parse::<Rotation>().is_err());
+    }
+}
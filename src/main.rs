@@ -1,64 +1,305 @@
 /*!
  * Main entry point for the messaging application.
  *
- * This module sets up the configuration, initializes the logger,
- * and starts the main event loop to handle user input and network events.
+ * This module parses the command line, sets up the configuration,
+ * initializes the logger, and runs a node with the terminal UI or stdin
+ * in front of it.
  */
 
-mod config;
-mod event;
-mod network;
-mod protocol;
-mod security;
-mod ui;
-mod utils;
-
-use config::Config;
-use futures::StreamExt;
-use log::error;
-use network::{create_swarm, listen_on};
-use tokio::io::AsyncBufReadExt;
-use ui::handle_user_input;
+use clap::Parser;
+use log::info;
+use sec_msg::cli::{Cli, ConfigCommand, Mode, Options};
+use sec_msg::maintenance::{self, MAINTENANCE_INTERVAL};
+use sec_msg::record::Recorder;
+use sec_msg::reload::{Hangup, Reloader};
+use sec_msg::render::Output;
+use sec_msg::shutdown::Termination;
+use sec_msg::theme::Theme;
+use sec_msg::ui::{self, AppLogger, Interface, UiEvent};
+use sec_msg::{
+    backup, config, dirs, email, feed, grpc, http, ipc, logfile, mqtt, nostr, record, security,
+    simulate, storage, systemd, utils, webhook, AppEvent, Clients, Config, Node,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config::new();
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.log_level))
-        .init();
-
-    let (local_key, local_peer_id) = utils::generate_keypair();
-
-    let topic = "chat";
-
-    let mut swarm = create_swarm(local_key.clone(), local_peer_id, topic).await?;
-
-    listen_on(&mut swarm)?;
+    let cli = Cli::parse();
+    if let Some(Mode::Config(ConfigCommand::Init { force })) = cli.command {
+        let path = config::init(&cli.options, force)?;
+        println!("Wrote {}", path.display());
+        return Ok(());
+    }
+    let mut config = match Config::new(&cli.options) {
+        Ok(config) => config,
+        // Listed one problem per line rather than as a debug dump.
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Some(Mode::Identity(command)) = &cli.command {
+        return backup::run(command, &config);
+    }
+    if let Some(Mode::Replay { path, fast }) = &cli.command {
+        AppLogger::init(&config.log_level, None, None)?;
+        let replayed = record::replay(path, &config, *fast).await?;
+        info!("Replayed {} events", replayed);
+        return Ok(());
+    }
+    if let Some(Mode::Simulate {
+        peers,
+        links,
+        rate,
+        duration,
+    }) = cli.command
+    {
+        // The peers would drown the report in their logs.
+        AppLogger::init(
+            cli.options.log_level.as_deref().unwrap_or("warn"),
+            None,
+            None,
+        )?;
+        let duration = Duration::from_secs(duration);
+        let report = simulate::run(&config, peers.into(), links.into(), rate, duration).await?;
+        match config.output {
+            Output::Json => println!("{}", serde_json::to_string(&report)?),
+            Output::Text => println!("{}", report),
+        }
+        return Ok(());
+    }
+    let socket_path = |socket: &Option<PathBuf>| {
+        socket
+            .clone()
+            .or_else(|| config.dirs.as_ref().map(dirs::Dirs::socket))
+            .ok_or("No data directory, pass --socket")
+    };
+    if let Some(Mode::Ctl {
+        socket,
+        identity,
+        command,
+    }) = &cli.command
+    {
+        let json = config.output == Output::Json;
+        return ipc::ctl(&socket_path(socket)?, identity.as_deref(), command, json).await;
+    }
+    let (socket, grpc, http, identities, mirrors, bridge, feeds, webhooks) = match &cli.command {
+        Some(Mode::Daemon {
+            socket,
+            grpc,
+            http,
+            identities,
+            mirrors,
+            mqtt,
+            mqtt_routes,
+            mqtt_qos,
+            feeds,
+            feed_interval,
+            webhooks,
+        }) => (
+            Some(socket_path(socket)?),
+            *grpc,
+            *http,
+            identities.clone(),
+            mirrors.clone(),
+            mqtt.clone()
+                .map(|broker| (broker, mqtt_routes.clone(), *mqtt_qos)),
+            (!feeds.is_empty()).then(|| (feeds.clone(), *feed_interval)),
+            webhooks.clone(),
+        ),
+        _ => (
+            None,
+            None,
+            None,
+            Vec::new(),
+            Vec::new(),
+            None,
+            None,
+            Vec::new(),
+        ),
+    };
+    if let Some(Mode::Keygen { path, force }) = cli.command {
+        let path = path
+            .or(config.identity)
+            .or_else(|| config.dirs.as_ref().map(dirs::Dirs::identity))
+            .ok_or("No data directory, pass a path")?;
+        let vault = match config.encrypt_storage {
+            true => Some(storage::Vault::new(storage::passphrase(
+                "Passphrase: ",
+                true,
+            )?)),
+            false => None,
+        };
+        let peer_id = utils::create_keypair(&path, force, vault.as_ref())?;
+        println!("Wrote {}", path.display());
+        println!("Peer ID:     {}", peer_id);
+        println!("Fingerprint: {}", security::fingerprint(&peer_id));
+        return Ok(());
+    }
+    // A relay or a daemon has no one at the keyboard.
+    let headless = cli.command == Some(Mode::Relay) || socket.is_some();
+    if headless {
+        if config.pipe_topic.is_some() {
+            return Err("--stdin-pipe cannot be used with relay or daemon".into());
+        }
+        config.interface = Interface::Plain;
+    }
+    let (ui_events, ui_rx) = tokio::sync::mpsc::unbounded_channel();
+    let tui = (config.interface == Interface::Tui).then(|| ui_events.clone());
+    let log_file = match config.log_file.clone() {
+        Some(settings) => {
+            let path = settings.path.clone();
+            Some(
+                logfile::RollingFile::open(settings)
+                    .map_err(|e| format!("Failed to open log file {:?}: {}", path, e))?,
+            )
+        }
+        None => None,
+    };
+    let logger = AppLogger::init(&config.log_level, tui.clone(), log_file)?;
 
-    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    // Asked before the terminal UI takes over the screen.
+    let vault = match config.encrypt_storage {
+        true => Some(Arc::new(storage::Vault::new(storage::passphrase(
+            "Passphrase: ",
+            false,
+        )?))),
+        false => None,
+    };
+    let (local_key, local_peer_id) = match &config.identity {
+        Some(path) => utils::load_keypair(path, vault.as_deref())?,
+        None => utils::generate_keypair(),
+    };
+    let mut mirror_keys = Vec::new();
+    for (topic, _) in &mirrors {
+        let domain = format!("{}{}", nostr::KEY_DOMAIN, topic);
+        mirror_keys.push(
+            local_key
+                .derive_secret(domain.as_bytes())
+                .ok_or("The identity cannot derive Nostr keys")?,
+        );
+    }
 
-    loop {
-        tokio::select! {
-            line = stdin.next_line() => {
-                match line {
-                    Ok(Some(line)) => {
-                        handle_user_input(line, &mut swarm, topic).await;
-                    }
-                    Ok(None) => {
-                        error!("stdin closed");
-                        break;
-                    }
-                    Err(e) => {
-                        error!("Error reading stdin: {:?}", e);
-                        break;
-                    }
-                }
-            }
-            event = swarm.next() => match event {
-                Some(event) => event::handle_event(event, &mut swarm).await,
-                None => error!("Swarm stream closed"),
+    let mut node = Node::new(&config, local_key, vault, tui.clone()).await?;
+    if let Some(path) = &cli.options.record {
+        node.state.recorder = Some(Recorder::create(path, local_peer_id)?);
+    }
+    node.state.reloader = Some(Reloader::new(cli.options, &config, logger, tui));
+    if let Some(settings) = config.email.clone() {
+        node.state.email = Some(email::start(settings, node.events(), &local_peer_id)?);
+    }
+    let app_events = node.events();
+    let mut others = Vec::new();
+    if let Some(path) = &socket {
+        let mut clients = Clients::new(node.client());
+        for (name, home) in &identities {
+            let other = start_identity(name, home).await?;
+            if !clients.insert(name, other.client()) {
+                return Err(format!("The identity {} is given twice", name).into());
             }
+            maintenance::schedule(other.events(), MAINTENANCE_INTERVAL);
+            others.push((other.events(), tokio::spawn(other.run())));
+        }
+        ipc::serve(path, clients)?;
+        let subscribers = node.subscribers();
+        if let Some(addr) = grpc {
+            grpc::serve(addr, app_events.clone(), subscribers.clone()).await?;
+        }
+        if let Some(addr) = http {
+            http::serve(addr, app_events.clone(), subscribers).await?;
+        }
+        for ((topic, relay), secret) in mirrors.into_iter().zip(mirror_keys) {
+            nostr::mirror(node.client(), topic, relay, secret)?;
+        }
+        if let Some((broker, routes, qos)) = bridge {
+            mqtt::bridge(node.client(), &broker, routes, qos, &local_peer_id)?;
+        }
+        if let Some((feeds, interval)) = feeds {
+            feed::watch(node.client(), feeds, Duration::from_secs(interval))?;
+        }
+        if !webhooks.is_empty() {
+            webhook::forward(node.client(), webhooks)?;
         }
     }
 
+    let ui_task = match config.interface {
+        Interface::Tui => Some(tokio::spawn(ui::run_tui(
+            ui_rx,
+            app_events.clone(),
+            node.input(),
+            node.state.notifier.focus(),
+            config.keymap.clone(),
+            Theme::new(config.theme),
+        ))),
+        Interface::Plain if socket.is_some() => {
+            info!("Running as {}", local_peer_id);
+            None
+        }
+        Interface::Plain if headless => {
+            info!("Relaying as {}", local_peer_id);
+            None
+        }
+        Interface::Plain => {
+            tokio::spawn(ui::read_stdin(app_events.clone(), node.input()));
+            None
+        }
+    };
+    maintenance::schedule(app_events.clone(), MAINTENANCE_INTERVAL);
+    Hangup::new()?.forward(app_events.clone());
+    Termination::new()?.forward(app_events);
+    systemd::notify(&format!("READY=1\nSTATUS=Running as {}", local_peer_id));
+
+    let stopped_by = node.run().await;
+    for (events, task) in others {
+        let _ = events.send(AppEvent::InputClosed);
+        let _ = task.await;
+    }
+    // A socket from systemd is left for the next activation.
+    if let Some(path) = socket.as_ref().filter(|_| !systemd::socket_activated()) {
+        let _ = std::fs::remove_file(path);
+    }
+
+    if let Some(task) = ui_task {
+        // The UI may already be gone if the user quit from it.
+        let _ = ui_events.send(UiEvent::Quit);
+        task.await??;
+    }
+    if let Some(signal) = stopped_by {
+        std::process::exit(signal.exit_code());
+    }
     Ok(())
 }
+
+/// Creates the node of an identity run by the daemon besides the default
+/// one, from the directory it is kept in.
+///
+/// # Arguments
+///
+/// * `name` - The name the identity is addressed by.
+/// * `home` - The directory of the identity, read as `--home` would be.
+async fn start_identity(name: &str, home: &Path) -> Result<Node, Box<dyn std::error::Error>> {
+    let options = Options {
+        home: Some(home.to_path_buf()),
+        ..Options::default()
+    };
+    let config = Config::new(&options).map_err(|e| format!("Invalid identity {}: {}", name, e))?;
+    let vault = match config.encrypt_storage {
+        true => Some(Arc::new(storage::Vault::new(storage::passphrase(
+            &format!("Passphrase of {}: ", name),
+            false,
+        )?))),
+        false => None,
+    };
+    let (local_key, local_peer_id) = match &config.identity {
+        Some(path) => utils::load_keypair(path, vault.as_deref())?,
+        None => utils::generate_keypair(),
+    };
+    info!("Running {} as {}", name, local_peer_id);
+    let mut node = Node::new(&config, local_key, vault, None).await?;
+    if let Some(settings) = config.email.clone() {
+        node.state.email = Some(email::start(settings, node.events(), &local_peer_id)?);
+    }
+    Ok(node)
+}
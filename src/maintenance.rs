@@ -0,0 +1,97 @@
+/*!
+ * Maintenance module for the messaging application.
+ *
+ * The caches of a node expire their entries as it runs, once a second, but
+ * a node running for weeks needs more upkeep than that. Every few minutes a
+ * timer asks the swarm loop for a maintenance pass, which releases the
+ * memory the duplicate filter and the rate limiter grew to during bursts,
+ * forgets the names of peers no longer remembered, refreshes the buckets of
+ * the DHT so topic discovery still finds peers once the network changed,
+ * and saves the history if messages were stored since it was last saved,
+ * so a node that crashes loses a few minutes of history at most.
+ */
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use libp2p::Swarm;
+use log::{debug, error};
+use tokio::time::{interval_at, Instant};
+
+use crate::app::{AppEvent, AppEvents};
+use crate::protocol::Protocols;
+use crate::state::AppState;
+use crate::storage::Vault;
+
+/// How often the swarm loop is maintained.
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Asks the swarm loop for a maintenance pass on every interval, until the
+/// loop is gone.
+///
+/// # Arguments
+///
+/// * `events` - The channel to the swarm loop.
+/// * `period` - The time between two passes.
+pub fn schedule(events: AppEvents, period: Duration) {
+    tokio::spawn(async move {
+        let mut interval = interval_at(Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if events.send(AppEvent::Maintain).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Does the upkeep of a node.
+///
+/// # Arguments
+///
+/// * `swarm` - The swarm of the node.
+/// * `state` - The application state.
+/// * `history_file` - Where the history is saved, if it is kept between runs.
+pub fn run(
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+    history_file: Option<&(PathBuf, Arc<Vault>)>,
+) {
+    state.middleware.compact();
+    let peers = &state.peers;
+    state.profiles.retain(|peer| peers.seen(peer).is_some());
+
+    // Refreshing needs a peer to start the lookups from.
+    if swarm.connected_peers().next().is_some() {
+        if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
+            debug!("Failed to refresh the DHT: {:?}", e);
+        }
+    }
+
+    if let Some((path, vault)) = history_file.filter(|_| state.history.is_dirty()) {
+        match state.history.save(path, vault) {
+            Ok(()) => debug!("Saved history to {:?}", path),
+            Err(e) => error!("Failed to save history to {:?}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::schedule;
+    use crate::app::AppEvent;
+
+    #[tokio::test]
+    async fn test_schedule() {
+        let (events, mut maintained) = mpsc::unbounded_channel();
+        schedule(events, Duration::from_millis(10));
+        for _ in 0..2 {
+            assert!(matches!(maintained.recv().await, Some(AppEvent::Maintain)));
+        }
+        // The timer stops with the loop.
+        drop(maintained);
+    }
+}
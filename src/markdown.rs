@@ -0,0 +1,254 @@
+/*!
+ * Markdown module for the messaging application.
+ *
+ * Other clients send messages formatted with Markdown. This module parses
+ * the small subset the terminal UI shows: bold, italics and inline code
+ * within a line, fenced code blocks, and bulleted and numbered lists.
+ * Anything else, links and HTML included, stays plain text, so a message
+ * can never do more than change how its own text looks.
+ */
+
+/// How a piece of text is emphasized.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Emphasis {
+    pub bold: bool,
+    pub italic: bool,
+    pub code: bool,
+}
+
+/// A run of text with the same emphasis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub emphasis: Emphasis,
+}
+
+/// What a line of a message is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineKind {
+    Text,
+    /// A line of a fenced code block, shown as is.
+    Code,
+    /// A list item, with its bullet or number.
+    Item(String),
+}
+
+/// A parsed line of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkdownLine {
+    pub kind: LineKind,
+    pub spans: Vec<Span>,
+}
+
+/// Parses the lines of a message.
+///
+/// # Arguments
+///
+/// * `text` - The sanitized message body.
+pub fn parse(text: &str) -> Vec<MarkdownLine> {
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(MarkdownLine {
+                kind: LineKind::Code,
+                spans: vec![Span {
+                    text: line.to_string(),
+                    emphasis: Emphasis {
+                        code: true,
+                        ..Emphasis::default()
+                    },
+                }],
+            });
+            continue;
+        }
+        let (kind, rest) = match list_item(line) {
+            Some((marker, rest)) => (LineKind::Item(marker), rest),
+            None => (LineKind::Text, line),
+        };
+        let mut spans = Vec::new();
+        inline(rest, Emphasis::default(), &mut spans);
+        lines.push(MarkdownLine { kind, spans });
+    }
+    lines
+}
+
+/// Splits a list item into its marker and its text.
+fn list_item(line: &str) -> Option<(String, &str)> {
+    let trimmed = line.trim_start();
+    if let Some(rest) = ["- ", "* ", "+ "]
+        .iter()
+        .find_map(|bullet| trimmed.strip_prefix(bullet))
+    {
+        return Some(("•".to_string(), rest));
+    }
+    let digits = trimmed.len()
+        - trimmed
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .len();
+    if digits == 0 || digits > 9 {
+        return None;
+    }
+    let rest = trimmed[digits..].strip_prefix(". ")?;
+    Some((format!("{}.", &trimmed[..digits]), rest))
+}
+
+/// Parses the emphasis of a line, appending its spans.
+fn inline(text: &str, emphasis: Emphasis, spans: &mut Vec<Span>) {
+    let mut plain = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let delimited = match c {
+            '`' => delimited(rest, "`").map(|(inner, after)| {
+                let code = Emphasis {
+                    code: true,
+                    ..emphasis
+                };
+                (inner, code, after)
+            }),
+            '*' | '_' if !follows_word(text, rest) => {
+                let double = if c == '*' { "**" } else { "__" };
+                if rest.starts_with(double) && !emphasis.bold {
+                    delimited(rest, double).map(|(inner, after)| {
+                        let bold = Emphasis {
+                            bold: true,
+                            ..emphasis
+                        };
+                        (inner, bold, after)
+                    })
+                } else if !emphasis.italic {
+                    delimited(rest, &double[..1]).map(|(inner, after)| {
+                        let italic = Emphasis {
+                            italic: true,
+                            ..emphasis
+                        };
+                        (inner, italic, after)
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        match delimited {
+            Some((inner, inner_emphasis, after)) => {
+                push(spans, std::mem::take(&mut plain), emphasis);
+                // Code is shown as is, other emphasis may nest.
+                if inner_emphasis.code {
+                    push(spans, inner.to_string(), inner_emphasis);
+                } else {
+                    inline(inner, inner_emphasis, spans);
+                }
+                rest = after;
+            }
+            None => {
+                plain.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    push(spans, plain, emphasis);
+}
+
+/// Splits text starting with a delimiter into what it encloses and what
+/// follows the closing delimiter.
+///
+/// Enclosed text must not be empty nor start or end with whitespace, so
+/// stray asterisks stay as they are.
+fn delimited<'a>(text: &'a str, delimiter: &str) -> Option<(&'a str, &'a str)> {
+    let after = &text[delimiter.len()..];
+    let end = after.find(delimiter)?;
+    let inner = &after[..end];
+    if inner.is_empty()
+        || inner.starts_with(char::is_whitespace)
+        || inner.ends_with(char::is_whitespace)
+    {
+        return None;
+    }
+    Some((inner, &after[end + delimiter.len()..]))
+}
+
+/// Returns whether `rest`, a suffix of `text`, directly follows a letter or
+/// digit, as the underscores of `snake_case` do.
+fn follows_word(text: &str, rest: &str) -> bool {
+    text[..text.len() - rest.len()]
+        .chars()
+        .next_back()
+        .is_some_and(char::is_alphanumeric)
+}
+
+/// Appends text to the spans, merging it with the last span if it has the
+/// same emphasis.
+fn push(spans: &mut Vec<Span>, text: String, emphasis: Emphasis) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.emphasis == emphasis => last.text.push_str(&text),
+        _ => spans.push(Span { text, emphasis }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Emphasis, LineKind, MarkdownLine, Span};
+
+    fn span(text: &str, bold: bool, italic: bool, code: bool) -> Span {
+        Span {
+            text: text.to_string(),
+            emphasis: Emphasis { bold, italic, code },
+        }
+    }
+
+    #[test]
+    fn test_inline() {
+        let spans = |text| parse(text).remove(0).spans;
+        assert_eq!(spans("plain"), vec![span("plain", false, false, false)]);
+        assert_eq!(
+            spans("a **bold _and italic_** `co*de*`"),
+            vec![
+                span("a ", false, false, false),
+                span("bold ", true, false, false),
+                span("and italic", true, true, false),
+                span(" ", false, false, false),
+                span("co*de*", false, false, true),
+            ]
+        );
+        assert_eq!(
+            spans("snake_case_name and 2 * 3 * 4"),
+            vec![span("snake_case_name and 2 * 3 * 4", false, false, false)]
+        );
+        assert_eq!(
+            spans("**unclosed"),
+            vec![span("**unclosed", false, false, false)]
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let lines = parse("steps:\n- one\n2. *two*\n```\nlet x = **y**;\n```\ndone");
+        let kinds: Vec<&LineKind> = lines.iter().map(|line| &line.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                &LineKind::Text,
+                &LineKind::Item("•".to_string()),
+                &LineKind::Item("2.".to_string()),
+                &LineKind::Code,
+                &LineKind::Text,
+            ]
+        );
+        assert_eq!(lines[2].spans, vec![span("two", false, true, false)]);
+        assert_eq!(
+            lines[3],
+            MarkdownLine {
+                kind: LineKind::Code,
+                spans: vec![span("let x = **y**;", false, false, true)],
+            }
+        );
+    }
+}
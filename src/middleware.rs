@@ -0,0 +1,438 @@
+/*!
+ * Middleware module for the messaging application.
+ *
+ * Every envelope goes through a chain of middleware layers on its way to
+ * and from the network, which is where what used to be special cases of
+ * the publish and receive paths now lives: metrics, deduplication, rate
+ * limiting, compression and padding are all layers, and an encryption
+ * layer would be one more. A layer implements the `Middleware` trait and
+ * is registered with a priority, like a hook.
+ *
+ * A layer can take part in four stages, each optional:
+ *
+ * - `filter` sees the raw message before it is published or decoded, and
+ *   may drop it, which is cheap enough to run before any signature check.
+ * - `seal` transforms the body of an outbound envelope before it is signed,
+ *   in priority order, and `open` restores an inbound body once the
+ *   signature was verified, in reverse order, so layers nest.
+ * - `observe` is told what happened to every message, so a layer can keep
+ *   counters without filtering anything itself.
+ *
+ * Unlike hooks, layers see bytes rather than payloads, and may rewrite the
+ * body as long as peers without the layer can still decode it or the
+ * envelope header records what was done.
+ */
+
+use std::{any::Any, error::Error};
+
+use libp2p::{identity, PeerId};
+use log::debug;
+
+use crate::compression::{self, Compression};
+use crate::dedup::DedupCache;
+use crate::hooks::{Direction, Verdict};
+use crate::protocol::{Body, Envelope, EnvelopeError, Payload, MAX_PAYLOAD_SIZE};
+use crate::rate_limit::RateLimiter;
+use crate::reputation::Reputation;
+use crate::stats::Stats;
+
+/// What a layer is told about a message.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    pub direction: Direction,
+    pub topic: &'a str,
+    /// The peer an inbound message came from, or the local peer.
+    pub peer: PeerId,
+}
+
+/// What became of a message once every filter saw it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome<'a> {
+    /// The message went on to be published or decoded.
+    Passed,
+    /// The layer of that name dropped the message.
+    Dropped(&'a str),
+}
+
+/// A layer of the pipeline envelopes go through.
+pub trait Middleware: Any + Send {
+    /// Returns the name the layer is logged and counted under.
+    fn name(&self) -> &str;
+
+    /// Decides whether a raw message goes on. Only drops count here, flags
+    /// and quarantines being for hooks.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the message comes from or goes to.
+    /// * `data` - The encoded envelope.
+    fn filter(&mut self, _context: &Context, _data: &[u8]) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Transforms the body of an envelope before it is signed.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the envelope goes to.
+    /// * `body` - The body, changed by the layer.
+    fn seal(&mut self, _context: &Context, _body: &mut Body) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Undoes `seal` on the body of an envelope whose signature holds.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the envelope comes from, the peer being the signer.
+    /// * `body` - The body, changed by the layer.
+    fn open(&mut self, _context: &Context, _body: &mut Body) -> Result<(), EnvelopeError> {
+        Ok(())
+    }
+
+    /// Learns what became of a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the message comes from or goes to.
+    /// * `data` - The encoded envelope.
+    /// * `outcome` - Whether the message passed the filters.
+    fn observe(&mut self, _context: &Context, _data: &[u8], _outcome: Outcome) {}
+
+    /// Does periodic upkeep, once a second.
+    fn tick(&mut self) {}
+
+    /// Releases the memory left over by bursts of messages, every few
+    /// minutes.
+    fn compact(&mut self) {}
+}
+
+/// A registered layer.
+struct Entry {
+    priority: i32,
+    layer: Box<dyn Middleware>,
+}
+
+/// The registered layers, in the order they seal.
+#[derive(Default)]
+pub struct Pipeline {
+    entries: Vec<Entry>,
+}
+
+impl Pipeline {
+    /// Creates a pipeline without any layer.
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Creates the pipeline every node runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate_limiter` - The limits on inbound messages.
+    pub fn standard(rate_limiter: RateLimiter) -> Self {
+        let mut pipeline = Pipeline::new();
+        pipeline.register(0, Box::new(Stats::new()));
+        pipeline.register(10, Box::new(DedupCache::new()));
+        pipeline.register(15, Box::new(Reputation::new()));
+        pipeline.register(20, Box::new(rate_limiter));
+        pipeline.register(100, Box::new(Compressor));
+        pipeline
+    }
+
+    /// Registers a layer.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - Where the layer sits, lower priorities filtering and
+    ///   sealing first and opening last.
+    /// * `layer` - The layer.
+    pub fn register(&mut self, priority: i32, layer: Box<dyn Middleware>) {
+        debug!(
+            "Registered middleware {} at priority {}",
+            layer.name(),
+            priority
+        );
+        let index = self
+            .entries
+            .partition_point(|entry| entry.priority <= priority);
+        self.entries.insert(index, Entry { priority, layer });
+    }
+
+    /// Returns the first layer of a type, to read or configure it.
+    pub fn get<T: Middleware>(&self) -> Option<&T> {
+        self.entries
+            .iter()
+            .find_map(|entry| (entry.layer.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    /// Returns the first layer of a type, to change it.
+    pub fn get_mut<T: Middleware>(&mut self) -> Option<&mut T> {
+        self.entries
+            .iter_mut()
+            .find_map(|entry| (entry.layer.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Runs the filters on a message, then tells every layer the outcome.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Where the message comes from or goes to.
+    /// * `data` - The encoded envelope.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the message goes on, `false` if a layer dropped it.
+    pub fn filter(&mut self, context: &Context, data: &[u8]) -> bool {
+        let mut dropped_by = None;
+        for entry in &mut self.entries {
+            if let Verdict::Drop(reason) = entry.layer.filter(context, data) {
+                debug!(
+                    "Middleware {} dropped an {} message from {:?} on {:?}: {}",
+                    entry.layer.name(),
+                    context.direction,
+                    context.peer,
+                    context.topic,
+                    reason
+                );
+                dropped_by = Some(entry.layer.name().to_string());
+                break;
+            }
+        }
+        let outcome = match &dropped_by {
+            Some(name) => Outcome::Dropped(name),
+            None => Outcome::Passed,
+        };
+        for entry in &mut self.entries {
+            entry.layer.observe(context, data, outcome);
+        }
+        dropped_by.is_none()
+    }
+
+    /// Wraps a payload into a signed envelope, sealing its body through
+    /// every layer.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope is published to.
+    /// * `payload` - The payload to wrap.
+    /// * `sender` - The display name of the local user, if any.
+    /// * `local_key` - The local identity keypair.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signed `Envelope` or an error.
+    pub fn seal(
+        &mut self,
+        topic: &str,
+        payload: &Payload,
+        sender: Option<String>,
+        local_key: &identity::Keypair,
+    ) -> Result<Envelope, Box<dyn Error>> {
+        let context = Context {
+            direction: Direction::Outbound,
+            topic,
+            peer: local_key.public().to_peer_id(),
+        };
+        let mut body = payload.body()?;
+        for entry in &mut self.entries {
+            entry
+                .layer
+                .seal(&context, &mut body)
+                .map_err(|e| format!("middleware {} failed: {}", entry.layer.name(), e))?;
+        }
+        Envelope::sign(topic, body, sender, local_key)
+    }
+
+    /// Verifies an envelope, which must have been sealed for the topic it
+    /// was received on, and decodes its payload, opening its body through
+    /// every layer.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope was received on.
+    /// * `envelope` - The envelope.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `PeerId` of the signer and the `Payload`.
+    pub fn open(
+        &mut self,
+        topic: &str,
+        envelope: &Envelope,
+    ) -> Result<(PeerId, Payload), EnvelopeError> {
+        let (signer, mut body) = envelope.verify(topic)?;
+        let context = Context {
+            direction: Direction::Inbound,
+            topic,
+            peer: signer,
+        };
+        for entry in self.entries.iter_mut().rev() {
+            entry.layer.open(&context, &mut body)?;
+        }
+        Ok((signer, body.decode()?))
+    }
+
+    /// Does the periodic upkeep of every layer.
+    pub fn tick(&mut self) {
+        for entry in &mut self.entries {
+            entry.layer.tick();
+        }
+    }
+
+    /// Releases the memory every layer holds beyond what it needs.
+    pub fn compact(&mut self) {
+        for entry in &mut self.entries {
+            entry.layer.compact();
+        }
+    }
+}
+
+/// A layer compressing bodies with LZ4 when that makes them smaller.
+pub struct Compressor;
+
+impl Middleware for Compressor {
+    fn name(&self) -> &str {
+        "compression"
+    }
+
+    fn seal(&mut self, _context: &Context, body: &mut Body) -> Result<(), Box<dyn Error>> {
+        if body.compression == Compression::None {
+            let (compression, data) = compression::compress(std::mem::take(&mut body.data));
+            body.compression = compression;
+            body.data = data;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, _context: &Context, body: &mut Body) -> Result<(), EnvelopeError> {
+        if body.compression != Compression::None {
+            body.data = compression::decompress(body.compression, &body.data, MAX_PAYLOAD_SIZE)?;
+            body.compression = Compression::None;
+        }
+        Ok(())
+    }
+}
+
+/// A layer padding uncompressed bodies to a multiple of a block size, so
+/// short messages do not give their length away.
+///
+/// Payloads are decoded from the start of the body and the zeros after
+/// them are ignored, so peers without the layer read padded envelopes as
+/// they are. Registered after the compressor, it leaves compressed bodies
+/// alone.
+pub struct Padding {
+    block: usize,
+}
+
+impl Padding {
+    /// Creates a new `Padding`.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - The size bodies are padded to a multiple of.
+    pub fn new(block: usize) -> Self {
+        Padding {
+            block: block.max(1),
+        }
+    }
+}
+
+impl Middleware for Padding {
+    fn name(&self) -> &str {
+        "padding"
+    }
+
+    fn seal(&mut self, _context: &Context, body: &mut Body) -> Result<(), Box<dyn Error>> {
+        if body.compression == Compression::None {
+            let padded = body.data.len().div_ceil(self.block) * self.block;
+            body.data.resize(padded.min(MAX_PAYLOAD_SIZE), 0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity, PeerId};
+
+    use super::{Compressor, Context, Middleware, Padding, Pipeline};
+    use crate::delivery::MessageId;
+    use crate::hooks::{Direction, Verdict};
+    use crate::protocol::{Payload, TextMessage};
+    use crate::rate_limit::{Limit, Limits, RateLimiter};
+    use crate::stats::Stats;
+
+    /// A layer dropping messages on one topic.
+    struct Block(&'static str);
+
+    impl Middleware for Block {
+        fn name(&self) -> &str {
+            "block"
+        }
+
+        fn filter(&mut self, context: &Context, _data: &[u8]) -> Verdict {
+            match context.topic == self.0 {
+                true => Verdict::Drop("blocked topic".to_string()),
+                false => Verdict::Continue,
+            }
+        }
+    }
+
+    fn text(body: &str) -> Payload {
+        Payload::Text(TextMessage {
+            id: MessageId::random(),
+            body: body.to_string(),
+            ack_requested: false,
+        })
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let mut pipeline = Pipeline::standard(RateLimiter::new(Limits::new(Limit::new(60, 2)), 8));
+
+        // Padded bodies are read as they are.
+        let mut padded = Pipeline::new();
+        padded.register(100, Box::new(Compressor));
+        padded.register(110, Box::new(Padding::new(256)));
+        let envelope = padded.seal("chat", &text("hi"), None, &keypair).unwrap();
+        assert_eq!(envelope.payload.len(), 256);
+        match envelope.open("chat").unwrap().1 {
+            Payload::Text(text) => assert_eq!(text.body, "hi"),
+            other => panic!("unexpected payload {:?}", other),
+        }
+
+        // Duplicates, rate limited and blocked messages are dropped and counted.
+        pipeline.register(30, Box::new(Block("spam")));
+        let peer = PeerId::random();
+        let inbound = |topic, peer| Context {
+            direction: Direction::Inbound,
+            topic,
+            peer,
+        };
+        assert!(pipeline.filter(&inbound("chat", peer), b"one"));
+        assert!(!pipeline.filter(&inbound("chat", peer), b"one"));
+        assert!(pipeline.filter(&inbound("chat", peer), b"two"));
+        assert!(!pipeline.filter(&inbound("chat", peer), b"three"));
+        assert!(!pipeline.filter(&inbound("spam", PeerId::random()), b"four"));
+        let outbound = Context {
+            direction: Direction::Outbound,
+            topic: "chat",
+            peer: PeerId::random(),
+        };
+        assert!(pipeline.filter(&outbound, b"one"));
+
+        let stats = pipeline.get::<Stats>().unwrap();
+        assert_eq!(stats.bytes_received, 18);
+        assert_eq!(stats.bytes_sent, 3);
+        let drops: Vec<(&str, u64)> = stats.drops().collect();
+        assert_eq!(drops, vec![("block", 1), ("dedup", 1), ("rate-limit", 1)]);
+        let topics: Vec<_> = stats
+            .topics()
+            .map(|(topic, counts)| (topic, counts.sent, counts.received))
+            .collect();
+        assert_eq!(topics, vec![("chat", 1, 2)]);
+        assert!(pipeline.get_mut::<RateLimiter>().is_some());
+    }
+}
@@ -0,0 +1,799 @@
+/*!
+ * Moderation module for the messaging application.
+ *
+ * This module tracks topic founders, moderators, and muted peers, and
+ * validates moderation directives received from peers. Directives are
+ * carried in signed envelopes, so the issuer of a directive is the envelope
+ * signer. The first founder claim seen for a topic is trusted, and a topic
+ * is only founded by such a claim; only the founder may grant moderator
+ * rights, and only moderators may mute or kick.
+ * The founder may hand the founder role over to another peer, staying on as
+ * a moderator. So that a topic outlives a founder who disappears, the
+ * moderators may also add a moderator without the founder by nominating it:
+ * it is added once a majority of the moderators, at least two, nominated it.
+ * Directives arriving ahead of the claim founding their topic are kept
+ * until it arrives.
+ *
+ * The founder may close a topic, making it a private group: messages from
+ * peers other than its moderators and members are dropped. The moderators
+ * are the admins of the group and keep its roster, adding and removing
+ * members directly, and peers also become members by presenting an invite
+ * token issued by an admin in a join directive. Each invite admits the
+ * peer it names once, so a leaked or replayed invite admits no one else. A
+ * kick takes membership away as well.
+ *
+ * The state is written to the data directory on every change, sealed when
+ * storage is encrypted, so muted and kicked peers stay restricted across
+ * restarts. Restrictions expire at a time relative to when the directive
+ * was issued, so replaying a directive from history does not extend them.
+ */
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    invite::{InviteError, InviteToken},
+    storage::{self, Vault},
+};
+
+/// How long a kicked peer stays muted on the topic it was kicked from.
+pub const KICK_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// Fewest nominations adding a moderator without the founder.
+pub const MIN_QUORUM: usize = 2;
+
+/// Most directives kept until the topic they were issued on is founded.
+pub const MAX_DEFERRED: usize = 64;
+
+/// A moderation directive for a topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationAction {
+    pub topic: String,
+    pub action: Action,
+}
+
+/// The moderation actions a peer can issue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    /// Claims the founder role of a topic without a known founder.
+    Found,
+    /// Grants moderator rights to a peer, on a topic the issuer founded.
+    Grant { peer: PeerId },
+    /// Hides the messages of a peer, optionally for a limited time.
+    Mute { peer: PeerId, seconds: Option<u64> },
+    /// Removes a peer from the topic.
+    Kick { peer: PeerId },
+    /// Makes the topic private to its moderators and members, on a topic
+    /// the issuer founded.
+    Close,
+    /// Admits the issuer to a private topic with an invite.
+    Join { token: InviteToken },
+    /// Adds a peer to the members of the topic.
+    Add { peer: PeerId },
+    /// Removes a peer from the members of the topic.
+    Remove { peer: PeerId },
+    /// Hands the founder role over to a peer.
+    Transfer { peer: PeerId },
+    /// Votes for a peer to become a moderator.
+    Nominate { peer: PeerId },
+}
+
+/// Reasons a moderation directive is rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The topic already has a different founder.
+    AlreadyFounded,
+    /// The topic has no founder yet.
+    NotFounded,
+    /// The issuer lacks the rights for the action.
+    NotAuthorized,
+    /// The invite presented to join is not valid.
+    InvalidInvite(InviteError),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::AlreadyFounded => write!(f, "topic already has a founder"),
+            Rejection::NotFounded => write!(f, "topic has no founder"),
+            Rejection::NotAuthorized => write!(f, "issuer is not authorized"),
+            Rejection::InvalidInvite(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Moderation state of a single topic.
+#[derive(Default, Serialize, Deserialize)]
+struct TopicModeration {
+    founder: Option<PeerId>,
+    moderators: HashSet<PeerId>,
+    /// Muted peers, and when in milliseconds since the epoch they are no
+    /// longer muted.
+    muted: HashMap<PeerId, Option<u64>>,
+    private: bool,
+    /// Peers admitted with an invite.
+    members: HashSet<PeerId>,
+    /// Nonces of the invites used to join, each admitting once.
+    spent: HashSet<u64>,
+    /// The moderators that nominated each peer not yet a moderator.
+    nominations: HashMap<PeerId, HashSet<PeerId>>,
+}
+
+impl TopicModeration {
+    fn is_moderator(&self, peer: &PeerId) -> bool {
+        self.founder.as_ref() == Some(peer) || self.moderators.contains(peer)
+    }
+
+    /// Returns how many nominations add a moderator: a majority of the
+    /// moderators, founder included.
+    fn quorum(&self) -> usize {
+        let founder = self
+            .founder
+            .filter(|founder| !self.moderators.contains(founder));
+        let moderators = self.moderators.len() + usize::from(founder.is_some());
+        (moderators / 2 + 1).max(MIN_QUORUM)
+    }
+
+    fn is_member(&self, peer: &PeerId) -> bool {
+        !self.private || self.is_moderator(peer) || self.members.contains(peer)
+    }
+}
+
+/// A peer muted or kicked on a topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Restriction {
+    pub topic: String,
+    pub peer: PeerId,
+    /// When the peer is no longer muted, in milliseconds since the epoch.
+    pub until: Option<u64>,
+}
+
+/// The roles and members of a topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Roster {
+    pub founder: Option<PeerId>,
+    /// The moderators other than the founder, sorted.
+    pub admins: Vec<PeerId>,
+    /// The members admitted by an admin or an invite, sorted.
+    pub members: Vec<PeerId>,
+    pub private: bool,
+}
+
+/// Moderation state of all topics.
+pub struct Moderation {
+    topics: HashMap<String, TopicModeration>,
+    /// The file the state is saved to, and the vault sealing it.
+    file: Option<(PathBuf, Option<Arc<Vault>>)>,
+    /// Directives received before the claim founding their topic, with
+    /// their issuer and when they were issued. Gossipsub may deliver a
+    /// directive ahead of the one published right before it.
+    deferred: VecDeque<(PeerId, ModerationAction, u64)>,
+}
+
+impl Moderation {
+    /// Creates a new, empty `Moderation` instance kept in memory only.
+    pub fn new() -> Self {
+        Moderation {
+            topics: HashMap::new(),
+            file: None,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// Loads the moderation state saved to a file, saving it there on every
+    /// change.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The moderation file, which need not exist yet.
+    /// * `vault` - The vault, if storage encryption is on.
+    pub fn load(path: PathBuf, vault: Option<Arc<Vault>>) -> Result<Self, Box<dyn Error>> {
+        let topics = match storage::read(&path, vault.as_deref())? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => HashMap::new(),
+        };
+        let mut moderation = Moderation {
+            topics,
+            file: Some((path, vault)),
+            deferred: VecDeque::new(),
+        };
+        moderation.expire();
+        Ok(moderation)
+    }
+
+    /// Saves the moderation state to its file, if it has one.
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        self.expire();
+        if let Some((path, vault)) = &self.file {
+            storage::write(path, &bincode::serialize(&self.topics)?, vault.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Validates and applies a directive issued by `issuer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `issuer` - The peer that signed the directive.
+    /// * `directive` - The directive to apply.
+    /// * `issued` - When the directive was issued, in milliseconds since the
+    ///   epoch.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the directive was accepted.
+    pub fn apply(
+        &mut self,
+        issuer: &PeerId,
+        directive: &ModerationAction,
+        issued: u64,
+    ) -> Result<(), Rejection> {
+        // Keeps a clock ahead from extending restrictions.
+        let issued = issued.min(now());
+        let topic = self.topics.entry(directive.topic.clone()).or_default();
+        match &directive.action {
+            Action::Found => match topic.founder {
+                Some(founder) if founder != *issuer => return Err(Rejection::AlreadyFounded),
+                _ => topic.founder = Some(*issuer),
+            },
+            Action::Grant { peer } => {
+                match topic.founder {
+                    None => return Err(Rejection::NotFounded),
+                    Some(founder) if founder != *issuer => return Err(Rejection::NotAuthorized),
+                    Some(_) => {}
+                }
+                topic.moderators.insert(*peer);
+                topic.nominations.remove(peer);
+            }
+            Action::Mute { peer, seconds } => {
+                if !topic.is_moderator(issuer) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                let until =
+                    seconds.map(|seconds| issued.saturating_add(seconds.saturating_mul(1000)));
+                topic.muted.insert(*peer, until);
+            }
+            Action::Kick { peer } => {
+                if !topic.is_moderator(issuer) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                let until = issued.saturating_add(KICK_COOLDOWN.as_millis() as u64);
+                topic.muted.insert(*peer, Some(until));
+                topic.members.remove(peer);
+            }
+            Action::Close => {
+                match topic.founder {
+                    None => return Err(Rejection::NotFounded),
+                    Some(founder) if founder != *issuer => return Err(Rejection::NotAuthorized),
+                    Some(_) => {}
+                }
+                topic.private = true;
+            }
+            Action::Join { token } => {
+                // The joining peer signs when it issued the directive, so
+                // the local clock decides whether the invite expired.
+                let inviter = token.verify(now()).map_err(Rejection::InvalidInvite)?;
+                if token.topic != directive.topic || !topic.is_moderator(&inviter) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                if token.invitee != *issuer {
+                    return Err(Rejection::InvalidInvite(InviteError::WrongPeer));
+                }
+                if !topic.spent.insert(token.nonce) {
+                    // The same join replayed from history changes nothing.
+                    return match topic.members.contains(issuer) {
+                        true => Ok(()),
+                        false => Err(Rejection::InvalidInvite(InviteError::Used)),
+                    };
+                }
+                topic.members.insert(*issuer);
+            }
+            Action::Add { peer } => {
+                if !topic.is_moderator(issuer) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                topic.members.insert(*peer);
+            }
+            Action::Remove { peer } => {
+                if !topic.is_moderator(issuer) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                topic.members.remove(peer);
+            }
+            Action::Transfer { peer } => {
+                if topic.founder.as_ref() != Some(issuer) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                topic.moderators.remove(peer);
+                topic.moderators.insert(*issuer);
+                topic.founder = Some(*peer);
+            }
+            Action::Nominate { peer } => {
+                if !topic.is_moderator(issuer) {
+                    return Err(Rejection::NotAuthorized);
+                }
+                if topic.is_moderator(peer) {
+                    return Ok(());
+                }
+                let mut voters = topic.nominations.remove(peer).unwrap_or_default();
+                voters.insert(*issuer);
+                // Nominations from peers no longer moderators do not count.
+                voters.retain(|voter| topic.is_moderator(voter));
+                if voters.len() >= topic.quorum() {
+                    topic.moderators.insert(*peer);
+                } else {
+                    topic.nominations.insert(*peer, voters);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps a directive rejected because its topic has no founder yet,
+    /// until the claim founding the topic arrives, dropping the oldest
+    /// beyond `MAX_DEFERRED`.
+    pub fn defer(&mut self, issuer: PeerId, directive: ModerationAction, issued: u64) {
+        if self.deferred.len() == MAX_DEFERRED {
+            self.deferred.pop_front();
+        }
+        self.deferred.push_back((issuer, directive, issued));
+    }
+
+    /// Takes the directives kept for a topic until it was founded, oldest
+    /// first.
+    pub fn take_deferred(&mut self, topic: &str) -> Vec<(PeerId, ModerationAction, u64)> {
+        let (taken, kept): (VecDeque<_>, _) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition(|(_, directive, _)| directive.topic == topic);
+        self.deferred = kept;
+        taken.into()
+    }
+
+    /// Returns the founder of a topic, if known.
+    pub fn founder(&self, topic: &str) -> Option<PeerId> {
+        self.topics.get(topic).and_then(|topic| topic.founder)
+    }
+
+    /// Returns how many moderators nominated `peer` on a topic, and how many
+    /// nominations add it.
+    pub fn nominations(&self, topic: &str, peer: &PeerId) -> (usize, usize) {
+        let Some(moderation) = self.topics.get(topic) else {
+            return (0, MIN_QUORUM);
+        };
+        let votes = moderation.nominations.get(peer).map_or(0, |voters| {
+            voters
+                .iter()
+                .filter(|voter| moderation.is_moderator(voter))
+                .count()
+        });
+        (votes, moderation.quorum())
+    }
+
+    /// Returns whether `peer` may mute and kick on a topic.
+    pub fn is_moderator(&self, topic: &str, peer: &PeerId) -> bool {
+        self.topics
+            .get(topic)
+            .is_some_and(|topic| topic.is_moderator(peer))
+    }
+
+    /// Returns the roster of a topic, if anything is known about it.
+    pub fn roster(&self, topic: &str) -> Option<Roster> {
+        let topic = self.topics.get(topic)?;
+        let mut admins: Vec<PeerId> = topic.moderators.iter().copied().collect();
+        admins.retain(|admin| topic.founder.as_ref() != Some(admin));
+        admins.sort();
+        let mut members: Vec<PeerId> = topic.members.iter().copied().collect();
+        members.sort();
+        Some(Roster {
+            founder: topic.founder,
+            admins,
+            members,
+            private: topic.private,
+        })
+    }
+
+    /// Returns whether a topic is private to its moderators and members.
+    pub fn is_private(&self, topic: &str) -> bool {
+        self.topics.get(topic).is_some_and(|topic| topic.private)
+    }
+
+    /// Returns whether `peer` may post on a topic, which is any peer unless
+    /// the topic is private.
+    pub fn is_member(&self, topic: &str, peer: &PeerId) -> bool {
+        self.topics
+            .get(topic)
+            .is_none_or(|topic| topic.is_member(peer))
+    }
+
+    /// Returns whether messages from `peer` on a topic should be hidden,
+    /// because it is muted or not a member of the private topic.
+    pub fn is_hidden(&self, topic: &str, peer: &PeerId) -> bool {
+        self.is_muted(topic, peer) || !self.is_member(topic, peer)
+    }
+
+    /// Returns whether `peer` is muted or kicked on a topic.
+    pub fn is_muted(&self, topic: &str, peer: &PeerId) -> bool {
+        let Some(until) = self.topics.get(topic).and_then(|t| t.muted.get(peer)) else {
+            return false;
+        };
+        until.is_none_or(|until| now() < until)
+    }
+
+    /// Returns the peers muted or kicked on every topic, by topic.
+    pub fn restrictions(&self) -> Vec<Restriction> {
+        let now = now();
+        let mut restrictions: Vec<Restriction> = self
+            .topics
+            .iter()
+            .flat_map(|(name, topic)| {
+                topic.muted.iter().map(|(peer, until)| Restriction {
+                    topic: name.clone(),
+                    peer: *peer,
+                    until: *until,
+                })
+            })
+            .filter(|restriction| restriction.until.is_none_or(|until| now < until))
+            .collect();
+        restrictions.sort_by_key(|restriction| {
+            (
+                restriction.topic.clone(),
+                restriction.until.unwrap_or(u64::MAX),
+            )
+        });
+        restrictions
+    }
+
+    /// Lifts the mute of a peer on a topic locally.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the peer was muted.
+    pub fn lift(&mut self, topic: &str, peer: &PeerId) -> bool {
+        let lifted = self.is_muted(topic, peer);
+        if let Some(topic) = self.topics.get_mut(topic) {
+            topic.muted.remove(peer);
+        }
+        lifted
+    }
+
+    /// Forgets the mutes that ran out.
+    fn expire(&mut self) {
+        let now = now();
+        for topic in self.topics.values_mut() {
+            topic
+                .muted
+                .retain(|_, until| until.is_none_or(|until| now < until));
+        }
+    }
+}
+
+impl Default for Moderation {
+    fn default() -> Self {
+        Moderation::new()
+    }
+}
+
+/// Returns the current time in milliseconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use libp2p::{identity, PeerId};
+    use zeroize::Zeroizing;
+
+    use super::{now, Action, Moderation, ModerationAction, Rejection, KICK_COOLDOWN};
+    use crate::invite::{InviteError, InviteToken};
+    use crate::storage::Vault;
+
+    fn directive(action: Action) -> ModerationAction {
+        ModerationAction {
+            topic: "chat".to_string(),
+            action,
+        }
+    }
+
+    #[test]
+    fn test_founder_grants_moderators() {
+        let founder = PeerId::random();
+        let moderator = PeerId::random();
+        let troll = PeerId::random();
+        let mut moderation = Moderation::new();
+
+        // Granting does not found a topic.
+        let early = directive(Action::Grant { peer: moderator });
+        assert_eq!(
+            moderation.apply(&founder, &early, now()),
+            Err(Rejection::NotFounded)
+        );
+        assert_eq!(moderation.founder("chat"), None);
+        moderation.defer(founder, early, now());
+        assert!(moderation.take_deferred("other").is_empty());
+
+        moderation
+            .apply(&founder, &directive(Action::Found), now())
+            .unwrap();
+        assert_eq!(
+            moderation.apply(&troll, &directive(Action::Found), now()),
+            Err(Rejection::AlreadyFounded)
+        );
+        assert_eq!(
+            moderation.apply(&troll, &directive(Action::Grant { peer: troll }), now()),
+            Err(Rejection::NotAuthorized)
+        );
+
+        // A grant arriving ahead of the claim applies once it is founded.
+        for (issuer, early, issued) in moderation.take_deferred("chat") {
+            moderation.apply(&issuer, &early, issued).unwrap();
+        }
+        assert!(moderation.take_deferred("chat").is_empty());
+        assert!(moderation.is_moderator("chat", &moderator));
+        assert!(!moderation.is_moderator("chat", &troll));
+        assert_eq!(moderation.founder("chat"), Some(founder));
+    }
+
+    #[test]
+    fn test_mute_and_kick() {
+        let founder = PeerId::random();
+        let troll = PeerId::random();
+        let mut moderation = Moderation::new();
+        moderation
+            .apply(&founder, &directive(Action::Found), now())
+            .unwrap();
+
+        assert_eq!(
+            moderation.apply(
+                &troll,
+                &directive(Action::Mute {
+                    peer: founder,
+                    seconds: None
+                }),
+                now()
+            ),
+            Err(Rejection::NotAuthorized)
+        );
+        moderation
+            .apply(
+                &founder,
+                &directive(Action::Mute {
+                    peer: troll,
+                    seconds: Some(0),
+                }),
+                now(),
+            )
+            .unwrap();
+        assert!(!moderation.is_muted("chat", &troll));
+
+        // A kick replayed after its cooldown does not restrict again.
+        let replayed = now() - KICK_COOLDOWN.as_millis() as u64;
+        moderation
+            .apply(&founder, &directive(Action::Kick { peer: troll }), replayed)
+            .unwrap();
+        assert!(!moderation.is_muted("chat", &troll));
+        moderation
+            .apply(&founder, &directive(Action::Kick { peer: troll }), now())
+            .unwrap();
+        assert!(moderation.is_muted("chat", &troll));
+        assert!(!moderation.is_muted("other", &troll));
+    }
+
+    #[test]
+    fn test_handover_and_quorum() {
+        let (founder, heir) = (PeerId::random(), PeerId::random());
+        let (first, second, candidate) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut moderation = Moderation::new();
+        moderation
+            .apply(&founder, &directive(Action::Found), now())
+            .unwrap();
+        assert_eq!(
+            moderation.apply(&heir, &directive(Action::Transfer { peer: heir }), now()),
+            Err(Rejection::NotAuthorized)
+        );
+        moderation
+            .apply(&founder, &directive(Action::Transfer { peer: heir }), now())
+            .unwrap();
+        assert_eq!(moderation.founder("chat"), Some(heir));
+        assert!(moderation.is_moderator("chat", &founder));
+
+        // The heir and three moderators: three nominations make a majority.
+        for peer in [first, second] {
+            moderation
+                .apply(&heir, &directive(Action::Grant { peer }), now())
+                .unwrap();
+        }
+        let nominate = directive(Action::Nominate { peer: candidate });
+        assert_eq!(
+            moderation.apply(&candidate, &nominate, now()),
+            Err(Rejection::NotAuthorized)
+        );
+        moderation.apply(&first, &nominate, now()).unwrap();
+        moderation.apply(&first, &nominate, now()).unwrap();
+        assert_eq!(moderation.nominations("chat", &candidate), (1, 3));
+        assert!(!moderation.is_moderator("chat", &candidate));
+        moderation.apply(&second, &nominate, now()).unwrap();
+        moderation.apply(&founder, &nominate, now()).unwrap();
+        assert!(moderation.is_moderator("chat", &candidate));
+        assert_eq!(moderation.nominations("chat", &candidate), (0, 3));
+    }
+
+    #[test]
+    fn test_private_topic_invites() {
+        let founder_key = identity::Keypair::generate_ed25519();
+        let founder = founder_key.public().to_peer_id();
+        let (guest, stranger) = (PeerId::random(), PeerId::random());
+        let mut moderation = Moderation::new();
+        assert!(!moderation.is_hidden("chat", &stranger));
+
+        // Closing a topic without a founder does not claim it.
+        assert_eq!(
+            moderation.apply(&founder, &directive(Action::Close), now()),
+            Err(Rejection::NotFounded)
+        );
+        assert_eq!(moderation.founder("chat"), None);
+        for action in [Action::Found, Action::Close] {
+            moderation
+                .apply(&founder, &directive(action), now())
+                .unwrap();
+        }
+        assert_eq!(
+            moderation.apply(&stranger, &directive(Action::Close), now()),
+            Err(Rejection::NotAuthorized)
+        );
+        assert!(moderation.is_private("chat"));
+        assert!(moderation.is_hidden("chat", &stranger));
+        assert!(!moderation.is_hidden("chat", &founder));
+
+        // Only invites from moderators of the same topic admit.
+        let stranger_key = identity::Keypair::generate_ed25519();
+        let forged = InviteToken::issue("chat", stranger, None, &stranger_key);
+        let join = |token| directive(Action::Join { token });
+        assert_eq!(
+            moderation.apply(&stranger, &join(forged.unwrap()), now()),
+            Err(Rejection::NotAuthorized)
+        );
+        let other = InviteToken::issue("other", stranger, None, &founder_key).unwrap();
+        assert_eq!(
+            moderation.apply(&stranger, &join(other), now()),
+            Err(Rejection::NotAuthorized)
+        );
+        // A joining peer backdating its directive does not revive an invite.
+        let expires = now() - 1;
+        let expired = InviteToken::issue("chat", stranger, Some(expires), &founder_key).unwrap();
+        assert_eq!(
+            moderation.apply(&stranger, &join(expired), expires - 60_000),
+            Err(Rejection::InvalidInvite(InviteError::Expired))
+        );
+
+        let token = InviteToken::issue("chat", guest, Some(now() + 60_000), &founder_key).unwrap();
+        moderation
+            .apply(&guest, &join(token.clone()), now())
+            .unwrap();
+        assert!(!moderation.is_hidden("chat", &guest));
+        // Replaying the join from history changes nothing.
+        moderation
+            .apply(&guest, &join(token.clone()), now())
+            .unwrap();
+        moderation
+            .apply(&founder, &directive(Action::Kick { peer: guest }), now())
+            .unwrap();
+        assert!(!moderation.is_member("chat", &guest));
+    }
+
+    #[test]
+    fn test_replayed_invite() {
+        let founder_key = identity::Keypair::generate_ed25519();
+        let founder = founder_key.public().to_peer_id();
+        let (guest, stranger) = (PeerId::random(), PeerId::random());
+        let mut moderation = Moderation::new();
+        for action in [Action::Found, Action::Close] {
+            moderation
+                .apply(&founder, &directive(action), now())
+                .unwrap();
+        }
+        let token = InviteToken::issue("chat", guest, None, &founder_key).unwrap();
+        let join = directive(Action::Join { token });
+
+        // A peer that saw the join on the topic cannot use the invite.
+        assert_eq!(
+            moderation.apply(&stranger, &join, now()),
+            Err(Rejection::InvalidInvite(InviteError::WrongPeer))
+        );
+        assert!(!moderation.is_member("chat", &stranger));
+
+        // Nor can the invited peer once kicked or removed.
+        moderation.apply(&guest, &join, now()).unwrap();
+        moderation
+            .apply(&founder, &directive(Action::Remove { peer: guest }), now())
+            .unwrap();
+        assert_eq!(
+            moderation.apply(&guest, &join, now()),
+            Err(Rejection::InvalidInvite(InviteError::Used))
+        );
+        assert!(!moderation.is_member("chat", &guest));
+    }
+
+    #[test]
+    fn test_group_roster() {
+        let (founder, admin, member) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut moderation = Moderation::new();
+        assert_eq!(moderation.roster("chat"), None);
+        for action in [Action::Found, Action::Close, Action::Grant { peer: admin }] {
+            moderation
+                .apply(&founder, &directive(action), now())
+                .unwrap();
+        }
+        assert_eq!(
+            moderation.apply(&member, &directive(Action::Add { peer: member }), now()),
+            Err(Rejection::NotAuthorized)
+        );
+        moderation
+            .apply(&admin, &directive(Action::Add { peer: member }), now())
+            .unwrap();
+        assert!(moderation.is_member("chat", &member));
+        let roster = moderation.roster("chat").unwrap();
+        assert_eq!(roster.founder, Some(founder));
+        assert_eq!(roster.admins, vec![admin]);
+        assert_eq!(roster.members, vec![member]);
+        assert!(roster.private);
+
+        moderation
+            .apply(&founder, &directive(Action::Remove { peer: member }), now())
+            .unwrap();
+        assert!(!moderation.is_member("chat", &member));
+        assert!(moderation.is_member("chat", &admin));
+    }
+
+    #[test]
+    fn test_save_and_lift() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-moderation-{}", std::process::id()));
+        let path = dir.join("moderation.db");
+        let vault = Arc::new(Vault::new(Zeroizing::new("correct horse".to_string())));
+        let (founder, troll, spammer) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let deputy = PeerId::random();
+        let mut moderation = Moderation::load(path.clone(), Some(vault.clone())).unwrap();
+        moderation
+            .apply(&founder, &directive(Action::Found), now())
+            .unwrap();
+        for (peer, seconds) in [(troll, None), (spammer, Some(60))] {
+            moderation
+                .apply(&founder, &directive(Action::Mute { peer, seconds }), now())
+                .unwrap();
+        }
+        moderation
+            .apply(&founder, &directive(Action::Grant { peer: deputy }), now())
+            .unwrap();
+        moderation
+            .apply(&deputy, &directive(Action::Nominate { peer: troll }), now())
+            .unwrap();
+        moderation.save().unwrap();
+
+        let mut loaded = Moderation::load(path, Some(vault)).unwrap();
+        assert_eq!(loaded.founder("chat"), Some(founder));
+        // Nominations count across restarts.
+        assert_eq!(loaded.nominations("chat", &troll), (1, 2));
+        let restrictions = loaded.restrictions();
+        assert_eq!(restrictions.len(), 2);
+        assert_eq!(restrictions[0].peer, spammer);
+        assert!(loaded.lift("chat", &troll));
+        assert!(!loaded.lift("chat", &troll));
+        assert!(!loaded.is_muted("chat", &troll));
+        assert!(loaded.is_muted("chat", &spammer));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+/*!
+ * MQTT module for the messaging application.
+ *
+ * A daemon can bridge topics to an MQTT broker, so devices and
+ * home-automation systems that speak MQTT take part in the mesh. Each route
+ * maps a topic to an MQTT topic: messages received on the topic are
+ * published to the broker as their bare body, and messages published to
+ * the broker by others are published on the topic. A route may name an MQTT
+ * filter with `+` or `#` wildcards instead, say to gather the readings of
+ * every sensor in a house; such a route only carries messages into the
+ * mesh, prefixed with the MQTT topic they came on, as there is no single
+ * topic to publish to.
+ *
+ * Messages go both ways at the quality of service the bridge is started
+ * with. The broker sends a bridge its own messages back when they match
+ * its filters, which are recognized and skipped, and retained messages are
+ * skipped too, so reconnecting does not replay stale readings on the mesh.
+ * The bridge reconnects to the broker with a growing delay whenever the
+ * connection fails. The bridge is built with the `mqtt` feature.
+ */
+
+/// The quality of service of a bridge if not given.
+pub const DEFAULT_QOS: u8 = 1;
+
+#[cfg(feature = "mqtt")]
+pub use broker::bridge;
+
+#[cfg(feature = "mqtt")]
+mod broker {
+    use std::{collections::VecDeque, error::Error, time::Duration};
+
+    use futures::{stream, StreamExt};
+    use libp2p::PeerId;
+    use log::{debug, error, info, warn};
+    use rumqttc::{
+        matches, qos, valid_filter, valid_topic, AsyncClient, Event, MqttOptions, Packet, Publish,
+        QoS,
+    };
+
+    use crate::client::{Client, ClientError};
+
+    /// Messages waiting to be handed to the connection to the broker.
+    const CAPACITY: usize = 64;
+
+    /// Messages sent to the broker remembered to recognize their echoes.
+    const MAX_ECHOES: usize = 64;
+
+    /// How often the broker is pinged on an idle connection.
+    const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+    /// First delay before reconnecting to the broker.
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Longest delay before reconnecting to the broker.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// A topic bridged to an MQTT topic or filter.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Route {
+        topic: String,
+        filter: String,
+    }
+
+    impl Route {
+        /// Whether messages of the topic are published to the broker,
+        /// which takes a filter without wildcards.
+        fn outbound(&self) -> bool {
+            valid_topic(&self.filter)
+        }
+
+        /// Returns the body a message of the broker is published with on
+        /// the topic, if the route takes it.
+        ///
+        /// # Arguments
+        ///
+        /// * `mqtt_topic` - The MQTT topic of the message.
+        /// * `body` - The payload of the message.
+        fn carry(&self, mqtt_topic: &str, body: &str) -> Option<String> {
+            if !matches(mqtt_topic, &self.filter) {
+                return None;
+            }
+            match self.outbound() {
+                true => Some(body.to_string()),
+                false => Some(format!("{}: {}", mqtt_topic, body)),
+            }
+        }
+    }
+
+    /// The messages last sent to the broker, whose echoes are skipped.
+    #[derive(Default)]
+    struct Echoes {
+        sent: VecDeque<(String, String)>,
+    }
+
+    impl Echoes {
+        /// Remembers a message sent to the broker.
+        fn sent(&mut self, mqtt_topic: &str, body: &str) {
+            if self.sent.len() == MAX_ECHOES {
+                self.sent.pop_front();
+            }
+            self.sent
+                .push_back((mqtt_topic.to_string(), body.to_string()));
+        }
+
+        /// Returns whether a message of the broker is the echo of one sent,
+        /// forgetting the message sent.
+        fn echo(&mut self, mqtt_topic: &str, body: &str) -> bool {
+            let position = self
+                .sent
+                .iter()
+                .position(|(topic, sent)| topic == mqtt_topic && sent == body);
+            position.and_then(|i| self.sent.remove(i)).is_some()
+        }
+    }
+
+    /// Adds a client ID to a broker URL that has none.
+    fn with_client_id(broker: &str, peer: &PeerId) -> String {
+        if broker.contains("client_id=") {
+            return broker.to_string();
+        }
+        let peer = peer.to_base58();
+        let separator = if broker.contains('?') { '&' } else { '?' };
+        // Brokers need not take client IDs longer than 23 bytes.
+        let id = &peer[peer.len().saturating_sub(14)..];
+        format!("{}{}client_id=sec_msg-{}", broker, separator, id)
+    }
+
+    /// Starts bridging topics to an MQTT broker.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The handle to the node.
+    /// * `broker` - The `mqtt://` or `mqtts://` URL of the broker, which may
+    ///   hold a user name and password.
+    /// * `routes` - The topics bridged, each with its MQTT topic or filter.
+    /// * `qos_level` - The quality of service messages are sent and
+    ///   received at, from 0 to 2.
+    /// * `peer` - The peer ID of the node, the client ID is made from.
+    pub fn bridge(
+        client: Client,
+        broker: &str,
+        routes: Vec<(String, String)>,
+        qos_level: u8,
+        peer: &PeerId,
+    ) -> Result<(), Box<dyn Error>> {
+        let level = qos(qos_level).map_err(|_| format!("Invalid MQTT QoS {}", qos_level))?;
+        let routes = routes
+            .into_iter()
+            .map(|(topic, filter)| match valid_filter(&filter) {
+                true => Ok(Route { topic, filter }),
+                false => Err(format!("Invalid MQTT topic {}", filter)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut options = MqttOptions::parse_url(with_client_id(broker, peer))
+            .map_err(|e| format!("Invalid MQTT broker {}: {}", broker, e))?;
+        options.set_keep_alive(KEEP_ALIVE);
+        let (host, port) = options.broker_address();
+        info!(
+            "Bridging {} topics to MQTT broker {}:{}",
+            routes.len(),
+            host,
+            port
+        );
+        // Joining the topics waits for the swarm loop, which runs later.
+        tokio::spawn(async move {
+            if let Err(e) = run(client, options, routes, level).await {
+                error!("The MQTT bridge stopped: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Bridges the topics until the node stops.
+    async fn run(
+        client: Client,
+        options: MqttOptions,
+        routes: Vec<Route>,
+        level: QoS,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut streams = Vec::new();
+        for route in routes.iter().filter(|route| route.outbound()) {
+            let filter = route.filter.clone();
+            let messages = client.subscribe(&route.topic).await?;
+            streams.push(messages.map(move |message| (filter.clone(), message.body)));
+        }
+        let outbound = !streams.is_empty();
+        let mut messages = stream::select_all(streams);
+        let (broker, mut connection) = AsyncClient::new(options, CAPACITY);
+        let mut echoes = Echoes::default();
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            tokio::select! {
+                message = messages.next(), if outbound => {
+                    let Some((mqtt_topic, body)) = message else {
+                        // The node stopped.
+                        let _ = broker.try_disconnect();
+                        return Ok(());
+                    };
+                    // Dropped rather than waited for while the broker is away.
+                    match broker.try_publish(&mqtt_topic, level, false, body.clone()) {
+                        Ok(()) => echoes.sent(&mqtt_topic, &body),
+                        Err(e) => warn!("Dropped a message to MQTT topic {}: {}", mqtt_topic, e),
+                    }
+                }
+                event = connection.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to the MQTT broker");
+                        backoff = MIN_BACKOFF;
+                        // Subscriptions do not outlive a clean session.
+                        for route in &routes {
+                            if let Err(e) = broker.try_subscribe(&route.filter, level) {
+                                warn!("Failed to subscribe to MQTT topic {}: {}", route.filter, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        match receive(&client, &routes, &mut echoes, publish).await {
+                            Err(ClientError::Stopped) => return Ok(()),
+                            Err(e) => warn!("Failed to publish a message from MQTT: {}", e),
+                            Ok(()) => {}
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT broker failed: {}, reconnecting in {:?}", e, backoff);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Publishes a message of the broker on the topics routed from it.
+    async fn receive(
+        client: &Client,
+        routes: &[Route],
+        echoes: &mut Echoes,
+        publish: Publish,
+    ) -> Result<(), ClientError> {
+        if publish.retain {
+            debug!(
+                "Skipping a retained message on MQTT topic {}",
+                publish.topic
+            );
+            return Ok(());
+        }
+        let Ok(body) = String::from_utf8(publish.payload.to_vec()) else {
+            debug!("Skipping a binary message on MQTT topic {}", publish.topic);
+            return Ok(());
+        };
+        if echoes.echo(&publish.topic, &body) {
+            return Ok(());
+        }
+        for route in routes {
+            if let Some(carried) = route.carry(&publish.topic, &body) {
+                client.publish(&route.topic, carried).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use libp2p::PeerId;
+
+        use super::{with_client_id, Echoes, Route};
+
+        #[test]
+        fn test_bridge() {
+            let lights = Route {
+                topic: "lights".to_string(),
+                filter: "home/lights".to_string(),
+            };
+            let sensors = Route {
+                topic: "sensors".to_string(),
+                filter: "home/+/temperature".to_string(),
+            };
+            assert!(lights.outbound());
+            assert!(!sensors.outbound());
+            assert_eq!(lights.carry("home/lights", "on").as_deref(), Some("on"));
+            assert_eq!(lights.carry("home/lights/kitchen", "on"), None);
+            assert_eq!(
+                sensors.carry("home/kitchen/temperature", "21.5").as_deref(),
+                Some("home/kitchen/temperature: 21.5")
+            );
+
+            let mut echoes = Echoes::default();
+            echoes.sent("home/lights", "on");
+            assert!(!echoes.echo("home/lights", "off"));
+            assert!(echoes.echo("home/lights", "on"));
+            // The same message sent by a device afterwards is not an echo.
+            assert!(!echoes.echo("home/lights", "on"));
+
+            let peer = PeerId::random();
+            let url = with_client_id("mqtt://broker:1883", &peer);
+            let id = url.strip_prefix("mqtt://broker:1883?client_id=").unwrap();
+            assert!(id.len() <= 23);
+            assert!(peer.to_base58().ends_with(&id["sec_msg-".len()..]));
+            assert_eq!(
+                with_client_id("mqtt://broker?client_id=lamp", &peer),
+                "mqtt://broker?client_id=lamp"
+            );
+            assert!(with_client_id("mqtt://broker?keep_alive_secs=5", &peer)
+                .starts_with("mqtt://broker?keep_alive_secs=5&client_id="));
+        }
+    }
+}
+
+/// Fails, as the MQTT bridge is built with the `mqtt` feature.
+#[cfg(not(feature = "mqtt"))]
+pub fn bridge(
+    _client: crate::client::Client,
+    _broker: &str,
+    _routes: Vec<(String, String)>,
+    _qos: u8,
+    _peer: &libp2p::PeerId,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without the MQTT bridge, rebuild with --features mqtt".into())
+}
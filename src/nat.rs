@@ -0,0 +1,145 @@
+/*!
+ * NAT status module for the messaging application.
+ *
+ * Connected peers report the address they observe us at through the
+ * identify protocol. A peer observing one of our listen addresses reaches
+ * us directly, while a peer observing another address reaches us through a
+ * NAT. This module keeps the latest observation of each connected peer and
+ * estimates the NAT status from the majority of them.
+ */
+
+use std::{collections::HashMap, fmt, net::IpAddr};
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+/// Whether we appear to be behind a NAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatStatus {
+    /// No peer reported an address yet.
+    Unknown,
+    /// Peers reach us at our listen addresses.
+    Public,
+    /// Peers reach us at addresses we do not listen on.
+    Private,
+}
+
+impl fmt::Display for NatStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatStatus::Unknown => write!(f, "unknown"),
+            NatStatus::Public => write!(f, "public"),
+            NatStatus::Private => write!(f, "private"),
+        }
+    }
+}
+
+/// Tracks the addresses peers observe us at.
+pub struct NatTracker {
+    /// Whether each peer observed one of our listen addresses.
+    observations: HashMap<PeerId, bool>,
+}
+
+impl NatTracker {
+    /// Creates a new `NatTracker` with no observations.
+    pub fn new() -> Self {
+        NatTracker {
+            observations: HashMap::new(),
+        }
+    }
+
+    /// Records the address a peer observed us at.
+    ///
+    /// Loopback addresses are ignored since they say nothing about how
+    /// other hosts reach us.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer that reported the address.
+    /// * `observed` - The address the peer observed.
+    /// * `listen_addrs` - Our listen addresses.
+    pub fn observed<'a>(
+        &mut self,
+        peer: PeerId,
+        observed: &Multiaddr,
+        listen_addrs: impl IntoIterator<Item = &'a Multiaddr>,
+    ) {
+        let Some(observed_ip) = ip(observed).filter(|ip| !ip.is_loopback()) else {
+            return;
+        };
+        let direct = listen_addrs
+            .into_iter()
+            .any(|address| ip(address) == Some(observed_ip));
+        self.observations.insert(peer, direct);
+    }
+
+    /// Forgets the observation of a peer whose last connection closed.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.observations.remove(peer);
+    }
+
+    /// Returns the NAT status most peers agree on, public on a tie.
+    pub fn status(&self) -> NatStatus {
+        if self.observations.is_empty() {
+            return NatStatus::Unknown;
+        }
+        let direct = self.observations.values().filter(|direct| **direct).count();
+        if 2 * direct >= self.observations.len() {
+            NatStatus::Public
+        } else {
+            NatStatus::Private
+        }
+    }
+}
+
+impl Default for NatTracker {
+    fn default() -> Self {
+        NatTracker::new()
+    }
+}
+
+/// Returns the IP address an address starts with.
+fn ip(address: &Multiaddr) -> Option<IpAddr> {
+    match address.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{Multiaddr, PeerId};
+
+    use super::{NatStatus, NatTracker};
+
+    #[test]
+    fn test_nat_status() {
+        let listen: Vec<Multiaddr> = vec![
+            "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            "/ip4/192.168.1.10/tcp/4001".parse().unwrap(),
+        ];
+        let mut nat = NatTracker::new();
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        nat.observed(alice, &"/ip4/127.0.0.1/tcp/5000".parse().unwrap(), &listen);
+        assert_eq!(nat.status(), NatStatus::Unknown);
+
+        nat.observed(
+            alice,
+            &"/ip4/203.0.113.7/tcp/5000".parse().unwrap(),
+            &listen,
+        );
+        assert_eq!(nat.status(), NatStatus::Private);
+        nat.observed(bob, &"/ip4/192.168.1.10/tcp/5000".parse().unwrap(), &listen);
+        assert_eq!(nat.status(), NatStatus::Public);
+        nat.observed(
+            carol,
+            &"/ip4/203.0.113.7/tcp/5001".parse().unwrap(),
+            &listen,
+        );
+        assert_eq!(nat.status(), NatStatus::Private);
+
+        nat.disconnected(&alice);
+        nat.disconnected(&carol);
+        assert_eq!(nat.status(), NatStatus::Public);
+    }
+}
@@ -1,15 +1,74 @@
 /*!
  * Network module for creating and managing the libp2p swarm.
  *
- * This module provides functions to create a libp2p swarm and handle
- * listening on specified addresses.
+ * This module provides functions to create a libp2p swarm, handle
+ * listening on specified addresses and dial the bootstrap peers. Nodes
+ * speak TCP and WebSocket; built for the browser, the swarm only dials
+ * out over WebSocket. Tests run swarms over the in-memory transport of
+ * libp2p instead, which connects them within the process.
+ *
+ * How long connections are kept is configurable. The swarm is assembled
+ * here rather than by the builder of libp2p, which gives every dial ten
+ * seconds whatever the configuration says.
  */
 
 use std::{error::Error, time::Duration};
 
-use libp2p::{identity, tcp, tls, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder};
+#[cfg(target_arch = "wasm32")]
+use libp2p::websocket_websys;
+#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+use libp2p::SwarmBuilder;
+use libp2p::{
+    core::{muxing::StreamMuxerBox, transport::timeout::TransportTimeout, upgrade::Version},
+    identity,
+    multiaddr::Protocol,
+    noise, swarm, yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use libp2p::{dns, tcp, tls, websocket};
+use log::{error, info};
 
 use crate::protocol::Protocols;
+use crate::topic::PubsubProtocol;
+
+/// How connections are kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionSettings {
+    /// How long a connection without traffic is kept open, which lets
+    /// pings be observed.
+    pub idle_timeout: Duration,
+    /// How long dialing a peer may take, the handshake included.
+    pub dial_timeout: Duration,
+    /// Whether connections without traffic are kept open whatever the
+    /// idle timeout.
+    pub keep_alive: bool,
+    /// Events of a connection buffered before it waits for the swarm.
+    pub event_buffer: usize,
+}
+
+impl ConnectionSettings {
+    /// Applies the settings to the configuration of a swarm.
+    fn apply(&self, config: swarm::Config) -> swarm::Config {
+        let idle_timeout = match self.keep_alive {
+            true => Duration::MAX,
+            false => self.idle_timeout,
+        };
+        config
+            .with_idle_connection_timeout(idle_timeout)
+            .with_per_connection_event_buffer_size(self.event_buffer)
+    }
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            idle_timeout: Duration::from_secs(30),
+            dial_timeout: Duration::from_secs(10),
+            keep_alive: false,
+            event_buffer: 7,
+        }
+    }
+}
 
 /// Creates a libp2p swarm with the specified keypair, peer ID, and topic.
 ///
@@ -18,6 +77,8 @@ use crate::protocol::Protocols;
 /// * `local_key` - The local identity keypair.
 /// * `local_peer_id` - The local peer ID.
 /// * `topic` - The topic to subscribe to.
+/// * `protocol` - The protocols to subscribe to the topic on.
+/// * `connection` - How connections are kept.
 ///
 /// # Returns
 ///
@@ -26,20 +87,96 @@ pub async fn create_swarm(
     local_key: identity::Keypair,
     local_peer_id: PeerId,
     topic: &str,
+    protocol: PubsubProtocol,
+    connection: &ConnectionSettings,
+) -> Result<Swarm<Protocols>, Box<dyn Error>> {
+    let mut behaviour = Protocols::new(local_peer_id, local_key.clone());
+
+    behaviour.subscribe(topic, protocol)?;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let (transport, config) = {
+        let tcp = tcp::tokio::Transport::new(tcp::Config::default())
+            .upgrade(Version::V1Lazy)
+            .authenticate(tls::Config::new(&local_key)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+        // Browsers reach the node over WebSocket, securing it with Noise.
+        let websocket = websocket::WsConfig::new(dns::tokio::Transport::system(
+            tcp::tokio::Transport::new(tcp::Config::default()),
+        )?)
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(&local_key)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+        let transport = websocket
+            .or_transport(tcp)
+            .map(|either, _| either.into_inner());
+        (transport, swarm::Config::with_tokio_executor())
+    };
+    // Browsers can only dial out, over WebSocket.
+    #[cfg(target_arch = "wasm32")]
+    let (transport, config) = {
+        let transport = websocket_websys::Transport::default()
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise::Config::new(&local_key)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+        (transport, swarm::Config::with_wasm_executor())
+    };
+
+    Ok(Swarm::new(
+        TransportTimeout::new(transport, connection.dial_timeout).boxed(),
+        behaviour,
+        local_peer_id,
+        connection.apply(config),
+    ))
+}
+
+/// Creates a libp2p swarm speaking only the in-memory transport, for tests
+/// running several nodes in one process.
+///
+/// # Arguments
+///
+/// * `local_key` - The local identity keypair.
+/// * `local_peer_id` - The local peer ID.
+/// * `topic` - The topic to subscribe to.
+/// * `protocol` - The protocols to subscribe to the topic on.
+/// * `chaos` - The chaos of the connections, calm by default.
+///
+/// # Returns
+///
+/// A `Result` containing the created `Swarm` or an error.
+#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+pub fn create_memory_swarm(
+    local_key: identity::Keypair,
+    local_peer_id: PeerId,
+    topic: &str,
+    protocol: PubsubProtocol,
+    chaos: crate::chaos::ChaosControl,
 ) -> Result<Swarm<Protocols>, Box<dyn Error>> {
-    let mut behaviour = Protocols::new(local_peer_id, local_key);
+    use libp2p::core::transport::MemoryTransport;
+
+    use crate::chaos::ChaosStream;
 
-    behaviour.subscribe(topic)?;
+    let mut behaviour = Protocols::new(local_peer_id, local_key.clone());
 
-    let swarm = SwarmBuilder::with_new_identity()
+    behaviour.subscribe(topic, protocol)?;
+
+    let swarm = SwarmBuilder::with_existing_identity(local_key)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            tls::Config::new,
-            yamux::Config::default,
-        )?
+        .with_other_transport(|key| {
+            let noise = noise::Config::new(key)?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(
+                MemoryTransport::default()
+                    .map(move |connection, _| ChaosStream::new(connection, chaos))
+                    .upgrade(Version::V1)
+                    .authenticate(noise)
+                    .multiplex(yamux::Config::default()),
+            )
+        })?
         .with_behaviour(|_| behaviour)?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(30))) // Allows us to observe pings for 30 seconds.
+        .with_swarm_config(|cfg| ConnectionSettings::default().apply(cfg))
         .build();
 
     Ok(swarm)
@@ -50,28 +187,60 @@ pub async fn create_swarm(
 /// # Arguments
 ///
 /// * `swarm` - The libp2p swarm.
+/// * `addrs` - The addresses to listen on.
 ///
 /// # Returns
 ///
 /// A `Result` indicating success or failure.
-pub fn listen_on(swarm: &mut Swarm<Protocols>) -> Result<(), Box<dyn Error>> {
-    let addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-    swarm.listen_on(addr)?;
+pub fn listen_on(swarm: &mut Swarm<Protocols>, addrs: &[Multiaddr]) -> Result<(), Box<dyn Error>> {
+    for addr in addrs {
+        swarm.listen_on(addr.clone())?;
+    }
     Ok(())
 }
 
+/// Dials the bootstrap peers, adding those whose address ends with their
+/// peer ID to the Kademlia routing table.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `addrs` - The addresses of the bootstrap peers.
+pub fn bootstrap(swarm: &mut Swarm<Protocols>, addrs: &[Multiaddr]) {
+    for addr in addrs {
+        if let Some(Protocol::P2p(peer_id)) = addr.iter().last() {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, addr.clone());
+        }
+        info!("Dialing bootstrap peer {:?}", addr);
+        if let Err(e) = swarm.dial(addr.clone()) {
+            error!("Failed to dial bootstrap peer {:?}: {:?}", addr, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use libp2p::{identity, PeerId};
 
-    use super::{create_swarm, listen_on};
+    use super::{create_swarm, listen_on, ConnectionSettings};
+    use crate::topic::PubsubProtocol;
 
     #[tokio::test]
     async fn test_create_swarm() {
         let keypair = identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from(keypair.public());
         let topic = "test-topic";
-        let swarm = create_swarm(keypair, peer_id, topic).await;
+        let swarm = create_swarm(
+            keypair,
+            peer_id,
+            topic,
+            PubsubProtocol::Both,
+            &ConnectionSettings::default(),
+        )
+        .await;
         assert!(swarm.is_ok());
     }
 
@@ -80,8 +249,17 @@ mod tests {
         let keypair = identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from(keypair.public());
         let topic = "test-topic";
-        let mut swarm = create_swarm(keypair, peer_id, topic).await.unwrap();
-        let result = listen_on(&mut swarm);
+        let mut swarm = create_swarm(
+            keypair,
+            peer_id,
+            topic,
+            PubsubProtocol::Both,
+            &ConnectionSettings::default(),
+        )
+        .await
+        .unwrap();
+        let addrs = vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()];
+        let result = listen_on(&mut swarm, &addrs);
         assert!(result.is_ok());
     }
 }
@@ -0,0 +1,380 @@
+/*!
+ * Node module for the messaging application.
+ *
+ * A `Node` is the messaging stack without a user interface: the swarm, the
+ * application state and the loop driving them. The binary puts a terminal
+ * UI or stdin in front of it, while other programs embedding the crate
+ * drive it by sending `AppEvent`s to the channel returned by `events`, and
+ * may follow it through the `UiEvent`s it sends. Typed lines go to the
+ * bounded channel returned by `input`, which waits while messages pile
+ * up. Bots are easier written against the `Client` handles returned by
+ * `client`.
+ */
+
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use libp2p::{identity, Swarm};
+use log::{error, info};
+use tokio::sync::mpsc::{self, Receiver, UnboundedReceiver, UnboundedSender};
+
+use crate::app::{AppEvent, AppEvents};
+use crate::client::Client;
+use crate::config::Config;
+use crate::contacts::Contacts;
+use crate::dirs::Dirs;
+use crate::event;
+use crate::history::History;
+use crate::hooks::{BlankHook, TraceHook};
+use crate::ignore::IgnoreList;
+use crate::ipc::{self, IpcHook, Subscribers};
+use crate::maintenance;
+use crate::moderation::Moderation;
+use crate::network::{bootstrap, create_swarm, listen_on};
+use crate::outgoing::{Input, INPUT_CAPACITY};
+use crate::presence::PRESENCE_TOPIC;
+use crate::protocol::{inbox_topic, Protocols};
+use crate::reload;
+use crate::reputation::Reputation;
+use crate::shutdown::Signal;
+use crate::state::AppState;
+use crate::storage::Vault;
+use crate::supervisor::supervise;
+use crate::systemd::{self, Watchdog};
+use crate::topic::PubsubProtocol;
+use crate::ui::{self, handle_user_input, publish_text, UiEvent};
+
+/// How long pipe mode waits for a peer subscribed to its topic before
+/// publishing anyway.
+const PIPE_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running messaging node.
+pub struct Node {
+    pub swarm: Swarm<Protocols>,
+    pub state: AppState,
+    events: UnboundedReceiver<AppEvent>,
+    /// The lines typed in the terminal UI or read from stdin.
+    lines: Receiver<String>,
+    input: Input,
+    /// The channel the UI is updated through, if there is one.
+    ui: Option<UnboundedSender<UiEvent>>,
+    /// The topic lines are published to in pipe mode.
+    pipe_topic: Option<String>,
+    /// The file the history is saved to on exit, when encrypted.
+    history_file: Option<(PathBuf, Arc<Vault>)>,
+    /// The clients following the messages of each topic.
+    subscribers: Subscribers,
+}
+
+impl Node {
+    /// Creates a node, listening and subscribed to the configured topics.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    /// * `local_key` - The identity keypair.
+    /// * `vault` - The vault, if storage encryption is on.
+    /// * `ui` - The channel the UI is updated through, if there is one.
+    pub async fn new(
+        config: &Config,
+        local_key: identity::Keypair,
+        vault: Option<Arc<Vault>>,
+        ui: Option<UnboundedSender<UiEvent>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let local_peer_id = local_key.public().to_peer_id();
+        let topic = config.pipe_topic.as_deref().unwrap_or(&config.topics[0]);
+        let swarm = create_swarm(
+            local_key.clone(),
+            local_peer_id,
+            topic,
+            config.pubsub_protocol,
+            &config.connection,
+        )
+        .await?;
+        Node::with_swarm(swarm, config, local_key, vault, ui)
+    }
+
+    /// Creates a node around a swarm subscribed to the first topic, such as
+    /// one over another transport, listening and subscribed to the other
+    /// configured topics.
+    ///
+    /// # Arguments
+    ///
+    /// * `swarm` - The swarm, made by `create_swarm` or alike.
+    /// * `config` - The configuration.
+    /// * `local_key` - The identity keypair of the swarm.
+    /// * `vault` - The vault, if storage encryption is on.
+    /// * `ui` - The channel the UI is updated through, if there is one.
+    pub fn with_swarm(
+        mut swarm: Swarm<Protocols>,
+        config: &Config,
+        local_key: identity::Keypair,
+        vault: Option<Arc<Vault>>,
+        ui: Option<UnboundedSender<UiEvent>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let local_peer_id = local_key.public().to_peer_id();
+        let topic = config.pipe_topic.as_deref().unwrap_or(&config.topics[0]);
+        listen_on(&mut swarm, &config.listen_addrs)?;
+        bootstrap(&mut swarm, &config.bootstrap);
+
+        // The UI, stdin, transfers and hangup signals all report to the loop.
+        let (app_events, events) = mpsc::unbounded_channel();
+        let (input, lines) = mpsc::channel(INPUT_CAPACITY);
+        let contacts = match config.dirs.as_ref().map(Dirs::contacts) {
+            Some(path) => Contacts::load(path.clone(), vault.clone())
+                .map_err(|e| format!("Failed to load contacts {:?}: {}", path, e))?,
+            None => Contacts::new(),
+        };
+        let mut state = AppState::new(local_key, config, contacts, app_events, ui.clone());
+        state.vault = vault.clone();
+        if let Some(path) = config.dirs.as_ref().map(Dirs::ignored) {
+            state.ignored = IgnoreList::load(path.clone(), vault.clone())
+                .map_err(|e| format!("Failed to load ignored peers {:?}: {}", path, e))?;
+        }
+        if let Some(path) = config.dirs.as_ref().map(Dirs::moderation) {
+            state.moderation = Moderation::load(path.clone(), vault.clone())
+                .map_err(|e| format!("Failed to load moderation state {:?}: {}", path, e))?;
+        }
+        if let Some(path) = config.dirs.as_ref().map(Dirs::reputation) {
+            let reputation = Reputation::load(path.clone(), vault.clone())
+                .map_err(|e| format!("Failed to load bans {:?}: {}", path, e))?;
+            if let Some(layer) = state.middleware.get_mut::<Reputation>() {
+                *layer = reputation;
+            }
+        }
+        // Plugins register their hooks here.
+        state.hooks.register(0, Box::new(TraceHook));
+        state.hooks.register(10, Box::new(BlankHook));
+        for hook in config.content_filter.hooks(&state.events) {
+            state.hooks.register(20, hook);
+        }
+        let subscribers = Subscribers::default();
+        // Runs last, so clients only see what the other hooks let through.
+        state
+            .hooks
+            .register(100, Box::new(IpcHook::new(subscribers.clone())));
+
+        state.topics.join(topic, config.pubsub_protocol);
+        if config.pipe_topic.is_none() {
+            for topic in &config.topics[1..] {
+                swarm
+                    .behaviour_mut()
+                    .subscribe(topic, config.pubsub_protocol)?;
+                state.topics.join(topic, config.pubsub_protocol);
+            }
+        }
+        // Inboxes use both protocols so any peer can reach them.
+        swarm
+            .behaviour_mut()
+            .subscribe(&inbox_topic(&local_peer_id), PubsubProtocol::Both)?;
+        swarm
+            .behaviour_mut()
+            .subscribe(PRESENCE_TOPIC, PubsubProtocol::Both)?;
+        // The history is only kept between runs when encrypted.
+        let history_file = vault
+            .zip(config.dirs.as_ref())
+            .map(|(vault, dirs)| (dirs.history(), vault));
+        if let Some((path, vault)) = &history_file {
+            // Fails rather than replacing a history it could not read on exit.
+            let saved = History::load(path, vault)
+                .map_err(|e| format!("Failed to load history {:?}: {}", path, e))?;
+            event::restore_history(saved, &mut state);
+            state.history.mark_clean();
+        }
+
+        Ok(Node {
+            swarm,
+            state,
+            events,
+            lines,
+            input,
+            ui,
+            pipe_topic: config.pipe_topic.clone(),
+            history_file,
+            subscribers,
+        })
+    }
+
+    /// Returns the channel driving the node.
+    pub fn events(&self) -> AppEvents {
+        self.state.events.clone()
+    }
+
+    /// Returns the channel the terminal UI and stdin send lines to, which
+    /// is not read while messages pile up.
+    pub fn input(&self) -> Input {
+        self.input.clone()
+    }
+
+    /// Returns a handle to the node, for bots and other programs.
+    pub fn client(&self) -> Client {
+        Client::new(self.events(), self.subscribers.clone())
+    }
+
+    /// Returns the clients following the messages of each topic, for the
+    /// servers of the daemon.
+    pub fn subscribers(&self) -> Subscribers {
+        self.subscribers.clone()
+    }
+
+    /// Runs the node until the user quits, the input is closed or a signal
+    /// asks it to stop, then leaves the topics and saves the history.
+    ///
+    /// # Returns
+    ///
+    /// The signal that stopped the node, if any.
+    pub async fn run(self) -> Option<Signal> {
+        let Node {
+            mut swarm,
+            mut state,
+            mut events,
+            mut lines,
+            // Kept so lines never reports the channel closed.
+            input: _input,
+            ui,
+            pipe_topic,
+            history_file,
+            subscribers: _,
+        } = self;
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        let local_peer_id = *swarm.local_peer_id();
+        let mut flush_ticker = tokio::time::interval(Duration::from_millis(10));
+        let started = Instant::now();
+        let mut watchdog = Watchdog::from_env();
+        let mut stopped_by = None;
+        // Whether stdin closed in pipe mode before a peer could receive.
+        let mut closed = false;
+        loop {
+            // Pipe mode holds lines back until a peer can receive them.
+            let ready = match &pipe_topic {
+                Some(topic) => {
+                    !swarm.behaviour().topic_peers(topic).is_empty()
+                        || started.elapsed() >= PIPE_PEER_TIMEOUT
+                }
+                None => true,
+            };
+            if closed && ready {
+                break;
+            }
+            tokio::select! {
+                Some(line) = lines.recv(), if ready && !state.outgoing.is_full() => {
+                    handle_line(line, pipe_topic.as_deref(), &mut swarm, &mut state).await;
+                    if state.quitting {
+                        break;
+                    }
+                    send_status(&ui, &swarm, &state);
+                }
+                event = events.recv(), if !closed => match event {
+                    Some(AppEvent::Input(line)) => {
+                        handle_line(line, pipe_topic.as_deref(), &mut swarm, &mut state).await;
+                        if state.quitting {
+                            break;
+                        }
+                        send_status(&ui, &swarm, &state);
+                    }
+                    // Pipe mode stops once the lines held back can go out.
+                    Some(AppEvent::InputClosed) | None if !ready => closed = true,
+                    // The user quit, or stdin was closed in pipe mode.
+                    Some(AppEvent::InputClosed) | None => break,
+                    Some(AppEvent::Transfer(event)) => event::handle_transfer_event(*event, &mut state),
+                    Some(AppEvent::Reload) => {
+                        if let Err(e) = reload::reload(&mut swarm, &mut state) {
+                            error!("Failed to reload the configuration: {}", e);
+                        }
+                    }
+                    Some(AppEvent::Maintain) => {
+                        maintenance::run(&mut swarm, &mut state, history_file.as_ref())
+                    }
+                    Some(AppEvent::Signal(signal)) => {
+                        info!("Received {}, shutting down", signal);
+                        stopped_by = Some(signal);
+                        break;
+                    }
+                    Some(AppEvent::Ipc(request, reply)) => {
+                        let _ = reply.send(ipc::handle(request, &mut swarm, &mut state));
+                        send_status(&ui, &swarm, &state);
+                    }
+                    Some(AppEvent::Email(peer, body)) => event::handle_email(peer, &body, &mut state),
+                    Some(AppEvent::Decided(id)) => event::handle_decided(id, &mut state),
+                    Some(AppEvent::Read(id)) => event::handle_read(id, &mut state),
+                },
+                event = swarm.next() => match event {
+                    Some(event) => {
+                        event::handle_event(event, &mut swarm, &mut state).await;
+                        send_status(&ui, &swarm, &state);
+                    }
+                    None => error!("Swarm stream closed"),
+                },
+                _ = ticker.tick() => {
+                    if let Some(recorder) = &mut state.recorder {
+                        recorder.tick();
+                    }
+                    event::handle_tick(&mut state);
+                    event::disconnect_banned(&mut swarm, &mut state);
+                    event::ping_peers(&mut swarm, &mut state);
+                    supervise(&mut swarm, &mut state);
+                    state.outbound.retry(swarm.behaviour(), &state.local_key);
+                    send_status(&ui, &swarm, &state);
+                    // Stops with the loop, so systemd restarts a hung node.
+                    if let Some(watchdog) = &mut watchdog {
+                        watchdog.tick();
+                    }
+                }
+                _ = flush_ticker.tick() => {
+                    while let Some((topic, body)) = state.outgoing.pop() {
+                        publish_text(body, &topic, &mut state);
+                    }
+                    state
+                        .outbound
+                        .flush(swarm.behaviour_mut(), &mut state.middleware, local_peer_id)
+                }
+            }
+        }
+        // Lines typed before the input closed are still sent, unpaced.
+        while let Ok(line) = lines.try_recv() {
+            handle_line(line, pipe_topic.as_deref(), &mut swarm, &mut state).await;
+        }
+        let pending: Vec<(String, String)> = state.outgoing.drain().collect();
+        for (topic, body) in pending {
+            publish_text(body, &topic, &mut state);
+        }
+
+        systemd::notify("STOPPING=1");
+        // Requests still waiting, and those sent from now on, are refused.
+        drop(events);
+        event::shutdown(&mut swarm, &mut state).await;
+        if let Some((path, vault)) = &history_file {
+            if let Err(e) = state.history.save(path, vault) {
+                error!("Failed to save history to {:?}: {}", path, e);
+            }
+        }
+        stopped_by
+    }
+}
+
+/// Handles a line typed by the user, published as it is in pipe mode.
+async fn handle_line(
+    line: String,
+    pipe_topic: Option<&str>,
+    swarm: &mut Swarm<Protocols>,
+    state: &mut AppState,
+) {
+    match pipe_topic {
+        Some(topic) => {
+            publish_text(line, topic, state);
+        }
+        None => handle_user_input(line, swarm, state).await,
+    }
+}
+
+/// Sends the status bar to the UI, if there is one.
+fn send_status(ui: &Option<UnboundedSender<UiEvent>>, swarm: &Swarm<Protocols>, state: &AppState) {
+    if let Some(ui) = ui {
+        let _ = ui.send(ui::status(swarm, state));
+    }
+}
@@ -0,0 +1,344 @@
+/*!
+ * Nostr module for the messaging application.
+ *
+ * A daemon can give topics a public shadow on Nostr: every message received
+ * on a mirrored topic is published to a relay as a kind-1 text note tagged
+ * with the topic, and the notes replying to the mirror are published back
+ * on the topic. The notes are signed by a key derived from the identity and
+ * the topic, so each topic has its own stable Nostr public key without a
+ * second secret to keep, and replies are told apart by the `p` tag naming
+ * that key. The signatures of replies are checked before they are
+ * published.
+ *
+ * A mirror reconnects to its relay with a growing delay whenever the
+ * connection fails, and messages received meanwhile are not mirrored. The
+ * mirror is built with the `nostr` feature.
+ */
+
+/// The domain keys of mirrors are derived with, followed by the topic.
+pub const KEY_DOMAIN: &str = "sec_msg nostr mirror ";
+
+#[cfg(feature = "nostr")]
+pub use bridge::mirror;
+
+#[cfg(feature = "nostr")]
+mod bridge {
+    use std::{
+        error::Error,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use futures::{SinkExt, Stream, StreamExt};
+    use k256::schnorr::{Signature, SigningKey, VerifyingKey};
+    use log::{debug, error, info, warn};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use tokio_tungstenite::{connect_async, tungstenite::Message as Frame};
+
+    use crate::client::{Client, Message};
+
+    /// The kind of short text notes.
+    const TEXT_NOTE: u32 = 1;
+
+    /// The ID of the subscription to replies on the relay.
+    const SUBSCRIPTION: &str = "sec_msg";
+
+    /// Longest note published on the topic, in bytes.
+    const MAX_CONTENT_LEN: usize = 4096;
+
+    /// First delay before reconnecting to a relay.
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Longest delay before reconnecting to a relay.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// An event of the Nostr protocol, as defined by NIP-01.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Event {
+        id: String,
+        pubkey: String,
+        created_at: u64,
+        kind: u32,
+        tags: Vec<Vec<String>>,
+        content: String,
+        sig: String,
+    }
+
+    impl Event {
+        /// Creates a text note signed with a key.
+        ///
+        /// # Arguments
+        ///
+        /// * `key` - The key of the mirror.
+        /// * `content` - The text of the note.
+        /// * `tags` - The tags of the note.
+        /// * `created_at` - When the note was written, in seconds since the
+        ///   epoch.
+        fn note(
+            key: &SigningKey,
+            content: String,
+            tags: Vec<Vec<String>>,
+            created_at: u64,
+        ) -> Result<Self, Box<dyn Error>> {
+            let pubkey = hex(&key.verifying_key().to_bytes());
+            let id = digest(&pubkey, created_at, TEXT_NOTE, &tags, &content);
+            let signature = key.sign_raw(&id, &rand::random())?;
+            Ok(Event {
+                id: hex(&id),
+                pubkey,
+                created_at,
+                kind: TEXT_NOTE,
+                tags,
+                content,
+                sig: hex(&signature.to_bytes()),
+            })
+        }
+
+        /// Returns whether the ID matches the contents and the signature the
+        /// ID.
+        fn verify(&self) -> bool {
+            let id = digest(
+                &self.pubkey,
+                self.created_at,
+                self.kind,
+                &self.tags,
+                &self.content,
+            );
+            let signed = || {
+                let key = VerifyingKey::from_bytes(&unhex(&self.pubkey)?).ok()?;
+                let signature = Signature::try_from(unhex(&self.sig)?.as_slice()).ok()?;
+                key.verify_raw(&id, &signature).ok()
+            };
+            unhex(&self.id).as_deref() == Some(&id[..]) && signed().is_some()
+        }
+    }
+
+    /// Computes the ID of an event, the hash of its serialized contents.
+    fn digest(
+        pubkey: &str,
+        created_at: u64,
+        kind: u32,
+        tags: &[Vec<String>],
+        content: &str,
+    ) -> [u8; 32] {
+        let serialized = json!([0, pubkey, created_at, kind, tags, content]).to_string();
+        Sha256::digest(serialized.as_bytes()).into()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn unhex(text: &str) -> Option<Vec<u8>> {
+        if !text.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+            .collect()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Starts mirroring a topic to a Nostr relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The handle to the node.
+    /// * `topic` - The topic mirrored.
+    /// * `relay` - The `ws://` or `wss://` URL of the relay.
+    /// * `secret` - The secret the key of the mirror is made from, derived
+    ///   from the identity with `KEY_DOMAIN`.
+    pub fn mirror(
+        client: Client,
+        topic: String,
+        relay: String,
+        secret: [u8; 32],
+    ) -> Result<(), Box<dyn Error>> {
+        let key = SigningKey::from_bytes(&secret)
+            .map_err(|e| format!("Failed to derive the Nostr key of {}: {}", topic, e))?;
+        info!(
+            "Mirroring {} to {} as {}",
+            topic,
+            relay,
+            hex(&key.verifying_key().to_bytes())
+        );
+        // Joining the topic waits for the swarm loop, which runs later.
+        tokio::spawn(async move {
+            let mut messages = match client.subscribe(&topic).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Failed to mirror {}: {}", topic, e);
+                    return;
+                }
+            };
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                match session(&client, &topic, &relay, &key, &mut messages, &mut backoff).await {
+                    // The node stopped.
+                    Ok(()) => return,
+                    Err(e) => warn!(
+                        "Nostr relay {} failed: {}, reconnecting in {:?}",
+                        relay, e, backoff
+                    ),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+        Ok(())
+    }
+
+    /// Mirrors a topic over one connection to the relay.
+    ///
+    /// # Returns
+    ///
+    /// `Ok` once the node stopped, or the error that ended the connection.
+    async fn session(
+        client: &Client,
+        topic: &str,
+        relay: &str,
+        key: &SigningKey,
+        messages: &mut (impl Stream<Item = Message> + Unpin),
+        backoff: &mut Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let (mut socket, _) = connect_async(relay).await?;
+        *backoff = MIN_BACKOFF;
+        let pubkey = hex(&key.verifying_key().to_bytes());
+        let filter = json!({ "kinds": [TEXT_NOTE], "#p": [pubkey], "since": now() });
+        let request = json!(["REQ", SUBSCRIPTION, filter]).to_string();
+        socket.send(Frame::Text(request)).await?;
+        debug!("Connected to Nostr relay {}", relay);
+        loop {
+            tokio::select! {
+                message = messages.next() => {
+                    let Some(message) = message else {
+                        let _ = socket.close(None).await;
+                        return Ok(());
+                    };
+                    let label = message.name.unwrap_or_else(|| message.peer.to_base58());
+                    let content = format!("{}: {}", label, message.body);
+                    let tags = vec![vec!["t".to_string(), topic.to_string()]];
+                    let event = Event::note(key, content, tags, now())?;
+                    socket.send(Frame::Text(json!(["EVENT", event]).to_string())).await?;
+                }
+                frame = socket.next() => match frame {
+                    Some(Ok(Frame::Text(text))) => receive(client, topic, &pubkey, &text).await?,
+                    Some(Ok(Frame::Close(_))) | None => return Err("the relay closed the connection".into()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                },
+            }
+        }
+    }
+
+    /// Handles a message of the relay, publishing the replies to the mirror.
+    async fn receive(
+        client: &Client,
+        topic: &str,
+        pubkey: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let message: Vec<Value> = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("Ignoring malformed message of a Nostr relay: {}", e);
+                return Ok(());
+            }
+        };
+        match message.first().and_then(Value::as_str) {
+            Some("EVENT") => {
+                let Some(event) = message
+                    .get(2)
+                    .and_then(|event| Event::deserialize(event).ok())
+                else {
+                    return Ok(());
+                };
+                if event.pubkey == pubkey || event.kind != TEXT_NOTE {
+                    return Ok(());
+                }
+                if event.content.len() > MAX_CONTENT_LEN || !event.verify() {
+                    debug!("Ignoring Nostr event {} on {}", event.id, topic);
+                    return Ok(());
+                }
+                let author = event.pubkey.get(..16).unwrap_or(&event.pubkey);
+                let body = format!("nostr:{}: {}", author, event.content);
+                client.publish(topic, body).await?;
+            }
+            Some("OK") if message.get(2) == Some(&Value::Bool(false)) => warn!(
+                "Nostr relay refused a note of {}: {}",
+                topic,
+                message.get(3).and_then(Value::as_str).unwrap_or_default()
+            ),
+            Some("NOTICE") => info!(
+                "Nostr relay notice: {}",
+                message.get(1).and_then(Value::as_str).unwrap_or_default()
+            ),
+            Some("CLOSED") => {
+                let reason = message.get(2).and_then(Value::as_str).unwrap_or_default();
+                return Err(format!("the relay closed the subscription: {}", reason).into());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use k256::schnorr::SigningKey;
+
+        use super::{digest, hex, unhex, Event};
+
+        #[test]
+        fn test_event() {
+            let key = SigningKey::from_bytes(&[7; 32]).unwrap();
+            let tags = vec![vec!["t".to_string(), "chat".to_string()]];
+            let event = Event::note(&key, "alice: hi".to_string(), tags, 1_700_000_000).unwrap();
+            assert!(event.verify());
+            assert_eq!(event.pubkey.len(), 64);
+            assert_eq!(event.sig.len(), 128);
+            assert_eq!(
+                unhex(&event.id).unwrap(),
+                digest(
+                    &event.pubkey,
+                    event.created_at,
+                    1,
+                    &event.tags,
+                    &event.content
+                )
+            );
+            assert_eq!(unhex(&hex(&[0, 171, 255])).unwrap(), vec![0, 171, 255]);
+            assert!(unhex("abc").is_none());
+
+            let mut forged = event.clone();
+            forged.content = "alice: send me your keys".to_string();
+            assert!(!forged.verify());
+            let mut unsigned = event.clone();
+            unsigned.sig = "00".repeat(64);
+            assert!(!unsigned.verify());
+
+            // Events travel as the JSON objects of NIP-01.
+            let json = serde_json::to_value(&event).unwrap();
+            assert_eq!(json["kind"], 1);
+            assert_eq!(json["tags"][0][1], "chat");
+        }
+    }
+}
+
+/// Fails, as the Nostr mirror is built with the `nostr` feature.
+#[cfg(not(feature = "nostr"))]
+pub fn mirror(
+    _client: crate::client::Client,
+    _topic: String,
+    _relay: String,
+    _secret: [u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without the Nostr mirror, rebuild with --features nostr".into())
+}
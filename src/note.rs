@@ -0,0 +1,300 @@
+/*!
+ * Shared note module for the messaging application.
+ *
+ * This module implements the text CRDT backing shared note topics. A note
+ * is a replicated growable array (RGA): every character has a unique ID
+ * made of a Lamport counter and the ID of the inserting peer, and is placed
+ * after the character it was typed after. Deleted characters are kept as
+ * tombstones, so concurrent edits applied in any order converge to the same
+ * text. Operations whose dependencies have not arrived yet are buffered.
+ */
+
+use std::collections::{HashMap, VecDeque};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the shared note topics.
+const NOTE_PREFIX: &str = "note/";
+
+/// Maximum number of operations buffered while waiting for their dependencies.
+pub const MAX_PENDING_OPS: usize = 10_000;
+
+/// Returns the topic backing the shared note with the specified name.
+pub fn note_topic(name: &str) -> String {
+    format!("{}{}", NOTE_PREFIX, name)
+}
+
+/// Returns whether a topic backs a shared note.
+pub fn is_note_topic(topic: &str) -> bool {
+    topic.starts_with(NOTE_PREFIX)
+}
+
+/// Unique identifier of a character in a note.
+///
+/// IDs are ordered by counter first, so later insertions sort higher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CharId {
+    pub counter: u64,
+    pub site: PeerId,
+}
+
+/// An edit of a shared note.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoteOp {
+    /// Inserts a character after another one, or at the start.
+    Insert {
+        id: CharId,
+        after: Option<CharId>,
+        ch: char,
+    },
+    /// Deletes a character.
+    Delete { id: CharId },
+}
+
+/// A character of a note, kept as a tombstone once deleted.
+struct Element {
+    id: CharId,
+    after: Option<CharId>,
+    ch: char,
+    deleted: bool,
+}
+
+/// A replica of a shared note.
+pub struct Note {
+    site: PeerId,
+    clock: u64,
+    elements: Vec<Element>,
+    positions: HashMap<CharId, usize>,
+    pending: VecDeque<NoteOp>,
+}
+
+impl Note {
+    /// Creates a new, empty note edited by the local peer `site`.
+    pub fn new(site: PeerId) -> Self {
+        Note {
+            site,
+            clock: 0,
+            elements: Vec::new(),
+            positions: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the visible text of the note.
+    pub fn text(&self) -> String {
+        self.elements
+            .iter()
+            .filter(|element| !element.deleted)
+            .map(|element| element.ch)
+            .collect()
+    }
+
+    /// Inserts text at a character index of the visible text.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The character index, clamped to the length of the text.
+    /// * `text` - The text to insert.
+    ///
+    /// # Returns
+    ///
+    /// The operations to broadcast.
+    pub fn insert(&mut self, index: usize, text: &str) -> Vec<NoteOp> {
+        let mut after = index
+            .checked_sub(1)
+            .and_then(|index| self.visible(index).or_else(|| self.visible_last()))
+            .map(|position| self.elements[position].id);
+        let mut ops = Vec::new();
+        for ch in text.chars() {
+            self.clock += 1;
+            let id = CharId {
+                counter: self.clock,
+                site: self.site,
+            };
+            let op = NoteOp::Insert { id, after, ch };
+            self.integrate(&op);
+            ops.push(op);
+            after = Some(id);
+        }
+        ops
+    }
+
+    /// Deletes a range of the visible text.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The character index of the first deleted character.
+    /// * `len` - The number of characters to delete.
+    ///
+    /// # Returns
+    ///
+    /// The operations to broadcast.
+    pub fn delete(&mut self, index: usize, len: usize) -> Vec<NoteOp> {
+        let ids: Vec<CharId> = self
+            .elements
+            .iter()
+            .filter(|element| !element.deleted)
+            .skip(index)
+            .take(len)
+            .map(|element| element.id)
+            .collect();
+        ids.into_iter()
+            .map(|id| {
+                let op = NoteOp::Delete { id };
+                self.integrate(&op);
+                op
+            })
+            .collect()
+    }
+
+    /// Applies operations received from other peers.
+    ///
+    /// # Returns
+    ///
+    /// Whether the visible text changed.
+    pub fn apply(&mut self, ops: Vec<NoteOp>) -> bool {
+        let before = self.text();
+        self.pending.extend(ops);
+        // Retry buffered operations until none of them can be integrated.
+        loop {
+            let count = self.pending.len();
+            let pending: Vec<NoteOp> = self.pending.drain(..).collect();
+            for op in pending {
+                if !self.integrate(&op) {
+                    self.pending.push_back(op);
+                }
+            }
+            if self.pending.len() == count {
+                break;
+            }
+        }
+        while self.pending.len() > MAX_PENDING_OPS {
+            self.pending.pop_front();
+        }
+        self.text() != before
+    }
+
+    /// Returns the operations rebuilding the whole note, in document order.
+    pub fn snapshot(&self) -> Vec<NoteOp> {
+        let inserts = self.elements.iter().map(|element| NoteOp::Insert {
+            id: element.id,
+            after: element.after,
+            ch: element.ch,
+        });
+        let deletes = self
+            .elements
+            .iter()
+            .filter(|element| element.deleted)
+            .map(|element| NoteOp::Delete { id: element.id });
+        inserts.chain(deletes).collect()
+    }
+
+    /// Integrates a single operation.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the operation depends on a character not received yet.
+    fn integrate(&mut self, op: &NoteOp) -> bool {
+        match op {
+            NoteOp::Insert { id, after, ch } => {
+                if self.positions.contains_key(id) {
+                    return true;
+                }
+                let mut position = match after {
+                    None => 0,
+                    Some(after) => match self.positions.get(after) {
+                        Some(position) => position + 1,
+                        None => return false,
+                    },
+                };
+                // Concurrent insertions after the same character are ordered
+                // by descending ID; later descendants of those all have
+                // higher IDs and are skipped with them.
+                while position < self.elements.len() && self.elements[position].id > *id {
+                    position += 1;
+                }
+                self.elements.insert(
+                    position,
+                    Element {
+                        id: *id,
+                        after: *after,
+                        ch: *ch,
+                        deleted: false,
+                    },
+                );
+                for (index, element) in self.elements.iter().enumerate().skip(position) {
+                    self.positions.insert(element.id, index);
+                }
+                self.clock = self.clock.max(id.counter);
+                true
+            }
+            NoteOp::Delete { id } => match self.positions.get(id) {
+                Some(&position) => {
+                    self.elements[position].deleted = true;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Returns the element position of the visible character at `index`.
+    fn visible(&self, index: usize) -> Option<usize> {
+        self.elements
+            .iter()
+            .enumerate()
+            .filter(|(_, element)| !element.deleted)
+            .nth(index)
+            .map(|(position, _)| position)
+    }
+
+    /// Returns the element position of the last visible character.
+    fn visible_last(&self) -> Option<usize> {
+        self.elements.iter().rposition(|element| !element.deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{is_note_topic, note_topic, Note};
+
+    #[test]
+    fn test_local_edits() {
+        let mut note = Note::new(PeerId::random());
+        note.insert(0, "hello world");
+        note.delete(5, 6);
+        note.insert(5, ", there");
+        note.insert(100, "!");
+        note.insert(0, ">");
+        assert_eq!(note.text(), ">hello, there!");
+        assert!(is_note_topic(&note_topic("plans")));
+    }
+
+    #[test]
+    fn test_concurrent_edits_converge() {
+        let mut alice = Note::new(PeerId::random());
+        let mut bob = Note::new(PeerId::random());
+        let base = alice.insert(0, "ac");
+        bob.apply(base);
+
+        let from_alice = alice.insert(1, "b");
+        let mut from_bob = bob.insert(1, "xy");
+        from_bob.extend(bob.delete(0, 1));
+
+        // Deliver in different orders, including operations before their
+        // dependencies.
+        let mut reversed = from_bob.clone();
+        reversed.reverse();
+        alice.apply(reversed);
+        bob.apply(from_alice);
+        assert_eq!(alice.text(), bob.text());
+        assert_eq!(alice.text().len(), 4);
+
+        let mut carol = Note::new(PeerId::random());
+        carol.apply(alice.snapshot());
+        assert_eq!(carol.text(), alice.text());
+    }
+}
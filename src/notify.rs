@@ -0,0 +1,243 @@
+/*!
+ * Desktop notification module for the messaging application.
+ *
+ * Direct messages and mentions of the local display name or of a watched
+ * keyword raise a native desktop notification while the terminal UI is not
+ * focused. Notifications can be turned off, silenced for a while with
+ * do-not-disturb, muted for single topics, or kept to direct messages.
+ * Builds without the `notifications` feature only log them.
+ */
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use log::debug;
+
+/// Decides when to raise desktop notifications, and raises them.
+pub struct Notifier {
+    enabled: bool,
+    do_not_disturb: bool,
+    /// Whether mentions and watched keywords raise notifications.
+    highlights: bool,
+    muted: HashSet<String>,
+    /// Watched keywords, highlighted like mentions.
+    keywords: Vec<String>,
+    /// Whether the terminal has focus, as reported by the terminal UI.
+    focused: Arc<AtomicBool>,
+}
+
+impl Notifier {
+    /// Creates a new `Notifier`, assuming the terminal has focus.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether notifications are raised at all.
+    /// * `keywords` - The watched keywords.
+    pub fn new(enabled: bool, keywords: Vec<String>) -> Self {
+        Notifier {
+            enabled,
+            do_not_disturb: false,
+            highlights: true,
+            muted: HashSet::new(),
+            keywords,
+            focused: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Returns the focus flag the terminal UI keeps up to date.
+    pub fn focus(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.focused)
+    }
+
+    /// Turns notifications on or off.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Turns do-not-disturb on or off.
+    pub fn set_do_not_disturb(&mut self, do_not_disturb: bool) {
+        self.do_not_disturb = do_not_disturb;
+    }
+
+    /// Turns notifications for mentions and watched keywords on or off.
+    pub fn set_highlights(&mut self, highlights: bool) {
+        self.highlights = highlights;
+    }
+
+    /// Returns whether mentions and watched keywords raise notifications.
+    pub fn highlights(&self) -> bool {
+        self.highlights
+    }
+
+    /// Starts highlighting messages containing a keyword.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the keyword was already watched.
+    pub fn watch(&mut self, keyword: &str) -> bool {
+        if self
+            .keywords
+            .iter()
+            .any(|k| k.eq_ignore_ascii_case(keyword))
+        {
+            return false;
+        }
+        self.keywords.push(keyword.to_string());
+        true
+    }
+
+    /// Stops highlighting messages containing a keyword.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the keyword was not watched.
+    pub fn unwatch(&mut self, keyword: &str) -> bool {
+        let len = self.keywords.len();
+        self.keywords.retain(|k| !k.eq_ignore_ascii_case(keyword));
+        self.keywords.len() != len
+    }
+
+    /// Replaces the watched keywords, such as when the configuration is
+    /// reloaded.
+    pub fn set_keywords(&mut self, keywords: Vec<String>) {
+        self.keywords = keywords;
+    }
+
+    /// Returns the watched keywords.
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Returns what a message is highlighted for: the local display name
+    /// if it is mentioned, or else the first watched keyword it contains.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The message text.
+    /// * `name` - The local display name, if any.
+    pub fn highlight<'a>(&'a self, body: &str, name: Option<&'a str>) -> Option<&'a str> {
+        name.into_iter()
+            .chain(self.keywords.iter().map(String::as_str))
+            .find(|word| mentions(body, word))
+    }
+
+    /// Stops notifying about a topic.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the topic was already muted.
+    pub fn mute(&mut self, topic: &str) -> bool {
+        self.muted.insert(topic.to_string())
+    }
+
+    /// Notifies about a muted topic again.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the topic was not muted.
+    pub fn unmute(&mut self, topic: &str) -> bool {
+        self.muted.remove(topic)
+    }
+
+    /// Returns whether a message on a topic should raise a notification.
+    pub fn should_notify(&self, topic: &str) -> bool {
+        self.enabled
+            && !self.do_not_disturb
+            && !self.muted.contains(topic)
+            && !self.focused.load(Ordering::Relaxed)
+    }
+
+    /// Raises a desktop notification without blocking the caller.
+    ///
+    /// # Arguments
+    ///
+    /// * `summary` - The notification title.
+    /// * `body` - The notification text.
+    pub fn notify(&self, summary: String, body: String) {
+        debug!("Notification: {}: {}", summary, body);
+        #[cfg(feature = "notifications")]
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = notify_rust::Notification::new()
+                .appname("sec_msg")
+                .summary(&summary)
+                .body(&body)
+                .show()
+            {
+                debug!("Failed to show notification: {}", e);
+            }
+        });
+    }
+}
+
+/// Returns whether a message mentions a display name, as a whole word
+/// optionally prefixed with `@`, ignoring case.
+///
+/// # Arguments
+///
+/// * `body` - The message text.
+/// * `name` - The mentioned display name.
+pub fn mentions(body: &str, name: &str) -> bool {
+    body.split(|c: char| !(c.is_alphanumeric() || c == '@' || c == '_' || c == '-'))
+        .map(|word| word.trim_start_matches('@'))
+        .any(|word| word.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{mentions, Notifier};
+
+    #[test]
+    fn test_should_notify() {
+        let mut notifier = Notifier::new(true, Vec::new());
+        assert!(!notifier.should_notify("chat"));
+
+        notifier.focus().store(false, Ordering::Relaxed);
+        assert!(notifier.should_notify("chat"));
+        assert!(notifier.mute("chat"));
+        assert!(!notifier.should_notify("chat"));
+        assert!(notifier.should_notify("rust"));
+
+        notifier.set_do_not_disturb(true);
+        assert!(!notifier.should_notify("rust"));
+    }
+
+    #[test]
+    fn test_highlight() {
+        let mut notifier = Notifier::new(true, vec!["deploy".to_string()]);
+        assert_eq!(
+            notifier.highlight("@alice the deploy failed", Some("alice")),
+            Some("alice")
+        );
+        assert_eq!(
+            notifier.highlight("Deploy failed", Some("alice")),
+            Some("deploy")
+        );
+        assert_eq!(notifier.highlight("redeploy failed", None), None);
+
+        assert!(notifier.watch("outage"));
+        assert!(!notifier.watch("Outage"));
+        assert_eq!(notifier.highlight("outage!", None), Some("outage"));
+        assert!(notifier.unwatch("OUTAGE"));
+        assert!(!notifier.unwatch("outage"));
+        assert_eq!(notifier.keywords(), ["deploy".to_string()]);
+
+        notifier.set_keywords(vec!["release".to_string()]);
+        assert_eq!(notifier.highlight("deploy failed", None), None);
+        assert_eq!(notifier.highlight("release day", None), Some("release"));
+    }
+
+    #[test]
+    fn test_mentions() {
+        assert!(mentions("hey @Alice, look", "alice"));
+        assert!(mentions("alice: look", "alice"));
+        assert!(!mentions("malice aforethought", "alice"));
+        assert!(!mentions("alice-bob", "alice"));
+    }
+}
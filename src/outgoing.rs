@@ -0,0 +1,175 @@
+/*!
+ * Outgoing module for the messaging application.
+ *
+ * Lines typed in the terminal UI or read from stdin reach the swarm loop
+ * over a bounded channel, and the chat messages among them wait in the
+ * `Outgoing` queue, which publishes them no faster than the rate limits
+ * of the node, as peers with the same settings limit it. A pasted block of
+ * text would otherwise go out as a burst of messages most peers drop.
+ * While messages wait, the lines typed to the same topic after them are
+ * coalesced into one message, up to what peers show of a message. Once the
+ * queue is full the loop stops reading lines, so the channel fills and the
+ * terminal UI shows the lines it holds back as sending until there is room
+ * again, and stdin is no longer read.
+ */
+
+use std::collections::VecDeque;
+
+use libp2p::PeerId;
+use tokio::sync::mpsc;
+
+use crate::rate_limit::{Limits, MessageClass, RateLimiter};
+use crate::security::{MAX_RENDERED_LEN, MAX_RENDERED_LINES};
+
+/// Lines the channel to the swarm loop holds.
+pub const INPUT_CAPACITY: usize = 32;
+
+/// Most messages waiting before lines are no longer read.
+const MAX_PENDING: usize = 16;
+
+/// The channel lines typed by the user are sent to the swarm loop on.
+pub type Input = mpsc::Sender<String>;
+
+/// A message waiting to be published.
+struct Pending {
+    topic: String,
+    body: String,
+    lines: usize,
+}
+
+/// Chat messages waiting to be published at the pace peers accept.
+pub struct Outgoing {
+    pending: VecDeque<Pending>,
+    /// Paces the messages of each topic as peers limit them.
+    pacer: RateLimiter,
+    peer: PeerId,
+}
+
+impl Outgoing {
+    /// Creates an empty queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The rate limits of the node, applied to its own messages.
+    /// * `peer` - The local peer ID.
+    pub fn new(limits: Limits, peer: PeerId) -> Self {
+        Outgoing {
+            pending: VecDeque::new(),
+            pacer: RateLimiter::new(limits, MAX_PENDING),
+            peer,
+        }
+    }
+
+    /// Changes the rate limits, after the configuration was reloaded.
+    pub fn reconfigure(&mut self, limits: Limits) {
+        self.pacer.reconfigure(limits, MAX_PENDING);
+    }
+
+    /// Queues a line, coalescing it with the last message waiting if it
+    /// goes to the same topic and both fit in one message.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic to publish to.
+    /// * `line` - The line typed.
+    pub fn push(&mut self, topic: &str, line: String) {
+        let lines = line.lines().count().max(1);
+        if let Some(last) = self.pending.back_mut().filter(|last| {
+            last.topic == topic
+                && last.lines + lines <= MAX_RENDERED_LINES
+                && last.body.chars().count() + 1 + line.chars().count() <= MAX_RENDERED_LEN
+        }) {
+            last.body.push('\n');
+            last.body.push_str(&line);
+            last.lines += lines;
+            return;
+        }
+        self.pending.push_back(Pending {
+            topic: topic.to_string(),
+            body: line,
+            lines,
+        });
+    }
+
+    /// Returns the number of messages waiting.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns whether no message is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns whether lines should no longer be read.
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= MAX_PENDING
+    }
+
+    /// Removes the next message, if peers would accept it now.
+    ///
+    /// # Returns
+    ///
+    /// The topic and body of the message.
+    pub fn pop(&mut self) -> Option<(String, String)> {
+        let first = self.pending.front()?;
+        if !self
+            .pacer
+            .check(self.peer, &first.topic, MessageClass::Chat)
+        {
+            return None;
+        }
+        self.pending
+            .pop_front()
+            .map(|pending| (pending.topic, pending.body))
+    }
+
+    /// Removes every message waiting, when the node stops.
+    pub fn drain(&mut self) -> impl Iterator<Item = (String, String)> + '_ {
+        self.pending
+            .drain(..)
+            .map(|pending| (pending.topic, pending.body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{Outgoing, MAX_PENDING};
+    use crate::rate_limit::{Limit, Limits};
+    use crate::security::MAX_RENDERED_LINES;
+
+    #[test]
+    fn test_pace_and_coalesce() {
+        let mut outgoing = Outgoing::new(Limits::new(Limit::new(60, 2)), PeerId::random());
+        for line in ["one", "two"] {
+            outgoing.push("chat", line.to_string());
+            assert_eq!(outgoing.pop(), Some(("chat".to_string(), line.to_string())));
+        }
+        // Out of tokens, the lines wait and are coalesced by topic.
+        outgoing.push("chat", "three".to_string());
+        outgoing.push("chat", "four".to_string());
+        outgoing.push("other", "five".to_string());
+        outgoing.push("chat", "six".to_string());
+        assert_eq!(outgoing.len(), 3);
+        assert_eq!(outgoing.pop(), None);
+        let drained: Vec<_> = outgoing.drain().collect();
+        assert_eq!(
+            drained,
+            [
+                ("chat".to_string(), "three\nfour".to_string()),
+                ("other".to_string(), "five".to_string()),
+                ("chat".to_string(), "six".to_string()),
+            ]
+        );
+        assert!(outgoing.is_empty());
+
+        // Messages hold no more lines than peers show.
+        for _ in 0..MAX_RENDERED_LINES * MAX_PENDING {
+            outgoing.push("chat", "line".to_string());
+        }
+        assert_eq!(outgoing.len(), MAX_PENDING);
+        assert!(outgoing.is_full());
+    }
+}
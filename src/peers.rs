@@ -0,0 +1,255 @@
+/*!
+ * Peer table module for the messaging application.
+ *
+ * This module keeps what the swarm reported about each connected peer: the
+ * address of its first connection, when it connected, and the round trip
+ * time measured by the ping protocol, a request-response protocol echoing a
+ * nonce. Connected peers are pinged periodically. It also remembers when
+ * peers were first and last seen, connected or through their messages, and
+ * tells watchers, such as apps embedding the node, when peers come and go.
+ */
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
+
+use libp2p::{request_response::OutboundRequestId, Multiaddr, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use web_time::Instant;
+
+/// Protocol name of the ping protocol.
+pub const PING_PROTOCOL: StreamProtocol = StreamProtocol::new("/sec_msg/ping/1.0.0");
+
+/// How often connected peers are pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Maximum number of peers remembered as seen.
+const MAX_SEEN_PEERS: usize = 10_000;
+
+/// Asks a peer to echo a nonce.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ping(pub u64);
+
+/// The nonce of a `Ping`, echoed back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pong(pub u64);
+
+/// What is known about a connected peer.
+pub struct ConnectedPeer {
+    /// Remote address of the first connection.
+    pub address: Multiaddr,
+    pub connected_at: Instant,
+    /// Latest round trip time, once a ping was answered.
+    pub rtt: Option<Duration>,
+}
+
+/// When a peer was seen.
+pub struct Seen {
+    pub first: Instant,
+    pub last: Instant,
+}
+
+/// A peer connecting or disconnecting, as told to watchers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Connected { peer: PeerId, address: Multiaddr },
+    Disconnected { peer: PeerId },
+}
+
+/// Tracks the connected peers and the pings sent to them.
+pub struct PeerTable {
+    peers: HashMap<PeerId, ConnectedPeer>,
+    pings: HashMap<OutboundRequestId, (u64, Instant)>,
+    last_ping: Instant,
+    seen: HashMap<PeerId, Seen>,
+    watchers: Vec<UnboundedSender<PeerEvent>>,
+}
+
+impl PeerTable {
+    /// Creates a new, empty `PeerTable`.
+    pub fn new() -> Self {
+        PeerTable {
+            peers: HashMap::new(),
+            pings: HashMap::new(),
+            last_ping: Instant::now(),
+            seen: HashMap::new(),
+            watchers: Vec::new(),
+        }
+    }
+
+    /// Returns a channel told when peers connect and disconnect from now on.
+    pub fn watch(&mut self) -> UnboundedReceiver<PeerEvent> {
+        let (watcher, events) = mpsc::unbounded_channel();
+        self.watchers.push(watcher);
+        events
+    }
+
+    /// Tells the watchers about a peer, forgetting those that went away.
+    fn notify(&mut self, event: PeerEvent) {
+        self.watchers
+            .retain(|watcher| watcher.send(event.clone()).is_ok());
+    }
+
+    /// Records a connection to a peer, keeping the first one if several are
+    /// established.
+    pub fn connected(&mut self, peer: PeerId, address: Multiaddr) {
+        self.saw(peer);
+        if let Entry::Vacant(entry) = self.peers.entry(peer) {
+            entry.insert(ConnectedPeer {
+                address: address.clone(),
+                connected_at: Instant::now(),
+                rtt: None,
+            });
+            self.notify(PeerEvent::Connected { peer, address });
+        }
+    }
+
+    /// Forgets a peer whose last connection closed.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.saw(*peer);
+        if self.peers.remove(peer).is_some() {
+            self.notify(PeerEvent::Disconnected { peer: *peer });
+        }
+    }
+
+    /// Records that a peer was seen now, forgetting the peer seen least
+    /// recently when too many are remembered.
+    pub fn saw(&mut self, peer: PeerId) {
+        let now = Instant::now();
+        if let Some(seen) = self.seen.get_mut(&peer) {
+            seen.last = now;
+            return;
+        }
+        if self.seen.len() >= MAX_SEEN_PEERS {
+            if let Some(oldest) = self
+                .seen
+                .iter()
+                .min_by_key(|(_, seen)| seen.last)
+                .map(|(peer, _)| *peer)
+            {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(
+            peer,
+            Seen {
+                first: now,
+                last: now,
+            },
+        );
+    }
+
+    /// Returns when a peer was seen, if it was.
+    pub fn seen(&self, peer: &PeerId) -> Option<&Seen> {
+        self.seen.get(peer)
+    }
+
+    /// Returns whether connected peers should be pinged again, restarting
+    /// the interval if so.
+    pub fn ping_due(&mut self) -> bool {
+        if self.last_ping.elapsed() < PING_INTERVAL {
+            return false;
+        }
+        self.last_ping = Instant::now();
+        true
+    }
+
+    /// Records a ping sent to a peer.
+    pub fn ping_sent(&mut self, request_id: OutboundRequestId, nonce: u64) {
+        self.pings.insert(request_id, (nonce, Instant::now()));
+    }
+
+    /// Applies the answer to a ping.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer that answered.
+    /// * `request_id` - The ping request ID.
+    /// * `pong` - The answer.
+    ///
+    /// # Returns
+    ///
+    /// The measured round trip time, if the answer matches the ping.
+    pub fn pong(
+        &mut self,
+        peer: &PeerId,
+        request_id: &OutboundRequestId,
+        pong: &Pong,
+    ) -> Option<Duration> {
+        let (nonce, sent_at) = self.pings.remove(request_id)?;
+        if nonce != pong.0 {
+            return None;
+        }
+        let rtt = sent_at.elapsed();
+        if let Some(connected) = self.peers.get_mut(peer) {
+            connected.rtt = Some(rtt);
+        }
+        Some(rtt)
+    }
+
+    /// Forgets a ping that failed.
+    pub fn ping_failed(&mut self, request_id: &OutboundRequestId) {
+        self.pings.remove(request_id);
+    }
+
+    /// Returns the connected peers, ordered by peer ID.
+    pub fn peers(&self) -> Vec<(&PeerId, &ConnectedPeer)> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(peer, _)| **peer);
+        peers
+    }
+
+    /// Returns what is known about a connected peer.
+    pub fn get(&self, peer: &PeerId) -> Option<&ConnectedPeer> {
+        self.peers.get(peer)
+    }
+
+    /// Returns whether a peer is connected.
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        PeerTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{Multiaddr, PeerId};
+
+    use super::{PeerEvent, PeerTable};
+
+    #[test]
+    fn test_connections() {
+        let mut table = PeerTable::new();
+        let mut events = table.watch();
+        let peer = PeerId::random();
+        let first: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let second: Multiaddr = "/ip4/127.0.0.1/tcp/4002".parse().unwrap();
+        table.connected(peer, first.clone());
+        table.connected(peer, second);
+        assert_eq!(table.peers().len(), 1);
+        assert_eq!(table.peers()[0].1.address, first);
+        assert!(!table.ping_due());
+
+        table.disconnected(&peer);
+        assert!(!table.is_connected(&peer));
+        assert_eq!(
+            events.try_recv(),
+            Ok(PeerEvent::Connected {
+                peer,
+                address: first
+            })
+        );
+        assert_eq!(events.try_recv(), Ok(PeerEvent::Disconnected { peer }));
+        assert!(events.try_recv().is_err());
+        let seen = table.seen(&peer).unwrap();
+        assert!(seen.first <= seen.last);
+        assert!(table.seen(&PeerId::random()).is_none());
+    }
+}
@@ -0,0 +1,235 @@
+/*!
+ * Presence module for the messaging application.
+ *
+ * Every node periodically publishes a signed presence beacon carrying its
+ * status and capabilities on the presence topic. This module tracks the
+ * beacons received, so peers that stop sending them are considered gone
+ * even while a connection to them is still open.
+ */
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+/// Topic presence beacons are published on.
+pub const PRESENCE_TOPIC: &str = "presence";
+
+/// How often a presence beacon is published.
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a peer is considered present after its last beacon.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Features announced by this build.
+pub const CAPABILITIES: &[&str] = &[
+    "receipts",
+    "file-transfer",
+    "history",
+    "notes",
+    "moderation",
+];
+
+/// Status announced in a presence beacon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+impl fmt::Display for PresenceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceStatus::Online => write!(f, "online"),
+            PresenceStatus::Away => write!(f, "away"),
+            PresenceStatus::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// A presence beacon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    pub capabilities: Vec<String>,
+}
+
+/// Last known presence of a peer.
+pub struct PeerPresence {
+    pub status: PresenceStatus,
+    pub capabilities: Vec<String>,
+    pub name: Option<String>,
+    pub last_seen: Instant,
+}
+
+/// Tracks the presence of peers and when to announce our own.
+pub struct PresenceTracker {
+    peers: HashMap<PeerId, PeerPresence>,
+    status: PresenceStatus,
+    announced_at: Option<Instant>,
+}
+
+impl PresenceTracker {
+    /// Creates a new `PresenceTracker` with the local status set to online.
+    pub fn new() -> Self {
+        PresenceTracker {
+            peers: HashMap::new(),
+            status: PresenceStatus::Online,
+            announced_at: None,
+        }
+    }
+
+    /// Returns the beacon announcing the local status.
+    pub fn beacon(&self) -> Presence {
+        Presence {
+            status: self.status,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Sets the local status and schedules an immediate beacon.
+    pub fn set_status(&mut self, status: PresenceStatus) {
+        self.status = status;
+        self.announced_at = None;
+    }
+
+    /// Schedules an immediate beacon, e.g. after connecting to a new peer.
+    pub fn announce_soon(&mut self) {
+        self.announced_at = None;
+    }
+
+    /// Returns whether a beacon is due and, if so, records it as sent.
+    pub fn beacon_due(&mut self) -> bool {
+        let due = self
+            .announced_at
+            .is_none_or(|announced_at| announced_at.elapsed() >= PRESENCE_INTERVAL);
+        if due {
+            self.announced_at = Some(Instant::now());
+        }
+        due
+    }
+
+    /// Records a beacon received from a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer that signed the beacon.
+    /// * `presence` - The beacon.
+    /// * `name` - The display name claimed by the peer, if any.
+    ///
+    /// # Returns
+    ///
+    /// The previous status of the peer, if it was present.
+    pub fn update(
+        &mut self,
+        peer: PeerId,
+        presence: Presence,
+        name: Option<String>,
+    ) -> Option<PresenceStatus> {
+        if presence.status == PresenceStatus::Offline {
+            return self.peers.remove(&peer).map(|previous| previous.status);
+        }
+        self.peers
+            .insert(
+                peer,
+                PeerPresence {
+                    status: presence.status,
+                    capabilities: presence.capabilities,
+                    name,
+                    last_seen: Instant::now(),
+                },
+            )
+            .map(|previous| previous.status)
+    }
+
+    /// Stops tracking peers without a beacon for `timeout`.
+    ///
+    /// # Returns
+    ///
+    /// The peers that are no longer present.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, presence)| presence.last_seen.elapsed() >= timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &expired {
+            self.peers.remove(peer);
+        }
+        expired
+    }
+
+    /// Returns the presence of a peer, if it is present.
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerPresence> {
+        self.peers.get(peer)
+    }
+
+    /// Returns the present peers, ordered by peer ID.
+    pub fn peers(&self) -> Vec<(&PeerId, &PeerPresence)> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(peer, _)| **peer);
+        peers
+    }
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        PresenceTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libp2p::PeerId;
+
+    use super::{Presence, PresenceStatus, PresenceTracker};
+
+    fn presence(status: PresenceStatus) -> Presence {
+        Presence {
+            status,
+            capabilities: vec!["receipts".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_update_and_expire() {
+        let mut tracker = PresenceTracker::new();
+        let peer = PeerId::random();
+
+        assert_eq!(
+            tracker.update(peer, presence(PresenceStatus::Online), None),
+            None
+        );
+        assert_eq!(
+            tracker.update(peer, presence(PresenceStatus::Away), None),
+            Some(PresenceStatus::Online)
+        );
+        assert_eq!(tracker.peers()[0].1.status, PresenceStatus::Away);
+        assert_eq!(
+            tracker.update(peer, presence(PresenceStatus::Offline), None),
+            Some(PresenceStatus::Away)
+        );
+        assert!(tracker.peers().is_empty());
+
+        tracker.update(peer, presence(PresenceStatus::Online), None);
+        assert!(tracker.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(tracker.expire(Duration::ZERO), vec![peer]);
+        assert!(tracker.peers().is_empty());
+    }
+
+    #[test]
+    fn test_beacon_schedule() {
+        let mut tracker = PresenceTracker::new();
+        assert!(tracker.beacon_due());
+        assert!(!tracker.beacon_due());
+
+        tracker.set_status(PresenceStatus::Away);
+        assert!(tracker.beacon_due());
+        assert_eq!(tracker.beacon().status, PresenceStatus::Away);
+    }
+}
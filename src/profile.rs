@@ -0,0 +1,262 @@
+/*!
+ * Profile module for the messaging application.
+ *
+ * Peers choose a display name with `/nick` and broadcast it in a profile
+ * envelope on the presence topic. The envelope is signed with the identity
+ * key of the peer, so a name can only be set by the peer it names. This
+ * module keeps the names received, and renders peers by name instead of
+ * by their raw peer ID. The aliases the user gave their contacts take
+ * precedence over the names peers chose themselves.
+ *
+ * Anyone may claim any name though, so a name is only trusted as far as
+ * the key behind it. When several peers, or a peer and the user, go by the
+ * same name regardless of case, each of the peers is shown with the start
+ * of the fingerprint of its key, which `/whois` shows in full and which
+ * stays the same whatever name the peer picks.
+ */
+
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::security::fingerprint;
+
+/// Maximum number of characters of a display name.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// The profile a peer announces about itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+}
+
+impl Profile {
+    /// Returns whether the name is non-empty, short enough and made of
+    /// printable characters other than whitespace.
+    pub fn is_valid(&self) -> bool {
+        !self.name.is_empty()
+            && self.name.chars().count() <= MAX_NAME_LEN
+            && !self
+                .name
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+    }
+}
+
+/// Characters of the fingerprint following a name shared by several
+/// peers, its first two groups.
+const FINGERPRINT_SUFFIX_LEN: usize = 9;
+
+/// Returns whether two names would be mistaken for one another.
+fn same_name(name: &str, other: &str) -> bool {
+    name.to_lowercase() == other.to_lowercase()
+}
+
+/// Display names of peers, by peer ID.
+pub struct Profiles {
+    names: HashMap<PeerId, String>,
+    /// Aliases of contacts.
+    aliases: HashMap<PeerId, String>,
+    /// The display name of the user.
+    own: Option<String>,
+}
+
+impl Profiles {
+    /// Creates a new, empty `Profiles` table.
+    pub fn new() -> Self {
+        Profiles {
+            names: HashMap::new(),
+            aliases: HashMap::new(),
+            own: None,
+        }
+    }
+
+    /// Records the name announced by a peer.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the name of the peer changed.
+    pub fn set(&mut self, peer: PeerId, name: String) -> bool {
+        self.names.insert(peer, name.clone()).as_ref() != Some(&name)
+    }
+
+    /// Sets the display name of the user, which peers claiming it are told
+    /// apart from.
+    pub fn set_own(&mut self, name: Option<String>) {
+        self.own = name;
+    }
+
+    /// Returns the other peers going by a name, and whether the user does,
+    /// to warn about a peer claiming it.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer claiming the name.
+    /// * `name` - The name claimed.
+    pub fn collisions(&self, peer: &PeerId, name: &str) -> (Vec<PeerId>, bool) {
+        let mut peers: Vec<PeerId> = self
+            .names
+            .iter()
+            .chain(&self.aliases)
+            .filter(|(other, other_name)| *other != peer && same_name(other_name, name))
+            .map(|(other, _)| *other)
+            .collect();
+        peers.sort();
+        peers.dedup();
+        let own = self.own.as_deref().is_some_and(|own| same_name(own, name));
+        (peers, own)
+    }
+
+    /// Sets or clears the alias of a contact.
+    pub fn set_alias(&mut self, peer: PeerId, alias: Option<String>) {
+        match alias {
+            Some(alias) => self.aliases.insert(peer, alias),
+            None => self.aliases.remove(&peer),
+        };
+    }
+
+    /// Forgets the names announced by peers no longer remembered, aliases
+    /// being kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `keep` - Whether the name of a peer is kept.
+    pub fn retain(&mut self, keep: impl Fn(&PeerId) -> bool) {
+        self.names.retain(|peer, _| keep(peer));
+    }
+
+    /// Returns the alias of a peer, or else the name it announced, if any.
+    pub fn name(&self, peer: &PeerId) -> Option<&str> {
+        self.aliases
+            .get(peer)
+            .or_else(|| self.names.get(peer))
+            .map(String::as_str)
+    }
+
+    /// Returns the peers with an alias or announced name, ignoring case.
+    pub fn named(&self, name: &str) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self
+            .aliases
+            .iter()
+            .chain(&self.names)
+            .filter(|(_, peer_name)| peer_name.eq_ignore_ascii_case(name))
+            .map(|(peer, _)| *peer)
+            .collect();
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+
+    /// Returns how a peer is shown to the user.
+    ///
+    /// Names shared by several peers, or by a peer and the user, are
+    /// followed by the start of the fingerprint of the peer to tell them
+    /// apart; peers without a name are shown by peer ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer to show.
+    /// * `claimed` - The name carried by the envelope being shown, used
+    ///   until the profile of the peer is received.
+    pub fn label(&self, peer: &PeerId, claimed: Option<&str>) -> String {
+        // Aliases are chosen by the user, who tells them apart.
+        if let Some(alias) = self.aliases.get(peer) {
+            return alias.clone();
+        }
+        let Some(name) = self.names.get(peer).map(String::as_str).or(claimed) else {
+            return peer.to_string();
+        };
+        let (peers, own) = self.collisions(peer, name);
+        if peers.is_empty() && !own {
+            return name.to_string();
+        }
+        format!(
+            "{} ({})",
+            name,
+            &fingerprint(peer)[..FINGERPRINT_SUFFIX_LEN]
+        )
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Profiles::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{Profile, Profiles, FINGERPRINT_SUFFIX_LEN};
+    use crate::security::fingerprint;
+
+    #[test]
+    fn test_profile_validity() {
+        let profile = |name: &str| Profile {
+            name: name.to_string(),
+        };
+        assert!(profile("alice").is_valid());
+        assert!(!profile("").is_valid());
+        assert!(!profile("alice smith").is_valid());
+        assert!(!profile(&"a".repeat(33)).is_valid());
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut profiles = Profiles::new();
+        let alice = PeerId::random();
+        let impostor = PeerId::random();
+        assert_eq!(profiles.label(&alice, None), alice.to_string());
+        assert_eq!(profiles.label(&alice, Some("al")), "al");
+
+        assert!(profiles.set(alice, "alice".to_string()));
+        assert!(!profiles.set(alice, "alice".to_string()));
+        assert_eq!(profiles.label(&alice, Some("al")), "alice");
+
+        let suffixed = |name: &str, peer: &PeerId| {
+            format!(
+                "{} ({})",
+                name,
+                &fingerprint(peer)[..FINGERPRINT_SUFFIX_LEN]
+            )
+        };
+        // Names differing in case only are mistaken for one another.
+        profiles.set(impostor, "Alice".to_string());
+        assert_eq!(profiles.label(&alice, None), suffixed("alice", &alice));
+        assert_eq!(
+            profiles.label(&impostor, None),
+            suffixed("Alice", &impostor)
+        );
+        assert_eq!(
+            profiles.collisions(&impostor, "Alice"),
+            (vec![alice], false)
+        );
+        assert_eq!(profiles.named("Alice").len(), 2);
+        assert!(profiles.named("bob").is_empty());
+        assert_ne!(
+            profiles.label(&alice, None),
+            profiles.label(&impostor, None)
+        );
+
+        // An alias wins over the announced name, and makes others claiming
+        // it stand out.
+        let bob = PeerId::random();
+        profiles.set_alias(alice, Some("bob".to_string()));
+        assert_eq!(profiles.label(&alice, None), "bob");
+        assert_eq!(profiles.name(&alice), Some("bob"));
+        assert_eq!(profiles.label(&bob, Some("bob")), suffixed("bob", &bob));
+        assert_eq!(profiles.named("bob"), [alice]);
+        profiles.set_alias(alice, None);
+        assert_eq!(profiles.label(&alice, None), suffixed("alice", &alice));
+
+        // Peers taking the name of the user stand out too.
+        let carol = PeerId::random();
+        profiles.set(carol, "carol".to_string());
+        assert_eq!(profiles.label(&carol, None), "carol");
+        profiles.set_own(Some("Carol".to_string()));
+        assert_eq!(profiles.label(&carol, None), suffixed("carol", &carol));
+        assert_eq!(profiles.collisions(&carol, "carol"), (vec![], true));
+    }
+}
@@ -3,24 +3,63 @@
  *
  * This module implements the `Protocols` struct, which combines Floodsub
  * and Gossipsub, and provides functions to subscribe the publish messages.
+ * It also carries the Kademlia DHT used to discover public topics, the
+ * streams used for large file transfers, and the identify protocol peers
+ * announce their version and features with.
+ * It also defines the versioned `Envelope` wrapping every published message,
+ * the fragmentation layer used for envelopes above the transmit limit, and
+ * the prioritized queue all outbound messages pass through.
  */
 
+use crate::compression::{Compression, CompressionError};
+use crate::delivery::{MessageId, Receipt};
+use crate::discovery::DISCOVERY_PROTOCOL;
+use crate::history::{HistoryRequest, HistoryResponse, HISTORY_PROTOCOL};
+use crate::hooks::Direction;
+use crate::middleware::{Context, Pipeline};
+use crate::moderation::ModerationAction;
+use crate::note::NoteOp;
+use crate::peers::{Ping, Pong, PING_PROTOCOL};
+use crate::presence::Presence;
+use crate::profile::Profile;
+use crate::reaction::Reaction;
+use crate::stream::{Streams, StreamsEvent};
+use crate::topic::PubsubProtocol;
+use crate::transfer::{FileRequest, FileResponse, FILE_PROTOCOL};
+use crate::version::{agent_version, protocol_version};
 use libp2p::{
     floodsub::{self, Floodsub, FloodsubEvent},
-    gossipsub::{self, MessageAuthenticity},
-    identity,
+    gossipsub::{self, MessageAuthenticity, PublishError},
+    identify, identity, kad,
+    request_response::{self, ProtocolSupport},
     swarm::NetworkBehaviour,
     PeerId,
 };
-use log::{error, info};
-use std::error::Error;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    time::Duration,
+};
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
 
-/// Network behavior combining Floodsub, Gossipsub, and mDNS protocols.
+/// Network behavior combining Floodsub, Gossipsub, the file transfer,
+/// history sync and ping protocols, the topic discovery DHT, the streaming
+/// protocol, and identify.
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ProtocolEvent")]
 pub struct Protocols {
     pub floodsub: Floodsub,
     pub gossipsub: gossipsub::Behaviour,
+    pub file_transfer: request_response::cbor::Behaviour<FileRequest, FileResponse>,
+    pub history: request_response::cbor::Behaviour<HistoryRequest, HistoryResponse>,
+    pub ping: request_response::cbor::Behaviour<Ping, Pong>,
+    pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    pub streams: Streams,
+    pub identify: identify::Behaviour,
 }
 
 impl Protocols {
@@ -35,19 +74,46 @@ impl Protocols {
     ///
     /// A new `Protocols` instance.
     pub fn new(local_peer_id: PeerId, local_key: identity::Keypair) -> Self {
-        // let gossipsub_config = gossipsub::ConfigBuilder::default()
-        //     .heartbeat_interval(Duration::from_secs(10))
-        //     .validation_mode(gossipsub::ValidationMode::Strict)
-        //     .build()
-        //     .expect("Valid gossipsub config");
+        let gossipsub_config = gossipsub::ConfigBuilder::default()
+            .message_id_fn(content_message_id)
+            .build()
+            .expect("Valid gossipsub config");
+
+        let mut kademlia_config = kad::Config::default();
+        kademlia_config.set_protocol_names(vec![DISCOVERY_PROTOCOL]);
+        let mut kademlia = kad::Behaviour::with_config(
+            local_peer_id,
+            kad::store::MemoryStore::new(local_peer_id),
+            kademlia_config,
+        );
+        // Serve the DHT even without a confirmed external address.
+        kademlia.set_mode(Some(kad::Mode::Server));
 
         Protocols {
             floodsub: Floodsub::new(local_peer_id),
             gossipsub: gossipsub::Behaviour::new(
                 MessageAuthenticity::Signed(local_key.clone()),
-                gossipsub::Config::default(),
+                gossipsub_config,
             )
             .expect("Valid gossipsub instance"),
+            file_transfer: request_response::cbor::Behaviour::new(
+                [(FILE_PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            history: request_response::cbor::Behaviour::new(
+                [(HISTORY_PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            ping: request_response::cbor::Behaviour::new(
+                [(PING_PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            kademlia,
+            streams: Streams::default(),
+            identify: identify::Behaviour::new(
+                identify::Config::new(protocol_version(), local_key.public())
+                    .with_agent_version(agent_version()),
+            ),
         }
     }
 
@@ -56,47 +122,1220 @@ impl Protocols {
     /// # Arguments
     ///
     /// * `topic` - The topic to subscribe to.
+    /// * `protocol` - The protocols to subscribe on.
     ///
     /// # Returns
     ///
     /// A `result` indicating success or failure.
-    pub fn subscribe(&mut self, topic: &str) -> Result<(), Box<dyn Error>> {
+    pub fn subscribe(
+        &mut self,
+        topic: &str,
+        protocol: PubsubProtocol,
+    ) -> Result<(), Box<dyn Error>> {
         let floodsub_topic = floodsub::Topic::new(topic);
-        if !self.floodsub.subscribe(floodsub_topic.clone()) {
+        if protocol.floodsub() && !self.floodsub.subscribe(floodsub_topic.clone()) {
             error!("Failed to subscribe to floodsub topic: {:?}", topic);
             return Err("Failed to subscribe to floodsub topic".into());
         }
 
         let gossipsub_topic = gossipsub::IdentTopic::new(topic);
-        if self.gossipsub.subscribe(&gossipsub_topic).is_err() {
+        if protocol.gossipsub() && self.gossipsub.subscribe(&gossipsub_topic).is_err() {
             error!("Failed to subscribe to gossipsub topic: {:?}", topic);
             return Err("Failed to subscribe to gossipsub topic".into());
         }
-        info!("Subscribed to topic: {:?}", topic);
+        info!("Subscribed to topic: {:?} on {}", topic, protocol);
         Ok(())
     }
 
-    /// Publishes a message to the specified topic.
+    /// Unsubscribes from the specified topic.
     ///
     /// # Arguments
     ///
-    /// * `topic` - The topic to publish to.
-    /// * `data` - The message data.
+    /// * `topic` - The topic to unsubscribe from.
+    /// * `protocol` - The protocols the topic was subscribed on.
     ///
     /// # Returns
     ///
     /// A `Result` indicating success or failure.
-    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    pub fn unsubscribe(
+        &mut self,
+        topic: &str,
+        protocol: PubsubProtocol,
+    ) -> Result<(), Box<dyn Error>> {
         let floodsub_topic = floodsub::Topic::new(topic);
-        self.floodsub.publish(floodsub_topic, data.to_vec());
+        if protocol.floodsub() && !self.floodsub.unsubscribe(floodsub_topic) {
+            error!("Not subscribed to floodsub topic: {:?}", topic);
+            return Err("Not subscribed to floodsub topic".into());
+        }
 
         let gossipsub_topic = gossipsub::IdentTopic::new(topic);
-        self.gossipsub.publish(gossipsub_topic, data.to_vec())?;
+        if !protocol.gossipsub() {
+            info!("Unsubscribed from topic: {:?}", topic);
+            return Ok(());
+        }
+        match self.gossipsub.unsubscribe(&gossipsub_topic) {
+            Ok(true) => {}
+            Ok(false) => {
+                error!("Not subscribed to gossipsub topic: {:?}", topic);
+                return Err("Not subscribed to gossipsub topic".into());
+            }
+            Err(e) => {
+                error!("Failed to unsubscribe from gossipsub topic: {:?}", topic);
+                return Err(e.into());
+            }
+        }
+        info!("Unsubscribed from topic: {:?}", topic);
+        Ok(())
+    }
 
-        let message = String::from_utf8_lossy(data);
-        info!("Published message {:?} to topic: {:?}", message, topic);
+    /// Publishes an encoded envelope to the specified topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic to publish to.
+    /// * `protocol` - The protocols to publish on.
+    /// * `data` - The encoded envelope.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        protocol: PubsubProtocol,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        // Larger envelopes are split into fragments before they get here.
+        if data.len() > MAX_ENVELOPE_SIZE {
+            return Err(format!(
+                "Message of {} bytes exceeds the {} byte limit of a single message",
+                data.len(),
+                MAX_ENVELOPE_SIZE
+            )
+            .into());
+        }
+        if protocol.floodsub() {
+            let floodsub_topic = floodsub::Topic::new(topic);
+            self.floodsub.publish(floodsub_topic, data.clone());
+        }
+
+        if protocol.gossipsub() {
+            let gossipsub_topic = gossipsub::IdentTopic::new(topic);
+            self.gossipsub.publish(gossipsub_topic, data.clone())?;
+        }
+
+        info!("Published {} bytes to topic: {:?}", data.len(), topic);
         Ok(())
     }
+
+    /// Returns the peers known to be subscribed to the specified topic.
+    ///
+    /// Only Gossipsub tracks the subscriptions of remote peers, so peers
+    /// using Floodsub alone are not included.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic to look up.
+    pub fn topic_peers(&self, topic: &str) -> Vec<PeerId> {
+        let hash = gossipsub::IdentTopic::new(topic).hash();
+        self.gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&hash))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+/// Derives the Gossipsub message ID from the topic and content of a message.
+///
+/// Identical envelopes republished by different peers share an ID, so
+/// Gossipsub itself forwards and delivers them only once.
+///
+/// # Arguments
+///
+/// * `message` - The Gossipsub message.
+pub fn content_message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    let mut hasher = Sha256::new();
+    hasher.update(message.topic.as_str().as_bytes());
+    hasher.update([0]);
+    hasher.update(&message.data);
+    gossipsub::MessageId::from(hasher.finalize().to_vec())
+}
+
+/// Bytes credited to a traffic class per unit of weight and scheduling round.
+const QUANTUM: usize = 16 * 1024;
+
+/// Most bytes of messages held back while no peer can receive them.
+const MAX_HELD_BYTES: usize = 16 * 1024 * 1024;
+
+/// Classes of outbound traffic, from highest to lowest priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Receipts, moderation directives, and other protocol responses.
+    Control,
+    /// Direct messages.
+    Direct,
+    /// Topic chat and shared note edits.
+    Topic,
+    /// File chunks and binary messages.
+    Bulk,
+}
+
+impl TrafficClass {
+    const ALL: [TrafficClass; 4] = [
+        TrafficClass::Control,
+        TrafficClass::Direct,
+        TrafficClass::Topic,
+        TrafficClass::Bulk,
+    ];
+
+    /// Returns the share of the bandwidth relative to the other classes.
+    fn weight(self) -> usize {
+        match self {
+            TrafficClass::Control => 8,
+            TrafficClass::Direct => 4,
+            TrafficClass::Topic => 2,
+            TrafficClass::Bulk => 1,
+        }
+    }
+
+    /// Returns the class of a payload published to a topic.
+    fn of(topic: &str, payload: &Payload) -> Self {
+        match payload {
+            Payload::Receipt(_)
+            | Payload::Moderation(_)
+            | Payload::Presence(_)
+            | Payload::Profile(_) => TrafficClass::Control,
+            Payload::Binary(_) => TrafficClass::Bulk,
+            _ if is_inbox_topic(topic) => TrafficClass::Direct,
+            _ => TrafficClass::Topic,
+        }
+    }
+}
+
+/// A message waiting in the outbound queue.
+pub enum Outbound {
+    /// An encoded envelope to publish.
+    Publish {
+        topic: String,
+        protocol: PubsubProtocol,
+        data: Vec<u8>,
+    },
+    /// A response to a file transfer request.
+    FileResponse {
+        channel: request_response::ResponseChannel<FileResponse>,
+        response: FileResponse,
+    },
+}
+
+impl Outbound {
+    /// Returns the number of bytes the message occupies on the wire.
+    fn len(&self) -> usize {
+        match self {
+            Outbound::Publish { data, .. } => data.len(),
+            Outbound::FileResponse { response, .. } => match response {
+                FileResponse::Chunk { data, .. } => data.len(),
+                _ => 0,
+            },
+        }
+    }
+}
+
+/// Signs an encoded envelope again with the current time.
+fn restamp(data: &[u8], local_key: &identity::Keypair) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(Envelope::decode(data)?.restamp(local_key)?.encode()?)
+}
+
+/// Prioritized queue of outbound messages.
+///
+/// Messages are sent at most at the configured rate. When several classes
+/// are waiting, each gets a share of the rate proportional to its weight
+/// using deficit round robin, so a large file transfer slows down chat
+/// only by its share while unused shares go to whoever is waiting.
+///
+/// Chat and direct messages Gossipsub cannot publish yet, no peer being
+/// subscribed to their topic as right after startup, are held back with
+/// the ones published to the same topic after them, and queued again in
+/// order by `retry` once a peer is. Control messages are sent again
+/// anyway, so they are not held.
+pub struct OutboundQueue {
+    queues: [VecDeque<Outbound>; 4],
+    /// Messages waiting for a peer subscribed to their topic.
+    held: VecDeque<(TrafficClass, Outbound)>,
+    deficits: [usize; 4],
+    current: usize,
+    /// Bytes sent per second.
+    rate: f64,
+    /// Bytes that may be sent right now, negative after a large message.
+    allowance: f64,
+    refilled_at: Instant,
+    /// Largest encoded envelope published, fragments included.
+    max_message_size: usize,
+}
+
+impl OutboundQueue {
+    /// Creates a new, empty `OutboundQueue`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - The bytes sent per second.
+    /// * `max_message_size` - The largest encoded envelope published.
+    pub fn new(rate: usize, max_message_size: usize) -> Self {
+        OutboundQueue {
+            queues: Default::default(),
+            held: VecDeque::new(),
+            deficits: [0; 4],
+            current: 0,
+            rate: rate.max(1) as f64,
+            allowance: 0.0,
+            refilled_at: Instant::now(),
+            max_message_size,
+        }
+    }
+
+    /// Changes the largest encoded envelope published.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Adds a message to the queue of its class.
+    pub fn push(&mut self, class: TrafficClass, message: Outbound) {
+        self.queues[class as usize].push_back(message);
+    }
+
+    /// Returns whether no message is waiting to be sent, leaving out the
+    /// held ones.
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Returns the number of messages held until a peer can receive them.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Returns whether messages to a topic are held.
+    fn is_holding(&self, topic: &str) -> bool {
+        self.held
+            .iter()
+            .any(|(_, held)| matches!(held, Outbound::Publish { topic: held, .. } if held == topic))
+    }
+
+    /// Holds a message until a peer is subscribed to its topic, dropping
+    /// the oldest ones held beyond `MAX_HELD_BYTES`.
+    fn hold(&mut self, class: TrafficClass, message: Outbound) {
+        self.held.push_back((class, message));
+        let mut bytes: usize = self.held.iter().map(|(_, message)| message.len()).sum();
+        while bytes > MAX_HELD_BYTES {
+            let Some((_, oldest)) = self.held.pop_front() else {
+                break;
+            };
+            warn!("Too many messages waiting for peers, dropped the oldest");
+            bytes -= oldest.len();
+        }
+    }
+
+    /// Queues again the held messages whose topic a peer is now subscribed
+    /// to, ahead of the messages of their class, signed again so peers do
+    /// not take them for stale replays.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocols` - The network behavior, knowing the subscribed peers.
+    /// * `local_key` - The local identity keypair.
+    pub fn retry(&mut self, protocols: &Protocols, local_key: &identity::Keypair) {
+        let mut released: Vec<(TrafficClass, Outbound)> = Vec::new();
+        let mut waiting = HashMap::new();
+        for (class, message) in std::mem::take(&mut self.held) {
+            let Outbound::Publish { topic, .. } = &message else {
+                continue;
+            };
+            let ready = *waiting
+                .entry(topic.clone())
+                .or_insert_with(|| !protocols.topic_peers(topic).is_empty());
+            match ready {
+                true => released.push((class, message)),
+                false => self.held.push_back((class, message)),
+            }
+        }
+        if released.is_empty() {
+            return;
+        }
+        info!("Peers joined, sending {} queued messages", released.len());
+        for (class, mut message) in released.into_iter().rev() {
+            if let Outbound::Publish { data, .. } = &mut message {
+                match restamp(data, local_key) {
+                    Ok(restamped) => *data = restamped,
+                    Err(e) => {
+                        error!("Failed to sign queued message again: {}", e);
+                        continue;
+                    }
+                }
+            }
+            self.queues[class as usize].push_front(message);
+        }
+    }
+
+    /// Seals a payload and queues it for publishing, splitting envelopes
+    /// above `MAX_ENVELOPE_SIZE` into fragments and refusing envelopes above
+    /// the maximum message size.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic to publish to.
+    /// * `protocol` - The protocols to publish on.
+    /// * `payload` - The payload to publish.
+    /// * `sender` - The display name of the local user, if any.
+    /// * `local_key` - The local identity keypair.
+    /// * `middleware` - The middleware every envelope is sealed through.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the encoded envelope before fragmentation or an error.
+    pub fn publish_payload(
+        &mut self,
+        topic: &str,
+        protocol: PubsubProtocol,
+        payload: &Payload,
+        sender: Option<String>,
+        local_key: &identity::Keypair,
+        middleware: &mut Pipeline,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let class = TrafficClass::of(topic, payload);
+        let envelope = middleware.seal(topic, payload, sender.clone(), local_key)?;
+        let data = envelope.encode()?;
+        if data.len() > self.max_message_size {
+            return Err(format!(
+                "Message of {} bytes exceeds the {} byte limit, send it with /send-file instead",
+                data.len(),
+                self.max_message_size
+            )
+            .into());
+        }
+        if data.len() <= MAX_ENVELOPE_SIZE {
+            self.push(
+                class,
+                Outbound::Publish {
+                    topic: topic.to_string(),
+                    protocol,
+                    data: data.clone(),
+                },
+            );
+            return Ok(data);
+        }
+
+        let fragments = Fragment::split(&data)?;
+        info!(
+            "Splitting {} byte envelope into {} fragments",
+            data.len(),
+            fragments.len()
+        );
+        for fragment in fragments {
+            let fragment = middleware.seal(
+                topic,
+                &Payload::Fragment(fragment),
+                sender.clone(),
+                local_key,
+            )?;
+            self.push(
+                class,
+                Outbound::Publish {
+                    topic: topic.to_string(),
+                    protocol,
+                    data: fragment.encode()?,
+                },
+            );
+        }
+        Ok(data)
+    }
+
+    /// Sends queued messages as far as the rate allows.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocols` - The network behavior to send the messages with.
+    /// * `middleware` - The middleware every message is filtered through.
+    /// * `peer` - The local peer ID.
+    pub fn flush(&mut self, protocols: &mut Protocols, middleware: &mut Pipeline, peer: PeerId) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        // Allow bursts of a tenth of a second after idling.
+        self.allowance = (self.allowance + elapsed * self.rate).min(self.rate / 10.0);
+        self.refilled_at = now;
+
+        while self.allowance > 0.0 {
+            let Some((class, message)) = self.next() else {
+                break;
+            };
+            self.allowance -= message.len() as f64;
+            match message {
+                Outbound::Publish {
+                    topic,
+                    protocol,
+                    data,
+                } => {
+                    let context = Context {
+                        direction: Direction::Outbound,
+                        topic: &topic,
+                        peer,
+                    };
+                    if !middleware.filter(&context, &data) {
+                        continue;
+                    }
+                    let holdable = class != TrafficClass::Control;
+                    // Keeps the messages of a topic in order.
+                    if holdable && self.is_holding(&topic) {
+                        self.hold(
+                            class,
+                            Outbound::Publish {
+                                topic,
+                                protocol,
+                                data,
+                            },
+                        );
+                        continue;
+                    }
+                    match protocols.publish(&topic, protocol, data.clone()) {
+                        Ok(()) => {}
+                        Err(e)
+                            if holdable
+                                && matches!(
+                                    e.downcast_ref::<PublishError>(),
+                                    Some(PublishError::InsufficientPeers)
+                                ) =>
+                        {
+                            info!("No peer on {:?} yet, the message is queued", topic);
+                            self.hold(
+                                class,
+                                Outbound::Publish {
+                                    topic,
+                                    protocol,
+                                    data,
+                                },
+                            );
+                        }
+                        Err(e) => error!("Failed to publish message: {:?} on {:?}", e, topic),
+                    }
+                }
+                Outbound::FileResponse { channel, response } => {
+                    if protocols
+                        .file_transfer
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        error!("Failed to respond to file request, the peer disconnected");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the next message to send and its class, using deficit round
+    /// robin.
+    fn next(&mut self) -> Option<(TrafficClass, Outbound)> {
+        if self.is_empty() {
+            return None;
+        }
+        loop {
+            let class = self.current;
+            match self.queues[class].front().map(Outbound::len) {
+                Some(len) if len <= self.deficits[class] => {
+                    self.deficits[class] -= len;
+                    let message = self.queues[class].pop_front();
+                    if self.queues[class].is_empty() {
+                        self.deficits[class] = 0;
+                    }
+                    return message.map(|message| (TrafficClass::ALL[class], message));
+                }
+                Some(_) => {}
+                None => self.deficits[class] = 0,
+            }
+            self.current = (self.current + 1) % TrafficClass::ALL.len();
+            if !self.queues[self.current].is_empty() {
+                self.deficits[self.current] += TrafficClass::ALL[self.current].weight() * QUANTUM;
+            }
+        }
+    }
+}
+
+/// Prefix of the direct message inbox topics.
+const INBOX_PREFIX: &str = "dm/";
+
+/// Returns the topic used as the direct message inbox of the specified peer.
+///
+/// # Arguments
+///
+/// * `peer_id` - The peer owning the inbox.
+pub fn inbox_topic(peer_id: &PeerId) -> String {
+    format!("{}{}", INBOX_PREFIX, peer_id)
+}
+
+/// Returns whether a topic is the direct message inbox of some peer.
+pub fn is_inbox_topic(topic: &str) -> bool {
+    topic.starts_with(INBOX_PREFIX)
+}
+
+/// Version of the envelope format produced by this build.
+pub const ENVELOPE_VERSION: u16 = 3;
+
+/// How far the timestamp of an envelope received live may be from the local
+/// clock. Replays within it are caught by the dedup cache, which remembers
+/// envelopes for twice as long.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Kind of payload carried by an envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u8", into = "u8")]
+pub enum EnvelopeKind {
+    Text,
+    Receipt,
+    Fragment,
+    Moderation,
+    Note,
+    Presence,
+    Topics,
+    Reaction,
+    Profile,
+    Binary,
+    /// A kind introduced by a newer client.
+    Unknown(u8),
+}
+
+impl From<u8> for EnvelopeKind {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => EnvelopeKind::Text,
+            1 => EnvelopeKind::Receipt,
+            2 => EnvelopeKind::Fragment,
+            3 => EnvelopeKind::Moderation,
+            4 => EnvelopeKind::Note,
+            5 => EnvelopeKind::Presence,
+            6 => EnvelopeKind::Topics,
+            7 => EnvelopeKind::Reaction,
+            8 => EnvelopeKind::Profile,
+            9 => EnvelopeKind::Binary,
+            other => EnvelopeKind::Unknown(other),
+        }
+    }
+}
+
+impl From<EnvelopeKind> for u8 {
+    fn from(kind: EnvelopeKind) -> Self {
+        match kind {
+            EnvelopeKind::Text => 0,
+            EnvelopeKind::Receipt => 1,
+            EnvelopeKind::Fragment => 2,
+            EnvelopeKind::Moderation => 3,
+            EnvelopeKind::Note => 4,
+            EnvelopeKind::Presence => 5,
+            EnvelopeKind::Topics => 6,
+            EnvelopeKind::Reaction => 7,
+            EnvelopeKind::Profile => 8,
+            EnvelopeKind::Binary => 9,
+            EnvelopeKind::Unknown(other) => other,
+        }
+    }
+}
+
+/// A chat message.
+///
+/// The body is a `String`, so a body that is not UTF-8 fails to decode
+/// rather than being shown with replacement characters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TextMessage {
+    pub id: MessageId,
+    pub body: String,
+    pub ack_requested: bool,
+}
+
+/// Longest media type of a binary message.
+pub const MAX_MEDIA_TYPE_LEN: usize = 127;
+
+/// Bytes published on a topic, such as an image, which are never shown as
+/// chat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinaryMessage {
+    pub id: MessageId,
+    /// The media type of the data, such as `image/png`.
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+impl BinaryMessage {
+    /// Returns whether the media type is a type and a subtype of printable
+    /// ASCII, short enough.
+    pub fn is_valid(&self) -> bool {
+        self.media_type.len() <= MAX_MEDIA_TYPE_LEN
+            && self.media_type.bytes().all(|b| b.is_ascii_graphic())
+            && self.media_type.split_once('/').is_some_and(|(kind, sub)| {
+                !kind.is_empty() && !sub.is_empty() && !sub.contains('/')
+            })
+    }
+}
+
+/// Decoded contents of an envelope.
+#[derive(Debug, Clone)]
+pub enum Payload {
+    /// A chat message, optionally requesting a delivery receipt.
+    Text(TextMessage),
+    /// A delivery or read receipt for a previously received message.
+    Receipt(Receipt),
+    /// A piece of an envelope too large to publish in one message.
+    Fragment(Fragment),
+    /// A signed moderation directive for a topic.
+    Moderation(ModerationAction),
+    /// Edits of the shared note backing the topic.
+    Note(Vec<NoteOp>),
+    /// A presence beacon of the signer.
+    Presence(Presence),
+    /// The public topics advertised by the signer on the DHT.
+    Topics(Vec<String>),
+    /// A reaction to a message on the same topic.
+    Reaction(Reaction),
+    /// The display name chosen by the signer.
+    Profile(Profile),
+    /// Bytes that are not text.
+    Binary(BinaryMessage),
+}
+
+impl Payload {
+    fn kind(&self) -> EnvelopeKind {
+        match self {
+            Payload::Text(_) => EnvelopeKind::Text,
+            Payload::Receipt(_) => EnvelopeKind::Receipt,
+            Payload::Fragment(_) => EnvelopeKind::Fragment,
+            Payload::Moderation(_) => EnvelopeKind::Moderation,
+            Payload::Note(_) => EnvelopeKind::Note,
+            Payload::Presence(_) => EnvelopeKind::Presence,
+            Payload::Topics(_) => EnvelopeKind::Topics,
+            Payload::Reaction(_) => EnvelopeKind::Reaction,
+            Payload::Profile(_) => EnvelopeKind::Profile,
+            Payload::Binary(_) => EnvelopeKind::Binary,
+        }
+    }
+
+    /// Encodes the payload into an uncompressed envelope body.
+    pub fn body(&self) -> Result<Body, EnvelopeError> {
+        let data = match self {
+            Payload::Text(text) => bincode::serialize(text)?,
+            Payload::Receipt(receipt) => bincode::serialize(receipt)?,
+            Payload::Fragment(fragment) => bincode::serialize(fragment)?,
+            Payload::Moderation(action) => bincode::serialize(action)?,
+            Payload::Note(ops) => bincode::serialize(ops)?,
+            Payload::Presence(presence) => bincode::serialize(presence)?,
+            Payload::Topics(topics) => bincode::serialize(topics)?,
+            Payload::Reaction(reaction) => bincode::serialize(reaction)?,
+            Payload::Profile(profile) => bincode::serialize(profile)?,
+            Payload::Binary(binary) => bincode::serialize(binary)?,
+        };
+        Ok(Body {
+            kind: self.kind(),
+            compression: Compression::None,
+            data,
+        })
+    }
+}
+
+/// Errors produced while decoding or opening an envelope.
+#[derive(Debug)]
+pub enum EnvelopeError {
+    /// The data is not a well-formed envelope.
+    Malformed(bincode::Error),
+    /// The envelope was produced by an incompatible client version.
+    UnsupportedVersion(u16),
+    /// The envelope carries a payload kind this client does not know.
+    UnknownKind(u8),
+    /// The envelope signature does not match its contents.
+    InvalidSignature,
+    /// The envelope was sealed for another topic.
+    WrongTopic(String),
+    /// The envelope timestamp is too far from the local clock.
+    Stale(u64),
+    /// The payload could not be decompressed.
+    Compression(CompressionError),
+    /// A middleware layer refused the payload.
+    Rejected(String),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "malformed envelope: {}", e),
+            EnvelopeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {}", version)
+            }
+            EnvelopeError::UnknownKind(kind) => write!(f, "unknown envelope kind {}", kind),
+            EnvelopeError::InvalidSignature => write!(f, "invalid envelope signature"),
+            EnvelopeError::WrongTopic(topic) => write!(f, "envelope sealed for topic {:?}", topic),
+            EnvelopeError::Stale(timestamp) => {
+                write!(
+                    f,
+                    "envelope timestamp {} too far from the local clock",
+                    timestamp
+                )
+            }
+            EnvelopeError::Compression(e) => write!(f, "{}", e),
+            EnvelopeError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for EnvelopeError {}
+
+impl From<bincode::Error> for EnvelopeError {
+    fn from(e: bincode::Error) -> Self {
+        EnvelopeError::Malformed(e)
+    }
+}
+
+impl From<CompressionError> for EnvelopeError {
+    fn from(e: CompressionError) -> Self {
+        EnvelopeError::Compression(e)
+    }
+}
+
+/// The contents of an envelope, as the middleware transforms them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Body {
+    pub kind: EnvelopeKind,
+    /// The algorithm the data is compressed with.
+    pub compression: Compression,
+    pub data: Vec<u8>,
+}
+
+impl Body {
+    /// Decodes the payload, once the middleware restored the data.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `Payload`, or an error if the data is still
+    /// compressed or the kind is unknown.
+    pub fn decode(&self) -> Result<Payload, EnvelopeError> {
+        if self.compression != Compression::None {
+            return Err(CompressionError::Unsupported(self.compression.into()).into());
+        }
+        let data = &self.data;
+        let payload = match self.kind {
+            EnvelopeKind::Text => Payload::Text(bincode::deserialize(data)?),
+            EnvelopeKind::Receipt => Payload::Receipt(bincode::deserialize(data)?),
+            EnvelopeKind::Fragment => Payload::Fragment(bincode::deserialize(data)?),
+            EnvelopeKind::Moderation => Payload::Moderation(bincode::deserialize(data)?),
+            EnvelopeKind::Note => Payload::Note(bincode::deserialize(data)?),
+            EnvelopeKind::Presence => Payload::Presence(bincode::deserialize(data)?),
+            EnvelopeKind::Topics => Payload::Topics(bincode::deserialize(data)?),
+            EnvelopeKind::Reaction => Payload::Reaction(bincode::deserialize(data)?),
+            EnvelopeKind::Profile => Payload::Profile(bincode::deserialize(data)?),
+            EnvelopeKind::Binary => Payload::Binary(bincode::deserialize(data)?),
+            EnvelopeKind::Unknown(kind) => return Err(EnvelopeError::UnknownKind(kind)),
+        };
+        Ok(payload)
+    }
+}
+
+/// Versioned, signed container for everything published by this application.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u16,
+    pub kind: EnvelopeKind,
+    pub compression: Compression,
+    /// The topic the envelope was sealed for, so it cannot be replayed on
+    /// another one.
+    pub topic: String,
+    pub sender: Option<String>,
+    pub timestamp: u64,
+    pub payload: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wraps a payload into an envelope signed with the local key, without
+    /// passing it through any middleware.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope is published to.
+    /// * `payload` - The payload to wrap.
+    /// * `sender` - The display name of the local user, if any.
+    /// * `local_key` - The local identity keypair.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signed `Envelope` or an error.
+    pub fn seal(
+        topic: &str,
+        payload: &Payload,
+        sender: Option<String>,
+        local_key: &identity::Keypair,
+    ) -> Result<Self, Box<dyn Error>> {
+        Envelope::sign(topic, payload.body()?, sender, local_key)
+    }
+
+    /// Wraps a body into an envelope signed with the local key.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope is published to.
+    /// * `body` - The body, as the middleware left it.
+    /// * `sender` - The display name of the local user, if any.
+    /// * `local_key` - The local identity keypair.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signed `Envelope` or an error.
+    pub fn sign(
+        topic: &str,
+        body: Body,
+        sender: Option<String>,
+        local_key: &identity::Keypair,
+    ) -> Result<Self, Box<dyn Error>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let mut envelope = Envelope {
+            version: ENVELOPE_VERSION,
+            kind: body.kind,
+            compression: body.compression,
+            topic: topic.to_string(),
+            sender,
+            timestamp,
+            payload: body.data,
+            public_key: local_key.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        envelope.signature = local_key.sign(&envelope.signed_bytes()?)?;
+        Ok(envelope)
+    }
+
+    /// Signs the envelope again with the current time, for one held back
+    /// until it can be published.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_key` - The local identity keypair, which signed it first.
+    pub fn restamp(&self, local_key: &identity::Keypair) -> Result<Self, Box<dyn Error>> {
+        let body = Body {
+            kind: self.kind,
+            compression: self.compression,
+            data: self.payload.clone(),
+        };
+        Envelope::sign(&self.topic, body, self.sender.clone(), local_key)
+    }
+
+    /// Serializes the envelope for publishing.
+    pub fn encode(&self) -> Result<Vec<u8>, EnvelopeError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Deserializes an envelope from received message data.
+    ///
+    /// The version is checked before the rest of the data is parsed, so
+    /// envelopes from newer clients are reported instead of misread.
+    pub fn decode(data: &[u8]) -> Result<Self, EnvelopeError> {
+        let version: u16 = bincode::deserialize(data)?;
+        if version != ENVELOPE_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        Ok(bincode::deserialize(data)?)
+    }
+
+    /// Reads the kind of an encoded envelope without parsing the rest, for
+    /// filters running before the signature is checked.
+    pub fn peek_kind(data: &[u8]) -> Option<EnvelopeKind> {
+        let (version, kind): (u16, EnvelopeKind) = bincode::deserialize(data).ok()?;
+        (version == ENVELOPE_VERSION).then_some(kind)
+    }
+
+    /// Verifies the signature and decodes the payload, without passing it
+    /// through any middleware.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope was received on.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `PeerId` of the signer and the `Payload`.
+    pub fn open(&self, topic: &str) -> Result<(PeerId, Payload), EnvelopeError> {
+        let (signer, body) = self.verify(topic)?;
+        Ok((signer, body.decode()?))
+    }
+
+    /// Verifies the signature, and that the envelope was sealed for the
+    /// topic it was received on.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the envelope was received on.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `PeerId` of the signer and the `Body`, for
+    /// the middleware to restore.
+    pub fn verify(&self, topic: &str) -> Result<(PeerId, Body), EnvelopeError> {
+        let public_key = identity::PublicKey::try_decode_protobuf(&self.public_key)
+            .map_err(|_| EnvelopeError::InvalidSignature)?;
+        if !public_key.verify(&self.signed_bytes()?, &self.signature) {
+            return Err(EnvelopeError::InvalidSignature);
+        }
+        if self.topic != topic {
+            return Err(EnvelopeError::WrongTopic(self.topic.clone()));
+        }
+        let body = Body {
+            kind: self.kind,
+            compression: self.compression,
+            data: self.payload.clone(),
+        };
+        Ok((PeerId::from(public_key), body))
+    }
+
+    /// Checks that the envelope was sealed within `MAX_CLOCK_SKEW` of the
+    /// local clock, as one received live must be. History is older, and is
+    /// not checked.
+    pub fn check_fresh(&self) -> Result<(), EnvelopeError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.check_fresh_at(now)
+    }
+
+    /// Checks the timestamp against the given time, in milliseconds since
+    /// the epoch.
+    fn check_fresh_at(&self, now: u64) -> Result<(), EnvelopeError> {
+        match now.abs_diff(self.timestamp) <= MAX_CLOCK_SKEW.as_millis() as u64 {
+            true => Ok(()),
+            false => Err(EnvelopeError::Stale(self.timestamp)),
+        }
+    }
+
+    /// Returns the bytes covered by the signature.
+    fn signed_bytes(&self) -> Result<Vec<u8>, EnvelopeError> {
+        Ok(bincode::serialize(&(
+            self.version,
+            self.kind,
+            self.compression,
+            &self.topic,
+            &self.sender,
+            self.timestamp,
+            &self.payload,
+            &self.public_key,
+        ))?)
+    }
+}
+
+/// Largest encoded envelope published as a single message, leaving headroom
+/// below the gossipsub transmit limit for framing and signatures.
+pub const MAX_ENVELOPE_SIZE: usize = 60 * 1024;
+
+/// Number of envelope bytes carried by each fragment.
+pub const FRAGMENT_SIZE: usize = 56 * 1024;
+
+/// Largest number of fragments accepted for a single envelope.
+pub const MAX_FRAGMENTS: u16 = 256;
+
+/// Largest decompressed payload accepted in an envelope.
+pub const MAX_PAYLOAD_SIZE: usize = FRAGMENT_SIZE * MAX_FRAGMENTS as usize;
+
+/// Largest encoded envelope published or accepted unless configured.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// How long an incomplete fragment set is kept before being dropped.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bound on the bytes buffered across all incomplete fragment sets.
+const MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+
+/// A numbered piece of an encoded envelope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fragment {
+    pub set_id: MessageId,
+    pub index: u16,
+    pub total: u16,
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    /// Splits an encoded envelope into fragments.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The encoded envelope.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the fragments, or an error if the envelope
+    /// needs more than `MAX_FRAGMENTS` pieces.
+    pub fn split(data: &[u8]) -> Result<Vec<Fragment>, Box<dyn Error>> {
+        let total = data.len().div_ceil(FRAGMENT_SIZE);
+        if total > MAX_FRAGMENTS as usize {
+            return Err(format!(
+                "Message of {} bytes exceeds the {} byte limit",
+                data.len(),
+                MAX_FRAGMENTS as usize * FRAGMENT_SIZE
+            )
+            .into());
+        }
+
+        let set_id = MessageId::random();
+        Ok(data
+            .chunks(FRAGMENT_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| Fragment {
+                set_id,
+                index: index as u16,
+                total: total as u16,
+                data: chunk.to_vec(),
+            })
+            .collect())
+    }
+}
+
+/// A fragment set that has not been fully received yet.
+struct PartialSet {
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+/// Reassembles fragmented envelopes received from peers.
+pub struct Reassembler {
+    partial: HashMap<(PeerId, MessageId), PartialSet>,
+    completed: HashMap<(PeerId, MessageId), Instant>,
+    buffered: usize,
+    /// Largest encoded envelope accepted, fragments included.
+    max_message_size: usize,
+}
+
+impl Reassembler {
+    /// Creates a new, empty `Reassembler` accepting envelopes of up to
+    /// `max_message_size` bytes.
+    pub fn new(max_message_size: usize) -> Self {
+        Reassembler {
+            partial: HashMap::new(),
+            completed: HashMap::new(),
+            buffered: 0,
+            max_message_size,
+        }
+    }
+
+    /// Changes the largest encoded envelope accepted.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Returns whether a message received is small enough to be parsed, as
+    /// a whole envelope or a fragment of one.
+    pub fn accepts(&self, len: usize) -> bool {
+        len <= self.max_message_size.min(MAX_ENVELOPE_SIZE)
+    }
+
+    /// Adds a received fragment.
+    ///
+    /// Duplicate fragments, fragments of already completed sets, fragments of
+    /// sets above the maximum message size, and fragments that would exceed
+    /// the buffering limits are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The peer that authored the fragment.
+    /// * `fragment` - The received fragment.
+    ///
+    /// # Returns
+    ///
+    /// The encoded envelope once every fragment of its set has arrived.
+    pub fn insert(&mut self, source: PeerId, fragment: Fragment) -> Option<Vec<u8>> {
+        let key = (source, fragment.set_id);
+        if fragment.total == 0
+            || fragment.total > MAX_FRAGMENTS
+            || fragment.index >= fragment.total
+            || fragment.data.len() > FRAGMENT_SIZE
+            || self.completed.contains_key(&key)
+        {
+            return None;
+        }
+        // Checked before the set is allocated, from the smallest size its
+        // fragments may add up to.
+        if (fragment.total as usize - 1) * FRAGMENT_SIZE >= self.max_message_size {
+            warn!(
+                "Dropping fragment from {:?}: message of {} fragments exceeds the {} byte limit",
+                source, fragment.total, self.max_message_size
+            );
+            return None;
+        }
+        if self.buffered + fragment.data.len() > MAX_BUFFERED_BYTES {
+            error!(
+                "Dropping fragment from {:?}: reassembly buffer full",
+                source
+            );
+            return None;
+        }
+
+        let set = self.partial.entry(key).or_insert_with(|| PartialSet {
+            parts: vec![None; fragment.total as usize],
+            received: 0,
+            bytes: 0,
+            started: Instant::now(),
+        });
+        let slot = set.parts.get_mut(fragment.index as usize)?;
+        if slot.is_some() {
+            return None;
+        }
+        set.received += 1;
+        set.bytes += fragment.data.len();
+        self.buffered += fragment.data.len();
+        *slot = Some(fragment.data);
+        if set.bytes > self.max_message_size {
+            warn!(
+                "Dropping message from {:?}: exceeds the {} byte limit",
+                source, self.max_message_size
+            );
+            let bytes = set.bytes;
+            self.partial.remove(&key);
+            self.buffered -= bytes;
+            return None;
+        }
+
+        if set.received < set.parts.len() {
+            return None;
+        }
+        let set = self.partial.remove(&key)?;
+        self.buffered -= set.bytes;
+        self.completed.insert(key, Instant::now());
+        Some(set.parts.into_iter().flatten().flatten().collect())
+    }
+
+    /// Drops fragment sets that have not completed within `timeout`.
+    ///
+    /// # Returns
+    ///
+    /// The authors and IDs of the dropped incomplete sets.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(PeerId, MessageId)> {
+        let now = Instant::now();
+        self.completed
+            .retain(|_, completed| now.duration_since(*completed) < timeout);
+
+        let expired: Vec<(PeerId, MessageId)> = self
+            .partial
+            .iter()
+            .filter(|(_, set)| now.duration_since(set.started) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            if let Some(set) = self.partial.remove(key) {
+                self.buffered -= set.bytes;
+            }
+        }
+        expired
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
 }
 
 /// Enumeration of protocol events.
@@ -104,6 +1343,12 @@ impl Protocols {
 pub enum ProtocolEvent {
     Floodsub(FloodsubEvent),
     Gossipsub(Box<gossipsub::Event>),
+    FileTransfer(request_response::Event<FileRequest, FileResponse>),
+    History(request_response::Event<HistoryRequest, HistoryResponse>),
+    Ping(request_response::Event<Ping, Pong>),
+    Kademlia(Box<kad::Event>),
+    Streams(StreamsEvent),
+    Identify(Box<identify::Event>),
 }
 
 impl From<FloodsubEvent> for ProtocolEvent {
@@ -118,6 +1363,42 @@ impl From<gossipsub::Event> for ProtocolEvent {
     }
 }
 
+impl From<request_response::Event<FileRequest, FileResponse>> for ProtocolEvent {
+    fn from(event: request_response::Event<FileRequest, FileResponse>) -> Self {
+        ProtocolEvent::FileTransfer(event)
+    }
+}
+
+impl From<request_response::Event<HistoryRequest, HistoryResponse>> for ProtocolEvent {
+    fn from(event: request_response::Event<HistoryRequest, HistoryResponse>) -> Self {
+        ProtocolEvent::History(event)
+    }
+}
+
+impl From<request_response::Event<Ping, Pong>> for ProtocolEvent {
+    fn from(event: request_response::Event<Ping, Pong>) -> Self {
+        ProtocolEvent::Ping(event)
+    }
+}
+
+impl From<kad::Event> for ProtocolEvent {
+    fn from(event: kad::Event) -> Self {
+        ProtocolEvent::Kademlia(Box::new(event))
+    }
+}
+
+impl From<StreamsEvent> for ProtocolEvent {
+    fn from(event: StreamsEvent) -> Self {
+        ProtocolEvent::Streams(event)
+    }
+}
+
+impl From<identify::Event> for ProtocolEvent {
+    fn from(event: identify::Event) -> Self {
+        ProtocolEvent::Identify(Box::new(event))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{thread, time::Duration};
@@ -128,7 +1409,17 @@ mod tests {
         identity, PeerId,
     };
 
-    use crate::protocol::Protocols;
+    use crate::compression::Compression;
+    use crate::dedup::{DedupCache, DEDUP_TTL};
+    use crate::delivery::{MessageId, Receipt, ReceiptKind};
+    use crate::middleware::{Compressor, Pipeline};
+    use crate::protocol::{
+        content_message_id, inbox_topic, BinaryMessage, Body, Envelope, EnvelopeError,
+        EnvelopeKind, Fragment, Outbound, OutboundQueue, Payload, Protocols, Reassembler,
+        TextMessage, TrafficClass, DEFAULT_MAX_MESSAGE_SIZE, ENVELOPE_VERSION, FRAGMENT_SIZE,
+        MAX_CLOCK_SKEW, MAX_FRAGMENTS, QUANTUM,
+    };
+    use crate::topic::PubsubProtocol;
 
     #[test]
     fn test_procotols_new() {
@@ -139,14 +1430,119 @@ mod tests {
         assert!(protocols.gossipsub.topics().next().is_none());
     }
 
+    #[test]
+    fn test_content_message_id() {
+        let message = |source, data: &[u8], topic: &str| gossipsub::Message {
+            source: Some(source),
+            data: data.to_vec(),
+            sequence_number: Some(rand::random()),
+            topic: gossipsub::IdentTopic::new(topic).hash(),
+        };
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        assert_eq!(
+            content_message_id(&message(alice, b"envelope", "chat")),
+            content_message_id(&message(bob, b"envelope", "chat"))
+        );
+        assert_ne!(
+            content_message_id(&message(alice, b"envelope", "chat")),
+            content_message_id(&message(alice, b"envelope", "other"))
+        );
+    }
+
+    #[test]
+    fn test_outbound_queue_shares() {
+        let message = |tag: u8| Outbound::Publish {
+            topic: "chat".to_string(),
+            protocol: PubsubProtocol::Both,
+            data: vec![tag; QUANTUM],
+        };
+        let mut queue = OutboundQueue::new(1024, DEFAULT_MAX_MESSAGE_SIZE);
+        for _ in 0..32 {
+            queue.push(TrafficClass::Bulk, message(3));
+            queue.push(TrafficClass::Topic, message(2));
+            queue.push(TrafficClass::Control, message(0));
+        }
+
+        let mut sent = [0; 4];
+        for _ in 0..33 {
+            match queue.next() {
+                Some((_, Outbound::Publish { data, .. })) => sent[data[0] as usize] += 1,
+                _ => panic!("queue drained early"),
+            }
+        }
+        // Control, topic, and bulk traffic share the bandwidth 8:2:1.
+        assert_eq!(sent, [24, 0, 6, 3]);
+
+        assert!(!queue.is_empty());
+        while queue.next().is_some() {}
+        assert!(queue.is_empty());
+        assert!(queue.next().is_none());
+    }
+
+    #[test]
+    fn test_outbound_queue_holds_without_peers() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let mut protocols = Protocols::new(peer_id, keypair.clone());
+        protocols.subscribe("chat", PubsubProtocol::Both).unwrap();
+        let message = |tag: u8| Outbound::Publish {
+            topic: "chat".to_string(),
+            protocol: PubsubProtocol::Both,
+            data: vec![tag; 16],
+        };
+        let mut queue = OutboundQueue::new(1 << 20, DEFAULT_MAX_MESSAGE_SIZE);
+        queue.push(TrafficClass::Topic, message(1));
+        queue.push(TrafficClass::Control, message(2));
+        queue.push(TrafficClass::Topic, message(3));
+        thread::sleep(Duration::from_millis(10));
+        queue.flush(&mut protocols, &mut Pipeline::new(), peer_id);
+
+        // Chat is held for a peer to join, control messages are not.
+        assert!(queue.is_empty());
+        assert_eq!(queue.held(), 2);
+        queue.retry(&protocols, &keypair);
+        assert!(queue.is_empty());
+        assert_eq!(queue.held(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_held_messages_sent_once_peers_join() {
+        use crate::ipc::{Request, Response};
+        use crate::testing::TestNet;
+
+        let alone = TestNet::new(1, &["chat"]).await.unwrap();
+        let other = TestNet::new(1, &["chat"]).await.unwrap();
+        let queued = || async {
+            match alone.client(0).ask(Request::Status).await {
+                Some(Response::Status { queued, .. }) => queued,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        };
+        alone.client(0).publish("chat", "first").await.unwrap();
+        alone.client(0).publish("chat", "second").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(queued().await, 2);
+
+        alone.client(0).dial(&other.node(0).address).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while queued().await > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("The queued messages were not sent");
+        alone.shutdown().await;
+        other.shutdown().await;
+    }
+
     #[test]
     fn test_subscribe_publish() {
         let keypair = identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from(keypair.public());
-        let mut protocols = Protocols::new(peer_id, keypair);
+        let mut protocols = Protocols::new(peer_id, keypair.clone());
 
         let topic = "test-topic";
-        protocols.subscribe(topic).unwrap();
+        protocols.subscribe(topic, PubsubProtocol::Both).unwrap();
 
         // Check Floodsub subscription
         let _floodsub_topic = floodsub::Topic::new(topic);
@@ -162,8 +1558,13 @@ mod tests {
 
         thread::sleep(Duration::from_millis(100));
 
-        let data = b"test-message";
-        match protocols.publish(topic, data) {
+        let payload = Payload::Text(TextMessage {
+            id: MessageId::random(),
+            body: "test-message".to_string(),
+            ack_requested: false,
+        });
+        let envelope = Envelope::seal(topic, &payload, None, &keypair).unwrap();
+        match protocols.publish(topic, PubsubProtocol::Both, envelope.encode().unwrap()) {
             Ok(_) => println!("Message published successfully"),
             Err(e) => {
                 if let Some(publish_error) = e.downcast_ref::<PublishError>() {
@@ -178,4 +1579,282 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_unsubscribe() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let mut protocols = Protocols::new(peer_id, keypair);
+
+        let topic = "test-topic";
+        protocols.subscribe(topic, PubsubProtocol::Both).unwrap();
+        protocols.unsubscribe(topic, PubsubProtocol::Both).unwrap();
+        assert!(protocols.gossipsub.topics().next().is_none());
+
+        // Leaving a topic twice is an error.
+        assert!(protocols.unsubscribe(topic, PubsubProtocol::Both).is_err());
+
+        // Floodsub-only topics never reach Gossipsub.
+        protocols
+            .subscribe(topic, PubsubProtocol::Floodsub)
+            .unwrap();
+        assert!(protocols.gossipsub.topics().next().is_none());
+        protocols
+            .unsubscribe(topic, PubsubProtocol::Floodsub)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let id = MessageId::random();
+        let payload = Payload::Text(TextMessage {
+            id,
+            body: "hello".to_string(),
+            ack_requested: true,
+        });
+        let envelope =
+            Envelope::seal("chat", &payload, Some("alice".to_string()), &keypair).unwrap();
+        let data = envelope.encode().unwrap();
+
+        assert_eq!(Envelope::peek_kind(&data), Some(EnvelopeKind::Text));
+        let decoded = Envelope::decode(&data).unwrap();
+        assert_eq!(decoded.sender.as_deref(), Some("alice"));
+        match decoded.open("chat").unwrap() {
+            (signer, Payload::Text(text)) => {
+                assert_eq!(signer, peer_id);
+                assert_eq!(text.id, id);
+                assert_eq!(text.body, "hello");
+                assert!(text.ack_requested);
+            }
+            other => panic!("Unexpected payload: {:?}", other),
+        }
+        assert!(Envelope::decode(b"not an envelope").is_err());
+    }
+
+    #[test]
+    fn test_text_and_binary_kinds() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let binary = |media_type: &str| BinaryMessage {
+            id: MessageId::random(),
+            media_type: media_type.to_string(),
+            data: vec![0x89, b'P', b'N', b'G', 0xff],
+        };
+        let payload = Payload::Binary(binary("image/png"));
+        let data = Envelope::seal("chat", &payload, None, &keypair)
+            .unwrap()
+            .encode()
+            .unwrap();
+        assert_eq!(Envelope::peek_kind(&data), Some(EnvelopeKind::Binary));
+        match Envelope::decode(&data).unwrap().open("chat").unwrap() {
+            (_, Payload::Binary(binary)) => {
+                assert!(binary.is_valid());
+                assert_eq!(binary.data, [0x89, b'P', b'N', b'G', 0xff]);
+            }
+            other => panic!("Unexpected payload: {:?}", other),
+        }
+        for media_type in ["", "image", "image/", "/png", "image/png/x", "image/p ng"] {
+            assert!(!binary(media_type).is_valid(), "{:?}", media_type);
+        }
+
+        // Text that is not UTF-8 is malformed rather than shown lossily.
+        let body = Body {
+            kind: EnvelopeKind::Text,
+            compression: Compression::None,
+            data: bincode::serialize(&(MessageId::random(), vec![0xffu8, 0xfe], false)).unwrap(),
+        };
+        assert!(matches!(body.decode(), Err(EnvelopeError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_envelope_rejects_tampering() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let payload = Payload::Receipt(Receipt {
+            message_id: MessageId::random(),
+            kind: ReceiptKind::Delivered,
+        });
+        let mut envelope = Envelope::seal("chat", &payload, None, &keypair).unwrap();
+        envelope.sender = Some("mallory".to_string());
+        assert!(matches!(
+            envelope.open("chat"),
+            Err(EnvelopeError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_envelope_replayed_across_topics() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let payload = Payload::Receipt(Receipt {
+            message_id: MessageId::random(),
+            kind: ReceiptKind::Read,
+        });
+        let envelope = Envelope::seal("chat", &payload, None, &keypair).unwrap();
+        assert!(envelope.open("chat").is_ok());
+
+        // Neither another topic nor an inbox takes it, even relabeled.
+        let inbox = inbox_topic(&PeerId::random());
+        for topic in ["other", inbox.as_str()] {
+            assert!(matches!(
+                envelope.open(topic),
+                Err(EnvelopeError::WrongTopic(_))
+            ));
+            let mut relabeled = envelope.clone();
+            relabeled.topic = topic.to_string();
+            assert!(matches!(
+                relabeled.open(topic),
+                Err(EnvelopeError::InvalidSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_envelope_replayed_after_dedup_ttl() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let payload = Payload::Receipt(Receipt {
+            message_id: MessageId::random(),
+            kind: ReceiptKind::Delivered,
+        });
+        let envelope = Envelope::seal("chat", &payload, None, &keypair).unwrap();
+        let data = envelope.encode().unwrap();
+        assert!(envelope.check_fresh().is_ok());
+
+        // A replay within the skew is a duplicate, and one after the dedup
+        // cache forgot the envelope is stale.
+        let mut seen = DedupCache::new();
+        assert!(seen.insert("chat", &data));
+        assert!(!seen.insert("chat", &data));
+        seen.expire(Duration::ZERO);
+        assert!(seen.insert("chat", &data));
+        let replayed_at = envelope.timestamp + DEDUP_TTL.as_millis() as u64;
+        assert!(matches!(
+            envelope.check_fresh_at(replayed_at),
+            Err(EnvelopeError::Stale(_))
+        ));
+        assert!(DEDUP_TTL >= MAX_CLOCK_SKEW * 2);
+
+        // Clocks may disagree within the skew either way.
+        let skew = MAX_CLOCK_SKEW.as_millis() as u64;
+        assert!(envelope.check_fresh_at(envelope.timestamp + skew).is_ok());
+        assert!(envelope.check_fresh_at(envelope.timestamp - skew).is_ok());
+        assert!(envelope
+            .check_fresh_at(envelope.timestamp - skew - 1)
+            .is_err());
+
+        // Messages held for peers are signed again when released.
+        let restamped = envelope.restamp(&keypair).unwrap();
+        assert!(restamped.timestamp >= envelope.timestamp);
+        assert!(restamped.open("chat").is_ok());
+    }
+
+    #[test]
+    fn test_envelope_unknown_version_and_kind() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let payload = Payload::Receipt(Receipt {
+            message_id: MessageId::random(),
+            kind: ReceiptKind::Read,
+        });
+        let mut envelope = Envelope::seal("chat", &payload, None, &keypair).unwrap();
+        envelope.version = ENVELOPE_VERSION + 1;
+        assert!(matches!(
+            Envelope::decode(&envelope.encode().unwrap()),
+            Err(EnvelopeError::UnsupportedVersion(_))
+        ));
+
+        envelope.version = ENVELOPE_VERSION;
+        envelope.kind = EnvelopeKind::Unknown(42);
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(decoded.kind, EnvelopeKind::Unknown(42));
+    }
+
+    #[test]
+    fn test_fragment_reassembly() {
+        let source = PeerId::random();
+        let data: Vec<u8> = (0..FRAGMENT_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut fragments = Fragment::split(&data).unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        // Fragments may arrive out of order and more than once.
+        let mut reassembler = Reassembler::default();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.iter().rev() {
+            assert!(reassembler.insert(source, fragment.clone()).is_none());
+        }
+        assert!(reassembler.insert(source, fragments[0].clone()).is_none());
+        assert_eq!(reassembler.insert(source, last.clone()), Some(data));
+
+        // Late duplicates of a completed set are ignored.
+        assert!(reassembler.insert(source, last).is_none());
+        assert!(reassembler.expire(Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_fragment_limits_and_timeout() {
+        let oversized = vec![0u8; FRAGMENT_SIZE * MAX_FRAGMENTS as usize + 1];
+        assert!(Fragment::split(&oversized).is_err());
+
+        let source = PeerId::random();
+        let fragments = Fragment::split(&[1u8; FRAGMENT_SIZE + 1]).unwrap();
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.insert(source, fragments[0].clone()).is_none());
+        let expired = reassembler.expire(Duration::ZERO);
+        assert_eq!(expired, vec![(source, fragments[0].set_id)]);
+
+        let mut bogus = fragments[1].clone();
+        bogus.index = bogus.total;
+        assert!(reassembler.insert(source, bogus).is_none());
+
+        // Messages above the maximum size are refused on both ends, sets of
+        // fragments before they are buffered.
+        let mut reassembler = Reassembler::new(FRAGMENT_SIZE);
+        assert!(reassembler.accepts(FRAGMENT_SIZE));
+        assert!(!reassembler.accepts(FRAGMENT_SIZE + 1));
+        assert!(reassembler.insert(source, fragments[0].clone()).is_none());
+        assert!(reassembler.partial.is_empty());
+
+        let keypair = identity::Keypair::generate_ed25519();
+        let mut queue = OutboundQueue::new(1 << 20, 1024);
+        let payload = Payload::Text(TextMessage {
+            id: MessageId::random(),
+            body: "x".repeat(1024),
+            ack_requested: false,
+        });
+        let error = queue
+            .publish_payload(
+                "chat",
+                PubsubProtocol::Both,
+                &payload,
+                None,
+                &keypair,
+                &mut Pipeline::new(),
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("/send-file"));
+    }
+
+    #[test]
+    fn test_envelope_compression() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let body = "compressible ".repeat(500);
+        let payload = Payload::Text(TextMessage {
+            id: MessageId::random(),
+            body: body.clone(),
+            ack_requested: false,
+        });
+        let mut middleware = Pipeline::new();
+        middleware.register(100, Box::new(Compressor));
+        let envelope = middleware.seal("chat", &payload, None, &keypair).unwrap();
+        assert_eq!(envelope.compression, Compression::Lz4);
+        assert!(envelope.payload.len() < body.len());
+        match middleware.open("chat", &envelope).unwrap() {
+            (_, Payload::Text(text)) => assert_eq!(text.body, body),
+            other => panic!("Unexpected payload: {:?}", other),
+        }
+        // Without the compressor, the body cannot be read.
+        assert!(envelope.open("chat").is_err());
+
+        let mut unsupported = envelope.clone();
+        unsupported.compression = Compression::Unknown(7);
+        assert!(middleware.open("chat", &unsupported).is_err());
+    }
 }
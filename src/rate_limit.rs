@@ -0,0 +1,405 @@
+/*!
+ * Rate limiting module for the messaging application.
+ *
+ * This module provides a per-peer token bucket limiting how many messages
+ * are processed from each peer. Every peer may send a burst of messages at
+ * once, after which its bucket refills at a steady rate, so unlike a fixed
+ * window there is no window edge at which twice the limit is allowed.
+ *
+ * Every peer has a bucket per topic, so a peer flooding a busy topic
+ * still gets its direct messages through, and control messages such as
+ * receipts and presence beacons are counted in buckets of their own, so
+ * chatting does not starve them nor they chatting. The limits can differ
+ * by topic and by class of peer: a topic may have its own limits, and
+ * contacts may be allowed more than strangers on the other topics.
+ *
+ * The number of tracked buckets is bounded: buckets that have refilled
+ * completely carry no information and are expired, and when the limit is
+ * reached the least recently used bucket is evicted.
+ *
+ * The limiter is the middleware layer filtering inbound messages.
+ */
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use libp2p::PeerId;
+use web_time::Instant;
+
+use crate::hooks::{Direction, Verdict};
+use crate::middleware::{Context, Middleware};
+use crate::protocol::{Envelope, EnvelopeKind};
+
+/// Sustained rate and burst of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    /// Messages allowed per minute.
+    pub per_minute: u32,
+    /// Messages allowed at once.
+    pub burst: u32,
+}
+
+impl Limit {
+    /// Creates a new `Limit`.
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Limit { per_minute, burst }
+    }
+
+    /// Tokens added per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    /// Maximum number of tokens a bucket holds.
+    fn capacity(&self) -> f64 {
+        f64::from(self.burst.max(1))
+    }
+}
+
+/// What a message is counted as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageClass {
+    /// Chat messages, reactions, note edits and everything else.
+    Chat,
+    /// Receipts, moderation directives, presence beacons and profiles.
+    Control,
+}
+
+impl MessageClass {
+    /// Returns the class of an encoded envelope, from its header alone.
+    pub fn of(data: &[u8]) -> Self {
+        match Envelope::peek_kind(data) {
+            Some(
+                EnvelopeKind::Receipt
+                | EnvelopeKind::Moderation
+                | EnvelopeKind::Presence
+                | EnvelopeKind::Profile,
+            ) => MessageClass::Control,
+            _ => MessageClass::Chat,
+        }
+    }
+}
+
+/// The limits applied to peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Limits of strangers, and of contacts without their own.
+    pub default: Limit,
+    /// Limits of contacts.
+    pub contact: Option<Limit>,
+    pub contacts: HashSet<PeerId>,
+    /// Limits of every peer on a topic, overriding the others.
+    pub topics: HashMap<String, Limit>,
+    /// Limits of the control messages of every peer on a topic.
+    pub control: Limit,
+}
+
+impl Limits {
+    /// Creates `Limits` applying the same limits everywhere, control
+    /// messages included.
+    pub fn new(default: Limit) -> Self {
+        Limits {
+            default,
+            contact: None,
+            contacts: HashSet::new(),
+            topics: HashMap::new(),
+            control: default,
+        }
+    }
+
+    /// Returns the limits of a bucket.
+    fn limit(&self, (peer, topic, class): &BucketKey) -> Limit {
+        if *class == MessageClass::Control {
+            return self.control;
+        }
+        if let Some(limit) = self.topics.get(topic) {
+            return *limit;
+        }
+        self.contact
+            .filter(|_| self.contacts.contains(peer))
+            .unwrap_or(self.default)
+    }
+}
+
+/// A peer, the topic and the class of the messages counted in a bucket.
+type BucketKey = (PeerId, String, MessageClass);
+
+/// Token bucket of a peer on a topic.
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Position of the bucket in the recency order.
+    tick: u64,
+}
+
+/// Token bucket rate limiter, per peer and topic.
+pub struct RateLimiter {
+    limits: Limits,
+    max_buckets: usize,
+    buckets: HashMap<BucketKey, TokenBucket>,
+    /// Tracked buckets ordered from least to most recently used.
+    recency: BTreeMap<u64, BucketKey>,
+    next_tick: u64,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits applied to peers.
+    /// * `max_buckets` - The maximum number of buckets tracked at once.
+    ///
+    /// # Returns
+    ///
+    /// A new `RateLimiter` instance.
+    pub fn new(limits: Limits, max_buckets: usize) -> Self {
+        RateLimiter {
+            limits,
+            max_buckets: max_buckets.max(1),
+            buckets: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_tick: 0,
+        }
+    }
+
+    /// Changes the limits, keeping the buckets of the tracked peers.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits applied to peers.
+    /// * `max_buckets` - The maximum number of buckets tracked at once.
+    pub fn reconfigure(&mut self, limits: Limits, max_buckets: usize) {
+        self.limits = limits;
+        self.max_buckets = max_buckets.max(1);
+        for (key, bucket) in &mut self.buckets {
+            bucket.tokens = bucket.tokens.min(self.limits.limit(key).capacity());
+        }
+        while self.buckets.len() > self.max_buckets {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.buckets.remove(&oldest);
+            }
+        }
+    }
+
+    /// Takes a token from the bucket of `peer` for a message on `topic`.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer the message comes from.
+    /// * `topic` - The topic of the message.
+    /// * `class` - What the message is counted as.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the message may be processed.
+    pub fn check(&mut self, peer: PeerId, topic: &str, class: MessageClass) -> bool {
+        self.check_at((peer, topic.to_string(), class), Instant::now())
+    }
+
+    /// Stops tracking buckets that have refilled completely.
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn check_at(&mut self, key: BucketKey, now: Instant) -> bool {
+        let limit = self.limits.limit(&key);
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= self.max_buckets {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.buckets.remove(&oldest);
+            }
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        let capacity = limit.capacity();
+        let bucket = self.buckets.entry(key.clone()).or_insert(TokenBucket {
+            tokens: capacity,
+            updated_at: now,
+            tick,
+        });
+        self.recency.remove(&bucket.tick);
+        self.recency.insert(tick, key);
+        bucket.tick = tick;
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * limit.rate()).min(capacity);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        // Buckets are visited from least recently used, so the first bucket
+        // still refilling ends the scan; buckets refilling faster behind it
+        // are expired on a later scan.
+        while let Some((&tick, key)) = self.recency.first_key_value() {
+            let limit = self.limits.limit(key);
+            if limit.per_minute == 0 {
+                break;
+            }
+            let refill = Duration::from_secs_f64(limit.capacity() / limit.rate());
+            let idle = self
+                .buckets
+                .get(key)
+                .is_none_or(|bucket| now.saturating_duration_since(bucket.updated_at) >= refill);
+            if !idle {
+                break;
+            }
+            self.buckets.remove(key);
+            self.recency.remove(&tick);
+        }
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn name(&self) -> &str {
+        "rate-limit"
+    }
+
+    fn filter(&mut self, context: &Context, data: &[u8]) -> Verdict {
+        match context.direction == Direction::Outbound
+            || self.check(context.peer, context.topic, MessageClass::of(data))
+        {
+            true => Verdict::Continue,
+            false => Verdict::Drop("rate limited".to_string()),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.expire();
+    }
+
+    fn compact(&mut self) {
+        self.buckets.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libp2p::PeerId;
+    use web_time::Instant;
+
+    use super::{BucketKey, Limit, Limits, MessageClass, RateLimiter};
+
+    fn chat(peer: PeerId, topic: &str) -> BucketKey {
+        (peer, topic.to_string(), MessageClass::Chat)
+    }
+
+    fn limiter(per_minute: u32, burst: u32, max_buckets: usize) -> RateLimiter {
+        RateLimiter::new(Limits::new(Limit::new(per_minute, burst)), max_buckets)
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let mut limiter = limiter(60, 3, 100);
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(chat(peer, "chat"), start));
+        }
+        assert!(!limiter.check_at(chat(peer, "chat"), start));
+        // Other peers have their own bucket.
+        assert!(limiter.check_at(chat(PeerId::random(), "chat"), start));
+
+        assert!(!limiter.check_at(chat(peer, "chat"), start + Duration::from_millis(999)));
+        assert!(limiter.check_at(chat(peer, "chat"), start + Duration::from_secs(1)));
+        assert!(!limiter.check_at(chat(peer, "chat"), start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_no_double_burst_at_window_edge() {
+        let mut limiter = limiter(60, 3, 100);
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        // A long idle period refills the bucket to the burst size only.
+        let idle = start + Duration::from_secs(3600);
+        let allowed = (0..10)
+            .filter(|_| limiter.check_at(chat(peer, "chat"), idle))
+            .count();
+        assert_eq!(allowed, 3);
+
+        // A clock going backwards does not add tokens.
+        assert!(!limiter.check_at(chat(peer, "chat"), start));
+    }
+
+    #[test]
+    fn test_tracked_peers_are_bounded() {
+        let mut limiter = limiter(60, 1, 2);
+        let start = Instant::now();
+        let (first, second, third) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        assert!(limiter.check_at(chat(first, "chat"), start));
+        assert!(limiter.check_at(chat(second, "chat"), start));
+        assert!(!limiter.check_at(chat(first, "chat"), start));
+        // The least recently seen peer is evicted and starts over.
+        assert!(limiter.check_at(chat(third, "chat"), start));
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(!limiter.check_at(chat(first, "chat"), start));
+        assert!(limiter.check_at(chat(second, "chat"), start));
+
+        limiter.expire_at(start + Duration::from_millis(500));
+        assert_eq!(limiter.buckets.len(), 2);
+        limiter.expire_at(start + Duration::from_secs(1));
+        assert!(limiter.buckets.is_empty());
+        assert!(limiter.recency.is_empty());
+    }
+
+    #[test]
+    fn test_topic_and_contact_limits() {
+        let (contact, stranger) = (PeerId::random(), PeerId::random());
+        let mut limits = Limits::new(Limit::new(60, 1));
+        limits.contact = Some(Limit::new(60, 3));
+        limits.contacts.insert(contact);
+        limits
+            .topics
+            .insert("announcements".to_string(), Limit::new(60, 2));
+        limits.control = Limit::new(60, 4);
+        let mut limiter = RateLimiter::new(limits, 100);
+        let start = Instant::now();
+
+        let allowed = |limiter: &mut RateLimiter, key: BucketKey| {
+            (0..10)
+                .filter(|_| limiter.check_at(key.clone(), start))
+                .count()
+        };
+        assert_eq!(allowed(&mut limiter, chat(stranger, "chat")), 1);
+        assert_eq!(allowed(&mut limiter, chat(contact, "chat")), 3);
+        assert_eq!(allowed(&mut limiter, chat(contact, "announcements")), 2);
+        assert_eq!(allowed(&mut limiter, chat(stranger, "announcements")), 2);
+        // Every topic has its own bucket, and control messages have theirs.
+        assert_eq!(allowed(&mut limiter, chat(stranger, "rust")), 1);
+        let control = (stranger, "chat".to_string(), MessageClass::Control);
+        assert_eq!(allowed(&mut limiter, control), 4);
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let mut limiter = limiter(60, 5, 3);
+        let start = Instant::now();
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            assert!(limiter.check_at(chat(*peer, "chat"), start));
+        }
+
+        // A smaller burst applies to the tokens left, and the least
+        // recently used buckets beyond the new bound are evicted.
+        limiter.reconfigure(Limits::new(Limit::new(120, 2)), 2);
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(!limiter.buckets.contains_key(&chat(peers[0], "chat")));
+        assert!(limiter.check_at(chat(peers[2], "chat"), start));
+        assert!(limiter.check_at(chat(peers[2], "chat"), start));
+        assert!(!limiter.check_at(chat(peers[2], "chat"), start));
+        assert!(limiter.check_at(chat(peers[2], "chat"), start + Duration::from_millis(500)));
+    }
+}
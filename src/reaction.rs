@@ -0,0 +1,220 @@
+/*!
+ * Reaction module for the messaging application.
+ *
+ * Reactions attach a short emoji to a message on the same topic instead of
+ * replying to it. This module keeps the recently seen messages of every
+ * topic and aggregates the reactions they received, so the UI can render
+ * them compactly and users can refer to messages by an ID prefix. Direct
+ * messages are acknowledged with receipts instead and cannot be reacted to.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::delivery::MessageId;
+use crate::protocol::is_inbox_topic;
+
+/// Number of recent messages reactions are aggregated for.
+pub const MAX_TRACKED_MESSAGES: usize = 1000;
+
+/// Maximum number of characters in a reaction.
+pub const MAX_REACTION_LEN: usize = 8;
+
+/// A reaction to a message, or its withdrawal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub target: MessageId,
+    pub emoji: String,
+    pub removed: bool,
+}
+
+impl Reaction {
+    /// Returns whether the reaction is short and printable.
+    pub fn is_valid(&self) -> bool {
+        let len = self.emoji.chars().count();
+        (1..=MAX_REACTION_LEN).contains(&len)
+            && !self
+                .emoji
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+    }
+}
+
+/// A recently seen message and the reactions it received.
+struct Reacted {
+    topic: String,
+    reactions: BTreeMap<String, BTreeSet<PeerId>>,
+}
+
+/// Recently seen messages and their reactions.
+pub struct Reactions {
+    messages: HashMap<MessageId, Reacted>,
+    order: VecDeque<MessageId>,
+}
+
+impl Reactions {
+    /// Creates a new, empty `Reactions` store.
+    pub fn new() -> Self {
+        Reactions {
+            messages: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records a message that can be reacted to.
+    ///
+    /// Messages on inbox topics are ignored.
+    /// # Arguments
+    ///
+    /// * `id` - The message ID.
+    /// * `topic` - The topic the message was published on.
+    pub fn record(&mut self, id: MessageId, topic: &str) {
+        if is_inbox_topic(topic) || self.messages.contains_key(&id) {
+            return;
+        }
+        self.messages.insert(
+            id,
+            Reacted {
+                topic: topic.to_string(),
+                reactions: BTreeMap::new(),
+            },
+        );
+        self.order.push_back(id);
+        if self.order.len() > MAX_TRACKED_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    /// Finds a recent message by a prefix of its hex ID.
+    ///
+    /// # Returns
+    ///
+    /// The message ID and topic, if exactly one message matches.
+    pub fn resolve(&self, prefix: &str) -> Option<(MessageId, &str)> {
+        let prefix = prefix.trim_start_matches('#').to_lowercase();
+        let mut matches = self
+            .messages
+            .iter()
+            .filter(|(id, _)| id.to_string().starts_with(&prefix));
+        match (matches.next(), matches.next()) {
+            (Some((id, message)), None) if !prefix.is_empty() => Some((*id, &message.topic)),
+            _ => None,
+        }
+    }
+
+    /// Applies a reaction received on a topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer that signed the reaction.
+    /// * `topic` - The topic the reaction was received on.
+    /// * `reaction` - The reaction.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the reactions of the target message changed.
+    pub fn apply(&mut self, peer: PeerId, topic: &str, reaction: &Reaction) -> bool {
+        if !reaction.is_valid() {
+            return false;
+        }
+        let Some(message) = self.messages.get_mut(&reaction.target) else {
+            return false;
+        };
+        if message.topic != topic {
+            return false;
+        }
+        if !reaction.removed {
+            return message
+                .reactions
+                .entry(reaction.emoji.clone())
+                .or_default()
+                .insert(peer);
+        }
+        let Some(peers) = message.reactions.get_mut(&reaction.emoji) else {
+            return false;
+        };
+        let removed = peers.remove(&peer);
+        if peers.is_empty() {
+            message.reactions.remove(&reaction.emoji);
+        }
+        removed
+    }
+
+    /// Returns the compact rendering of the reactions to a message.
+    pub fn summary(&self, id: &MessageId) -> String {
+        self.messages
+            .get(id)
+            .map(|message| {
+                message
+                    .reactions
+                    .iter()
+                    .map(|(emoji, peers)| format!("{} {}", emoji, peers.len()))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Reactions {
+    fn default() -> Self {
+        Reactions::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{Reaction, Reactions};
+    use crate::delivery::MessageId;
+
+    fn reaction(target: MessageId, emoji: &str, removed: bool) -> Reaction {
+        Reaction {
+            target,
+            emoji: emoji.to_string(),
+            removed,
+        }
+    }
+
+    #[test]
+    fn test_aggregate_reactions() {
+        let mut reactions = Reactions::new();
+        let id = MessageId::random();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        reactions.record(id, "chat");
+
+        assert!(reactions.apply(alice, "chat", &reaction(id, "👍", false)));
+        assert!(!reactions.apply(alice, "chat", &reaction(id, "👍", false)));
+        assert!(reactions.apply(bob, "chat", &reaction(id, "👍", false)));
+        assert!(reactions.apply(bob, "chat", &reaction(id, "🎉", false)));
+        assert_eq!(reactions.summary(&id), "🎉 1  👍 2");
+
+        assert!(reactions.apply(bob, "chat", &reaction(id, "🎉", true)));
+        assert_eq!(reactions.summary(&id), "👍 2");
+
+        // Reactions on other topics, to unknown messages or too long are ignored.
+        assert!(!reactions.apply(alice, "other", &reaction(id, "🎉", false)));
+        assert!(!reactions.apply(alice, "chat", &reaction(MessageId::random(), "👍", false)));
+        assert!(!reactions.apply(alice, "chat", &reaction(id, "way too long", false)));
+    }
+
+    #[test]
+    fn test_resolve_prefix() {
+        let mut reactions = Reactions::new();
+        let id = MessageId([0xab; 16]);
+        reactions.record(id, "chat");
+        reactions.record(MessageId([0xac; 16]), "chat");
+
+        assert_eq!(reactions.resolve("#abab"), Some((id, "chat")));
+        assert_eq!(reactions.resolve("a"), None);
+        assert_eq!(reactions.resolve(""), None);
+
+        reactions.record(MessageId([0xad; 16]), "dm/someone");
+        assert_eq!(reactions.resolve("ad"), None);
+    }
+}
@@ -0,0 +1,534 @@
+/*!
+ * Record module for the messaging application.
+ *
+ * A node started with `--record PATH` writes the events it receives to a
+ * file: the connections of peers, the messages and subscriptions of both
+ * pubsub protocols, and the ticks of its loop, each with the time it came
+ * at. `sec_msg replay PATH` feeds them back through the event handlers of
+ * an offline node, in the same order and at the same pace, so what a user
+ * saw can be reproduced on another machine without their peers. Requests
+ * between peers, such as history and file transfers, are not recorded, as
+ * their answers go through channels that cannot be rebuilt.
+ *
+ * A recording starts with a magic number and a header naming the peer that
+ * recorded it, followed by the events encoded with bincode. It holds the
+ * messages received in clear, so it should be shared with care. A node
+ * stopped abruptly leaves a recording that replays up to its last whole
+ * event.
+ */
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    num::NonZeroU32,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{
+    core::{ConnectedPoint, Endpoint},
+    floodsub::{FloodsubEvent, FloodsubMessage, Topic},
+    gossipsub::{self, TopicHash},
+    swarm::{ConnectionId, SwarmEvent},
+    Multiaddr, PeerId,
+};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::event;
+use crate::network::create_swarm;
+use crate::node::Node;
+use crate::protocol::ProtocolEvent;
+use crate::ui::Interface;
+use crate::utils;
+
+/// First bytes of a recording.
+const MAGIC: &[u8; 8] = b"SECMSGRC";
+
+/// Version of the format of recordings.
+const VERSION: u16 = 1;
+
+/// What a recording starts with.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Header {
+    version: u16,
+    /// The peer that recorded the events.
+    peer: PeerId,
+    /// When the recording started, in milliseconds since the Unix epoch.
+    started: u64,
+}
+
+/// The pubsub protocol an event came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Pubsub {
+    Floodsub,
+    Gossipsub,
+}
+
+/// An event as recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Recorded {
+    Connected {
+        peer: PeerId,
+        address: Multiaddr,
+        dialer: bool,
+        established: u32,
+    },
+    Disconnected {
+        peer: PeerId,
+        address: Multiaddr,
+        dialer: bool,
+        remaining: u32,
+    },
+    FloodsubMessage {
+        source: PeerId,
+        topics: Vec<String>,
+        data: Vec<u8>,
+        sequence_number: Vec<u8>,
+    },
+    GossipsubMessage {
+        propagation_source: PeerId,
+        id: Vec<u8>,
+        source: Option<PeerId>,
+        topic: String,
+        data: Vec<u8>,
+        sequence_number: Option<u64>,
+    },
+    Subscribed {
+        peer: PeerId,
+        topic: String,
+        pubsub: Pubsub,
+    },
+    Unsubscribed {
+        peer: PeerId,
+        topic: String,
+        pubsub: Pubsub,
+    },
+    /// The loop of the node ticked.
+    Tick,
+}
+
+/// An event and when it came.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    /// Milliseconds since the recording started.
+    at: u64,
+    event: Recorded,
+}
+
+impl Recorded {
+    /// Returns the recorded form of an event, if it is recorded.
+    fn from_event(event: &SwarmEvent<ProtocolEvent>) -> Option<Self> {
+        let recorded = match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => Recorded::Connected {
+                peer: *peer_id,
+                address: endpoint.get_remote_address().clone(),
+                dialer: endpoint.is_dialer(),
+                established: num_established.get(),
+            },
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => Recorded::Disconnected {
+                peer: *peer_id,
+                address: endpoint.get_remote_address().clone(),
+                dialer: endpoint.is_dialer(),
+                remaining: *num_established,
+            },
+            SwarmEvent::Behaviour(ProtocolEvent::Floodsub(event)) => match event {
+                FloodsubEvent::Message(message) => Recorded::FloodsubMessage {
+                    source: message.source,
+                    topics: message.topics.iter().map(|t| t.id().to_string()).collect(),
+                    data: message.data.to_vec(),
+                    sequence_number: message.sequence_number.clone(),
+                },
+                FloodsubEvent::Subscribed { peer_id, topic } => Recorded::Subscribed {
+                    peer: *peer_id,
+                    topic: topic.id().to_string(),
+                    pubsub: Pubsub::Floodsub,
+                },
+                FloodsubEvent::Unsubscribed { peer_id, topic } => Recorded::Unsubscribed {
+                    peer: *peer_id,
+                    topic: topic.id().to_string(),
+                    pubsub: Pubsub::Floodsub,
+                },
+            },
+            SwarmEvent::Behaviour(ProtocolEvent::Gossipsub(event)) => match event.as_ref() {
+                gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
+                    message,
+                } => Recorded::GossipsubMessage {
+                    propagation_source: *propagation_source,
+                    id: message_id.0.clone(),
+                    source: message.source,
+                    topic: message.topic.as_str().to_string(),
+                    data: message.data.clone(),
+                    sequence_number: message.sequence_number,
+                },
+                gossipsub::Event::Subscribed { peer_id, topic } => Recorded::Subscribed {
+                    peer: *peer_id,
+                    topic: topic.as_str().to_string(),
+                    pubsub: Pubsub::Gossipsub,
+                },
+                gossipsub::Event::Unsubscribed { peer_id, topic } => Recorded::Unsubscribed {
+                    peer: *peer_id,
+                    topic: topic.as_str().to_string(),
+                    pubsub: Pubsub::Gossipsub,
+                },
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(recorded)
+    }
+
+    /// Rebuilds the event, or `None` for a tick.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The number of the connection, for the connection
+    ///   events.
+    fn into_event(self, connection: usize) -> Option<SwarmEvent<ProtocolEvent>> {
+        let endpoint = |address: Multiaddr, dialer: bool| match dialer {
+            true => ConnectedPoint::Dialer {
+                address,
+                role_override: Endpoint::Dialer,
+            },
+            false => ConnectedPoint::Listener {
+                local_addr: Multiaddr::empty(),
+                send_back_addr: address,
+            },
+        };
+        let event = match self {
+            Recorded::Connected {
+                peer,
+                address,
+                dialer,
+                established,
+            } => SwarmEvent::ConnectionEstablished {
+                peer_id: peer,
+                connection_id: ConnectionId::new_unchecked(connection),
+                endpoint: endpoint(address, dialer),
+                num_established: NonZeroU32::new(established).unwrap_or(NonZeroU32::MIN),
+                concurrent_dial_errors: None,
+                established_in: Duration::ZERO,
+            },
+            Recorded::Disconnected {
+                peer,
+                address,
+                dialer,
+                remaining,
+            } => SwarmEvent::ConnectionClosed {
+                peer_id: peer,
+                connection_id: ConnectionId::new_unchecked(connection),
+                endpoint: endpoint(address, dialer),
+                num_established: remaining,
+                cause: None,
+            },
+            Recorded::FloodsubMessage {
+                source,
+                topics,
+                data,
+                sequence_number,
+            } => SwarmEvent::Behaviour(ProtocolEvent::Floodsub(FloodsubEvent::Message(
+                FloodsubMessage {
+                    source,
+                    data: data.into(),
+                    sequence_number,
+                    topics: topics.into_iter().map(Topic::new).collect(),
+                },
+            ))),
+            Recorded::GossipsubMessage {
+                propagation_source,
+                id,
+                source,
+                topic,
+                data,
+                sequence_number,
+            } => SwarmEvent::Behaviour(
+                gossipsub::Event::Message {
+                    propagation_source,
+                    message_id: gossipsub::MessageId(id),
+                    message: gossipsub::Message {
+                        source,
+                        data,
+                        sequence_number,
+                        topic: TopicHash::from_raw(topic),
+                    },
+                }
+                .into(),
+            ),
+            Recorded::Subscribed {
+                peer,
+                topic,
+                pubsub: Pubsub::Floodsub,
+            } => SwarmEvent::Behaviour(ProtocolEvent::Floodsub(FloodsubEvent::Subscribed {
+                peer_id: peer,
+                topic: Topic::new(topic),
+            })),
+            Recorded::Subscribed {
+                peer,
+                topic,
+                pubsub: Pubsub::Gossipsub,
+            } => SwarmEvent::Behaviour(
+                gossipsub::Event::Subscribed {
+                    peer_id: peer,
+                    topic: TopicHash::from_raw(topic),
+                }
+                .into(),
+            ),
+            Recorded::Unsubscribed {
+                peer,
+                topic,
+                pubsub: Pubsub::Floodsub,
+            } => SwarmEvent::Behaviour(ProtocolEvent::Floodsub(FloodsubEvent::Unsubscribed {
+                peer_id: peer,
+                topic: Topic::new(topic),
+            })),
+            Recorded::Unsubscribed {
+                peer,
+                topic,
+                pubsub: Pubsub::Gossipsub,
+            } => SwarmEvent::Behaviour(
+                gossipsub::Event::Unsubscribed {
+                    peer_id: peer,
+                    topic: TopicHash::from_raw(topic),
+                }
+                .into(),
+            ),
+            Recorded::Tick => return None,
+        };
+        Some(event)
+    }
+}
+
+/// Writes the events received by a node to a file.
+pub struct Recorder {
+    /// The file, until writing to it fails.
+    file: Option<BufWriter<File>>,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Creates a recording, replacing any file at its path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to write.
+    /// * `peer` - The local peer ID.
+    pub fn create(path: &Path, peer: PeerId) -> Result<Self, Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        file.write_all(MAGIC)?;
+        bincode::serialize_into(
+            &mut file,
+            &Header {
+                version: VERSION,
+                peer,
+                started,
+            },
+        )?;
+        file.flush()?;
+        info!("Recording the events received to {:?}", path);
+        Ok(Recorder {
+            file: Some(file),
+            started: Instant::now(),
+        })
+    }
+
+    /// Records an event, if it is one of those recorded.
+    pub fn record(&mut self, event: &SwarmEvent<ProtocolEvent>) {
+        if let Some(event) = Recorded::from_event(event) {
+            self.write(event);
+        }
+    }
+
+    /// Records a tick of the loop of the node.
+    pub fn tick(&mut self) {
+        self.write(Recorded::Tick);
+    }
+
+    fn write(&mut self, event: Recorded) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let entry = Entry {
+            at: self.started.elapsed().as_millis() as u64,
+            event,
+        };
+        // Flushed so the events before a crash are kept.
+        let written = bincode::serialize_into(&mut *file, &entry)
+            .map_err(|e| e.to_string())
+            .and_then(|()| file.flush().map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            error!("Stopped recording: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+/// Reads the header of a recording.
+fn read_header(reader: &mut impl Read) -> Result<Header, Box<dyn Error>> {
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err("Not a recording".into());
+    }
+    let header: Header = bincode::deserialize_from(&mut *reader)?;
+    if header.version != VERSION {
+        return Err(format!("Unsupported recording version {}", header.version).into());
+    }
+    Ok(header)
+}
+
+/// Reads the next event of a recording, or `None` at its end.
+fn read_entry(reader: &mut impl Read) -> Result<Option<Entry>, Box<dyn Error>> {
+    match bincode::deserialize_from(reader) {
+        Ok(entry) => Ok(Some(entry)),
+        // The end, or an event cut short by a crash.
+        Err(e) if matches!(&*e, bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof) => {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Feeds the events of a recording to an offline node.
+///
+/// # Arguments
+///
+/// * `path` - The recording.
+/// * `config` - The configuration of the node, without its addresses and
+///   data directory.
+/// * `fast` - Whether the events are fed without waiting for the time
+///   they came at.
+///
+/// # Returns
+///
+/// A `Result` containing the number of events replayed.
+pub async fn replay(path: &Path, config: &Config, fast: bool) -> Result<usize, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = read_header(&mut reader)?;
+    info!(
+        "Replaying the events received by {} from {:?}",
+        header.peer, path
+    );
+    let mut config = config.clone();
+    config.listen_addrs = Vec::new();
+    config.bootstrap = Vec::new();
+    config.dirs = None;
+    config.email = None;
+    config.pipe_topic = None;
+    config.interface = Interface::Plain;
+    let (local_key, local_peer_id) = utils::generate_keypair();
+    let swarm = create_swarm(
+        local_key.clone(),
+        local_peer_id,
+        &config.topics[0],
+        config.pubsub_protocol,
+        &config.connection,
+    )
+    .await?;
+    let mut node = Node::with_swarm(swarm, &config, local_key, None, None)?;
+
+    let started = Instant::now();
+    let mut replayed = 0;
+    while let Some(entry) = read_entry(&mut reader)? {
+        if !fast {
+            tokio::time::sleep_until(started + Duration::from_millis(entry.at)).await;
+        }
+        match entry.event.into_event(replayed) {
+            Some(event) => event::handle_event(event, &mut node.swarm, &mut node.state).await,
+            None => event::handle_tick(&mut node.state),
+        }
+        replayed += 1;
+    }
+    if replayed == 0 {
+        warn!("The recording holds no events");
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use libp2p::{
+        floodsub::{FloodsubEvent, FloodsubMessage, Topic},
+        gossipsub,
+        swarm::SwarmEvent,
+        PeerId,
+    };
+
+    use super::{read_entry, read_header, Entry, Header, Recorded, MAGIC, VERSION};
+    use crate::protocol::ProtocolEvent;
+
+    #[test]
+    fn test_round_trip() {
+        let peer = PeerId::random();
+        let floodsub = SwarmEvent::Behaviour(ProtocolEvent::Floodsub(FloodsubEvent::Message(
+            FloodsubMessage {
+                source: peer,
+                data: vec![1, 2, 3].into(),
+                sequence_number: vec![7],
+                topics: vec![Topic::new("chat")],
+            },
+        )));
+        let gossipsub = SwarmEvent::Behaviour(ProtocolEvent::from(gossipsub::Event::Subscribed {
+            peer_id: peer,
+            topic: gossipsub::TopicHash::from_raw("chat"),
+        }));
+        let mut data = MAGIC.to_vec();
+        let header = Header {
+            version: VERSION,
+            peer,
+            started: 1,
+        };
+        bincode::serialize_into(&mut data, &header).unwrap();
+        let recorded: Vec<Recorded> = [&floodsub, &gossipsub]
+            .into_iter()
+            .map(|event| Recorded::from_event(event).unwrap())
+            .chain([Recorded::Tick])
+            .collect();
+        for (at, event) in recorded.iter().enumerate() {
+            let entry = Entry {
+                at: at as u64,
+                event: event.clone(),
+            };
+            bincode::serialize_into(&mut data, &entry).unwrap();
+        }
+        // A crash leaves half an event at the end.
+        data.extend_from_slice(&[3, 0]);
+
+        let mut reader = Cursor::new(data);
+        assert_eq!(read_header(&mut reader).unwrap(), header);
+        let mut read = Vec::new();
+        while let Some(entry) = read_entry(&mut reader).unwrap() {
+            read.push(entry.event);
+        }
+        assert_eq!(read, recorded);
+
+        // Rebuilt, the events are recorded the same.
+        let rebuilt = read[0].clone().into_event(0).unwrap();
+        assert_eq!(Recorded::from_event(&rebuilt).unwrap(), recorded[0]);
+        let rebuilt = read[1].clone().into_event(1).unwrap();
+        assert_eq!(Recorded::from_event(&rebuilt).unwrap(), recorded[1]);
+        assert!(read[2].clone().into_event(2).is_none());
+
+        assert!(read_header(&mut Cursor::new(b"SECMSG\x00\x01".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+/*!
+ * Reload module for the messaging application.
+ *
+ * This module re-reads the configuration on SIGHUP or `/reload` and
+ * applies the settings that can change while running: the log level, the
+ * rate limits, the watched keywords, the bootstrap peers and the theme of
+ * the terminal UI. The swarm keeps running, so no connection is dropped;
+ * other settings take effect on the next start.
+ */
+
+use libp2p::{Multiaddr, Swarm};
+use log::info;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::app::{AppEvent, AppEvents};
+use crate::cli::Options;
+use crate::config::Config;
+use crate::network::bootstrap;
+use crate::protocol::Protocols;
+use crate::rate_limit::RateLimiter;
+use crate::state::AppState;
+use crate::theme::Theme;
+use crate::ui::{AppLogger, UiEvent};
+
+/// What is needed to reload the configuration.
+pub struct Reloader {
+    /// The command line options the configuration was read with.
+    options: Options,
+    logger: &'static AppLogger,
+    /// The channel to the terminal UI, if it runs.
+    ui: Option<UnboundedSender<UiEvent>>,
+    /// The bootstrap peers already dialed.
+    bootstrap: Vec<Multiaddr>,
+}
+
+impl Reloader {
+    /// Creates a new `Reloader`.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The command line options.
+    /// * `config` - The configuration read at startup.
+    /// * `logger` - The global logger.
+    /// * `ui` - The channel to the terminal UI, if it runs.
+    pub fn new(
+        options: Options,
+        config: &Config,
+        logger: &'static AppLogger,
+        ui: Option<UnboundedSender<UiEvent>>,
+    ) -> Self {
+        Reloader {
+            options,
+            logger,
+            ui,
+            bootstrap: config.bootstrap.clone(),
+        }
+    }
+}
+
+/// Re-reads the configuration and applies the settings that can change
+/// while running.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success, or why the configuration was kept.
+pub fn reload(swarm: &mut Swarm<Protocols>, state: &mut AppState) -> Result<(), String> {
+    let reloader = state
+        .reloader
+        .as_mut()
+        .ok_or("Reloading is not available")?;
+    let config = Config::new(&reloader.options).map_err(|e| e.to_string())?;
+
+    reloader.logger.set_level(&config.log_level);
+    let new_peers: Vec<Multiaddr> = config
+        .bootstrap
+        .iter()
+        .filter(|addr| !reloader.bootstrap.contains(addr))
+        .cloned()
+        .collect();
+    reloader.bootstrap = config.bootstrap.clone();
+    state.supervisor.set_bootstrap(config.bootstrap);
+    if let Some(ui) = &reloader.ui {
+        let _ = ui.send(UiEvent::Theme(Theme::new(config.theme)));
+    }
+    bootstrap(swarm, &new_peers);
+    state.outgoing.reconfigure(config.rate_limits.clone());
+    if let Some(rate_limiter) = state.middleware.get_mut::<RateLimiter>() {
+        rate_limiter.reconfigure(config.rate_limits, config.rate_limit_peers);
+    }
+    state.notifier.set_keywords(config.keywords);
+    state.outbound.set_max_message_size(config.max_message_size);
+    state
+        .reassembler
+        .set_max_message_size(config.max_message_size);
+    info!("Reloaded the configuration");
+    Ok(())
+}
+
+/// Hangup signals asking for the configuration to be reloaded, which never
+/// arrive on platforms without them.
+pub struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    /// Starts listening for hangup signals.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Hangup {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    /// Waits for the next hangup signal.
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+
+    /// Asks the swarm loop to reload the configuration on every hangup
+    /// signal, until the loop is gone.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The channel to the swarm loop.
+    pub fn forward(mut self, events: AppEvents) {
+        tokio::spawn(async move {
+            loop {
+                self.recv().await;
+                if events.send(AppEvent::Reload).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,478 @@
+/*!
+ * Message rendering module for the messaging application.
+ *
+ * Chat messages are shown apart from the log: each one carries the wall
+ * clock time it was sent at and its author, drawn in a color derived from
+ * the peer ID so a peer keeps its color across sessions and peers. The
+ * terminal UI draws rendered messages itself; otherwise they are printed
+ * to stdout, colored when it is a terminal. Messages mentioning the local
+ * display name or a watched keyword are highlighted. The latest rendered
+ * messages are kept in a bounded scrollback the user can search.
+ *
+ * For bots and bridges, messages and significant events can instead be
+ * written to stdout as JSON lines, one object per line with an `event`
+ * field naming what happened.
+ */
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+use chrono::{Local, TimeZone};
+use crossterm::style::{Color, Stylize};
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::delivery::MessageId;
+use crate::ui::UiEvent;
+
+/// Colors peers are drawn in, as xterm 256 color indices readable on both
+/// dark and light backgrounds.
+const PEER_COLORS: [u8; 12] = [33, 39, 41, 70, 106, 130, 135, 166, 169, 172, 178, 202];
+
+/// Number of rendered messages kept in the scrollback.
+pub const SCROLLBACK_LIMIT: usize = 5000;
+
+/// Indentation of the lines of a message body after its first, so they
+/// cannot pass for other messages.
+pub const BODY_INDENT: &str = "    ";
+
+/// How times are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    H12,
+    H24,
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "12" | "12h" => Ok(Clock::H12),
+            "24" | "24h" => Ok(Clock::H24),
+            other => Err(format!("unknown clock {:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clock::H12 => write!(f, "12h"),
+            Clock::H24 => write!(f, "24h"),
+        }
+    }
+}
+
+/// What is written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Rendered chat messages.
+    Text,
+    /// JSON lines describing messages and events.
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            other => Err(format!("unknown output {:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Text => write!(f, "text"),
+            Output::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A JSON object written on a single line.
+pub struct JsonLine(String);
+
+impl JsonLine {
+    /// Starts an object describing an event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - What happened, such as `message` or `peer_connected`.
+    pub fn new(event: &str) -> Self {
+        JsonLine(String::from("{")).str("event", event)
+    }
+
+    /// Adds a string field.
+    pub fn str(mut self, key: &str, value: &str) -> Self {
+        self.key(key);
+        escape_into(&mut self.0, value);
+        self
+    }
+
+    /// Adds a boolean field.
+    pub fn bool(mut self, key: &str, value: bool) -> Self {
+        self.key(key);
+        self.0.push_str(if value { "true" } else { "false" });
+        self
+    }
+
+    /// Adds a number field.
+    pub fn num(mut self, key: &str, value: u64) -> Self {
+        self.key(key);
+        self.0.push_str(&value.to_string());
+        self
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        escape_into(&mut self.0, key);
+        self.0.push(':');
+    }
+}
+
+impl fmt::Display for JsonLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}}}", self.0)
+    }
+}
+
+/// Appends a string to `out` as a quoted JSON string.
+fn escape_into(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// How messages are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageFormat {
+    pub clock: Clock,
+    /// Whether named peers are followed by the end of their peer ID.
+    pub peer_suffix: bool,
+}
+
+/// A chat message ready to be shown.
+#[derive(Debug, Clone)]
+pub struct RenderedMessage {
+    pub time: String,
+    /// When the message was sent, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub topic: String,
+    pub id: MessageId,
+    /// The peer that authored the message.
+    pub source: PeerId,
+    pub sender: String,
+    /// Color of the sender, as an xterm 256 color index.
+    pub color: u8,
+    pub body: String,
+    /// Whether the message mentions the local display name or a watched
+    /// keyword.
+    pub highlight: bool,
+    /// Why a content filter flagged the message, if one did.
+    pub flag: Option<String>,
+}
+
+impl fmt::Display for RenderedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} [{}] #{} {}{}: {}",
+            self.time,
+            self.topic,
+            self.id.short(),
+            self.sender,
+            self.flag_marker(),
+            self.body.replace('\n', &format!("\n{}", BODY_INDENT))
+        )
+    }
+}
+
+impl RenderedMessage {
+    /// Returns what follows the sender of a flagged message, or nothing.
+    pub fn flag_marker(&self) -> String {
+        self.flag
+            .as_ref()
+            .map(|reason| format!(" [flagged: {}]", reason))
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the color a peer is drawn in.
+pub fn peer_color(peer: &PeerId) -> u8 {
+    let hash = Sha256::digest(peer.to_bytes());
+    PEER_COLORS[hash[0] as usize % PEER_COLORS.len()]
+}
+
+/// The latest rendered messages, oldest first.
+pub struct Scrollback {
+    messages: VecDeque<RenderedMessage>,
+    limit: usize,
+}
+
+impl Scrollback {
+    /// Creates a new, empty `Scrollback` keeping up to `limit` messages.
+    pub fn new(limit: usize) -> Self {
+        Scrollback {
+            messages: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Appends a message, dropping the oldest one when full.
+    pub fn push(&mut self, message: RenderedMessage) {
+        if self.messages.len() == self.limit {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    /// Returns the messages containing a pattern, ignoring case, oldest
+    /// first.
+    pub fn search(&self, pattern: &str) -> Vec<&RenderedMessage> {
+        let pattern = pattern.to_lowercase();
+        self.messages
+            .iter()
+            .filter(|message| message.to_string().to_lowercase().contains(&pattern))
+            .collect()
+    }
+}
+
+/// Renders chat messages and sends them to the terminal UI or stdout.
+pub struct Renderer {
+    pub format: MessageFormat,
+    pub scrollback: Scrollback,
+    output: Output,
+    ui: Option<UnboundedSender<UiEvent>>,
+    color: bool,
+}
+
+impl Renderer {
+    /// Creates a new `Renderer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - How messages are rendered.
+    /// * `output` - What is written to stdout.
+    /// * `ui` - The channel to the terminal UI, if it runs.
+    pub fn new(
+        format: MessageFormat,
+        output: Output,
+        ui: Option<UnboundedSender<UiEvent>>,
+    ) -> Self {
+        Renderer {
+            format,
+            scrollback: Scrollback::new(SCROLLBACK_LIMIT),
+            output,
+            ui,
+            color: io::stdout().is_terminal(),
+        }
+    }
+
+    /// Renders a chat message, not highlighted.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the message was received on.
+    /// * `id` - The message ID.
+    /// * `source` - The peer that authored the message.
+    /// * `sender` - How the author is shown, its name or peer ID.
+    /// * `timestamp` - When the message was sent, in milliseconds since the
+    ///   Unix epoch.
+    /// * `body` - The message text.
+    pub fn render(
+        &self,
+        topic: &str,
+        id: &MessageId,
+        source: &PeerId,
+        sender: &str,
+        timestamp: u64,
+        body: &str,
+    ) -> RenderedMessage {
+        let time = Local
+            .timestamp_millis_opt(timestamp as i64)
+            .single()
+            .unwrap_or_else(Local::now);
+        let time = match self.format.clock {
+            Clock::H12 => time.format("%I:%M %p"),
+            Clock::H24 => time.format("%H:%M"),
+        };
+        let source_id = source.to_base58();
+        let suffix = &source_id[source_id.len().saturating_sub(6)..];
+        // Names shared by several peers already carry the suffix.
+        let sender = if self.format.peer_suffix && sender != source_id && !sender.contains(suffix) {
+            format!("{} …{}", sender, suffix)
+        } else {
+            sender.to_string()
+        };
+        RenderedMessage {
+            time: time.to_string(),
+            timestamp,
+            topic: topic.to_string(),
+            id: *id,
+            source: *source,
+            sender,
+            color: peer_color(source),
+            body: body.to_string(),
+            highlight: false,
+            flag: None,
+        }
+    }
+
+    /// Returns whether messages are shown in the terminal UI, which tells
+    /// when they are read, rather than written to stdout as they arrive.
+    pub fn has_ui(&self) -> bool {
+        self.output != Output::Json && self.ui.is_some()
+    }
+
+    /// Writes an event to stdout if JSON lines are written.
+    pub fn emit(&self, event: JsonLine) {
+        if self.output == Output::Json {
+            println!("{}", event);
+        }
+    }
+
+    /// Shows a rendered message and keeps it in the scrollback.
+    pub fn show(&mut self, message: RenderedMessage) {
+        self.scrollback.push(message.clone());
+        if self.output == Output::Json {
+            let mut line = JsonLine::new("message")
+                .str("id", &message.id.to_string())
+                .str("topic", &message.topic)
+                .str("sender", &message.source.to_base58())
+                .str("name", &message.sender)
+                .num("timestamp", message.timestamp)
+                .str("body", &message.body)
+                .bool("highlight", message.highlight);
+            if let Some(reason) = &message.flag {
+                line = line.str("flag", reason);
+            }
+            println!("{}", line);
+        } else if let Some(ui) = &self.ui {
+            // The UI may already be gone while shutting down.
+            let _ = ui.send(UiEvent::Message(message));
+        } else if self.color {
+            let body = message.body.replace('\n', &format!("\n{}", BODY_INDENT));
+            let body = if message.highlight {
+                body.yellow().bold()
+            } else {
+                body.stylize()
+            };
+            println!(
+                "{} [{}] {} {}{}: {}",
+                message.time.as_str().dark_grey(),
+                message.topic,
+                format!("#{}", message.id.short()).dark_grey(),
+                message
+                    .sender
+                    .as_str()
+                    .with(Color::AnsiValue(message.color))
+                    .bold(),
+                message.flag_marker().red(),
+                body
+            );
+        } else {
+            println!("{}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{peer_color, Clock, JsonLine, MessageFormat, Output, Renderer, Scrollback};
+    use crate::delivery::MessageId;
+
+    #[test]
+    fn test_render() {
+        let peer = PeerId::random();
+        let id = MessageId::random();
+        let mut renderer = Renderer::new(
+            MessageFormat {
+                clock: Clock::H24,
+                peer_suffix: false,
+            },
+            Output::Text,
+            None,
+        );
+        let message = renderer.render("chat", &id, &peer, "alice", 0, "hi");
+        assert_eq!(message.time.len(), 5);
+        assert_eq!(message.sender, "alice");
+        assert_eq!(message.color, peer_color(&peer));
+        assert_eq!(
+            message.to_string(),
+            format!("{} [chat] #{} alice: hi", message.time, id.short())
+        );
+
+        renderer.format = MessageFormat {
+            clock: Clock::H12,
+            peer_suffix: true,
+        };
+        let message = renderer.render("chat", &id, &peer, "alice", 0, "hi");
+        assert!(message.time.ends_with('M'));
+        assert!(peer.to_base58().ends_with(&message.sender[9..]));
+        // Peers shown by peer ID get no suffix.
+        let unnamed = renderer.render("chat", &id, &peer, &peer.to_base58(), 0, "hi");
+        assert_eq!(unnamed.sender, peer.to_base58());
+    }
+
+    #[test]
+    fn test_scrollback_search() {
+        let renderer = Renderer::new(
+            MessageFormat {
+                clock: Clock::H24,
+                peer_suffix: false,
+            },
+            Output::Text,
+            None,
+        );
+        let peer = PeerId::random();
+        let mut scrollback = Scrollback::new(2);
+        for body in ["Hello there", "bye", "hello again"] {
+            scrollback.push(renderer.render("chat", &MessageId::random(), &peer, "alice", 0, body));
+        }
+        let found: Vec<&str> = scrollback
+            .search("HELLO")
+            .iter()
+            .map(|message| message.body.as_str())
+            .collect();
+        assert_eq!(found, vec!["hello again"]);
+        assert_eq!(scrollback.search("alice").len(), 2);
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = JsonLine::new("message")
+            .str("body", "say \"hi\"\\\n\u{1}")
+            .num("timestamp", 42)
+            .bool("highlight", true);
+        assert_eq!(
+            line.to_string(),
+            r#"{"event":"message","body":"say \"hi\"\\\n\u0001","timestamp":42,"highlight":true}"#
+        );
+        assert_eq!("json".parse(), Ok(Output::Json));
+        assert!("xml".parse::<Output>().is_err());
+    }
+}
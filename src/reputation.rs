@@ -0,0 +1,381 @@
+/*!
+ * Reputation module for the messaging application.
+ *
+ * A peer exceeding the rate limits has its messages dropped one at a time,
+ * which costs a flooding peer nothing. The reputation store counts these
+ * violations, and a peer committing `MAX_VIOLATIONS` of them within
+ * `VIOLATION_WINDOW` is banned: the swarm loop disconnects it and refuses
+ * its connections, and its messages relayed by other peers are dropped,
+ * until the ban ends. Every ban lasts twice as long as the one before,
+ * from `FIRST_BAN` up to `MAX_BAN`, and a peer banned no more for
+ * `FORGIVE_AFTER` starts over.
+ *
+ * The store is a middleware layer, which sees what the rate limiter drops.
+ * Floodsub does not authenticate the source it reports, so a violation is
+ * only counted against a peer once the envelope proves the peer signed it,
+ * and flooding under the name of another peer gets that peer no ban.
+ * Bans are written to the data directory as they are issued, sealed when
+ * storage is encrypted, so a restart neither lifts a ban nor resets the
+ * escalation.
+ */
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use libp2p::PeerId;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::hooks::{Direction, Verdict};
+use crate::middleware::{Context, Middleware, Outcome};
+use crate::moderation::now;
+use crate::protocol::Envelope;
+use crate::storage::{self, Vault};
+
+/// Violations of the rate limits within the window after which a peer is
+/// banned.
+pub const MAX_VIOLATIONS: usize = 50;
+
+/// How long violations are counted.
+pub const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long the first ban of a peer lasts.
+pub const FIRST_BAN: Duration = Duration::from_secs(60);
+
+/// How long a ban lasts at most.
+pub const MAX_BAN: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long after its last ban a peer starts over.
+pub const FORGIVE_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The bans of a peer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Record {
+    /// Number of times the peer was banned.
+    bans: u32,
+    /// When the last ban ends, in milliseconds since the epoch.
+    until: u64,
+}
+
+/// A banned peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub peer: PeerId,
+    /// Number of times the peer was banned.
+    pub bans: u32,
+    /// When the ban ends, in milliseconds since the epoch.
+    pub until: u64,
+}
+
+/// Violations and bans of peers.
+pub struct Reputation {
+    /// When each peer violated the rate limits lately, in milliseconds since
+    /// the epoch.
+    violations: HashMap<PeerId, VecDeque<u64>>,
+    records: HashMap<PeerId, Record>,
+    /// Peers banned since the swarm loop last disconnected them.
+    banned: Vec<PeerId>,
+    /// The file bans are saved to, and the vault sealing it.
+    file: Option<(PathBuf, Option<Arc<Vault>>)>,
+}
+
+impl Reputation {
+    /// Creates a new, empty store kept in memory only.
+    pub fn new() -> Self {
+        Reputation {
+            violations: HashMap::new(),
+            records: HashMap::new(),
+            banned: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Loads the bans saved to a file, saving them there as they are issued.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The reputation file, which need not exist yet.
+    /// * `vault` - The vault, if storage encryption is on.
+    pub fn load(path: PathBuf, vault: Option<Arc<Vault>>) -> Result<Self, Box<dyn Error>> {
+        let records = match storage::read(&path, vault.as_deref())? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => HashMap::new(),
+        };
+        let mut reputation = Reputation {
+            records,
+            file: Some((path, vault)),
+            ..Reputation::new()
+        };
+        reputation.expire_at(now());
+        Ok(reputation)
+    }
+
+    /// Saves the bans to their file, if there is one.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some((path, vault)) = &self.file {
+            storage::write(path, &bincode::serialize(&self.records)?, vault.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a peer is banned.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.is_banned_at(peer, now())
+    }
+
+    /// Returns the peers banned now, sorted by when their ban ends.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = now();
+        let mut bans: Vec<Ban> = self
+            .records
+            .iter()
+            .filter(|(_, record)| record.until > now)
+            .map(|(peer, record)| Ban {
+                peer: *peer,
+                bans: record.bans,
+                until: record.until,
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+
+    /// Returns the peers banned since this was last called, to be
+    /// disconnected.
+    pub fn take_banned(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.banned)
+    }
+
+    fn is_banned_at(&self, peer: &PeerId, now: u64) -> bool {
+        self.records
+            .get(peer)
+            .is_some_and(|record| record.until > now)
+    }
+
+    /// Counts a violation of the rate limits, banning the peer once it
+    /// committed too many.
+    ///
+    /// # Returns
+    ///
+    /// How long the peer is banned for, if it was banned.
+    fn violation_at(&mut self, peer: PeerId, now: u64) -> Option<Duration> {
+        if self.is_banned_at(&peer, now) {
+            return None;
+        }
+        let window = VIOLATION_WINDOW.as_millis() as u64;
+        let violations = self.violations.entry(peer).or_default();
+        while violations
+            .front()
+            .is_some_and(|at| now.saturating_sub(*at) >= window)
+        {
+            violations.pop_front();
+        }
+        violations.push_back(now);
+        if violations.len() < MAX_VIOLATIONS {
+            return None;
+        }
+        self.violations.remove(&peer);
+
+        let bans = self.records.get(&peer).map_or(0, |record| record.bans);
+        let duration = FIRST_BAN
+            .saturating_mul(2u32.saturating_pow(bans))
+            .min(MAX_BAN);
+        self.records.insert(
+            peer,
+            Record {
+                bans: bans + 1,
+                until: now + duration.as_millis() as u64,
+            },
+        );
+        self.banned.push(peer);
+        if let Err(e) = self.save() {
+            error!("Failed to save the bans: {}", e);
+        }
+        Some(duration)
+    }
+
+    /// Forgets violations out of the window and peers forgiven.
+    fn expire_at(&mut self, now: u64) {
+        let window = VIOLATION_WINDOW.as_millis() as u64;
+        self.violations.retain(|_, violations| {
+            violations
+                .back()
+                .is_some_and(|at| now.saturating_sub(*at) < window)
+        });
+        let forgive = FORGIVE_AFTER.as_millis() as u64;
+        self.records
+            .retain(|_, record| now.saturating_sub(record.until) < forgive);
+    }
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Reputation::new()
+    }
+}
+
+impl Middleware for Reputation {
+    fn name(&self) -> &str {
+        "reputation"
+    }
+
+    fn filter(&mut self, context: &Context, _data: &[u8]) -> Verdict {
+        match context.direction == Direction::Inbound && self.is_banned(&context.peer) {
+            true => Verdict::Drop("banned".to_string()),
+            false => Verdict::Continue,
+        }
+    }
+
+    fn observe(&mut self, context: &Context, data: &[u8], outcome: Outcome) {
+        if context.direction != Direction::Inbound
+            || outcome != Outcome::Dropped("rate-limit")
+            || !signed_by(data, context.topic, &context.peer)
+        {
+            return;
+        }
+        if let Some(duration) = self.violation_at(context.peer, now()) {
+            warn!(
+                "Banned {:?} for {:?} after repeatedly exceeding the rate limits",
+                context.peer, duration
+            );
+        }
+    }
+
+    fn tick(&mut self) {
+        self.expire_at(now());
+    }
+
+    fn compact(&mut self) {
+        self.violations.shrink_to_fit();
+        self.records.shrink_to_fit();
+    }
+}
+
+/// Returns whether message data is an envelope a peer signed lately for
+/// the topic it was received on. Older envelopes and those of other topics
+/// may be replayed by anyone.
+fn signed_by(data: &[u8], topic: &str, peer: &PeerId) -> bool {
+    Envelope::decode(data)
+        .and_then(|envelope| {
+            envelope.check_fresh()?;
+            envelope.verify(topic)
+        })
+        .is_ok_and(|(signer, _)| signer == *peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use libp2p::{identity, PeerId};
+    use zeroize::Zeroizing;
+
+    use super::{Reputation, FIRST_BAN, FORGIVE_AFTER, MAX_BAN, MAX_VIOLATIONS, VIOLATION_WINDOW};
+    use crate::delivery::MessageId;
+    use crate::hooks::Direction;
+    use crate::middleware::{Context, Middleware, Outcome};
+    use crate::protocol::{Envelope, Payload, TextMessage};
+    use crate::storage::Vault;
+
+    fn ms(duration: std::time::Duration) -> u64 {
+        duration.as_millis() as u64
+    }
+
+    #[test]
+    fn test_escalating_bans() {
+        let path =
+            std::env::temp_dir().join(format!("sec_msg-reputation-{}.db", std::process::id()));
+        let vault = Arc::new(Vault::new(Zeroizing::new("correct horse".to_string())));
+        let mut reputation = Reputation::load(path.clone(), Some(vault.clone())).unwrap();
+        let peer = PeerId::random();
+        let mut now = super::now();
+
+        // Violations spread beyond the window are forgiven.
+        for _ in 0..MAX_VIOLATIONS * 2 {
+            assert_eq!(reputation.violation_at(peer, now), None);
+            now += ms(VIOLATION_WINDOW) / MAX_VIOLATIONS as u64 * 2;
+        }
+        assert!(!reputation.is_banned_at(&peer, now));
+
+        // Each ban lasts twice as long as the one before, up to a day.
+        let mut expected = FIRST_BAN;
+        for _ in 0..12 {
+            let bans: Vec<_> = (0..MAX_VIOLATIONS)
+                .filter_map(|_| reputation.violation_at(peer, now))
+                .collect();
+            assert_eq!(bans, [expected]);
+            assert!(reputation.is_banned_at(&peer, now));
+            assert_eq!(reputation.take_banned(), [peer]);
+            // Violations while banned count for nothing.
+            assert_eq!(reputation.violation_at(peer, now), None);
+            now += ms(expected);
+            assert!(!reputation.is_banned_at(&peer, now));
+            expected = (expected * 2).min(MAX_BAN);
+        }
+        assert_eq!(expected, MAX_BAN);
+
+        // Bans survive a restart, and peers are forgiven in time.
+        let mut reloaded = Reputation::load(path.clone(), Some(vault)).unwrap();
+        assert_eq!(reloaded.records[&peer].bans, 12);
+        reloaded.expire_at(now + ms(FORGIVE_AFTER));
+        assert!(reloaded.records.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_spoofed_source() {
+        let honest = identity::Keypair::generate_ed25519();
+        let attacker = identity::Keypair::generate_ed25519();
+        let peer = honest.public().to_peer_id();
+        let flood = |key: &identity::Keypair, topic: &str| {
+            let payload = Payload::Text(TextMessage {
+                id: MessageId::random(),
+                body: "flood".to_string(),
+                ack_requested: false,
+            });
+            Envelope::seal(topic, &payload, None, key)
+                .unwrap()
+                .encode()
+                .unwrap()
+        };
+        let context = Context {
+            direction: Direction::Inbound,
+            topic: "chat",
+            peer,
+        };
+        let mut reputation = Reputation::new();
+
+        // Floods claiming the honest peer as their Floodsub source count
+        // for nothing, signed by someone else or not envelopes at all.
+        for _ in 0..MAX_VIOLATIONS {
+            reputation.observe(
+                &context,
+                &flood(&attacker, "chat"),
+                Outcome::Dropped("rate-limit"),
+            );
+            reputation.observe(&context, b"junk", Outcome::Dropped("rate-limit"));
+        }
+        assert!(!reputation.is_banned(&peer));
+
+        // Nor do envelopes of the honest peer replayed from another topic.
+        for _ in 0..MAX_VIOLATIONS {
+            let replayed = flood(&honest, "other");
+            reputation.observe(&context, &replayed, Outcome::Dropped("rate-limit"));
+        }
+        assert!(!reputation.is_banned(&peer));
+
+        for _ in 0..MAX_VIOLATIONS {
+            reputation.observe(
+                &context,
+                &flood(&honest, "chat"),
+                Outcome::Dropped("rate-limit"),
+            );
+        }
+        assert!(reputation.is_banned(&peer));
+    }
+}
@@ -1 +1,169 @@
-// Placeholder for security-related functionalities
+/*!
+ * Security module for the messaging application.
+ *
+ * Text received from peers ends up in the terminal. This module strips what
+ * could take control of it, escape sequences and other control characters,
+ * along with the bidirectional formatting characters that can make text
+ * read differently than it is stored, and bounds how much of the text is
+ * shown. Message bodies may keep a few line breaks, so formatted messages
+ * keep their shape. It also derives the fingerprints users compare to check
+ * they talk to the peer they think they do.
+ */
+
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+/// Maximum number of characters of a message body that are shown.
+pub const MAX_RENDERED_LEN: usize = 2000;
+
+/// Maximum number of lines of a message body that are shown, further line
+/// breaks becoming spaces.
+pub const MAX_RENDERED_LINES: usize = 20;
+
+/// Maximum number of characters of a name, such as a claimed display name
+/// or an agent version, that are shown.
+pub const MAX_RENDERED_NAME_LEN: usize = 64;
+
+/// Returns the fingerprint of the public key of a peer, as groups of hex
+/// digits.
+///
+/// Peer IDs embed or hash the public key, so hashing the peer ID commits
+/// to the key.
+pub fn fingerprint(peer: &PeerId) -> String {
+    let hash = Sha256::digest(peer.to_bytes());
+    hash[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Makes text received from a peer safe to show on a single line.
+///
+/// Escape sequences are removed, tabs and line breaks become spaces and
+/// other control characters are dropped. Text longer than `max_len`
+/// characters is cut and ends with an ellipsis.
+///
+/// # Arguments
+///
+/// * `text` - The received text.
+/// * `max_len` - The maximum number of characters kept.
+pub fn sanitize(text: &str, max_len: usize) -> String {
+    clean(text, max_len, 1)
+}
+
+/// Makes a received message body safe to show, keeping its first line
+/// breaks.
+///
+/// Like [`sanitize`], except that up to `MAX_RENDERED_LINES` lines are
+/// kept and CRLF line endings become line breaks.
+///
+/// # Arguments
+///
+/// * `text` - The received text.
+/// * `max_len` - The maximum number of characters kept.
+pub fn sanitize_multiline(text: &str, max_len: usize) -> String {
+    clean(text, max_len, MAX_RENDERED_LINES)
+}
+
+/// Removes escape sequences and control characters from a text, keeping
+/// at most `max_lines` lines.
+fn clean(text: &str, max_len: usize, max_lines: usize) -> String {
+    let mut sanitized = String::with_capacity(text.len().min(max_len));
+    let mut len = 0;
+    let mut lines = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\x1b' => {
+                match chars.next() {
+                    // Control sequences end with a byte in '@'..='~'.
+                    Some('[') => skip_control_sequence(&mut chars),
+                    // Operating system commands end with BEL or ESC '\'.
+                    Some(']' | 'P' | '^' | '_') => {
+                        while let Some(c) = chars.next() {
+                            if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                                break;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            '\u{9b}' => {
+                skip_control_sequence(&mut chars);
+                continue;
+            }
+            '\r' if max_lines > 1 && chars.peek() == Some(&'\n') => continue,
+            '\n' if lines < max_lines => {
+                lines += 1;
+                '\n'
+            }
+            '\t' | '\n' | '\r' => ' ',
+            c if c.is_control() || is_bidi_control(c) => continue,
+            c => c,
+        };
+        if len == max_len {
+            sanitized.push('…');
+            break;
+        }
+        sanitized.push(c);
+        len += 1;
+    }
+    sanitized
+}
+
+/// Skips the parameters and final byte of a control sequence.
+fn skip_control_sequence(chars: &mut impl Iterator<Item = char>) {
+    for c in chars {
+        if ('@'..='~').contains(&c) {
+            break;
+        }
+    }
+}
+
+/// Returns whether a character changes the direction text is shown in.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{200e}' | '\u{200f}')
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{fingerprint, sanitize, sanitize_multiline, MAX_RENDERED_LINES};
+
+    #[test]
+    fn test_fingerprint() {
+        let peer = PeerId::random();
+        assert_eq!(fingerprint(&peer), fingerprint(&peer));
+        assert_eq!(fingerprint(&peer).len(), 8 * 4 + 7);
+        assert_ne!(fingerprint(&peer), fingerprint(&PeerId::random()));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("hello, world", 100), "hello, world");
+        assert_eq!(sanitize("\x1b[31mred\x1b[0m text", 100), "red text");
+        assert_eq!(
+            sanitize("\x1b]0;pwned\x07title \x1b]8;;http://x\x1b\\link", 100),
+            "title link"
+        );
+        assert_eq!(sanitize("a\tb\nc\r\x07\x08d\u{9b}2Je", 100), "a b c de");
+        assert_eq!(sanitize("abc\u{202e}fed", 100), "abcfed");
+        assert_eq!(sanitize("héllo wörld", 5), "héllo…");
+        assert_eq!(sanitize("hello", 5), "hello");
+        assert_eq!(sanitize("unterminated \x1b[31", 100), "unterminated ");
+    }
+
+    #[test]
+    fn test_sanitize_multiline() {
+        assert_eq!(sanitize_multiline("a\r\nb\n\x1b[2Jc\rd", 100), "a\nb\nc d");
+        let many = "x\n".repeat(2 * MAX_RENDERED_LINES);
+        assert_eq!(
+            sanitize_multiline(&many, 1000).lines().count(),
+            MAX_RENDERED_LINES
+        );
+    }
+}
@@ -0,0 +1,98 @@
+/*!
+ * Shutdown module for the messaging application.
+ *
+ * Ctrl-C and termination signals stop the node the same way quitting does:
+ * the swarm loop stops taking input, flushes the messages waiting to be
+ * sent, leaves its topics, closes its connections and saves the history,
+ * and the process then exits with the code of the signal. A second signal
+ * during that exits at once, for a shutdown that hangs.
+ */
+
+use std::{fmt, io};
+
+use log::warn;
+
+use crate::app::{AppEvent, AppEvents};
+
+/// A signal asking the process to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Ctrl-C, or SIGINT.
+    Interrupt,
+    /// SIGTERM, sent by service managers.
+    Terminate,
+}
+
+impl Signal {
+    /// Returns the code the process exits with, that of a shell for a
+    /// process killed by the signal.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Signal::Interrupt => 130,
+            Signal::Terminate => 143,
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Signal::Interrupt => write!(f, "SIGINT"),
+            Signal::Terminate => write!(f, "SIGTERM"),
+        }
+    }
+}
+
+/// Signals asking the process to stop, termination signals never arriving
+/// on platforms without them.
+pub struct Termination {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Termination {
+    /// Starts listening for signals, which no longer kill the process.
+    pub fn new() -> io::Result<Self> {
+        Ok(Termination {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    /// Waits for the next signal.
+    pub async fn recv(&mut self) -> io::Result<Signal> {
+        #[cfg(unix)]
+        let terminate = self.terminate.recv();
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<Option<()>>();
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|()| Signal::Interrupt),
+            _ = terminate => Ok(Signal::Terminate),
+        }
+    }
+
+    /// Asks the swarm loop to shut down on the first signal, and exits on
+    /// the second.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The channel to the swarm loop.
+    pub fn forward(mut self, events: AppEvents) {
+        tokio::spawn(async move {
+            let signal = match self.recv().await {
+                Ok(signal) => signal,
+                Err(e) => {
+                    warn!("Failed to listen for signals: {}", e);
+                    return;
+                }
+            };
+            if events.send(AppEvent::Signal(signal)).is_err() {
+                return;
+            }
+            if let Ok(signal) = self.recv().await {
+                warn!("Received a second {}, exiting now", signal);
+                std::process::exit(signal.exit_code());
+            }
+        });
+    }
+}
@@ -0,0 +1,301 @@
+/*!
+ * Simulation module for the messaging application.
+ *
+ * `sec_msg simulate` runs many virtual peers in one process, connected over
+ * the in-memory transport as in the end-to-end tests, each to a few random
+ * others, and has random peers publish on the first topic at a steady rate.
+ * Every message is timed from its publishing to its arrival at each of the
+ * other peers, and the report gives the delivery latency and the share of
+ * deliveries lost, whether the rate limiter or pubsub dropped them. The
+ * peers run with the settings of the configuration, rate limits and pubsub
+ * protocol included, so the simulation is a bench for tuning them before a
+ * change reaches a real network. The simulator is built with the `simulate`
+ * feature.
+ */
+
+use std::fmt;
+
+use serde::Serialize;
+
+/// Number of peers simulated if not given.
+pub const DEFAULT_PEERS: u16 = 10;
+
+/// Messages published per second if not given.
+pub const DEFAULT_RATE: u32 = 10;
+
+/// How long messages are published if not given, in seconds.
+pub const DEFAULT_DURATION: u64 = 30;
+
+/// Number of peers each peer connects to when it starts, if not given.
+pub const DEFAULT_LINKS: u16 = 8;
+
+#[cfg(feature = "simulate")]
+pub use simulator::run;
+
+/// The outcome of a simulation.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub peers: usize,
+    /// Messages published.
+    pub sent: usize,
+    /// Deliveries expected, one per message and peer other than its sender.
+    pub expected: usize,
+    pub delivered: usize,
+    /// Messages received more than once by a peer.
+    pub duplicates: usize,
+    /// Delivery latencies, in milliseconds.
+    pub latency_p50: f64,
+    pub latency_p90: f64,
+    pub latency_p99: f64,
+    pub latency_max: f64,
+}
+
+impl Report {
+    /// Returns the share of deliveries lost, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        match self.expected {
+            0 => 0.0,
+            expected => expected.saturating_sub(self.delivered) as f64 / expected as f64,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Peers:      {}", self.peers)?;
+        writeln!(f, "Sent:       {} messages", self.sent)?;
+        writeln!(
+            f,
+            "Delivered:  {} of {} ({:.1}% lost)",
+            self.delivered,
+            self.expected,
+            self.loss() * 100.0
+        )?;
+        writeln!(f, "Duplicates: {}", self.duplicates)?;
+        write!(
+            f,
+            "Latency:    p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+            self.latency_p50, self.latency_p90, self.latency_p99, self.latency_max
+        )
+    }
+}
+
+#[cfg(feature = "simulate")]
+mod simulator {
+    use std::{collections::HashSet, error::Error, time::Duration};
+
+    use futures::StreamExt;
+    use log::{debug, info, warn};
+    use tokio::sync::mpsc;
+    use web_time::Instant;
+
+    use super::Report;
+    use crate::config::Config;
+    use crate::testing::TestNet;
+
+    /// Longest the last messages may take to arrive once publishing stops.
+    const DRAIN: Duration = Duration::from_secs(5);
+
+    /// A message received by a peer.
+    struct Delivery {
+        seq: u64,
+        receiver: usize,
+        latency: Duration,
+    }
+
+    /// The deliveries seen so far.
+    #[derive(Default)]
+    struct Deliveries {
+        seen: HashSet<(u64, usize)>,
+        latencies: Vec<Duration>,
+        duplicates: usize,
+    }
+
+    impl Deliveries {
+        fn record(&mut self, delivery: Delivery) {
+            if self.seen.insert((delivery.seq, delivery.receiver)) {
+                self.latencies.push(delivery.latency);
+            } else {
+                self.duplicates += 1;
+            }
+        }
+
+        /// Returns the report of a simulation.
+        fn report(mut self, peers: usize, sent: usize) -> Report {
+            self.latencies.sort();
+            let millis = |q: f64| {
+                let last = self.latencies.len().saturating_sub(1);
+                self.latencies
+                    .get((last as f64 * q).round() as usize)
+                    .map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+            };
+            Report {
+                peers,
+                sent,
+                expected: sent * peers.saturating_sub(1),
+                delivered: self.latencies.len(),
+                duplicates: self.duplicates,
+                latency_p50: millis(0.5),
+                latency_p90: millis(0.9),
+                latency_p99: millis(0.99),
+                latency_max: millis(1.0),
+            }
+        }
+    }
+
+    /// Runs virtual peers publishing on the first topic of a configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration every peer runs with.
+    /// * `peers` - The number of peers, at least two.
+    /// * `links` - The number of peers each peer connects to when it starts.
+    /// * `rate` - The messages published per second, by random peers.
+    /// * `duration` - How long messages are published.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `Report` of the deliveries.
+    pub async fn run(
+        config: &Config,
+        peers: usize,
+        links: usize,
+        rate: u32,
+        duration: Duration,
+    ) -> Result<Report, Box<dyn Error>> {
+        let topic = &config.topics[0];
+        info!("Starting {} peers on {}", peers, topic);
+        let net = TestNet::with_config(peers, links, config).await?;
+        let start = Instant::now();
+        let (deliveries_tx, mut deliveries_rx) = mpsc::unbounded_channel();
+        for receiver in 0..peers {
+            let mut messages = net.client(receiver).subscribe(topic).await?;
+            let deliveries = deliveries_tx.clone();
+            tokio::spawn(async move {
+                while let Some(message) = messages.next().await {
+                    let Some((seq, latency)) = parse(&message.body, start) else {
+                        continue;
+                    };
+                    let delivery = Delivery {
+                        seq,
+                        receiver,
+                        latency,
+                    };
+                    if deliveries.send(delivery).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(deliveries_tx);
+
+        info!("Publishing {} messages per second for {:?}", rate, duration);
+        let mut deliveries = Deliveries::default();
+        let sent = {
+            let publishing = publish(&net, peers, topic, rate, duration, start);
+            tokio::pin!(publishing);
+            loop {
+                tokio::select! {
+                    sent = &mut publishing => break sent,
+                    Some(delivery) = deliveries_rx.recv() => deliveries.record(delivery),
+                }
+            }
+        };
+        let expected = sent * (peers - 1);
+        let drained = tokio::time::timeout(DRAIN, async {
+            while deliveries.latencies.len() < expected {
+                match deliveries_rx.recv().await {
+                    Some(delivery) => deliveries.record(delivery),
+                    None => break,
+                }
+            }
+        })
+        .await;
+        if drained.is_err() {
+            debug!("Stopped waiting for the last deliveries");
+        }
+        net.shutdown().await;
+        Ok(deliveries.report(peers, sent))
+    }
+
+    /// Publishes messages from random peers at a rate.
+    ///
+    /// # Returns
+    ///
+    /// The number of messages published.
+    async fn publish(
+        net: &TestNet,
+        peers: usize,
+        topic: &str,
+        rate: u32,
+        duration: Duration,
+        start: Instant,
+    ) -> usize {
+        let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / f64::from(rate)));
+        let total = (duration.as_secs_f64() * f64::from(rate)).round() as u64;
+        let mut sent = 0;
+        for seq in 0..total {
+            ticks.tick().await;
+            let sender = rand::random::<usize>() % peers;
+            let body = format!("sim {} {}", seq, start.elapsed().as_micros());
+            match net.client(sender).publish(topic, body).await {
+                Ok(()) => sent += 1,
+                Err(e) => warn!("Peer {} failed to publish: {}", sender, e),
+            }
+        }
+        sent
+    }
+
+    /// Returns the number of a simulated message and how long ago it was
+    /// published.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The body of the message.
+    /// * `start` - When the simulation started.
+    fn parse(body: &str, start: Instant) -> Option<(u64, Duration)> {
+        let mut fields = body.strip_prefix("sim ")?.split(' ');
+        let seq = fields.next()?.parse().ok()?;
+        let sent_at = Duration::from_micros(fields.next()?.parse().ok()?);
+        Some((seq, start.elapsed().saturating_sub(sent_at)))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::time::Duration;
+
+        use super::run;
+        use crate::cli::Options;
+        use crate::config::Config;
+
+        #[tokio::test]
+        async fn test_simulate() {
+            let options = Options {
+                topic: vec!["sim".to_string()],
+                ..Options::default()
+            };
+            let config = Config::from_options(&options).unwrap();
+            let report = run(&config, 3, 1, 20, Duration::from_millis(500))
+                .await
+                .unwrap();
+            assert_eq!(report.peers, 3);
+            assert_eq!(report.sent, 10);
+            assert_eq!(report.expected, 20);
+            assert_eq!(report.delivered, 20);
+            assert_eq!(report.duplicates, 0);
+            assert!(report.latency_p50 <= report.latency_max);
+        }
+    }
+}
+
+/// Fails, as the simulator is built with the `simulate` feature.
+#[cfg(not(feature = "simulate"))]
+pub async fn run(
+    _config: &crate::config::Config,
+    _peers: usize,
+    _links: usize,
+    _rate: u32,
+    _duration: std::time::Duration,
+) -> Result<Report, Box<dyn std::error::Error>> {
+    Err("Built without the simulator, rebuild with --features simulate".into())
+}
@@ -0,0 +1,165 @@
+/*!
+ * Application state module for the messaging application.
+ *
+ * This module defines the state shared between the user input handlers
+ * and the swarm event handlers.
+ */
+
+use std::{collections::HashMap, sync::Arc};
+
+use libp2p::identity;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    app::AppEvents,
+    config::Config,
+    contacts::Contacts,
+    content_filter::{Quarantine, Quarantined},
+    delivery::{DeliveryTracker, MessageId, ReadReceiptPolicy},
+    discovery::Discovery,
+    email::Outbox,
+    history::History,
+    hooks::Hooks,
+    ignore::IgnoreList,
+    middleware::Pipeline,
+    moderation::Moderation,
+    nat::NatTracker,
+    note::Note,
+    notify::Notifier,
+    outgoing::Outgoing,
+    peers::PeerTable,
+    presence::PresenceTracker,
+    profile::Profiles,
+    protocol::{OutboundQueue, Reassembler},
+    rate_limit::RateLimiter,
+    reaction::Reactions,
+    record::Recorder,
+    reload::Reloader,
+    render::Renderer,
+    storage::Vault,
+    supervisor::Supervisor,
+    topic::TopicManager,
+    transfer::TransferManager,
+    ui::UiEvent,
+    version::Versions,
+};
+
+/// State shared by the user input and swarm event handlers.
+pub struct AppState {
+    pub local_key: identity::Keypair,
+    pub display_name: Option<String>,
+    pub topics: TopicManager,
+    pub deliveries: DeliveryTracker,
+    pub read_receipts: ReadReceiptPolicy,
+    pub reassembler: Reassembler,
+    pub transfers: TransferManager,
+    pub moderation: Moderation,
+    pub history: History,
+    /// Shared notes by topic.
+    pub notes: HashMap<String, Note>,
+    pub outbound: OutboundQueue,
+    /// Chat messages typed, waiting to be published at the pace peers
+    /// accept.
+    pub outgoing: Outgoing,
+    pub presence: PresenceTracker,
+    pub discovery: Discovery,
+    pub supervisor: Supervisor,
+    pub reactions: Reactions,
+    /// The channel to the swarm loop, for the tasks it spawns.
+    pub events: AppEvents,
+    pub versions: Versions,
+    pub peers: PeerTable,
+    pub nat: NatTracker,
+    pub profiles: Profiles,
+    pub contacts: Contacts,
+    /// Peers whose messages are hidden locally.
+    pub ignored: IgnoreList,
+    pub notifier: Notifier,
+    pub renderer: Renderer,
+    pub hooks: Hooks,
+    /// Messages the content filters held back for review.
+    pub quarantine: Quarantine,
+    /// Messages held back while a content filter decides on them, with
+    /// the raw message recorded to the history once they are shown.
+    pub pending: HashMap<MessageId, (Quarantined, Vec<u8>)>,
+    /// The layers every envelope goes through.
+    pub middleware: Pipeline,
+    /// What `/reload` needs, set once the logger is installed.
+    pub reloader: Option<Reloader>,
+    /// The gateway sending direct messages by email, if configured.
+    pub email: Option<Outbox>,
+    /// The file the events received are recorded to, if any.
+    pub recorder: Option<Recorder>,
+    /// The vault, if storage encryption is on.
+    pub vault: Option<Arc<Vault>>,
+    /// Whether the user asked to quit.
+    pub quitting: bool,
+}
+
+impl AppState {
+    /// Creates a new `AppState` for the local identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_key` - The local identity keypair.
+    /// * `config` - The application configuration.
+    /// * `contacts` - The contacts of the user.
+    /// * `events` - The channel to the swarm loop.
+    /// * `ui` - The channel to the terminal UI, if it runs.
+    ///
+    /// # Returns
+    ///
+    /// A new `AppState` instance.
+    pub fn new(
+        local_key: identity::Keypair,
+        config: &Config,
+        contacts: Contacts,
+        events: AppEvents,
+        ui: Option<UnboundedSender<UiEvent>>,
+    ) -> Self {
+        let local_peer_id = local_key.public().to_peer_id();
+        let mut profiles = Profiles::new();
+        for contact in contacts.list() {
+            profiles.set_alias(contact.peer, Some(contact.alias.clone()));
+        }
+        AppState {
+            local_key,
+            display_name: None,
+            topics: TopicManager::new(config.pubsub_protocol),
+            deliveries: DeliveryTracker::new(),
+            read_receipts: ReadReceiptPolicy::new(),
+            reassembler: Reassembler::new(config.max_message_size),
+            transfers: TransferManager::new(config.download_dir.clone()),
+            moderation: Moderation::new(),
+            history: History::new(),
+            notes: HashMap::new(),
+            outbound: OutboundQueue::new(config.outbound_rate, config.max_message_size),
+            outgoing: Outgoing::new(config.rate_limits.clone(), local_peer_id),
+            presence: PresenceTracker::new(),
+            discovery: Discovery::new(),
+            supervisor: Supervisor::new(config.bootstrap.clone(), config.listen_addrs.clone()),
+            reactions: Reactions::new(),
+            events,
+            versions: Versions::new(),
+            peers: PeerTable::new(),
+            nat: NatTracker::new(),
+            profiles,
+            contacts,
+            ignored: IgnoreList::new(),
+            notifier: Notifier::new(config.notifications, config.keywords.clone()),
+            renderer: Renderer::new(config.message_format, config.output, ui),
+            hooks: Hooks::new(),
+            quarantine: Quarantine::new(),
+            pending: HashMap::new(),
+            middleware: Pipeline::standard(RateLimiter::new(
+                config.rate_limits.clone(),
+                config.rate_limit_peers,
+            )),
+            reloader: None,
+            email: None,
+            recorder: None,
+            vault: None,
+            quitting: false,
+        }
+    }
+}
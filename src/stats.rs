@@ -0,0 +1,200 @@
+/*!
+ * Statistics module for the messaging application.
+ *
+ * This module keeps the counters shown by `/stats`: how long the node has
+ * been up, how many messages were sent and received on each topic, how
+ * many received messages each middleware layer dropped, and how many bytes
+ * of pubsub traffic went each way. The counters are a middleware layer
+ * themselves, told about every message that flows through the pipeline.
+ */
+
+use std::{collections::BTreeMap, time::Duration};
+
+use web_time::Instant;
+
+use crate::hooks::Direction;
+use crate::middleware::{Context, Middleware, Outcome};
+
+/// Message counts of a single topic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TopicStats {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Runtime counters of the node.
+pub struct Stats {
+    started_at: Instant,
+    topics: BTreeMap<String, TopicStats>,
+    /// Received messages dropped, by the layer that dropped them.
+    dropped: BTreeMap<String, u64>,
+    /// Bytes of pubsub messages published.
+    pub bytes_sent: u64,
+    /// Bytes of pubsub messages received, including dropped ones.
+    pub bytes_received: u64,
+}
+
+impl Stats {
+    /// Creates a new `Stats` with every counter at zero.
+    pub fn new() -> Self {
+        Stats {
+            started_at: Instant::now(),
+            topics: BTreeMap::new(),
+            dropped: BTreeMap::new(),
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+
+    /// Returns how long the node has been up.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Records a message published on a topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic the message was published on.
+    /// * `len` - The size of the message in bytes.
+    pub fn sent(&mut self, topic: &str, len: usize) {
+        self.topic(topic).sent += 1;
+        self.bytes_sent += len as u64;
+    }
+
+    /// Records a message arriving, before it is checked.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The size of the message in bytes.
+    pub fn arrived(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+    }
+
+    /// Records a message accepted on a topic.
+    pub fn received(&mut self, topic: &str) {
+        self.topic(topic).received += 1;
+    }
+
+    /// Records a message dropped by a middleware layer.
+    pub fn dropped(&mut self, layer: &str) {
+        *self.dropped.entry(layer.to_string()).or_default() += 1;
+    }
+
+    /// Returns the number of messages each layer dropped, ordered by layer.
+    pub fn drops(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.dropped
+            .iter()
+            .map(|(layer, count)| (layer.as_str(), *count))
+    }
+
+    /// Returns the message counts of every topic, ordered by topic.
+    pub fn topics(&self) -> impl Iterator<Item = (&str, &TopicStats)> {
+        self.topics
+            .iter()
+            .map(|(topic, stats)| (topic.as_str(), stats))
+    }
+
+    /// Returns the counts of a topic, adding it if needed.
+    fn topic(&mut self, topic: &str) -> &mut TopicStats {
+        if !self.topics.contains_key(topic) {
+            self.topics.insert(topic.to_string(), TopicStats::default());
+        }
+        self.topics.get_mut(topic).unwrap()
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+impl Middleware for Stats {
+    fn name(&self) -> &str {
+        "metrics"
+    }
+
+    fn observe(&mut self, context: &Context, data: &[u8], outcome: Outcome) {
+        match (context.direction, outcome) {
+            (Direction::Outbound, Outcome::Passed) => self.sent(context.topic, data.len()),
+            (Direction::Outbound, Outcome::Dropped(_)) => {}
+            (Direction::Inbound, outcome) => {
+                self.arrived(data.len());
+                match outcome {
+                    Outcome::Passed => self.received(context.topic),
+                    Outcome::Dropped(layer) => self.dropped(layer),
+                }
+            }
+        }
+    }
+}
+
+/// Formats a byte count with a binary unit.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats a duration as hours, minutes and seconds.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_bytes, format_duration, Stats, TopicStats};
+
+    #[test]
+    fn test_counters() {
+        let mut stats = Stats::new();
+        stats.sent("chat", 100);
+        stats.arrived(40);
+        stats.received("chat");
+        stats.arrived(40);
+        stats.dropped("dedup");
+        stats.arrived(10);
+        stats.received("alerts");
+        assert_eq!(stats.bytes_sent, 100);
+        assert_eq!(stats.bytes_received, 90);
+        assert_eq!(
+            stats.topics().collect::<Vec<_>>(),
+            vec![
+                (
+                    "alerts",
+                    &TopicStats {
+                        sent: 0,
+                        received: 1
+                    }
+                ),
+                (
+                    "chat",
+                    &TopicStats {
+                        sent: 1,
+                        received: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 02m 03s");
+    }
+}
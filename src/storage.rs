@@ -0,0 +1,341 @@
+/*!
+ * Storage module for the messaging application.
+ *
+ * This module keeps the files of the data directory encrypted at rest, so
+ * a stolen disk does not leak the identity or the message history. The key
+ * is derived from a passphrase with Argon2id, and every file is sealed with
+ * XChaCha20-Poly1305 under a fresh random nonce, which also authenticates
+ * it: a wrong passphrase or a modified file is detected instead of being
+ * read as garbage.
+ *
+ * A sealed file starts with a magic number, then the salt its key was
+ * derived with and the nonce, all of which are authenticated along with
+ * the contents. Files written before encryption was turned on are read as
+ * they are and sealed the next time they are written.
+ */
+
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use zeroize::Zeroizing;
+
+/// First bytes of a sealed file.
+const MAGIC: &[u8; 8] = b"SECMSG\x00\x01";
+
+/// Length of the salt a key is derived with.
+const SALT_LEN: usize = 16;
+
+/// Length of the nonce of a sealed file.
+const NONCE_LEN: usize = 24;
+
+/// Length of the header of a sealed file.
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+/// Environment variable holding the passphrase, for when no one is at the
+/// keyboard to type it.
+pub const PASSPHRASE_VAR: &str = "SEC_MSG_PASSPHRASE";
+
+/// Errors that can occur while reading or writing stored files.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The file is sealed but storage encryption is off.
+    Locked,
+    /// The passphrase is wrong, or the file was modified.
+    WrongPassphrase,
+    /// The file is too short to be sealed.
+    Truncated,
+    /// No passphrase could be read.
+    NoPassphrase(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Locked => write!(
+                f,
+                "the file is encrypted, set encrypt = true in [storage] to read it"
+            ),
+            StorageError::WrongPassphrase => {
+                write!(f, "wrong passphrase, or the file was modified")
+            }
+            StorageError::Truncated => write!(f, "the encrypted file is truncated"),
+            StorageError::NoPassphrase(reason) => write!(
+                f,
+                "no passphrase ({}), type it at the prompt or set {}",
+                reason, PASSPHRASE_VAR
+            ),
+            StorageError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// Key derived from the passphrase for a salt.
+type DerivedKey = ([u8; SALT_LEN], Zeroizing<[u8; 32]>);
+
+/// Seals and opens files with keys derived from a passphrase.
+pub struct Vault {
+    passphrase: Zeroizing<String>,
+    /// Keys derived so far, the first one sealing new files, so that a
+    /// single derivation is needed once every file has been rewritten.
+    keys: Mutex<Vec<DerivedKey>>,
+}
+
+impl Vault {
+    /// Creates a new `Vault`.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase keys are derived from.
+    pub fn new(passphrase: Zeroizing<String>) -> Self {
+        Vault {
+            passphrase,
+            keys: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Encrypts data under a fresh nonce.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let (salt, key) = {
+            let mut keys = self.keys.lock().expect("vault lock");
+            if keys.is_empty() {
+                let salt: [u8; SALT_LEN] = rand::random();
+                keys.push((salt, self.derive(&salt)));
+            }
+            keys[0].clone()
+        };
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&salt);
+        sealed.extend_from_slice(&nonce);
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &sealed,
+                },
+            )
+            .expect("encryption of a bounded buffer");
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts data sealed with the same passphrase.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, StorageError> {
+        if sealed.len() < HEADER_LEN || !is_sealed(sealed) {
+            return Err(StorageError::Truncated);
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let salt: [u8; SALT_LEN] = header[MAGIC.len()..MAGIC.len() + SALT_LEN]
+            .try_into()
+            .expect("salt length");
+        let nonce = XNonce::from_slice(&header[MAGIC.len() + SALT_LEN..]);
+        let key = {
+            let mut keys = self.keys.lock().expect("vault lock");
+            match keys.iter().find(|(known, _)| *known == salt) {
+                Some((_, key)) => key.clone(),
+                None => {
+                    let key = self.derive(&salt);
+                    keys.push((salt, key.clone()));
+                    key
+                }
+            }
+        };
+        XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| StorageError::WrongPassphrase)
+    }
+
+    fn derive(&self, salt: &[u8; SALT_LEN]) -> Zeroizing<[u8; 32]> {
+        let mut key = Zeroizing::new([0; 32]);
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key[..])
+            .expect("valid key derivation parameters");
+        key
+    }
+}
+
+/// Returns whether data was sealed by a `Vault`.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Returns the contents of stored data, decrypting it if sealed.
+///
+/// # Arguments
+///
+/// * `data` - The data as read from disk.
+/// * `vault` - The vault, if storage encryption is on.
+pub fn unseal(data: Vec<u8>, vault: Option<&Vault>) -> Result<Vec<u8>, StorageError> {
+    match vault {
+        _ if !is_sealed(&data) => Ok(data),
+        Some(vault) => vault.open(&data),
+        None => Err(StorageError::Locked),
+    }
+}
+
+/// Reads a stored file.
+///
+/// # Arguments
+///
+/// * `path` - The file.
+/// * `vault` - The vault, if storage encryption is on.
+///
+/// # Returns
+///
+/// A `Result` containing the contents, or `None` if the file does not
+/// exist.
+pub fn read(path: &Path, vault: Option<&Vault>) -> Result<Option<Vec<u8>>, StorageError> {
+    match fs::read(path) {
+        Ok(data) => unseal(data, vault).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces a stored file, sealing it if storage encryption is on.
+///
+/// The contents are written to a temporary file only the current user may
+/// read and then renamed over the file, so a crash never leaves it half
+/// written.
+///
+/// # Arguments
+///
+/// * `path` - The file.
+/// * `contents` - The new contents.
+/// * `vault` - The vault, if storage encryption is on.
+pub fn write(path: &Path, contents: &[u8], vault: Option<&Vault>) -> Result<(), StorageError> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let data = match vault {
+        Some(vault) => vault.seal(contents),
+        None => contents.to_vec(),
+    };
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(&temporary)?, &data)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Reads the passphrase from the environment, or asks for it.
+///
+/// # Arguments
+///
+/// * `prompt` - What the passphrase is asked with.
+/// * `confirm` - Whether a typed passphrase is asked twice, for a new one.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn passphrase(prompt: &str, confirm: bool) -> Result<Zeroizing<String>, StorageError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return match passphrase.is_empty() {
+            true => Err(StorageError::NoPassphrase(format!(
+                "{} is empty",
+                PASSPHRASE_VAR
+            ))),
+            false => Ok(Zeroizing::new(passphrase)),
+        };
+    }
+    let ask = |text: &str| {
+        rpassword::prompt_password(text)
+            .map(Zeroizing::new)
+            .map_err(|e| StorageError::NoPassphrase(e.to_string()))
+    };
+    let passphrase = ask(prompt)?;
+    if passphrase.is_empty() {
+        return Err(StorageError::NoPassphrase("none typed".to_string()));
+    }
+    if confirm && *ask("Repeat the passphrase: ")? != *passphrase {
+        return Err(StorageError::NoPassphrase("the two differ".to_string()));
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use zeroize::Zeroizing;
+
+    use super::{read, unseal, write, StorageError, Vault, HEADER_LEN};
+
+    #[test]
+    fn test_seal_and_open() {
+        let vault = Vault::new(Zeroizing::new("correct horse".to_string()));
+        let sealed = vault.seal(b"meet at noon");
+        assert!(!sealed
+            .windows(b"meet at noon".len())
+            .any(|window| window == b"meet at noon"));
+        assert_eq!(vault.open(&sealed).unwrap(), b"meet at noon");
+        // Every file gets its own nonce.
+        assert_ne!(vault.seal(b"meet at noon"), sealed);
+
+        let mut modified = sealed.clone();
+        *modified.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            vault.open(&modified),
+            Err(StorageError::WrongPassphrase)
+        ));
+        // The salt is authenticated too.
+        let mut modified = sealed.clone();
+        modified[HEADER_LEN - 1] ^= 1;
+        assert!(vault.open(&modified).is_err());
+        let wrong = Vault::new(Zeroizing::new("battery staple".to_string()));
+        assert!(matches!(
+            wrong.open(&sealed),
+            Err(StorageError::WrongPassphrase)
+        ));
+
+        // Plain files are read as they are, sealed ones need the vault.
+        assert_eq!(unseal(b"plain".to_vec(), Some(&vault)).unwrap(), b"plain");
+        assert!(matches!(unseal(sealed, None), Err(StorageError::Locked)));
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-storage-{}", std::process::id()));
+        let path = dir.join("history.db");
+        let vault = Vault::new(Zeroizing::new("correct horse".to_string()));
+        assert!(read(&path, Some(&vault)).unwrap().is_none());
+        write(&path, b"first", Some(&vault)).unwrap();
+        write(&path, b"second", Some(&vault)).unwrap();
+        assert_eq!(read(&path, Some(&vault)).unwrap().unwrap(), b"second");
+        assert!(read(&path, None).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,463 @@
+/*!
+ * Streaming module for the messaging application.
+ *
+ * Files above `STREAM_THRESHOLD` are not pulled chunk by chunk over the file
+ * transfer protocol but sent over a dedicated `/sec_msg/stream/1.0.0`
+ * stream. The receiver opens the stream and asks for a transfer starting at
+ * the size of its partial file, so interrupted transfers resume. The sender
+ * then writes length-prefixed frames as long as it holds credit, which the
+ * receiver grants as it writes received data to disk.
+ *
+ * This module provides the behaviour handing out such streams and the tasks
+ * driving both ends of a transfer. The tasks run next to the swarm and
+ * report back to the event loop over a channel.
+ */
+
+use std::{
+    collections::VecDeque,
+    task::{Context, Poll, Waker},
+};
+
+use libp2p::{
+    core::{upgrade::ReadyUpgrade, Endpoint},
+    swarm::{
+        handler::{
+            ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        },
+        ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+        NetworkBehaviour, NotifyHandler, SubstreamProtocol, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId, Stream, StreamProtocol,
+};
+
+use crate::transfer::TransferId;
+
+/// Protocol name of the streaming protocol.
+pub const STREAM_PROTOCOL: StreamProtocol = StreamProtocol::new("/sec_msg/stream/1.0.0");
+
+/// Size above which files are streamed instead of pulled chunk by chunk.
+pub const STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Number of bytes the sender may have in flight without new credit.
+pub const STREAM_WINDOW: u64 = 8 * 1024 * 1024;
+
+/// Events emitted by the streaming behaviour.
+#[derive(Debug)]
+pub enum StreamsEvent {
+    /// A peer opened a stream to request a transfer.
+    Inbound { peer: PeerId, stream: Stream },
+    /// A stream requested for a transfer was opened.
+    Outbound {
+        peer: PeerId,
+        transfer_id: TransferId,
+        stream: Stream,
+    },
+    /// A stream requested for a transfer could not be opened.
+    Failed {
+        peer: PeerId,
+        transfer_id: TransferId,
+        error: String,
+    },
+}
+
+/// Progress of the transfer tasks, reported to the event loop.
+#[derive(Debug)]
+pub enum TransferEvent {
+    /// A peer asked for a transfer, starting at an offset.
+    Requested {
+        peer: PeerId,
+        transfer_id: TransferId,
+        offset: u64,
+        stream: Stream,
+    },
+    /// The receiving end wrote data up to `received` bytes.
+    Progress {
+        transfer_id: TransferId,
+        received: u64,
+    },
+    /// The receiving end stopped, with the number of bytes of the partial
+    /// file or an error and the bytes written until then.
+    Received {
+        peer: PeerId,
+        transfer_id: TransferId,
+        result: Result<u64, (String, u64)>,
+    },
+    /// The sending end stopped.
+    Sent {
+        peer: PeerId,
+        transfer_id: TransferId,
+        result: Result<u64, String>,
+    },
+}
+
+/// Network behaviour opening and accepting transfer streams.
+#[derive(Default)]
+pub struct Streams {
+    events: VecDeque<ToSwarm<StreamsEvent, TransferId>>,
+    waker: Option<Waker>,
+}
+
+impl Streams {
+    /// Opens a stream to a connected peer for the specified transfer.
+    ///
+    /// The result is reported as a `StreamsEvent`.
+    pub fn open(&mut self, peer: PeerId, transfer_id: TransferId) {
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::Any,
+            event: transfer_id,
+        });
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl NetworkBehaviour for Streams {
+    type ConnectionHandler = Handler;
+    type ToSwarm = StreamsEvent;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::default())
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::default())
+    }
+
+    fn on_swarm_event(&mut self, _event: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        let event = match event {
+            HandlerEvent::Inbound(stream) => StreamsEvent::Inbound { peer, stream },
+            HandlerEvent::Outbound(transfer_id, stream) => StreamsEvent::Outbound {
+                peer,
+                transfer_id,
+                stream,
+            },
+            HandlerEvent::Failed(transfer_id, error) => StreamsEvent::Failed {
+                peer,
+                transfer_id,
+                error,
+            },
+        };
+        self.events.push_back(ToSwarm::GenerateEvent(event));
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<StreamsEvent, THandlerInEvent<Self>>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Events sent by a connection handler to the streaming behaviour.
+#[derive(Debug)]
+pub enum HandlerEvent {
+    Inbound(Stream),
+    Outbound(TransferId, Stream),
+    Failed(TransferId, String),
+}
+
+/// Connection handler negotiating transfer streams.
+#[derive(Default)]
+pub struct Handler {
+    requested: VecDeque<TransferId>,
+    events: VecDeque<HandlerEvent>,
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = TransferId;
+    type ToBehaviour = HandlerEvent;
+    type InboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = TransferId;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, ()> {
+        SubstreamProtocol::new(ReadyUpgrade::new(STREAM_PROTOCOL), ())
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, TransferId, HandlerEvent>> {
+        if let Some(transfer_id) = self.requested.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(ReadyUpgrade::new(STREAM_PROTOCOL), transfer_id),
+            });
+        }
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event)),
+            None => Poll::Pending,
+        }
+    }
+
+    fn on_behaviour_event(&mut self, transfer_id: TransferId) {
+        self.requested.push_back(transfer_id);
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol, (), TransferId>,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: stream,
+                ..
+            }) => self.events.push_back(HandlerEvent::Inbound(stream)),
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: stream,
+                info: transfer_id,
+            }) => self
+                .events
+                .push_back(HandlerEvent::Outbound(transfer_id, stream)),
+            ConnectionEvent::DialUpgradeError(DialUpgradeError {
+                info: transfer_id,
+                error,
+            }) => self
+                .events
+                .push_back(HandlerEvent::Failed(transfer_id, error.to_string())),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use tasks::{read_request, receive_file, send_file};
+
+/// The tasks, which read and write files and so are left out of the browser
+/// build.
+#[cfg(not(target_arch = "wasm32"))]
+mod tasks {
+    use std::{error::Error, io, path::PathBuf, time::Duration};
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use libp2p::{PeerId, Stream};
+    use log::debug;
+    use tokio::{
+        fs::{File, OpenOptions},
+        io::{AsyncReadExt as _, AsyncSeekExt, AsyncWriteExt as _},
+        time::timeout,
+    };
+
+    use super::{TransferEvent, STREAM_WINDOW};
+    use crate::app::{AppEvent, AppEvents};
+    use crate::delivery::MessageId;
+    use crate::transfer::TransferId;
+
+    /// Maximum number of bytes carried by a single frame.
+    const FRAME_SIZE: usize = 64 * 1024;
+
+    /// How long either end waits for the other before giving up.
+    const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Reads the transfer request at the start of an inbound stream and hands
+    /// the stream back to the event loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer that opened the stream.
+    /// * `stream` - The inbound stream.
+    /// * `events` - The channel to the event loop.
+    pub async fn read_request(peer: PeerId, mut stream: Stream, events: AppEvents) {
+        let mut header = [0u8; 24];
+        match timeout(STREAM_IDLE_TIMEOUT, stream.read_exact(&mut header)).await {
+            Ok(Ok(())) => {
+                let mut transfer_id = [0u8; 16];
+                transfer_id.copy_from_slice(&header[..16]);
+                let offset = u64::from_be_bytes(header[16..].try_into().expect("8 bytes"));
+                let _ = events.send(AppEvent::Transfer(Box::new(TransferEvent::Requested {
+                    peer,
+                    transfer_id: MessageId(transfer_id),
+                    offset,
+                    stream,
+                })));
+            }
+            Ok(Err(e)) => debug!("Invalid stream request from {:?}: {}", peer, e),
+            Err(_) => debug!("Stream request from {:?} timed out", peer),
+        }
+    }
+
+    /// Sends a file over a stream, starting at the requested offset.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The receiving peer.
+    /// * `transfer_id` - The transfer.
+    /// * `stream` - The stream opened by the receiver.
+    /// * `path` - The path of the file.
+    /// * `offset` - The offset to start at.
+    /// * `events` - The channel to the event loop.
+    pub async fn send_file(
+        peer: PeerId,
+        transfer_id: TransferId,
+        stream: Stream,
+        path: PathBuf,
+        offset: u64,
+        events: AppEvents,
+    ) {
+        let result = write_frames(stream, path, offset)
+            .await
+            .map_err(|e| e.to_string());
+        let _ = events.send(AppEvent::Transfer(Box::new(TransferEvent::Sent {
+            peer,
+            transfer_id,
+            result,
+        })));
+    }
+
+    /// Writes the frames of a file, waiting for credit whenever it runs out.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of bytes sent or an error.
+    async fn write_frames(
+        mut stream: Stream,
+        path: PathBuf,
+        offset: u64,
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut file = File::open(&path).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        let mut credit = STREAM_WINDOW;
+        let mut sent = 0;
+        let mut buffer = vec![0u8; FRAME_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            while credit < read as u64 {
+                let mut grant = [0u8; 8];
+                timeout(STREAM_IDLE_TIMEOUT, stream.read_exact(&mut grant)).await??;
+                credit += u64::from_be_bytes(grant);
+            }
+            credit -= read as u64;
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            if read == 0 {
+                break;
+            }
+            timeout(STREAM_IDLE_TIMEOUT, stream.write_all(&buffer[..read])).await??;
+            sent += read as u64;
+        }
+        stream.close().await?;
+        Ok(sent)
+    }
+
+    /// Receives a file over a stream opened for a transfer.
+    ///
+    /// The data is appended to the partial file, which already holds `offset`
+    /// bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The sending peer.
+    /// * `transfer_id` - The transfer.
+    /// * `stream` - The stream opened to the sender.
+    /// * `part_path` - The path of the partial file.
+    /// * `offset` - The number of bytes already received.
+    /// * `size` - The size of the file.
+    /// * `events` - The channel to the event loop.
+    pub async fn receive_file(
+        peer: PeerId,
+        transfer_id: TransferId,
+        stream: Stream,
+        part_path: PathBuf,
+        offset: u64,
+        size: u64,
+        events: AppEvents,
+    ) {
+        let mut received = offset;
+        let result = read_frames(transfer_id, stream, part_path, size, &mut received, &events)
+            .await
+            .map(|()| received)
+            .map_err(|e| (e.to_string(), received));
+        let _ = events.send(AppEvent::Transfer(Box::new(TransferEvent::Received {
+            peer,
+            transfer_id,
+            result,
+        })));
+    }
+
+    /// Requests a transfer and writes the received frames to the partial file,
+    /// granting credit as data is written.
+    async fn read_frames(
+        transfer_id: TransferId,
+        mut stream: Stream,
+        part_path: PathBuf,
+        size: u64,
+        received: &mut u64,
+        events: &AppEvents,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&part_path)
+            .await?;
+        file.set_len(*received).await?;
+        file.seek(io::SeekFrom::Start(*received)).await?;
+
+        let mut header = transfer_id.0.to_vec();
+        header.extend_from_slice(&received.to_be_bytes());
+        stream.write_all(&header).await?;
+        stream.flush().await?;
+
+        let mut consumed = 0;
+        let mut buffer = vec![0u8; FRAME_SIZE];
+        loop {
+            let mut length = [0u8; 4];
+            timeout(STREAM_IDLE_TIMEOUT, stream.read_exact(&mut length)).await??;
+            let length = u32::from_be_bytes(length) as usize;
+            if length == 0 {
+                break;
+            }
+            if length > FRAME_SIZE || *received + length as u64 > size {
+                return Err("Frame does not match the offered file".into());
+            }
+            timeout(
+                STREAM_IDLE_TIMEOUT,
+                stream.read_exact(&mut buffer[..length]),
+            )
+            .await??;
+            file.write_all(&buffer[..length]).await?;
+            *received += length as u64;
+            consumed += length as u64;
+
+            if consumed >= STREAM_WINDOW / 2 {
+                file.flush().await?;
+                stream.write_all(&consumed.to_be_bytes()).await?;
+                stream.flush().await?;
+                consumed = 0;
+                let _ = events.send(AppEvent::Transfer(Box::new(TransferEvent::Progress {
+                    transfer_id,
+                    received: *received,
+                })));
+            }
+        }
+        file.flush().await?;
+        if *received != size {
+            return Err("Stream ended before the end of the file".into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+/*!
+ * Supervisor module for the messaging application.
+ *
+ * Networks come and go under a running node: a laptop sleeps, Wi-Fi
+ * switches, an interface disappears and its listener with it. The
+ * supervisor checks the health of the swarm every tick and repairs it
+ * without the user having to: it redials the bootstrap peers that are not
+ * connected and listens again once every listener closed, backing off
+ * between attempts, and after the network was lost it refreshes the
+ * Kademlia routing table, publishes the topic advertisements again and
+ * re-subscribes the topics no peer is subscribed to, so meshes form again
+ * right away. Inboxes are left alone, other peers never subscribing to
+ * them.
+ *
+ * The network counts as lost when the last peer disconnects, or when the
+ * time between two ticks shows the machine was asleep.
+ */
+
+use std::time::{Duration, Instant, SystemTime};
+
+use libp2p::{multiaddr::Protocol, Multiaddr, Swarm};
+use log::{debug, info, warn};
+
+use crate::event::advertise_topics;
+use crate::network::{bootstrap, listen_on};
+use crate::presence::PRESENCE_TOPIC;
+use crate::protocol::Protocols;
+use crate::state::AppState;
+use crate::topic::PubsubProtocol;
+
+/// The first delay between repair attempts.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The longest delay between repair attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A gap between ticks beyond which the machine is taken to have slept.
+const SLEEP_GAP: Duration = Duration::from_secs(30);
+
+/// What the supervisor sees of the swarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// The number of connected peers.
+    pub peers: usize,
+    /// The number of open listeners.
+    pub listeners: usize,
+    /// Whether some bootstrap peers are not connected.
+    pub bootstrap_missing: bool,
+}
+
+/// What the supervisor decides to do.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Repairs {
+    /// Dial the bootstrap peers that are not connected.
+    pub redial: bool,
+    /// Listen on the configured addresses again.
+    pub relisten: bool,
+    /// Refresh the DHT and the subscriptions, the network being back.
+    pub refresh: bool,
+}
+
+/// Watches the health of the swarm between ticks.
+pub struct Supervisor {
+    bootstrap: Vec<Multiaddr>,
+    listen_addrs: Vec<Multiaddr>,
+    /// The delay before the next attempt after this one.
+    backoff: Duration,
+    /// When repairs may next be attempted.
+    next_attempt: Instant,
+    /// The wall clock time of the last tick, which unlike `Instant` goes on
+    /// while the machine sleeps.
+    last_tick: SystemTime,
+    /// Whether peers were connected at the last tick.
+    online: bool,
+    /// Whether the network was lost since it was last refreshed.
+    lost: bool,
+}
+
+impl Supervisor {
+    /// Creates a supervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `bootstrap` - The addresses of the bootstrap peers.
+    /// * `listen_addrs` - The addresses listened on.
+    pub fn new(bootstrap: Vec<Multiaddr>, listen_addrs: Vec<Multiaddr>) -> Self {
+        Supervisor {
+            bootstrap,
+            listen_addrs,
+            backoff: MIN_BACKOFF,
+            next_attempt: Instant::now() + MIN_BACKOFF,
+            last_tick: SystemTime::now(),
+            online: false,
+            lost: false,
+        }
+    }
+
+    /// Replaces the bootstrap peers, when the configuration is reloaded.
+    pub fn set_bootstrap(&mut self, bootstrap: Vec<Multiaddr>) {
+        self.bootstrap = bootstrap;
+    }
+
+    /// Decides what to repair on a tick.
+    ///
+    /// # Arguments
+    ///
+    /// * `health` - What the swarm looks like.
+    /// * `now` - The time of the tick.
+    /// * `wall` - The wall clock time of the tick.
+    pub fn check(&mut self, health: Health, now: Instant, wall: SystemTime) -> Repairs {
+        let mut repairs = Repairs::default();
+        let slept = wall
+            .duration_since(self.last_tick)
+            .is_ok_and(|gap| gap > SLEEP_GAP);
+        self.last_tick = wall;
+        let disconnected = self.online && health.peers == 0;
+        if slept {
+            info!("Woke up from sleep, reconnecting");
+        } else if disconnected {
+            debug!("The last peer disconnected");
+        }
+        if slept || disconnected {
+            self.lost = true;
+            // Retries at once rather than after the backoff reached so far.
+            self.backoff = MIN_BACKOFF;
+            self.next_attempt = now;
+        }
+        self.online = health.peers > 0;
+
+        let broken =
+            health.bootstrap_missing || (health.listeners == 0 && !self.listen_addrs.is_empty());
+        if !broken {
+            self.backoff = MIN_BACKOFF;
+        } else if now >= self.next_attempt {
+            repairs.redial = health.bootstrap_missing;
+            repairs.relisten = health.listeners == 0 && !self.listen_addrs.is_empty();
+            self.next_attempt = now + self.backoff;
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        }
+        if self.lost && self.online {
+            self.lost = false;
+            repairs.refresh = true;
+        }
+        repairs
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Supervisor::new(Vec::new(), Vec::new())
+    }
+}
+
+/// Checks the health of the swarm and repairs it.
+///
+/// # Arguments
+///
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub fn supervise(swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    let missing: Vec<Multiaddr> = state
+        .supervisor
+        .bootstrap
+        .iter()
+        .filter(|addr| match addr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => !swarm.is_connected(&peer_id),
+            // Without a peer ID, any connection will do.
+            _ => swarm.connected_peers().next().is_none(),
+        })
+        .cloned()
+        .collect();
+    let health = Health {
+        peers: swarm.connected_peers().count(),
+        listeners: swarm.listeners().count(),
+        bootstrap_missing: !missing.is_empty(),
+    };
+    let repairs = state
+        .supervisor
+        .check(health, Instant::now(), SystemTime::now());
+    if repairs.redial {
+        bootstrap(swarm, &missing);
+    }
+    if repairs.relisten {
+        info!("No listener left, listening again");
+        if let Err(e) = listen_on(swarm, &state.supervisor.listen_addrs) {
+            warn!("Failed to listen again: {}", e);
+        }
+    }
+    if repairs.refresh {
+        refresh(swarm, state);
+    }
+}
+
+/// Refreshes the DHT and the subscriptions once the network is back.
+fn refresh(swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    info!("Network back, refreshing the DHT and the subscriptions");
+    if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
+        debug!("Failed to refresh the routing table: {:?}", e);
+    }
+    advertise_topics(swarm, state);
+
+    let mut topics: Vec<(String, PubsubProtocol)> = state
+        .topics
+        .subscribed()
+        .map(|(topic, protocol)| (topic.to_string(), protocol))
+        .collect();
+    topics.push((PRESENCE_TOPIC.to_string(), PubsubProtocol::Both));
+    for (topic, protocol) in topics {
+        if !swarm.behaviour().topic_peers(&topic).is_empty() {
+            continue;
+        }
+        // Subscribing anew announces the topic to the peers connected now.
+        let behaviour = swarm.behaviour_mut();
+        let result = behaviour
+            .unsubscribe(&topic, protocol)
+            .and_then(|_| behaviour.subscribe(&topic, protocol));
+        if let Err(e) = result {
+            warn!("Failed to re-subscribe to {:?}: {}", topic, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{Health, Repairs, Supervisor, MAX_BACKOFF, MIN_BACKOFF};
+
+    fn health(peers: usize, listeners: usize, bootstrap_missing: bool) -> Health {
+        Health {
+            peers,
+            listeners,
+            bootstrap_missing,
+        }
+    }
+
+    #[test]
+    fn test_supervisor() {
+        let listen = vec!["/ip4/0.0.0.0/tcp/0".parse().unwrap()];
+        let mut supervisor = Supervisor::new(Vec::new(), listen);
+        let start = Instant::now();
+        let wall = SystemTime::now();
+        let second = Duration::from_secs(1);
+
+        // Healthy, nothing to do.
+        let repairs = supervisor.check(health(1, 1, false), start, wall);
+        assert_eq!(repairs, Repairs::default());
+
+        // The last peer leaves and a bootstrap peer is missing: redials at
+        // once, then backs off.
+        let repairs = supervisor.check(health(0, 1, true), start + second, wall + second);
+        assert!(repairs.redial && !repairs.relisten && !repairs.refresh);
+        let repairs = supervisor.check(health(0, 1, true), start + second * 2, wall + second * 2);
+        assert_eq!(repairs, Repairs::default());
+        let later = start + second + MIN_BACKOFF;
+        let repairs = supervisor.check(health(0, 0, true), later, wall + 6 * second);
+        assert!(repairs.redial && repairs.relisten);
+        assert_eq!(supervisor.backoff, MIN_BACKOFF * 4);
+
+        // Peers are back: the subscriptions are refreshed, once.
+        let repairs = supervisor.check(health(2, 1, false), later + second, wall + 7 * second);
+        assert!(repairs.refresh && !repairs.redial);
+        assert_eq!(supervisor.backoff, MIN_BACKOFF);
+        let repairs = supervisor.check(health(2, 1, false), later + 2 * second, wall + 8 * second);
+        assert_eq!(repairs, Repairs::default());
+
+        // Waking up from sleep counts as losing the network.
+        let woke = wall + Duration::from_secs(3600);
+        let repairs = supervisor.check(health(2, 1, true), later + 3 * second, woke);
+        assert!(repairs.redial && repairs.refresh);
+
+        for tick in 0..20 {
+            supervisor.check(health(0, 1, true), later + MAX_BACKOFF * tick, woke);
+        }
+        assert_eq!(supervisor.backoff, MAX_BACKOFF);
+    }
+}
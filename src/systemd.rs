@@ -0,0 +1,193 @@
+/*!
+ * Systemd module for the messaging application.
+ *
+ * Run as a `Type=notify` service, the node tells systemd when it is ready,
+ * what it is doing and when it stops, over the socket in `NOTIFY_SOCKET`.
+ * With `WatchdogSec=` set, the swarm loop also pings systemd from its tick,
+ * so a node whose loop hangs stops pinging and is restarted. The daemon
+ * can be socket-activated too: when systemd passes it a listening Unix
+ * socket, clients are served on that socket instead of one bound by the
+ * daemon. Outside systemd, all of this does nothing.
+ */
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+/// The first file descriptor passed by systemd.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Tells systemd about a change of state, such as `READY=1`.
+///
+/// # Arguments
+///
+/// * `state` - The newline-separated assignments sent.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        debug!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Sends a notification to a socket, whose name starts with `@` when it
+/// is abstract.
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Whether systemd passed listening sockets to this process.
+pub fn socket_activated() -> bool {
+    listen_fds() > 0
+}
+
+/// Returns the number of sockets systemd passed to this process.
+fn listen_fds() -> i32 {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    match for_us {
+        true => env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse().ok())
+            .unwrap_or(0),
+        false => 0,
+    }
+}
+
+/// Takes the listening Unix socket passed by systemd, if any.
+///
+/// # Returns
+///
+/// The first socket passed, which must be a Unix stream socket, or none
+/// when the process was not socket-activated.
+#[cfg(unix)]
+pub fn activated_listener() -> std::io::Result<Option<std::os::unix::net::UnixListener>> {
+    use std::os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::net::UnixListener,
+    };
+
+    let fds = listen_fds();
+    if fds > 1 {
+        debug!("Using the first of the {} sockets passed by systemd", fds);
+    }
+    if fds < 1 {
+        return Ok(None);
+    }
+    // SAFETY: systemd passes the sockets as open descriptors from 3 on,
+    // which nothing else in the process owns.
+    let listener = UnixListener::from(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) });
+    // Fails on a socket of another family.
+    listener.local_addr()?;
+    Ok(Some(listener))
+}
+
+/// Pings systemd often enough to keep its watchdog from firing.
+pub struct Watchdog {
+    interval: Duration,
+    pinged: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Creates the watchdog systemd asked for, if any.
+    pub fn from_env() -> Option<Self> {
+        Watchdog::new(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        )
+    }
+
+    /// Creates a watchdog from the values systemd sets.
+    ///
+    /// # Arguments
+    ///
+    /// * `usec` - The timeout of the watchdog, in microseconds.
+    /// * `pid` - The process the watchdog is for, if set.
+    /// * `own_pid` - The ID of this process.
+    fn new(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Self> {
+        if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+            return None;
+        }
+        let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+        Some(Watchdog {
+            // Half the timeout, as systemd recommends.
+            interval: Duration::from_micros(usec / 2),
+            pinged: None,
+        })
+    }
+
+    /// Returns whether a ping is due, counting it as sent.
+    fn due(&mut self, now: Instant) -> bool {
+        let due = self
+            .pinged
+            .is_none_or(|pinged| now.duration_since(pinged) >= self.interval);
+        if due {
+            self.pinged = Some(now);
+        }
+        due
+    }
+
+    /// Pings systemd if a ping is due, on every tick of the swarm loop.
+    pub fn tick(&mut self) {
+        if self.due(Instant::now()) {
+            notify("WATCHDOG=1");
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{send, Watchdog};
+
+    #[test]
+    fn test_notify() {
+        assert!(Watchdog::new(None, None, 7).is_none());
+        assert!(Watchdog::new(Some("10000000"), Some("8"), 7).is_none());
+        let mut watchdog = Watchdog::new(Some("10000000"), Some("7"), 7).unwrap();
+        assert_eq!(watchdog.interval, Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(watchdog.due(start));
+        assert!(!watchdog.due(start + Duration::from_secs(4)));
+        assert!(watchdog.due(start + Duration::from_secs(5)));
+
+        let dir = std::env::temp_dir().join(format!("sec_msg-systemd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buffer = [0u8; 16];
+        let read = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"READY=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
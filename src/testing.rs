@@ -0,0 +1,247 @@
+/*!
+ * Testing module for the messaging application.
+ *
+ * End-to-end tests run several nodes in one process, connected over the
+ * in-memory transport of libp2p rather than sockets. A `TestNet` builds the
+ * nodes, each dialing the ones before it, or a few of them at random in
+ * larger networks, and drives their swarms until every node knows the nodes
+ * it is connected to are subscribed to the topics, so a message published
+ * right after is received without waiting an arbitrary while. The nodes
+ * then run their usual loops and are driven through `Client` handles, as
+ * bots drive them:
+ *
+ * ```ignore
+ * let net = TestNet::new(2, &["chat"]).await?;
+ * let mut messages = net.client(1).subscribe("chat").await?;
+ * net.client(0).publish("chat", "hi").await?;
+ * assert_eq!(messages.next().await.unwrap().body, "hi");
+ * ```
+ *
+ * The connections between the nodes share a `ChaosControl`, which turns the
+ * network hostile once the nodes settled.
+ *
+ * The harness is built for the tests of the crate, and for those of other
+ * crates with the `testing` feature.
+ */
+
+use std::{error::Error, time::Duration};
+
+use futures::{future::select_all, StreamExt};
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
+use rand::seq::index;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::app::{AppEvent, AppEvents};
+use crate::chaos::ChaosControl;
+use crate::cli::Options;
+use crate::client::Client;
+use crate::config::Config;
+use crate::event;
+use crate::network::create_memory_swarm;
+use crate::node::Node;
+use crate::render::Output;
+use crate::shutdown::Signal;
+
+/// Longest the nodes may take to find each other, for a few of them.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest each node may add to the time taken to find each other.
+const SETTLE_TIMEOUT_PER_NODE: Duration = Duration::from_secs(1);
+
+/// A node of a test network.
+pub struct TestNode {
+    pub peer_id: PeerId,
+    /// The in-memory address the node listens on, with its peer ID.
+    pub address: Multiaddr,
+    client: Client,
+    events: AppEvents,
+    task: JoinHandle<Option<Signal>>,
+}
+
+/// Nodes connected to each other over the in-memory transport.
+pub struct TestNet {
+    nodes: Vec<TestNode>,
+    chaos: ChaosControl,
+}
+
+impl TestNet {
+    /// Starts connected nodes subscribed to topics, once every node knows
+    /// every other one is subscribed to them.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of nodes.
+    /// * `topics` - The topics every node joins, at least one.
+    pub async fn new(count: usize, topics: &[&str]) -> Result<Self, Box<dyn Error>> {
+        let options = Options {
+            topic: topics.iter().map(|topic| topic.to_string()).collect(),
+            ..Options::default()
+        };
+        TestNet::with_config(count, count, &Config::from_options(&options)?).await
+    }
+
+    /// Starts nodes sharing a configuration, each connected to random
+    /// nodes started before it, once every node knows the nodes it is
+    /// connected to are subscribed to its topics.
+    ///
+    /// The nodes keep nothing on disk, show nothing, and ignore the
+    /// addresses, identity and email gateway of the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of nodes.
+    /// * `links` - The number of nodes each node connects to when it
+    ///   starts, all the ones before it if fewer.
+    /// * `config` - The configuration of every node.
+    pub async fn with_config(
+        count: usize,
+        links: usize,
+        config: &Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let topics: Vec<&str> = config.topics.iter().map(String::as_str).collect();
+        let mut nodes = Vec::with_capacity(count);
+        let mut addresses: Vec<Multiaddr> = Vec::with_capacity(count);
+        let mut neighbours = vec![0; count];
+        let chaos = ChaosControl::default();
+        for index in 0..count {
+            let local_key = identity::Keypair::generate_ed25519();
+            let peer_id = local_key.public().to_peer_id();
+            let memory = Multiaddr::from(Protocol::Memory(rand::random()));
+            let address = memory.clone().with(Protocol::P2p(peer_id));
+            let mut config = config.clone();
+            config.identity = None;
+            config.dirs = None;
+            config.email = None;
+            config.pipe_topic = None;
+            config.notifications = false;
+            config.encrypt_storage = false;
+            config.output = Output::Text;
+            config.listen_addrs = vec![memory];
+            let earlier = index::sample(&mut rand::thread_rng(), index, links.min(index));
+            for other in earlier.iter() {
+                neighbours[other] += 1;
+            }
+            neighbours[index] = earlier.len();
+            config.bootstrap = earlier
+                .iter()
+                .map(|other| addresses[other].clone())
+                .collect();
+            let swarm = create_memory_swarm(
+                local_key.clone(),
+                peer_id,
+                &config.topics[0],
+                config.pubsub_protocol,
+                chaos.clone(),
+            )?;
+            // Messages are shown to a UI that is not there.
+            let (ui, _) = mpsc::unbounded_channel();
+            nodes.push(Node::with_swarm(swarm, &config, local_key, None, Some(ui))?);
+            addresses.push(address);
+        }
+        let timeout = SETTLE_TIMEOUT.max(SETTLE_TIMEOUT_PER_NODE * count as u32);
+        tokio::time::timeout(timeout, settle(&mut nodes, &neighbours, &topics))
+            .await
+            .map_err(|_| "The test nodes did not find each other")?;
+        let nodes = nodes
+            .into_iter()
+            .zip(addresses)
+            .map(|(node, address)| TestNode {
+                peer_id: *node.swarm.local_peer_id(),
+                address,
+                client: node.client(),
+                events: node.events(),
+                task: tokio::spawn(node.run()),
+            })
+            .collect();
+        Ok(TestNet { nodes, chaos })
+    }
+
+    /// Returns a node.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The node, in the order they were started.
+    pub fn node(&self, index: usize) -> &TestNode {
+        &self.nodes[index]
+    }
+
+    /// Returns the handle to a node.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The node, in the order they were started.
+    pub fn client(&self, index: usize) -> &Client {
+        &self.nodes[index].client
+    }
+
+    /// Returns the chaos of the connections between the nodes, calm until
+    /// changed.
+    pub fn chaos(&self) -> &ChaosControl {
+        &self.chaos
+    }
+
+    /// Stops the nodes, waiting for them to leave their topics.
+    pub async fn shutdown(self) {
+        for node in &self.nodes {
+            let _ = node.events.send(AppEvent::InputClosed);
+        }
+        for node in self.nodes {
+            let _ = node.task.await;
+        }
+    }
+}
+
+/// Drives the swarms of nodes until each sees the nodes it is connected to
+/// subscribed to the topics.
+///
+/// # Arguments
+///
+/// * `nodes` - The nodes.
+/// * `neighbours` - The number of nodes each node is connected to.
+/// * `topics` - The topics.
+async fn settle(nodes: &mut [Node], neighbours: &[usize], topics: &[&str]) {
+    let settled = |(node, &neighbours): (&Node, &usize)| {
+        topics
+            .iter()
+            .all(|topic| node.swarm.behaviour().topic_peers(topic).len() >= neighbours)
+    };
+    while !nodes.iter().zip(neighbours).all(settled) {
+        let (event, index) = {
+            let next = nodes.iter_mut().enumerate().map(|(index, node)| {
+                Box::pin(async move { (node.swarm.select_next_some().await, index) })
+            });
+            select_all(next).await.0
+        };
+        let node = &mut nodes[index];
+        event::handle_event(event, &mut node.swarm, &mut node.state).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::TestNet;
+
+    #[tokio::test]
+    async fn test_publish_and_receive() {
+        let net = TestNet::new(3, &["chat"]).await.unwrap();
+        let mut second = net.client(1).subscribe("chat").await.unwrap();
+        let mut third = net.client(2).subscribe("chat").await.unwrap();
+        net.client(0).publish("chat", "hello").await.unwrap();
+        for messages in [&mut second, &mut third] {
+            let message = messages.next().await.unwrap();
+            assert_eq!(message.body, "hello");
+            assert_eq!(message.peer, net.node(0).peer_id);
+        }
+        // Sent over both pubsub protocols, the first message arrived once,
+        // so the next one received is the next one sent.
+        net.client(0).publish("chat", "again").await.unwrap();
+        for messages in [&mut second, &mut third] {
+            assert_eq!(messages.next().await.unwrap().body, "again");
+        }
+        let peers = net.client(0).peers().await.unwrap();
+        assert_eq!(peers.len(), 2);
+        net.shutdown().await;
+    }
+}
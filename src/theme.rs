@@ -0,0 +1,211 @@
+/*!
+ * Theme module for the messaging application.
+ *
+ * This module gathers the styles the terminal UI draws with: the colors of
+ * messages, log lines and badges, how timestamps look, and the borders of
+ * the panes. Besides the default theme there is a high-contrast theme for
+ * low-quality displays and a monochrome theme that relies on bold, dim,
+ * underlined and reversed text alone, for terminals without colors.
+ */
+
+use std::{fmt, str::FromStr};
+
+use log::Level;
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, BorderType},
+};
+
+/// The styles the terminal UI draws with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    /// Message bodies and informational log lines.
+    pub text: Style,
+    pub timestamp: Style,
+    /// Message IDs and other secondary details.
+    pub dim: Style,
+    pub error: Style,
+    pub warning: Style,
+    /// Debug and trace log lines.
+    pub debug: Style,
+    /// Messages mentioning us or a watched keyword.
+    pub highlight: Style,
+    /// Inline code and code blocks.
+    pub code: Style,
+    /// Unread message counts.
+    pub badge: Style,
+    /// Unread mention counts.
+    pub mention_badge: Style,
+    /// The focused conversation.
+    pub selected: Style,
+    pub status_bar: Style,
+    pub border: Style,
+    pub border_type: BorderType,
+    /// Whether senders are drawn in their own color.
+    pub sender_colors: bool,
+}
+
+/// The built-in themes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThemeName {
+    Default,
+    HighContrast,
+    Monochrome,
+}
+
+impl FromStr for ThemeName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(ThemeName::Default),
+            "high-contrast" => Ok(ThemeName::HighContrast),
+            "monochrome" => Ok(ThemeName::Monochrome),
+            other => Err(format!("unknown theme {:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for ThemeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThemeName::Default => write!(f, "default"),
+            ThemeName::HighContrast => write!(f, "high-contrast"),
+            ThemeName::Monochrome => write!(f, "monochrome"),
+        }
+    }
+}
+
+impl Theme {
+    /// Returns a built-in theme.
+    pub fn new(name: ThemeName) -> Self {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let reversed = Style::default().add_modifier(Modifier::REVERSED);
+        match name {
+            ThemeName::Default => Theme {
+                text: Style::default(),
+                timestamp: Style::default().fg(Color::DarkGray),
+                dim: Style::default().fg(Color::DarkGray),
+                error: Style::default().fg(Color::Red),
+                warning: Style::default().fg(Color::Yellow),
+                debug: Style::default().fg(Color::DarkGray),
+                highlight: bold.fg(Color::Yellow),
+                code: Style::default().fg(Color::Cyan),
+                badge: bold.fg(Color::Yellow),
+                mention_badge: bold.fg(Color::Red),
+                selected: reversed,
+                status_bar: reversed,
+                border: Style::default(),
+                border_type: BorderType::Plain,
+                sender_colors: true,
+            },
+            ThemeName::HighContrast => Theme {
+                text: Style::default().fg(Color::White),
+                timestamp: Style::default().fg(Color::Gray),
+                dim: Style::default().fg(Color::Gray),
+                error: bold.fg(Color::LightRed),
+                warning: bold.fg(Color::LightYellow),
+                debug: Style::default().fg(Color::Gray),
+                highlight: bold.fg(Color::Black).bg(Color::LightYellow),
+                code: bold.fg(Color::LightCyan),
+                badge: bold.fg(Color::LightYellow),
+                mention_badge: bold.fg(Color::Black).bg(Color::LightRed),
+                selected: bold.fg(Color::Black).bg(Color::White),
+                status_bar: bold.fg(Color::Black).bg(Color::White),
+                border: Style::default().fg(Color::White),
+                border_type: BorderType::Thick,
+                sender_colors: true,
+            },
+            ThemeName::Monochrome => Theme {
+                text: Style::default(),
+                timestamp: Style::default().add_modifier(Modifier::DIM),
+                dim: Style::default().add_modifier(Modifier::DIM),
+                error: bold,
+                warning: bold,
+                debug: Style::default().add_modifier(Modifier::DIM),
+                highlight: bold.add_modifier(Modifier::UNDERLINED),
+                code: Style::default().add_modifier(Modifier::UNDERLINED),
+                badge: bold,
+                mention_badge: bold.add_modifier(Modifier::UNDERLINED),
+                selected: reversed,
+                status_bar: reversed,
+                border: Style::default(),
+                border_type: BorderType::Plain,
+                sender_colors: false,
+            },
+        }
+    }
+
+    /// Returns the style of a log line.
+    pub fn log(&self, level: Level) -> Style {
+        match level {
+            Level::Error => self.error,
+            Level::Warn => self.warning,
+            Level::Info => self.text,
+            Level::Debug | Level::Trace => self.debug,
+        }
+    }
+
+    /// Returns the style of a sender drawn in a color.
+    pub fn sender(&self, color: u8) -> Style {
+        let style = Style::default().add_modifier(Modifier::BOLD);
+        if self.sender_colors {
+            style.fg(Color::Indexed(color))
+        } else {
+            style
+        }
+    }
+
+    /// Returns a bordered block with a title.
+    pub fn block<'a>(&self, title: impl Into<Line<'a>>) -> Block<'a> {
+        Block::bordered()
+            .border_type(self.border_type)
+            .border_style(self.border)
+            .title(title)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::new(ThemeName::Default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use ratatui::style::{Color, Modifier};
+
+    use super::{Theme, ThemeName};
+
+    #[test]
+    fn test_themes() {
+        assert_eq!("high-contrast".parse(), Ok(ThemeName::HighContrast));
+        assert!("solarized".parse::<ThemeName>().is_err());
+        assert_eq!(ThemeName::Monochrome.to_string(), "monochrome");
+
+        let default = Theme::default();
+        assert_eq!(default.sender(33).fg, Some(Color::Indexed(33)));
+        assert_eq!(default.log(Level::Error).fg, Some(Color::Red));
+
+        // The monochrome theme sets no colors at all.
+        let monochrome = Theme::new(ThemeName::Monochrome);
+        assert_eq!(monochrome.sender(33).fg, None);
+        for style in [
+            monochrome.text,
+            monochrome.timestamp,
+            monochrome.error,
+            monochrome.highlight,
+            monochrome.code,
+            monochrome.mention_badge,
+            monochrome.status_bar,
+        ] {
+            assert_eq!((style.fg, style.bg), (None, None));
+        }
+        assert!(monochrome
+            .highlight
+            .add_modifier
+            .contains(Modifier::UNDERLINED));
+    }
+}
@@ -0,0 +1,204 @@
+/*!
+ * Topic management module for the messaging application.
+ *
+ * This module keeps track of the topics the local node is subscribed to,
+ * the pubsub protocols each of them uses, and which of them plain user
+ * input is published to.
+ */
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// Pubsub protocols a topic is subscribed and published on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PubsubProtocol {
+    Floodsub,
+    Gossipsub,
+    Both,
+}
+
+impl PubsubProtocol {
+    /// Returns whether Floodsub is used.
+    pub fn floodsub(self) -> bool {
+        matches!(self, PubsubProtocol::Floodsub | PubsubProtocol::Both)
+    }
+
+    /// Returns whether Gossipsub is used.
+    pub fn gossipsub(self) -> bool {
+        matches!(self, PubsubProtocol::Gossipsub | PubsubProtocol::Both)
+    }
+}
+
+impl FromStr for PubsubProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "floodsub" => Ok(PubsubProtocol::Floodsub),
+            "gossipsub" => Ok(PubsubProtocol::Gossipsub),
+            "both" => Ok(PubsubProtocol::Both),
+            other => Err(format!("unknown pubsub protocol {:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for PubsubProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PubsubProtocol::Floodsub => write!(f, "floodsub"),
+            PubsubProtocol::Gossipsub => write!(f, "gossipsub"),
+            PubsubProtocol::Both => write!(f, "both"),
+        }
+    }
+}
+
+/// Local bookkeeping of subscribed topics.
+pub struct TopicManager {
+    subscribed: BTreeMap<String, PubsubProtocol>,
+    active: Option<String>,
+    default_protocol: PubsubProtocol,
+}
+
+impl TopicManager {
+    /// Creates a new `TopicManager` with no subscriptions.
+    ///
+    /// # Arguments
+    ///
+    /// * `default_protocol` - The protocols used by topics joined without a choice.
+    ///
+    /// # Returns
+    ///
+    /// A new `TopicManager` instance.
+    pub fn new(default_protocol: PubsubProtocol) -> Self {
+        TopicManager {
+            subscribed: BTreeMap::new(),
+            active: None,
+            default_protocol,
+        }
+    }
+
+    /// Records a subscription to the specified topic.
+    ///
+    /// The first joined topic becomes the active topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic that was subscribed to.
+    /// * `protocol` - The protocols the topic was subscribed on.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the topic was not already tracked.
+    pub fn join(&mut self, topic: &str, protocol: PubsubProtocol) -> bool {
+        if self.active.is_none() {
+            self.active = Some(topic.to_string());
+        }
+        self.subscribed
+            .insert(topic.to_string(), protocol)
+            .is_none()
+    }
+
+    /// Removes all local state for the specified topic.
+    ///
+    /// If the topic was the active topic, another subscribed topic (if any)
+    /// becomes active.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic that was unsubscribed from.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the topic was tracked.
+    pub fn leave(&mut self, topic: &str) -> bool {
+        let removed = self.subscribed.remove(topic).is_some();
+        if self.active.as_deref() == Some(topic) {
+            self.active = self.subscribed.keys().next().cloned();
+        }
+        removed
+    }
+
+    /// Makes the specified subscribed topic the active topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic plain user input should be published to.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the topic is subscribed.
+    pub fn switch(&mut self, topic: &str) -> bool {
+        if !self.is_subscribed(topic) {
+            return false;
+        }
+        self.active = Some(topic.to_string());
+        true
+    }
+
+    /// Returns whether the specified topic is subscribed.
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscribed.contains_key(topic)
+    }
+
+    /// Returns the subscribed topics and their protocols, in order.
+    pub fn subscribed(&self) -> impl Iterator<Item = (&str, PubsubProtocol)> {
+        self.subscribed
+            .iter()
+            .map(|(topic, protocol)| (topic.as_str(), *protocol))
+    }
+
+    /// Returns the protocols messages to the specified topic are published on.
+    ///
+    /// Topics that are not subscribed, such as the inboxes of other peers,
+    /// are published on both protocols.
+    pub fn protocol(&self, topic: &str) -> PubsubProtocol {
+        self.subscribed
+            .get(topic)
+            .copied()
+            .unwrap_or(PubsubProtocol::Both)
+    }
+
+    /// Returns the protocols used by topics joined without a choice.
+    pub fn default_protocol(&self) -> PubsubProtocol {
+        self.default_protocol
+    }
+
+    /// Returns the topic plain user input is published to.
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PubsubProtocol, TopicManager};
+
+    #[test]
+    fn test_join_sets_active() {
+        let mut topics = TopicManager::new(PubsubProtocol::Both);
+        assert!(topics.join("chat", PubsubProtocol::Both));
+        assert!(!topics.join("chat", PubsubProtocol::Both));
+        assert!(topics.join("other", PubsubProtocol::Gossipsub));
+        assert_eq!(topics.active(), Some("chat"));
+        assert!(topics.switch("other"));
+        assert!(!topics.switch("dm/peer"));
+        assert_eq!(topics.active(), Some("other"));
+        assert!(topics.is_subscribed("other"));
+        assert_eq!(topics.protocol("other"), PubsubProtocol::Gossipsub);
+        assert_eq!(topics.protocol("dm/peer"), PubsubProtocol::Both);
+        assert_eq!("floodsub".parse(), Ok(PubsubProtocol::Floodsub));
+        assert!("carrier-pigeon".parse::<PubsubProtocol>().is_err());
+    }
+
+    #[test]
+    fn test_leave_cleans_up() {
+        let mut topics = TopicManager::new(PubsubProtocol::Both);
+        topics.join("chat", PubsubProtocol::Both);
+        topics.join("other", PubsubProtocol::Both);
+        assert!(topics.leave("chat"));
+        assert!(!topics.is_subscribed("chat"));
+        assert_eq!(topics.active(), Some("other"));
+        assert!(topics.leave("other"));
+        assert_eq!(topics.active(), None);
+        assert!(!topics.leave("other"));
+    }
+}
@@ -0,0 +1,520 @@
+/*!
+ * File transfer module for the messaging application.
+ *
+ * This module defines the request-response messages of the file transfer
+ * protocol and the bookkeeping for outgoing and incoming transfers. The
+ * receiver pulls a file chunk by chunk into a partial file named after the
+ * file hash, so an interrupted transfer of the same file resumes where it
+ * stopped, and the file is only moved into place once its hash matches.
+ */
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use libp2p::{request_response::OutboundRequestId, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::compression::{self, Compression};
+use crate::delivery::MessageId;
+use crate::stream::STREAM_THRESHOLD;
+
+/// Protocol name of the file transfer protocol.
+pub const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/sec_msg/file/1.0.0");
+
+/// Number of bytes requested per chunk.
+pub const CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Identifier of a file transfer.
+pub type TransferId = MessageId;
+
+/// Requests sent by the file transfer protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileRequest {
+    /// Announces a file the sender wants to transfer.
+    Offer {
+        transfer_id: TransferId,
+        name: String,
+        size: u64,
+        sha256: [u8; 32],
+    },
+    /// Asks the sender for a range of an offered file.
+    Chunk {
+        transfer_id: TransferId,
+        offset: u64,
+        length: u32,
+        /// Whether the requester accepts compressed chunks.
+        compression: bool,
+    },
+}
+
+/// Responses sent by the file transfer protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileResponse {
+    /// The request was accepted.
+    Ack,
+    /// The requested bytes of a file.
+    Chunk {
+        compression: Compression,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// The request could not be served.
+    Error(String),
+}
+
+/// A file offered to a peer.
+pub struct OutgoingFile {
+    pub peer: PeerId,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// A file offered by a peer.
+pub struct IncomingFile {
+    pub peer: PeerId,
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+    pub received: u64,
+    part_path: PathBuf,
+}
+
+impl IncomingFile {
+    /// Appends a received chunk at the specified offset of the partial file.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset the chunk was requested at.
+    /// * `data` - The chunk data.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        if offset != self.received || offset + data.len() as u64 > self.size {
+            return Err("Chunk does not match the requested range".into());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.part_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the path of the partial file.
+    pub fn part_path(&self) -> &Path {
+        &self.part_path
+    }
+
+    /// Returns whether the file is large enough to be streamed.
+    pub fn is_streamed(&self) -> bool {
+        self.size > STREAM_THRESHOLD
+    }
+
+    /// Returns whether every byte of the file has been received.
+    pub fn is_complete(&self) -> bool {
+        self.received >= self.size
+    }
+
+    /// Returns the next chunk request for this transfer.
+    pub fn next_request(&self, transfer_id: TransferId) -> FileRequest {
+        FileRequest::Chunk {
+            transfer_id,
+            offset: self.received,
+            length: CHUNK_SIZE.min((self.size - self.received) as u32),
+            compression: true,
+        }
+    }
+
+    /// Verifies the hash of the completed file and moves it into the download
+    /// directory.
+    ///
+    /// The partial file is removed if the hash does not match.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the path of the saved file or an error.
+    pub fn finish(&self) -> Result<PathBuf, Box<dyn Error>> {
+        if hash_file(&self.part_path)? != self.sha256 {
+            fs::remove_file(&self.part_path)?;
+            return Err("Hash mismatch, the received file was discarded".into());
+        }
+
+        let directory = self.part_path.parent().unwrap_or(Path::new("."));
+        let mut target = directory.join(&self.name);
+        let mut counter = 1;
+        while target.exists() {
+            target = directory.join(format!("{}.{}", self.name, counter));
+            counter += 1;
+        }
+        fs::rename(&self.part_path, &target)?;
+        Ok(target)
+    }
+}
+
+/// Bookkeeping for all file transfers of the local node.
+pub struct TransferManager {
+    download_dir: PathBuf,
+    outgoing: HashMap<TransferId, OutgoingFile>,
+    offered: HashMap<TransferId, IncomingFile>,
+    incoming: HashMap<TransferId, IncomingFile>,
+    in_flight: HashMap<OutboundRequestId, (TransferId, u64)>,
+}
+
+impl TransferManager {
+    /// Creates a new `TransferManager` saving files to `download_dir`.
+    pub fn new(download_dir: PathBuf) -> Self {
+        TransferManager {
+            download_dir,
+            outgoing: HashMap::new(),
+            offered: HashMap::new(),
+            incoming: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Prepares a file to be offered to a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer the file is offered to.
+    /// * `path` - The path of the file.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the offer request to send or an error.
+    pub fn offer(&mut self, peer: PeerId, path: &Path) -> Result<FileRequest, Box<dyn Error>> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("Path does not name a file")?
+            .to_string();
+        let size = fs::metadata(path)?.len();
+        let sha256 = hash_file(path)?;
+        let transfer_id = TransferId::random();
+
+        self.outgoing.insert(
+            transfer_id,
+            OutgoingFile {
+                peer,
+                path: path.to_path_buf(),
+                size,
+            },
+        );
+        Ok(FileRequest::Offer {
+            transfer_id,
+            name,
+            size,
+            sha256,
+        })
+    }
+
+    /// Records a file offered by a peer until it is accepted.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the offer was recorded.
+    pub fn receive_offer(
+        &mut self,
+        peer: PeerId,
+        transfer_id: TransferId,
+        name: &str,
+        size: u64,
+        sha256: [u8; 32],
+    ) -> Result<(), Box<dyn Error>> {
+        let name = sanitize_file_name(name).ok_or("Invalid file name")?;
+        let part_path = self.download_dir.join(format!("{}.part", hex(&sha256)));
+        self.offered.insert(
+            transfer_id,
+            IncomingFile {
+                peer,
+                name,
+                size,
+                sha256,
+                received: 0,
+                part_path,
+            },
+        );
+        Ok(())
+    }
+
+    /// Accepts an offered file, resuming from a partial file if one exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The accepted transfer.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the offering peer and the first chunk request.
+    pub fn accept(
+        &mut self,
+        transfer_id: &TransferId,
+    ) -> Result<(PeerId, FileRequest), Box<dyn Error>> {
+        let mut file = self
+            .offered
+            .remove(transfer_id)
+            .ok_or("No such file offer")?;
+        fs::create_dir_all(&self.download_dir)?;
+        file.received = fs::metadata(&file.part_path)
+            .map(|metadata| metadata.len().min(file.size))
+            .unwrap_or(0);
+
+        let peer = file.peer;
+        let request = file.next_request(*transfer_id);
+        self.incoming.insert(*transfer_id, file);
+        Ok((peer, request))
+    }
+
+    /// Finds a pending offer whose ID starts with `prefix`.
+    pub fn find_offer(&self, prefix: &str) -> Option<TransferId> {
+        let mut matches = self
+            .offered
+            .keys()
+            .filter(|id| id.to_string().starts_with(prefix));
+        match (matches.next(), matches.next()) {
+            (Some(id), None) => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// Reads the requested range of an outgoing file.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer requesting the chunk.
+    /// * `transfer_id` - The requested transfer.
+    /// * `offset` - The offset of the chunk.
+    /// * `length` - The requested length of the chunk.
+    /// * `compression` - Whether the chunk may be compressed.
+    ///
+    /// # Returns
+    ///
+    /// The response to send back.
+    pub fn read_chunk(
+        &self,
+        peer: &PeerId,
+        transfer_id: &TransferId,
+        offset: u64,
+        length: u32,
+        compression: bool,
+    ) -> FileResponse {
+        let Some(file) = self.outgoing.get(transfer_id).filter(|f| f.peer == *peer) else {
+            return FileResponse::Error("Unknown transfer".to_string());
+        };
+        if offset > file.size {
+            return FileResponse::Error("Offset beyond end of file".to_string());
+        }
+
+        let length = length.min(CHUNK_SIZE) as u64;
+        let result = File::open(&file.path).and_then(|mut f| {
+            f.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::new();
+            f.take(length).read_to_end(&mut data)?;
+            Ok(data)
+        });
+        match result {
+            Ok(data) if compression => {
+                let (compression, data) = compression::compress(data);
+                FileResponse::Chunk { compression, data }
+            }
+            Ok(data) => FileResponse::Chunk {
+                compression: Compression::None,
+                data,
+            },
+            Err(e) => FileResponse::Error(e.to_string()),
+        }
+    }
+
+    /// Returns the path of an outgoing file offered to `peer`.
+    pub fn outgoing_path(&self, peer: &PeerId, transfer_id: &TransferId) -> Option<&Path> {
+        self.outgoing
+            .get(transfer_id)
+            .filter(|file| file.peer == *peer)
+            .map(|file| file.path.as_path())
+    }
+
+    /// Records an outstanding chunk request.
+    pub fn track_request(&mut self, request_id: OutboundRequestId, request: &FileRequest) {
+        if let FileRequest::Chunk {
+            transfer_id,
+            offset,
+            ..
+        } = request
+        {
+            self.in_flight.insert(request_id, (*transfer_id, *offset));
+        }
+    }
+
+    /// Takes the transfer and offset an outstanding chunk request belongs to.
+    pub fn take_request(&mut self, request_id: &OutboundRequestId) -> Option<(TransferId, u64)> {
+        self.in_flight.remove(request_id)
+    }
+
+    /// Returns a mutable reference to an incoming transfer.
+    pub fn incoming_mut(&mut self, transfer_id: &TransferId) -> Option<&mut IncomingFile> {
+        self.incoming.get_mut(transfer_id)
+    }
+
+    /// Stops tracking an incoming transfer, returning it to the offered
+    /// transfers so it can be accepted again to resume.
+    pub fn suspend(&mut self, transfer_id: &TransferId) {
+        if let Some(file) = self.incoming.remove(transfer_id) {
+            self.offered.insert(*transfer_id, file);
+        }
+    }
+
+    /// Stops tracking a finished incoming transfer.
+    pub fn complete(&mut self, transfer_id: &TransferId) -> Option<IncomingFile> {
+        self.incoming.remove(transfer_id)
+    }
+}
+
+/// Restores a chunk received in a `FileResponse::Chunk`.
+pub fn decompress_chunk(compression: Compression, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(compression::decompress(
+        compression,
+        data,
+        CHUNK_SIZE as usize,
+    )?)
+}
+
+/// Computes the SHA-256 hash of a file.
+pub fn hash_file(path: &Path) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Reduces a file name chosen by a peer to a plain file name.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    if name.is_empty() || name.starts_with('.') {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Formats bytes as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use libp2p::PeerId;
+
+    use super::{
+        decompress_chunk, hash_file, sanitize_file_name, FileRequest, FileResponse, TransferManager,
+    };
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(
+            sanitize_file_name("notes.txt").as_deref(),
+            Some("notes.txt")
+        );
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(sanitize_file_name(".bashrc"), None);
+        assert_eq!(sanitize_file_name(".."), None);
+    }
+
+    #[test]
+    fn test_transfer_roundtrip_with_resume() {
+        let root = std::env::temp_dir().join(format!("sec_msg-transfer-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let source = root.join("source.bin");
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &contents).unwrap();
+
+        let sender_peer = PeerId::random();
+        let receiver_peer = PeerId::random();
+        let mut sender = TransferManager::new(root.join("unused"));
+        let mut receiver = TransferManager::new(root.join("downloads"));
+
+        let FileRequest::Offer {
+            transfer_id,
+            name,
+            size,
+            sha256,
+        } = sender.offer(receiver_peer, &source).unwrap()
+        else {
+            panic!("Expected an offer");
+        };
+        assert_eq!(sha256, hash_file(&source).unwrap());
+        receiver
+            .receive_offer(sender_peer, transfer_id, &name, size, sha256)
+            .unwrap();
+
+        // Receive the first chunk, then simulate an interruption.
+        let (_, request) = receiver.accept(&transfer_id).unwrap();
+        let FileRequest::Chunk { offset, length, .. } = request else {
+            panic!("Expected a chunk request");
+        };
+        let FileResponse::Chunk { compression, data } =
+            sender.read_chunk(&receiver_peer, &transfer_id, offset, length, true)
+        else {
+            panic!("Expected a chunk");
+        };
+        let data = decompress_chunk(compression, &data).unwrap();
+        let file = receiver.incoming_mut(&transfer_id).unwrap();
+        file.write_chunk(offset, &data).unwrap();
+        receiver.suspend(&transfer_id);
+
+        // Accepting again resumes after the bytes already on disk.
+        let (_, mut request) = receiver.accept(&transfer_id).unwrap();
+        loop {
+            let FileRequest::Chunk { offset, length, .. } = request else {
+                panic!("Expected a chunk request");
+            };
+            assert!(offset > 0);
+            let FileResponse::Chunk { compression, data } =
+                sender.read_chunk(&receiver_peer, &transfer_id, offset, length, false)
+            else {
+                panic!("Expected a chunk");
+            };
+            let data = decompress_chunk(compression, &data).unwrap();
+            let file = receiver.incoming_mut(&transfer_id).unwrap();
+            file.write_chunk(offset, &data).unwrap();
+            if file.is_complete() {
+                break;
+            }
+            request = file.next_request(transfer_id);
+        }
+
+        let file = receiver.complete(&transfer_id).unwrap();
+        let saved = file.finish().unwrap();
+        assert_eq!(saved, root.join("downloads").join("source.bin"));
+        assert_eq!(fs::read(&saved).unwrap(), contents);
+
+        // Other peers cannot read offered files.
+        assert!(matches!(
+            sender.read_chunk(&PeerId::random(), &transfer_id, 0, 10, true),
+            FileResponse::Error(_)
+        ));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,40 +1,1366 @@
 /*!
  * User interface module for handling user input.
  *
- * This module provides functions to process and handle user input commands.
+ * This module provides functions to process and handle user input commands,
+ * and the terminal UI. The terminal UI runs in its own task: the swarm loop
+ * sends it log lines and status updates over a channel, and it sends back
+ * the lines typed by the user and which messages were shown in the focused
+ * conversation, so read receipts only go out for those. Its sidebar lists the conversations: the
+ * subscribed topics and the peers that sent direct messages, with the
+ * messages and mentions received while they were not focused. Switching
+ * topics from the keyboard sends a `/topic` command. A status bar above
+ * the input line shows the health of the node. When not run interactively,
+ * lines are read from stdin and logs are written to stderr instead.
  */
 
-use crate::protocol::Protocols;
-use libp2p::Swarm;
-use log::{error, info};
+use crate::app::{AppEvent, AppEvents};
+use crate::command::{self, Completions};
+use crate::delivery::MessageId;
+use crate::emoji;
+use crate::hooks::{Direction, HookContext, Verdict};
+use crate::keys::{Binding, Keymap};
+use crate::logfile::RollingFile;
+use crate::markdown::{self, LineKind, MarkdownLine};
+use crate::nat::NatStatus;
+use crate::outgoing::Input;
+use crate::protocol::{is_inbox_topic, BinaryMessage, Payload, Protocols, TextMessage};
+use crate::render::{RenderedMessage, BODY_INDENT};
+use crate::state::AppState;
+use crate::theme::Theme;
+use chrono::Local;
+use crossterm::event::{
+    DisableFocusChange, EnableFocusChange, Event, EventStream, KeyCode, KeyEvent, KeyEventKind,
+    KeyModifiers,
+};
+use crossterm::execute;
+use futures::StreamExt;
+use libp2p::{PeerId, Swarm};
+use log::{error, info, Level, Log, Metadata, Record, SetLoggerError};
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::{Line, Span, Text},
+    widgets::{List, ListItem, Paragraph},
+    Frame,
+};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::{fmt, io};
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-/// Handles user input commands and executes the corresponding actions.
+/// Maximum number of lines kept in the message pane.
+const MAX_PANE_LINES: usize = 1000;
+
+/// Number of lines scrolled by page up and page down.
+const SCROLL_PAGE: usize = 10;
+
+/// Width of the conversation list.
+const CONVERSATION_LIST_WIDTH: u16 = 20;
+
+/// Width of the peer list.
+const PEER_LIST_WIDTH: u16 = 28;
+
+/// Maximum number of input lines shown while composing.
+const MAX_INPUT_ROWS: usize = 8;
+
+/// Maximum number of completion candidates listed at once.
+const MAX_LISTED_COMPLETIONS: usize = 30;
+
+/// How the user interacts with the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    /// Full screen terminal UI.
+    Tui,
+    /// Lines read from stdin, logs written to stderr.
+    Plain,
+}
+
+impl FromStr for Interface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tui" => Ok(Interface::Tui),
+            "plain" => Ok(Interface::Plain),
+            other => Err(format!("unknown interface {:?}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interface::Tui => write!(f, "tui"),
+            Interface::Plain => write!(f, "plain"),
+        }
+    }
+}
+
+/// Events sent from the swarm loop to the terminal UI.
+#[derive(Debug)]
+pub enum UiEvent {
+    /// A log line to show in the message pane.
+    Line(Level, String),
+    /// A chat message to show in the message pane.
+    Message(RenderedMessage),
+    /// The active topic, the subscribed topics, the connected peers, what
+    /// input completes to and the health of the node.
+    Status {
+        topic: Option<String>,
+        topics: Vec<String>,
+        peers: Vec<String>,
+        completions: Completions,
+        health: Health,
+    },
+    /// The theme changed, after the configuration was reloaded.
+    Theme(Theme),
+    /// The swarm loop shut down.
+    Quit,
+}
+
+/// Health of the node shown in the status bar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// End of the local peer ID.
+    pub peer_id: String,
+    pub listen_addrs: Vec<String>,
+    pub nat: NatStatus,
+    /// The number of messages waiting for peers.
+    pub queued: usize,
+    /// The number of messages typed waiting to be published.
+    pub sending: usize,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            peer_id: String::new(),
+            listen_addrs: Vec::new(),
+            nat: NatStatus::Unknown,
+            queued: 0,
+            sending: 0,
+        }
+    }
+}
+
+/// Logger forwarding log records to the terminal UI, or writing them to
+/// stderr when it does not run. Its filter can be replaced while running.
+pub struct AppLogger {
+    filter: RwLock<env_logger::Logger>,
+    events: Option<UnboundedSender<UiEvent>>,
+    /// The log file, if enabled.
+    file: Option<Mutex<RollingFile>>,
+}
+
+impl AppLogger {
+    /// Installs an `AppLogger` as the global logger.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The log filter, such as `info` or `sec_msg=debug`.
+    /// * `events` - The channel to the terminal UI, if it runs.
+    /// * `file` - The file records are also written to, if enabled.
+    ///
+    /// # Returns
+    ///
+    /// The installed logger, whose filter may be replaced later.
+    pub fn init(
+        level: &str,
+        events: Option<UnboundedSender<UiEvent>>,
+        file: Option<RollingFile>,
+    ) -> Result<&'static AppLogger, SetLoggerError> {
+        let filter = log_filter(level);
+        log::set_max_level(filter.filter());
+        let logger = Box::leak(Box::new(AppLogger {
+            filter: RwLock::new(filter),
+            events,
+            file: file.map(Mutex::new),
+        }));
+        log::set_logger(logger)?;
+        Ok(logger)
+    }
+
+    /// Replaces the log filter.
+    pub fn set_level(&self, level: &str) {
+        let filter = log_filter(level);
+        log::set_max_level(filter.filter());
+        *self.filter.write().unwrap_or_else(PoisonError::into_inner) = filter;
+    }
+}
+
+/// Builds the logger deciding which records are shown.
+fn log_filter(level: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(level).build()
+}
+
+impl Log for AppLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let filter = self.filter.read().unwrap_or_else(PoisonError::into_inner);
+        filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let filter = self.filter.read().unwrap_or_else(PoisonError::into_inner);
+        if !filter.matches(record) {
+            return;
+        }
+        if let Some(file) = &self.file {
+            let line = format!(
+                "{} {:<5} {}: {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            // Failing to write is not logged, which would fail again.
+            let _ = file
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write_line(&line);
+        }
+        match &self.events {
+            // The UI may already be gone while shutting down.
+            Some(events) => {
+                let _ = events.send(UiEvent::Line(record.level(), record.args().to_string()));
+            }
+            None => filter.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        if self.events.is_none() {
+            self.filter
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .flush();
+        }
+    }
+}
+
+/// Builds the status shown by the terminal UI.
 ///
 /// # Arguments
 ///
-/// * `line` - The user input line.
 /// * `swarm` - The libp2p swarm.
-/// * `topic` - The topic to publish messages to.
-pub async fn handle_user_input(line: String, swarm: &mut Swarm<Protocols>, topic: &str) {
-    if line.starts_with("/connect") {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() == 2 {
-            match parts[1].parse::<libp2p::Multiaddr>() {
-                Ok(addr) => {
-                    info!("Dialing {:?}", addr);
-                    swarm.dial(addr).unwrap_or_else(|e| {
-                        error!("Failed to dial address: {:?} on topic {:?}", e, topic)
-                    });
-                }
-                Err(_) => error!("Invalid multiaddress"),
+/// * `state` - The application state.
+pub fn status(swarm: &Swarm<Protocols>, state: &AppState) -> UiEvent {
+    let short_id = |peer_id: &PeerId| {
+        let id = peer_id.to_base58();
+        id[id.len().saturating_sub(8)..].to_string()
+    };
+    let mut peers: Vec<String> = swarm
+        .connected_peers()
+        .map(|peer_id| {
+            let short = short_id(peer_id);
+            let name = state.profiles.name(peer_id);
+            match (state.presence.get(peer_id), name) {
+                (Some(presence), _) => format!(
+                    "{} {} ({})",
+                    name.or(presence.name.as_deref()).unwrap_or(&short),
+                    presence.status,
+                    short
+                ),
+                (None, Some(name)) => format!("{} ({})", name, short),
+                (None, None) => short.to_string(),
+            }
+        })
+        .collect();
+    peers.sort();
+    UiEvent::Status {
+        topic: state.topics.active().map(str::to_string),
+        topics: state
+            .topics
+            .subscribed()
+            .map(|(topic, _)| topic.to_string())
+            .collect(),
+        peers,
+        completions: Completions::new(state),
+        health: Health {
+            peer_id: short_id(swarm.local_peer_id()),
+            listen_addrs: swarm.listeners().map(ToString::to_string).collect(),
+            nat: state.nat.status(),
+            queued: state.outbound.held(),
+            sending: state.outgoing.len(),
+        },
+    }
+}
+
+/// Forwards the lines read from stdin until it is closed, then tells the
+/// swarm loop. Stdin is not read while the swarm loop has no room for
+/// more lines.
+///
+/// # Arguments
+///
+/// * `input` - The channel to the swarm loop.
+/// * `lines` - The channel to the swarm loop for the lines read.
+pub async fn read_stdin(input: AppEvents, lines: Input) {
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        match stdin.next_line().await {
+            Ok(Some(line)) => {
+                if lines.send(line).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {
+                info!("stdin closed");
+                break;
             }
+            Err(e) => {
+                error!("Error reading stdin: {:?}", e);
+                break;
+            }
+        }
+    }
+    let _ = input.send(AppEvent::InputClosed);
+}
+
+/// What a key press asks the terminal UI to do.
+#[derive(Debug, PartialEq, Eq)]
+enum KeyAction {
+    /// Send the typed line.
+    Submit(String),
+    Quit,
+}
+
+/// A line of the message pane.
+enum PaneLine {
+    Log(Level, String),
+    Message(RenderedMessage),
+}
+
+impl PaneLine {
+    /// Draws the line, chat messages with their sender in its color and
+    /// their Markdown formatting unless `raw`.
+    fn to_item(&self, theme: &Theme, raw: bool) -> ListItem<'_> {
+        match self {
+            PaneLine::Log(level, line) => ListItem::new(line.as_str()).style(theme.log(*level)),
+            PaneLine::Message(message) => {
+                let mut header = vec![
+                    Span::styled(message.time.as_str(), theme.timestamp),
+                    Span::styled(format!(" [{}] ", message.topic), theme.text),
+                    Span::styled(format!("#{} ", message.id.short()), theme.dim),
+                    Span::styled(message.sender.as_str(), theme.sender(message.color)),
+                    Span::styled(message.flag_marker(), theme.warning),
+                    Span::styled(": ", theme.text),
+                ];
+                let base = if message.highlight {
+                    theme.highlight
+                } else {
+                    theme.text
+                };
+                let body: Vec<(bool, Vec<Span>)> = if raw {
+                    message
+                        .body
+                        .lines()
+                        .map(|line| (true, vec![Span::styled(line, base)]))
+                        .collect()
+                } else {
+                    markdown::parse(&message.body)
+                        .into_iter()
+                        .map(|line| markdown_line(line, base, theme))
+                        .collect()
+                };
+                let mut lines = Vec::new();
+                for (index, (text, spans)) in body.into_iter().enumerate() {
+                    // Text starts after the sender, blocks below it.
+                    if index == 0 && text {
+                        header.extend(spans);
+                        lines.push(Line::from(std::mem::take(&mut header)));
+                        continue;
+                    }
+                    if index == 0 {
+                        lines.push(Line::from(std::mem::take(&mut header)));
+                    }
+                    let mut indented = vec![Span::styled(BODY_INDENT, theme.text)];
+                    indented.extend(spans);
+                    lines.push(Line::from(indented));
+                }
+                if lines.is_empty() {
+                    lines.push(Line::from(header));
+                }
+                ListItem::new(Text::from(lines))
+            }
+        }
+    }
+}
+
+/// Returns the spans of a Markdown line, and whether it is plain text.
+fn markdown_line(line: MarkdownLine, base: Style, theme: &Theme) -> (bool, Vec<Span<'static>>) {
+    let mut spans = match &line.kind {
+        LineKind::Text => Vec::new(),
+        LineKind::Code => vec![Span::styled("│ ", theme.dim)],
+        LineKind::Item(marker) => vec![Span::styled(format!("{} ", marker), base)],
+    };
+    spans.extend(line.spans.into_iter().map(|span| {
+        let mut style = base;
+        if span.emphasis.bold {
+            style = style.add_modifier(Modifier::BOLD);
+        }
+        if span.emphasis.italic {
+            style = style.add_modifier(Modifier::ITALIC);
+        }
+        if span.emphasis.code {
+            style = style.patch(theme.code);
+        }
+        Span::styled(span.text, style)
+    }));
+    (line.kind == LineKind::Text, spans)
+}
+
+/// A conversation listed in the sidebar.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Conversation {
+    Topic(String),
+    /// Direct messages from a peer.
+    Direct(PeerId),
+}
+
+/// Messages received in a conversation while it was not focused.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Unread {
+    messages: usize,
+    /// Messages mentioning the local display name or a watched keyword.
+    mentions: usize,
+}
+
+/// State of the terminal UI.
+struct Tui {
+    lines: VecDeque<PaneLine>,
+    input: String,
+    /// Number of lines scrolled back from the latest one.
+    scroll: usize,
+    topic: Option<String>,
+    topics: Vec<String>,
+    /// Peers that sent direct messages, with how they are shown.
+    directs: Vec<(PeerId, String)>,
+    /// The conversation shown in the message pane.
+    focus: Option<Conversation>,
+    unread: HashMap<Conversation, Unread>,
+    /// Ids of the messages received in a conversation while it was not
+    /// focused, read once it is.
+    unseen: HashMap<Conversation, VecDeque<MessageId>>,
+    /// Ids of the messages shown in the focused conversation, for the
+    /// swarm loop to send read receipts for.
+    read: Vec<MessageId>,
+    peers: Vec<String>,
+    completions: Completions,
+    health: Health,
+    /// Lines typed the swarm loop has no room for yet.
+    unsent: VecDeque<String>,
+    keymap: Keymap,
+    theme: Theme,
+    /// Whether messages are shown without their Markdown formatting.
+    raw: bool,
+}
+
+impl Tui {
+    fn new(keymap: Keymap, theme: Theme) -> Self {
+        Tui {
+            lines: VecDeque::new(),
+            input: String::new(),
+            scroll: 0,
+            topic: None,
+            topics: Vec::new(),
+            directs: Vec::new(),
+            focus: None,
+            unread: HashMap::new(),
+            unseen: HashMap::new(),
+            read: Vec::new(),
+            peers: Vec::new(),
+            completions: Completions::default(),
+            health: Health::default(),
+            unsent: VecDeque::new(),
+            keymap,
+            theme,
+            raw: false,
+        }
+    }
+
+    /// Applies an event sent by the swarm loop.
+    fn apply(&mut self, event: UiEvent) {
+        match event {
+            UiEvent::Line(level, line) => self.push(PaneLine::Log(level, line)),
+            UiEvent::Message(message) => {
+                let conversation = self.conversation(&message);
+                if let Some(Conversation::Direct(peer)) = &conversation {
+                    if !self.directs.iter().any(|(direct, _)| direct == peer) {
+                        self.directs.push((*peer, message.sender.clone()));
+                    }
+                }
+                match conversation.filter(|c| self.focus.as_ref() != Some(c)) {
+                    Some(conversation) => {
+                        let unseen = self.unseen.entry(conversation.clone()).or_default();
+                        if unseen.len() == MAX_PANE_LINES {
+                            unseen.pop_front();
+                        }
+                        unseen.push_back(message.id);
+                        let unread = self.unread.entry(conversation).or_default();
+                        unread.messages += 1;
+                        if message.highlight {
+                            unread.mentions += 1;
+                        }
+                    }
+                    None => self.read.push(message.id),
+                }
+                self.push(PaneLine::Message(message));
+            }
+            UiEvent::Status {
+                topic,
+                topics,
+                peers,
+                completions,
+                health,
+            } => {
+                // Follow the active topic when it changes, such as on /topic.
+                if topic != self.topic {
+                    self.focus = topic.clone().map(Conversation::Topic);
+                    self.scroll = 0;
+                }
+                if let Some(focus) = self.focus.clone() {
+                    self.mark_read(&focus);
+                }
+                let listed = |conversation: &Conversation| match conversation {
+                    Conversation::Topic(topic) => topics.contains(topic),
+                    Conversation::Direct(_) => true,
+                };
+                self.unread.retain(|conversation, _| listed(conversation));
+                self.unseen.retain(|conversation, _| listed(conversation));
+                self.topic = topic;
+                self.topics = topics;
+                self.peers = peers;
+                self.completions = completions;
+                self.health = health;
+            }
+            UiEvent::Theme(theme) => self.theme = theme,
+            // Handled by `run_tui`.
+            UiEvent::Quit => {}
+        }
+    }
+
+    /// Marks the messages received in a conversation while it was not
+    /// focused as read, now that it is.
+    fn mark_read(&mut self, conversation: &Conversation) {
+        self.unread.remove(conversation);
+        if let Some(unseen) = self.unseen.remove(conversation) {
+            self.read.extend(unseen);
+        }
+    }
+
+    /// Appends a line to the message pane.
+    fn push(&mut self, line: PaneLine) {
+        if self.lines.len() == MAX_PANE_LINES {
+            self.lines.pop_front();
+        }
+        let shown = self.shows(&line);
+        self.lines.push_back(line);
+        // Keep the view still while scrolled back.
+        if self.scroll > 0 && shown {
+            self.scroll = (self.scroll + 1).min(self.visible().len());
+        }
+    }
+
+    /// Returns the conversation a message belongs to, if it is listed.
+    fn conversation(&self, message: &RenderedMessage) -> Option<Conversation> {
+        if is_inbox_topic(&message.topic) {
+            Some(Conversation::Direct(message.source))
+        } else if self.topics.contains(&message.topic) {
+            Some(Conversation::Topic(message.topic.clone()))
         } else {
-            error!("Usage: /connect <multiaddress>");
+            None
         }
-    } else {
-        info!("Publishing message: {:?}", line);
-        if let Err(e) = swarm.behaviour_mut().publish(topic, line.as_bytes()) {
-            error!("Failed to publish message: {:?} on {:?}", e, topic);
+    }
+
+    /// Returns the listed conversations, topics first.
+    fn conversations(&self) -> Vec<Conversation> {
+        self.topics
+            .iter()
+            .cloned()
+            .map(Conversation::Topic)
+            .chain(
+                self.directs
+                    .iter()
+                    .map(|(peer, _)| Conversation::Direct(*peer)),
+            )
+            .collect()
+    }
+
+    /// Returns how a conversation is shown.
+    fn label(&self, conversation: &Conversation) -> String {
+        match conversation {
+            Conversation::Topic(topic) => topic.clone(),
+            Conversation::Direct(peer) => {
+                let name = self
+                    .directs
+                    .iter()
+                    .find(|(direct, _)| direct == peer)
+                    .map_or(String::new(), |(_, name)| name.clone());
+                format!("@{}", name)
+            }
+        }
+    }
+
+    /// Returns whether a line belongs to the focused conversation: logs and
+    /// messages outside the listed conversations are shown whatever the
+    /// focus.
+    fn shows(&self, line: &PaneLine) -> bool {
+        match line {
+            PaneLine::Log(..) => true,
+            PaneLine::Message(message) => match self.conversation(message) {
+                Some(conversation) => self.focus.as_ref() == Some(&conversation),
+                None => true,
+            },
+        }
+    }
+
+    /// Returns the lines of the focused conversation, oldest first.
+    fn visible(&self) -> Vec<&PaneLine> {
+        self.lines.iter().filter(|line| self.shows(line)).collect()
+    }
+
+    /// Focuses the listed conversation at an index.
+    ///
+    /// Focusing a topic makes it the active one, which the swarm loop is
+    /// told with a `/topic` command; the switch is shown right away rather
+    /// than on its next status update.
+    fn switch_to(&mut self, index: usize) -> Option<KeyAction> {
+        let conversation = self.conversations().get(index)?.clone();
+        if self.focus.as_ref() == Some(&conversation) {
+            return None;
+        }
+        self.mark_read(&conversation);
+        self.scroll = 0;
+        self.focus = Some(conversation.clone());
+        match conversation {
+            Conversation::Topic(topic) => {
+                self.topic = Some(topic.clone());
+                Some(KeyAction::Submit(format!("/topic {}", topic)))
+            }
+            Conversation::Direct(_) => None,
         }
     }
+
+    /// Focuses the conversation `offset` places after the focused one,
+    /// wrapping around the sidebar.
+    fn cycle(&mut self, offset: isize) -> Option<KeyAction> {
+        let conversations = self.conversations();
+        if conversations.is_empty() {
+            return None;
+        }
+        let current = conversations
+            .iter()
+            .position(|conversation| self.focus.as_ref() == Some(conversation))
+            .unwrap_or(0);
+        let index = (current as isize + offset).rem_euclid(conversations.len() as isize);
+        self.switch_to(index as usize)
+    }
+
+    /// Edits the input line, scrolls the message pane or switches topics.
+    fn handle_key(&mut self, key: KeyEvent) -> Option<KeyAction> {
+        if let Some(binding) = self.keymap.binding(&key) {
+            return self.run_binding(binding);
+        }
+        match key.code {
+            KeyCode::Char(c @ '1'..='9') if key.modifiers.contains(KeyModifiers::ALT) => {
+                return self.switch_to(usize::from(c as u8 - b'1'))
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Does what a bound key does.
+    fn run_binding(&mut self, binding: Binding) -> Option<KeyAction> {
+        match binding {
+            Binding::Quit => return Some(KeyAction::Quit),
+            Binding::Next => return self.cycle(1),
+            Binding::Previous => return self.cycle(-1),
+            Binding::Clear => self.input.clear(),
+            Binding::Complete => self.complete(),
+            Binding::Send if !self.input.is_empty() => {
+                self.scroll = 0;
+                let line = std::mem::take(&mut self.input);
+                // Plain input in a direct conversation answers the peer.
+                return Some(KeyAction::Submit(match &self.focus {
+                    Some(Conversation::Direct(peer)) if !line.starts_with('/') => {
+                        format!("/msg {} {}", peer, line)
+                    }
+                    _ => line,
+                }));
+            }
+            Binding::Send => {}
+            Binding::Newline => self.input.push('\n'),
+            Binding::ScrollUp => {
+                self.scroll = (self.scroll + SCROLL_PAGE).min(self.visible().len());
+            }
+            Binding::ScrollDown => self.scroll = self.scroll.saturating_sub(SCROLL_PAGE),
+            Binding::ScrollTop => self.scroll = self.visible().len(),
+            Binding::ScrollBottom => self.scroll = 0,
+            Binding::Raw => self.raw = !self.raw,
+            Binding::Search => {
+                if !self.input.starts_with("/search ") {
+                    self.input = "/search ".to_string();
+                }
+            }
+        }
+        None
+    }
+
+    /// Completes the last word of the input line, listing the candidates
+    /// when they share no longer prefix.
+    fn complete(&mut self) {
+        let (start, candidates) = self.completions.complete(&self.input);
+        match candidates.as_slice() {
+            [] => {}
+            [candidate] => {
+                self.input.truncate(start);
+                self.input.push_str(candidate);
+                self.input.push(' ');
+            }
+            [first, rest @ ..] => {
+                let prefix = rest.iter().fold(first.as_str(), |prefix, candidate| {
+                    let len = prefix
+                        .char_indices()
+                        .zip(candidate.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(prefix.len().min(candidate.len()), |((index, _), _)| index);
+                    &prefix[..len]
+                });
+                if prefix.len() > self.input.len() - start {
+                    self.input.truncate(start);
+                    self.input.push_str(prefix);
+                } else if candidates.len() > MAX_LISTED_COMPLETIONS {
+                    let listed = candidates[..MAX_LISTED_COMPLETIONS].join("  ");
+                    let more = candidates.len() - MAX_LISTED_COMPLETIONS;
+                    self.apply(UiEvent::Line(
+                        Level::Info,
+                        format!("{}  and {} more", listed, more),
+                    ));
+                } else {
+                    self.apply(UiEvent::Line(Level::Info, candidates.join("  ")));
+                }
+            }
+        }
+    }
+
+    /// Draws the conversation list, the message pane, the peer list, the
+    /// status bar and the input line.
+    fn draw(&self, frame: &mut Frame) {
+        let input_lines: Vec<&str> = self.input.split('\n').collect();
+        let input_rows = input_lines.len().min(MAX_INPUT_ROWS);
+        let [main, status_area, input_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(input_rows as u16 + 2),
+        ])
+        .areas(frame.area());
+        let [conversations_area, messages_area, peers_area] = Layout::horizontal([
+            Constraint::Length(CONVERSATION_LIST_WIDTH),
+            Constraint::Min(10),
+            Constraint::Length(PEER_LIST_WIDTH),
+        ])
+        .areas(main);
+
+        let conversations: Vec<ListItem> = self
+            .conversations()
+            .iter()
+            .enumerate()
+            .map(|(index, conversation)| {
+                let mut spans = vec![Span::styled(
+                    format!("{} {}", index + 1, self.label(conversation)),
+                    self.theme.text,
+                )];
+                if let Some(unread) = self.unread.get(conversation) {
+                    spans.push(Span::styled(
+                        format!(" ({})", unread.messages),
+                        self.theme.badge,
+                    ));
+                    if unread.mentions > 0 {
+                        spans.push(Span::styled(
+                            format!(" @{}", unread.mentions),
+                            self.theme.mention_badge,
+                        ));
+                    }
+                }
+                let item = ListItem::new(Line::from(spans));
+                if self.focus.as_ref() == Some(conversation) {
+                    item.style(self.theme.selected)
+                } else {
+                    item
+                }
+            })
+            .collect();
+        frame.render_widget(
+            List::new(conversations).block(self.theme.block(" Conversations ")),
+            conversations_area,
+        );
+
+        let visible = self.visible();
+        let height = usize::from(messages_area.height.saturating_sub(2));
+        let end = visible.len() - self.scroll.min(visible.len());
+        // Fill the pane from the latest line up, messages taking several
+        // rows.
+        let mut messages = Vec::new();
+        let mut rows = 0;
+        for line in visible[..end].iter().rev() {
+            let item = line.to_item(&self.theme, self.raw);
+            rows += item.height();
+            if rows > height && !messages.is_empty() {
+                break;
+            }
+            messages.push(item);
+        }
+        messages.reverse();
+        let name = self
+            .focus
+            .as_ref()
+            .map_or("Messages".to_string(), |focus| self.label(focus));
+        let raw = if self.raw { " raw" } else { "" };
+        let title = if self.scroll > 0 {
+            format!(" {}{} (-{}) ", name, raw, self.scroll)
+        } else {
+            format!(" {}{} ", name, raw)
+        };
+        frame.render_widget(
+            List::new(messages).block(self.theme.block(title)),
+            messages_area,
+        );
+
+        let peers: Vec<ListItem> = self
+            .peers
+            .iter()
+            .map(|peer| ListItem::new(peer.as_str()).style(self.theme.text))
+            .collect();
+        frame.render_widget(
+            List::new(peers).block(self.theme.block(format!(" Peers ({}) ", self.peers.len()))),
+            peers_area,
+        );
+
+        let listening = if self.health.listen_addrs.is_empty() {
+            "not listening".to_string()
+        } else {
+            self.health.listen_addrs.join(", ")
+        };
+        let unread = self
+            .unread
+            .values()
+            .fold(Unread::default(), |total, unread| Unread {
+                messages: total.messages + unread.messages,
+                mentions: total.mentions + unread.mentions,
+            });
+        let unread = match unread {
+            Unread { messages: 0, .. } => String::new(),
+            Unread {
+                messages,
+                mentions: 0,
+            } => format!(" | {} unread", messages),
+            Unread { messages, mentions } => {
+                format!(" | {} unread, {} mentions", messages, mentions)
+            }
+        };
+        let queued = match self.health.queued {
+            0 => String::new(),
+            queued => format!(" | {} queued", queued),
+        };
+        let sending = match self.health.sending + self.unsent.len() {
+            0 => String::new(),
+            sending => format!(" | sending… {}", sending),
+        };
+        let status = format!(
+            " {} | {} peers | NAT {} | {}{}{}{} | {}",
+            self.health.peer_id,
+            self.peers.len(),
+            self.health.nat,
+            self.topic.as_deref().unwrap_or("no topic"),
+            unread,
+            sending,
+            queued,
+            listening
+        );
+        frame.render_widget(
+            Paragraph::new(status).style(self.theme.status_bar),
+            status_area,
+        );
+
+        let title = match (&self.focus, &self.topic) {
+            (Some(direct @ Conversation::Direct(_)), _) => format!(" To {} ", self.label(direct)),
+            (_, Some(topic)) => format!(" [{}] ", topic),
+            (_, None) => " No topic, use /join <topic> ".to_string(),
+        };
+        // Keep the last lines and the end of long lines in view, emoji
+        // taking two columns.
+        let width = usize::from(input_area.width.saturating_sub(3));
+        let shown: Vec<&str> = input_lines[input_lines.len() - input_rows..]
+            .iter()
+            .map(|line| tail(line, width))
+            .collect();
+        let cursor = u16::try_from(shown.last().map_or(0, |line| line.width())).unwrap_or(u16::MAX);
+        frame.render_widget(
+            Paragraph::new(shown.into_iter().map(Line::raw).collect::<Vec<_>>())
+                .style(self.theme.text)
+                .block(self.theme.block(title)),
+            input_area,
+        );
+        frame.set_cursor_position((input_area.x + 1 + cursor, input_area.y + input_rows as u16));
+    }
+}
+
+/// Returns the longest end of a text that fits in `width` columns.
+fn tail(text: &str, width: usize) -> &str {
+    let mut columns = 0;
+    for (index, c) in text.char_indices().rev() {
+        columns += c.width().unwrap_or(0);
+        if columns > width {
+            return &text[index + c.len_utf8()..];
+        }
+    }
+    text
+}
+
+/// Runs the terminal UI until the user quits.
+///
+/// # Arguments
+///
+/// * `events` - The events sent by the swarm loop.
+/// * `input` - The channel to the swarm loop.
+/// * `lines` - The channel to the swarm loop for the typed lines.
+/// * `focused` - The flag updated when the terminal gains or loses focus.
+/// * `keymap` - What the keys do.
+/// * `theme` - The styles to draw with.
+pub async fn run_tui(
+    mut events: UnboundedReceiver<UiEvent>,
+    input: AppEvents,
+    lines: Input,
+    focused: Arc<AtomicBool>,
+    keymap: Keymap,
+    theme: Theme,
+) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    // Focus changes tell when to raise desktop notifications.
+    if let Err(e) = execute!(io::stdout(), EnableFocusChange) {
+        ratatui::restore();
+        return Err(e);
+    }
+    let mut keys = EventStream::new();
+    let mut tui = Tui::new(keymap, theme);
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| tui.draw(frame)) {
+            break Err(e);
+        }
+        for id in tui.read.drain(..) {
+            let _ = input.send(AppEvent::Read(id));
+        }
+        tokio::select! {
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    match tui.handle_key(key) {
+                        Some(KeyAction::Submit(line)) => tui.unsent.push_back(line),
+                        Some(KeyAction::Quit) => break Ok(()),
+                        None => {}
+                    }
+                }
+                Some(Ok(Event::FocusGained)) => focused.store(true, Ordering::Relaxed),
+                Some(Ok(Event::FocusLost)) => focused.store(false, Ordering::Relaxed),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            },
+            // Lines are handed over as the swarm loop makes room for them.
+            permit = lines.reserve(), if !tui.unsent.is_empty() => match permit {
+                Ok(permit) => {
+                    if let Some(line) = tui.unsent.pop_front() {
+                        permit.send(line);
+                    }
+                }
+                // The swarm loop is gone.
+                Err(_) => break Ok(()),
+            },
+            event = events.recv() => match event {
+                Some(UiEvent::Quit) | None => break Ok(()),
+                Some(event) => {
+                    tui.apply(event);
+                    // Draw once for a burst of events.
+                    let mut quit = false;
+                    while let Ok(event) = events.try_recv() {
+                        if matches!(event, UiEvent::Quit) {
+                            quit = true;
+                            break;
+                        }
+                        tui.apply(event);
+                    }
+                    if quit {
+                        break Ok(());
+                    }
+                }
+            },
+        }
+    };
+    let _ = execute!(io::stdout(), DisableFocusChange);
+    ratatui::restore();
+    let _ = input.send(AppEvent::InputClosed);
+    result
+}
+
+/// Handles a line typed by the user, running it if it is a command and
+/// queueing it for the active topic otherwise.
+///
+/// # Arguments
+///
+/// * `line` - The user input line.
+/// * `swarm` - The libp2p swarm.
+/// * `state` - The application state.
+pub async fn handle_user_input(line: String, swarm: &mut Swarm<Protocols>, state: &mut AppState) {
+    if line.starts_with('/') {
+        if let Err(e) = command::run(&line, swarm, state) {
+            error!("{}", e);
+        }
+        return;
+    }
+    let Some(topic) = state.topics.active() else {
+        error!("Not subscribed to any topic, use /join <topic>");
+        return;
+    };
+    let topic = topic.to_string();
+    state.outgoing.push(&topic, emoji::expand(&line));
+}
+
+/// Publishes a line as a text message.
+///
+/// # Arguments
+///
+/// * `line` - The message text.
+/// * `topic` - The topic to publish to.
+/// * `state` - The application state.
+///
+/// # Returns
+///
+/// `true` if the message was queued for publishing.
+pub fn publish_text(line: String, topic: &str, state: &mut AppState) -> bool {
+    info!("Publishing message: {:?}", line);
+    let id = MessageId::random();
+    let payload = Payload::Text(TextMessage {
+        id,
+        body: line,
+        ack_requested: false,
+    });
+    if !publish_payload(state, topic, &payload) {
+        return false;
+    }
+    state.reactions.record(id, topic);
+    true
+}
+
+/// Publishes bytes that are not text.
+///
+/// # Arguments
+///
+/// * `binary` - The message.
+/// * `topic` - The topic to publish to.
+/// * `state` - The application state.
+///
+/// # Returns
+///
+/// `true` if the message was queued for publishing.
+pub fn publish_binary(binary: BinaryMessage, topic: &str, state: &mut AppState) -> bool {
+    info!(
+        "Publishing {} bytes of {}",
+        binary.data.len(),
+        binary.media_type
+    );
+    publish_payload(state, topic, &Payload::Binary(binary))
+}
+
+/// Publishes a payload, logging any failure.
+///
+/// # Arguments
+///
+/// * `state` - The application state.
+/// * `topic` - The topic to publish to.
+/// * `payload` - The payload to publish.
+///
+/// # Returns
+///
+/// `true` if the payload was queued for publishing.
+pub fn publish_payload(state: &mut AppState, topic: &str, payload: &Payload) -> bool {
+    let mut payload = payload.clone();
+    let context = HookContext {
+        direction: Direction::Outbound,
+        topic,
+        peer: state.local_key.public().to_peer_id(),
+        sender: state.display_name.as_deref(),
+    };
+    if matches!(
+        state.hooks.run(&context, &mut payload),
+        Verdict::Drop(_) | Verdict::Quarantine(_) | Verdict::Pending
+    ) {
+        return false;
+    }
+    let result = state.outbound.publish_payload(
+        topic,
+        state.topics.protocol(topic),
+        &payload,
+        state.display_name.clone(),
+        &state.local_key,
+        &mut state.middleware,
+    );
+    match result {
+        Ok(data) => {
+            // Gossipsub does not deliver our own messages back to us.
+            state.history.record(topic, &data);
+            true
+        }
+        Err(e) => {
+            error!("Failed to publish message on {:?}: {}", topic, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use log::Level;
+
+    use libp2p::PeerId;
+
+    use super::{
+        tail, Conversation, Health, Interface, KeyAction, PaneLine, Tui, UiEvent, Unread,
+        SCROLL_PAGE,
+    };
+    use crate::command::Completions;
+    use crate::delivery::MessageId;
+    use crate::keys::Keymap;
+    use crate::render::RenderedMessage;
+    use crate::theme::{Theme, ThemeName};
+
+    #[test]
+    fn test_interface_from_str() {
+        assert_eq!("tui".parse(), Ok(Interface::Tui));
+        assert_eq!("plain".parse(), Ok(Interface::Plain));
+        assert!("gui".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn test_tui_input_and_scroll() {
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let mut tui = Tui::new(Keymap::default(), Theme::default());
+        for c in "hi!".chars() {
+            assert_eq!(tui.handle_key(key(KeyCode::Char(c))), None);
+        }
+        tui.handle_key(key(KeyCode::Backspace));
+        assert_eq!(
+            tui.handle_key(key(KeyCode::Enter)),
+            Some(KeyAction::Submit("hi".to_string()))
+        );
+        assert_eq!(tui.handle_key(key(KeyCode::Enter)), None);
+
+        tui.handle_key(key(KeyCode::Char('a')));
+        tui.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT));
+        tui.handle_key(key(KeyCode::Char('b')));
+        assert_eq!(
+            tui.handle_key(key(KeyCode::Enter)),
+            Some(KeyAction::Submit("a\nb".to_string()))
+        );
+
+        for i in 0..2 * SCROLL_PAGE {
+            tui.apply(UiEvent::Line(Level::Info, i.to_string()));
+        }
+        tui.handle_key(key(KeyCode::PageUp));
+        tui.apply(UiEvent::Line(Level::Info, "new".to_string()));
+        assert_eq!(tui.scroll, SCROLL_PAGE + 1);
+        tui.handle_key(key(KeyCode::PageUp));
+        tui.handle_key(key(KeyCode::PageUp));
+        assert_eq!(tui.scroll, tui.lines.len());
+
+        tui.apply(UiEvent::Status {
+            topic: None,
+            topics: Vec::new(),
+            peers: Vec::new(),
+            health: Health::default(),
+            completions: Completions {
+                peers: Vec::new(),
+                topics: vec!["rust".to_string(), "rustaceans".to_string()],
+            },
+        });
+        for c in "/jo".chars() {
+            tui.handle_key(key(KeyCode::Char(c)));
+        }
+        tui.handle_key(key(KeyCode::Tab));
+        tui.handle_key(key(KeyCode::Char('r')));
+        tui.handle_key(key(KeyCode::Tab));
+        assert_eq!(tui.input, "/join rust");
+
+        let search = KeyEvent::new(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        tui.handle_key(search);
+        assert_eq!(tui.input, "/search ");
+
+        let quit = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(tui.handle_key(quit), Some(KeyAction::Quit));
+        tui.keymap = "quit=ctrl-q".parse().unwrap();
+        assert_eq!(tui.handle_key(quit), None);
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail("hello", 10), "hello");
+        assert_eq!(tail("hello", 3), "llo");
+        assert_eq!(tail("ab🎉", 3), "b🎉");
+        assert_eq!(tail("ab🎉", 2), "🎉");
+        assert_eq!(tail("ab🎉", 1), "");
+    }
+
+    #[test]
+    fn test_message_rows() {
+        let message = |body: &str| {
+            PaneLine::Message(RenderedMessage {
+                time: "12:00".to_string(),
+                timestamp: 0,
+                topic: "chat".to_string(),
+                id: MessageId::random(),
+                source: PeerId::random(),
+                sender: "alice".to_string(),
+                color: 33,
+                body: body.to_string(),
+                highlight: false,
+                flag: None,
+            })
+        };
+        assert_eq!(
+            message("**hi**").to_item(&Theme::default(), false).height(),
+            1
+        );
+        let list = message("todo:\n- one\n```\ncode\n```");
+        assert_eq!(list.to_item(&Theme::default(), false).height(), 3);
+        assert_eq!(list.to_item(&Theme::default(), true).height(), 5);
+        // Blocks start below the sender.
+        assert_eq!(
+            message("- one").to_item(&Theme::default(), false).height(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_tui_conversations() {
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let alt = |code| KeyEvent::new(code, KeyModifiers::ALT);
+        let alice = PeerId::random();
+        let message = |topic: &str, body: &str| {
+            UiEvent::Message(RenderedMessage {
+                time: "12:00".to_string(),
+                timestamp: 0,
+                topic: topic.to_string(),
+                id: MessageId::random(),
+                source: alice,
+                sender: "alice".to_string(),
+                color: 33,
+                body: body.to_string(),
+                highlight: body.contains("@bob"),
+                flag: None,
+            })
+        };
+        let mut tui = Tui::new(Keymap::default(), Theme::default());
+        tui.apply(UiEvent::Status {
+            topic: Some("chat".to_string()),
+            topics: vec!["chat".to_string(), "rust".to_string()],
+            peers: Vec::new(),
+            completions: Completions::default(),
+            health: Health::default(),
+        });
+        tui.apply(message("chat", "hi"));
+        tui.apply(message("rust", "hi"));
+        tui.apply(message("rust", "hi @bob"));
+        tui.apply(message("dm/me", "psst"));
+        tui.apply(message("elsewhere", "hi"));
+        let rust = Conversation::Topic("rust".to_string());
+        let direct = Conversation::Direct(alice);
+        assert_eq!(
+            tui.unread.get(&rust),
+            Some(&Unread {
+                messages: 2,
+                mentions: 1
+            })
+        );
+        assert_eq!(
+            tui.unread.get(&direct).map(|unread| unread.messages),
+            Some(1)
+        );
+        assert_eq!(tui.visible().len(), 2);
+
+        assert_eq!(
+            tui.handle_key(alt(KeyCode::Right)),
+            Some(KeyAction::Submit("/topic rust".to_string()))
+        );
+        assert!(!tui.unread.contains_key(&rust));
+        assert_eq!(tui.visible().len(), 3);
+        assert_eq!(tui.handle_key(alt(KeyCode::Char('2'))), None);
+        assert_eq!(tui.handle_key(alt(KeyCode::Char('4'))), None);
+
+        assert_eq!(tui.handle_key(alt(KeyCode::Char('3'))), None);
+        assert!(tui.unread.is_empty());
+        assert_eq!(tui.visible().len(), 2);
+        tui.handle_key(key(KeyCode::Char('k')));
+        assert_eq!(
+            tui.handle_key(key(KeyCode::Enter)),
+            Some(KeyAction::Submit(format!("/msg {} k", alice)))
+        );
+        assert_eq!(
+            tui.handle_key(alt(KeyCode::Right)),
+            Some(KeyAction::Submit("/topic chat".to_string()))
+        );
+
+        // A reloaded configuration may change the theme.
+        let monochrome = Theme::new(ThemeName::Monochrome);
+        tui.apply(UiEvent::Theme(monochrome.clone()));
+        assert_eq!(tui.theme, monochrome);
+    }
+
+    #[test]
+    fn test_tui_read_messages() {
+        let alt = |code| KeyEvent::new(code, KeyModifiers::ALT);
+        let message = |topic: &str| {
+            let id = MessageId::random();
+            let message = UiEvent::Message(RenderedMessage {
+                time: "12:00".to_string(),
+                timestamp: 0,
+                topic: topic.to_string(),
+                id,
+                source: PeerId::random(),
+                sender: "alice".to_string(),
+                color: 33,
+                body: "hi".to_string(),
+                highlight: false,
+                flag: None,
+            });
+            (id, message)
+        };
+        let mut tui = Tui::new(Keymap::default(), Theme::default());
+        tui.apply(UiEvent::Status {
+            topic: Some("chat".to_string()),
+            topics: vec!["chat".to_string(), "rust".to_string()],
+            peers: Vec::new(),
+            completions: Completions::default(),
+            health: Health::default(),
+        });
+
+        // Only the message of the focused topic is read as it arrives.
+        let (focused, event) = message("chat");
+        tui.apply(event);
+        let (unfocused, event) = message("rust");
+        tui.apply(event);
+        assert_eq!(std::mem::take(&mut tui.read), [focused]);
+
+        // The other one is once its topic is focused.
+        tui.handle_key(alt(KeyCode::Right));
+        assert_eq!(std::mem::take(&mut tui.read), [unfocused]);
+        tui.handle_key(alt(KeyCode::Left));
+        assert!(tui.read.is_empty());
+    }
 }
@@ -2,12 +2,19 @@
  * Utility functions for the messaging application.
  *
  * This module provides utility functions for generating keypairs
- * and peer IDs.
+ * and peer IDs, and for keeping a keypair in a file across runs,
+ * encrypted when storage encryption is on.
  */
 
+use std::{error::Error, fs, io, path::Path};
+
 use libp2p::{identity, PeerId};
 use log::info;
 
+use zeroize::Zeroizing;
+
+use crate::storage::{self, Vault};
+
 /// Generates a new Ed25519 keypair and corresponding peer ID.
 ///
 /// # Returns
@@ -20,15 +27,152 @@ pub fn generate_keypair() -> (identity::Keypair, PeerId) {
     (local_key, local_peer_id)
 }
 
+/// Loads the keypair kept in a file, generating and saving one if the
+/// file does not exist.
+///
+/// # Arguments
+///
+/// * `path` - The file the keypair is kept in.
+/// * `vault` - The vault, if storage encryption is on.
+///
+/// # Returns
+///
+/// A `Result` containing the keypair and peer ID, or an error if the file
+/// cannot be read, decrypted, decoded or written.
+pub fn load_keypair(
+    path: &Path,
+    vault: Option<&Vault>,
+) -> Result<(identity::Keypair, PeerId), Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(data) => {
+            let sealed = storage::is_sealed(&data);
+            let bytes = Zeroizing::new(
+                storage::unseal(data, vault)
+                    .map_err(|e| format!("Failed to read identity file {:?}: {}", path, e))?,
+            );
+            let local_key = identity::Keypair::from_protobuf_encoding(&bytes)
+                .map_err(|e| format!("Invalid identity file {:?}: {}", path, e))?;
+            let local_peer_id = PeerId::from(local_key.public());
+            info!("Loaded key pair with peer id: {:?}", local_peer_id);
+            // Identities kept from before encryption was turned on.
+            if !sealed && vault.is_some() {
+                storage::write(path, &bytes, vault)?;
+                info!("Encrypted identity file {:?}", path);
+            }
+            Ok((local_key, local_peer_id))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let (local_key, local_peer_id) = generate_keypair();
+            save_keypair(&local_key, path, vault)?;
+            info!("Saved key pair to {:?}", path);
+            Ok((local_key, local_peer_id))
+        }
+        Err(e) => Err(format!("Failed to read identity file {:?}: {}", path, e).into()),
+    }
+}
+
+/// Generates a keypair and saves it to a file, for `keygen`.
+///
+/// # Arguments
+///
+/// * `path` - The file the keypair is kept in.
+/// * `force` - Whether an existing keypair is replaced.
+/// * `vault` - The vault, if storage encryption is on.
+///
+/// # Returns
+///
+/// A `Result` containing the peer ID of the new keypair.
+pub fn create_keypair(
+    path: &Path,
+    force: bool,
+    vault: Option<&Vault>,
+) -> Result<PeerId, Box<dyn Error>> {
+    let (local_key, local_peer_id) = generate_keypair();
+    install_keypair(&local_key, path, force, vault)?;
+    Ok(local_peer_id)
+}
+
+/// Saves an existing keypair to a file, for `identity import`.
+///
+/// # Arguments
+///
+/// * `local_key` - The keypair.
+/// * `path` - The file the keypair is kept in.
+/// * `force` - Whether an existing keypair is replaced.
+/// * `vault` - The vault, if storage encryption is on.
+pub fn install_keypair(
+    local_key: &identity::Keypair,
+    path: &Path,
+    force: bool,
+    vault: Option<&Vault>,
+) -> Result<(), Box<dyn Error>> {
+    if path.exists() {
+        if !force {
+            return Err(format!("{:?} already exists, pass --force to replace it", path).into());
+        }
+        fs::remove_file(path)?;
+    }
+    save_keypair(local_key, path, vault)
+}
+
+/// Writes a keypair to a file only the current user may read.
+fn save_keypair(
+    local_key: &identity::Keypair,
+    path: &Path,
+    vault: Option<&Vault>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let encoded = local_key.to_protobuf_encoding()?;
+    let bytes = match vault {
+        Some(vault) => vault.seal(&encoded),
+        None => encoded,
+    };
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, &bytes)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use libp2p::PeerId;
+    use zeroize::Zeroizing;
 
-    use super::generate_keypair;
+    use super::{create_keypair, generate_keypair, load_keypair};
+    use crate::storage::{is_sealed, Vault};
 
     #[test]
     fn test_generate_keypair() {
         let (keypair, peer_id) = generate_keypair();
         assert_eq!(peer_id, PeerId::from(keypair.public()));
     }
+
+    #[test]
+    fn test_load_keypair() {
+        let dir = std::env::temp_dir().join(format!("sec_msg-{}", PeerId::random()));
+        let path = dir.join("identity.key");
+        let (_, generated) = load_keypair(&path, None).unwrap();
+        let (_, loaded) = load_keypair(&path, None).unwrap();
+        assert_eq!(generated, loaded);
+        assert!(create_keypair(&path, false, None).is_err());
+        let (_, replaced) = load_keypair(&path, None).unwrap();
+        assert_eq!(generated, replaced);
+        let created = create_keypair(&path, true, None).unwrap();
+        assert_ne!(generated, created);
+
+        // Turning encryption on seals the identity, which then needs it.
+        let vault = Vault::new(Zeroizing::new("correct horse".to_string()));
+        let (_, sealed) = load_keypair(&path, Some(&vault)).unwrap();
+        assert_eq!(sealed, created);
+        assert!(is_sealed(&std::fs::read(&path).unwrap()));
+        assert!(load_keypair(&path, None).is_err());
+        assert_eq!(load_keypair(&path, Some(&vault)).unwrap().1, created);
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(load_keypair(&path, None).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
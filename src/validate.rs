@@ -0,0 +1,416 @@
+/*!
+ * Validation module for the messaging application.
+ *
+ * This module collects the problems found while reading the configuration,
+ * so they are all reported at once instead of one per run. Every setting
+ * is read along with where it was set, the line of the configuration file,
+ * the environment variable or the command line option, which the reported
+ * problem points at.
+ *
+ * Environment variables are read from a snapshot taken with the file, so
+ * the variables that set nothing can be reported as well.
+ */
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use toml::Spanned;
+
+/// Where a setting was set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// A key of the configuration file, with its line.
+    File {
+        path: PathBuf,
+        line: usize,
+        key: String,
+    },
+    /// An environment variable.
+    Env(String),
+    /// A command line option.
+    Cli(&'static str),
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File { path, line, key } => write!(f, "{}:{}: {}", path.display(), line, key),
+            Origin::Env(name) => write!(f, "environment variable {}", name),
+            Origin::Cli(name) => write!(f, "option {}", name),
+        }
+    }
+}
+
+/// A value along with where it was set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting<T> {
+    pub value: T,
+    pub origin: Origin,
+}
+
+impl<T> Setting<T> {
+    /// Creates a new `Setting`.
+    pub fn new(value: T, origin: Origin) -> Self {
+        Setting { value, origin }
+    }
+}
+
+/// An invalid setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub origin: Origin,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)
+    }
+}
+
+/// Every problem found in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<Problem>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Reads settings, collecting their problems.
+pub struct Checker<'a> {
+    /// The path and text of the configuration file, for line numbers.
+    file: Option<(&'a Path, &'a str)>,
+    /// The environment variables.
+    env: BTreeMap<String, String>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    /// Creates a new `Checker`.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path and text of the configuration file, if read.
+    /// * `env` - The environment variables.
+    pub fn new(file: Option<(&'a Path, &'a str)>, env: BTreeMap<String, String>) -> Self {
+        Checker {
+            file,
+            env,
+            problems: Vec::new(),
+        }
+    }
+
+    /// Records a problem.
+    pub fn report(&mut self, origin: &Origin, message: impl Into<String>) {
+        self.problems.push(Problem {
+            origin: origin.clone(),
+            message: message.into(),
+        });
+    }
+
+    /// Returns the origin of a key of the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key, with the table it is in.
+    /// * `span` - Where its value is in the file.
+    pub fn in_file(&self, key: &str, span: Range<usize>) -> Origin {
+        let (path, line) = match self.file {
+            Some((path, text)) => {
+                let start = span.start.min(text.len());
+                (path, text[..start].matches('\n').count() + 1)
+            }
+            None => (Path::new("config.toml"), 0),
+        };
+        Origin::File {
+            path: path.to_path_buf(),
+            line,
+            key: key.to_string(),
+        }
+    }
+
+    /// Reads an environment variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The environment variable.
+    /// * `parse` - Parses its value.
+    pub fn env<T>(
+        &mut self,
+        name: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<Setting<T>> {
+        let value = self.env.get(name)?.clone();
+        self.parse(Origin::Env(name.to_string()), &value, parse)
+    }
+
+    /// Reads an environment variable holding a comma-separated list.
+    pub fn env_list<T>(
+        &mut self,
+        name: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<Setting<Vec<Setting<T>>>> {
+        let value = self.env.get(name)?.clone();
+        let origin = Origin::Env(name.to_string());
+        let items = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .filter_map(|item| self.parse(origin.clone(), item, &parse))
+            .collect();
+        Some(Setting::new(items, origin))
+    }
+
+    /// Reads a value of the configuration file that is parsed from text.
+    pub fn file<T>(
+        &mut self,
+        key: &str,
+        value: Option<Spanned<String>>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<Setting<T>> {
+        let value = value?;
+        let origin = self.in_file(key, value.span());
+        self.parse(origin, value.get_ref(), parse)
+    }
+
+    /// Reads a list of the configuration file whose items are parsed from
+    /// text.
+    pub fn file_list<T>(
+        &mut self,
+        key: &str,
+        value: Option<Spanned<Vec<Spanned<String>>>>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<Setting<Vec<Setting<T>>>> {
+        let value = value?;
+        let origin = self.in_file(key, value.span());
+        let items = value
+            .into_inner()
+            .into_iter()
+            .filter_map(|item| {
+                let origin = self.in_file(key, item.span());
+                self.parse(origin, item.get_ref(), &parse)
+            })
+            .collect();
+        Some(Setting::new(items, origin))
+    }
+
+    /// Reads a value of the configuration file that needs no parsing.
+    pub fn value<T>(&self, key: &str, value: Option<Spanned<T>>) -> Option<Setting<T>> {
+        let value = value?;
+        let origin = self.in_file(key, value.span());
+        Some(Setting::new(value.into_inner(), origin))
+    }
+
+    /// Reports the environment variables starting with `prefix` that are
+    /// not among the `known` ones, most likely misspelled.
+    pub fn unknown_env(&mut self, prefix: &str, known: &[&str], expected: &str) {
+        let unknown: Vec<String> = self
+            .env
+            .keys()
+            .filter(|name| name.starts_with(prefix) && !known.contains(&name.as_str()))
+            .cloned()
+            .collect();
+        for name in unknown {
+            self.report(
+                &Origin::Env(name),
+                format!("unknown setting, expected {}", expected),
+            );
+        }
+    }
+
+    /// Returns every problem recorded, if any.
+    pub fn finish(self) -> Result<(), ConfigError> {
+        if self.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError {
+                problems: self.problems,
+            })
+        }
+    }
+
+    fn parse<T>(
+        &mut self,
+        origin: Origin,
+        value: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<Setting<T>> {
+        match parse(value) {
+            Ok(parsed) => Some(Setting::new(parsed, origin)),
+            Err(e) => {
+                self.report(&origin, e);
+                None
+            }
+        }
+    }
+}
+
+/// Returns a parser for types implementing `FromStr` whose errors tell
+/// what was expected.
+///
+/// # Arguments
+///
+/// * `expected` - What a valid value looks like.
+pub fn parsed<T>(expected: &'static str) -> impl Fn(&str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    move |value| {
+        value
+            .trim()
+            .parse()
+            .map_err(|e| format!("{:?} is invalid ({}), expected {}", value, e, expected))
+    }
+}
+
+/// Parses an `on` or `off` switch.
+pub fn switch(value: &str) -> Result<bool, String> {
+    match value.trim() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => Err(format!("{:?} is invalid, expected on or off", other)),
+    }
+}
+
+/// Checks a log filter, such as `info` or `sec_msg=debug,libp2p=warn`.
+pub fn log_filter(value: &str) -> Result<String, String> {
+    const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+    // A filter may end with a regular expression matching the messages.
+    let directives = value.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim) {
+        let level = match directive.split_once('=') {
+            Some((_, level)) => level,
+            // A lone module name enables every level of that module.
+            None if !LEVELS.contains(&directive.to_ascii_lowercase().as_str()) => continue,
+            None => directive,
+        };
+        if !LEVELS.contains(&level.trim().to_ascii_lowercase().as_str()) {
+            return Err(format!(
+                "{:?} has an unknown level {:?}, expected one of {}",
+                value,
+                level,
+                LEVELS.join(", ")
+            ));
+        }
+    }
+    Ok(value.to_string())
+}
+
+/// Returns whether an address is an IP address with a TCP port, the only
+/// addresses the transport supports.
+pub fn is_tcp(addr: &Multiaddr) -> bool {
+    let mut protocols = addr.iter();
+    matches!(
+        (protocols.next(), protocols.next()),
+        (
+            Some(Protocol::Ip4(_) | Protocol::Ip6(_)),
+            Some(Protocol::Tcp(_))
+        )
+    )
+}
+
+/// Returns the TCP port of an address.
+pub fn tcp_port(addr: &Multiaddr) -> Option<u16> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Tcp(port) => Some(port),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::Path};
+
+    use libp2p::Multiaddr;
+
+    use super::{is_tcp, log_filter, parsed, switch, Checker, Origin};
+
+    #[test]
+    fn test_checker() {
+        let text = "theme = \"neon\"\n\nlisten = [\n  \"/ip4/0.0.0.0/tcp/1\",\n  \"nowhere\",\n]\n";
+        let path = Path::new("/etc/sec_msg.toml");
+        #[derive(serde::Deserialize)]
+        struct File {
+            theme: Option<toml::Spanned<String>>,
+            listen: Option<toml::Spanned<Vec<toml::Spanned<String>>>>,
+        }
+        let file: File = toml::from_str(text).unwrap();
+        let env = BTreeMap::from([
+            ("SEC_MSG_UI_THEME".to_string(), "neon".to_string()),
+            ("SEC_MSG_THEM".to_string(), "dark".to_string()),
+        ]);
+        let mut checker = Checker::new(Some((path, text)), env);
+        let listen = checker
+            .file_list("listen", file.listen, parsed::<Multiaddr>("a multiaddr"))
+            .unwrap();
+        assert_eq!(listen.value.len(), 1);
+        assert_eq!(
+            listen.origin,
+            Origin::File {
+                path: path.to_path_buf(),
+                line: 3,
+                key: "listen".to_string()
+            }
+        );
+        checker.report(&listen.value[0].origin, "port 1 is reserved");
+        assert!(checker.file("theme", file.theme, switch).is_none());
+        assert!(checker.env("SEC_MSG_UI_THEME", switch).is_none());
+        assert!(checker.env("SEC_MSG_UI_CLOCK", switch).is_none());
+        checker.unknown_env("SEC_MSG_", &["SEC_MSG_UI_THEME"], "a known variable");
+
+        // Every problem is reported, in the order found.
+        let error = checker.finish().unwrap_err();
+        let lines: Vec<String> = error.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("/etc/sec_msg.toml:5: listen: \"nowhere\" is invalid"));
+        assert_eq!(lines[1], "/etc/sec_msg.toml:4: listen: port 1 is reserved");
+        assert_eq!(
+            lines[2],
+            "/etc/sec_msg.toml:1: theme: \"neon\" is invalid, expected on or off"
+        );
+        assert_eq!(
+            lines[3],
+            "environment variable SEC_MSG_UI_THEME: \"neon\" is invalid, expected on or off"
+        );
+        assert_eq!(
+            lines[4],
+            "environment variable SEC_MSG_THEM: unknown setting, expected a known variable"
+        );
+        assert!(error.to_string().starts_with("Invalid configuration:\n  "));
+        assert!(Checker::new(None, BTreeMap::new()).finish().is_ok());
+    }
+
+    #[test]
+    fn test_rules() {
+        assert!(log_filter("info").is_ok());
+        assert!(log_filter("sec_msg=debug, libp2p=WARN").is_ok());
+        assert!(log_filter("sec_msg").is_ok());
+        assert!(log_filter("info/ping").is_ok());
+        assert!(log_filter("sec_msg=loud").is_err());
+
+        let addr = |text: &str| text.parse::<Multiaddr>().unwrap();
+        assert!(is_tcp(&addr(
+            "/ip6/::1/tcp/4001/p2p/12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE"
+        )));
+        assert!(!is_tcp(&addr("/dns4/example.org/tcp/4001")));
+        assert!(!is_tcp(&addr("/ip4/127.0.0.1/udp/4001/quic-v1")));
+    }
+}
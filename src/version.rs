@@ -0,0 +1,190 @@
+/*!
+ * Version negotiation module for the messaging application.
+ *
+ * Peers identify each other on every connection. The identify protocol
+ * version carries the envelope version spoken by the peer and the list of
+ * supported protocols tells which features it offers. This module keeps
+ * what each connected peer announced, so incompatible peers are reported
+ * once instead of on every message they send, and optional features are
+ * only used with peers supporting them.
+ */
+
+use std::collections::{HashMap, HashSet};
+
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+
+use crate::discovery::DISCOVERY_PROTOCOL;
+use crate::history::HISTORY_PROTOCOL;
+use crate::protocol::ENVELOPE_VERSION;
+use crate::stream::STREAM_PROTOCOL;
+use crate::transfer::FILE_PROTOCOL;
+
+/// Prefix of the identify protocol version announced by `sec_msg` peers.
+const PROTOCOL_VERSION_PREFIX: &str = "/sec_msg/";
+
+/// Features, by the protocol implementing them.
+const FEATURES: &[(StreamProtocol, &str)] = &[
+    (FILE_PROTOCOL, "file-transfer"),
+    (HISTORY_PROTOCOL, "history"),
+    (STREAM_PROTOCOL, "streaming"),
+    (DISCOVERY_PROTOCOL, "discovery"),
+];
+
+/// Returns the identify protocol version announced by this build.
+pub fn protocol_version() -> String {
+    format!("{}{}", PROTOCOL_VERSION_PREFIX, ENVELOPE_VERSION)
+}
+
+/// Returns the identify agent version announced by this build.
+pub fn agent_version() -> String {
+    format!("sec_msg/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Whether a peer speaks the local envelope version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compatibility {
+    Compatible,
+    /// The peer speaks an older envelope version.
+    Older(u16),
+    /// The peer speaks a newer envelope version.
+    Newer(u16),
+    /// The peer does not run `sec_msg`.
+    Foreign,
+}
+
+/// What a peer announced about itself.
+pub struct PeerVersion {
+    pub agent: String,
+    pub compatibility: Compatibility,
+    protocols: Vec<StreamProtocol>,
+    /// The addresses the peer listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+}
+
+impl PeerVersion {
+    /// Interprets the identify information of a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol_version` - The announced protocol version.
+    /// * `agent` - The announced agent version.
+    /// * `protocols` - The announced supported protocols.
+    pub fn new(protocol_version: &str, agent: String, protocols: Vec<StreamProtocol>) -> Self {
+        let compatibility = match protocol_version
+            .strip_prefix(PROTOCOL_VERSION_PREFIX)
+            .and_then(|version| version.parse::<u16>().ok())
+        {
+            Some(version) if version == ENVELOPE_VERSION => Compatibility::Compatible,
+            Some(version) if version < ENVELOPE_VERSION => Compatibility::Older(version),
+            Some(version) => Compatibility::Newer(version),
+            None => Compatibility::Foreign,
+        };
+        PeerVersion {
+            agent,
+            compatibility,
+            protocols,
+            listen_addrs: Vec::new(),
+        }
+    }
+
+    /// Returns the names of the known features the peer supports.
+    pub fn features(&self) -> Vec<&'static str> {
+        FEATURES
+            .iter()
+            .filter(|(protocol, _)| self.protocols.contains(protocol))
+            .map(|(_, feature)| *feature)
+            .collect()
+    }
+}
+
+/// What connected peers announced about themselves.
+pub struct Versions {
+    peers: HashMap<PeerId, PeerVersion>,
+    warned: HashSet<PeerId>,
+}
+
+impl Versions {
+    /// Creates a new, empty `Versions` table.
+    pub fn new() -> Self {
+        Versions {
+            peers: HashMap::new(),
+            warned: HashSet::new(),
+        }
+    }
+
+    /// Records what a peer announced.
+    pub fn insert(&mut self, peer: PeerId, version: PeerVersion) {
+        self.peers.insert(peer, version);
+    }
+
+    /// Forgets a disconnected peer.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+        self.warned.remove(peer);
+    }
+
+    /// Returns what a peer announced, if it was identified.
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerVersion> {
+        self.peers.get(peer)
+    }
+
+    /// Returns whether a peer supports a protocol.
+    ///
+    /// Peers that were not identified yet are assumed to support it.
+    pub fn supports(&self, peer: &PeerId, protocol: &StreamProtocol) -> bool {
+        self.peers
+            .get(peer)
+            .is_none_or(|version| version.protocols.contains(protocol))
+    }
+
+    /// Returns whether an incompatibility of a peer should be reported,
+    /// which is only the case the first time.
+    pub fn should_warn(&mut self, peer: PeerId) -> bool {
+        self.warned.insert(peer)
+    }
+}
+
+impl Default for Versions {
+    fn default() -> Self {
+        Versions::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::{protocol_version, Compatibility, PeerVersion, Versions};
+    use crate::protocol::ENVELOPE_VERSION;
+    use crate::stream::STREAM_PROTOCOL;
+    use crate::transfer::FILE_PROTOCOL;
+
+    #[test]
+    fn test_compatibility() {
+        let version = |protocol_version: &str| {
+            PeerVersion::new(protocol_version, String::new(), Vec::new()).compatibility
+        };
+        assert_eq!(version(&protocol_version()), Compatibility::Compatible);
+        assert_eq!(
+            version(&format!("/sec_msg/{}", ENVELOPE_VERSION + 1)),
+            Compatibility::Newer(ENVELOPE_VERSION + 1)
+        );
+        assert_eq!(version("/sec_msg/1"), Compatibility::Older(1));
+        assert_eq!(version("/ipfs/0.1.0"), Compatibility::Foreign);
+    }
+
+    #[test]
+    fn test_features() {
+        let mut versions = Versions::new();
+        let peer = PeerId::random();
+        assert!(versions.supports(&peer, &STREAM_PROTOCOL));
+
+        let version = PeerVersion::new(&protocol_version(), String::new(), vec![FILE_PROTOCOL]);
+        assert_eq!(version.features(), vec!["file-transfer"]);
+        versions.insert(peer, version);
+        assert!(!versions.supports(&peer, &STREAM_PROTOCOL));
+
+        assert!(versions.should_warn(peer));
+        assert!(!versions.should_warn(peer));
+    }
+}
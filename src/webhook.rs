@@ -0,0 +1,259 @@
+/*!
+ * Webhook module for the messaging application.
+ *
+ * Webhooks are the glue between topics and the services around them, such
+ * as CI alerts and chatops. Inbound, the HTTP API of a daemon serves
+ * `POST /hooks/{topic}`, which takes any JSON document and publishes it on
+ * the topic: its `text`, `body`, `message` or `content` field if it has
+ * one, as sent by most chat integrations, and the compact document
+ * otherwise. Outbound, a daemon started with `--webhook TOPIC=URL` posts
+ * every message received on the topic to the URL as a JSON object, whose
+ * `text` field makes it readable by Slack and Mattermost as is.
+ *
+ * Both ways are authenticated with the shared secret set in
+ * `SEC_MSG_WEBHOOK_SECRET`. Outbound requests carry the HMAC-SHA256 of
+ * their body in `X-Hub-Signature-256`, as GitHub sends it, and inbound
+ * requests carry either that signature or the secret itself in
+ * `X-Webhook-Secret`, for services that cannot sign. Messages a daemon
+ * publishes are not posted back by it, so a hook in both directions does
+ * not loop. Webhooks are built with the `webhooks` feature.
+ */
+
+/// The environment variable holding the secret webhooks are signed with.
+pub const SECRET_VAR: &str = "SEC_MSG_WEBHOOK_SECRET";
+
+/// The header carrying the signature of the body.
+pub const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+
+/// The header carrying the secret, when a request is not signed.
+pub const SECRET_HEADER: &str = "x-webhook-secret";
+
+#[cfg(feature = "webhooks")]
+pub use hooks::{forward, sign, text, verify};
+
+#[cfg(feature = "webhooks")]
+mod hooks {
+    use std::{env, error::Error, time::Duration};
+
+    use futures::StreamExt;
+    use hmac::{Hmac, Mac};
+    use log::{debug, error, info, warn};
+    use serde_json::{json, Value};
+    use sha2::Sha256;
+
+    use super::{SECRET_VAR, SIGNATURE_HEADER};
+    use crate::client::{Client, Message};
+
+    /// Fields holding the text of a document, the first one found used.
+    const TEXT_FIELDS: [&str; 4] = ["text", "body", "message", "content"];
+
+    /// Times a message is posted before it is dropped.
+    const ATTEMPTS: u32 = 3;
+
+    /// First delay before posting a message again.
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    /// Longest a hook may take to answer.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Returns the signature of a body, as sent in `X-Hub-Signature-256`.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The shared secret.
+    /// * `body` - The body of the request.
+    pub fn sign(secret: &[u8], body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key");
+        mac.update(body);
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("sha256={}", hex)
+    }
+
+    /// Returns whether a signature matches a body, comparing in constant
+    /// time.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The shared secret.
+    /// * `body` - The body of the request.
+    /// * `signature` - The value of `X-Hub-Signature-256`.
+    pub fn verify(secret: &[u8], body: &[u8], signature: &str) -> bool {
+        let Some(hex) = signature.strip_prefix("sha256=") else {
+            return false;
+        };
+        let digest: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key");
+        mac.update(body);
+        digest.is_some_and(|digest| mac.verify_slice(&digest).is_ok())
+    }
+
+    /// Returns the text published for a JSON document.
+    pub fn text(document: &Value) -> String {
+        let field = TEXT_FIELDS
+            .iter()
+            .find_map(|field| document.get(field).and_then(Value::as_str));
+        match (document.as_str(), field) {
+            (Some(text), _) | (_, Some(text)) => text.to_string(),
+            _ => document.to_string(),
+        }
+    }
+
+    /// The JSON object a message is posted as.
+    fn payload(message: &Message) -> Value {
+        let label = message
+            .name
+            .clone()
+            .unwrap_or_else(|| message.peer.to_base58());
+        json!({
+            "topic": message.topic,
+            "id": message.id,
+            "peer": message.peer.to_base58(),
+            "name": message.name,
+            "body": message.body,
+            "text": format!("{}: {}", label, message.body),
+        })
+    }
+
+    /// Starts posting the messages of topics to webhooks.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The handle to the node.
+    /// * `hooks` - The topics forwarded, each with the URL posted to.
+    pub fn forward(client: Client, hooks: Vec<(String, String)>) -> Result<(), Box<dyn Error>> {
+        let secret = env::var(SECRET_VAR)
+            .ok()
+            .filter(|secret| !secret.is_empty());
+        if secret.is_none() {
+            warn!("{} is not set, webhooks are posted unsigned", SECRET_VAR);
+        }
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("sec_msg/", env!("CARGO_PKG_VERSION")))
+            .timeout(TIMEOUT)
+            .build()?;
+        for (topic, url) in hooks {
+            info!("Posting the messages of {} to {}", topic, url);
+            let (client, http, secret) = (client.clone(), http.clone(), secret.clone());
+            // Joining the topic waits for the swarm loop, which runs later.
+            tokio::spawn(async move {
+                let mut messages = match client.subscribe(&topic).await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        error!("Failed to forward {}: {}", topic, e);
+                        return;
+                    }
+                };
+                while let Some(message) = messages.next().await {
+                    let body = payload(&message).to_string();
+                    post(&http, &url, body, secret.as_deref()).await;
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Posts a message to a webhook, trying again with a growing delay
+    /// when it fails.
+    async fn post(http: &reqwest::Client, url: &str, body: String, secret: Option<&str>) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=ATTEMPTS {
+            let mut request = http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json");
+            if let Some(secret) = secret {
+                request =
+                    request.header(SIGNATURE_HEADER, sign(secret.as_bytes(), body.as_bytes()));
+            }
+            match request.body(body.clone()).send().await {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Posted a message to {}", url);
+                    return;
+                }
+                Ok(response) => warn!(
+                    "Webhook {} answered {} (attempt {} of {})",
+                    url,
+                    response.status(),
+                    attempt,
+                    ATTEMPTS
+                ),
+                Err(e) => warn!(
+                    "Webhook {} failed: {} (attempt {} of {})",
+                    url, e, attempt, ATTEMPTS
+                ),
+            }
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        error!("Dropped a message to webhook {}", url);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use libp2p::PeerId;
+        use serde_json::json;
+
+        use super::{payload, sign, text, verify};
+        use crate::client::Message;
+
+        #[test]
+        fn test_webhooks() {
+            let signature = sign(b"It's a Secret to Everybody", b"Hello, World!");
+            // The example of the GitHub documentation.
+            assert_eq!(
+                signature,
+                "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+            );
+            assert!(verify(
+                b"It's a Secret to Everybody",
+                b"Hello, World!",
+                &signature
+            ));
+            assert!(!verify(
+                b"It's a Secret to Everybody",
+                b"Hello, World?",
+                &signature
+            ));
+            assert!(!verify(b"wrong", b"Hello, World!", &signature));
+            assert!(!verify(
+                b"It's a Secret to Everybody",
+                b"Hello, World!",
+                "sha256=zz"
+            ));
+
+            assert_eq!(text(&json!({"text": "build failed"})), "build failed");
+            assert_eq!(text(&json!({"content": "deployed"})), "deployed");
+            assert_eq!(text(&json!("plain")), "plain");
+            assert_eq!(
+                text(&json!({"status": "firing", "count": 2})),
+                r#"{"count":2,"status":"firing"}"#
+            );
+
+            let peer = PeerId::random();
+            let message = Message {
+                topic: "ci".to_string(),
+                id: "abc".to_string(),
+                peer,
+                name: Some("alice".to_string()),
+                body: "ship it".to_string(),
+            };
+            let posted = payload(&message);
+            assert_eq!(posted["text"], "alice: ship it");
+            assert_eq!(posted["peer"], peer.to_base58());
+        }
+    }
+}
+
+/// Fails, as webhooks are built with the `webhooks` feature.
+#[cfg(not(feature = "webhooks"))]
+pub fn forward(
+    _client: crate::client::Client,
+    _hooks: Vec<(String, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without webhooks, rebuild with --features webhooks".into())
+}